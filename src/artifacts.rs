@@ -0,0 +1,160 @@
+// The files in a run's directory, such as transcripts, pictures and raw data,
+// listed with the test each one belongs to, its size and its hash, so that a
+// failure package put together for RMA analysis can be checked for completeness.
+extern crate base64;
+extern crate sha2;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use self::sha2::{Digest, Sha256};
+
+/// The list of a finished run's artifacts, in its run directory.
+pub const MANIFEST_FILE: &str = "artifacts.tsv";
+
+/// Files that belong to the run as a whole, rather than to a test.
+const RUN_FILES: [&str; 3] = ["events.log", "junit.xml", MANIFEST_FILE];
+
+#[derive(Clone, Debug)]
+pub struct Artifact {
+    /// Where the file is, relative to the run directory, with "/" between directories.
+    pub path: String,
+
+    /// The id of the test the file belongs to, if it's known.
+    pub test: Option<String>,
+
+    pub size: u64,
+
+    /// The SHA-256 of the file's contents, in hex.
+    pub sha256: String,
+}
+
+/// Every file in a run directory other than the manifest, in order of path.
+/// `linked` says which test each of the files that tests announced belongs to.
+pub fn collect(directory: &Path, linked: &HashMap<String, String>) -> io::Result<Vec<Artifact>> {
+    let mut paths = vec![];
+    find_files(directory, "", &mut paths)?;
+    paths.sort();
+
+    let mut artifacts = vec![];
+    for path in paths {
+        if path == MANIFEST_FILE {
+            continue;
+        }
+        let (size, sha256) = hash(&directory.join(&path))?;
+        artifacts.push(Artifact {
+            test: linked.get(&path).cloned().or_else(|| owner(&path)),
+            path: path,
+            size: size,
+            sha256: sha256,
+        });
+    }
+    Ok(artifacts)
+}
+
+/// The artifacts of a run, from its manifest if it has finished, or as they are now if it hasn't.
+pub fn list(directory: &Path) -> io::Result<Vec<Artifact>> {
+    match read_manifest(directory) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => collect(directory, &HashMap::new()),
+        result => result,
+    }
+}
+
+/// Write the manifest, one artifact per line: path, test (or "-"), size, and hash.
+pub fn write_manifest(directory: &Path, artifacts: &[Artifact]) -> io::Result<()> {
+    let mut manifest = File::create(directory.join(MANIFEST_FILE))?;
+    for artifact in artifacts {
+        writeln!(
+            manifest,
+            "{}\t{}\t{}\t{}",
+            artifact.path,
+            artifact.test.as_ref().map(|t| t.as_str()).unwrap_or("-"),
+            artifact.size,
+            artifact.sha256
+        )?;
+    }
+    manifest.flush()
+}
+
+fn read_manifest(directory: &Path) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = vec![];
+    for line in BufReader::new(File::open(directory.join(MANIFEST_FILE))?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        artifacts.push(Artifact {
+            path: fields[0].to_owned(),
+            test: if fields[1] == "-" { None } else { Some(fields[1].to_owned()) },
+            size: fields[2].parse().unwrap_or(0),
+            sha256: fields[3].to_owned(),
+        });
+    }
+    Ok(artifacts)
+}
+
+/// The contents of one artifact, as base64 so that they fit on a line.
+pub fn read_base64(directory: &Path, path: &str) -> io::Result<String> {
+    let full_path = resolve(directory, path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not in the run directory", path)))?;
+    let mut contents = vec![];
+    File::open(full_path)?.read_to_end(&mut contents)?;
+    Ok(base64::encode(&contents))
+}
+
+/// Where a path relative to the run directory is, as long as it doesn't lead out of it.
+pub fn resolve(directory: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty() || relative.components().any(|c| match c {
+        Component::Normal(_) => false,
+        _ => true,
+    }) {
+        return None;
+    }
+    Some(directory.join(relative))
+}
+
+/// Which test a file belongs to, going by where it is: a test's transcript is named after it,
+/// and tests may keep their artifacts in a directory of their own under "artifacts/".
+fn owner(path: &str) -> Option<String> {
+    let parts: Vec<&str> = path.split('/').collect();
+    match parts.len() {
+        1 if !RUN_FILES.contains(&path) && path.ends_with(".log") => Some(path[..path.len() - ".log".len()].to_owned()),
+        n if n > 2 && parts[0] == "artifacts" => Some(parts[1].to_owned()),
+        _ => None,
+    }
+}
+
+fn find_files(directory: &Path, prefix: &str, paths: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_files(&entry.path(), &format!("{}/", name), paths)?;
+        } else if file_type.is_file() {
+            paths.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// The size and SHA-256 of a file.
+fn hash(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buffer = [0; 65536];
+    let mut size = 0;
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.input(&buffer[..count]);
+        size += count as u64;
+    }
+    Ok((size, format!("{:x}", hasher.result())))
+}
@@ -0,0 +1,117 @@
+use std::time;
+
+use timestamp;
+use unit::UnitName;
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+/// A control command that was received from an interface or trigger,
+/// kept so that it's possible to trace who did what, and when.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    /// The unit that sent the command.
+    unit: UnitName,
+
+    /// The operator that was logged in when the command was received, if any.
+    operator: Option<String>,
+
+    /// The command itself, e.g. "start".
+    verb: String,
+
+    /// Any arguments that were given with the command.
+    arguments: String,
+
+    /// Number of seconds since the epoch
+    unix_time: u64,
+
+    /// Number of nanoseconds since the epoch
+    unix_time_nsecs: u32,
+
+    /// Wall-clock time in RFC3339 format, in the configured timezone
+    timestamp: String,
+}
+
+impl AuditEntry {
+    /// Make an audit entry for a control message, if it's one that changes
+    /// what the system is doing.  Queries such as "jig" and "tests" aren't recorded.
+    pub fn from_control(msg: &ManagerControlMessage, operator: &Option<String>) -> Option<AuditEntry> {
+        let (verb, arguments) = match msg.contents {
+            ManagerControlMessageContents::StartScenario(None) => ("start", "".to_owned()),
+            ManagerControlMessageContents::StartScenario(Some(ref scenario)) => ("start", scenario.id().clone()),
+            ManagerControlMessageContents::Scenario(ref scenario) => ("scenario", scenario.id().clone()),
+            ManagerControlMessageContents::FireTrigger(ref trigger) => ("trigger", trigger.id().clone()),
+            ManagerControlMessageContents::Operator(ref new_operator) => {
+                ("operator", new_operator.clone().unwrap_or_default())
+            }
+            ManagerControlMessageContents::Serial(ref serial) => ("serial", serial.clone()),
+            ManagerControlMessageContents::Variables(ref variables) => (
+                "variables",
+                variables
+                    .iter()
+                    .map(|&(ref k, ref v)| format!("{}={}", k, v))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            ManagerControlMessageContents::Tag(ref tags) => (
+                "tag",
+                tags.iter()
+                    .map(|&(ref k, ref v)| format!("{}={}", k, v))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            ManagerControlMessageContents::JigState(health, ref reason) => {
+                ("jigstate", format!("{} {}", health, reason).trim().to_owned())
+            }
+            ManagerControlMessageContents::Shutdown(ref reason) => ("shutdown", reason.clone().unwrap_or_default()),
+            ManagerControlMessageContents::Rescan => ("rescan", "".to_owned()),
+            ManagerControlMessageContents::Profile(ref profile) => ("profile", profile.clone()),
+            ManagerControlMessageContents::Golden(mode) => ("golden", mode.to_string()),
+            ManagerControlMessageContents::Recover(resume) => {
+                ("recover", if resume { "resume" } else { "invalidate" }.to_owned())
+            }
+            _ => return None,
+        };
+
+        let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(d) => d,
+            Err(_) => time::Duration::new(0, 0),
+        };
+
+        Some(AuditEntry {
+            unit: msg.sender.clone(),
+            operator: operator.clone(),
+            verb: verb.to_owned(),
+            arguments: arguments,
+            unix_time: now.as_secs(),
+            unix_time_nsecs: now.subsec_nanos(),
+            timestamp: timestamp::rfc3339(now.as_secs(), now.subsec_nanos()),
+        })
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.unit
+    }
+
+    pub fn operator(&self) -> &Option<String> {
+        &self.operator
+    }
+
+    pub fn verb(&self) -> &String {
+        &self.verb
+    }
+
+    pub fn arguments(&self) -> &String {
+        &self.arguments
+    }
+
+    pub fn secs(&self) -> u64 {
+        self.unix_time
+    }
+
+    pub fn nsecs(&self) -> u32 {
+        self.unix_time_nsecs
+    }
+
+    pub fn timestamp(&self) -> &String {
+        &self.timestamp
+    }
+}
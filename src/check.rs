@@ -0,0 +1,204 @@
+// `exclave check` parses every unit in the configuration directories and
+// makes sure they can be loaded together, without starting anything.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use config::Config;
+use plugin;
+use unit::{is_manifest, manifest_units, UnitDescriptionError, UnitIncompatibleReason, UnitKind, UnitName};
+use unitbroadcaster::UnitBroadcaster;
+use unitmanager::UnitManager;
+use units::custom::CustomDescription;
+use units::interface::InterfaceDescription;
+use units::jig::JigDescription;
+use units::limits::LimitsDescription;
+use units::logger::LoggerDescription;
+use units::resource::ResourceDescription;
+use units::scenario::ScenarioDescription;
+use units::test::TestDescription;
+use units::trigger::TriggerDescription;
+
+/// A unit file that has been parsed successfully.
+enum Description {
+    Interface(InterfaceDescription),
+    Jig(JigDescription),
+    Limits(LimitsDescription),
+    Logger(LoggerDescription),
+    Resource(ResourceDescription),
+    Scenario(ScenarioDescription),
+    Test(TestDescription),
+    Trigger(TriggerDescription),
+    Custom(CustomDescription),
+}
+
+/// The number of problems found.
+#[derive(Default)]
+pub struct CheckResult {
+    pub errors: u32,
+    pub warnings: u32,
+}
+
+/// Check every unit in `dirs`, printing a line for each problem found.
+/// If `namespaced` is set, units in subdirectories get the directories as part of their id.
+pub fn check(dirs: &[&str], namespaced: bool, config: &Arc<Mutex<Config>>) -> io::Result<CheckResult> {
+    load(dirs, namespaced, config, &mut io::stdout()).map(|(_, result)| result)
+}
+
+/// Load every unit in `dirs` into a manager of its own, writing a line to `out` for each problem
+/// found.  Nothing is selected or started, so the manager can only be used to look at them.
+pub fn load<W: Write>(dirs: &[&str], namespaced: bool, config: &Arc<Mutex<Config>>, out: &mut W) -> io::Result<(UnitManager, CheckResult)> {
+    let mut result = CheckResult::default();
+    let mut descriptions = vec![];
+
+    for dir in dirs {
+        let mut paths = vec![];
+        unit_files(Path::new(dir), &mut paths)?;
+        paths.sort();
+        for (name, path) in units(&paths, Path::new(dir), namespaced) {
+            match parse(&name, &path) {
+                Ok(description) => descriptions.push((path, description)),
+                Err(e) => {
+                    result.errors += 1;
+                    match e.locate(&name, &path) {
+                        // The parser lists the line of each syntax error itself.
+                        UnitDescriptionError::ParseError(ref pe) => writeln!(out, "{}: error: {}", path.display(), pe)?,
+                        UnitDescriptionError::Located(line, column, ref directive, ref e) => {
+                            writeln!(out, "{}:{}:{}: error: {}", path.display(), line, column, e)?;
+                            writeln!(out, "    {}", directive)?;
+                        }
+                        e => writeln!(out, "{}: error: {}", path.display(), e)?,
+                    }
+                }
+            }
+        }
+    }
+
+    // Load the units in the same order as the library does, so that
+    // scenarios can find their tests and everything can find its jig.
+    let manager = UnitManager::new(&UnitBroadcaster::new(), config);
+    let mut order = vec![UnitKind::Jig, UnitKind::Interface, UnitKind::Logger, UnitKind::Trigger];
+    order.extend(plugin::registered_kinds().into_iter().map(|kind| UnitKind::Custom(Arc::new(kind))));
+    order.extend(vec![UnitKind::Limits, UnitKind::Resource, UnitKind::Test, UnitKind::Scenario]);
+    for kind in order.iter() {
+        let mut pending: Vec<&(PathBuf, Description)> = descriptions.iter().filter(|entry| entry.1.kind() == *kind).collect();
+
+        // Units may require others of the same kind, so keep retrying the ones
+        // that were missing a requirement for as long as that gets more of them loaded.
+        let mut failed = vec![];
+        loop {
+            let mut waiting = vec![];
+            let attempted = pending.len();
+            for entry in pending {
+                match load_unit(&manager, &entry.1) {
+                    Ok(()) => (),
+                    Err(e @ UnitIncompatibleReason::RequirementNotLoaded(_)) => waiting.push((entry, e)),
+                    Err(e) => failed.push((entry, e)),
+                }
+            }
+            if waiting.is_empty() || waiting.len() == attempted {
+                failed.extend(waiting);
+                break;
+            }
+            pending = waiting.into_iter().map(|(entry, _)| entry).collect();
+        }
+
+        for (&(ref path, _), e) in failed {
+            match e {
+                // Broken dependencies are a mistake in the units themselves.
+                UnitIncompatibleReason::DependencyError(_) => {
+                    result.errors += 1;
+                    writeln!(out, "{}: error: {}", path.display(), e)?;
+                }
+                // Anything else depends on the station this is run on.
+                _ => {
+                    result.warnings += 1;
+                    writeln!(out, "{}: warning: not compatible with this station: {}", path.display(), e)?;
+                }
+            }
+        }
+    }
+
+    Ok((manager, result))
+}
+
+impl Description {
+    fn kind(&self) -> UnitKind {
+        match *self {
+            Description::Interface(_) => UnitKind::Interface,
+            Description::Jig(_) => UnitKind::Jig,
+            Description::Limits(_) => UnitKind::Limits,
+            Description::Logger(_) => UnitKind::Logger,
+            Description::Resource(_) => UnitKind::Resource,
+            Description::Scenario(_) => UnitKind::Scenario,
+            Description::Test(_) => UnitKind::Test,
+            Description::Trigger(_) => UnitKind::Trigger,
+            Description::Custom(ref d) => d.id().kind().clone(),
+        }
+    }
+}
+
+fn load_unit(manager: &UnitManager, description: &Description) -> Result<(), UnitIncompatibleReason> {
+    match *description {
+        Description::Interface(ref d) => manager.load_interface(d).map(|_| ()),
+        Description::Jig(ref d) => manager.load_jig(d).map(|_| ()),
+        Description::Limits(ref d) => manager.load_limits(d).map(|_| ()),
+        Description::Logger(ref d) => manager.load_logger(d).map(|_| ()),
+        Description::Resource(ref d) => manager.load_resource(d).map(|_| ()),
+        Description::Scenario(ref d) => manager.load_scenario(d).map(|_| ()),
+        Description::Test(ref d) => manager.load_test(d).map(|_| ()),
+        Description::Trigger(ref d) => manager.load_trigger(d).map(|_| ()),
+        Description::Custom(ref d) => manager.load_custom(d).map(|_| ()),
+    }
+}
+
+/// Every file in `dir` and its subdirectories.
+fn unit_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            unit_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The name of every unit in `paths`, along with the file it's defined in.  A manifest
+/// may define several units, which are named as if each had a file of its own next to it.
+fn units(paths: &[PathBuf], dir: &Path, namespaced: bool) -> Vec<(UnitName, PathBuf)> {
+    let mut units = vec![];
+    for path in paths {
+        if is_manifest(path) {
+            let contents = match fs::read_to_string(path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            for (file, _, _) in manifest_units(&contents) {
+                if let Ok(name) = UnitName::from_path_in(&path.with_file_name(file), dir, namespaced) {
+                    units.push((name, path.clone()));
+                }
+            }
+        } else if let Ok(name) = UnitName::from_path_in(path, dir, namespaced) {
+            units.push((name, path.clone()));
+        }
+    }
+    units
+}
+
+fn parse(name: &UnitName, path: &Path) -> Result<Description, UnitDescriptionError> {
+    Ok(match *name.kind() {
+        UnitKind::Interface => Description::Interface(InterfaceDescription::from_path(name.clone(), path)?),
+        UnitKind::Jig => Description::Jig(JigDescription::from_path(name.clone(), path)?),
+        UnitKind::Limits => Description::Limits(LimitsDescription::from_path(name.clone(), path)?),
+        UnitKind::Logger => Description::Logger(LoggerDescription::from_path(name.clone(), path)?),
+        UnitKind::Resource => Description::Resource(ResourceDescription::from_path(name.clone(), path)?),
+        UnitKind::Scenario => Description::Scenario(ScenarioDescription::from_path(name.clone(), path)?),
+        UnitKind::Test => Description::Test(TestDescription::from_path(name.clone(), path)?),
+        UnitKind::Trigger => Description::Trigger(TriggerDescription::from_path(name.clone(), path)?),
+        UnitKind::Custom(_) => Description::Custom(CustomDescription::from_path(name.clone(), path)?),
+        UnitKind::Internal => unreachable!("unit files are never internal"),
+    })
+}
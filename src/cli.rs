@@ -0,0 +1,737 @@
+// The exclave binary's command line.  It lives in the library so that the modules
+// it drives needn't be exported along with the Engine; src/main.rs only calls `main`.
+extern crate clap;
+
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use check;
+use config;
+use configfile;
+use daemon;
+use engine::{Engine, Timing};
+use exitcode;
+use internalmetrics;
+use oneshot;
+use plan;
+use plugin;
+use replay;
+use sdnotify;
+use spc;
+use standby;
+use statussocket;
+use supervisor;
+use terminal;
+use timestamp;
+use triggers;
+use unit;
+use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+
+use self::clap::{App, AppSettings, Arg, Shell, SubCommand};
+
+/// Every option and subcommand.  This is built again to generate shell completions.
+fn cli() -> App<'static, 'static> {
+    App::new("Exclave Testing System")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(env!("GIT_VERSION"))
+        .author("Sean Cross <sean@xobs.io>")
+        .about("Orchestrates the Common Factory Test Interface server")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("CONFIG_DIR")
+                .short("c")
+                .long("config-dir")
+                .value_name("CONFIG_DIR")
+                .number_of_values(1)
+                .required(true)
+                .multiple(true)
+                .takes_value(true)
+                .help("Directory where configuration unit files are stored.  Units in later directories override ones with the same name in earlier directories"),
+        )
+        .arg(
+            Arg::with_name("CONFIG_FILE")
+                .short("f")
+                .long("config-file")
+                .value_name("CONFIG_FILE")
+                .help("Read settings from this file, and again whenever it changes.  Options given on the command line take precedence")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .short("P")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Start with this station profile from the configuration file, rather than the one it names")
+                .required(false)
+                .requires("CONFIG_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("PLAIN")
+                .short("p")
+                .long("plain-output")
+                .help("Force output to be 'plain' (rather than auto-detected)"),
+        )
+        .arg(
+            Arg::with_name("QUIET")
+                .short("q")
+                .long("no-output")
+                .help("Prevent console output entirely"),
+        )
+        .arg(
+            Arg::with_name("DEBUG_LOGFILE")
+                .short("9")
+                .long("debug-log")
+                .help("Log all internal messages to the specified file")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LOG_DIR")
+                .short("l")
+                .long("log-dir")
+                .value_name("LOG_DIR")
+                .help("Give each scenario run its own log directory underneath this one")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("TIMEZONE")
+                .short("z")
+                .long("timezone")
+                .value_name("TIMEZONE")
+                .help("Timezone for log timestamps: UTC, local, or an offset such as +05:30")
+                .required(false)
+                .takes_value(true)
+                .validator(|v| timestamp::TimeZone::from_str(&v).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("KEYBOARD_TRIGGER")
+                .short("k")
+                .long("keyboard-trigger")
+                .help("Run default scenario on enter key press"),
+        )
+        .arg(
+            Arg::with_name("DEBOUNCE")
+                .short("d")
+                .long("debounce")
+                .value_name("MILLISECONDS")
+                .help("Merge changes to the same unit file that happen within this many milliseconds")
+                .default_value("100")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("SETTLE_DELAY")
+                .long("settle-delay")
+                .value_name("MILLISECONDS")
+                .help("Wait until unit files have stopped changing for this many milliseconds before working out which units changed")
+                .default_value("200")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("RESCAN_DELAY")
+                .long("rescan-delay")
+                .value_name("MILLISECONDS")
+                .help("Wait until unit files have stopped changing for this many milliseconds before rescanning")
+                .default_value("1000")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("MIN_RESCAN_INTERVAL")
+                .long("min-rescan-interval")
+                .value_name("MILLISECONDS")
+                .help("Never rescan because unit files changed less than this many milliseconds after the last rescan.  The RESCAN command isn't affected")
+                .default_value("0")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("TERMINATE_TIMEOUT")
+                .long("terminate-timeout")
+                .value_name("SECONDS")
+                .help("How long to give programs to exit after asking them to, before killing them")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("JIG")
+                .long("jig")
+                .value_name("JIG")
+                .help("Use this jig if it's compatible, rather than whichever one is found first")
+                .takes_value(true)
+                .validator(|v| unit::UnitName::from_str(&v, "jig").map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("SCENARIO")
+                .long("scenario")
+                .value_name("SCENARIO")
+                .help("Select this scenario at startup, rather than the jig's default")
+                .takes_value(true)
+                .validator(|v| unit::UnitName::from_str(&v, "scenario").map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("SIMULATE")
+                .long("simulate")
+                .help("Rehearse without hardware: every jig is compatible, tests run ExecSimulate or pass, and hardware triggers wait for TRIGGER.  Programs see EXCLAVE_SIMULATE=1"),
+        )
+        .arg(
+            Arg::with_name("LOG_LEVEL")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Which log messages to print: error, info for everything from units, or debug to include exclave's own.  Defaults to debug")
+                .takes_value(true)
+                .validator(|v| terminal::LogLevel::from_str(&v).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("DAEMON")
+                .short("D")
+                .long("daemon")
+                .help("Detach from the terminal and run in the background"),
+        )
+        .arg(
+            Arg::with_name("PID_FILE")
+                .long("pid-file")
+                .value_name("PID_FILE")
+                .help("Write the process id to this file, which is removed on exit")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("NAMESPACE")
+                .short("n")
+                .long("namespace")
+                .help("Prefix the ids of units in subdirectories with their directory, e.g. tests/rf/power.test becomes tests/rf/power"),
+        )
+        .arg(
+            Arg::with_name("SOCKET")
+                .short("s")
+                .long("socket")
+                .value_name("SOCKET")
+                .help("Local socket used by `exclave list` to query a running instance")
+                .default_value(statussocket::DEFAULT_SOCKET_PATH)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("METRICS_LISTEN")
+                .long("metrics-listen")
+                .value_name("ADDRESS")
+                .help("Serve metrics about exclave itself for Prometheus on this address, e.g. 0.0.0.0:9101")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("STANDBY_PEER")
+                .long("standby-peer")
+                .value_name("ADDRESS")
+                .help("Run as one of a hot-standby pair, sending heartbeats to the other instance at this address, e.g. 10.0.0.2:9110")
+                .requires("STANDBY_LISTEN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("STANDBY_LISTEN")
+                .long("standby-listen")
+                .value_name("ADDRESS")
+                .help("Listen for the other instance's heartbeats on this address, e.g. 0.0.0.0:9110")
+                .requires("STANDBY_PEER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("BACKUP")
+                .long("backup")
+                .help("Be the backup of a hot-standby pair, which only becomes active if the other instance isn't")
+                .requires("STANDBY_PEER"),
+        )
+        .arg(
+            Arg::with_name("STANDBY_TIMEOUT")
+                .long("standby-timeout")
+                .value_name("MILLISECONDS")
+                .help("Take over once the active instance hasn't been heard from for this many milliseconds")
+                .default_value("3000")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("INSTRUMENT")
+                .long("instrument")
+                .help("Time rescans, parsing unit files, starting tests, and writing to interfaces, for the metrics and the debug log"),
+        )
+        .arg(
+            Arg::with_name("PLUGIN")
+                .long("plugin")
+                .value_name("PLUGIN")
+                .number_of_values(1)
+                .multiple(true)
+                .takes_value(true)
+                .help("Load a plugin, a shared library that adds a kind of unit of its own.  Needs a build with the \"plugins\" feature"),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Run the station, loading units and starting scenarios as they're asked for, until told to exit.  This is what exclave does when no subcommand is given"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check that every unit in the configuration directories can be loaded, without starting anything"),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Print the tests a scenario would run, in order, without running them")
+                .arg(
+                    Arg::with_name("SCENARIO")
+                        .value_name("SCENARIO")
+                        .help("The scenario to plan.  Defaults to the one given with --scenario or DefaultScenario")
+                        .validator(|v| unit::UnitName::from_str(&v, "scenario").map(|_| ()).map_err(|e| e.to_string())),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("spc")
+                .about("Print process-capability figures for each measurement in the runs in the log directory, comparing them with the limits in the units")
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the figures as CSV or JSON")
+                        .takes_value(true)
+                        .possible_values(&["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::with_name("SINCE")
+                        .long("since")
+                        .value_name("DURATION")
+                        .help("Only include runs that started this long ago or less, e.g. \"30days\"")
+                        .takes_value(true)
+                        .validator(|v| spc::parse_since(&v).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("TAG")
+                        .long("tag")
+                        .value_name("KEY=VALUE")
+                        .help("Only include runs with this variable or tag, e.g. \"firmware=1.4.2\".  May be given more than once")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(|v| triggers::split_variable(&v).map(|_| ()).ok_or_else(|| "expected KEY=VALUE".to_owned())),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the units known to a running instance, along with their status"),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run a scenario once, print a summary, and exit with 0 if it passed, 1 if it failed, 2 if it couldn't be run, or 3 if it was aborted")
+                .arg(
+                    Arg::with_name("SCENARIO")
+                        .value_name("SCENARIO")
+                        .help("The scenario to run")
+                        .required(true)
+                        .validator(|v| unit::UnitName::from_str(&v, "scenario").map(|_| ()).map_err(|e| e.to_string())),
+                )
+                .arg(
+                    Arg::with_name("JIG")
+                        .long("jig")
+                        .value_name("JIG")
+                        .help("Use this jig if it's compatible, rather than whichever one is found first")
+                        .takes_value(true)
+                        .validator(|v| unit::UnitName::from_str(&v, "jig").map(|_| ()).map_err(|e| e.to_string())),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Load the interfaces and loggers as usual, and feed them the log messages from a recorded run, without starting any scenarios")
+                .arg(
+                    Arg::with_name("EVENTS")
+                        .value_name("EVENTS")
+                        .help("The events.log of the run to replay")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("SPEED")
+                        .long("speed")
+                        .value_name("SPEED")
+                        .help("How many times faster than it was recorded to replay the run, e.g. 10")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|v| replay::parse_speed(&v).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("EXIT")
+                        .long("exit")
+                        .help("Shut down once the whole run has been replayed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a completion script for SHELL")
+                .arg(
+                    Arg::with_name("SHELL")
+                        .value_name("SHELL")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
+
+/// Run exclave as the command line asks.
+pub fn main() {
+    let config = Arc::new(Mutex::new(config::Config::new()));
+
+    let matches = cli()
+        .get_matches_safe()
+        .unwrap_or_else(|e| {
+            // --help and --version aren't errors.
+            if !e.use_stderr() {
+                e.exit();
+            }
+            eprintln!("{}", e.message);
+            process::exit(exitcode::CONFIG_ERROR);
+        });
+
+    if let Some(completions_matches) = matches.subcommand_matches("completions") {
+        let shell = completions_matches.value_of("SHELL").unwrap().parse::<Shell>().unwrap();
+        cli().gen_completions_to("exclave", shell, &mut std::io::stdout());
+        process::exit(exitcode::SUCCESS);
+    }
+
+    let config_dirs: Vec<_> = matches.values_of("CONFIG_DIR").map(|v| v.collect()).unwrap_or(vec![]);
+    let socket_path = std::path::Path::new(matches.value_of("SOCKET").unwrap());
+    let debounce = Duration::from_millis(matches.value_of("DEBOUNCE").unwrap().parse().unwrap());
+    let settle_delay = Duration::from_millis(matches.value_of("SETTLE_DELAY").unwrap().parse().unwrap());
+    let rescan_delay = Duration::from_millis(matches.value_of("RESCAN_DELAY").unwrap().parse().unwrap());
+    let min_rescan_interval = Duration::from_millis(matches.value_of("MIN_RESCAN_INTERVAL").unwrap().parse().unwrap());
+    let run_matches = matches.subcommand_matches("run");
+    let replay_matches = matches.subcommand_matches("replay");
+    let output_type = if matches.is_present("DAEMON") {
+        Some(terminal::TerminalOutputType::None)
+    } else if matches.is_present("PLAIN") {
+        Some(terminal::TerminalOutputType::Plain)
+    } else if matches.is_present("QUIET") || run_matches.is_some() {
+        Some(terminal::TerminalOutputType::None)
+    } else {
+        None
+    };
+
+    // Settings given on the command line take precedence over the configuration file.
+    let mut overrides = configfile::Settings::default();
+    overrides.terminate_timeout = matches.value_of("TERMINATE_TIMEOUT").map(|t| Duration::from_secs(t.parse().unwrap()));
+    overrides.default_jig = matches.value_of("JIG").map(|j| unit::UnitName::from_str(j, "jig").unwrap());
+    overrides.default_scenario = matches.value_of("SCENARIO").map(|s| unit::UnitName::from_str(s, "scenario").unwrap());
+    overrides.log_level = matches.value_of("LOG_LEVEL").map(|l| terminal::LogLevel::from_str(l).unwrap());
+    overrides.log_directory = matches.value_of("LOG_DIR").map(std::path::PathBuf::from);
+    overrides.profile = matches.value_of("PROFILE").map(|p| p.to_owned());
+    overrides.timezone = matches.value_of("TIMEZONE").map(|z| timestamp::TimeZone::from_str(z).unwrap());
+    if let Some(run_matches) = run_matches {
+        if let Some(jig) = run_matches.value_of("JIG") {
+            overrides.default_jig = Some(unit::UnitName::from_str(jig, "jig").unwrap());
+        }
+        let scenario = run_matches.value_of("SCENARIO").unwrap();
+        overrides.default_scenario = Some(unit::UnitName::from_str(scenario, "scenario").unwrap());
+        // The scenario is started once, by `run` itself.
+        overrides.auto_start = Some(false);
+    }
+    if replay_matches.is_some() {
+        overrides.auto_start = Some(false);
+    }
+
+    let config_file = matches.value_of("CONFIG_FILE").map(std::path::Path::new);
+    let settings = match config_file {
+        None => overrides.clone(),
+        Some(path) => match configfile::Settings::from_path(path, matches.value_of("PROFILE")) {
+            Ok(s) => overrides.or(&s),
+            Err(e) => {
+                eprintln!("unable to read {}: {}", path.display(), e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        },
+    };
+    let log_level = Arc::new(Mutex::new(terminal::LogLevel::Debug));
+    settings.apply(&mut config.lock().unwrap(), &log_level);
+
+    // A replay has no jig or hardware, so it's simulated as well.
+    if matches.is_present("SIMULATE") || replay_matches.is_some() {
+        config.lock().unwrap().set_simulate(true);
+        std::env::set_var("EXCLAVE_SIMULATE", "1");
+    }
+    if replay_matches.is_some() {
+        config.lock().unwrap().set_replaying(true);
+    }
+
+    if matches.subcommand_matches("list").is_some() {
+        if let Err(e) = statussocket::list(socket_path) {
+            eprintln!("unable to query {}: {}", socket_path.display(), e);
+            process::exit(exitcode::CONFIG_ERROR);
+        }
+        process::exit(exitcode::SUCCESS);
+    }
+
+    // Only `exclave list` and `exclave completions` can get by without a config directory.
+    if config_dirs.is_empty() {
+        eprintln!("at least one config directory must be specified with -c");
+        process::exit(exitcode::CONFIG_ERROR);
+    }
+
+    // Plugins' kinds have to be known before any unit files are looked at.
+    for path in matches.values_of("PLUGIN").map(|v| v.collect()).unwrap_or(vec![]) {
+        if let Err(e) = plugin::load(std::path::Path::new(path)) {
+            eprintln!("{}", e);
+            process::exit(exitcode::CONFIG_ERROR);
+        }
+    }
+
+    if matches.subcommand_matches("check").is_some() {
+        match check::check(&config_dirs, matches.is_present("NAMESPACE"), &config) {
+            Ok(result) => {
+                println!("{} error(s), {} warning(s)", result.errors, result.warnings);
+                process::exit(if result.errors > 0 { exitcode::INVALID_UNITS } else { exitcode::SUCCESS });
+            }
+            Err(e) => {
+                eprintln!("unable to check units: {}", e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        }
+    }
+
+    if let Some(plan_matches) = matches.subcommand_matches("plan") {
+        let default_scenario = config.lock().unwrap().default_scenario().clone();
+        let scenario = match plan_matches.value_of("SCENARIO") {
+            Some(s) => unit::UnitName::from_str(s, "scenario").unwrap(),
+            None => match default_scenario {
+                Some(s) => s,
+                None => {
+                    eprintln!("no scenario given, and there's no default scenario");
+                    process::exit(exitcode::CONFIG_ERROR);
+                }
+            },
+        };
+        match plan::plan(&config_dirs, matches.is_present("NAMESPACE"), &config, &scenario) {
+            Ok(true) => process::exit(exitcode::SUCCESS),
+            Ok(false) => process::exit(exitcode::NOT_RUN),
+            Err(e) => {
+                eprintln!("unable to load units: {}", e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        }
+    }
+
+    if let Some(spc_matches) = matches.subcommand_matches("spc") {
+        let log_directory = match *config.lock().unwrap().log_directory() {
+            Some(ref d) => d.clone(),
+            None => {
+                eprintln!("no log directory given with -l or LogDirectory, so there are no runs to work from");
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        };
+        let format = spc::SpcFormat::from_str(spc_matches.value_of("FORMAT").unwrap()).unwrap();
+        let since = spc_matches.value_of("SINCE").map(|s| spc::parse_since(s).unwrap());
+        let filter: Vec<(String, String)> = spc_matches
+            .values_of("TAG")
+            .map(|tags| tags.map(|t| triggers::split_variable(t).unwrap()).collect())
+            .unwrap_or_default();
+        match spc::spc(&config_dirs, matches.is_present("NAMESPACE"), &config, &log_directory, since, &filter, format) {
+            Ok(()) => process::exit(exitcode::SUCCESS),
+            Err(e) => {
+                eprintln!("unable to work out capability from {}: {}", log_directory.display(), e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        }
+    }
+
+    // Nothing may start a thread before this, as only the calling thread survives.
+    if matches.is_present("DAEMON") {
+        if let Err(e) = daemon::detach() {
+            eprintln!("unable to run as a daemon: {}", e);
+            process::exit(exitcode::CONFIG_ERROR);
+        }
+    }
+
+    let unit_broadcaster = UnitBroadcaster::new();
+    unit_broadcaster.set_instrumented(matches.is_present("INSTRUMENT"));
+    let timing = Timing {
+        debounce: debounce,
+        settle_delay: settle_delay,
+        rescan_delay: rescan_delay,
+        min_rescan_interval: min_rescan_interval,
+    };
+    let mut engine = Engine::new(&unit_broadcaster, &config, &timing, matches.is_present("NAMESPACE"));
+
+    if let Err(e) = daemon::handle_signals(&unit_broadcaster) {
+        eprintln!("unable to handle signals: {}", e);
+        process::exit(exitcode::CONFIG_ERROR);
+    }
+
+    // Removed again when main() returns, once the units have been stopped.
+    let pid_file = match matches.value_of("PID_FILE") {
+        None => None,
+        Some(path) => match daemon::PidFile::create(std::path::Path::new(path)) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("unable to write pid file {}: {}", path, e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        },
+    };
+
+    // Before any units are found, so that every program they start is covered.
+    if let Err(e) = supervisor::supervise() {
+        unit_broadcaster.log("main", format!("Unable to supervise programs, so they may outlive exclave: {}", e));
+    }
+
+    // Start listening before any units are found, so that none of their statuses are missed.
+    if let Err(e) = statussocket::start(socket_path, &unit_broadcaster) {
+        unit_broadcaster.log("main", format!("Unable to open status socket {}: {}", socket_path.display(), e));
+    }
+
+    let mut internal_metrics = matches.value_of("METRICS_LISTEN").map(|address| {
+        match internalmetrics::InternalMetrics::start(address) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("unable to serve metrics on {}: {}", address, e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        }
+    });
+
+    if let (Some(peer), Some(listen)) = (matches.value_of("STANDBY_PEER"), matches.value_of("STANDBY_LISTEN")) {
+        let preference = if matches.is_present("BACKUP") {
+            standby::Preference::Backup
+        } else {
+            standby::Preference::Primary
+        };
+        let timeout = Duration::from_millis(matches.value_of("STANDBY_TIMEOUT").unwrap().parse().unwrap());
+        if let Err(e) = standby::start(listen, peer, preference, timeout, engine.manager(), &unit_broadcaster) {
+            eprintln!("unable to listen for heartbeats on {}: {}", listen, e);
+            process::exit(exitcode::CONFIG_ERROR);
+        }
+    }
+
+    terminal::TerminalInterface::start(output_type, &log_level, &unit_broadcaster, matches.is_present("KEYBOARD_TRIGGER") && run_matches.is_none());
+
+    let config_file = config_file.map(|path| {
+        match configfile::ConfigFile::new(path, &overrides, &config, &log_level, &unit_broadcaster) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("unable to watch configuration file: {}", e);
+                process::exit(exitcode::CONFIG_ERROR);
+            }
+        }
+    });
+
+    let mut one_shot = run_matches.map(|m| {
+        let scenario = unit::UnitName::from_str(m.value_of("SCENARIO").unwrap(), "scenario").unwrap();
+        oneshot::OneShot::new(&scenario, &unit_broadcaster)
+    });
+    let mut replay = replay_matches.map(|m| {
+        let events = std::path::Path::new(m.value_of("EVENTS").unwrap());
+        let speed = replay::parse_speed(m.value_of("SPEED").unwrap()).unwrap();
+        let memory_limit = config.lock().unwrap().replay_memory();
+        replay::Replay::new(events, speed, m.is_present("EXIT"), memory_limit, &unit_broadcaster)
+    });
+
+    if let Err(e) = engine.add_unit_directories(&config_dirs) {
+        eprintln!("{}", e);
+        process::exit(exitcode::CONFIG_ERROR);
+    }
+    let mut profile_dirs = vec![];
+    switch_profile_dirs(&mut profile_dirs, &config, &mut engine);
+
+    let mut sd_notify = sdnotify::SdNotify::new(&unit_broadcaster);
+
+    unit_broadcaster.log("main", format!("Exclave initializing"));
+
+    let mut debug_file = match matches.value_of("DEBUG_LOGFILE") {
+        None => None,
+        Some(dv) => {
+            use std::fs::File;
+            use std::path::Path;
+            let path = Path::new(dv);
+            Some(File::create(&path).expect("Couldn't create logfile"))
+        }
+    };
+    // Main message loop.  Monitor messages and pass them to each component.
+    let mut loops = 1;
+    while let Some(msg) = engine.next() {
+        if let Some(file) = debug_file.as_mut() {
+            use std::io::Write;
+            use std::time;
+
+            let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+                Ok(d) => d,
+                Err(_) => time::Duration::new(0, 0),
+            };
+
+            let unix_time = now.as_secs();
+            let unix_time_nsecs = now.subsec_nanos();
+
+            writeln!(
+                file,
+                "{}:{}.{} {:?}",
+                loops,
+                unix_time,
+                unix_time_nsecs,
+                msg
+            ).expect("Couldn't write message to logfile");
+        }
+        loops = loops + 1;
+        if let Some(ref config_file) = config_file {
+            if config_file.process_message(&msg, engine.manager()) {
+                switch_profile_dirs(&mut profile_dirs, &config, &mut engine);
+            }
+        }
+        if let Some(ref mut one_shot) = one_shot {
+            one_shot.process_message(&msg);
+        }
+        if let Some(ref mut replay) = replay {
+            replay.process_message(&msg);
+        }
+        if let Some(ref mut internal_metrics) = internal_metrics {
+            internal_metrics.process_message(&msg, engine.manager());
+        }
+        // Last, so that a watchdog ping is only answered once everything else has handled it.
+        if let Some(ref mut sd_notify) = sd_notify {
+            // The manager has its own thread, and has to have caught up as well.
+            match msg {
+                UnitEvent::WatchdogPing | UnitEvent::RescanFinish => engine.manager().flush(),
+                _ => (),
+            }
+            sd_notify.process_message(&msg);
+        }
+    }
+
+    // The manager deals with messages on a thread of its own, and may still be stopping units.
+    engine.manager().flush();
+
+    // `run` was shut down before the scenario finished if there's no result.
+    if let Some(one_shot) = one_shot {
+        drop(pid_file);
+        process::exit(one_shot.exit_code().unwrap_or(exitcode::ABORTED));
+    }
+}
+
+/// Swap the unit directories of the previous station profile, if any, for those of the current one.
+/// They're added after the ones given with -c, so that their units take precedence.
+fn switch_profile_dirs(
+    current: &mut Vec<PathBuf>,
+    config: &Arc<Mutex<config::Config>>,
+    engine: &mut Engine,
+) {
+    let dirs = config.lock().unwrap().unit_directories().clone();
+    if dirs == *current {
+        return;
+    }
+    for dir in current.iter() {
+        engine.remove_unit_directory(dir);
+    }
+    // One at a time, so that one that can't be watched doesn't stop the rest from being added.
+    for dir in &dirs {
+        if let Err(e) = engine.add_unit_directories(&[dir.to_string_lossy()]) {
+            engine.broadcaster().log("main", e);
+        }
+    }
+    *current = dirs;
+}
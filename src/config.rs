@@ -1,16 +1,118 @@
-use std::cell::RefCell;
+use std::env;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use golden::Tolerance;
+use mes::MesTarget;
+use operators::OperatorList;
+use retention::Retention;
+use unit::{hostname, UnitName};
+
 const DEFAULT_TIMEOUT_SECS: u64 = 5;
 
+/// The names of the site-wide variables, which unit files may refer to.  Units are parsed
+/// without a Config to hand, so they're kept here as well.
+static SITE_VARIABLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Yield figures are worked out over the last day, unless other windows are configured.
+const DEFAULT_YIELD_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// How far a golden run's measurements may be from the baseline, unless the baseline says otherwise.
+pub const DEFAULT_GOLDEN_TOLERANCE: Tolerance = Tolerance::Percent(5.0);
+
+/// Warn when the volume that runs are logged to has less than this many bytes free.
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 100 * 1024 * 1024;
+
+/// How many bytes of a run's messages `exclave replay` keeps in memory before spilling the rest to disk.
+pub const DEFAULT_REPLAY_MEMORY: u64 = 16 * 1024 * 1024;
+
 pub struct Config {
     timeout: Duration,
-    jig_working_directory: Rc<RefCell<Option<PathBuf>>>,
-    scenario_working_directory: Rc<RefCell<Option<PathBuf>>>,
+    jig_working_directory: Arc<Mutex<Option<PathBuf>>>,
+    scenario_working_directory: Arc<Mutex<Option<PathBuf>>>,
     paths: Vec<PathBuf>,
     terminate_timeout: Duration,
+
+    /// If set, each scenario run gets its own log directory underneath this one.
+    log_directory: Option<PathBuf>,
+
+    /// How many bytes must be free on the log directory's volume before it counts as nearly full.
+    min_free_space: u64,
+
+    /// How many bytes of messages a replay may keep in memory while they wait to be replayed.
+    replay_memory: u64,
+
+    /// If set, a row is added to the CSV summaries in this directory as each scenario run finishes.
+    summary_directory: Option<PathBuf>,
+
+    /// How many old runs to keep in the log directory.
+    retention: Retention,
+
+    /// The windows of time, ending now, that yield figures are worked out over.
+    yield_windows: Vec<Duration>,
+
+    /// Where the result of each run is sent as it finishes, if anywhere.
+    mes: Option<MesTarget>,
+
+    /// Where results are kept until they've been delivered, so they survive a restart.
+    mes_queue_directory: Option<PathBuf>,
+
+    /// The central server that the result of each run is uploaded to, if any, where results
+    /// are kept until they've been uploaded, and the name of the station they come from.
+    upload_url: Option<String>,
+    upload_queue_directory: Option<PathBuf>,
+    upload_station: Option<String>,
+
+    /// Where the baseline that golden runs are compared with is kept for each scenario.
+    golden_directory: Option<PathBuf>,
+
+    /// The tolerance given to measurements when a baseline is recorded.
+    golden_tolerance: Tolerance,
+
+    /// Where the journal of the run in progress is kept, so that it can be recovered after a crash.
+    state_directory: Option<PathBuf>,
+
+    /// Where the operators who may log in are listed.  Anyone may if this isn't set.
+    operators: Option<OperatorList>,
+
+    /// What to run to print a label for a device that passes, and a reject tag for one that fails.
+    label_command: Option<String>,
+    reject_command: Option<String>,
+
+    /// The jig to use when several are compatible, overriding whichever would be picked otherwise.
+    default_jig: Option<UnitName>,
+
+    /// The scenario to select at startup, overriding the jig's DefaultScenario.
+    default_scenario: Option<UnitName>,
+
+    /// Whether to pretend to be on any jig, e.g. on the bench, rather than checking.
+    simulate: bool,
+
+    /// Whether a recorded run is being replayed, in which case no scenario may be started.
+    replaying: bool,
+
+    /// The station profile in use, if any, and every one that could be picked instead.
+    profile: Option<String>,
+    profiles: Vec<String>,
+
+    /// Unit directories added by the profile, which take precedence over the ones given with -c.
+    unit_directories: Vec<PathBuf>,
+
+    /// Variables that every run starts out with, unless it's given its own values for them.
+    variables: Vec<(String, String)>,
+
+    /// Site-wide variables that are set in the environment of exclave and everything it starts.
+    environment: Vec<(String, String)>,
+
+    /// Whether to start the default scenario at startup, without waiting to be asked.
+    auto_start: bool,
+
+    /// Whether a scenario that's running when exclave is told to shut down gets to finish first.
+    finish_on_shutdown: bool,
+
+    /// The most tests that may run at once, including daemons, or None for no limit.
+    max_tests: Option<usize>,
 }
 
 impl Config {
@@ -18,8 +120,8 @@ impl Config {
         Config {
             timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
             terminate_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
-            jig_working_directory: Rc::new(RefCell::new(None)),
-            scenario_working_directory: Rc::new(RefCell::new(None)),
+            jig_working_directory: Arc::new(Mutex::new(None)),
+            scenario_working_directory: Arc::new(Mutex::new(None)),
             paths: vec![
                 Path::new("/usr/local/sbin").to_owned(),
                 Path::new("/usr/local/bin").to_owned(),
@@ -27,6 +129,35 @@ impl Config {
                 Path::new("/usr/bin").to_owned(),
                 Path::new("/sbin:/bin").to_owned(),
             ],
+            log_directory: None,
+            default_jig: None,
+            default_scenario: None,
+            simulate: false,
+            replaying: false,
+            profile: None,
+            profiles: vec![],
+            unit_directories: vec![],
+            variables: vec![],
+            environment: vec![],
+            auto_start: false,
+            finish_on_shutdown: false,
+            max_tests: None,
+            min_free_space: DEFAULT_MIN_FREE_SPACE,
+            replay_memory: DEFAULT_REPLAY_MEMORY,
+            summary_directory: None,
+            retention: Retention::default(),
+            yield_windows: vec![Duration::from_secs(DEFAULT_YIELD_WINDOW_SECS)],
+            mes: None,
+            mes_queue_directory: None,
+            upload_url: None,
+            upload_queue_directory: None,
+            upload_station: None,
+            golden_directory: None,
+            golden_tolerance: DEFAULT_GOLDEN_TOLERANCE,
+            state_directory: None,
+            operators: None,
+            label_command: None,
+            reject_command: None,
         }
     }
 
@@ -34,10 +165,123 @@ impl Config {
         &self.timeout
     }
 
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
     pub fn terminate_timeout(&self) -> &Duration {
         &self.terminate_timeout
     }
 
+    pub fn set_terminate_timeout(&mut self, timeout: Duration) {
+        self.terminate_timeout = timeout;
+    }
+
+    pub fn default_jig(&self) -> &Option<UnitName> {
+        &self.default_jig
+    }
+
+    pub fn set_default_jig(&mut self, jig: Option<UnitName>) {
+        self.default_jig = jig;
+    }
+
+    pub fn default_scenario(&self) -> &Option<UnitName> {
+        &self.default_scenario
+    }
+
+    pub fn set_default_scenario(&mut self, scenario: Option<UnitName>) {
+        self.default_scenario = scenario;
+    }
+
+    pub fn simulate(&self) -> bool {
+        self.simulate
+    }
+
+    pub fn set_simulate(&mut self, simulate: bool) {
+        self.simulate = simulate;
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.replaying
+    }
+
+    pub fn set_replaying(&mut self, replaying: bool) {
+        self.replaying = replaying;
+    }
+
+    pub fn profile(&self) -> &Option<String> {
+        &self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile;
+    }
+
+    pub fn profiles(&self) -> &Vec<String> {
+        &self.profiles
+    }
+
+    pub fn set_profiles(&mut self, profiles: Vec<String>) {
+        self.profiles = profiles;
+    }
+
+    pub fn unit_directories(&self) -> &Vec<PathBuf> {
+        &self.unit_directories
+    }
+
+    pub fn set_unit_directories(&mut self, dirs: Vec<PathBuf>) {
+        self.unit_directories = dirs;
+    }
+
+    pub fn variables(&self) -> &Vec<(String, String)> {
+        &self.variables
+    }
+
+    pub fn set_variables(&mut self, variables: Vec<(String, String)>) {
+        self.variables = variables;
+    }
+
+    pub fn auto_start(&self) -> bool {
+        self.auto_start
+    }
+
+    pub fn set_auto_start(&mut self, auto_start: bool) {
+        self.auto_start = auto_start;
+    }
+
+    pub fn finish_on_shutdown(&self) -> bool {
+        self.finish_on_shutdown
+    }
+
+    pub fn set_finish_on_shutdown(&mut self, finish_on_shutdown: bool) {
+        self.finish_on_shutdown = finish_on_shutdown;
+    }
+
+    pub fn max_tests(&self) -> Option<usize> {
+        self.max_tests
+    }
+
+    pub fn set_max_tests(&mut self, max_tests: Option<usize>) {
+        self.max_tests = max_tests;
+    }
+
+    pub fn environment(&self) -> &Vec<(String, String)> {
+        &self.environment
+    }
+
+    /// Set the site-wide variables, exporting them so that programs started from here on
+    /// and unit files parsed from here on see them.  Ones that are no longer set are removed.
+    pub fn set_environment(&mut self, environment: Vec<(String, String)>) {
+        for &(ref key, _) in &self.environment {
+            env::remove_var(key);
+        }
+        for &(ref key, ref value) in &environment {
+            env::set_var(key, value);
+        }
+        *SITE_VARIABLES.lock().unwrap() = environment.iter().map(|&(ref key, _)| key.clone()).collect();
+        self.environment = environment;
+    }
+
     /// Return a working directory composed of the unit's directory,
     /// the jig working directory, and the scenario working directory.
     pub fn working_directory(&self, default: &Path, wd: &Option<PathBuf>) -> PathBuf {
@@ -48,10 +292,10 @@ impl Config {
         // println!("Scenario directory: {:?}", self.scenario_working_directory.borrow());
         // println!("<<<");
         let mut p = default.to_owned();
-        if let Some(jwd) = &*self.jig_working_directory.borrow() {
+        if let Some(jwd) = &*self.jig_working_directory.lock().unwrap() {
             p.push(jwd);
         }
-        if let Some(swd) = &*self.scenario_working_directory.borrow() {
+        if let Some(swd) = &*self.scenario_working_directory.lock().unwrap() {
             p.push(swd);
         }
         if let Some(wd) = wd {
@@ -67,19 +311,172 @@ impl Config {
         &self.paths
     }
 
+    pub fn log_directory(&self) -> &Option<PathBuf> {
+        &self.log_directory
+    }
+
+    pub fn set_log_directory(&mut self, new_path: &Path) {
+        self.log_directory = Some(new_path.to_owned());
+    }
+
+    pub fn clear_log_directory(&mut self) {
+        self.log_directory = None;
+    }
+
+    pub fn min_free_space(&self) -> u64 {
+        self.min_free_space
+    }
+
+    pub fn set_min_free_space(&mut self, bytes: u64) {
+        self.min_free_space = bytes;
+    }
+
+    pub fn replay_memory(&self) -> u64 {
+        self.replay_memory
+    }
+
+    pub fn set_replay_memory(&mut self, bytes: u64) {
+        self.replay_memory = bytes;
+    }
+
+    pub fn summary_directory(&self) -> &Option<PathBuf> {
+        &self.summary_directory
+    }
+
+    pub fn set_summary_directory(&mut self, new_path: &Path) {
+        self.summary_directory = Some(new_path.to_owned());
+    }
+
+    pub fn clear_summary_directory(&mut self) {
+        self.summary_directory = None;
+    }
+
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    pub fn yield_windows(&self) -> &Vec<Duration> {
+        &self.yield_windows
+    }
+
+    /// Go back to the default window if `windows` is empty.
+    pub fn set_yield_windows(&mut self, windows: Vec<Duration>) {
+        self.yield_windows = if windows.is_empty() {
+            vec![Duration::from_secs(DEFAULT_YIELD_WINDOW_SECS)]
+        } else {
+            windows
+        };
+    }
+
+    pub fn mes(&self) -> &Option<MesTarget> {
+        &self.mes
+    }
+
+    pub fn set_mes(&mut self, mes: Option<MesTarget>) {
+        self.mes = mes;
+    }
+
+    pub fn mes_queue_directory(&self) -> &Option<PathBuf> {
+        &self.mes_queue_directory
+    }
+
+    pub fn set_mes_queue_directory(&mut self, directory: Option<PathBuf>) {
+        self.mes_queue_directory = directory;
+    }
+
+    pub fn upload_url(&self) -> &Option<String> {
+        &self.upload_url
+    }
+
+    pub fn upload_queue_directory(&self) -> &Option<PathBuf> {
+        &self.upload_queue_directory
+    }
+
+    /// The name this station's results are uploaded under, which is its hostname unless one was given.
+    pub fn upload_station(&self) -> String {
+        self.upload_station.clone().unwrap_or_else(hostname)
+    }
+
+    pub fn set_upload(&mut self, url: Option<String>, queue_directory: Option<PathBuf>, station: Option<String>) {
+        self.upload_url = url;
+        self.upload_queue_directory = queue_directory;
+        self.upload_station = station;
+    }
+
+    pub fn golden_directory(&self) -> &Option<PathBuf> {
+        &self.golden_directory
+    }
+
+    pub fn set_golden_directory(&mut self, directory: Option<PathBuf>) {
+        self.golden_directory = directory;
+    }
+
+    pub fn golden_tolerance(&self) -> Tolerance {
+        self.golden_tolerance
+    }
+
+    pub fn set_golden_tolerance(&mut self, tolerance: Tolerance) {
+        self.golden_tolerance = tolerance;
+    }
+
+    pub fn state_directory(&self) -> &Option<PathBuf> {
+        &self.state_directory
+    }
+
+    pub fn set_state_directory(&mut self, directory: Option<PathBuf>) {
+        self.state_directory = directory;
+    }
+
+    pub fn operators(&self) -> &Option<OperatorList> {
+        &self.operators
+    }
+
+    pub fn set_operators(&mut self, operators: Option<OperatorList>) {
+        self.operators = operators;
+    }
+
+    /// What to run to print a label or reject tag, depending on whether the run passed.
+    pub fn label_command(&self, passed: bool) -> &Option<String> {
+        if passed {
+            &self.label_command
+        } else {
+            &self.reject_command
+        }
+    }
+
+    pub fn set_label_commands(&mut self, label: Option<String>, reject: Option<String>) {
+        self.label_command = label;
+        self.reject_command = reject;
+    }
+
+    /// How long runs need to be remembered for to work out yield figures.  Devices tested
+    /// within a window may have been tested before it began, so that's twice the longest window.
+    pub fn yield_history(&self) -> Duration {
+        self.yield_windows.iter().max().map(|w| *w * 2).unwrap_or(Duration::from_secs(0))
+    }
+
     pub fn set_jig_working_directory(&self, new_path: &Path) {
-        *self.jig_working_directory.borrow_mut() = Some(new_path.to_owned());
+        *self.jig_working_directory.lock().unwrap() = Some(new_path.to_owned());
     }
 
     pub fn clear_jig_working_directory(&self) {
-        *self.jig_working_directory.borrow_mut() = None;
+        *self.jig_working_directory.lock().unwrap() = None;
     }
 
     pub fn set_scenario_working_directory(&self, new_path: &Path) {
-        *self.scenario_working_directory.borrow_mut() = Some(new_path.to_owned());
+        *self.scenario_working_directory.lock().unwrap() = Some(new_path.to_owned());
     }
 
     pub fn clear_scenario_working_directory(&self) {
-        *self.scenario_working_directory.borrow_mut() = None;
+        *self.scenario_working_directory.lock().unwrap() = None;
     }
 }
+
+/// Whether `name` is one of the site-wide variables from [Variables] in the configuration file.
+pub fn is_site_variable(name: &str) -> bool {
+    SITE_VARIABLES.lock().unwrap().iter().any(|key| key == name)
+}
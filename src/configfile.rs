@@ -0,0 +1,549 @@
+// The global configuration file, which is read again whenever it changes
+// or exclave is sent SIGHUP, without restarting any units.
+extern crate humantime;
+extern crate systemd_parser;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use config::{Config, DEFAULT_GOLDEN_TOLERANCE, DEFAULT_MIN_FREE_SPACE, DEFAULT_REPLAY_MEMORY};
+use golden::Tolerance;
+use logsinks::elastic::split_url;
+use managerhandle::ManagerHandle;
+use mes::MesTarget;
+use operators::OperatorList;
+use retention::Retention;
+use terminal::LogLevel;
+use timestamp::{self, TimeZone};
+use triggers::split_variable;
+use unit::{expand_variables, UnitDescriptionError, UnitName, LANGUAGE_ENV};
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+use watch::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use self::humantime::parse_duration;
+use self::systemd_parser::items::{DirectiveEntry, UnitDirective};
+
+/// Sections such as [profile.eol] describe a station profile called "eol".
+const PROFILE_PREFIX: &str = "profile.";
+
+/// Everything that may be set in the configuration file, or on the command line.
+/// Anything that's None is left at its default.
+#[derive(Clone, Default)]
+pub struct Settings {
+    /// Timeout: How long programs such as a jig's TestProgram may run for.
+    pub timeout: Option<Duration>,
+
+    /// TerminateTimeout: How long programs get to exit after being asked to.
+    pub terminate_timeout: Option<Duration>,
+
+    /// DefaultJig: The jig to use when several are compatible.
+    pub default_jig: Option<UnitName>,
+
+    /// DefaultScenario: The scenario to select, rather than the jig's default.
+    pub default_scenario: Option<UnitName>,
+
+    /// LogLevel: Which log messages to print.
+    pub log_level: Option<LogLevel>,
+
+    /// LogDirectory: Where each run gets its own log directory.
+    pub log_directory: Option<PathBuf>,
+
+    /// MinFreeSpace: How much space must be left on the log directory's volume, in bytes.
+    pub min_free_space: Option<u64>,
+
+    /// ReplayMemory: How many bytes of messages `exclave replay` may keep in memory.
+    pub replay_memory: Option<u64>,
+
+    /// SummaryDirectory: Where CSV summaries of every run, and of each day, are kept.
+    pub summary_directory: Option<PathBuf>,
+
+    /// MaxRuns: How many runs to keep in the log directory.
+    pub max_runs: Option<usize>,
+
+    /// MaxRunAge: How long to keep runs in the log directory for.
+    pub max_run_age: Option<Duration>,
+
+    /// MaxLogSize: How many bytes the runs in the log directory may take up.
+    pub max_log_size: Option<u64>,
+
+    /// YieldWindows: The windows of time that yield figures are worked out over, e.g. "8h 24h 7d".
+    pub yield_windows: Option<Vec<Duration>>,
+
+    /// MesUrl or MesCommand: Where the result of each run is sent as it finishes.
+    pub mes: Option<MesTarget>,
+
+    /// MesQueueDirectory: Where results are kept until they've been delivered.
+    pub mes_queue_directory: Option<PathBuf>,
+
+    /// UploadUrl: The central server that the result of each run is uploaded to.
+    pub upload_url: Option<String>,
+
+    /// UploadQueueDirectory: Where results are kept until they've been uploaded.
+    pub upload_queue_directory: Option<PathBuf>,
+
+    /// UploadStation: The name this station's results are uploaded under.  Defaults to the hostname.
+    pub upload_station: Option<String>,
+
+    /// GoldenDirectory: Where the baselines that golden runs are compared with are kept.
+    pub golden_directory: Option<PathBuf>,
+
+    /// GoldenTolerance: The tolerance given to measurements when a baseline is recorded, e.g. "5%" or "0.1".
+    pub golden_tolerance: Option<Tolerance>,
+
+    /// StateDirectory: Where the journal of the run in progress is kept, to recover it after a crash.
+    pub state_directory: Option<PathBuf>,
+
+    /// OperatorFile or OperatorUrl: Where the operators who may log in are listed.
+    pub operators: Option<OperatorList>,
+
+    /// LabelCommand: What to run to print a label once a run passes, e.g. "print-label {serial} {date}".
+    pub label_command: Option<String>,
+
+    /// RejectCommand: What to run to print a reject tag once a run fails.
+    pub reject_command: Option<String>,
+
+    /// TimeZone: The timezone that log timestamps are written in.
+    pub timezone: Option<TimeZone>,
+
+    /// Language: The language that units are described in, where they've been translated.
+    pub language: Option<String>,
+
+    /// AutoStart: Whether to start the default scenario as soon as the units have been loaded.
+    pub auto_start: Option<bool>,
+
+    /// OnShutdown: Whether a running scenario is aborted or finished when exclave shuts down.
+    pub finish_on_shutdown: Option<bool>,
+
+    /// MaxTests: The most tests that may run at once, or 0 for no limit.
+    pub max_tests: Option<usize>,
+
+    /// Profile: The station profile whose settings take precedence over the [Exclave] section.
+    pub profile: Option<String>,
+
+    /// Every profile in the file, any of which may be switched to.
+    pub profiles: Vec<String>,
+
+    /// UnitDirectories: More unit directories, which take precedence over the ones given with -c.
+    pub unit_directories: Option<Vec<PathBuf>>,
+
+    /// Variables: key=value pairs that every run starts out with.
+    pub variables: Option<Vec<(String, String)>>,
+
+    /// The [Variables] section: site-wide NAME=value pairs, such as the line or the
+    /// address of the MES, set in the environment and expanded in unit files.
+    pub environment: Option<Vec<(String, String)>>,
+}
+
+/// Split a configuration file into its sections, along with their names.  Each is
+/// parsed on its own, as the parser won't allow a key to be used in more than one.
+fn sections(contents: &str) -> Vec<(String, String)> {
+    let mut sections: Vec<(String, String)> = vec![];
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.len() > 2 && trimmed.starts_with('[') && trimmed.ends_with(']') {
+            sections.push((trimmed[1..trimmed.len() - 1].trim().to_owned(), String::new()));
+        } else if let Some(section) = sections.last_mut() {
+            section.1.push_str(line);
+            section.1.push('\n');
+        }
+    }
+    sections
+}
+
+/// Parse a size such as "500M" or "2G" into a number of bytes.
+fn size(value: &str) -> Option<u64> {
+    let value = value.trim_end_matches(|c| c == 'B' || c == 'b');
+    let (number, scale) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        Some('T') => (&value[..value.len() - 1], 1 << 40),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok().and_then(|n| n.checked_mul(scale))
+}
+
+/// Read the NAME=value lines of the [Variables] section.  It isn't handed to the parser,
+/// as names such as MES_URL are allowed here but not as keys.
+fn environment(body: &str) -> Result<Vec<(String, String)>, UnitDescriptionError> {
+    let mut environment = vec![];
+    for line in body.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let (name, value) = match (parts.next().map(|n| n.trim()), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                (name, value.trim())
+            }
+            _ => {
+                return Err(UnitDescriptionError::InvalidValue(
+                    "Variables".to_owned(),
+                    line.splitn(2, '=').next().unwrap_or("").trim().to_owned(),
+                    line.to_owned(),
+                    vec!["NAME=value".to_owned()],
+                ))
+            }
+        };
+        environment.push((name.to_owned(), value.to_owned()));
+    }
+    Ok(environment)
+}
+
+impl Settings {
+    /// Read the [Exclave] section of a configuration file, with the section for `profile` on top
+    /// of it.  If `profile` is None, the one named by Profile= in [Exclave] is used, if any.
+    pub fn from_path(path: &Path, profile: Option<&str>) -> Result<Settings, UnitDescriptionError> {
+        let contents = fs::read_to_string(path)?;
+        let sections = sections(&expand_variables(&contents));
+        let section = |name: &str| -> Result<Settings, UnitDescriptionError> {
+            match sections.iter().find(|s| s.0 == name) {
+                Some(&(_, ref body)) => Settings::from_section(name, body),
+                None => Ok(Settings::default()),
+            }
+        };
+
+        let mut settings = section("Exclave")?;
+        if let Some(&(_, ref body)) = sections.iter().find(|s| s.0 == "Variables") {
+            settings.environment = Some(environment(body)?);
+        }
+        settings.profiles = sections
+            .iter()
+            .filter(|s| s.0.starts_with(PROFILE_PREFIX))
+            .map(|s| s.0[PROFILE_PREFIX.len()..].to_owned())
+            .collect();
+        let profile = match profile.map(|p| p.to_owned()).or(settings.profile.clone()) {
+            Some(p) => p,
+            None => return Ok(settings),
+        };
+        if !settings.profiles.contains(&profile) {
+            return Err(UnitDescriptionError::InvalidValue(
+                "Exclave".to_owned(),
+                "Profile".to_owned(),
+                profile,
+                settings.profiles.clone(),
+            ));
+        }
+
+        let mut settings = section(&format!("{}{}", PROFILE_PREFIX, profile))?.or(&settings);
+        settings.profile = Some(profile);
+        Ok(settings)
+    }
+
+    /// Read the settings in one section of the file, given without its [header].
+    fn from_section(name: &str, body: &str) -> Result<Settings, UnitDescriptionError> {
+        // Profile names needn't be valid section names, so give every section the same one.
+        let file = systemd_parser::parse_string(&format!("[Exclave]\n{}", body))?;
+        let invalid = |key: &str, value: &str, allowed: &str| {
+            UnitDescriptionError::InvalidValue(name.to_owned(), key.to_owned(), value.to_owned(), vec![allowed.to_owned()])
+        };
+        let duration = |key: &str, value: &str| match value.parse::<u64>() {
+            Ok(secs) => Ok(Duration::from_secs(secs)),
+            Err(_) => parse_duration(value).map_err(|_| invalid(key, value, "a number of seconds, or a duration such as \"500ms\"")),
+        };
+
+        let mut settings = Settings::default();
+        let mut directives: Vec<&UnitDirective> = vec![];
+        for entry in file.lookup_by_category("Exclave") {
+            match entry {
+                &DirectiveEntry::Solo(ref directive) => directives.push(directive),
+                &DirectiveEntry::Many(ref many) => directives.extend(many.iter()),
+            }
+        }
+        for directive in directives {
+            let value = match directive.value() {
+                Some(v) => v.trim(),
+                None => continue,
+            };
+            match directive.key() {
+                "Timeout" => settings.timeout = Some(duration("Timeout", value)?),
+                "TerminateTimeout" => settings.terminate_timeout = Some(duration("TerminateTimeout", value)?),
+                "DefaultJig" => settings.default_jig = Some(UnitName::from_str(value, "jig")?),
+                "DefaultScenario" => settings.default_scenario = Some(UnitName::from_str(value, "scenario")?),
+                "LogLevel" => {
+                    settings.log_level = Some(LogLevel::from_str(value).map_err(|_| invalid("LogLevel", value, "error, info, or debug"))?)
+                }
+                "LogDirectory" => settings.log_directory = Some(PathBuf::from(value)),
+                "MinFreeSpace" => {
+                    settings.min_free_space = Some(size(value).ok_or_else(|| invalid("MinFreeSpace", value, "a number of bytes, or a size such as \"500M\" or \"2G\""))?)
+                }
+                "ReplayMemory" => {
+                    settings.replay_memory = Some(size(value).ok_or_else(|| invalid("ReplayMemory", value, "a number of bytes, or a size such as \"16M\""))?)
+                }
+                "SummaryDirectory" => settings.summary_directory = Some(PathBuf::from(value)),
+                "MaxRuns" => settings.max_runs = Some(value.parse().map_err(|_| invalid("MaxRuns", value, "a number of runs"))?),
+                "MaxRunAge" => settings.max_run_age = Some(duration("MaxRunAge", value)?),
+                "MesUrl" => {
+                    split_url(value, "http", 80).map_err(|_| invalid("MesUrl", value, "an http:// URL"))?;
+                    settings.mes = Some(MesTarget::Url(value.to_owned()));
+                }
+                "MesCommand" => settings.mes = Some(MesTarget::Command(value.to_owned())),
+                "MesQueueDirectory" => settings.mes_queue_directory = Some(PathBuf::from(value)),
+                "UploadUrl" => {
+                    split_url(value, "http", 80).map_err(|_| invalid("UploadUrl", value, "an http:// URL"))?;
+                    settings.upload_url = Some(value.to_owned());
+                }
+                "UploadQueueDirectory" => settings.upload_queue_directory = Some(PathBuf::from(value)),
+                "UploadStation" => settings.upload_station = Some(value.to_owned()),
+                "StateDirectory" => settings.state_directory = Some(PathBuf::from(value)),
+                "OperatorFile" => settings.operators = Some(OperatorList::File(PathBuf::from(value))),
+                "OperatorUrl" => {
+                    split_url(value, "http", 80).map_err(|_| invalid("OperatorUrl", value, "an http:// URL"))?;
+                    settings.operators = Some(OperatorList::Url(value.to_owned()));
+                }
+                "LabelCommand" => settings.label_command = Some(value.to_owned()),
+                "RejectCommand" => settings.reject_command = Some(value.to_owned()),
+                "GoldenDirectory" => settings.golden_directory = Some(PathBuf::from(value)),
+                "GoldenTolerance" => {
+                    settings.golden_tolerance = Some(value.parse().map_err(|_| invalid("GoldenTolerance", value, "a number, or a percentage such as \"5%\""))?)
+                }
+                "YieldWindows" => {
+                    let windows: Result<Vec<Duration>, UnitDescriptionError> = value.split_whitespace().map(|w| duration("YieldWindows", w)).collect();
+                    settings.yield_windows = Some(windows?);
+                }
+                "MaxLogSize" => {
+                    settings.max_log_size = Some(size(value).ok_or_else(|| invalid("MaxLogSize", value, "a number of bytes, or a size such as \"500M\" or \"2G\""))?)
+                }
+                "TimeZone" => settings.timezone = Some(TimeZone::from_str(value).map_err(|_| invalid("TimeZone", value, "UTC, local, or an offset such as +05:30"))?),
+                "Language" => settings.language = Some(value.to_owned()),
+                "AutoStart" => {
+                    settings.auto_start = Some(match value.to_lowercase().as_ref() {
+                        "yes" | "true" => true,
+                        "no" | "false" => false,
+                        _ => return Err(invalid("AutoStart", value, "yes or no")),
+                    })
+                }
+                "OnShutdown" => {
+                    settings.finish_on_shutdown = Some(match value.to_lowercase().as_ref() {
+                        "abort" => false,
+                        "finish" => true,
+                        _ => return Err(invalid("OnShutdown", value, "abort or finish")),
+                    })
+                }
+                "MaxTests" => settings.max_tests = Some(value.parse().map_err(|_| invalid("MaxTests", value, "a number of tests"))?),
+                "Profile" => settings.profile = Some(value.to_owned()),
+                // Both may be given more than once, to add to the list.
+                "UnitDirectories" => settings
+                    .unit_directories
+                    .get_or_insert(vec![])
+                    .extend(value.split(|c: char| c == ',' || c.is_whitespace()).filter(|d| !d.is_empty()).map(PathBuf::from)),
+                "Variables" => {
+                    let variables = settings.variables.get_or_insert(vec![]);
+                    for word in value.split_whitespace() {
+                        variables.push(split_variable(word).ok_or_else(|| invalid("Variables", word, "key=value"))?);
+                    }
+                }
+                &_ => (),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// These settings, falling back to `other` for anything that isn't set.
+    pub fn or(&self, other: &Settings) -> Settings {
+        Settings {
+            timeout: self.timeout.or(other.timeout),
+            terminate_timeout: self.terminate_timeout.or(other.terminate_timeout),
+            default_jig: self.default_jig.clone().or(other.default_jig.clone()),
+            default_scenario: self.default_scenario.clone().or(other.default_scenario.clone()),
+            log_level: self.log_level.or(other.log_level),
+            log_directory: self.log_directory.clone().or(other.log_directory.clone()),
+            min_free_space: self.min_free_space.or(other.min_free_space),
+            replay_memory: self.replay_memory.or(other.replay_memory),
+            summary_directory: self.summary_directory.clone().or(other.summary_directory.clone()),
+            max_runs: self.max_runs.or(other.max_runs),
+            max_run_age: self.max_run_age.or(other.max_run_age),
+            max_log_size: self.max_log_size.or(other.max_log_size),
+            yield_windows: self.yield_windows.clone().or(other.yield_windows.clone()),
+            mes: self.mes.clone().or(other.mes.clone()),
+            mes_queue_directory: self.mes_queue_directory.clone().or(other.mes_queue_directory.clone()),
+            upload_url: self.upload_url.clone().or(other.upload_url.clone()),
+            upload_queue_directory: self.upload_queue_directory.clone().or(other.upload_queue_directory.clone()),
+            upload_station: self.upload_station.clone().or(other.upload_station.clone()),
+            golden_directory: self.golden_directory.clone().or(other.golden_directory.clone()),
+            golden_tolerance: self.golden_tolerance.or(other.golden_tolerance),
+            state_directory: self.state_directory.clone().or(other.state_directory.clone()),
+            operators: self.operators.clone().or(other.operators.clone()),
+            label_command: self.label_command.clone().or(other.label_command.clone()),
+            reject_command: self.reject_command.clone().or(other.reject_command.clone()),
+            timezone: self.timezone.or(other.timezone),
+            language: self.language.clone().or(other.language.clone()),
+            auto_start: self.auto_start.or(other.auto_start),
+            finish_on_shutdown: self.finish_on_shutdown.or(other.finish_on_shutdown),
+            max_tests: self.max_tests.or(other.max_tests),
+            profile: self.profile.clone().or(other.profile.clone()),
+            profiles: if self.profiles.is_empty() { other.profiles.clone() } else { self.profiles.clone() },
+            unit_directories: self.unit_directories.clone().or(other.unit_directories.clone()),
+            variables: self.variables.clone().or(other.variables.clone()),
+            environment: self.environment.clone().or(other.environment.clone()),
+        }
+    }
+
+    /// Put the settings in place, going back to the defaults for the ones that aren't set.
+    pub fn apply(&self, config: &mut Config, log_level: &Mutex<LogLevel>) {
+        let defaults = Config::new();
+        config.set_timeout(self.timeout.unwrap_or(*defaults.timeout()));
+        config.set_terminate_timeout(self.terminate_timeout.unwrap_or(*defaults.terminate_timeout()));
+        config.set_default_jig(self.default_jig.clone());
+        config.set_default_scenario(self.default_scenario.clone());
+        match self.log_directory {
+            Some(ref dir) => config.set_log_directory(dir),
+            None => config.clear_log_directory(),
+        }
+        config.set_min_free_space(self.min_free_space.unwrap_or(DEFAULT_MIN_FREE_SPACE));
+        config.set_replay_memory(self.replay_memory.unwrap_or(DEFAULT_REPLAY_MEMORY));
+        match self.summary_directory {
+            Some(ref dir) => config.set_summary_directory(dir),
+            None => config.clear_summary_directory(),
+        }
+        config.set_yield_windows(self.yield_windows.clone().unwrap_or_default());
+        config.set_mes(self.mes.clone());
+        config.set_mes_queue_directory(self.mes_queue_directory.clone());
+        config.set_upload(self.upload_url.clone(), self.upload_queue_directory.clone(), self.upload_station.clone());
+        config.set_golden_directory(self.golden_directory.clone());
+        config.set_golden_tolerance(self.golden_tolerance.unwrap_or(DEFAULT_GOLDEN_TOLERANCE));
+        config.set_state_directory(self.state_directory.clone());
+        config.set_operators(self.operators.clone());
+        config.set_label_commands(self.label_command.clone(), self.reject_command.clone());
+        // Zero means no limit, the same as leaving it out.
+        config.set_retention(Retention {
+            max_runs: self.max_runs.filter(|&max| max > 0),
+            max_age: self.max_run_age.filter(|max| *max > Duration::from_secs(0)),
+            max_size: self.max_log_size.filter(|&max| max > 0),
+        });
+        config.set_profile(self.profile.clone());
+        config.set_profiles(self.profiles.clone());
+        config.set_unit_directories(self.unit_directories.clone().unwrap_or_default());
+        config.set_variables(self.variables.clone().unwrap_or_default());
+        config.set_environment(self.environment.clone().unwrap_or_default());
+        config.set_auto_start(self.auto_start.unwrap_or(false));
+        config.set_finish_on_shutdown(self.finish_on_shutdown.unwrap_or(false));
+        config.set_max_tests(self.max_tests.filter(|&max| max > 0));
+        timestamp::set_timezone(self.timezone.unwrap_or(TimeZone::Utc));
+        match self.language {
+            Some(ref language) => env::set_var(LANGUAGE_ENV, language),
+            None => env::remove_var(LANGUAGE_ENV),
+        }
+        *log_level.lock().unwrap() = self.log_level.unwrap_or(LogLevel::Debug);
+    }
+}
+
+pub struct ConfigFile {
+    path: PathBuf,
+
+    /// Settings given on the command line, which take precedence over the file.
+    /// The profile isn't one of them, as it may be switched to another one later.
+    overrides: Settings,
+
+    config: Arc<Mutex<Config>>,
+    log_level: Arc<Mutex<LogLevel>>,
+    broadcaster: UnitBroadcaster,
+
+    /// The profile that the settings in place came from, to go back to if switching fails.
+    profile: RefCell<Option<String>>,
+
+    /// Kept around for as long as the file should be watched.
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigFile {
+    /// Watch the configuration file at `path`, which should already be in place, for changes.
+    pub fn new(
+        path: &Path,
+        overrides: &Settings,
+        config: &Arc<Mutex<Config>>,
+        log_level: &Arc<Mutex<LogLevel>>,
+        broadcaster: &UnitBroadcaster,
+    ) -> Result<ConfigFile, String> {
+        // Editors often replace the file rather than writing to it, so watch its directory.
+        let dir = path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let path = dir.join(path.file_name().ok_or(format!("{} is not a file", path.display()))?);
+
+        let (tx, rx) = channel();
+        let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_millis(100)).map_err(|e| format!("{}", e))?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("unable to watch {}: {}", dir.display(), e))?;
+
+        let thread_path = path.clone();
+        let thread_broadcaster = broadcaster.clone();
+        thread::spawn(move || {
+            while let Ok(event) = rx.recv() {
+                let changed = match event {
+                    DebouncedEvent::Create(ref p) | DebouncedEvent::Write(ref p) => *p == thread_path,
+                    DebouncedEvent::Rename(_, ref p) => *p == thread_path,
+                    _ => false,
+                };
+                if changed {
+                    thread_broadcaster.broadcast(&UnitEvent::ConfigReloadRequest);
+                }
+            }
+        });
+
+        Ok(ConfigFile {
+            path: path,
+            overrides: Settings {
+                profile: None,
+                ..overrides.clone()
+            },
+            config: config.clone(),
+            log_level: log_level.clone(),
+            broadcaster: broadcaster.clone(),
+            profile: RefCell::new(config.lock().unwrap().profile().clone()),
+            _watcher: watcher,
+        })
+    }
+
+    /// Returns true if the settings were reloaded, in which case the profile's
+    /// unit directories may have changed.
+    pub fn process_message(&self, msg: &UnitEvent, manager: &ManagerHandle) -> bool {
+        match msg {
+            &UnitEvent::ConfigReloadRequest => self.reload(manager, false),
+            // Unit files are being read again too, so they'll see any new variables.
+            &UnitEvent::ReloadRequest => self.reload(manager, true),
+            _ => false,
+        }
+    }
+
+    /// Read the file again and put the new settings in place, staying with the current
+    /// profile.  If it can't be read, e.g. because it was saved halfway through an edit,
+    /// the settings that were in place stay that way.  Unit files are read again if the
+    /// site-wide variables or the language changed, unless `units_reloading`, as what's
+    /// in them depends on both.
+    fn reload(&self, manager: &ManagerHandle, units_reloading: bool) -> bool {
+        let id = UnitName::internal("config");
+        let profile = self.config.lock().unwrap().profile().clone();
+        let settings = match Settings::from_path(&self.path, profile.as_ref().map(|p| p.as_str())) {
+            Ok(s) => s,
+            Err(e) => {
+                let message = format!("unable to reload {}, keeping the previous settings: {}", self.path.display(), e);
+                self.broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(id, message)));
+                self.config.lock().unwrap().set_profile(self.profile.borrow().clone());
+                return false;
+            }
+        };
+        let environment = self.config.lock().unwrap().environment().clone();
+        let language = env::var(LANGUAGE_ENV).ok();
+        self.overrides.or(&settings).apply(&mut self.config.lock().unwrap(), &self.log_level);
+        *self.profile.borrow_mut() = settings.profile.clone();
+        manager.send(|manager| manager.config_changed());
+        self.broadcaster.log("config", format!("Reloaded {}", self.path.display()));
+        let changed = *self.config.lock().unwrap().environment() != environment || env::var(LANGUAGE_ENV).ok() != language;
+        if !units_reloading && changed {
+            self.broadcaster.log("config", "Variables or language changed, reloading unit files".to_owned());
+            self.broadcaster.broadcast(&UnitEvent::ReloadRequest);
+        }
+        true
+    }
+}
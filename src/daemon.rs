@@ -0,0 +1,183 @@
+// Running as a daemon: detaching from the terminal, keeping a PID file,
+// and turning signals into reloads and graceful shutdowns.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use unit::UnitName;
+use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+/// A file holding our process id, which is removed when exclave exits.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile { path: path.to_owned() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Ask the manager to stop every unit and shut down, as if an interface had sent EXIT.
+fn request_shutdown(broadcaster: &UnitBroadcaster, reason: &str) {
+    let id = UnitName::internal("signal");
+    let contents = ManagerControlMessageContents::Shutdown(Some(reason.to_owned()));
+    broadcaster.broadcast(&UnitEvent::ManagerRequest(ManagerControlMessage::new(&id, contents)));
+}
+
+/// Move into the background, detached from the terminal.  This must be done
+/// before any threads are started, as only the calling thread carries on.
+#[cfg(unix)]
+pub fn detach() -> io::Result<()> {
+    unix::detach()
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "daemon mode is only supported on Unix"))
+}
+
+/// SIGTERM and SIGINT stop every unit before shutting down, and a second one exits
+/// straight away.  SIGHUP reads every unit file again.
+#[cfg(unix)]
+pub fn handle_signals(broadcaster: &UnitBroadcaster) -> io::Result<()> {
+    unix::handle_signals(broadcaster)
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals(broadcaster: &UnitBroadcaster) -> io::Result<()> {
+    extern crate ctrlc;
+
+    let broadcaster = broadcaster.clone();
+    ctrlc::set_handler(move || request_shutdown(&broadcaster, "received Ctrl-C"))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+}
+
+#[cfg(unix)]
+mod unix {
+    extern crate libc;
+
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    use std::process;
+    use std::ptr;
+    use std::thread;
+
+    use exitcode;
+    use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+
+    /// The end of the pipe that the signal handler writes to.
+    static mut SIGNAL_PIPE: libc::c_int = -1;
+
+    /// Where the calling thread's errno is kept.
+    #[cfg(any(target_os = "linux", target_os = "emscripten"))]
+    unsafe fn errno() -> *mut libc::c_int {
+        libc::__errno_location()
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+    unsafe fn errno() -> *mut libc::c_int {
+        libc::__error()
+    }
+
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    unsafe fn errno() -> *mut libc::c_int {
+        libc::__errno()
+    }
+
+    /// Only async-signal-safe calls may be made here, so just pass the
+    /// signal on to the thread that deals with it.  The write may set errno,
+    /// which mustn't change under whatever the signal interrupted.
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let byte = signal as u8;
+        unsafe {
+            let saved = *errno();
+            libc::write(SIGNAL_PIPE, &byte as *const u8 as *const libc::c_void, 1);
+            *errno() = saved;
+        }
+    }
+
+    pub fn detach() -> io::Result<()> {
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            _ => process::exit(0),
+        }
+        if unsafe { libc::setsid() } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Nothing is left to read from or write to.
+        let null = unsafe { libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR) };
+        if null == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in 0..3 {
+            unsafe { libc::dup2(null, fd) };
+        }
+        if null > 2 {
+            unsafe { libc::close(null) };
+        }
+        Ok(())
+    }
+
+    pub fn handle_signals(broadcaster: &UnitBroadcaster) -> io::Result<()> {
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Programs that are started mustn't hold on to the pipe.
+        for &fd in fds.iter() {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        unsafe { SIGNAL_PIPE = fds[1] };
+
+        // Handlers, unlike blocked signals, are reset in programs that are started.
+        for &signal in [libc::SIGHUP, libc::SIGINT, libc::SIGTERM].iter() {
+            let mut action: libc::sigaction = unsafe { mem::zeroed() };
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+        let broadcaster = broadcaster.clone();
+        thread::spawn(move || {
+            let mut stopping = false;
+            let mut buf = [0u8; 1];
+            while pipe.read_exact(&mut buf).is_ok() {
+                match buf[0] as libc::c_int {
+                    libc::SIGHUP => {
+                        broadcaster.log("signal", "received SIGHUP, reloading unit files".to_owned());
+                        broadcaster.broadcast(&UnitEvent::ReloadRequest);
+                    }
+                    // Units that won't stop shouldn't keep exclave from exiting.
+                    _ if stopping => {
+                        eprintln!("exiting without waiting for units to stop");
+                        process::exit(exitcode::ABORTED);
+                    }
+                    signal => {
+                        stopping = true;
+                        let name = if signal == libc::SIGINT { "SIGINT" } else { "SIGTERM" };
+                        super::request_shutdown(&broadcaster, &format!("received {}", name));
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+// The test engine, for Rust programs that run tests themselves rather than
+// starting exclave and talking CFTI to it.  An Engine is what the exclave binary
+// runs: the unit library and manager, along with the loader and watcher that
+// find units in unit directories.  Units can also be added straight from the
+// text of their unit files, and virtual interfaces, which run no program, let
+// the embedding program send commands and see what an interface would be sent.
+// Everything that happens is broadcast as a UnitEvent, and `subscribe()` gets a
+// channel of them.
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use config::Config;
+use managerhandle::ManagerHandle;
+use quiesce::Quiesce;
+use unit::{UnitKind, UnitName};
+use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+use unitlibrary::UnitLibrary;
+use unitloader::UnitLoader;
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+use units::custom::CustomDescription;
+use units::interface::{Capture, Interface, InterfaceDescription};
+use units::jig::JigDescription;
+use units::limits::LimitsDescription;
+use units::resource::ResourceDescription;
+use units::scenario::ScenarioDescription;
+use units::test::TestDescription;
+use unitwatcher::UnitWatcher;
+
+/// Where units that are added from strings appear to have been loaded from, which
+/// makes the current directory the one that the programs they run are started in.
+const VIRTUAL_UNIT_PATH: &str = "./virtual";
+
+/// How soon changes to unit files are acted on.  The defaults are the same as exclave's own.
+#[derive(Clone, Debug)]
+pub struct Timing {
+    /// Changes to the same file that happen within this long are merged.
+    pub debounce: Duration,
+
+    /// Unit directories must have stopped changing for this long before it's worked out which units changed.
+    pub settle_delay: Duration,
+
+    /// Unit files must have stopped changing for this long before there's a rescan.
+    pub rescan_delay: Duration,
+
+    /// Rescans because unit files changed are at least this far apart.
+    pub min_rescan_interval: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            debounce: Duration::from_millis(100),
+            settle_delay: Duration::from_millis(200),
+            rescan_delay: Duration::from_millis(1000),
+            min_rescan_interval: Duration::from_millis(0),
+        }
+    }
+}
+
+pub struct Engine {
+    broadcaster: UnitBroadcaster,
+    receiver: Receiver<UnitEvent>,
+    control: Sender<ManagerControlMessage>,
+    library: UnitLibrary,
+    loader: UnitLoader,
+    watcher: UnitWatcher,
+    quiesce: Quiesce,
+
+    /// Units added from strings that are started at the next rescan.  They aren't in any
+    /// unit directory, so the library doesn't know to start them itself.
+    added: RefCell<Vec<UnitName>>,
+}
+
+impl Engine {
+    /// An engine that broadcasts its events on `broadcaster`.  It has no units until some are
+    /// added, either from unit directories or one at a time, and does nothing until it's run.
+    /// With `namespaced`, units in subdirectories are named after the directory they're in.
+    pub fn new(broadcaster: &UnitBroadcaster, config: &Arc<Mutex<Config>>, timing: &Timing, namespaced: bool) -> Engine {
+        // Subscribe first, so that nothing the library does is missed.
+        let receiver = broadcaster.subscribe();
+        let library = UnitLibrary::new(broadcaster, config);
+        let control = library.get_manager().get_control_channel();
+        Engine {
+            broadcaster: broadcaster.clone(),
+            receiver: receiver,
+            control: control,
+            library: library,
+            loader: UnitLoader::new(broadcaster),
+            watcher: UnitWatcher::new(broadcaster, timing.debounce, timing.settle_delay, namespaced),
+            quiesce: Quiesce::new(timing.rescan_delay, timing.min_rescan_interval, broadcaster),
+            added: RefCell::new(vec![]),
+        }
+    }
+
+    /// Load units from `dirs`, and watch them for changes.  If the same unit is in more than one,
+    /// the one in the last directory wins, including directories that were added before.
+    pub fn add_unit_directories<S: AsRef<str>>(&mut self, dirs: &[S]) -> Result<(), String> {
+        // The loader has to know about all of them before any units are found.
+        for dir in dirs {
+            self.loader.add_path(dir.as_ref());
+        }
+        for dir in dirs {
+            self.watcher
+                .add_path(dir.as_ref())
+                .map_err(|e| format!("unable to add unit directory {}: {}", dir.as_ref(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Stop watching a unit directory.  Its units are unloaded.
+    pub fn remove_unit_directory(&mut self, dir: &Path) {
+        self.watcher.remove_path(dir);
+        self.loader.remove_path(dir);
+    }
+
+    /// Load a unit from the text of its unit file, rather than from a unit directory.  Tests, jigs,
+    /// scenarios, limits, resources, and units of kinds that plugins add can be added this way.  Interfaces are added with
+    /// `add_interface()` instead.  Units are selected and activated at the next rescan.
+    pub fn add_unit(&self, name: &UnitName, unit_text: &str) -> Result<(), String> {
+        let name = name.clone();
+        let path = PathBuf::from(VIRTUAL_UNIT_PATH);
+        let manager = self.manager();
+        let loaded = match *name.kind() {
+            UnitKind::Test => {
+                let desc = TestDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_test(&desc))
+            }
+            UnitKind::Jig => {
+                let desc = JigDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_jig(&desc))
+            }
+            UnitKind::Scenario => {
+                let desc = ScenarioDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_scenario(&desc))
+            }
+            UnitKind::Limits => {
+                let desc = LimitsDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_limits(&desc))
+            }
+            UnitKind::Resource => {
+                let desc = ResourceDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_resource(&desc))
+            }
+            UnitKind::Custom(_) => {
+                let desc = CustomDescription::from_string(unit_text, name, &path).map_err(|e| e.to_string())?;
+                manager.call(move |m| m.load_custom(&desc))
+            }
+            ref kind => return Err(format!("{} units can't be added from a string", kind)),
+        };
+        match loaded {
+            Ok(Ok(id)) => {
+                // Jigs, and units of the kinds that plugins add, are started once they're loaded, rather than by a scenario.
+                match *id.kind() {
+                    UnitKind::Jig | UnitKind::Custom(_) => self.added.borrow_mut().push(id.clone()),
+                    _ => (),
+                }
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Load an interface that runs no program, and is sent everything a real interface would be.
+    pub fn add_interface(&self, name: &UnitName) -> Result<VirtualInterface, String> {
+        let capture = Capture::new();
+        let desc = InterfaceDescription::in_memory(name.clone(), &capture);
+        match self.manager().call(move |m| m.load_interface(&desc)) {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(e) => return Err(e.to_string()),
+        }
+        self.added.borrow_mut().push(name.clone());
+        Ok(VirtualInterface {
+            id: name.clone(),
+            capture: capture,
+            control: self.control.clone(),
+        })
+    }
+
+    /// Every event from now on.  The engine only acts on events as it's run, so whoever
+    /// subscribes must keep up, or their events queue up until they do.
+    pub fn subscribe(&self) -> Receiver<UnitEvent> {
+        self.broadcaster.subscribe()
+    }
+
+    pub fn broadcaster(&self) -> &UnitBroadcaster {
+        &self.broadcaster
+    }
+
+    pub fn manager(&self) -> &ManagerHandle {
+        self.library.get_manager()
+    }
+
+    /// Select and activate whatever has been loaded, without waiting for unit files to stop changing.
+    pub fn rescan(&self) {
+        for id in self.added.borrow_mut().drain(..) {
+            self.manager().send(move |manager| {
+                manager.select(&id);
+                manager.activate(&id);
+            });
+        }
+        self.broadcaster.broadcast(&UnitEvent::RescanRequest);
+    }
+
+    /// Start scenario `name`, or the default scenario if there's no name.
+    pub fn start_scenario(&self, name: Option<&UnitName>) {
+        let contents = ManagerControlMessageContents::StartScenario(name.cloned());
+        self.control
+            .send(ManagerControlMessage::new(&UnitName::internal("engine"), contents))
+            .ok();
+    }
+
+    /// Stop every unit, and have `run()` return once they have.
+    pub fn shutdown(&self) {
+        self.broadcaster.broadcast(&UnitEvent::Shutdown);
+    }
+
+    /// Wait for the next event, and act on it.  It's returned so that the embedding program can
+    /// act on it too, or None once the engine has shut down.
+    pub fn next(&mut self) -> Option<UnitEvent> {
+        let msg = self.receiver.recv().ok()?;
+        self.watcher.process_message(&msg);
+        self.loader.process_message(&msg);
+        self.library.process_message(&msg);
+        self.quiesce.process_message(&msg);
+        Some(msg)
+    }
+
+    /// Act on events until the engine is shut down, e.g. with `shutdown()` or
+    /// an interface's SHUTDOWN, and then wait for its units to have stopped.
+    pub fn run(&mut self) {
+        while self.next().is_some() {}
+        self.manager().flush();
+    }
+}
+
+/// An interface added with `Engine::add_interface()`.
+pub struct VirtualInterface {
+    id: UnitName,
+    capture: Capture,
+    control: Sender<ManagerControlMessage>,
+}
+
+impl VirtualInterface {
+    pub fn id(&self) -> &UnitName {
+        &self.id
+    }
+
+    /// Send a line of CFTI, e.g. "START" or "SCENARIO simple", as if the interface's program had printed it.
+    pub fn send(&self, line: &str) {
+        Interface::command(&self.id, &self.control, line);
+    }
+
+    /// Every line the interface has been sent so far.
+    pub fn received(&self) -> Vec<String> {
+        self.capture.lines()
+    }
+
+    /// Every line the interface has been sent since the last time this was called.
+    pub fn take_received(&self) -> Vec<String> {
+        self.capture.take()
+    }
+}
@@ -0,0 +1,21 @@
+// The codes that exclave exits with, so that wrapper scripts and CI can
+// tell what happened without reading the log.
+
+/// The scenario passed, `exclave check` found nothing wrong, or exclave was shut down normally.
+pub const SUCCESS: i32 = 0;
+
+/// The scenario ran, and failed.
+pub const FAILED: i32 = 1;
+
+/// The scenario couldn't be run at all, e.g. because it isn't compatible with the jig.
+pub const NOT_RUN: i32 = 2;
+
+/// The scenario was stopped before it finished, e.g. because exclave was sent SIGTERM.
+pub const ABORTED: i32 = 3;
+
+/// `exclave check` found units that can't be parsed or loaded.
+pub const INVALID_UNITS: i32 = 4;
+
+/// exclave couldn't do what it was asked, e.g. because the command line or configuration
+/// file is wrong, a directory couldn't be read, or no instance is running for `exclave list`.
+pub const CONFIG_ERROR: i32 = 5;
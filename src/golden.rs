@@ -0,0 +1,257 @@
+// Golden-sample runs: a known-good reference unit is run through a scenario, and
+// what it measured is compared with a baseline recorded from it when the station
+// was known to be good.  A station whose fixtures or instruments have drifted
+// shows up here before it starts failing production units that are fine.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use unit::UnitName;
+
+/// What to do with a golden run once it finishes.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum GoldenMode {
+    /// Save what the reference unit did as the scenario's baseline.
+    Record,
+
+    /// Compare what the reference unit did with the scenario's baseline.
+    Verify,
+}
+
+impl GoldenMode {
+    pub fn from_str(s: &str) -> Option<GoldenMode> {
+        match s.to_lowercase().as_str() {
+            "record" => Some(GoldenMode::Record),
+            "verify" => Some(GoldenMode::Verify),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GoldenMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GoldenMode::Record => write!(f, "record"),
+            GoldenMode::Verify => write!(f, "verify"),
+        }
+    }
+}
+
+/// How far a measurement may be from the baseline before the station counts as having drifted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tolerance {
+    /// Within this much of the baseline, in the measurement's own unit.
+    Absolute(f64),
+
+    /// Within this percentage of the baseline.
+    Percent(f64),
+}
+
+impl Tolerance {
+    fn allows(&self, baseline: f64, value: f64) -> bool {
+        let difference = (value - baseline).abs();
+        match *self {
+            Tolerance::Absolute(t) => difference <= t,
+            Tolerance::Percent(p) => difference <= baseline.abs() * p / 100.0,
+        }
+    }
+}
+
+impl FromStr for Tolerance {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Tolerance, ()> {
+        let (number, percent) = if s.ends_with('%') { (&s[..s.len() - 1], true) } else { (s, false) };
+        match number.parse::<f64>() {
+            Ok(n) if n >= 0.0 && percent => Ok(Tolerance::Percent(n)),
+            Ok(n) if n >= 0.0 => Ok(Tolerance::Absolute(n)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tolerance::Absolute(t) => write!(f, "{}", t),
+            Tolerance::Percent(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Measurement {
+    value: String,
+    tolerance: Tolerance,
+    unit: Option<String>,
+}
+
+/// What a golden run did: how each test ended up, and what each test measured.
+/// Saved as a scenario's baseline, it's also what later golden runs are compared with.
+#[derive(Debug, Clone)]
+pub struct Golden {
+    mode: GoldenMode,
+    scenario: UnitName,
+
+    /// "pass", "fail", or "skip", by test id.
+    tests: BTreeMap<String, String>,
+
+    /// By test id and measurement name.  A test that reports the same measurement twice is
+    /// compared on the last value it reported.
+    measurements: BTreeMap<(String, String), Measurement>,
+}
+
+impl Golden {
+    pub fn new(mode: GoldenMode, scenario: &UnitName) -> Golden {
+        Golden {
+            mode: mode,
+            scenario: scenario.clone(),
+            tests: BTreeMap::new(),
+            measurements: BTreeMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> GoldenMode {
+        self.mode
+    }
+
+    pub fn scenario(&self) -> &UnitName {
+        &self.scenario
+    }
+
+    /// Where a scenario's baseline is kept.
+    pub fn baseline_path(directory: &Path, scenario: &UnitName) -> PathBuf {
+        directory.join(format!("{}.golden", scenario.id()))
+    }
+
+    pub fn test_finished(&mut self, test: &UnitName, result: &str) {
+        self.tests.insert(test.id().clone(), result.to_owned());
+    }
+
+    /// Note a measurement.  When recording, `tolerance` is what the baseline allows for it.
+    pub fn measure(&mut self, test: &UnitName, name: &str, value: &str, unit: Option<String>, tolerance: Tolerance) {
+        self.measurements.insert(
+            (test.id().clone(), name.to_owned()),
+            Measurement {
+                value: value.to_owned(),
+                tolerance: tolerance,
+                unit: unit,
+            },
+        );
+    }
+
+    /// How many measurements there were.
+    pub fn measurement_count(&self) -> usize {
+        self.measurements.len()
+    }
+
+    /// Save as the scenario's baseline, one line per test and per measurement.  The tolerances
+    /// can be tightened or loosened by editing the file, as long as it's kept tab-separated.
+    pub fn save(&self, directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        let path = Self::baseline_path(directory, &self.scenario);
+        let mut file = File::create(&path)?;
+        writeln!(file, "# Golden baseline for {}", self.scenario)?;
+        writeln!(file, "# test\t<test>\t<result>")?;
+        writeln!(file, "# measure\t<test>\t<name>\t<value>\t<tolerance>\t<unit>")?;
+        for (test, result) in &self.tests {
+            writeln!(file, "test\t{}\t{}", test, result)?;
+        }
+        for (&(ref test, ref name), measurement) in &self.measurements {
+            writeln!(
+                file,
+                "measure\t{}\t{}\t{}\t{}\t{}",
+                test,
+                name,
+                measurement.value,
+                measurement.tolerance,
+                measurement.unit.as_ref().map(|u| u.as_str()).unwrap_or("")
+            )?;
+        }
+        file.flush()
+    }
+
+    /// Load a scenario's baseline.
+    pub fn load(directory: &Path, scenario: &UnitName) -> io::Result<Golden> {
+        let path = Self::baseline_path(directory, scenario);
+        let mut baseline = Golden::new(GoldenMode::Record, scenario);
+        for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} line {} is not a test or measurement", path.to_string_lossy(), number + 1),
+                )
+            };
+            match fields[0] {
+                "test" if fields.len() >= 3 => {
+                    baseline.tests.insert(fields[1].to_owned(), fields[2].to_owned());
+                }
+                "measure" if fields.len() >= 5 => {
+                    let tolerance = fields[4].parse().map_err(|_| invalid())?;
+                    baseline.measurements.insert(
+                        (fields[1].to_owned(), fields[2].to_owned()),
+                        Measurement {
+                            value: fields[3].to_owned(),
+                            tolerance: tolerance,
+                            unit: fields.get(5).filter(|u| !u.is_empty()).map(|u| u.to_string()),
+                        },
+                    );
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(baseline)
+    }
+
+    /// Every way this run differs from the baseline.  Tests and measurements that aren't in the
+    /// baseline are ignored, so that a scenario can gain tests without recording a new one.
+    pub fn drift_from(&self, baseline: &Golden) -> Vec<String> {
+        let mut drift = vec![];
+        for (test, expected) in &baseline.tests {
+            match self.tests.get(test) {
+                None => drift.push(format!("{} did not run, but was {} in the baseline", test, expected)),
+                Some(result) if result != expected => {
+                    drift.push(format!("{} was {}, but was {} in the baseline", test, result, expected))
+                }
+                Some(_) => (),
+            }
+        }
+
+        for (&(ref test, ref name), expected) in &baseline.measurements {
+            let with_unit = |value: &str| match expected.unit {
+                Some(ref unit) => format!("{} {}", value, unit),
+                None => value.to_owned(),
+            };
+            let measured = match self.measurements.get(&(test.clone(), name.clone())) {
+                None => {
+                    drift.push(format!("{} did not measure {}", test, name));
+                    continue;
+                }
+                Some(m) => m,
+            };
+            let matches = match (expected.value.parse::<f64>(), measured.value.parse::<f64>()) {
+                (Ok(baseline_value), Ok(value)) => expected.tolerance.allows(baseline_value, value),
+                // Anything that isn't a number, e.g. a firmware version, must match exactly.
+                _ => expected.value == measured.value,
+            };
+            if !matches {
+                drift.push(format!(
+                    "{} measured {} as {}, but it was {} in the baseline, within {}",
+                    test,
+                    name,
+                    with_unit(&measured.value),
+                    with_unit(&expected.value),
+                    expected.tolerance
+                ));
+            }
+        }
+        drift
+    }
+}
@@ -0,0 +1,236 @@
+// An optional HTTP endpoint for Prometheus to scrape, describing exclave itself
+// rather than the tests it runs, so that a fleet of stations can be monitored.
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use logsinks::metrics::{listen, prometheus_escape, serve_metrics};
+use managerhandle::ManagerHandle;
+use unit::UnitName;
+use unitbroadcaster::{UnitEvent, UnitStatus};
+use unitmanager::ManagerControlMessageContents;
+use yieldstats::YieldWindow;
+
+/// Logger queues and yield figures are only looked at this often, as each logger's queue has
+/// to be locked, and working out yield figures means going over every recent run.
+const QUEUE_REFRESH_MILLIS: u64 = 1000;
+
+/// How long to wait on the manager for its figures.  It may be busy, e.g. loading units,
+/// and the main loop shouldn't be held up by it.
+const MANAGER_TIMEOUT_MILLIS: u64 = 100;
+
+#[derive(Default)]
+struct State {
+    /// The state of every unit that hasn't been removed, e.g. "active".
+    units: BTreeMap<UnitName, &'static str>,
+
+    rescans: u64,
+    rescan_seconds_sum: f64,
+    last_rescan_seconds: f64,
+
+    /// Events handled by the main loop.
+    events: u64,
+
+    /// Tests, interfaces, and loggers that are running.
+    running_units: usize,
+
+    /// Messages waiting to be written to each logger.
+    logger_queues: BTreeMap<String, usize>,
+
+    runs_passed: u64,
+    runs_failed: u64,
+
+    /// Yield figures for each of the configured windows.
+    yields: Vec<YieldWindow>,
+
+    /// How many times each kind of thing was timed, and how long it took altogether, with --instrument.
+    timings: BTreeMap<&'static str, (u64, f64)>,
+}
+
+impl State {
+    fn prometheus_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP exclave_units Units known to exclave, by kind and state.\n");
+        out.push_str("# TYPE exclave_units gauge\n");
+        let mut counts: BTreeMap<(String, &'static str), u64> = BTreeMap::new();
+        for (name, state) in &self.units {
+            *counts.entry((name.kind().to_string(), *state)).or_insert(0) += 1;
+        }
+        for (&(ref kind, state), count) in &counts {
+            out.push_str(&format!("exclave_units{{kind=\"{}\",state=\"{}\"}} {}\n", kind, state, count));
+        }
+
+        out.push_str("# HELP exclave_rescans_total Rescans of the unit directories that have finished.\n");
+        out.push_str("# TYPE exclave_rescans_total counter\n");
+        out.push_str(&format!("exclave_rescans_total {}\n", self.rescans));
+        out.push_str("# HELP exclave_rescan_duration_seconds How long rescans of the unit directories take.\n");
+        out.push_str("# TYPE exclave_rescan_duration_seconds summary\n");
+        out.push_str(&format!("exclave_rescan_duration_seconds_sum {}\n", self.rescan_seconds_sum));
+        out.push_str(&format!("exclave_rescan_duration_seconds_count {}\n", self.rescans));
+        out.push_str("# HELP exclave_last_rescan_duration_seconds How long the most recent rescan took.\n");
+        out.push_str("# TYPE exclave_last_rescan_duration_seconds gauge\n");
+        out.push_str(&format!("exclave_last_rescan_duration_seconds {}\n", self.last_rescan_seconds));
+
+        out.push_str("# HELP exclave_events_total Events handled by the main loop.\n");
+        out.push_str("# TYPE exclave_events_total counter\n");
+        out.push_str(&format!("exclave_events_total {}\n", self.events));
+
+        out.push_str("# HELP exclave_running_units Tests, interfaces, and loggers that are running.\n");
+        out.push_str("# TYPE exclave_running_units gauge\n");
+        out.push_str(&format!("exclave_running_units {}\n", self.running_units));
+
+        out.push_str("# HELP exclave_logger_queue_length Messages waiting to be written to each logger.\n");
+        out.push_str("# TYPE exclave_logger_queue_length gauge\n");
+        for (logger, length) in &self.logger_queues {
+            out.push_str(&format!("exclave_logger_queue_length{{logger=\"{}\"}} {}\n", prometheus_escape(logger), length));
+        }
+
+        out.push_str("# HELP exclave_timing_seconds How long rescans, parsing unit files, starting tests, and writing to interfaces take, with --instrument.\n");
+        out.push_str("# TYPE exclave_timing_seconds summary\n");
+        for (kind, &(count, sum)) in &self.timings {
+            out.push_str(&format!("exclave_timing_seconds_sum{{kind=\"{}\"}} {}\n", kind, sum));
+            out.push_str(&format!("exclave_timing_seconds_count{{kind=\"{}\"}} {}\n", kind, count));
+        }
+
+        out.push_str("# HELP exclave_scenario_runs_total Scenario runs that have finished, whichever scenario it was.\n");
+        out.push_str("# TYPE exclave_scenario_runs_total counter\n");
+        out.push_str(&format!("exclave_scenario_runs_total{{result=\"pass\"}} {}\n", self.runs_passed));
+        out.push_str(&format!("exclave_scenario_runs_total{{result=\"fail\"}} {}\n", self.runs_failed));
+
+        out.push_str("# HELP exclave_yield_runs Scenario runs that started within the window, in seconds.\n");
+        out.push_str("# TYPE exclave_yield_runs gauge\n");
+        for y in &self.yields {
+            out.push_str(&format!("exclave_yield_runs{{window=\"{}\"}} {}\n", y.window.as_secs(), y.runs));
+        }
+        out.push_str("# HELP exclave_first_pass_yield Fraction of devices first tested within the window that passed that first time.\n");
+        out.push_str("# TYPE exclave_first_pass_yield gauge\n");
+        for y in &self.yields {
+            if let Some(fpy) = y.first_pass_yield {
+                out.push_str(&format!("exclave_first_pass_yield{{window=\"{}\"}} {}\n", y.window.as_secs(), fpy));
+            }
+        }
+        out.push_str("# HELP exclave_retest_rate Fraction of runs within the window that were of a device tested before.\n");
+        out.push_str("# TYPE exclave_retest_rate gauge\n");
+        for y in &self.yields {
+            if let Some(rate) = y.retest_rate {
+                out.push_str(&format!("exclave_retest_rate{{window=\"{}\"}} {}\n", y.window.as_secs(), rate));
+            }
+        }
+        out.push_str("# HELP exclave_top_test_failures Failures within the window of the tests that failed most often.\n");
+        out.push_str("# TYPE exclave_top_test_failures gauge\n");
+        for y in &self.yields {
+            for &(ref test, count) in &y.top_failures {
+                out.push_str(&format!(
+                    "exclave_top_test_failures{{window=\"{}\",test=\"{}\"}} {}\n",
+                    y.window.as_secs(),
+                    prometheus_escape(test),
+                    count
+                ));
+            }
+        }
+        out
+    }
+}
+
+pub struct InternalMetrics {
+    state: Arc<Mutex<State>>,
+
+    /// When the rescan that's underway started.
+    rescan_started: Option<Instant>,
+
+    /// When the logger queues were last looked at.
+    queues_refreshed: Option<Instant>,
+}
+
+impl InternalMetrics {
+    /// Start serving metrics on `address`, e.g. "0.0.0.0:9101".
+    pub fn start(address: &str) -> io::Result<InternalMetrics> {
+        let listener = listen(address)?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let thr_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    serve_metrics(stream, &|| thr_state.lock().unwrap().prometheus_text()).ok();
+                }
+            }
+        });
+
+        Ok(InternalMetrics {
+            state: state,
+            rescan_started: None,
+            queues_refreshed: None,
+        })
+    }
+
+    pub fn process_message(&mut self, msg: &UnitEvent, manager: &ManagerHandle) {
+        let mut state = self.state.lock().unwrap();
+        state.events += 1;
+        match *msg {
+            UnitEvent::Status(ref evt) => match *evt.status() {
+                UnitStatus::Removed(_) => {
+                    state.units.remove(evt.name());
+                }
+                ref status => {
+                    state.units.insert(evt.name().clone(), status.state());
+                }
+            },
+            UnitEvent::RescanStart => self.rescan_started = Some(Instant::now()),
+            UnitEvent::Timing(ref timing) => {
+                let entry = state.timings.entry(timing.kind.name()).or_insert((0, 0.0));
+                entry.0 += 1;
+                entry.1 += timing.seconds();
+            }
+            UnitEvent::RescanFinish => {
+                if let Some(started) = self.rescan_started.take() {
+                    let elapsed = started.elapsed();
+                    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+                    state.rescans += 1;
+                    state.rescan_seconds_sum += seconds;
+                    state.last_rescan_seconds = seconds;
+                }
+            }
+            UnitEvent::ManagerRequest(ref request) => {
+                if let ManagerControlMessageContents::ScenarioFinished(code, _) = request.contents {
+                    // Scenarios finish with 200 when every test passed.
+                    if code == 200 {
+                        state.runs_passed += 1;
+                    } else {
+                        state.runs_failed += 1;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let refresh = match self.queues_refreshed {
+            None => true,
+            Some(at) => at.elapsed() >= Duration::from_millis(QUEUE_REFRESH_MILLIS),
+        };
+        let reply = manager.call_timeout(Duration::from_millis(MANAGER_TIMEOUT_MILLIS), move |manager| {
+            let details = if refresh {
+                Some((manager.logger_queue_lengths(), manager.yield_windows()))
+            } else {
+                None
+            };
+            (manager.running_units(), details)
+        });
+        let (running_units, details) = match reply {
+            Ok(reply) => reply,
+            // The figures from last time stand until the manager has a moment.
+            Err(_) => return,
+        };
+        state.running_units = running_units;
+        if let Some((queues, yields)) = details {
+            self.queues_refreshed = Some(Instant::now());
+            state.logger_queues = queues
+                .into_iter()
+                .map(|(id, length)| (id.id().clone(), length))
+                .collect();
+            state.yields = yields;
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// A journal of the run in progress, kept on disk so that if exclave stops
+// partway through a run, e.g. because the station lost power, it knows about
+// it when it starts again, and the operator can resume the run or throw it out.
+extern crate serde_json;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The journal's name in the state directory.
+pub const JOURNAL_FILE: &str = "run.journal";
+
+/// The result code that interrupted runs are recorded with.
+pub const INTERRUPTED_CODE: u32 = 503;
+
+/// What's known about the run in progress.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub scenario: String,
+
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub serial: Option<String>,
+
+    /// The variables the run was started with.
+    #[serde(default)]
+    pub variables: Vec<(String, String)>,
+
+    /// When the run started, in RFC3339 format.
+    pub started: String,
+
+    /// The run's directory, if it's being logged.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub run_directory: Option<PathBuf>,
+
+    /// The tests that have passed so far, in the order they passed.
+    #[serde(default)]
+    pub passed: Vec<String>,
+
+    /// The tests that have failed or been skipped so far.
+    #[serde(default)]
+    pub not_passed: Vec<String>,
+}
+
+impl Journal {
+    /// Write the journal to `directory`, replacing the last one.  It's written to a new file
+    /// which is synced before it's moved into place, so that losing power partway through
+    /// leaves either the old journal or the new one, and never half of one.
+    pub fn save(&self, directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(JOURNAL_FILE);
+        let temp_path = path.with_extension("journal.tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(serde_json::to_string(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &path)
+    }
+
+    /// The journal of a run that was in progress when exclave last stopped, if there was one.
+    pub fn load(directory: &Path) -> io::Result<Option<Journal>> {
+        let mut contents = String::new();
+        match File::open(directory.join(JOURNAL_FILE)) {
+            Ok(mut file) => file.read_to_string(&mut contents)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    /// Remove the journal, once the run has finished or been dealt with.
+    pub fn clear(directory: &Path) -> io::Result<()> {
+        match fs::remove_file(directory.join(JOURNAL_FILE)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// The name of the run's directory, if it's being logged.
+    pub fn run_id(&self) -> Option<String> {
+        self.run_directory
+            .as_ref()
+            .and_then(|d| d.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+    }
+
+    /// Note in the run's event log that it was interrupted, or that it was thrown out afterwards.
+    pub fn mark_run(&self, line: &str) -> io::Result<()> {
+        let directory = match self.run_directory {
+            Some(ref d) => d,
+            None => return Ok(()),
+        };
+        let mut events = OpenOptions::new().append(true).open(directory.join("events.log"))?;
+        writeln!(events, "{}", line)
+    }
+}
@@ -0,0 +1,181 @@
+// JUnit XML reports for each run, so that CI servers and quality dashboards
+// that already read them can show results from the factory floor too.
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use unit::UnitName;
+
+/// How a test ended up.
+pub enum Outcome {
+    /// Started, but hasn't finished yet.  Tests still running when the run
+    /// finishes, e.g. because it was aborted, are reported as errors.
+    Running,
+    Passed,
+    Failed(i32 /* result code */, String /* message */),
+    Skipped(String /* reason */),
+}
+
+struct TestCase {
+    test: UnitName,
+    started: Instant,
+    time: Duration,
+    outcome: Outcome,
+}
+
+/// What's known about the run as a whole, for the test suite's attributes and properties.
+pub struct RunInfo<'a> {
+    pub scenario: &'a UnitName,
+    pub run_id: &'a str,
+    pub serial: Option<&'a String>,
+    pub operator: Option<&'a String>,
+    pub variables: &'a [(String, String)],
+
+    /// When the run started, in RFC3339 format.
+    pub timestamp: &'a str,
+
+    /// How long the run took.
+    pub time: Duration,
+}
+
+/// The tests of a single run, in the order they were started or skipped.
+pub struct Report {
+    cases: Vec<TestCase>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report { cases: vec![] }
+    }
+
+    pub fn started(&mut self, test: &UnitName) {
+        self.cases.push(TestCase {
+            test: test.clone(),
+            started: Instant::now(),
+            time: Duration::from_secs(0),
+            outcome: Outcome::Running,
+        });
+    }
+
+    /// Note how a test ended up.  Skipped tests were never started, so they're added here.
+    pub fn finished(&mut self, test: &UnitName, outcome: Outcome) {
+        let running = self.cases.iter_mut().rev().find(|c| &c.test == test && match c.outcome {
+            Outcome::Running => true,
+            _ => false,
+        });
+        match running {
+            Some(case) => {
+                case.time = case.started.elapsed();
+                case.outcome = outcome;
+            }
+            None => self.cases.push(TestCase {
+                test: test.clone(),
+                started: Instant::now(),
+                time: Duration::from_secs(0),
+                outcome: outcome,
+            }),
+        }
+    }
+
+    /// Write the report as a single test suite named after the scenario.  Each test's
+    /// output is included from its transcript, if it printed anything.
+    pub fn write<W: Write>(&self, out: &mut W, run: &RunInfo, transcript_path: &dyn Fn(&UnitName) -> PathBuf) -> io::Result<()> {
+        let (mut failures, mut errors, mut skipped) = (0, 0, 0);
+        for case in &self.cases {
+            match case.outcome {
+                Outcome::Running => errors += 1,
+                Outcome::Passed => (),
+                Outcome::Failed(..) => failures += 1,
+                Outcome::Skipped(_) => skipped += 1,
+            }
+        }
+
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(
+            out,
+            "<testsuite name=\"{}\" id=\"{}\" timestamp=\"{}\" time=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\">",
+            escape(run.scenario.id()),
+            escape(run.run_id),
+            escape(run.timestamp),
+            seconds(run.time),
+            self.cases.len(),
+            failures,
+            errors,
+            skipped
+        )?;
+        if run.serial.is_some() || run.operator.is_some() || !run.variables.is_empty() {
+            writeln!(out, "  <properties>")?;
+            if let Some(serial) = run.serial {
+                writeln!(out, "    <property name=\"serial\" value=\"{}\"/>", escape(serial))?;
+            }
+            if let Some(operator) = run.operator {
+                writeln!(out, "    <property name=\"operator\" value=\"{}\"/>", escape(operator))?;
+            }
+            // Variables, including tags, are named after themselves, e.g. "firmware".
+            for &(ref key, ref value) in run.variables {
+                writeln!(out, "    <property name=\"{}\" value=\"{}\"/>", escape(key), escape(value))?;
+            }
+            writeln!(out, "  </properties>")?;
+        }
+
+        for case in &self.cases {
+            writeln!(
+                out,
+                "  <testcase classname=\"{}\" name=\"{}\" time=\"{}\">",
+                escape(run.scenario.id()),
+                escape(case.test.id()),
+                seconds(case.time)
+            )?;
+            match case.outcome {
+                Outcome::Running => writeln!(out, "    <error message=\"did not finish before the run ended\"/>")?,
+                Outcome::Passed => (),
+                Outcome::Failed(code, ref message) => writeln!(
+                    out,
+                    "    <failure type=\"{}\" message=\"{}\"/>",
+                    code,
+                    escape(message)
+                )?,
+                Outcome::Skipped(ref reason) => writeln!(out, "    <skipped message=\"{}\"/>", escape(reason))?,
+            }
+            if let Some(transcript) = read_transcript(&transcript_path(&case.test)) {
+                writeln!(out, "    <system-out>{}</system-out>", escape(&transcript))?;
+            }
+            writeln!(out, "  </testcase>")?;
+        }
+
+        writeln!(out, "</testsuite>")?;
+        out.flush()
+    }
+}
+
+/// Whatever the test printed, as recorded in its transcript.
+fn read_transcript(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    Some(contents)
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:03}", duration.as_secs(), duration.subsec_millis())
+}
+
+/// Make a string safe to use as XML text or in an attribute.  Control characters,
+/// such as the escape sequences that colour a test's output, aren't allowed at all.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,124 @@
+// Printing a label for each device that passes, or a reject tag for each one
+// that fails, by running a command once its run has finished.  The command is
+// written with placeholders such as {serial} and {date}, which are filled in
+// from the run's result, so that most printers can be driven without a script.
+extern crate serde_json;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use program::Exec;
+use summary::RunResult;
+use unit::UnitName;
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+
+/// How long a print command may take before it's given up on.
+const PRINT_TIMEOUT_SECS: u64 = 60;
+
+/// Fill in the placeholders in `template` from a run's result:
+///
+/// * {serial} - the serial number of the device under test
+/// * {scenario} - the scenario's id
+/// * {verdict} - "pass" or "fail"
+/// * {code} - the scenario's result code
+/// * {message} - the scenario's result message
+/// * {date} and {time} - when the run finished, e.g. "2018-02-14" and "00:28:07", in the configured timezone
+/// * {operator} - the operator who was logged in
+/// * {run} - the id of the run's log directory
+/// * {var:key} - the run's variable or tag called "key"
+/// * {{ and }} - a literal "{" or "}"
+///
+/// Each value is quoted, so that a message with spaces in it stays one argument.
+/// Values that aren't known are empty, and anything else in braces is left alone.
+fn expand(template: &str, result: &RunResult) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(idx) = rest.find(|c: char| c == '{' || c == '}') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            expanded.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let value = match rest.find('}') {
+            Some(end) if rest.starts_with('{') => value_of(&rest[1..end], result).map(|v| (v, end)),
+            _ => None,
+        };
+        match value {
+            Some((value, end)) => {
+                expanded.push_str(&quote(&value));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                expanded.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn value_of(name: &str, result: &RunResult) -> Option<String> {
+    if name.starts_with("var:") {
+        return Some(result.variables.get(&name["var:".len()..]).cloned().unwrap_or_default());
+    }
+    Some(match name {
+        "serial" => result.serial.clone().unwrap_or_default(),
+        "scenario" => result.scenario.clone(),
+        "verdict" => if result.passed { "pass" } else { "fail" }.to_owned(),
+        "code" => result.result.to_string(),
+        "message" => result.message.clone(),
+        // `finished` is RFC3339, e.g. "2018-02-14T00:28:07+13:00".
+        "date" => result.finished.get(..10).unwrap_or("").to_owned(),
+        "time" => result.finished.get(11..19).unwrap_or("").to_owned(),
+        "operator" => result.operator.clone().unwrap_or_default(),
+        "run" => result.run.clone().unwrap_or_default(),
+        _ => return None,
+    })
+}
+
+/// Quote a value for the shell, so that it's passed on exactly as it is.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Run a print command for a finished run, in the background so that the next run needn't wait
+/// for the printer.  The result is given as JSON on its standard input as well, in the same shape
+/// as it's sent to the MES, for printers that need more than the placeholders offer.
+pub fn print(template: &str, result: &RunResult, broadcaster: &UnitBroadcaster) {
+    let command = expand(template, result);
+    let body = match serde_json::to_string(result) {
+        Ok(b) => b,
+        Err(e) => {
+            broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("labels"), format!("unable to encode result: {}", e))));
+            return;
+        }
+    };
+    let bc = broadcaster.clone();
+    thread::spawn(move || {
+        let id = UnitName::internal("labels");
+        let mut cmd = Exec::new(&command);
+        cmd.timeout(Duration::from_secs(PRINT_TIMEOUT_SECS));
+        let mut running = match cmd.start() {
+            Ok(r) => r,
+            Err(e) => {
+                bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id, format!("unable to run {}: {:?}", command, e))));
+                return;
+            }
+        };
+        {
+            let mut input = running.take_input();
+            // A command that doesn't read the result may have exited already.
+            input.write_all(body.as_bytes()).ok();
+        }
+        let waiter = running.waiter();
+        waiter.wait();
+        match waiter.result() {
+            0 => bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id, format!("printed with {}", command)))),
+            code => bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id, format!("{} exited with {}", command, code)))),
+        }
+    });
+}
@@ -0,0 +1,68 @@
+//! Exclave runs factory tests.  Everything it does lives in this library, so that
+//! the `exclave` binary is only its command line, and so that integration tests,
+//! both exclave's own and those of whoever writes units for it, can drive the
+//! same unit manager.  `engine::Engine` is that unit manager, along with the unit
+//! directories it loads from, for programs that embed exclave rather than run it.
+//! See the `testing` module for a harness that writes scenario tests with it.
+//! Only what an embedding program or a plugin needs is public: the Engine, the
+//! configuration, unit names, the events it broadcasts, and `plugin`.
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+
+pub(crate) mod artifacts;
+pub(crate) mod audit;
+pub(crate) mod check;
+#[doc(hidden)]
+pub mod cli;
+pub mod config;
+pub(crate) mod configfile;
+pub(crate) mod daemon;
+pub mod engine;
+pub(crate) mod exitcode;
+pub(crate) mod golden;
+pub(crate) mod internalmetrics;
+pub(crate) mod journal;
+pub(crate) mod junit;
+pub(crate) mod labels;
+pub(crate) mod logsinks;
+pub mod managerhandle;
+pub(crate) mod mes;
+pub(crate) mod oneshot;
+pub(crate) mod operators;
+pub(crate) mod parsecache;
+pub(crate) mod plan;
+pub mod plugin;
+pub(crate) mod program;
+pub(crate) mod quiesce;
+pub(crate) mod reactor;
+pub(crate) mod replay;
+pub(crate) mod retention;
+pub(crate) mod run;
+pub(crate) mod sdnotify;
+pub(crate) mod spc;
+pub(crate) mod spillbuffer;
+pub(crate) mod standby;
+pub(crate) mod statussocket;
+pub(crate) mod summary;
+pub(crate) mod supervisor;
+pub(crate) mod terminal;
+pub mod testing;
+pub(crate) mod teststats;
+pub(crate) mod timestamp;
+pub(crate) mod triggers;
+pub mod unit;
+pub mod unitbroadcaster;
+pub(crate) mod unitlibrary;
+pub(crate) mod unitloader;
+pub(crate) mod unitmanager;
+pub(crate) mod units;
+pub(crate) mod unitwatcher;
+pub(crate) mod upload;
+pub(crate) mod usage;
+pub(crate) mod watch;
+pub(crate) mod yieldstats;
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,339 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use logsinks::{LogSink, Record};
+use timestamp::rfc3339_seconds;
+use unit::UnitName;
+use unitmanager::ManagerStatusMessage;
+
+/// How measurements are laid out in the CSV file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsvLayout {
+    /// One row per measurement.
+    Long,
+
+    /// One row per test, with one column per measurement name.
+    Wide,
+}
+
+struct Measurement {
+    secs: u64,
+    name: String,
+    value: String,
+    unit: Option<String>,
+
+    /// How far the value was inside its limit, if it had one.
+    margin: Option<String>,
+}
+
+/// Collects MEASURE records as a scenario runs, and appends them to a CSV
+/// file keyed by serial number and test once the scenario finishes.
+///
+/// Rows are held back until the end of the run so that a serial number
+/// reported by any test, even the last one, ends up on every row.
+pub struct CsvSink {
+    path: PathBuf,
+    layout: CsvLayout,
+
+    /// Names of the run variables that get a column each, e.g. a lot code.
+    variable_columns: Vec<String>,
+
+    /// The scenario currently running, if any.
+    scenario: Option<UnitName>,
+
+    /// The serial number of the device under test, if one was reported.
+    serial: String,
+
+    /// Variables the run was started with.
+    variables: Vec<(String, String)>,
+
+    /// Measurements for each test, in the order the tests reported them.
+    measurements: Vec<(UnitName, Vec<Measurement>)>,
+
+    /// How each test finished.
+    results: Vec<(UnitName, &'static str)>,
+}
+
+impl CsvSink {
+    pub fn new(path: PathBuf, layout: CsvLayout, variable_columns: Vec<String>) -> io::Result<CsvSink> {
+        // Make sure the file can be written to before the first run comes along.
+        OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(CsvSink {
+            path: path,
+            layout: layout,
+            variable_columns: variable_columns,
+            scenario: None,
+            serial: "".to_owned(),
+            variables: vec![],
+            measurements: vec![],
+            results: vec![],
+        })
+    }
+
+    fn reset(&mut self, scenario: Option<UnitName>) {
+        self.scenario = scenario;
+        self.serial = "".to_owned();
+        self.variables.clear();
+        self.measurements.clear();
+        self.results.clear();
+    }
+
+    fn set_result(&mut self, test: UnitName, result: &'static str) {
+        self.results.retain(|&(ref id, _)| *id != test);
+        self.results.push((test, result));
+    }
+
+    fn result_of(&self, test: &UnitName) -> &'static str {
+        for &(ref id, result) in &self.results {
+            if id == test {
+                return result;
+            }
+        }
+        ""
+    }
+
+    fn add_measurement(&mut self, test: &UnitName, measurement: Measurement) {
+        for &mut (ref id, ref mut list) in self.measurements.iter_mut() {
+            if id == test {
+                list.push(measurement);
+                return;
+            }
+        }
+        self.measurements.push((test.clone(), vec![measurement]));
+    }
+
+    /// A margin is logged just after the measurement it's for.
+    fn add_margin(&mut self, test: &UnitName, name: &str, margin: String) {
+        for &mut (ref id, ref mut list) in self.measurements.iter_mut() {
+            if id == test {
+                if let Some(m) = list.iter_mut().rev().find(|m| m.name == name) {
+                    m.margin = Some(margin);
+                }
+                return;
+            }
+        }
+    }
+
+    /// The value of each variable column for this run.
+    fn variable_values(&self) -> Vec<String> {
+        self.variable_columns
+            .iter()
+            .map(|column| {
+                self.variables
+                    .iter()
+                    .rev()
+                    .find(|&&(ref key, _)| key == column)
+                    .map(|&(_, ref value)| value.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Append the measurements from the run that just finished.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.measurements.is_empty() {
+            return Ok(());
+        }
+        match self.layout {
+            CsvLayout::Long => self.flush_long(),
+            CsvLayout::Wide => self.flush_wide(),
+        }
+    }
+
+    fn flush_long(&self) -> io::Result<()> {
+        let needs_header = self.path.metadata().map(|m| m.len() == 0).unwrap_or(true);
+        // Files started before margins were recorded keep the columns they have.
+        let with_margin = needs_header
+            || File::open(&self.path)
+                .ok()
+                .and_then(|f| BufReader::new(f).lines().next())
+                .and_then(|line| line.ok())
+                .map(|line| split_row(&line).last().map(|s| s == "margin").unwrap_or(false))
+                .unwrap_or(false);
+        let mut output = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if needs_header {
+            let mut header: Vec<String> = ["timestamp", "serial", "scenario"].iter().map(|s| s.to_string()).collect();
+            header.extend(self.variable_columns.iter().cloned());
+            header.extend(["test", "measurement", "value", "unit", "result", "margin"].iter().map(|s| s.to_string()));
+            write_row(&mut output, &header)?;
+        }
+
+        let scenario = self.scenario.as_ref().map(|s| s.id().clone()).unwrap_or_default();
+        let variables = self.variable_values();
+        for &(ref test, ref measurements) in &self.measurements {
+            for m in measurements {
+                let mut row = vec![rfc3339_seconds(m.secs), self.serial.clone(), scenario.clone()];
+                row.extend(variables.iter().cloned());
+                row.extend(vec![
+                    test.id().clone(),
+                    m.name.clone(),
+                    m.value.clone(),
+                    m.unit.clone().unwrap_or_default(),
+                    self.result_of(test).to_owned(),
+                ]);
+                if with_margin {
+                    row.push(m.margin.clone().unwrap_or_default());
+                }
+                write_row(&mut output, &row)?;
+            }
+        }
+        output.flush()
+    }
+
+    fn flush_wide(&self) -> io::Result<()> {
+        // Read back the existing header, if any, to figure out the measurement columns.
+        let mut old_header: Vec<String> = vec![];
+        if let Ok(f) = File::open(&self.path) {
+            if let Some(Ok(line)) = BufReader::new(f).lines().next() {
+                old_header = split_row(&line);
+            }
+        }
+        let mut fixed: Vec<String> = ["timestamp", "serial", "scenario"].iter().map(|s| s.to_string()).collect();
+        fixed.extend(self.variable_columns.iter().cloned());
+        fixed.extend(["test", "result"].iter().map(|s| s.to_string()));
+
+        // The file's other columns are kept, in the same order, after the fixed ones.  They're
+        // usually measurements, but may be variables that have since been taken out of Variables=.
+        let mut header = fixed.clone();
+        header.extend(old_header.iter().filter(|c| !fixed.contains(c)).cloned());
+
+        // A measurement's margin, if it has one, goes in a column of its own beside it.
+        for &(_, ref measurements) in &self.measurements {
+            for m in measurements {
+                if !header.contains(&m.name) {
+                    header.push(m.name.clone());
+                }
+                let margin_column = margin_column(&m.name);
+                if m.margin.is_some() && !header.contains(&margin_column) {
+                    header.push(margin_column);
+                }
+            }
+        }
+
+        // New measurement columns, or a change to Variables=, mean the existing file needs a new
+        // header, with each row's values moved under the columns they were in before.
+        if header != old_header && self.path.metadata().map(|m| m.len() > 0).unwrap_or(false) {
+            let mut rows = vec![];
+            for line in BufReader::new(File::open(&self.path)?).lines().skip(1) {
+                rows.push(split_row(&line?));
+            }
+            let mut output = File::create(&self.path)?;
+            write_row(&mut output, &header)?;
+            for row in rows {
+                let row: Vec<String> = header
+                    .iter()
+                    .map(|column| match old_header.iter().position(|c| c == column) {
+                        Some(idx) => row.get(idx).cloned().unwrap_or_default(),
+                        None => String::new(),
+                    })
+                    .collect();
+                write_row(&mut output, &row)?;
+            }
+        }
+
+        let needs_header = self.path.metadata().map(|m| m.len() == 0).unwrap_or(true);
+        let mut output = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if needs_header {
+            write_row(&mut output, &header)?;
+        }
+
+        let scenario = self.scenario.as_ref().map(|s| s.id().clone()).unwrap_or_default();
+        let variables = self.variable_values();
+        for &(ref test, ref measurements) in &self.measurements {
+            let last = measurements.last().unwrap();
+            let mut row = vec![rfc3339_seconds(last.secs), self.serial.clone(), scenario.clone()];
+            row.extend(variables.iter().cloned());
+            row.push(test.id().clone());
+            row.push(self.result_of(test).to_owned());
+            for column in &header[fixed.len()..] {
+                let value = match measurements.iter().rev().find(|m| m.name == *column) {
+                    Some(m) => m.value.clone(),
+                    None => measurements
+                        .iter()
+                        .rev()
+                        .find(|m| margin_column(&m.name) == *column)
+                        .and_then(|m| m.margin.clone())
+                        .unwrap_or_default(),
+                };
+                row.push(value);
+            }
+            write_row(&mut output, &row)?;
+        }
+        output.flush()
+    }
+}
+
+impl LogSink for CsvSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        match msg {
+            ManagerStatusMessage::Start(scenario) => self.reset(Some(scenario)),
+            ManagerStatusMessage::Log(entry) => match Record::from_log(&entry) {
+                Some(Record::Serial(serial)) => self.serial = serial,
+                Some(Record::Variable(key, value)) => self.variables.push((key, value)),
+                Some(Record::Measure(name, value, unit)) => self.add_measurement(
+                    entry.id(),
+                    Measurement {
+                        secs: entry.secs(),
+                        name: name,
+                        value: value,
+                        unit: unit,
+                        margin: None,
+                    },
+                ),
+                Some(Record::Margin(name, margin, _)) => self.add_margin(entry.id(), &name, margin),
+                Some(Record::Artifact(_)) | Some(Record::Tag(_, _)) | None => (),
+            },
+            ManagerStatusMessage::Pass(test, _) => self.set_result(test, "pass"),
+            ManagerStatusMessage::Fail(test, _, _) => self.set_result(test, "fail"),
+            ManagerStatusMessage::Skipped(test, _) => self.set_result(test, "skip"),
+            ManagerStatusMessage::Finished(_, _, _) => {
+                let result = self.flush();
+                self.reset(None);
+                result?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// The wide layout's column for a measurement's margin.
+fn margin_column(name: &str) -> String {
+    format!("{} margin", name)
+}
+
+fn escape(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Write a row of fields, quoting the ones that need it.
+pub fn write_row<W: Write>(output: &mut W, fields: &[String]) -> io::Result<()> {
+    let escaped: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+    writeln!(output, "{}", escaped.join(","))
+}
+
+/// Split a row written by `write_row` back into its fields.
+pub fn split_row(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(::std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
@@ -0,0 +1,384 @@
+extern crate serde_json;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use logsinks::{LogSink, Record};
+use timestamp::now;
+use unitmanager::ManagerStatusMessage;
+
+/// How long to wait on the server before giving up on a batch.
+const SHIP_TIMEOUT_SECS: u64 = 10;
+
+/// How many batches' worth of events are kept while the server can't be reached.  Beyond
+/// that, the oldest events are dropped.
+const MAX_RETAINED_BATCHES: usize = 10;
+
+/// A single event, in the shape it gets indexed.
+#[derive(Serialize)]
+struct Event<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: String,
+
+    /// What kind of event this is, e.g. "log", "pass", or "finish".
+    event: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    station: Option<&'a String>,
+
+    #[serde(skip_serializing_if = "str::is_empty")]
+    scenario: &'a str,
+
+    #[serde(skip_serializing_if = "str::is_empty")]
+    serial: &'a str,
+
+    /// Variables the run was started with, such as a lot code.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    variables: &'a BTreeMap<String, String>,
+
+    unit: String,
+    unit_type: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operator: Option<String>,
+
+    message: String,
+}
+
+/// Split "scheme://host:port/rest" into "host:port" and "/rest".
+pub fn split_url<'a>(url: &'a str, scheme: &str, default_port: u16) -> io::Result<(String, &'a str)> {
+    let prefix = format!("{}://", scheme);
+    let rest = if url.starts_with(&prefix) {
+        &url[prefix.len()..]
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported url {}, only {} is supported", url, prefix),
+        ));
+    };
+    let (address, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, ""),
+    };
+    let address = if address.contains(':') {
+        address.to_owned()
+    } else {
+        format!("{}:{}", address, default_port)
+    };
+    Ok((address, path))
+}
+
+/// Turns messages into JSON documents, and collects them into batches.
+struct Batcher {
+    station: Option<String>,
+    batch_size: usize,
+    scenario: String,
+    serial: String,
+    variables: BTreeMap<String, String>,
+    documents: Vec<String>,
+
+    /// How many documents have been added since the batch was last sent, or tried to be.
+    added: usize,
+}
+
+impl Batcher {
+    fn new(batch_size: usize, station: Option<String>) -> Batcher {
+        Batcher {
+            station: station,
+            batch_size: batch_size,
+            scenario: "".to_owned(),
+            serial: "".to_owned(),
+            variables: BTreeMap::new(),
+            documents: vec![],
+            added: 0,
+        }
+    }
+
+    /// Add a message to the batch.  Returns true if the batch is full and should be sent.
+    fn add(&mut self, msg: ManagerStatusMessage) -> io::Result<bool> {
+        let (event, timestamp, unit, result, operator, message) = match msg {
+            ManagerStatusMessage::Start(scenario) => {
+                self.scenario = scenario.id().clone();
+                self.serial = "".to_owned();
+                self.variables.clear();
+                ("start", now(), scenario, None, None, "".to_owned())
+            }
+            ManagerStatusMessage::Log(entry) => {
+                match Record::from_log(&entry) {
+                    Some(Record::Serial(serial)) => self.serial = serial,
+                    Some(Record::Variable(key, value)) => {
+                        self.variables.insert(key, value);
+                    }
+                    _ => (),
+                }
+                (
+                    "log",
+                    entry.timestamp().clone(),
+                    entry.id().clone(),
+                    None,
+                    None,
+                    entry.message().clone(),
+                )
+            }
+            ManagerStatusMessage::Running(test) => ("running", now(), test, None, None, "".to_owned()),
+            ManagerStatusMessage::Pass(test, message) => ("pass", now(), test, Some(0), None, message),
+            ManagerStatusMessage::Fail(test, code, message) => ("fail", now(), test, Some(code as i64), None, message),
+            ManagerStatusMessage::Skipped(test, reason) => ("skip", now(), test, None, None, reason),
+            ManagerStatusMessage::Finished(scenario, code, message) => {
+                ("finish", now(), scenario, Some(code as i64), None, message)
+            }
+            ManagerStatusMessage::Audit(entry) => (
+                "audit",
+                entry.timestamp().clone(),
+                entry.id().clone(),
+                None,
+                entry.operator().clone(),
+                format!("{} {}", entry.verb(), entry.arguments()).trim().to_owned(),
+            ),
+            _ => return Ok(false),
+        };
+
+        let document = serde_json::to_string(&Event {
+            timestamp: timestamp,
+            event: event,
+            station: self.station.as_ref(),
+            scenario: &self.scenario,
+            serial: &self.serial,
+            variables: &self.variables,
+            unit: unit.id().clone(),
+            unit_type: format!("{}", unit.kind()),
+            result: result,
+            operator: operator,
+            message: message,
+        })?;
+        self.documents.push(document);
+
+        if event == "finish" {
+            self.scenario = "".to_owned();
+            self.serial = "".to_owned();
+            self.variables.clear();
+        }
+
+        let limit = cmp::max(self.batch_size, 1) * MAX_RETAINED_BATCHES;
+        if self.documents.len() > limit {
+            let excess = self.documents.len() - limit;
+            self.documents.drain(..excess);
+        }
+        self.added += 1;
+        Ok(self.added >= self.batch_size)
+    }
+
+    /// The documents to send, which stay in the batch until they've been accepted.
+    fn take(&mut self) -> &[String] {
+        self.added = 0;
+        &self.documents
+    }
+}
+
+/// Which of a bulk request's documents were rejected only because Elasticsearch was too busy for
+/// them, and may be accepted if they're sent again.  None if `response` can't be made sense of.
+fn retryable(response: &str, count: usize) -> Option<Vec<bool>> {
+    let body = response.splitn(2, "\r\n\r\n").nth(1)?;
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    let items = body.get("items")?.as_array()?;
+    if items.len() != count {
+        return None;
+    }
+    Some(
+        items
+            .iter()
+            .map(|item| {
+                let status = item.get("index").and_then(|i| i.get("status")).and_then(|s| s.as_u64()).unwrap_or(0);
+                status == 429 || status >= 500
+            })
+            .collect(),
+    )
+}
+
+/// Ships events to the Elasticsearch bulk API.
+pub struct ElasticsearchSink {
+    batcher: Batcher,
+
+    /// host:port of the Elasticsearch server.
+    address: String,
+
+    /// The index that events are stored in.
+    index: String,
+}
+
+impl ElasticsearchSink {
+    /// Create a sink that indexes events into the given URL, which must be
+    /// of the form "http://host:port/index".
+    pub fn new(url: &str, batch_size: usize, station: Option<String>) -> io::Result<ElasticsearchSink> {
+        let (address, path) = split_url(url, "http", 9200)?;
+        let index = path.trim_matches('/');
+        if index.is_empty() || index.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("url {} must name an index, e.g. http://localhost:9200/exclave", url),
+            ));
+        }
+        Ok(ElasticsearchSink {
+            batcher: Batcher::new(batch_size, station),
+            address: address,
+            index: index.to_owned(),
+        })
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.batcher.documents.is_empty() {
+            return Ok(());
+        }
+        let action = format!("{{\"index\":{{\"_index\":{}}}}}\n", serde_json::to_string(&self.index)?);
+        let mut body = String::new();
+        for document in self.batcher.take() {
+            body.push_str(&action);
+            body.push_str(document);
+            body.push('\n');
+        }
+
+        let mut stream = TcpStream::connect(&*self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(SHIP_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(SHIP_TIMEOUT_SECS)))?;
+        write!(
+            stream,
+            "POST /_bulk HTTP/1.0\r\nHost: {}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\n\r\n{}",
+            self.address,
+            body.len(),
+            body
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        let mut response = String::new();
+        reader.read_to_string(&mut response)?;
+        match status.split_whitespace().nth(1) {
+            // The bulk API reports success overall even if individual documents were rejected.
+            Some(code) if code.starts_with('2') && !response.contains("\"errors\":true") => {
+                self.batcher.documents.clear();
+                Ok(())
+            }
+            Some(code) if code.starts_with('2') => {
+                // Anything rejected for good, e.g. because it doesn't fit the index's mapping, would
+                // only be rejected again, and the rest are already indexed.
+                if let Some(retry) = retryable(&response, self.batcher.documents.len()) {
+                    let documents = mem::replace(&mut self.batcher.documents, vec![]);
+                    self.batcher.documents = documents
+                        .into_iter()
+                        .zip(retry)
+                        .filter(|&(_, retry)| retry)
+                        .map(|(document, _)| document)
+                        .collect();
+                }
+                Err(io::Error::new(io::ErrorKind::Other, "elasticsearch rejected some events"))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("elasticsearch rejected events: {}", status.trim()),
+            )),
+        }
+    }
+}
+
+impl LogSink for ElasticsearchSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        if self.batcher.add(msg)? {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for ElasticsearchSink {
+    fn drop(&mut self) {
+        self.send().ok();
+    }
+}
+
+/// Ships events to a Logstash tcp input using the json_lines codec.
+pub struct LogstashSink {
+    batcher: Batcher,
+
+    /// host:port of the Logstash input.
+    address: String,
+
+    /// The connection to Logstash, opened when the first batch is sent.
+    connection: Option<TcpStream>,
+}
+
+impl LogstashSink {
+    /// Create a sink that sends events to the given URL, which must be of
+    /// the form "tcp://host:port".
+    pub fn new(url: &str, batch_size: usize, station: Option<String>) -> io::Result<LogstashSink> {
+        let (address, _) = split_url(url, "tcp", 5000)?;
+        Ok(LogstashSink {
+            batcher: Batcher::new(batch_size, station),
+            address: address,
+            connection: None,
+        })
+    }
+
+    fn send_once(&mut self, body: &[u8]) -> io::Result<()> {
+        if self.connection.is_none() {
+            let stream = TcpStream::connect(&*self.address)?;
+            stream.set_write_timeout(Some(Duration::from_secs(SHIP_TIMEOUT_SECS)))?;
+            self.connection = Some(stream);
+        }
+        let result = self.connection.as_mut().unwrap().write_all(body);
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.batcher.documents.is_empty() {
+            return Ok(());
+        }
+        let mut body = String::new();
+        for document in self.batcher.take() {
+            body.push_str(document);
+            body.push('\n');
+        }
+
+        // Logstash may have been restarted since the last batch, so have one more go on a new connection.
+        let had_connection = self.connection.is_some();
+        match self.send_once(body.as_bytes()) {
+            Err(_) if had_connection => self.send_once(body.as_bytes()),
+            result => result,
+        }?;
+        self.batcher.documents.clear();
+        Ok(())
+    }
+}
+
+impl LogSink for LogstashSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        if self.batcher.add(msg)? {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for LogstashSink {
+    fn drop(&mut self) {
+        self.send().ok();
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logsinks::LogSink;
+use unit::UnitName;
+use unitmanager::ManagerStatusMessage;
+
+/// How long to wait on the metrics server before giving up on a push.
+const PUSH_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Default)]
+struct ScenarioCounts {
+    runs: u64,
+    passed: u64,
+    failed: u64,
+}
+
+#[derive(Default)]
+struct TestCounts {
+    passed: u64,
+    failed: u64,
+    skipped: u64,
+    duration_sum: f64,
+    duration_count: u64,
+    last_duration: f64,
+}
+
+/// Running totals of scenario and test results, shared by the metrics sinks.
+#[derive(Default)]
+struct Metrics {
+    scenarios: BTreeMap<String, ScenarioCounts>,
+    tests: BTreeMap<String, TestCounts>,
+
+    /// The scenario currently running, and when it started.
+    current: Option<(String, Instant)>,
+
+    /// When each currently-running test started.
+    running: BTreeMap<String, Instant>,
+}
+
+/// What happened as a result of a message, for sinks that push updates as they go.
+enum MetricsUpdate {
+    Test(String /* scenario */, String /* test */, &'static str /* result */, Option<f64> /* duration */),
+    Scenario(String /* scenario */, u32 /* result code */, Option<f64> /* duration */),
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+impl Metrics {
+    fn scenario_name(&self) -> String {
+        self.current.as_ref().map(|c| c.0.clone()).unwrap_or_default()
+    }
+
+    fn test_finished(&mut self, test: &UnitName, result: &'static str) -> MetricsUpdate {
+        let duration = self.running.remove(test.id()).map(|start| seconds(start.elapsed()));
+        {
+            let counts = self.tests.entry(test.id().clone()).or_default();
+            match result {
+                "pass" => counts.passed += 1,
+                "fail" => counts.failed += 1,
+                _ => counts.skipped += 1,
+            }
+            if let Some(d) = duration {
+                counts.duration_sum += d;
+                counts.duration_count += 1;
+                counts.last_duration = d;
+            }
+        }
+        MetricsUpdate::Test(self.scenario_name(), test.id().clone(), result, duration)
+    }
+
+    /// Update the totals from a message, returning what changed, if anything.
+    fn update(&mut self, msg: &ManagerStatusMessage) -> Option<MetricsUpdate> {
+        match *msg {
+            ManagerStatusMessage::Start(ref scenario) => {
+                self.current = Some((scenario.id().clone(), Instant::now()));
+                self.running.clear();
+                None
+            }
+            ManagerStatusMessage::Running(ref test) => {
+                self.running.insert(test.id().clone(), Instant::now());
+                None
+            }
+            ManagerStatusMessage::Pass(ref test, _) => Some(self.test_finished(test, "pass")),
+            ManagerStatusMessage::Fail(ref test, _, _) => Some(self.test_finished(test, "fail")),
+            ManagerStatusMessage::Skipped(ref test, _) => Some(self.test_finished(test, "skip")),
+            ManagerStatusMessage::Finished(ref scenario, code, _) => {
+                let duration = match self.current.take() {
+                    Some((ref name, start)) if name == scenario.id() => Some(seconds(start.elapsed())),
+                    _ => None,
+                };
+                let counts = self.scenarios
+                    .entry(scenario.id().clone())
+                    .or_default();
+                counts.runs += 1;
+                // Scenarios finish with 200 when every test passed.
+                if code == 200 {
+                    counts.passed += 1;
+                } else {
+                    counts.failed += 1;
+                }
+                Some(MetricsUpdate::Scenario(scenario.id().clone(), code, duration))
+            }
+            _ => None,
+        }
+    }
+
+    /// Render the totals in the Prometheus text exposition format.
+    fn prometheus_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP exclave_runs_total Scenario runs that have finished.\n");
+        out.push_str("# TYPE exclave_runs_total counter\n");
+        for (name, counts) in &self.scenarios {
+            out.push_str(&format!("exclave_runs_total{{scenario=\"{}\",result=\"pass\"}} {}\n", prometheus_escape(name), counts.passed));
+            out.push_str(&format!("exclave_runs_total{{scenario=\"{}\",result=\"fail\"}} {}\n", prometheus_escape(name), counts.failed));
+        }
+        out.push_str("# HELP exclave_tests_total Tests that have finished.\n");
+        out.push_str("# TYPE exclave_tests_total counter\n");
+        for (name, counts) in &self.tests {
+            for &(result, count) in &[("pass", counts.passed), ("fail", counts.failed), ("skip", counts.skipped)] {
+                out.push_str(&format!("exclave_tests_total{{test=\"{}\",result=\"{}\"}} {}\n", prometheus_escape(name), result, count));
+            }
+        }
+        out.push_str("# HELP exclave_test_duration_seconds How long tests take to run.\n");
+        out.push_str("# TYPE exclave_test_duration_seconds summary\n");
+        for (name, counts) in &self.tests {
+            out.push_str(&format!("exclave_test_duration_seconds_sum{{test=\"{}\"}} {}\n", prometheus_escape(name), counts.duration_sum));
+            out.push_str(&format!("exclave_test_duration_seconds_count{{test=\"{}\"}} {}\n", prometheus_escape(name), counts.duration_count));
+        }
+        out.push_str("# HELP exclave_test_last_duration_seconds How long the most recent run of each test took.\n");
+        out.push_str("# TYPE exclave_test_last_duration_seconds gauge\n");
+        for (name, counts) in &self.tests {
+            out.push_str(&format!("exclave_test_last_duration_seconds{{test=\"{}\"}} {}\n", prometheus_escape(name), counts.last_duration));
+        }
+        out
+    }
+}
+
+pub fn prometheus_escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+/// Escape a tag key or value for the InfluxDB line protocol.
+fn influx_escape(s: &str) -> String {
+    s.replace("\\", "\\\\")
+        .replace(",", "\\,")
+        .replace("=", "\\=")
+        .replace(" ", "\\ ")
+}
+
+/// Pushes metrics to InfluxDB, using its HTTP line protocol API.
+pub struct InfluxSink {
+    metrics: Metrics,
+
+    /// host:port of the InfluxDB server.
+    address: String,
+
+    /// Path and query of the write endpoint, e.g. "/write?db=exclave".
+    request_path: String,
+
+    /// Optional name of this station, added as a tag to every point.
+    station: Option<String>,
+}
+
+impl InfluxSink {
+    /// Create a sink that writes to the given URL, which must be of
+    /// the form "http://host:port/write?db=database".
+    pub fn new(url: &str, station: Option<String>) -> io::Result<InfluxSink> {
+        let rest = if url.starts_with("http://") {
+            &url[7..]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported url {}, only http:// is supported", url)));
+        };
+        let (address, request_path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/write"),
+        };
+        let address = if address.contains(':') {
+            address.to_owned()
+        } else {
+            format!("{}:8086", address)
+        };
+
+        Ok(InfluxSink {
+            metrics: Metrics::default(),
+            address: address,
+            request_path: request_path.to_owned(),
+            station: station,
+        })
+    }
+
+    fn tags(&self, pairs: &[(&str, &str)]) -> String {
+        let mut tags = String::new();
+        if let Some(ref station) = self.station {
+            tags.push_str(&format!(",station={}", influx_escape(station)));
+        }
+        for &(key, value) in pairs {
+            if !value.is_empty() {
+                tags.push_str(&format!(",{}={}", key, influx_escape(value)));
+            }
+        }
+        tags
+    }
+
+    fn line(&self, update: &MetricsUpdate) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        let timestamp = now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64;
+        match *update {
+            MetricsUpdate::Test(ref scenario, ref test, result, duration) => {
+                let mut fields = format!("result=\"{}\"", result);
+                if let Some(d) = duration {
+                    fields.push_str(&format!(",duration={}", d));
+                }
+                format!("exclave_test{} {} {}", self.tags(&[("scenario", scenario), ("test", test)]), fields, timestamp)
+            }
+            MetricsUpdate::Scenario(ref scenario, code, duration) => {
+                let counts = &self.metrics.scenarios[scenario];
+                let mut fields = format!(
+                    "result_code={}i,runs_total={}i,runs_passed={}i,runs_failed={}i",
+                    code, counts.runs, counts.passed, counts.failed
+                );
+                if let Some(d) = duration {
+                    fields.push_str(&format!(",duration={}", d));
+                }
+                format!("exclave_run{} {} {}", self.tags(&[("scenario", scenario)]), fields, timestamp)
+            }
+        }
+    }
+
+    fn push(&self, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&*self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(PUSH_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(PUSH_TIMEOUT_SECS)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            self.request_path,
+            self.address,
+            body.len(),
+            body
+        )?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::Other, format!("influxdb rejected metrics: {}", status.trim()))),
+        }
+    }
+}
+
+impl LogSink for InfluxSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        match self.metrics.update(&msg) {
+            None => Ok(()),
+            Some(update) => {
+                let line = self.line(&update);
+                self.push(&line)
+            }
+        }
+    }
+}
+
+/// Exposes metrics over HTTP for Prometheus to scrape.
+pub struct PrometheusSink {
+    metrics: Arc<Mutex<Metrics>>,
+    address: String,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl PrometheusSink {
+    /// Start serving metrics on the given address, e.g. "0.0.0.0:9102".
+    pub fn new(address: &str) -> io::Result<PrometheusSink> {
+        let listener = listen(address)?;
+        let local_address = format!("{}", listener.local_addr()?);
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thr_metrics = metrics.clone();
+        let thr_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thr_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    serve_metrics(stream, &|| thr_metrics.lock().unwrap().prometheus_text()).ok();
+                }
+            }
+        });
+
+        Ok(PrometheusSink {
+            metrics: metrics,
+            address: local_address,
+            shutdown: shutdown,
+        })
+    }
+
+}
+
+/// Listen for Prometheus on `address`.  Builds without the "network" feature don't listen on anything.
+#[cfg(feature = "network")]
+pub fn listen(address: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+}
+
+#[cfg(not(feature = "network"))]
+pub fn listen(_address: &str) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "serving metrics isn't supported by this build of exclave",
+    ))
+}
+
+/// Answer a single HTTP request for /metrics with the text that `render` makes, in the
+/// Prometheus text exposition format.  Anything else gets a 404.
+pub fn serve_metrics(mut stream: TcpStream, render: &dyn Fn() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(PUSH_TIMEOUT_SECS)))?;
+
+    // Only the request line matters, but the headers must be drained before replying.
+    let mut request = String::new();
+    {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        reader.read_line(&mut request)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    if path == "/metrics" || path == "/" {
+        let body = render();
+        write!(
+            stream,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+    } else {
+        write!(stream, "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+    }
+    stream.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+impl LogSink for PrometheusSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        self.metrics.lock().unwrap().update(&msg);
+        Ok(())
+    }
+}
+
+impl Drop for PrometheusSink {
+    fn drop(&mut self) {
+        // Wake the listener up so it notices it should stop, and frees the port.
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Ok(mut stream) = TcpStream::connect(&*self.address) {
+            let mut discard = vec![];
+            stream.write_all(b"GET /shutdown HTTP/1.0\r\n\r\n").ok();
+            stream.read_to_end(&mut discard).ok();
+        }
+    }
+}
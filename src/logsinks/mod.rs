@@ -0,0 +1,100 @@
+// Built-in logger backends.  A Logger unit either feeds its messages to an
+// external program, or to one of the sinks in here.
+use std::io;
+
+use unit::UnitKind;
+use unitbroadcaster::{LogEntry, LogType};
+use unitmanager::ManagerStatusMessage;
+
+pub mod csv;
+pub mod elastic;
+pub mod metrics;
+pub mod mqtt;
+
+/// Something that a Logger can write its messages to.
+/// Sinks are driven from the logger's writer thread, so they must be `Send`.
+pub trait LogSink: Send {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()>;
+
+    /// Called whenever the logger has caught up with its queue.
+    /// Sinks that batch their output should send what they have.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Structured records that tests can report by printing specially-formatted lines.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    /// `MEASURE <name> <value> [<unit>]`
+    Measure(String /* name */, String /* value */, Option<String> /* unit */),
+
+    /// `SERIAL <serial number>`
+    Serial(String),
+
+    /// `VAR <key>=<value>`, logged by the scenario for each variable it was started with,
+    /// and for each tag that's attached to the run after it started.
+    Variable(String /* key */, String /* value */),
+
+    /// `TAG <key>=<value>`, for something a test found out about the device that the run should
+    /// be tagged with, e.g. the firmware version it read back.
+    Tag(String /* key */, String /* value */),
+
+    /// `ARTIFACT <path>`, for a file the test stored, relative to the artifact directory.
+    Artifact(String),
+
+    /// `MARGIN <name> <margin> [<unit>]`, logged after a measurement that has a limit:
+    /// how far it was inside the limit, or outside it if the margin is negative.
+    Margin(String /* name */, String /* margin */, Option<String> /* unit */),
+}
+
+impl Record {
+    /// Pick a record out of a line a test printed to stdout, if there is one.
+    /// Serial numbers and variables may also come from the scenario, e.g. when a trigger supplied them.
+    pub fn from_log(entry: &LogEntry) -> Option<Record> {
+        if *entry.kind() != LogType::Info {
+            return None;
+        }
+        let from_test = *entry.id().kind() == UnitKind::Test;
+        if !from_test && *entry.id().kind() != UnitKind::Scenario {
+            return None;
+        }
+        let mut words = entry.message().split_whitespace();
+        match words.next() {
+            Some("MEASURE") if from_test => {
+                let name = words.next()?.to_owned();
+                let value = words.next()?.to_owned();
+                let unit = words.next().map(|s| s.to_owned());
+                Some(Record::Measure(name, value, unit))
+            }
+            Some("MARGIN") if from_test => {
+                let name = words.next()?.to_owned();
+                let margin = words.next()?.to_owned();
+                let unit = words.next().map(|s| s.to_owned());
+                Some(Record::Margin(name, margin, unit))
+            }
+            Some("SERIAL") => words.next().map(|s| Record::Serial(s.to_owned())),
+            Some("ARTIFACT") if from_test => {
+                let path = entry.message()["ARTIFACT".len()..].trim();
+                if path.is_empty() {
+                    None
+                } else {
+                    Some(Record::Artifact(path.to_owned()))
+                }
+            }
+            Some("TAG") if from_test => {
+                let mut parts = entry.message()["TAG".len()..].trim().splitn(2, '=');
+                let key = parts.next().filter(|k| !k.is_empty())?.to_owned();
+                let value = parts.next()?.to_owned();
+                Some(Record::Tag(key, value))
+            }
+            Some("VAR") => {
+                let mut parts = entry.message()["VAR".len()..].trim().splitn(2, '=');
+                let key = parts.next()?.to_owned();
+                let value = parts.next()?.to_owned();
+                Some(Record::Variable(key, value))
+            }
+            _ => None,
+        }
+    }
+}
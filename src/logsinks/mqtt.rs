@@ -0,0 +1,306 @@
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::time::Duration;
+
+use logsinks::{LogSink, Record};
+use timestamp;
+use unitmanager::ManagerStatusMessage;
+
+/// How long to wait on the broker before giving up on a publish.
+const BROKER_TIMEOUT_SECS: u64 = 5;
+
+/// The port MQTT brokers listen on when none is given.
+const DEFAULT_PORT: u16 = 1883;
+
+#[derive(Serialize)]
+struct TestSummary {
+    test: String,
+    result: &'static str,
+    message: String,
+}
+
+/// Published to "<topic>/runs" when a scenario finishes.
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    timestamp: String,
+    station: &'a Option<String>,
+    serial: &'a str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    variables: &'a BTreeMap<String, String>,
+    scenario: String,
+    result: u32,
+    message: String,
+    tests: &'a [TestSummary],
+}
+
+/// Published to "<topic>/failures" as soon as a test fails.
+#[derive(Serialize)]
+struct TestFailure<'a> {
+    timestamp: String,
+    station: &'a Option<String>,
+    serial: &'a str,
+    scenario: &'a str,
+    test: String,
+    result: i32,
+    message: String,
+}
+
+/// Append an MQTT length-prefixed string.
+fn put_string(packet: &mut Vec<u8>, s: &[u8]) {
+    packet.push((s.len() >> 8) as u8);
+    packet.push(s.len() as u8);
+    packet.extend_from_slice(s);
+}
+
+/// Frame a packet body with its fixed header.
+fn frame(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Publishes run summaries and test failures to an MQTT broker.
+///
+/// This is independent of any interface that might also talk MQTT, so results
+/// can go to a different broker, with different credentials, and keep flowing
+/// while the interface is restarted.
+pub struct MqttSink {
+    /// host:port of the broker.
+    address: String,
+
+    /// Prefix for the topics that get published to.
+    topic: String,
+
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+
+    /// Optional name of this station, added to every message.
+    station: Option<String>,
+
+    /// The connection to the broker, opened on the first publish.
+    connection: Option<TcpStream>,
+
+    /// The last packet identifier used.
+    packet_id: u16,
+
+    scenario: String,
+    serial: String,
+    variables: BTreeMap<String, String>,
+    tests: Vec<TestSummary>,
+}
+
+impl MqttSink {
+    /// Create a sink that publishes to the broker at the given URL, which
+    /// must be of the form "mqtt://host[:port]".
+    pub fn new(
+        url: &str,
+        topic: String,
+        username: Option<String>,
+        password: Option<String>,
+        station: Option<String>,
+    ) -> io::Result<MqttSink> {
+        let rest = if url.starts_with("mqtt://") {
+            &url[7..]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported url {}, only mqtt:// is supported", url)));
+        };
+        let address = rest.trim_end_matches('/');
+        let address = if address.contains(':') {
+            address.to_owned()
+        } else {
+            format!("{}:{}", address, DEFAULT_PORT)
+        };
+
+        // Brokers kick off older connections with the same client id, so make it unique per station.
+        let client_id = match station {
+            Some(ref s) => format!("exclave-{}", s),
+            None => format!("exclave-{}", process::id()),
+        };
+
+        Ok(MqttSink {
+            address: address,
+            topic: topic.trim_end_matches('/').to_owned(),
+            client_id: client_id,
+            username: username,
+            password: password,
+            station: station,
+            connection: None,
+            packet_id: 0,
+            scenario: "".to_owned(),
+            serial: "".to_owned(),
+            variables: BTreeMap::new(),
+            tests: vec![],
+        })
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&*self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(BROKER_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(BROKER_TIMEOUT_SECS)))?;
+
+        let mut flags = 0x02; // Clean session
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+        let mut body = vec![];
+        put_string(&mut body, b"MQTT");
+        body.push(4); // Protocol level 3.1.1
+        body.push(flags);
+        // A keepalive of 0 means the broker won't drop us when things are quiet.
+        body.extend_from_slice(&[0, 0]);
+        put_string(&mut body, self.client_id.as_bytes());
+        if let Some(ref username) = self.username {
+            put_string(&mut body, username.as_bytes());
+        }
+        if let Some(ref password) = self.password {
+            put_string(&mut body, password.as_bytes());
+        }
+        stream.write_all(&frame(0x10, &body))?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != 0x20 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "mqtt broker sent an unexpected reply to connect"));
+        }
+        match connack[3] {
+            0 => Ok(stream),
+            4 | 5 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "mqtt broker rejected our credentials")),
+            code => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("mqtt broker refused connection: code {}", code))),
+        }
+    }
+
+    /// Publish a message at QoS 1, and wait for the broker to acknowledge it.
+    fn publish_once(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        let packet_id = self.packet_id;
+
+        let mut body = vec![];
+        put_string(&mut body, topic.as_bytes());
+        body.push((packet_id >> 8) as u8);
+        body.push(packet_id as u8);
+        body.extend_from_slice(payload);
+
+        let stream = self.connection.as_mut().unwrap();
+        stream.write_all(&frame(0x32, &body))?;
+
+        let mut puback = [0; 4];
+        stream.read_exact(&mut puback)?;
+        if puback[0] != 0x40 || ((puback[2] as u16) << 8 | puback[3] as u16) != packet_id {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "mqtt broker did not acknowledge publish"));
+        }
+        Ok(())
+    }
+
+    /// Publish a message, reconnecting once if the broker went away since the last one.
+    fn publish(&mut self, subtopic: &str, payload: &[u8]) -> io::Result<()> {
+        let topic = format!("{}/{}", self.topic, subtopic);
+        let had_connection = self.connection.is_some();
+        match self.publish_once(&topic, payload) {
+            Ok(()) => Ok(()),
+            Err(_) if had_connection => {
+                self.connection = None;
+                self.publish_once(&topic, payload).map_err(|e| {
+                    self.connection = None;
+                    e
+                })
+            }
+            Err(e) => {
+                self.connection = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn add_result(&mut self, test: String, result: &'static str, message: String) {
+        self.tests.retain(|t| t.test != test);
+        self.tests.push(TestSummary {
+            test: test,
+            result: result,
+            message: message,
+        });
+    }
+}
+
+impl LogSink for MqttSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> io::Result<()> {
+        match msg {
+            ManagerStatusMessage::Start(scenario) => {
+                self.scenario = scenario.id().clone();
+                self.serial = "".to_owned();
+                self.variables.clear();
+                self.tests.clear();
+            }
+            ManagerStatusMessage::Log(entry) => {
+                match Record::from_log(&entry) {
+                    Some(Record::Serial(serial)) => self.serial = serial,
+                    Some(Record::Variable(key, value)) => {
+                        self.variables.insert(key, value);
+                    }
+                    _ => (),
+                }
+            }
+            ManagerStatusMessage::Pass(test, message) => self.add_result(test.id().clone(), "pass", message),
+            ManagerStatusMessage::Skipped(test, message) => self.add_result(test.id().clone(), "skip", message),
+            ManagerStatusMessage::Fail(test, code, message) => {
+                let payload = serde_json::to_vec(&TestFailure {
+                    timestamp: timestamp::now(),
+                    station: &self.station,
+                    serial: &self.serial,
+                    scenario: &self.scenario,
+                    test: test.id().clone(),
+                    result: code,
+                    message: message.clone(),
+                })?;
+                self.add_result(test.id().clone(), "fail", message);
+                self.publish("failures", &payload)?;
+            }
+            ManagerStatusMessage::Finished(scenario, code, message) => {
+                let payload = serde_json::to_vec(&RunSummary {
+                    timestamp: timestamp::now(),
+                    station: &self.station,
+                    serial: &self.serial,
+                    variables: &self.variables,
+                    scenario: scenario.id().clone(),
+                    result: code,
+                    message: message,
+                    tests: &self.tests,
+                })?;
+                self.tests.clear();
+                self.publish("runs", &payload)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        if let Some(ref mut stream) = self.connection {
+            stream.write_all(&frame(0xe0, &[])).ok();
+        }
+    }
+}
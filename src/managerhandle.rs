@@ -0,0 +1,135 @@
+// The UnitManager runs on a thread of its own, and everything else reaches it
+// through a ManagerHandle, which sends it requests and waits for the replies.
+// The units it looks after are kept in Rc<RefCell<..>>s, and with only one
+// thread ever touching them, one request can't find something still borrowed
+// partway through another, however the calls into the manager happen to nest.
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use config::Config;
+use supervisor::panic_message;
+use unit::UnitName;
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+use unitmanager::{ManagerControlMessage, UnitManager};
+
+/// Something for the manager to do, on its own thread.
+type Request = Box<dyn FnOnce(&UnitManager) + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManagerError {
+    /// The manager didn't reply in time.  It still carries out the request once it gets to it.
+    Timeout,
+
+    /// The manager won't reply, because the request panicked or the manager has stopped.
+    NoReply,
+}
+
+impl ManagerError {
+    /// A short, fixed code for the error, for programs to act on.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ManagerError::Timeout => "timeout",
+            ManagerError::NoReply => "no-reply",
+        }
+    }
+}
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManagerError::Timeout => write!(f, "the unit manager didn't reply in time"),
+            ManagerError::NoReply => write!(f, "the unit manager didn't reply"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ManagerHandle {
+    requests: Sender<Request>,
+    control_sender: Sender<ManagerControlMessage>,
+}
+
+impl ManagerHandle {
+    /// Start a manager on a thread of its own.  Requests are dealt with one at a time, in the order they were made.
+    pub fn start(broadcaster: &UnitBroadcaster, config: &Arc<Mutex<Config>>) -> ManagerHandle {
+        let (requests, receiver) = channel::<Request>();
+        let (started, control) = channel();
+        let bc = broadcaster.clone();
+        let cfg = config.clone();
+        thread::spawn(move || {
+            // The manager isn't Send, so it has to be made here.
+            let manager = UnitManager::new(&bc, &cfg);
+            started.send(manager.get_control_channel()).ok();
+            for request in receiver {
+                // A request that panics only loses its own reply, rather than every request after it.
+                if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| request(&manager))) {
+                    let message = format!("request to the unit manager panicked: {}", panic_message(&e));
+                    bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("manager"), message)));
+                }
+            }
+        });
+
+        ManagerHandle {
+            requests: requests,
+            control_sender: control.recv().expect("unit manager thread exited before it started"),
+        }
+    }
+
+    /// Have the manager call `f`, and wait for what it returns.
+    pub fn call<T, F>(&self, f: F) -> Result<T, ManagerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&UnitManager) -> T + Send + 'static,
+    {
+        self.request(f)?.recv().map_err(|_| ManagerError::NoReply)
+    }
+
+    /// Have the manager call `f`, but only wait `timeout` for what it returns, e.g. so that the
+    /// main loop isn't held up by a manager that's busy loading units.
+    pub fn call_timeout<T, F>(&self, timeout: Duration, f: F) -> Result<T, ManagerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&UnitManager) -> T + Send + 'static,
+    {
+        self.request(f)?.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => ManagerError::Timeout,
+            RecvTimeoutError::Disconnected => ManagerError::NoReply,
+        })
+    }
+
+    /// Have the manager call `f`, without waiting for it to.
+    pub fn send<F>(&self, f: F)
+    where
+        F: FnOnce(&UnitManager) + Send + 'static,
+    {
+        // The manager is only gone once exclave is exiting.
+        self.requests.send(Box::new(f)).ok();
+    }
+
+    /// Wait for the manager to deal with everything it's been sent so far.
+    pub fn flush(&self) {
+        self.call(|_| ()).ok();
+    }
+
+    pub fn get_control_channel(&self) -> Sender<ManagerControlMessage> {
+        self.control_sender.clone()
+    }
+
+    fn request<T, F>(&self, f: F) -> Result<Receiver<T>, ManagerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&UnitManager) -> T + Send + 'static,
+    {
+        let (reply, receiver) = channel();
+        self.requests
+            .send(Box::new(move |manager: &UnitManager| {
+                reply.send(f(manager)).ok();
+            }))
+            .map_err(|_| ManagerError::NoReply)?;
+        Ok(receiver)
+    }
+}
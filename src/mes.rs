@@ -0,0 +1,215 @@
+// Reporting the result of each run to the factory's MES as soon as it finishes,
+// so that route control doesn't need a separate program watching exclave's logs.
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use logsinks::elastic::split_url;
+use program::Exec;
+use summary::RunResult;
+use unit::UnitName;
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+
+/// How long to wait on the MES before counting a delivery as failed.
+const DELIVERY_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait before retrying after the first failed delivery.  The wait doubles
+/// after every failure in a row, up to the maximum, so a long outage isn't hammered.
+const RETRY_MIN_SECS: u64 = 5;
+const RETRY_MAX_SECS: u64 = 300;
+
+/// Where results go.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MesTarget {
+    /// POSTed as JSON to an http:// URL.
+    Url(String),
+
+    /// Given as JSON on the standard input of a command, which exits 0 once it has delivered it.
+    Command(String),
+}
+
+/// A result waiting to be delivered, along with the copy of it in the queue directory, if there is one.
+struct Pending {
+    body: String,
+    path: Option<PathBuf>,
+}
+
+/// Delivers results in the background, in the order the runs finished.  Results that
+/// can't be delivered are retried until they are, and if there's a queue directory
+/// they're kept there in the meantime, so that they survive exclave being restarted.
+pub struct MesReporter {
+    target: MesTarget,
+    queue_directory: Option<PathBuf>,
+    sender: Sender<Pending>,
+}
+
+impl MesReporter {
+    pub fn start(target: MesTarget, queue_directory: Option<PathBuf>, broadcaster: &UnitBroadcaster) -> MesReporter {
+        let (sender, receiver) = channel::<Pending>();
+        let mut queue = VecDeque::new();
+        let id = UnitName::internal("mes");
+
+        // Anything left over from last time goes first.
+        if let Some(ref directory) = queue_directory {
+            match queued(directory) {
+                Ok(pending) => queue.extend(pending),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(
+                    id.clone(),
+                    format!("unable to read queued results from {}: {}", directory.to_string_lossy(), e),
+                ))),
+            }
+        }
+
+        let thr_target = target.clone();
+        let bc = broadcaster.clone();
+        thread::spawn(move || {
+            let mut retry = Duration::from_secs(RETRY_MIN_SECS);
+            loop {
+                // Wait for a result if there's nothing to do, or pick up any new ones if there is.
+                if queue.is_empty() {
+                    match receiver.recv() {
+                        Ok(pending) => queue.push_back(pending),
+                        Err(_) => return,
+                    }
+                }
+                while let Ok(pending) = receiver.try_recv() {
+                    queue.push_back(pending);
+                }
+
+                let result = deliver(&thr_target, &queue.front().unwrap().body);
+                match result {
+                    Ok(()) => {
+                        let delivered = queue.pop_front().unwrap();
+                        if let Some(path) = delivered.path {
+                            fs::remove_file(path).ok();
+                        }
+                        if retry > Duration::from_secs(RETRY_MIN_SECS) {
+                            bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), "delivering results again".to_owned())));
+                        }
+                        retry = Duration::from_secs(RETRY_MIN_SECS);
+                    }
+                    Err(e) => {
+                        bc.broadcast(&UnitEvent::Log(LogEntry::new_error(
+                            id.clone(),
+                            format!("unable to deliver result, {} waiting, retrying in {}s: {}", queue.len(), retry.as_secs(), e),
+                        )));
+                        // Keep collecting new results while waiting to retry.
+                        match receiver.recv_timeout(retry) {
+                            Ok(pending) => queue.push_back(pending),
+                            Err(RecvTimeoutError::Timeout) => (),
+                            // exclave is exiting.  Whatever is in the queue directory is delivered next time.
+                            Err(RecvTimeoutError::Disconnected) => return,
+                        }
+                        retry = (retry * 2).min(Duration::from_secs(RETRY_MAX_SECS));
+                    }
+                }
+            }
+        });
+
+        MesReporter {
+            target: target,
+            queue_directory: queue_directory,
+            sender: sender,
+        }
+    }
+
+    pub fn target(&self) -> &MesTarget {
+        &self.target
+    }
+
+    pub fn queue_directory(&self) -> &Option<PathBuf> {
+        &self.queue_directory
+    }
+
+    /// Queue a result to be delivered.  It's written to the queue directory first, if there is one.
+    pub fn report(&self, result: &RunResult) -> io::Result<()> {
+        let body = serde_json::to_string(result)?;
+        let path = match self.queue_directory {
+            None => None,
+            Some(ref directory) => {
+                fs::create_dir_all(directory)?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+                // Named so that they sort in the order the runs finished.
+                let path = directory.join(format!("{:012}.{:09}.json", now.as_secs(), now.subsec_nanos()));
+                File::create(&path)?.write_all(body.as_bytes())?;
+                Some(path)
+            }
+        };
+        self.sender
+            .send(Pending { body: body, path: path })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "result delivery has stopped"))
+    }
+}
+
+/// Results that were queued but not delivered, oldest first.
+fn queued(directory: &Path) -> io::Result<Vec<Pending>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+        .collect();
+    paths.sort();
+
+    let mut pending = vec![];
+    for path in paths {
+        let mut body = String::new();
+        File::open(&path)?.read_to_string(&mut body)?;
+        pending.push(Pending { body: body, path: Some(path) });
+    }
+    Ok(pending)
+}
+
+fn deliver(target: &MesTarget, body: &str) -> io::Result<()> {
+    match *target {
+        MesTarget::Url(ref url) => post(url, body),
+        MesTarget::Command(ref command) => run(command, body),
+    }
+}
+
+fn post(url: &str, body: &str) -> io::Result<()> {
+    let (address, path) = split_url(url, "http", 80)?;
+    let path = if path.is_empty() { "/" } else { path };
+    let mut stream = TcpStream::connect(&*address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(DELIVERY_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(DELIVERY_TIMEOUT_SECS)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        address,
+        body.len(),
+        body
+    )?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, format!("MES rejected result: {}", status.trim()))),
+    }
+}
+
+fn run(command: &str, body: &str) -> io::Result<()> {
+    let mut cmd = Exec::new(command);
+    cmd.timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECS));
+    let mut running = cmd
+        .start()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("unable to run {}: {:?}", command, e)))?;
+    {
+        let mut input = running.take_input();
+        input.write_all(body.as_bytes())?;
+    }
+    let waiter = running.waiter();
+    waiter.wait();
+    match waiter.result() {
+        0 => Ok(()),
+        code => Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", command, code))),
+    }
+}
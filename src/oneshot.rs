@@ -0,0 +1,154 @@
+// `exclave run` loads the units, runs a single scenario without anyone
+// at the station, prints how it went, and exits.
+use std::time::Instant;
+
+use exitcode::{ABORTED, FAILED, NOT_RUN, SUCCESS};
+use unit::{UnitKind, UnitName};
+use unitbroadcaster::{LogType, UnitBroadcaster, UnitEvent, UnitStatus};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents, OUT_OF_SPEC_MESSAGE};
+
+/// How a test went.
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+pub struct OneShot {
+    /// Who the requests to the manager come from.
+    id: UnitName,
+    scenario: UnitName,
+    broadcaster: UnitBroadcaster,
+
+    /// When the scenario was started, if it has been.
+    started: Option<Instant>,
+
+    /// Every test that finished or was skipped, in order.
+    tests: Vec<(UnitName, Outcome)>,
+
+    /// Tests that reported measurements outside of their limits.
+    out_of_spec: Vec<UnitName>,
+
+    /// What to exit with, once the scenario is done.
+    exit_code: Option<i32>,
+}
+
+impl OneShot {
+    pub fn new(scenario: &UnitName, broadcaster: &UnitBroadcaster) -> OneShot {
+        OneShot {
+            id: UnitName::internal("run"),
+            scenario: scenario.clone(),
+            broadcaster: broadcaster.clone(),
+            started: None,
+            tests: vec![],
+            out_of_spec: vec![],
+            exit_code: None,
+        }
+    }
+
+    /// How the scenario went, as one of the codes in `exitcode`.  None until it's done.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn process_message(&mut self, msg: &UnitEvent) {
+        if self.exit_code.is_some() {
+            return;
+        }
+        match *msg {
+            // Everything that can be loaded has been once the first rescan is done.
+            UnitEvent::RescanFinish if self.started.is_none() => self.start(),
+            UnitEvent::Status(ref evt) if self.started.is_some() && *evt.name() == self.scenario => match *evt.status() {
+                UnitStatus::LoadFailed(_, ref reason)
+                | UnitStatus::Incompatible(_, ref reason)
+                | UnitStatus::SelectFailed(_, ref reason)
+                | UnitStatus::ActivationFailed(_, ref reason) => self.finish(NOT_RUN, reason),
+                _ => (),
+            },
+            UnitEvent::Log(ref log) if self.started.is_some() => {
+                if *log.id() == self.id && *log.kind() == LogType::Error {
+                    let reason = log.message().clone();
+                    self.finish(NOT_RUN, &reason);
+                } else if *log.id().kind() == UnitKind::Test && log.message().starts_with(OUT_OF_SPEC_MESSAGE) {
+                    self.out_of_spec.push(log.id().clone());
+                }
+            }
+            UnitEvent::ManagerRequest(ref request) if self.started.is_some() => self.manager_request(request),
+            // Something else, e.g. SIGTERM, shut exclave down before the scenario finished.
+            UnitEvent::Shutdown => {
+                self.report(ABORTED, "shut down before the scenario finished");
+                self.exit_code = Some(ABORTED);
+            }
+            _ => (),
+        }
+    }
+
+    fn start(&mut self) {
+        self.started = Some(Instant::now());
+        self.request(ManagerControlMessageContents::StartScenario(Some(self.scenario.clone())));
+    }
+
+    fn manager_request(&mut self, request: &ManagerControlMessage) {
+        let &ManagerControlMessage { ref sender, ref contents } = request;
+        match *contents {
+            ManagerControlMessageContents::TestStarted => self.out_of_spec.retain(|t| t != sender),
+            ManagerControlMessageContents::TestFinished(result, ref message) => {
+                let outcome = match result {
+                    0 if self.out_of_spec.contains(sender) => Outcome::Failed("measurement out of spec".to_owned()),
+                    0 => Outcome::Passed,
+                    r => Outcome::Failed(format!("returned {}: {}", r, message)),
+                };
+                self.tests.push((sender.clone(), outcome));
+            }
+            ManagerControlMessageContents::Skip(ref test, ref reason) => {
+                self.tests.push((test.clone(), Outcome::Skipped(reason.clone())));
+            }
+            ManagerControlMessageContents::ScenarioFinished(code, ref message) if *sender == self.scenario => {
+                let exit_code = if code == 200 { SUCCESS } else { FAILED };
+                self.finish(exit_code, message);
+            }
+            _ => (),
+        }
+    }
+
+    /// Print a summary and shut down, stopping every unit on the way out.
+    fn finish(&mut self, exit_code: i32, message: &str) {
+        self.report(exit_code, message);
+        self.exit_code = Some(exit_code);
+        self.request(ManagerControlMessageContents::Shutdown(Some(format!("scenario {}", verdict(exit_code)))));
+    }
+
+    /// Print how the scenario went, along with the result of each test.
+    fn report(&self, exit_code: i32, message: &str) {
+        let elapsed = self.started.map(|s| s.elapsed()).unwrap_or_default();
+        println!(
+            "Scenario {} {} after {}.{:03}s: {}",
+            self.scenario,
+            verdict(exit_code),
+            elapsed.as_secs(),
+            elapsed.subsec_millis(),
+            message
+        );
+        for &(ref test, ref outcome) in &self.tests {
+            match *outcome {
+                Outcome::Passed => println!("    pass  {}", test),
+                Outcome::Failed(ref why) => println!("    FAIL  {}: {}", test, why),
+                Outcome::Skipped(ref why) => println!("    skip  {}: {}", test, why),
+            }
+        }
+    }
+
+    fn request(&self, contents: ManagerControlMessageContents) {
+        self.broadcaster
+            .broadcast(&UnitEvent::ManagerRequest(ManagerControlMessage::new(&self.id, contents)));
+    }
+}
+
+fn verdict(exit_code: i32) -> &'static str {
+    match exit_code {
+        SUCCESS => "passed",
+        NOT_RUN => "could not be run",
+        ABORTED => "was aborted",
+        _ => "failed",
+    }
+}
@@ -0,0 +1,84 @@
+// Checking that an operator who logs in is someone who's allowed to run the
+// station, against a list kept in a file, or by a web service such as one
+// that knows which badges are current.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use logsinks::elastic::split_url;
+
+/// How long to wait on the web service before refusing the login.
+const LOOKUP_TIMEOUT_SECS: u64 = 10;
+
+/// Where the operators who may log in are listed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OperatorList {
+    /// A file with an operator id at the start of each line.  It's read again for each
+    /// login, so operators can be added or removed without restarting exclave.
+    File(PathBuf),
+
+    /// An http:// URL that the operator id is added to the end of.  The operator may
+    /// log in if a GET of it succeeds.
+    Url(String),
+}
+
+impl OperatorList {
+    /// Whether the operator `id` may log in, and if not, why not.
+    pub fn check(&self, id: &str) -> Result<(), String> {
+        match *self {
+            OperatorList::File(ref path) => match listed(path, id) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(format!("{} is not in {}", id, path.to_string_lossy())),
+                Err(e) => Err(format!("unable to read {}: {}", path.to_string_lossy(), e)),
+            },
+            OperatorList::Url(ref url) => look_up(url, id),
+        }
+    }
+}
+
+/// Whether `id` starts one of the lines in the file.  Anything after it, such as the operator's name, is ignored.
+fn listed(path: &PathBuf, id: &str) -> io::Result<bool> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.split_whitespace().next() == Some(id) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn look_up(url: &str, id: &str) -> Result<(), String> {
+    let (address, path) = split_url(url, "http", 80).map_err(|e| e.to_string())?;
+    let path = format!("{}/{}", path.trim_end_matches('/'), encode(id));
+    let status = (|| -> io::Result<String> {
+        let mut stream = TcpStream::connect(&*address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(LOOKUP_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(LOOKUP_TIMEOUT_SECS)))?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, address)?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        Ok(status)
+    })()
+    .map_err(|e| format!("unable to look the operator up at {}: {}", address, e))?;
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some("404") => Err(format!("{} is not a known operator", id)),
+        _ => Err(format!("operator lookup failed: {}", status.trim())),
+    }
+}
+
+/// Percent-encode an operator id so that it can go in a URL path.
+fn encode(id: &str) -> String {
+    id.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
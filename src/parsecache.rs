@@ -0,0 +1,125 @@
+// Unit files that have already been parsed, kept in the state directory so that
+// restarting exclave doesn't mean parsing every unit file over again.  Entries are
+// keyed by a hash of the file's contents and of the values of the variables it uses,
+// so a file is parsed again if either has changed since.
+extern crate serde_json;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The cache's name in the state directory.
+pub const PARSE_CACHE_FILE: &str = "units.cache";
+
+/// A section heading or a directive of a unit file, once its variables have been expanded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Item {
+    Category(String),
+    Directive(String, Option<String>),
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u64, Vec<Item>>,
+
+    /// The entries that have been looked up or added since exclave started.  Only these are
+    /// saved, so that files which have since been edited or removed don't stay in it forever.
+    used: HashSet<u64>,
+
+    /// The latest key for each unit file's text, by a hash of the text alone.
+    latest: HashMap<u64, u64>,
+
+    /// Whether there's anything that hasn't been saved yet.
+    changed: bool,
+}
+
+/// Unit files are parsed without a UnitLibrary to hand, so the cache is kept here.
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+/// The key for a unit file's `text`, once it's been expanded with the variables that hash to `inputs`.
+pub fn key(text: &str, inputs: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    inputs.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The items that were parsed from the file with `key` before, if there are any.
+pub fn get(key: u64) -> Option<Vec<Item>> {
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(Cache::default);
+    let items = cache.entries.get(&key).cloned()?;
+    if cache.used.insert(key) {
+        cache.changed = true;
+    }
+    Some(items)
+}
+
+/// Add the items parsed from `text`, which has `key`.  They replace anything parsed from the same
+/// text with other variables, so that a file using a run's variables doesn't add an entry every run.
+pub fn insert(text: &str, key: u64, items: Vec<Item>) {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    let text_hash = hasher.finish();
+
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(Cache::default);
+    if let Some(old) = cache.latest.insert(text_hash, key) {
+        if old != key {
+            cache.entries.remove(&old);
+            cache.used.remove(&old);
+        }
+    }
+    cache.entries.insert(key, items);
+    cache.used.insert(key);
+    cache.changed = true;
+}
+
+/// Add the entries that were saved in `directory` the last time exclave ran.
+pub fn load(directory: &Path) -> io::Result<()> {
+    let mut contents = String::new();
+    match File::open(directory.join(PARSE_CACHE_FILE)) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let saved: HashMap<u64, Vec<Item>> = serde_json::from_str(&contents)?;
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(Cache::default);
+    for (key, items) in saved {
+        cache.entries.entry(key).or_insert(items);
+    }
+    Ok(())
+}
+
+/// Write the entries that are still in use to `directory`, if any have changed.  As with the
+/// run journal, a half-written cache is never left in place of the last one.
+pub fn save(directory: &Path) -> io::Result<()> {
+    let body = {
+        let cache = CACHE.lock().unwrap();
+        let cache = match *cache {
+            Some(ref c) if c.changed => c,
+            _ => return Ok(()),
+        };
+        let used = &cache.used;
+        let entries: HashMap<&u64, &Vec<Item>> = cache.entries.iter().filter(|&(key, _)| used.contains(key)).collect();
+        serde_json::to_string(&entries)?
+    };
+    fs::create_dir_all(directory)?;
+    let path = directory.join(PARSE_CACHE_FILE);
+    let temp_path = path.with_extension("cache.tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(body.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, &path)?;
+    if let Some(ref mut cache) = *CACHE.lock().unwrap() {
+        cache.changed = false;
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+// `exclave plan` loads the units the same way as `exclave check`, and
+// prints the order a scenario would run its tests in, without running any.
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use check;
+use config::Config;
+use unit::UnitName;
+
+/// Print the tests that `scenario` would run, in order.  Returns false if the
+/// scenario couldn't be loaded, in which case the reason has already been printed.
+pub fn plan(dirs: &[&str], namespaced: bool, config: &Arc<Mutex<Config>>, scenario: &UnitName) -> io::Result<bool> {
+    let (manager, _) = check::load(dirs, namespaced, config, &mut io::stdout())?;
+    let scenario = match manager.get_scenario_named(scenario) {
+        Some(s) => s,
+        None => {
+            println!("scenario {} could not be loaded", scenario);
+            return Ok(false);
+        }
+    };
+    let scenario = scenario.borrow();
+
+    let tests = scenario.test_sequence();
+    println!("{} ({}): {} test(s)", scenario.id(), scenario.name(), tests.len());
+    if !scenario.description().is_empty() {
+        println!("    {}", scenario.description());
+    }
+    for (step, id) in tests.iter().enumerate() {
+        let test = match manager.get_test_named(id) {
+            Some(t) => t,
+            None => {
+                println!("{:4}. {}", step + 1, id);
+                continue;
+            }
+        };
+        let test = test.borrow();
+        let mut notes = vec![];
+        if let Some(timeout) = *test.timeout() {
+            notes.push(format!("timeout {}", seconds(timeout)));
+        }
+        if test.is_daemon() {
+            notes.push("daemon".to_owned());
+        }
+        if notes.is_empty() {
+            println!("{:4}. {}: {}", step + 1, id, test.name());
+        } else {
+            println!("{:4}. {}: {} ({})", step + 1, id, test.name(), notes.join(", "));
+        }
+    }
+    Ok(true)
+}
+
+fn seconds(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}.{:03}s", duration.as_secs(), duration.subsec_millis())
+    }
+}
@@ -0,0 +1,276 @@
+// Plugins add unit kinds of their own, such as a driver for an instrument that
+// only one site has, or a logger that writes to an in-house database, without
+// exclave having to be changed for them.  A plugin is a shared library that
+// exports an `exclave_plugin_v1` function, which returns a table of the
+// functions its units are made and run with.  Only plain C crosses between
+// exclave and a plugin, so a plugin needn't be built with the same compiler as
+// exclave was, or even be written in Rust.  Plugins that are written in Rust
+// implement `PluginUnit`, and `declare_plugin!` makes the table for them.
+//
+// Once a plugin's kind has been registered, unit files with its kind as their
+// extension, e.g. "scope.instrument", are loaded like any other unit, and the
+// plugin is given their text to make a unit from.
+#[cfg(feature = "plugins")]
+extern crate libloading;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Mutex, Once};
+
+use unit::UnitName;
+
+/// What every plugin exports.  It's a function that takes nothing, and returns a `PluginVTable`.
+/// If the table ever has to change, the new one will be under a new name.
+pub const ENTRY_POINT: &str = "exclave_plugin_v1";
+
+/// A unit of a plugin's kind, which only the plugin knows what to do with.
+pub type RawUnit = *mut c_void;
+
+/// What exclave gives each of a plugin's units to report back with.
+#[repr(C)]
+pub struct Host {
+    pub context: *mut c_void,
+
+    /// Log `message` as the unit, as an error if `error` is set.  It may be called from any thread.
+    pub log: extern "C" fn(context: *mut c_void, error: bool, message: *const c_char),
+}
+
+/// A plugin's unit kind, and the functions that its units are made and run with.  Strings
+/// are nul-terminated UTF-8.  Units are only ever called from one thread at a time, which
+/// is the unit manager's, so they should return quickly and leave anything slow to a thread.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginVTable {
+    /// The kind of unit, which is also the extension of their unit files, e.g. "instrument".
+    pub kind: *const c_char,
+
+    /// Make a unit from the text of its unit file, or return null, having logged why as an
+    /// error, if it isn't valid.  Nothing should be started yet, as `exclave check` makes
+    /// units just to see whether they can be made.  `host` is valid until the unit is destroyed.
+    pub create: extern "C" fn(id: *const c_char, text: *const c_char, host: *const Host) -> RawUnit,
+
+    /// Start the unit.  Returns false, having logged why as an error, if it couldn't be started.
+    pub activate: extern "C" fn(unit: RawUnit) -> bool,
+
+    /// Stop the unit.  It may be started again later on.
+    pub deactivate: extern "C" fn(unit: RawUnit),
+
+    /// Something that happened while the unit was running, as a line of JSON.  `kind` is "log",
+    /// "audit", "transition", or "stats", and the JSON is the same as a logger would be sent.
+    pub event: extern "C" fn(unit: RawUnit, kind: *const c_char, json: *const c_char),
+
+    /// Free the unit.  It's been stopped first, if it was running.
+    pub destroy: extern "C" fn(unit: RawUnit),
+}
+
+// The kind is a string that lives as long as the plugin does, which is as long as exclave runs.
+unsafe impl Send for PluginVTable {}
+unsafe impl Sync for PluginVTable {}
+
+static REGISTRY: Once = Once::new();
+
+/// Every unit kind that plugins have added, by kind.  Plugins are never unloaded.
+static mut KINDS: *const Mutex<HashMap<String, PluginVTable>> = ptr::null();
+
+fn kinds() -> &'static Mutex<HashMap<String, PluginVTable>> {
+    REGISTRY.call_once(|| unsafe { KINDS = Box::into_raw(Box::new(Mutex::new(HashMap::new()))) });
+    unsafe { &*KINDS }
+}
+
+/// Add the unit kind that `vtable` describes, and return what it's called.  This is done by
+/// `load()` for plugins in shared libraries, but a program that embeds exclave can also
+/// register kinds that are built into it.  Kinds have to be registered before any unit
+/// directories are added, or their units won't be recognized.
+pub fn register(vtable: PluginVTable) -> Result<String, String> {
+    if vtable.kind.is_null() {
+        return Err("the plugin doesn't say what kind of unit it's for".to_owned());
+    }
+    let kind = unsafe { CStr::from_ptr(vtable.kind) }.to_string_lossy().into_owned();
+    if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') || kind == "toml" {
+        return Err(format!("\"{}\" can't be a unit kind", kind));
+    }
+    // Whether it's a built-in kind, or one that's already been registered.
+    if UnitName::from_str("unit", &kind).is_ok() {
+        return Err(format!("there's already a unit kind called \"{}\"", kind));
+    }
+    kinds().lock().unwrap().insert(kind.clone(), vtable);
+    Ok(kind)
+}
+
+/// The functions that units of `kind` are run with, if a plugin has added it.
+pub fn find(kind: &str) -> Option<PluginVTable> {
+    kinds().lock().unwrap().get(kind).cloned()
+}
+
+/// The kinds that plugins have added, in order.
+pub fn registered_kinds() -> Vec<String> {
+    let mut kinds: Vec<String> = kinds().lock().unwrap().keys().cloned().collect();
+    kinds.sort();
+    kinds
+}
+
+/// Load the plugin at `path`, and register the unit kind it adds.
+#[cfg(feature = "plugins")]
+pub fn load(path: &Path) -> Result<String, String> {
+    let library = libloading::Library::new(path).map_err(|e| format!("unable to load plugin {}: {}", path.display(), e))?;
+    let vtable = unsafe {
+        let entry: libloading::Symbol<extern "C" fn() -> PluginVTable> = library
+            .get(ENTRY_POINT.as_bytes())
+            .map_err(|e| format!("unable to load plugin {}: {}", path.display(), e))?;
+        entry()
+    };
+    let kind = register(vtable).map_err(|e| format!("unable to load plugin {}: {}", path.display(), e))?;
+
+    // Its units, and the kind's name, are in the library, so it has to stay loaded.
+    ::std::mem::forget(library);
+    Ok(kind)
+}
+
+#[cfg(not(feature = "plugins"))]
+pub fn load(path: &Path) -> Result<String, String> {
+    Err(format!(
+        "unable to load plugin {}: plugins aren't supported by this build of exclave",
+        path.display()
+    ))
+}
+
+/// A unit of a plugin's kind, for plugins written in Rust.  `declare_plugin!` turns a type
+/// that implements this into a plugin.  Panics are caught before they reach exclave, and
+/// are treated as if the unit had returned an error.
+pub trait PluginUnit: Sized {
+    /// Make a unit from the text of its unit file.  Nothing should be started yet.
+    /// `host` may be kept, and passed to the unit's own threads, for as long as the unit lives.
+    fn create(id: &str, text: &str, host: PluginHost) -> Result<Self, String>;
+
+    fn activate(&mut self) -> Result<(), String>;
+
+    fn deactivate(&mut self) {}
+
+    /// Something that happened while the unit was running.  See `PluginVTable::event`.
+    fn event(&mut self, _kind: &str, _json: &str) {}
+}
+
+/// Logs as one of a plugin's units.
+#[derive(Clone, Copy)]
+pub struct PluginHost {
+    host: *const Host,
+}
+
+// Exclave's end of the host can be called from any thread.
+unsafe impl Send for PluginHost {}
+unsafe impl Sync for PluginHost {}
+
+impl PluginHost {
+    pub fn log(&self, message: &str) {
+        self.write(false, message);
+    }
+
+    pub fn error(&self, message: &str) {
+        self.write(true, message);
+    }
+
+    fn write(&self, error: bool, message: &str) {
+        // A message can't have a nul in it, so it ends there.
+        let message = CString::new(message.split('\0').next().unwrap_or("")).unwrap_or_default();
+        let host = unsafe { &*self.host };
+        (host.log)(host.context, error, message.as_ptr());
+    }
+}
+
+/// A unit, along with what it reports its errors with.
+struct Hosted<T> {
+    unit: T,
+    host: PluginHost,
+}
+
+impl PluginVTable {
+    /// The table for units of type `T`.  `kind` has to end with a nul.
+    pub fn new<T: PluginUnit>(kind: &'static str) -> PluginVTable {
+        assert!(kind.ends_with('\0'), "unit kind must be nul-terminated");
+        PluginVTable {
+            kind: kind.as_ptr() as *const c_char,
+            create: create::<T>,
+            activate: activate::<T>,
+            deactivate: deactivate::<T>,
+            event: event::<T>,
+            destroy: destroy::<T>,
+        }
+    }
+}
+
+fn text(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+}
+
+extern "C" fn create<T: PluginUnit>(id: *const c_char, unit_text: *const c_char, host: *const Host) -> RawUnit {
+    let host = PluginHost { host: host };
+    let (id, unit_text) = (text(id), text(unit_text));
+    match panic::catch_unwind(AssertUnwindSafe(|| T::create(&id, &unit_text, host))) {
+        Ok(Ok(unit)) => Box::into_raw(Box::new(Hosted { unit: unit, host: host })) as RawUnit,
+        Ok(Err(e)) => {
+            host.error(&e);
+            ptr::null_mut()
+        }
+        Err(_) => {
+            host.error("plugin panicked while making the unit");
+            ptr::null_mut()
+        }
+    }
+}
+
+extern "C" fn activate<T: PluginUnit>(unit: RawUnit) -> bool {
+    let hosted = unsafe { &mut *(unit as *mut Hosted<T>) };
+    match panic::catch_unwind(AssertUnwindSafe(|| hosted.unit.activate())) {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            hosted.host.error(&e);
+            false
+        }
+        Err(_) => {
+            hosted.host.error("plugin panicked while starting the unit");
+            false
+        }
+    }
+}
+
+extern "C" fn deactivate<T: PluginUnit>(unit: RawUnit) {
+    let hosted = unsafe { &mut *(unit as *mut Hosted<T>) };
+    if panic::catch_unwind(AssertUnwindSafe(|| hosted.unit.deactivate())).is_err() {
+        hosted.host.error("plugin panicked while stopping the unit");
+    }
+}
+
+extern "C" fn event<T: PluginUnit>(unit: RawUnit, kind: *const c_char, json: *const c_char) {
+    let hosted = unsafe { &mut *(unit as *mut Hosted<T>) };
+    let (kind, json) = (text(kind), text(json));
+    if panic::catch_unwind(AssertUnwindSafe(|| hosted.unit.event(&kind, &json))).is_err() {
+        hosted.host.error("plugin panicked while handling an event");
+    }
+}
+
+extern "C" fn destroy<T: PluginUnit>(unit: RawUnit) {
+    let hosted = unsafe { Box::from_raw(unit as *mut Hosted<T>) };
+    let host = hosted.host;
+    if panic::catch_unwind(AssertUnwindSafe(move || drop(hosted))).is_err() {
+        host.error("plugin panicked while freeing the unit");
+    }
+}
+
+/// Export `$unit`, which implements `PluginUnit`, as the units of kind `$kind`, e.g.
+/// `declare_plugin!("instrument", Instrument);`.  The plugin has to be built as a cdylib.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($kind:expr, $unit:ty) => {
+        #[no_mangle]
+        pub extern "C" fn exclave_plugin_v1() -> $crate::plugin::PluginVTable {
+            $crate::plugin::PluginVTable::new::<$unit>(concat!($kind, "\0"))
+        }
+    };
+}
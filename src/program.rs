@@ -0,0 +1,555 @@
+// Starting the programs that units run, and stopping them again.  Their output
+// is read by the reactor, which has to be able to poll it, so exclave starts them
+// itself rather than through runny, whose streams can only be read.  Otherwise
+// they're started the same way: on Unix, each program's stdin and stdout are a
+// pseudoterminal, so that it doesn't hold its output back as it would writing to a
+// pipe, and its stderr is a pipe.  Each program leads a session of its own, which
+// is signalled as a whole when it's stopped, so that nothing it started is left
+// running behind it.  On Windows, each program is put in a job object of its own
+// instead, and stopping it terminates the whole job.
+extern crate runny;
+extern crate shlex;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use self::runny::running::RunningError;
+use self::runny::RunnyError;
+
+use usage::Meter;
+
+/// A program to be started.
+pub struct Exec {
+    cmd: String,
+    working_directory: Option<PathBuf>,
+    timeout: Option<Duration>,
+    meter: Option<Meter>,
+}
+
+impl Exec {
+    pub fn new(cmd: &str) -> Exec {
+        Exec {
+            cmd: cmd.to_owned(),
+            working_directory: None,
+            timeout: None,
+            meter: None,
+        }
+    }
+
+    pub fn directory(&mut self, wd: &Option<PathBuf>) -> &mut Exec {
+        self.working_directory = wd.clone();
+        self
+    }
+
+    /// Kill the program if it's still running after `timeout`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Exec {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Count what the program uses towards `meter`, once it's exited.
+    pub fn meter(&mut self, meter: &Meter) -> &mut Exec {
+        self.meter = Some(meter.clone());
+        self
+    }
+
+    pub fn start(&self) -> Result<Program, RunnyError> {
+        // Backslashes are kept, as they're how Windows separates paths.
+        let mut args = match shlex::split(&self.cmd.replace("\\", "\\\\")) {
+            Some(ref args) if args.is_empty() => return Err(RunnyError::NoCommandSpecified),
+            Some(args) => args,
+            None => return Err(RunnyError::NoCommandSpecified),
+        };
+        let mut cmd = Command::new(args.remove(0));
+        cmd.args(args);
+        if let Some(ref wd) = self.working_directory {
+            cmd.current_dir(wd);
+        }
+
+        let (child, group, input, output, error) = sys::spawn(cmd).map_err(RunnyError::RunnyIoError)?;
+        let state = Arc::new(State {
+            group: group,
+            meter: self.meter.clone(),
+            result: Mutex::new(None),
+            exited: Condvar::new(),
+        });
+
+        let thr_state = state.clone();
+        thread::spawn(move || thr_state.reap(child));
+
+        if let Some(timeout) = self.timeout {
+            let thr_state = state.clone();
+            thread::spawn(move || {
+                if thr_state.wait_timeout(timeout).is_none() {
+                    thr_state.terminate(None);
+                }
+            });
+        }
+
+        Ok(Program {
+            input: Some(ProgramInput { stream: input }),
+            output: Some(ProgramOutput { stream: output }),
+            error: Some(ProgramOutput { stream: error }),
+            state: state,
+        })
+    }
+}
+
+/// How a program is getting on, shared between everyone waiting for it.
+struct State {
+    /// The program, along with everything it starts, as a whole.
+    group: sys::Group,
+
+    /// What the program used is added to this once it's been reaped.
+    meter: Option<Meter>,
+
+    /// The return code, once the program has exited.  A program that was killed by a signal
+    /// returns -2, and one that couldn't be waited for returns -1, as they did under runny.
+    result: Mutex<Option<i32>>,
+    exited: Condvar,
+}
+
+impl State {
+    fn reap(&self, child: Child) {
+        let (result, usage) = sys::wait(child);
+        if let Some(ref meter) = self.meter {
+            meter.add(&usage);
+        }
+        *self.result.lock().unwrap() = Some(result);
+        self.exited.notify_all();
+    }
+
+    fn wait(&self) -> i32 {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            if let Some(result) = *result {
+                return result;
+            }
+            result = self.exited.wait(result).unwrap();
+        }
+    }
+
+    fn wait_timeout(&self, timeout: Duration) -> Option<i32> {
+        let deadline = Instant::now() + timeout;
+        let mut result = self.result.lock().unwrap();
+        loop {
+            if result.is_some() {
+                return *result;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            result = self.exited.wait_timeout(result, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Ask the program to exit, give it `timeout` to do so, and then kill it, along with everything
+    /// that it started.  A program that has already exited is left alone.
+    fn terminate(&self, timeout: Option<Duration>) -> i32 {
+        if let Some(result) = *self.result.lock().unwrap() {
+            return result;
+        }
+        self.group.ask_to_exit();
+        if let Some(timeout) = timeout {
+            if let Some(result) = self.wait_timeout(timeout) {
+                return result;
+            }
+        }
+        self.group.kill();
+        self.wait()
+    }
+}
+
+/// A program that's been started.  It's killed if it's still running when this is dropped.
+pub struct Program {
+    input: Option<ProgramInput>,
+    output: Option<ProgramOutput>,
+    error: Option<ProgramOutput>,
+    state: Arc<State>,
+}
+
+impl Program {
+    pub fn take_input(&mut self) -> ProgramInput {
+        self.input.take().expect("program's input was already taken")
+    }
+
+    pub fn take_output(&mut self) -> ProgramOutput {
+        self.output.take().expect("program's output was already taken")
+    }
+
+    pub fn take_error(&mut self) -> ProgramOutput {
+        self.error.take().expect("program's error output was already taken")
+    }
+
+    /// Something to wait for the program with, or stop it with, from another thread.
+    pub fn waiter(&self) -> Waiter {
+        Waiter {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Wait for the program to exit, and return its return code.
+    pub fn wait(&self) -> Result<i32, RunningError> {
+        Ok(self.state.wait())
+    }
+
+    /// The program's return code, waiting for it to exit if it hasn't yet.
+    pub fn result(&self) -> i32 {
+        self.state.wait()
+    }
+
+    /// Stop the program, giving it `timeout` to exit by itself first, and return its return code.
+    pub fn terminate(&self, timeout: Option<Duration>) -> Result<i32, RunningError> {
+        Ok(self.state.terminate(timeout))
+    }
+}
+
+impl Read for Program {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.output {
+            Some(ref mut output) => output.read(buf),
+            None => Err(io::Error::new(io::ErrorKind::Other, "program's output was already taken")),
+        }
+    }
+}
+
+impl Write for Program {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.input {
+            Some(ref mut input) => input.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::Other, "program's input was already taken")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.input {
+            Some(ref mut input) => input.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        self.state.terminate(None);
+    }
+}
+
+/// Waits for a program, or stops it, without holding on to the program itself.
+#[derive(Clone)]
+pub struct Waiter {
+    state: Arc<State>,
+}
+
+impl Waiter {
+    pub fn wait(&self) {
+        self.state.wait();
+    }
+
+    pub fn result(&self) -> i32 {
+        self.state.wait()
+    }
+
+    /// Stop the program in the background, giving it `timeout` to exit by itself first.
+    pub fn terminate(&self, timeout: &Option<Duration>) {
+        let state = self.state.clone();
+        let timeout = *timeout;
+        thread::spawn(move || state.terminate(timeout));
+    }
+}
+
+/// A program's stdout or stderr.
+pub struct ProgramOutput {
+    stream: File,
+}
+
+impl Read for ProgramOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // A pseudoterminal reports EIO rather than the end of the file once the program has hung up.
+            #[cfg(unix)]
+            Err(ref e) if e.raw_os_error() == Some(sys::EIO) => Ok(0),
+            other => other,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for ProgramOutput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// A program's stdin.
+pub struct ProgramInput {
+    stream: File,
+}
+
+impl Write for ProgramInput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    extern crate libc;
+
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::ptr;
+    use std::time::Duration;
+
+    use usage::Usage;
+
+    pub const EIO: i32 = libc::EIO;
+
+    /// A program's session.
+    pub struct Group {
+        pid: libc::pid_t,
+    }
+
+    impl Group {
+        /// Ask everything in the program's session to exit.
+        pub fn ask_to_exit(&self) {
+            unsafe { libc::kill(-self.pid, libc::SIGTERM) };
+        }
+
+        /// Kill everything in the program's session.
+        pub fn kill(&self) {
+            unsafe { libc::kill(-self.pid, libc::SIGKILL) };
+        }
+    }
+
+    /// Start `cmd`, and return it along with its session, and its stdin, stdout and stderr.
+    pub fn spawn(mut cmd: Command) -> io::Result<(Child, Group, File, File, File)> {
+        let (mut master, mut slave) = (-1, -1);
+        if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), ptr::null_mut()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Each of these is owned from here on, so that they're closed again if anything goes wrong.
+        let output = unsafe { File::from_raw_fd(master) };
+        let stdin = unsafe { Stdio::from_raw_fd(slave) };
+        cloexec(master);
+        cloexec(slave);
+        raw(master)?;
+
+        let input = unsafe { File::from_raw_fd(dup(master)?) };
+        let stdout = unsafe { Stdio::from_raw_fd(dup(slave)?) };
+
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let error = unsafe { File::from_raw_fd(fds[0]) };
+        let stderr = unsafe { Stdio::from_raw_fd(fds[1]) };
+        cloexec(fds[0]);
+        cloexec(fds[1]);
+
+        cmd.stdin(stdin).stdout(stdout).stderr(stderr);
+        // Only setsid() is called here, as between fork and exec is no place to be doing anything
+        // else.  It can't fail, as the child has only just been forked and can't lead a group yet.
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        // The child's ends are closed here once it's started, so that the parent sees it hang up.
+        let child = cmd.spawn()?;
+        let group = Group {
+            pid: child.id() as libc::pid_t,
+        };
+        Ok((child, group, input, output, error))
+    }
+
+    /// Wait for `child` to exit, and return its return code, along with what it and anything it
+    /// waited for used.
+    pub fn wait(child: Child) -> (i32, Usage) {
+        let mut status = 0;
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+        loop {
+            let ret = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut rusage) };
+            if ret == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if ret == -1 {
+                return (-1, Usage::default());
+            }
+            break;
+        }
+        let duration = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        // macOS counts bytes, where everything else counts kilobytes.
+        let max_rss = rusage.ru_maxrss as u64;
+        let max_rss = if cfg!(target_os = "macos") { max_rss / 1024 } else { max_rss };
+        let usage = Usage::new(duration(rusage.ru_utime), duration(rusage.ru_stime), max_rss);
+
+        if libc::WIFEXITED(status) {
+            (libc::WEXITSTATUS(status), usage)
+        } else {
+            (-2, usage)
+        }
+    }
+
+    fn cloexec(fd: RawFd) {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    fn dup(fd: RawFd) -> io::Result<RawFd> {
+        let new = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if new == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(new)
+        }
+    }
+
+    /// Pass everything through the pseudoterminal untouched, without echoing what's written to it.
+    fn raw(fd: RawFd) -> io::Result<()> {
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { libc::cfmakeraw(&mut termios) };
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    extern crate kernel32;
+    extern crate winapi;
+
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle};
+    use std::mem;
+    use std::os::windows::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::ptr;
+    use std::time::Duration;
+
+    use usage::Usage;
+
+    /// A program's job object, which anything it starts is put in too.
+    pub struct Group {
+        pid: u32,
+
+        /// Null if the program couldn't be put in a job of its own, which needs Windows 8 or later
+        /// if exclave is itself in a job.  Only the program itself can be killed then.
+        job: winapi::HANDLE,
+    }
+
+    // The job is only ever terminated and closed, both of which may be done from any thread.
+    unsafe impl Send for Group {}
+    unsafe impl Sync for Group {}
+
+    impl Group {
+        /// Send the program a Ctrl-Break, which is the closest Windows has to SIGTERM.  This only
+        /// reaches programs that share exclave's console, so anything else is killed after the timeout.
+        pub fn ask_to_exit(&self) {
+            unsafe { kernel32::GenerateConsoleCtrlEvent(winapi::CTRL_BREAK_EVENT, self.pid) };
+        }
+
+        /// Kill everything in the program's job.
+        pub fn kill(&self) {
+            unsafe {
+                if !self.job.is_null() {
+                    kernel32::TerminateJobObject(self.job, 1);
+                    return;
+                }
+                let handle = kernel32::OpenProcess(winapi::PROCESS_TERMINATE, 0, self.pid);
+                if !handle.is_null() {
+                    kernel32::TerminateProcess(handle, 1);
+                    kernel32::CloseHandle(handle);
+                }
+            }
+        }
+    }
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            if !self.job.is_null() {
+                unsafe { kernel32::CloseHandle(self.job) };
+            }
+        }
+    }
+
+    /// Start `cmd`, and return it along with its job, and its stdin, stdout and stderr.
+    pub fn spawn(mut cmd: Command) -> io::Result<(Child, Group, File, File, File)> {
+        // A process group of its own lets the program be sent a Ctrl-Break without exclave getting one too.
+        cmd.creation_flags(winapi::CREATE_NEW_PROCESS_GROUP);
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let input = unsafe { File::from_raw_handle(child.stdin.take().unwrap().into_raw_handle()) };
+        let output = unsafe { File::from_raw_handle(child.stdout.take().unwrap().into_raw_handle()) };
+        let error = unsafe { File::from_raw_handle(child.stderr.take().unwrap().into_raw_handle()) };
+
+        // The program has already started by the time it's put in the job, so anything it starts
+        // straight away escapes it.  That's still in exclave's own job, so it goes once exclave does.
+        let mut job = unsafe { kernel32::CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if !job.is_null() && unsafe { kernel32::AssignProcessToJobObject(job, child.as_raw_handle() as winapi::HANDLE) } == 0 {
+            unsafe { kernel32::CloseHandle(job) };
+            job = ptr::null_mut();
+        }
+        let group = Group {
+            pid: child.id(),
+            job: job,
+        };
+        Ok((child, group, input, output, error))
+    }
+
+    /// Wait for `child` to exit, and return its return code, along with what it used.  Unlike on
+    /// Unix, this doesn't include anything it started.
+    pub fn wait(mut child: Child) -> (i32, Usage) {
+        let result = match child.wait() {
+            Ok(status) => status.code().unwrap_or(-2),
+            Err(_) => return (-1, Usage::default()),
+        };
+        (result, usage(&child))
+    }
+
+    fn usage(child: &Child) -> Usage {
+        let handle = child.as_raw_handle() as winapi::HANDLE;
+        let mut times: [winapi::FILETIME; 4] = unsafe { mem::zeroed() };
+        if unsafe { kernel32::GetProcessTimes(handle, &mut times[0], &mut times[1], &mut times[2], &mut times[3]) } == 0 {
+            return Usage::default();
+        }
+        // Process times are counted in 100ns intervals.
+        let duration = |t: &winapi::FILETIME| {
+            let ticks = (t.dwHighDateTime as u64) << 32 | t.dwLowDateTime as u64;
+            Duration::new(ticks / 10_000_000, (ticks % 10_000_000) as u32 * 100)
+        };
+
+        let mut counters: winapi::PROCESS_MEMORY_COUNTERS = unsafe { mem::zeroed() };
+        counters.cb = mem::size_of::<winapi::PROCESS_MEMORY_COUNTERS>() as winapi::DWORD;
+        let max_rss = if unsafe { kernel32::K32GetProcessMemoryInfo(handle, &mut counters, counters.cb) } != 0 {
+            counters.PeakWorkingSetSize as u64 / 1024
+        } else {
+            0
+        };
+        Usage::new(duration(&times[3]), duration(&times[2]), max_rss)
+    }
+}
@@ -5,34 +5,68 @@
 mod timer;
 
 use self::timer::WatchTimer;
-use super::unitbroadcaster::{UnitBroadcaster, UnitEvent};
+use super::unitbroadcaster::{UnitBroadcaster, UnitEvent, UnitStatus};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct Quiesce {
     timer_id: Option<u64>,
     timer: WatchTimer,
+    delay: Duration,
+
+    /// Rescans caused by unit files changing are at least this far apart.
+    min_interval: Duration,
+
+    /// When the last rescan started, whatever asked for it.
+    last_rescan: Option<Instant>,
 }
 
 impl Quiesce {
-    pub fn new(delay: Duration, broadcaster: &UnitBroadcaster) -> Quiesce {
+    pub fn new(delay: Duration, min_interval: Duration, broadcaster: &UnitBroadcaster) -> Quiesce {
         Quiesce {
             timer_id: None,
-            timer: WatchTimer::new(broadcaster, delay),
+            timer: WatchTimer::new(broadcaster),
+            delay: delay,
+            min_interval: min_interval,
+            last_rescan: None,
         }
     }
+    /// Request a single rescan once unit files have stopped changing.
+    /// Anything that isn't about a unit file, including everything the
+    /// rescan itself emits, leaves the timer alone, so a burst of changes
+    /// during a deploy results in one rescan rather than many.
     pub fn process_message(&mut self, evt: &UnitEvent) {
-        if evt == &UnitEvent::RescanRequest || evt == &UnitEvent::RescanStart || evt == &UnitEvent::RescanFinish {
-            return;
+        let status = match evt {
+            &UnitEvent::Status(ref status) => status.status(),
+            &UnitEvent::RescanStart => {
+                self.last_rescan = Some(Instant::now());
+                return;
+            }
+            _ => return,
+        };
+        match status {
+            &UnitStatus::Added(_)
+            | &UnitStatus::Updated(_)
+            | &UnitStatus::Removed(_)
+            | &UnitStatus::LoadStarted(_)
+            | &UnitStatus::UpdateStarted(_)
+            | &UnitStatus::UnloadStarted(_) => self.restart_timer(UnitEvent::RescanRequest),
+            _ => (),
         }
-        self.restart_timer(UnitEvent::RescanRequest);
     }
 
     fn restart_timer(&mut self, event: UnitEvent) {
         if let Some(timer_id) = self.timer_id {
             self.timer.ignore(timer_id);
         }
-        self.timer_id = Some(self.timer.schedule(event));
+        // Files that keep on changing can't cause rescans any closer together than min_interval.
+        let mut when = Instant::now() + self.delay;
+        if let Some(last_rescan) = self.last_rescan {
+            if last_rescan + self.min_interval > when {
+                when = last_rescan + self.min_interval;
+            }
+        }
+        self.timer_id = Some(self.timer.schedule_at(event, when));
     }
 
 }
\ No newline at end of file
@@ -131,13 +131,10 @@ pub struct WatchTimer {
     counter: u64,
     schedule_tx: mpsc::Sender<Action>,
     trigger: Arc<Condvar>,
-    delay: Duration,
 }
 
 impl WatchTimer {
-    pub fn new(broadcaster: &UnitBroadcaster,
-               delay: Duration)
-               -> WatchTimer {
+    pub fn new(broadcaster: &UnitBroadcaster) -> WatchTimer {
         let (schedule_tx, schedule_rx) = mpsc::channel();
         let trigger = Arc::new(Condvar::new());
 
@@ -151,17 +148,17 @@ impl WatchTimer {
             counter: 0,
             schedule_tx: schedule_tx,
             trigger: trigger,
-            delay: delay,
         }
     }
 
-    pub fn schedule(&mut self, event: UnitEvent) -> u64 {
+    /// Schedule an event for a particular time.
+    pub fn schedule_at(&mut self, event: UnitEvent, when: Instant) -> u64 {
         self.counter = self.counter.wrapping_add(1);
 
         self.schedule_tx
             .send(Action::Schedule(ScheduledEvent {
                 id: self.counter,
-                when: Instant::now() + self.delay,
+                when: when,
                 event: event,
             }))
             .expect("Failed to send a request to the global scheduling worker");
@@ -0,0 +1,304 @@
+// Reading the output of the programs that units run.  Every interface, trigger,
+// logger and test has its stdout and stderr read a line at a time, and a thread
+// for each of those would leave a station with dozens of units carrying hundreds
+// of threads that spend nearly all of their time waiting.  Instead, one thread
+// waits on all of them at once with poll(2), and hands each line on as it arrives.
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use supervisor;
+use unit::UnitName;
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+/// What's done with each line read from a stream.  It's given `Some(line)` for each line, without
+/// its line ending, and then `None` once the stream has been read to the end.  Returning false stops
+/// the stream from being read any further, e.g. once there's nobody left to pass the lines on to.
+pub type LineHandler = Box<dyn FnMut(Option<&[u8]>) -> bool + Send>;
+
+/// Lets whoever started reading a stream wait until it's been read to the end, so that
+/// nothing a program printed is missed by whatever comes after it exits.
+pub struct Finished {
+    receiver: Receiver<()>,
+}
+
+impl Finished {
+    fn new() -> (Sender<()>, Finished) {
+        let (sender, receiver) = channel();
+        (sender, Finished { receiver: receiver })
+    }
+
+    /// Wait for the stream to be finished with, but for no longer than `timeout`.  Nothing is ever
+    /// sent; the sender being dropped, along with the rest of the stream, is what ends the wait.
+    /// Returns false if it wasn't, e.g. because a program that the stream's program started is
+    /// holding it open.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        match self.receiver.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Read `stream`, which is the output of a program that unit `id` started, a line at a time
+/// in the background, passing each line to `handler`.
+#[cfg(unix)]
+pub fn watch_lines<R, F>(stream: R, id: &UnitName, control: &Sender<ManagerControlMessage>, handler: F) -> Finished
+where
+    R: Read + AsRawFd + Send + 'static,
+    F: FnMut(Option<String>) -> bool + Send + 'static,
+{
+    let (sender, finished) = Finished::new();
+    let fd = stream.as_raw_fd();
+    let handler = decoding(id, control, handler);
+    match unix::reactor() {
+        Some(reactor) => reactor.add(fd, Box::new(stream), handler, sender),
+        // There's no reactor if its pipe couldn't be made, but the stream can still be read.
+        None => watch_on_thread(stream, handler, sender),
+    }
+    finished
+}
+
+#[cfg(not(unix))]
+pub fn watch_lines<R, F>(stream: R, id: &UnitName, control: &Sender<ManagerControlMessage>, handler: F) -> Finished
+where
+    R: Read + Send + 'static,
+    F: FnMut(Option<String>) -> bool + Send + 'static,
+{
+    let (sender, finished) = Finished::new();
+    watch_on_thread(stream, decoding(id, control, handler), sender);
+    finished
+}
+
+/// Turn each line into a String for `handler`.  Programs don't always print valid UTF-8, and
+/// that's no reason to stop reading them, so anything that isn't is replaced with U+FFFD.  The
+/// first time that happens on a stream, the unit's log says so, as the output isn't quite what
+/// the program printed any more.  A handler that panics fails its unit, and stops the stream.
+fn decoding<F>(id: &UnitName, control: &Sender<ManagerControlMessage>, mut handler: F) -> LineHandler
+where
+    F: FnMut(Option<String>) -> bool + Send + 'static,
+{
+    let id = id.clone();
+    let control = control.clone();
+    let mut warned = false;
+    Box::new(move |line: Option<&[u8]>| {
+        let line = line.map(|bytes| match String::from_utf8_lossy(bytes) {
+            Cow::Borrowed(line) => line.to_owned(),
+            Cow::Owned(line) => {
+                if !warned {
+                    warned = true;
+                    let message = "output isn't valid UTF-8, so the parts that aren't have been replaced".to_owned();
+                    control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(message))).ok();
+                }
+                line
+            }
+        });
+        match panic::catch_unwind(AssertUnwindSafe(|| handler(line))) {
+            Ok(more) => more,
+            Err(e) => {
+                supervisor::report_panic(&id, &control, &e);
+                false
+            }
+        }
+    })
+}
+
+/// Read a stream on a thread of its own.
+fn watch_on_thread<R: Read + Send + 'static>(stream: R, mut handler: LineHandler, finished: Sender<()>) {
+    thread::spawn(move || {
+        let _finished = finished;
+        let mut reader = BufReader::new(stream);
+        let mut line = vec![];
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if !handle(&mut handler, Some(trim_line(&line))) {
+                        return;
+                    }
+                }
+            }
+        }
+        handle(&mut handler, None);
+    });
+}
+
+/// Pass a line on.  A handler that panics is treated as having asked to stop, so
+/// that one misbehaving unit can't take the other units' output down with it.
+fn handle(handler: &mut LineHandler, line: Option<&[u8]>) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(|| handler(line))).unwrap_or(false)
+}
+
+/// A line as it was read, without its line ending, in the same way as `BufRead::lines()`.
+fn trim_line(bytes: &[u8]) -> &[u8] {
+    let mut end = bytes.len();
+    if end > 0 && bytes[end - 1] == b'\n' {
+        end -= 1;
+        if end > 0 && bytes[end - 1] == b'\r' {
+            end -= 1;
+        }
+    }
+    &bytes[..end]
+}
+
+#[cfg(unix)]
+mod unix {
+    extern crate libc;
+
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+    use std::sync::mpsc::Sender;
+    use std::sync::{Mutex, Once};
+    use std::thread;
+
+    use super::{handle, trim_line, LineHandler};
+
+    /// How much is read from a stream at a time.
+    const READ_SIZE: usize = 4096;
+
+    static START: Once = Once::new();
+
+    /// The reactor, once it's been started, or null if it couldn't be.  It's never stopped.
+    static mut REACTOR: *const Reactor = ptr::null();
+
+    /// A stream being read, and the part of a line that's been read from it so far.
+    struct Stream {
+        fd: RawFd,
+        reader: Box<dyn Read + Send>,
+        partial: Vec<u8>,
+        handler: LineHandler,
+
+        /// Dropped along with the stream, which lets its `Finished` know.
+        _finished: Sender<()>,
+    }
+
+    impl Stream {
+        /// Read whatever is waiting, and pass on each line that's been completed.
+        /// Returns false once the stream is finished with.
+        fn read(&mut self, buf: &mut [u8]) -> bool {
+            let count = match self.reader.read(buf) {
+                Ok(count) => count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return true,
+                // A stream that can't be read any more has ended as far as anyone here is concerned.
+                Err(_) => 0,
+            };
+
+            if count == 0 {
+                // The last line may not have had a line ending.
+                if !self.partial.is_empty() && !handle(&mut self.handler, Some(trim_line(&self.partial))) {
+                    return false;
+                }
+                handle(&mut self.handler, None);
+                return false;
+            }
+
+            self.partial.extend_from_slice(&buf[..count]);
+            while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+                let rest = self.partial.split_off(end + 1);
+                let line = mem::replace(&mut self.partial, rest);
+                if !handle(&mut self.handler, Some(trim_line(&line))) {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+
+    pub struct Reactor {
+        /// Streams that have been added since the reactor last looked.
+        added: Mutex<Vec<Stream>>,
+
+        /// Written to in order to wake the reactor up when a stream is added.
+        wake: RawFd,
+    }
+
+    impl Reactor {
+        pub fn add(&self, fd: RawFd, reader: Box<dyn Read + Send>, handler: LineHandler, finished: Sender<()>) {
+            self.added.lock().unwrap().push(Stream {
+                fd: fd,
+                reader: reader,
+                partial: vec![],
+                handler: handler,
+                _finished: finished,
+            });
+            let byte = 0u8;
+            unsafe {
+                libc::write(self.wake, &byte as *const u8 as *const libc::c_void, 1);
+            }
+        }
+    }
+
+    /// The reactor, which is started the first time it's needed.
+    pub fn reactor() -> Option<&'static Reactor> {
+        START.call_once(|| match start() {
+            Ok(reactor) => unsafe { REACTOR = reactor },
+            Err(e) => eprintln!("unable to start reading program output: {}", e),
+        });
+        unsafe { REACTOR.as_ref() }
+    }
+
+    fn start() -> io::Result<&'static Reactor> {
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Programs that are started mustn't hold on to the pipe, and the reactor
+        // mustn't block emptying it.
+        for &fd in fds.iter() {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        unsafe { libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK) };
+
+        let reactor: &'static Reactor = Box::leak(Box::new(Reactor {
+            added: Mutex::new(vec![]),
+            wake: fds[1],
+        }));
+        let woken = fds[0];
+        thread::spawn(move || run(reactor, woken));
+        Ok(reactor)
+    }
+
+    fn run(reactor: &'static Reactor, woken: RawFd) {
+        let mut streams: Vec<Stream> = vec![];
+        let mut buf = [0u8; READ_SIZE];
+        loop {
+            streams.extend(reactor.added.lock().unwrap().drain(..));
+
+            // The wake pipe goes first, followed by each stream in order.
+            let mut fds: Vec<libc::pollfd> = Some(woken)
+                .into_iter()
+                .chain(streams.iter().map(|s| s.fd))
+                .map(|fd| libc::pollfd {
+                    fd: fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+                // Interrupted by a signal.  Nothing else can go wrong with a list of open pipes.
+                continue;
+            }
+
+            if fds[0].revents != 0 {
+                while unsafe { libc::read(woken, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+            }
+
+            // Work backwards, so that a stream that's finished with can be swapped out for one
+            // that's already been looked at.  A hangup or an error is picked up by the read.
+            for idx in (0..streams.len()).rev() {
+                if fds[idx + 1].revents != 0 && !streams[idx].read(&mut buf) {
+                    streams.swap_remove(idx);
+                }
+            }
+        }
+    }
+}
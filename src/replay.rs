@@ -0,0 +1,186 @@
+// `exclave replay` feeds the log messages from a recorded run's events.log
+// back through the broadcaster, so that interfaces and loggers can be
+// developed against real production traffic without a jig or a device.
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use spillbuffer::SpillBuffer;
+use unit::UnitName;
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+pub struct Replay {
+    /// Who the requests to the manager come from.
+    id: UnitName,
+    path: PathBuf,
+
+    /// How many times faster than it was recorded to replay the run.
+    speed: f64,
+
+    /// Whether to shut down once the whole log has been replayed.
+    exit: bool,
+
+    /// How many bytes of messages to keep in memory while they wait to be replayed.
+    /// The rest wait on disk, as a run that went on for days can have a very long log.
+    memory_limit: u64,
+
+    broadcaster: UnitBroadcaster,
+    started: bool,
+}
+
+impl Replay {
+    pub fn new(path: &Path, speed: f64, exit: bool, memory_limit: u64, broadcaster: &UnitBroadcaster) -> Replay {
+        Replay {
+            id: UnitName::internal("replay"),
+            path: path.to_owned(),
+            speed: speed,
+            exit: exit,
+            memory_limit: memory_limit,
+            broadcaster: broadcaster.clone(),
+            started: false,
+        }
+    }
+
+    pub fn process_message(&mut self, msg: &UnitEvent) {
+        match *msg {
+            // The interfaces and loggers have been started once the first rescan is done.
+            UnitEvent::RescanFinish if !self.started => {
+                self.started = true;
+                self.start();
+            }
+            _ => (),
+        }
+    }
+
+    fn start(&self) {
+        let mut events = match read_events(&self.path, self.memory_limit) {
+            Ok(events) => events,
+            Err(e) => {
+                self.log_error(format!("unable to read {}: {}", self.path.display(), e));
+                return;
+            }
+        };
+        let spilled = match events.spilled() {
+            0 => String::new(),
+            n => format!(", {} of them from disk", n),
+        };
+        self.broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_info(
+            self.id.clone(),
+            format!("replaying {} messages from {} at {}x{}", events.len(), self.path.display(), self.speed, spilled),
+        )));
+
+        let id = self.id.clone();
+        let speed = self.speed;
+        let exit = self.exit;
+        let broadcaster = self.broadcaster.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            loop {
+                let record = match events.pop() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let message = format!("unable to read back spilled messages: {}", e);
+                        broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(id.clone(), message)));
+                        break;
+                    }
+                };
+                let (offset, entry) = match parse_line(&String::from_utf8_lossy(&record)) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                let due = Duration::from_millis((duration_ms(offset) as f64 / speed) as u64);
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    thread::sleep(due - elapsed);
+                }
+                broadcaster.broadcast(&UnitEvent::Log(entry));
+            }
+            broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), "replay finished".to_owned())));
+            if exit {
+                let contents = ManagerControlMessageContents::Shutdown(Some("replay finished".to_owned()));
+                broadcaster.broadcast(&UnitEvent::ManagerRequest(ManagerControlMessage::new(&id, contents)));
+            }
+        });
+    }
+
+    fn log_error(&self, message: String) {
+        self.broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(self.id.clone(), message)));
+    }
+}
+
+/// The replay speed given on the command line, e.g. "1" for as it was recorded, or "10" for ten times faster.
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("{} is not a positive number", s)),
+    }
+}
+
+/// The log messages in an event log.  Its "stats" lines and final "result" line aren't log
+/// messages, and are left out.  Each message is given the time it's replayed at, rather than
+/// the time it was recorded at, so that anything watching sees them as they'd see a live run.
+///
+/// The messages are kept as they appear in the log, but with the time since the first message
+/// in place of the time they were logged at, and only `memory_limit` bytes of them are kept in
+/// memory.  The rest are spilled to a file in the temporary directory until they're replayed.
+fn read_events(path: &Path, memory_limit: u64) -> io::Result<SpillBuffer> {
+    let mut events = SpillBuffer::new(memory_limit, &env::temp_dir());
+    let mut first = None;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let logged = match parse_line(&line) {
+            Some((logged, _)) => logged,
+            None => continue,
+        };
+        let first = *first.get_or_insert(logged);
+        // Clocks can step backwards partway through a run.
+        let offset = if logged > first { logged - first } else { Duration::from_secs(0) };
+        let rest = &line[line.find('\t').unwrap()..];
+        events.push(format!("{}.{:09}{}", offset.as_secs(), offset.subsec_nanos(), rest).into_bytes())?;
+    }
+    Ok(events)
+}
+
+/// A log message from a line of an event log, and when it was logged.
+fn parse_line(line: &str) -> Option<(Duration, LogEntry)> {
+    // Each line is "<secs>.<nsecs>\t<kind>\t<unit>\t<message>".
+    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let logged = parse_time(fields[0])?;
+    let id = unit_name(fields[2])?;
+    let entry = match fields[1] {
+        "info" => LogEntry::new_info(id, fields[3].to_owned()),
+        "error" => LogEntry::new_error(id, fields[3].to_owned()),
+        _ => return None,
+    };
+    Some((logged, entry))
+}
+
+fn parse_time(s: &str) -> Option<Duration> {
+    let mut parts = s.splitn(2, '.');
+    let secs = parts.next()?.parse::<u64>().ok()?;
+    let nsecs = match parts.next() {
+        Some(n) => n.parse::<u32>().ok()?,
+        None => 0,
+    };
+    Some(Duration::new(secs, nsecs))
+}
+
+/// The unit a message was logged by, as written in the event log, e.g. "led.test", or "mes.internal".
+fn unit_name(s: &str) -> Option<UnitName> {
+    if s.ends_with(".internal") {
+        return Some(UnitName::internal(&s[..s.len() - ".internal".len()]));
+    }
+    UnitName::from_str(s, "internal").ok()
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
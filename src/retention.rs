@@ -0,0 +1,96 @@
+// Removing old run directories, so that a station's log directory doesn't
+// slowly fill its storage with runs that nobody is going to look at again.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How much run history to keep.  Anything that isn't set is unlimited.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// How many runs to keep.
+    pub max_runs: Option<usize>,
+
+    /// How long to keep runs for, counted from when they finished.
+    pub max_age: Option<Duration>,
+
+    /// How many bytes all the runs together may take up.
+    pub max_size: Option<u64>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_runs.is_none() && self.max_age.is_none() && self.max_size.is_none()
+    }
+}
+
+/// What was removed.
+#[derive(Default)]
+pub struct Pruned {
+    pub runs: usize,
+    pub bytes: u64,
+}
+
+struct RunDirectory {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Remove the oldest runs in `directory` until what's left is within `retention`.  The most
+/// recent run is always kept, however big or old it is, as it may be the one being looked at.
+pub fn prune(directory: &Path, retention: &Retention) -> io::Result<Pruned> {
+    let mut runs = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        // Anything else in the directory, e.g. a CSV file that a logger writes to, isn't a run.
+        let events = match path.join("events.log").metadata() {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        runs.push(RunDirectory {
+            modified: events.modified()?,
+            size: size_of(&path)?,
+            path: path,
+        });
+    }
+    runs.sort_by_key(|r| r.modified);
+
+    let now = SystemTime::now();
+    let mut total: u64 = runs.iter().map(|r| r.size).sum();
+    let mut remaining = runs.len();
+    let mut pruned = Pruned::default();
+    for run in runs.iter().take(runs.len().saturating_sub(1)) {
+        let too_many = retention.max_runs.map(|max| remaining > max).unwrap_or(false);
+        let too_old = match retention.max_age {
+            Some(max) => now.duration_since(run.modified).map(|age| age > max).unwrap_or(false),
+            None => false,
+        };
+        let too_big = retention.max_size.map(|max| total > max).unwrap_or(false);
+        if !too_many && !too_old && !too_big {
+            break;
+        }
+
+        fs::remove_dir_all(&run.path)?;
+        remaining -= 1;
+        total -= run.size;
+        pruned.runs += 1;
+        pruned.bytes += run.size;
+    }
+    Ok(pruned)
+}
+
+/// How many bytes the files underneath `path` take up.
+fn size_of(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += size_of(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,320 @@
+extern crate humantime;
+#[cfg(unix)]
+extern crate libc;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use self::humantime::{format_duration, format_rfc3339_seconds};
+
+use artifacts;
+use junit::{self, Outcome};
+use logsinks::Record;
+use unit::{UnitKind, UnitName};
+use unitbroadcaster::LogEntry;
+use usage::ProcessStats;
+
+/// Environment variable pointing tests and support programs at the run directory.
+pub const RUN_DIRECTORY_ENV: &str = "EXCLAVE_RUN_DIR";
+
+/// Environment variable pointing tests and support programs at the artifact directory.
+pub const ARTIFACT_DIRECTORY_ENV: &str = "EXCLAVE_ARTIFACT_DIR";
+
+/// Environment variable holding the serial number of the device under test, when it's known.
+pub const SERIAL_ENV: &str = "EXCLAVE_SERIAL";
+
+/// Environment variable holding the id of the operator who's logged in, if one is.
+pub const OPERATOR_ENV: &str = "EXCLAVE_OPERATOR";
+
+/// Prefix of the environment variables holding variables that were passed in when the run was started.
+pub const VARIABLE_ENV_PREFIX: &str = "EXCLAVE_VAR_";
+
+/// The environment variable that a run variable is made available in, e.g. "EXCLAVE_VAR_REVISION" for "revision".
+pub fn variable_env(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", VARIABLE_ENV_PREFIX, name)
+}
+
+/// How many bytes are free for exclave to use on the volume holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Other, "free space can only be checked on Unix"))
+}
+
+/// A single execution of a scenario.
+///
+/// When a log directory is configured, each run gets its own directory
+/// underneath it containing an `events.log` with everything that happened
+/// during the run, a transcript for each test that produced output, an
+/// `artifacts/` directory that tests may store files in, and a `junit.xml`
+/// report and `artifacts.tsv` manifest once the run has finished.
+pub struct Run {
+    /// A unique name for this run, which is also the name of its directory.
+    id: String,
+
+    /// The scenario being run.
+    scenario: UnitName,
+
+    /// The serial number of the device under test, if it's known.
+    serial: RefCell<Option<String>>,
+
+    /// The operator who was logged in when the run started, if one was.
+    operator: Option<String>,
+
+    /// The variables the run was started with, and any tags attached to it since, as the scenario logged them.
+    variables: RefCell<Vec<(String, String)>>,
+
+    /// The directory that holds the run's logs and artifacts.
+    directory: PathBuf,
+
+    /// When the run was started.
+    started: SystemTime,
+
+    /// Log of every event that happened during this run.
+    events: RefCell<File>,
+
+    /// Per-test transcripts, opened as tests produce output.
+    transcripts: RefCell<HashMap<UnitName, File>>,
+
+    /// How each test that was started or skipped ended up.
+    report: RefCell<junit::Report>,
+
+    /// Artifacts that tests announced, by path relative to the run directory, and the test each belongs to.
+    linked: RefCell<HashMap<String, String>>,
+}
+
+impl Run {
+    /// Create a new run directory underneath `base`.
+    /// The directory is named after the start time, the serial number
+    /// (if known), and the scenario.
+    pub fn new(base: &Path, scenario: &UnitName, serial: Option<&str>, operator: Option<&str>) -> io::Result<Run> {
+        let started = SystemTime::now();
+
+        // Turn "2018-02-14T00:28:07Z" into "20180214T002807Z", which is friendlier as a filename.
+        let timestamp: String = format!("{}", format_rfc3339_seconds(started))
+            .chars()
+            .filter(|c| *c != '-' && *c != ':')
+            .collect();
+        let mut name = timestamp;
+        if let Some(serial) = serial {
+            name.push('-');
+            name.push_str(&Self::sanitize(serial));
+        }
+        name.push('-');
+        name.push_str(&Self::sanitize(scenario.id()));
+
+        // Two runs may start within the same second, so make sure the name is unique.
+        fs::create_dir_all(base)?;
+        let mut id = name.clone();
+        let mut suffix = 1;
+        let directory = loop {
+            let candidate = base.join(&id);
+            match fs::create_dir(&candidate) {
+                Ok(_) => break candidate,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    id = format!("{}-{}", name, suffix);
+                    suffix += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        fs::create_dir(directory.join("artifacts"))?;
+
+        let events = File::create(directory.join("events.log"))?;
+
+        Ok(Run {
+            id: id,
+            scenario: scenario.clone(),
+            serial: RefCell::new(serial.map(|s| s.to_owned())),
+            operator: operator.map(|o| o.to_owned()),
+            variables: RefCell::new(vec![]),
+            directory: directory,
+            started: started,
+            events: RefCell::new(events),
+            transcripts: RefCell::new(HashMap::new()),
+            report: RefCell::new(junit::Report::new()),
+            linked: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn scenario(&self) -> &UnitName {
+        &self.scenario
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn artifact_directory(&self) -> PathBuf {
+        self.directory.join("artifacts")
+    }
+
+    /// Make the run directory available to programs started from here on.
+    pub fn export(&self) {
+        env::set_var(RUN_DIRECTORY_ENV, &self.directory);
+        env::set_var(ARTIFACT_DIRECTORY_ENV, self.artifact_directory());
+    }
+
+    /// Stop advertising the run directory to new programs.
+    pub fn unexport(&self) {
+        env::remove_var(RUN_DIRECTORY_ENV);
+        env::remove_var(ARTIFACT_DIRECTORY_ENV);
+    }
+
+    /// Record a log entry in the event log, and in the test's transcript if it came from a test.
+    pub fn record(&self, entry: &LogEntry) -> io::Result<()> {
+        writeln!(
+            self.events.borrow_mut(),
+            "{}.{:09}\t{}\t{}\t{}",
+            entry.secs(),
+            entry.nsecs(),
+            entry.kind().as_str(),
+            entry.id(),
+            entry.message()
+        )?;
+
+        if *entry.id().kind() != UnitKind::Test {
+            if let Some(Record::Variable(key, value)) = Record::from_log(entry) {
+                let mut variables = self.variables.borrow_mut();
+                variables.retain(|&(ref k, _)| *k != key);
+                variables.push((key, value));
+            }
+            return Ok(());
+        }
+
+        let mut transcripts = self.transcripts.borrow_mut();
+        if !transcripts.contains_key(entry.id()) {
+            let file = OpenOptions::new().create(true).append(true).open(self.transcript_path(entry.id()))?;
+            transcripts.insert(entry.id().clone(), file);
+        }
+        let transcript = transcripts.get_mut(entry.id()).unwrap();
+        writeln!(transcript, "{}", entry.message())
+    }
+
+    /// A test reported the serial number after the run started, too late to name the directory after it.
+    /// It's still recorded with the result, and in the JUnit report.
+    pub fn set_serial(&self, serial: &str) {
+        *self.serial.borrow_mut() = Some(serial.to_owned());
+    }
+
+    /// A test announced a file it stored, either as a path relative to the artifact directory, or as a
+    /// full path, which must be somewhere in the run directory.  Returns where it is in the run directory.
+    pub fn link_artifact(&self, test: &UnitName, path: &str) -> Option<String> {
+        let full_path = self.artifact_directory().join(path);
+        let relative = full_path.strip_prefix(&self.directory).ok()?;
+        let relative: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        let relative = relative.join("/");
+        artifacts::resolve(&self.directory, &relative)?;
+        self.linked.borrow_mut().insert(relative.clone(), test.id().clone());
+        Some(relative)
+    }
+
+    /// The files in the run directory so far, and the tests they belong to.
+    pub fn artifacts(&self) -> io::Result<Vec<artifacts::Artifact>> {
+        let mut owners = self.linked.borrow().clone();
+        for test in self.transcripts.borrow().keys() {
+            owners.insert(format!("{}.log", Self::sanitize(test.id())), test.id().clone());
+        }
+        artifacts::collect(&self.directory, &owners)
+    }
+
+    /// Note that a test has started, so that its time can be reported.
+    pub fn test_started(&self, test: &UnitName) {
+        self.report.borrow_mut().started(test);
+    }
+
+    /// Note how a test ended up, for the report written when the run finishes.
+    pub fn test_finished(&self, test: &UnitName, outcome: Outcome) {
+        self.report.borrow_mut().finished(test, outcome);
+    }
+
+    /// Record what a unit's program used in the event log, e.g. to find the tests that take longest.
+    pub fn record_stats(&self, stats: &ProcessStats) -> io::Result<()> {
+        let max_rss = match stats.max_rss_kb() {
+            Some(kb) => format!("{}K", kb),
+            None => "-".to_owned(),
+        };
+        writeln!(
+            self.events.borrow_mut(),
+            "{}.{:09}\tstats\t{}\twall={}ms user={}ms system={}ms maxrss={}",
+            stats.secs(),
+            stats.nsecs(),
+            stats.id(),
+            stats.wall_ms(),
+            stats.user_ms(),
+            stats.system_ms(),
+            max_rss
+        )
+    }
+
+    /// Note the result of the run, and how long it took, at the end of the event log,
+    /// and write the JUnit report and the list of artifacts.
+    pub fn finish(&self, code: u32, message: &str) -> io::Result<()> {
+        let elapsed = self.started.elapsed().unwrap_or(Duration::from_secs(0));
+        {
+            let mut events = self.events.borrow_mut();
+            let serial = self.serial.borrow().clone().unwrap_or_default();
+            writeln!(events, "result\t{}\t{}\t{}\t{}", code, message, format_duration(elapsed), serial)?;
+            events.flush()?;
+        }
+
+        // Transcripts must be complete before they're copied into the report.
+        for transcript in self.transcripts.borrow_mut().values_mut() {
+            transcript.flush()?;
+        }
+        let mut junit = File::create(self.directory.join("junit.xml"))?;
+        let serial = self.serial.borrow();
+        let variables = self.variables.borrow();
+        let run = junit::RunInfo {
+            scenario: &self.scenario,
+            run_id: &self.id,
+            serial: serial.as_ref(),
+            operator: self.operator.as_ref(),
+            variables: &variables,
+            timestamp: &format!("{}", format_rfc3339_seconds(self.started)),
+            time: elapsed,
+        };
+        self.report.borrow().write(&mut junit, &run, &|test| self.transcript_path(test))?;
+
+        // Written last, so that it covers everything else.
+        artifacts::write_manifest(&self.directory, &self.artifacts()?)
+    }
+
+    /// Where a test's output is written.
+    fn transcript_path(&self, test: &UnitName) -> PathBuf {
+        self.directory.join(format!("{}.log", Self::sanitize(test.id())))
+    }
+
+    /// Make a string safe to use as part of a filename.
+    fn sanitize(s: &str) -> String {
+        s.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect()
+    }
+}
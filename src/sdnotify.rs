@@ -0,0 +1,108 @@
+// Telling systemd when exclave is ready, and that it's still healthy,
+// when it's run as a Type=notify service with WatchdogSec= set.
+use std::env;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+
+pub struct SdNotify {
+    /// The socket systemd listens for notifications on.
+    socket: String,
+
+    /// Whether READY=1 has been sent.
+    ready: bool,
+}
+
+impl SdNotify {
+    /// Start talking to systemd, if exclave was started by it.  If the service has a watchdog,
+    /// the main loop is sent a ping at twice the rate that systemd expects to hear from it.
+    /// Each one is only passed on once every component has handled everything before it,
+    /// so a wedged loop or manager stops the pings and gets exclave restarted.
+    pub fn new(broadcaster: &UnitBroadcaster) -> Option<SdNotify> {
+        let socket = env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog_usec = env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok());
+        let watchdog_pid = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok());
+
+        // Programs that units start mustn't be able to speak for exclave.
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"].iter() {
+            env::remove_var(var);
+        }
+
+        match (watchdog_usec, watchdog_pid) {
+            (Some(usec), pid) if usec > 0 && pid.map(|p| p == ::std::process::id()).unwrap_or(true) => {
+                let interval = Duration::from_micros(usec / 2);
+                let broadcaster = broadcaster.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(interval);
+                    broadcaster.broadcast(&UnitEvent::WatchdogPing);
+                });
+            }
+            _ => (),
+        }
+
+        Some(SdNotify { socket: socket, ready: false })
+    }
+
+    pub fn process_message(&mut self, msg: &UnitEvent) {
+        let state = match *msg {
+            // Every unit has been loaded once the first rescan is done.
+            UnitEvent::RescanFinish if !self.ready => {
+                self.ready = true;
+                "READY=1"
+            }
+            UnitEvent::WatchdogPing => "WATCHDOG=1",
+            UnitEvent::Shutdown => "STOPPING=1",
+            _ => return,
+        };
+        // There's nobody to tell if systemd can't be reached.
+        send(&self.socket, state).ok();
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    extern crate libc;
+
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+
+    let sock = UnixDatagram::unbound()?;
+    if !socket.starts_with('@') {
+        sock.send_to(state.as_bytes(), socket)?;
+        return Ok(());
+    }
+
+    // Sockets in the abstract namespace start with a NUL rather than an '@', which std can't address.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let name = &socket.as_bytes()[1..];
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "NOTIFY_SOCKET is too long"));
+    }
+    for (dest, src) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dest = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    let result = unsafe {
+        libc::sendto(
+            sock.as_raw_fd(),
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_: &str, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "systemd is only available on Unix"))
+}
@@ -0,0 +1,279 @@
+// `exclave spc` works out process-capability figures for each measurement from
+// the runs in the log directory, for quality engineering reviews: how much a
+// measurement varies from device to device, and how comfortably that fits
+// within its limits.
+extern crate humantime;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use check;
+use config::Config;
+use logsinks::csv::write_row;
+use logsinks::Record;
+use unit::{UnitKind, UnitName};
+use unitbroadcaster::LogEntry;
+use unitmanager::UnitManager;
+use units::limits::format_number;
+
+/// How the figures are printed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpcFormat {
+    Csv,
+    Json,
+}
+
+impl SpcFormat {
+    pub fn from_str(s: &str) -> Option<SpcFormat> {
+        match s.to_lowercase().as_str() {
+            "csv" => Some(SpcFormat::Csv),
+            "json" => Some(SpcFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Capability figures for one measurement of one test.  Runs with different limits for it,
+/// e.g. because they were of different SKUs, get figures of their own.
+#[derive(Clone, Debug, Serialize)]
+pub struct Capability {
+    pub test: String,
+    pub measurement: String,
+    pub unit: Option<String>,
+
+    /// How many times it was measured.
+    pub count: usize,
+
+    pub mean: f64,
+
+    /// The sample standard deviation, if it was measured more than once.
+    pub sigma: Option<f64>,
+
+    pub min: f64,
+    pub max: f64,
+
+    /// The lower and upper specification limits.  Where more than one Limits unit covers the
+    /// measurement, these are the tightest of them.
+    pub lsl: Option<f64>,
+    pub usl: Option<f64>,
+
+    /// How well the spread fits between the limits, if there are both.
+    pub cp: Option<f64>,
+
+    /// How well the spread fits within the nearer limit, allowing for the mean being off-centre.
+    pub cpk: Option<f64>,
+}
+
+pub(crate) struct Samples {
+    pub(crate) test: String,
+    pub(crate) measurement: String,
+    pub(crate) unit: Option<String>,
+    pub(crate) lsl: Option<f64>,
+    pub(crate) usl: Option<f64>,
+    pub(crate) values: Vec<f64>,
+}
+
+impl Samples {
+    pub(crate) fn capability(&self) -> Capability {
+        let count = self.values.len();
+        let mean = self.values.iter().sum::<f64>() / count as f64;
+        let sigma = if count > 1 {
+            Some((self.values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (count - 1) as f64).sqrt())
+        } else {
+            None
+        };
+        // A measurement that never varies has no meaningful capability.
+        let spread = sigma.filter(|s| *s > 0.0).map(|s| 3.0 * s);
+        let cp = match (self.lsl, self.usl, spread) {
+            (Some(lsl), Some(usl), Some(spread)) => Some((usl - lsl) / (2.0 * spread)),
+            _ => None,
+        };
+        let cpu = match (self.usl, spread) {
+            (Some(usl), Some(spread)) => Some((usl - mean) / spread),
+            _ => None,
+        };
+        let cpl = match (self.lsl, spread) {
+            (Some(lsl), Some(spread)) => Some((mean - lsl) / spread),
+            _ => None,
+        };
+        let cpk = match (cpl, cpu) {
+            (Some(l), Some(u)) => Some(l.min(u)),
+            (k, None) | (None, k) => k,
+        };
+        Capability {
+            test: self.test.clone(),
+            measurement: self.measurement.clone(),
+            unit: self.unit.clone(),
+            count: count,
+            mean: mean,
+            sigma: sigma,
+            min: self.values.iter().cloned().fold(::std::f64::INFINITY, f64::min),
+            max: self.values.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max),
+            lsl: self.lsl,
+            usl: self.usl,
+            cp: cp,
+            cpk: cpk,
+        }
+    }
+}
+
+/// How far back to look, as a number of seconds or a duration such as "30days".
+pub fn parse_since(s: &str) -> Result<Duration, String> {
+    match s.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_duration(s).map_err(|e| e.to_string()),
+    }
+}
+
+/// Print capability figures for every numeric measurement in the runs under `log_directory`
+/// that started within `since`, or in all of them, and that have every variable or tag in
+/// `filter`.  Units are loaded as for `exclave check`, so that each measurement is compared
+/// with the limits it's checked against now.
+pub fn spc(
+    dirs: &[&str],
+    namespaced: bool,
+    config: &Arc<Mutex<Config>>,
+    log_directory: &Path,
+    since: Option<Duration>,
+    filter: &[(String, String)],
+    format: SpcFormat,
+) -> io::Result<()> {
+    // Problems with the units go to stderr, to keep them out of the figures.
+    let (manager, _) = check::load(dirs, namespaced, config, &mut io::stderr())?;
+    let cutoff = since.map(|since| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+        now.saturating_sub(since.as_secs())
+    });
+
+    let mut samples = BTreeMap::new();
+    let mut run_directories: Vec<_> = fs::read_dir(log_directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("events.log").is_file())
+        .collect();
+    run_directories.sort();
+    for directory in run_directories {
+        if let Err(e) = add_run(&manager, &directory.join("events.log"), cutoff, filter, &mut samples) {
+            eprintln!("unable to read {}: {}", directory.join("events.log").display(), e);
+        }
+    }
+
+    let figures: Vec<Capability> = samples.values().map(|s| s.capability()).collect();
+    match format {
+        SpcFormat::Json => println!("{}", serde_json::to_string_pretty(&figures)?),
+        SpcFormat::Csv => {
+            let stdout = io::stdout();
+            let mut output = stdout.lock();
+            let header = [
+                "test", "measurement", "unit", "count", "mean", "sigma", "min", "max", "lsl", "usl", "cp", "cpk",
+            ];
+            write_row(&mut output, &header.iter().map(|s| s.to_string()).collect::<Vec<_>>())?;
+            let number = |n: Option<f64>| n.map(format_number).unwrap_or_default();
+            for c in figures {
+                write_row(
+                    &mut output,
+                    &[
+                        c.test,
+                        c.measurement,
+                        c.unit.unwrap_or_default(),
+                        c.count.to_string(),
+                        format_number(c.mean),
+                        number(c.sigma),
+                        format_number(c.min),
+                        format_number(c.max),
+                        number(c.lsl),
+                        number(c.usl),
+                        number(c.cp),
+                        number(c.cpk),
+                    ],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add the measurements from one run's event log, unless it started before `cutoff`, or
+/// doesn't have every one of the variables or tags in `filter`.
+fn add_run(
+    manager: &UnitManager,
+    events: &Path,
+    cutoff: Option<u64>,
+    filter: &[(String, String)],
+    samples: &mut BTreeMap<(String, String, String, String), Samples>,
+) -> io::Result<()> {
+    // Tags can be added partway through a run, so the whole log is read before any of it is used.
+    let mut variables: Vec<(String, String)> = vec![];
+    let mut measurements = vec![];
+    for (number, line) in BufReader::new(File::open(events)?).lines().enumerate() {
+        let line = line?;
+        // Each line is "<secs>.<nsecs>\t<kind>\t<unit>\t<message>", apart from the final "result" line.
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        if number == 0 {
+            let started = fields[0].split('.').next().and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+            if cutoff.map(|cutoff| started < cutoff).unwrap_or(false) {
+                return Ok(());
+            }
+        }
+        if fields[1] != "info" {
+            continue;
+        }
+        let id = match UnitName::from_str(fields[2], "test") {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        if *id.kind() != UnitKind::Test && *id.kind() != UnitKind::Scenario {
+            continue;
+        }
+        match Record::from_log(&LogEntry::new_info(id.clone(), fields[3].to_owned())) {
+            Some(Record::Variable(key, value)) => {
+                variables.retain(|&(ref k, _)| *k != key);
+                variables.push((key, value));
+            }
+            Some(Record::Measure(name, value, unit)) => {
+                // Things like firmware versions have no capability to speak of.
+                if let Ok(value) = value.parse::<f64>() {
+                    measurements.push((id, name, value, unit));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !filter.iter().all(|wanted| variables.contains(wanted)) {
+        return Ok(());
+    }
+    for (id, name, value, unit) in measurements {
+        let limits = manager.limits_for(&id, &name, &variables);
+        let lsl = limits.iter().filter_map(|l| l.min()).fold(None, |a: Option<f64>, m| Some(a.map_or(m, |a| a.max(m))));
+        let usl = limits.iter().filter_map(|l| l.max()).fold(None, |a: Option<f64>, m| Some(a.map_or(m, |a| a.min(m))));
+        let unit = limits.iter().filter_map(|l| l.unit()).next().cloned().or(unit);
+        let key = (
+            id.id().clone(),
+            name.clone(),
+            lsl.map(format_number).unwrap_or_default(),
+            usl.map(format_number).unwrap_or_default(),
+        );
+        samples
+            .entry(key)
+            .or_insert_with(|| Samples {
+                test: id.id().clone(),
+                measurement: name,
+                unit: unit,
+                lsl: lsl,
+                usl: usl,
+                values: vec![],
+            })
+            .values
+            .push(value);
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+// A first-in, first-out queue of records that only keeps so many bytes of them
+// in memory.  Once it's full, the oldest records are written out to a segment
+// file on disk, and read back from there as they're taken off the queue, so that
+// replaying a long burn-in run can't use up the RAM of a small controller.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lets several buffers in the same process have segment files of their own.
+static SEGMENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The records that have been spilled to disk.  Each one is its length, as
+/// four little-endian bytes, followed by the record itself.
+struct Segment {
+    path: PathBuf,
+    file: File,
+
+    /// Where the next record is read from, and where the next one is written to.
+    read_pos: u64,
+    write_pos: u64,
+
+    count: usize,
+}
+
+impl Segment {
+    fn create(directory: &Path) -> io::Result<Segment> {
+        let name = format!("exclave-spill-{}-{}", process::id(), SEGMENTS.fetch_add(1, Ordering::SeqCst));
+        let path = directory.join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Segment {
+            path: path,
+            file: file,
+            read_pos: 0,
+            write_pos: 0,
+            count: 0,
+        })
+    }
+
+    fn push(&mut self, record: &[u8]) -> io::Result<()> {
+        let len = record.len() as u32;
+        let header = [len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+        self.file.seek(SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(&header)?;
+        self.file.write_all(record)?;
+        self.write_pos += 4 + record.len() as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        self.file.seek(SeekFrom::Start(self.read_pos))?;
+        self.file.read_exact(&mut header)?;
+        let len = u32::from(header[0]) | u32::from(header[1]) << 8 | u32::from(header[2]) << 16 | u32::from(header[3]) << 24;
+        let mut record = vec![0u8; len as usize];
+        self.file.read_exact(&mut record)?;
+        self.read_pos += 4 + u64::from(len);
+        self.count -= 1;
+
+        // Once everything has been read back, the file can start again from the beginning.
+        if self.count == 0 {
+            self.file.set_len(0)?;
+            self.read_pos = 0;
+            self.write_pos = 0;
+        }
+        Ok(record)
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+pub struct SpillBuffer {
+    /// The newest records, oldest first.  Everything in the segment is older than these.
+    memory: VecDeque<Vec<u8>>,
+    memory_bytes: usize,
+
+    /// How many bytes of records may be kept in memory.
+    limit: usize,
+
+    /// Where the segment file is made, the first time anything is spilled.
+    directory: PathBuf,
+    segment: Option<Segment>,
+}
+
+impl SpillBuffer {
+    /// A buffer that keeps up to `limit` bytes of records in memory, and spills the rest to a
+    /// segment file in `directory`.  The file is removed again when the buffer is dropped.
+    pub fn new(limit: u64, directory: &Path) -> SpillBuffer {
+        SpillBuffer {
+            memory: VecDeque::new(),
+            memory_bytes: 0,
+            limit: if limit > usize::max_value() as u64 { usize::max_value() } else { limit as usize },
+            directory: directory.to_owned(),
+            segment: None,
+        }
+    }
+
+    /// Add a record to the back of the queue, spilling the oldest records in memory
+    /// to disk if that takes it over its limit.
+    pub fn push(&mut self, record: Vec<u8>) -> io::Result<()> {
+        self.memory_bytes += record.len();
+        self.memory.push_back(record);
+        while self.memory_bytes > self.limit {
+            let oldest = match self.memory.pop_front() {
+                Some(r) => r,
+                None => break,
+            };
+            self.memory_bytes -= oldest.len();
+            if self.segment.is_none() {
+                self.segment = Some(Segment::create(&self.directory)?);
+            }
+            self.segment.as_mut().unwrap().push(&oldest)?;
+        }
+        Ok(())
+    }
+
+    /// Take the oldest record off the front of the queue, if there is one.
+    pub fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(ref mut segment) = self.segment {
+            if segment.count > 0 {
+                return segment.pop().map(Some);
+            }
+        }
+        let record = self.memory.pop_front();
+        if let Some(ref r) = record {
+            self.memory_bytes -= r.len();
+        }
+        Ok(record)
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.spilled()
+    }
+
+    /// How many of the records are on disk rather than in memory.
+    pub fn spilled(&self) -> usize {
+        self.segment.as_ref().map(|s| s.count).unwrap_or(0)
+    }
+}
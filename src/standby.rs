@@ -0,0 +1,156 @@
+// Hot standby, for lines where a station that's down stops the conveyor.  Two
+// instances on redundant controllers send each other heartbeats, and only the
+// active one runs the jig and listens to triggers.  If the standby stops hearing
+// from the active one, it takes over.
+use std::io;
+use std::net::UdpSocket;
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use managerhandle::ManagerHandle;
+use unitbroadcaster::UnitBroadcaster;
+
+/// What each heartbeat starts with, so that anything else sent to the port is ignored.
+const HEARTBEAT_PREFIX: &str = "exclave-heartbeat";
+
+/// How many heartbeats are sent in each timeout period, so that a few can go missing.
+const HEARTBEATS_PER_TIMEOUT: u32 = 4;
+
+/// Which of the pair should be active when both are up.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Preference {
+    Primary,
+    Backup,
+}
+
+impl Preference {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Preference::Primary => "primary",
+            Preference::Backup => "backup",
+        }
+    }
+}
+
+/// A heartbeat from the other instance.
+struct Heartbeat {
+    active: bool,
+    preference: Preference,
+}
+
+impl Heartbeat {
+    fn parse(datagram: &[u8]) -> Option<Heartbeat> {
+        let text = str::from_utf8(datagram).ok()?;
+        let mut words = text.split_whitespace();
+        if words.next() != Some(HEARTBEAT_PREFIX) {
+            return None;
+        }
+        let active = match words.next()? {
+            "active" => true,
+            "standby" => false,
+            _ => return None,
+        };
+        let preference = match words.next()? {
+            "primary" => Preference::Primary,
+            "backup" => Preference::Backup,
+            _ => return None,
+        };
+        Some(Heartbeat {
+            active: active,
+            preference: preference,
+        })
+    }
+}
+
+/// Start in standby, and take over once nothing has been heard from an active instance at `peer`
+/// for `timeout`.  The backup waits twice as long at startup, so that when both start together the
+/// primary is the one that becomes active.  Should both ever be active, e.g. once a broken network
+/// link is mended, the backup goes back to standing by.
+pub fn start(
+    listen: &str,
+    peer: &str,
+    preference: Preference,
+    timeout: Duration,
+    manager: &ManagerHandle,
+    broadcaster: &UnitBroadcaster,
+) -> io::Result<()> {
+    let socket = bind(listen)?;
+    let interval = timeout / HEARTBEATS_PER_TIMEOUT;
+    socket.set_read_timeout(Some(interval))?;
+    // Jigs and triggers mustn't be started by the first rescan if the other instance is active.
+    manager.call(|m| m.set_standby(true)).ok();
+
+    let peer = peer.to_owned();
+    let manager = manager.clone();
+    let bc = broadcaster.clone();
+    thread::spawn(move || {
+        let started = Instant::now();
+        let startup_wait = match preference {
+            Preference::Primary => timeout,
+            Preference::Backup => timeout * 2,
+        };
+        let mut active = false;
+        let mut last_heard: Option<Instant> = None;
+        let mut last_sent: Option<Instant> = None;
+        let mut buf = [0u8; 128];
+        loop {
+            if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                if let Some(heartbeat) = Heartbeat::parse(&buf[..len]) {
+                    if heartbeat.active {
+                        last_heard = Some(Instant::now());
+                        if active && preference == Preference::Backup && heartbeat.preference == Preference::Primary {
+                            bc.log("standby", format!("{} is active as well, so standing by for it", peer));
+                            active = false;
+                            manager.send(|m| m.set_standby(true));
+                        }
+                    }
+                }
+            }
+
+            let silent = match last_heard {
+                Some(heard) => heard.elapsed() > timeout,
+                None => started.elapsed() > startup_wait,
+            };
+            if !active && silent {
+                match last_heard {
+                    Some(heard) => bc.log("standby", format!("nothing heard from {} for {}ms, so taking over", peer, millis(heard.elapsed()))),
+                    None => bc.log("standby", format!("nothing heard from {}, so becoming active", peer)),
+                }
+                active = true;
+                manager.send(|m| m.set_standby(false));
+            }
+
+            if last_sent.map(|sent| sent.elapsed() >= interval).unwrap_or(true) {
+                let heartbeat = format!(
+                    "{} {} {}",
+                    HEARTBEAT_PREFIX,
+                    if active { "active" } else { "standby" },
+                    preference.as_str()
+                );
+                // The peer may well be down, which is what heartbeats are for.
+                socket.send_to(heartbeat.as_bytes(), &*peer).ok();
+                last_sent = Some(Instant::now());
+            }
+        }
+    });
+    Ok(())
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Heartbeats are sent over the network, so builds without the "network" feature can't have a standby.
+#[cfg(feature = "network")]
+fn bind(address: &str) -> io::Result<UdpSocket> {
+    UdpSocket::bind(address)
+}
+
+#[cfg(not(feature = "network"))]
+fn bind(_address: &str) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "hot standby isn't supported by this build of exclave",
+    ))
+}
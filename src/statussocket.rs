@@ -0,0 +1,136 @@
+// A local socket that reports the status of every unit, so that
+// `exclave list` can inspect a running instance.
+use std::io;
+use std::path::Path;
+
+use unitbroadcaster::UnitBroadcaster;
+
+/// Where the socket lives, unless otherwise specified.
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/exclave.sock";
+
+/// Keep track of unit statuses, and answer LIST requests on the socket at `path`.
+#[cfg(unix)]
+pub fn start(path: &Path, broadcaster: &UnitBroadcaster) -> io::Result<()> {
+    unix::start(path, broadcaster)
+}
+
+#[cfg(not(unix))]
+pub fn start(_path: &Path, _broadcaster: &UnitBroadcaster) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "the status socket is only supported on Unix"))
+}
+
+/// Ask the instance listening on `path` for the status of its units, and print them.
+#[cfg(unix)]
+pub fn list(path: &Path) -> io::Result<()> {
+    unix::list(path)
+}
+
+#[cfg(not(unix))]
+pub fn list(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "the status socket is only supported on Unix"))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use unit::UnitName;
+    use unitbroadcaster::{UnitBroadcaster, UnitEvent, UnitStatus};
+
+    /// The most recent status of each unit.
+    type StatusTable = Arc<Mutex<BTreeMap<UnitName, UnitStatus>>>;
+
+    /// One unit per line: name, kind, state, and the details of its last status change.
+    fn listing(table: &StatusTable) -> String {
+        let mut out = String::new();
+        for (name, status) in table.lock().unwrap().iter() {
+            out.push_str(&format!("{}\t{}\t{}\t{}\n", name.id(), name.kind(), status.state(), status));
+        }
+        out
+    }
+
+    pub fn start(path: &Path, broadcaster: &UnitBroadcaster) -> io::Result<()> {
+        // A socket left behind by an instance that didn't shut down cleanly would stop us from binding.
+        if UnixStream::connect(path).is_err() {
+            fs::remove_file(path).ok();
+        }
+        let listener = UnixListener::bind(path)?;
+
+        let table: StatusTable = Arc::new(Mutex::new(BTreeMap::new()));
+        let receiver = broadcaster.subscribe();
+        let thr_table = table.clone();
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                if let UnitEvent::Status(ref stat) = event {
+                    let mut table = thr_table.lock().unwrap();
+                    match *stat.status() {
+                        UnitStatus::Removed(_) => {
+                            table.remove(stat.name());
+                        }
+                        ref status => {
+                            table.insert(stat.name().clone(), status.clone());
+                        }
+                    }
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let table = table.clone();
+                    thread::spawn(move || serve(stream, &table));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Answer a single request, then hang up so the client can read until the socket closes.
+    fn serve(stream: UnixStream, table: &StatusTable) {
+        let mut writer = match stream.try_clone() {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+        let mut request = String::new();
+        if BufReader::new(stream).read_line(&mut request).is_err() {
+            return;
+        }
+        let response = match request.trim().to_lowercase().as_str() {
+            "list" => listing(table),
+            other => format!("ERROR unknown request {}\n", other),
+        };
+        writer.write_all(response.as_bytes()).ok();
+    }
+
+    pub fn list(path: &Path) -> io::Result<()> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.write_all(b"LIST\n")?;
+
+        let mut rows = vec![];
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let fields: Vec<String> = line.splitn(4, '\t').map(|f| f.to_owned()).collect();
+            if fields.len() != 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, line));
+            }
+            rows.push(fields);
+        }
+
+        let nw = rows.iter().map(|r| r[0].len()).max().unwrap_or(0).max(4);
+        let kw = rows.iter().map(|r| r[1].len()).max().unwrap_or(0).max(4);
+        println!("{:nw$}  {:kw$}  {}", "UNIT", "KIND", "STATE", nw = nw, kw = kw);
+        for row in rows {
+            // The details are only interesting when something went wrong.
+            let details = if row[2] == "failed" || row[2] == "degraded" { row[3].as_str() } else { "" };
+            println!("{:nw$}  {:kw$}  {:8}  {}", row[0], row[1], row[2], details, nw = nw, kw = kw);
+        }
+        Ok(())
+    }
+}
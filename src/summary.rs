@@ -0,0 +1,304 @@
+// CSV summaries of every run, and of each day's runs, for sites whose
+// quality process lives in spreadsheets rather than in a database.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logsinks::csv::{split_row, write_row};
+use timestamp;
+use unit::UnitName;
+use yieldstats::RunOutcome;
+
+/// One row per run.
+pub const RUNS_FILE: &str = "runs.csv";
+
+/// One row per day and scenario, updated as each run finishes.
+pub const DAILY_FILE: &str = "daily.csv";
+
+/// One row per test in each run.
+pub const TESTS_FILE: &str = "tests.csv";
+
+const RUNS_HEADER: [&str; 9] = ["timestamp", "serial", "scenario", "verdict", "code", "duration", "failed_tests", "message", "variables"];
+const DAILY_HEADER: [&str; 7] = ["date", "scenario", "runs", "passed", "failed", "yield", "total_duration"];
+const TESTS_HEADER: [&str; 8] = ["timestamp", "serial", "scenario", "test", "result", "code", "duration", "message"];
+
+/// How one test in a run ended up.
+#[derive(Clone, Debug, Serialize)]
+pub struct TestResult {
+    pub test: String,
+
+    /// "pass", "fail", or "skip".
+    pub result: &'static str,
+
+    /// The test's result code, if it ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+
+    pub message: String,
+
+    /// How long the test ran for, if it ran.
+    #[serde(skip)]
+    pub duration: Option<Duration>,
+}
+
+/// Everything about a run that has finished, in the shape it's sent to the MES.
+#[derive(Debug, Serialize)]
+pub struct RunResult {
+    pub scenario: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+
+    /// Variables the run was started with, such as a lot code.
+    pub variables: BTreeMap<String, String>,
+
+    /// The scenario's result code, which is 200 if every test passed.
+    pub result: u32,
+    pub passed: bool,
+    pub message: String,
+
+    /// When the run started and finished, in RFC3339 format, in the configured timezone.
+    pub started: String,
+    pub finished: String,
+    pub duration_ms: u64,
+
+    /// The id of the run's log directory, if runs are being logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<String>,
+
+    /// Every test that finished or was skipped, in that order.
+    pub tests: Vec<TestResult>,
+}
+
+/// What's known about a run while it's underway.
+pub struct RunSummary {
+    scenario: UnitName,
+
+    /// The serial number of the device under test, if one was given or reported.
+    serial: Option<String>,
+
+    /// When the run started, in seconds since the epoch, to date it by.
+    started_secs: u64,
+
+    started: Instant,
+
+    /// Tests that finished or were skipped, in that order.
+    tests: Vec<TestResult>,
+
+    /// When each test that's running now started.
+    running: HashMap<String, Instant>,
+}
+
+impl RunSummary {
+    pub fn new(scenario: &UnitName, serial: Option<String>) -> RunSummary {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        RunSummary {
+            scenario: scenario.clone(),
+            serial: serial,
+            started_secs: now.as_secs(),
+            started: Instant::now(),
+            tests: vec![],
+            running: HashMap::new(),
+        }
+    }
+
+    /// When the run started, in seconds since the epoch.
+    pub fn started_secs(&self) -> u64 {
+        self.started_secs
+    }
+
+    /// Tests that finished or were skipped so far, in that order.
+    pub fn tests(&self) -> &Vec<TestResult> {
+        &self.tests
+    }
+
+    pub fn test_started(&mut self, test: &UnitName) {
+        self.running.insert(test.id().clone(), Instant::now());
+    }
+
+    /// A test reported the serial number partway through the run.
+    pub fn set_serial(&mut self, serial: &str) {
+        self.serial = Some(serial.to_owned());
+    }
+
+    pub fn test_finished(&mut self, test: &UnitName, result: &'static str, code: Option<i32>, message: &str) {
+        let duration = self.running.remove(test.id()).map(|started| started.elapsed());
+        self.tests.push(TestResult {
+            test: test.id().clone(),
+            result: result,
+            code: code,
+            message: message.to_owned(),
+            duration: duration,
+        });
+    }
+
+    /// Ids of the tests that failed, in the order they finished.
+    fn failed(&self) -> Vec<String> {
+        self.tests.iter().filter(|t| t.result == "fail").map(|t| t.test.clone()).collect()
+    }
+
+    /// The structured result of the run, now that it has finished.
+    pub fn result(&self, code: u32, message: &str, operator: Option<String>, variables: &[(String, String)], run: Option<String>) -> RunResult {
+        let elapsed = self.started.elapsed();
+        RunResult {
+            scenario: self.scenario.id().clone(),
+            serial: self.serial.clone(),
+            operator: operator,
+            variables: variables.iter().cloned().collect(),
+            result: code,
+            passed: code == 200,
+            message: message.to_owned(),
+            started: timestamp::rfc3339_seconds(self.started_secs),
+            finished: timestamp::now(),
+            duration_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            run: run,
+            tests: self.tests.clone(),
+        }
+    }
+
+    /// How the run went, for yield figures.
+    pub fn outcome(&self, code: u32) -> RunOutcome {
+        RunOutcome {
+            started: self.started_secs,
+            serial: self.serial.clone(),
+            passed: code == 200,
+            failed_tests: self.failed(),
+        }
+    }
+
+    /// Add the run to the end of runs.csv in `directory`, and each of its tests to the end of tests.csv,
+    /// and count it in today's row of daily.csv.  `variables` includes any tags the run was given.
+    pub fn write(&self, directory: &Path, code: u32, message: &str, variables: &[(String, String)]) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        let elapsed = self.started.elapsed();
+        let duration = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        let timestamp = timestamp::rfc3339_seconds(self.started_secs);
+        // Scenarios finish with 200 when every test passed.
+        let passed = code == 200;
+
+        let runs_path = directory.join(RUNS_FILE);
+        let needs_header = runs_path.metadata().map(|m| m.len() == 0).unwrap_or(true);
+        // Files started before variables were recorded keep the columns they have.
+        let with_variables = needs_header
+            || File::open(&runs_path)
+                .ok()
+                .and_then(|f| BufReader::new(f).lines().next())
+                .and_then(|line| line.ok())
+                .map(|line| split_row(&line).last().map(|s| s == "variables").unwrap_or(false))
+                .unwrap_or(false);
+        let mut runs = OpenOptions::new().create(true).append(true).open(&runs_path)?;
+        if needs_header {
+            write_row(&mut runs, &header(&RUNS_HEADER))?;
+        }
+        let mut row = vec![
+            timestamp.clone(),
+            self.serial.clone().unwrap_or_default(),
+            self.scenario.id().clone(),
+            if passed { "pass" } else { "fail" }.to_owned(),
+            code.to_string(),
+            format!("{:.3}", duration),
+            self.failed().join(" "),
+            message.to_owned(),
+        ];
+        if with_variables {
+            row.push(variables.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(" "));
+        }
+        write_row(&mut runs, &row)?;
+
+        let tests_path = directory.join(TESTS_FILE);
+        let needs_header = tests_path.metadata().map(|m| m.len() == 0).unwrap_or(true);
+        let mut tests = OpenOptions::new().create(true).append(true).open(&tests_path)?;
+        if needs_header {
+            write_row(&mut tests, &header(&TESTS_HEADER))?;
+        }
+        for test in &self.tests {
+            write_row(
+                &mut tests,
+                &[
+                    timestamp.clone(),
+                    self.serial.clone().unwrap_or_default(),
+                    self.scenario.id().clone(),
+                    test.test.clone(),
+                    test.result.to_owned(),
+                    test.code.map(|c| c.to_string()).unwrap_or_default(),
+                    test.duration.map(|d| format!("{}.{:03}", d.as_secs(), d.subsec_millis())).unwrap_or_default(),
+                    test.message.clone(),
+                ],
+            )?;
+        }
+
+        // The date is in the configured timezone, so that a day's runs are the ones from one shift pattern.
+        let date = timestamp[..10].to_owned();
+        update_daily(&directory.join(DAILY_FILE), &date, self.scenario.id(), passed, duration)
+    }
+}
+
+#[derive(Default)]
+struct Day {
+    passed: u64,
+    failed: u64,
+    total_duration: f64,
+}
+
+/// Count a run in its day's row, rewriting the whole file, which only grows by a row or so a day.
+fn update_daily(path: &Path, date: &str, scenario: &str, passed: bool, duration: f64) -> io::Result<()> {
+    let mut days: BTreeMap<(String, String), Day> = BTreeMap::new();
+    if let Ok(file) = File::open(path) {
+        for line in BufReader::new(file).lines().skip(1) {
+            let fields = split_row(&line?);
+            if fields.len() < DAILY_HEADER.len() {
+                continue;
+            }
+            days.insert(
+                (fields[0].clone(), fields[1].clone()),
+                Day {
+                    passed: fields[3].parse().unwrap_or(0),
+                    failed: fields[4].parse().unwrap_or(0),
+                    total_duration: fields[6].parse().unwrap_or(0.0),
+                },
+            );
+        }
+    }
+
+    {
+        let day = days.entry((date.to_owned(), scenario.to_owned())).or_insert_with(Day::default);
+        if passed {
+            day.passed += 1;
+        } else {
+            day.failed += 1;
+        }
+        day.total_duration += duration;
+    }
+
+    // Write a new copy and move it into place, so that a spreadsheet never sees half of one.
+    let temp_path = path.with_extension("csv.tmp");
+    {
+        let mut output = File::create(&temp_path)?;
+        write_row(&mut output, &header(&DAILY_HEADER))?;
+        for (&(ref date, ref scenario), day) in &days {
+            let runs = day.passed + day.failed;
+            write_row(
+                &mut output,
+                &[
+                    date.clone(),
+                    scenario.clone(),
+                    runs.to_string(),
+                    day.passed.to_string(),
+                    day.failed.to_string(),
+                    format!("{:.1}", day.passed as f64 * 100.0 / runs as f64),
+                    format!("{:.3}", day.total_duration),
+                ],
+            )?;
+        }
+    }
+    fs::rename(&temp_path, path)
+}
+
+fn header(columns: &[&str]) -> Vec<String> {
+    columns.iter().map(|s| s.to_string()).collect()
+}
@@ -0,0 +1,248 @@
+// Keeping track of every program that units start, along with anything
+// those programs start in turn, so that none of them outlive exclave.
+// Interfaces, loggers and triggers all start a program that's meant to keep
+// running for as long as they're active, and they all start, stop, reap and
+// restart it here, so that each of them handles it going away the same way.
+extern crate runny;
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use self::runny::RunnyError;
+
+use program::{Exec, Program, ProgramInput, ProgramOutput};
+use unit::{RestartPolicy, UnitDeactivateError, UnitName};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+use usage::Meter;
+
+/// A program that fails this many times within RESTART_INTERVAL_SECS is given up on.
+const RESTART_BURST: usize = 5;
+const RESTART_INTERVAL_SECS: u64 = 60;
+
+/// How a supervised program ended, shared with the thread waiting for it.
+struct Exit {
+    /// Set when the program is being stopped on purpose.
+    expected: AtomicBool,
+
+    /// The return code, once the program has exited.
+    status: Mutex<Option<i32>>,
+}
+
+/// A program that a unit keeps running while it's active.  It's started in a process group of its
+/// own, and reaped as soon as it exits, rather than being left as a zombie until its unit stops.
+/// If it exits without being asked to, the manager is sent an UnexpectedExit with its return code.
+pub struct Supervised {
+    process: Program,
+    exit: Arc<Exit>,
+}
+
+impl Supervised {
+    pub fn start(
+        id: &UnitName,
+        cmd: &str,
+        directory: PathBuf,
+        meter: Option<&Meter>,
+        control: &Sender<ManagerControlMessage>,
+    ) -> Result<Supervised, RunnyError> {
+        let mut exec = Exec::new(cmd);
+        exec.directory(&Some(directory));
+        if let Some(meter) = meter {
+            exec.meter(meter);
+        }
+        let process = exec.start()?;
+
+        let exit = Arc::new(Exit {
+            expected: AtomicBool::new(false),
+            status: Mutex::new(None),
+        });
+        let thr_exit = exit.clone();
+        let thr_waiter = process.waiter();
+        let thr_id = id.clone();
+        let thr_control = control.clone();
+        spawn_worker(id.clone(), control.clone(), move || {
+            thr_waiter.wait();
+            let result = thr_waiter.result();
+            *thr_exit.status.lock().unwrap() = Some(result);
+            if !thr_exit.expected.load(Ordering::SeqCst) {
+                thr_control
+                    .send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::UnexpectedExit(result)))
+                    .ok();
+            }
+        });
+
+        Ok(Supervised {
+            process: process,
+            exit: exit,
+        })
+    }
+
+    pub fn take_output(&mut self) -> ProgramOutput {
+        self.process.take_output()
+    }
+
+    pub fn take_error(&mut self) -> ProgramOutput {
+        self.process.take_error()
+    }
+
+    pub fn take_input(&mut self) -> ProgramInput {
+        self.process.take_input()
+    }
+
+    /// The program's return code, if it has exited.
+    pub fn exit_status(&self) -> Option<i32> {
+        *self.exit.status.lock().unwrap()
+    }
+
+    /// Ask the program to exit, and kill its process group if it hasn't within `timeout`.  A program
+    /// that had already exited by itself has been reported as an UnexpectedExit, so it isn't an error here.
+    pub fn stop(self, timeout: Option<Duration>) -> Result<(), UnitDeactivateError> {
+        self.exit.expected.store(true, Ordering::SeqCst);
+        let exited = self.exit_status().is_some();
+        match self.process.terminate(timeout) {
+            Ok(_) if exited => Ok(()),
+            Ok(0) => Ok(()),
+            Ok(i) => Err(UnitDeactivateError::NonZeroReturn(i)),
+            Err(e) => Err(UnitDeactivateError::RunningError(e)),
+        }
+    }
+}
+
+impl Write for Supervised {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.process.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.process.flush()
+    }
+}
+
+/// Run `work` on a thread of its own, on behalf of unit `id`.  If it panics, the manager is sent
+/// a Panicked message with what the panic said, so that the unit is failed, and restarted if its
+/// policy allows it, rather than being left active without the thread that was doing its work.
+pub fn spawn_worker<F>(id: UnitName, control: Sender<ManagerControlMessage>, work: F) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(work)) {
+            report_panic(&id, &control, &e);
+        }
+    })
+}
+
+/// Let the manager know that something working on behalf of unit `id` panicked.
+pub fn report_panic(id: &UnitName, control: &Sender<ManagerControlMessage>, payload: &Box<dyn Any + Send>) {
+    let message = panic_message(payload).to_owned();
+    control.send(ManagerControlMessage::new(id, ManagerControlMessageContents::Panicked(message))).ok();
+}
+
+/// What a panic said, if it said anything.
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "no message"
+    }
+}
+
+/// When a unit's program was recently restarted, so that one that can't stay up isn't restarted forever.
+#[derive(Default)]
+pub struct Restarts {
+    times: VecDeque<Instant>,
+}
+
+impl Restarts {
+    pub fn new() -> Restarts {
+        Restarts::default()
+    }
+
+    /// Whether `policy` calls for restarting a program that failed because of `reason`.  A
+    /// `code` of Some(0) means it exited cleanly, and None that it failed some other way.
+    /// Each restart that's allowed is counted, and the reason it's not is returned otherwise.
+    pub fn allow(&mut self, policy: RestartPolicy, code: Option<i32>, reason: &str) -> Result<(), String> {
+        match (policy, code) {
+            (RestartPolicy::No, _) => return Err(reason.to_owned()),
+            (RestartPolicy::OnFailure, Some(0)) => return Err(reason.to_owned()),
+            _ => (),
+        }
+
+        let interval = Duration::from_secs(RESTART_INTERVAL_SECS);
+        while self.times.front().map(|t| t.elapsed() > interval).unwrap_or(false) {
+            self.times.pop_front();
+        }
+        if self.times.len() >= RESTART_BURST {
+            return Err(format!(
+                "{}, and it failed {} times in {} seconds, so not restarting it",
+                reason, RESTART_BURST, RESTART_INTERVAL_SECS
+            ));
+        }
+        self.times.push_back(Instant::now());
+        Ok(())
+    }
+}
+
+/// Make sure that every program started from now on, and everything it starts, is
+/// killed when exclave exits, however it exits.  On Unix, each program is already
+/// started in a process group of its own, which is signalled when its unit stops.
+#[cfg(not(windows))]
+pub fn supervise() -> io::Result<()> {
+    Ok(())
+}
+
+/// Windows has no process groups or signals to stop them with, so exclave puts itself
+/// in a job object, which every program it starts, and every program they start, joins
+/// as well.  The job is closed when exclave exits, even if it crashes or is killed
+/// from Task Manager, which kills everything that's still running in it.
+#[cfg(windows)]
+pub fn supervise() -> io::Result<()> {
+    windows::supervise()
+}
+
+#[cfg(windows)]
+mod windows {
+    extern crate kernel32;
+    extern crate winapi;
+
+    use std::io;
+    use std::mem;
+    use std::ptr;
+
+    pub fn supervise() -> io::Result<()> {
+        let job = unsafe { kernel32::CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut limits: winapi::JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+        limits.BasicLimitInformation.LimitFlags = winapi::JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        let result = unsafe {
+            kernel32::SetInformationJobObject(
+                job,
+                winapi::JobObjectExtendedLimitInformation,
+                &mut limits as *mut _ as winapi::LPVOID,
+                mem::size_of_val(&limits) as winapi::DWORD,
+            )
+        };
+        // Windows 7 doesn't allow jobs to be nested, so this fails if whatever
+        // started exclave, e.g. a service manager, put it in a job already.
+        if result == 0 || unsafe { kernel32::AssignProcessToJobObject(job, kernel32::GetCurrentProcess()) } == 0 {
+            let error = io::Error::last_os_error();
+            unsafe { kernel32::CloseHandle(job) };
+            return Err(error);
+        }
+
+        // The handle is deliberately never closed, as that would kill exclave along with everything else.
+        Ok(())
+    }
+}
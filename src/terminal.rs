@@ -2,8 +2,9 @@ extern crate console;
 
 use self::console::Term;
 use unit::{UnitKind, UnitName};
-use unitbroadcaster::{LogEntry, UnitCategoryStatus, UnitEvent, UnitStatus};
+use unitbroadcaster::{LogEntry, LogType, UnitCategoryStatus, UnitEvent, UnitStatus};
 use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Receiver;
 use unitbroadcaster::{UnitBroadcaster};
 use std::thread;
@@ -16,6 +17,38 @@ pub enum TerminalOutputType {
     None,
 }
 
+/// How much of the log to show.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    /// Only errors.
+    Error,
+
+    /// Messages from units, but not exclave's own.
+    Info,
+
+    /// Everything, including exclave's own messages.
+    Debug,
+}
+
+impl LogLevel {
+    pub fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s.to_lowercase().as_ref() {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("invalid log level {}, expected error, info, or debug", s)),
+        }
+    }
+
+    fn shows(&self, entry: &LogEntry) -> bool {
+        match *self {
+            LogLevel::Error => *entry.kind() == LogType::Error,
+            LogLevel::Info => *entry.kind() == LogType::Error || *entry.id().kind() != UnitKind::Internal,
+            LogLevel::Debug => true,
+        }
+    }
+}
+
 const MAX_LOG_HISTORY: usize = 25;
 
 pub struct TerminalInterface {
@@ -42,10 +75,13 @@ pub struct TerminalInterface {
 
     /// How many lines of history to keep.
     log_history: usize,
+
+    /// Which log messages to show, which may be changed by reloading the configuration.
+    log_level: Arc<Mutex<LogLevel>>,
 }
 
 impl TerminalInterface {
-    pub fn start(output_type: Option<TerminalOutputType>, broadcaster: &UnitBroadcaster, monitor_keypress: bool) {
+    pub fn start(output_type: Option<TerminalOutputType>, log_level: &Arc<Mutex<LogLevel>>, broadcaster: &UnitBroadcaster, monitor_keypress: bool) {
         let stdout = Term::stdout();
         let output_type = match output_type {
             Some(s) => s,
@@ -53,6 +89,7 @@ impl TerminalInterface {
             None => TerminalOutputType::Plain,
         };
         let receiver = broadcaster.subscribe();
+        let log_level = log_level.clone();
 
         thread::spawn(move || {
             let mut ti = TerminalInterface {
@@ -63,6 +100,7 @@ impl TerminalInterface {
                 last_line_count: 0,
                 logs: vec![],
                 log_history: MAX_LOG_HISTORY,
+                log_level: log_level.clone(),
             };
 
             while let Ok(event) = receiver.recv() {
@@ -81,7 +119,12 @@ impl TerminalInterface {
             thread::spawn(move || {
                 loop {
                     let mut line = String::new();
-                    std::io::stdin().read_line(&mut line).expect("Failed to read line");
+                    // Without a terminal, e.g. when started by an init system, stdin is usually
+                    // closed straight away, and there's nobody to press enter anyway.
+                    match std::io::stdin().read_line(&mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => (),
+                    }
                     thread_broadcaster.broadcast(&UnitEvent::ManagerRequest(ManagerControlMessage::new(&id, ManagerControlMessageContents::StartScenario(None))));
                 }
             });
@@ -112,6 +155,9 @@ impl TerminalInterface {
                     .insert(stat.name().clone(), stat.status().clone());
             }
             UnitEvent::Log(ref log) => {
+                if !self.log_level.lock().unwrap().shows(log) {
+                    return;
+                }
                 // Ensure we have a vec for the logs to be stored.
                 self.logs.push(log.clone());
                 if self.logs.len() > self.log_history {
@@ -121,8 +167,12 @@ impl TerminalInterface {
             UnitEvent::RescanStart => (),
             UnitEvent::RescanFinish => (),
             UnitEvent::RescanRequest => (),
+            UnitEvent::ReloadRequest => (),
+            UnitEvent::ConfigReloadRequest => (),
             UnitEvent::Shutdown => (),
             UnitEvent::ManagerRequest(_) => (),
+            UnitEvent::WatchdogPing => (),
+            UnitEvent::Timing(_) => (),
         }
 
         match self.output_type {
@@ -137,11 +187,15 @@ impl TerminalInterface {
             UnitEvent::Status(stat) => println!("    {} -> {}", stat.name(), stat.status()),
             UnitEvent::Category(stat) => println!("{}: {}", stat.kind(), stat.status()),
             UnitEvent::RescanRequest => println!("Unit rescan requested"),
+            UnitEvent::ReloadRequest => println!("Unit reload requested"),
+            UnitEvent::ConfigReloadRequest => println!("Configuration reload requested"),
             UnitEvent::RescanStart => println!("Started unit recsan..."),
             UnitEvent::RescanFinish => println!("Finished rescanning units"),
             UnitEvent::Shutdown => println!("Shutting down"),
             UnitEvent::Log(log) => println!("{}", log),
             UnitEvent::ManagerRequest(_) => (),
+            UnitEvent::WatchdogPing => (),
+            UnitEvent::Timing(_) => (),
         };
     }
 
@@ -1,3 +1,4 @@
+use std::io::ErrorKind;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvError, Sender};
 use std::sync::{Arc, Mutex};
@@ -5,19 +6,25 @@ use std::thread;
 use std::time::Duration;
 
 use config::Config;
+use parsecache::{self, Item};
+use plugin::{self, PluginHost, PluginUnit, PluginVTable};
 
-use unit::{UnitKind, UnitName};
+use unit::{expand_unit_text, expansion_inputs, referenced_inputs, UnitKind, UnitName};
 use unitbroadcaster::{UnitBroadcaster, UnitEvent, UnitStatus};
 use unitlibrary::UnitLibrary;
-use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents, ManagerStatusMessage};
 
 use units::interface::InterfaceDescription;
 use units::jig::JigDescription;
-use units::logger::LoggerDescription;
+use units::logger::{LoggerDescription, LoggerOverflow, LoggerQueue};
 use units::scenario::ScenarioDescription;
 use units::test::TestDescription;
 use units::trigger::TriggerDescription;
 
+use spc::Samples;
+use testing::Harness;
+use timestamp::{parse_rfc3339, rfc3339_in, TimeZone};
+
 struct Exclave {
     broadcaster: UnitBroadcaster,
     receiver: Receiver<UnitEvent>,
@@ -103,7 +110,7 @@ impl Exclave {
         let broadcaster = UnitBroadcaster::new();
         let receiver = broadcaster.subscribe();
         let library = UnitLibrary::new(&broadcaster, &config);
-        let control = library.get_manager().borrow().get_control_channel();
+        let control = library.get_manager().get_control_channel();
         //    let unit_loader = UnitLoader::new(&unit_broadcaster);
         //    let mut unit_watcher = UnitWatcher::new(&unit_broadcaster);
 
@@ -133,15 +140,19 @@ impl Exclave {
                         .unwrap();
                 self.library
                     .get_manager()
-                    .borrow()
-                    .load_test(&desc)
+                    .call(move |manager| manager.load_test(&desc))
+                    .unwrap()
                     .unwrap();
             }
             UnitKind::Jig => {
                 let desc =
                     JigDescription::from_string(unit_text, name, &PathBuf::from("test/config"))
                         .unwrap();
-                self.library.get_manager().borrow().load_jig(&desc).unwrap();
+                self.library
+                    .get_manager()
+                    .call(move |manager| manager.load_jig(&desc))
+                    .unwrap()
+                    .unwrap();
             }
             UnitKind::Scenario => {
                 let desc = ScenarioDescription::from_string(
@@ -151,8 +162,8 @@ impl Exclave {
                 ).unwrap();
                 self.library
                     .get_manager()
-                    .borrow()
-                    .load_scenario(&desc)
+                    .call(move |manager| manager.load_scenario(&desc))
+                    .unwrap()
                     .unwrap();
             }
             _ => unimplemented!(),
@@ -217,12 +228,13 @@ fn load_dependency() {
     exclave.add_unit(&UnitName::from_str("generic", "jig").unwrap(), GENERIC_JIG);
     exclave.rescan();
 
+    let jig = UnitName::from_str("generic", "jig").unwrap();
     assert!(
         exclave
             .library
             .get_manager()
-            .borrow()
-            .jig_is_loaded(&UnitName::from_str("generic", "jig").unwrap())
+            .call(move |manager| manager.jig_is_loaded(&jig))
+            .unwrap()
     );
 }
 
@@ -518,3 +530,312 @@ Tests=master
         }
     }
 }
+
+#[test]
+/// Start a scenario from an in-memory interface, and check what the interface was told.
+fn interface_start() {
+    let mut exclave = Harness::new(None);
+    let three_name = UnitName::from_str("three", "scenario").unwrap();
+
+    for n in 1..=3 {
+        exclave
+            .add_unit(
+                &UnitName::from_str(&format!("test{}", n), "test").unwrap(),
+                &make_sleep_test(&format!("test{}-start", n), None, &format!("test{}-end", n), None),
+            ).unwrap();
+    }
+    exclave.add_unit(&three_name, THREE_TEST_SCENARIO).unwrap();
+    let interface = exclave.add_interface(&UnitName::from_str("panel", "interface").unwrap()).unwrap();
+    exclave.rescan();
+
+    interface.send("START three");
+    let (code, _) = exclave.wait_for_finish(&three_name).unwrap();
+    assert_eq!(code, 200);
+
+    // The interface is told once the manager has dealt with the scenario finishing.
+    exclave.manager().flush();
+    let received = interface.received();
+    assert!(received.iter().any(|line| line == "START three"));
+    for n in 1..=3 {
+        assert!(received.iter().any(|line| line.starts_with(&format!("PASS test{}", n))));
+    }
+    assert!(received.iter().any(|line| line.starts_with("FINISH three 200")));
+}
+
+/// A plugin's unit that logs once it's been started.
+struct Probe {
+    host: PluginHost,
+}
+
+impl PluginUnit for Probe {
+    fn create(_id: &str, _text: &str, host: PluginHost) -> Result<Probe, String> {
+        Ok(Probe { host: host })
+    }
+
+    fn activate(&mut self) -> Result<(), String> {
+        self.host.log("probe started");
+        Ok(())
+    }
+}
+
+#[test]
+/// A unit of a kind that a plugin has added is loaded, and then started, like a logger would be.
+fn plugin_unit() {
+    plugin::register(PluginVTable::new::<Probe>("probe\0")).unwrap();
+    let mut exclave = Harness::new(Some(Duration::from_secs(30)));
+    let probe = UnitName::from_str("bench", "probe").unwrap();
+    exclave.add_unit(&probe, "[Probe]\nName=Bench probe\n").unwrap();
+    exclave.rescan();
+
+    exclave.wait_for_log(&probe, "probe started").unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// A test whose program leaves something running that holds on to its output still finishes.
+fn test_with_background_child() {
+    let mut exclave = Harness::new(Some(Duration::from_secs(30)));
+    let scenario_name = UnitName::from_str("background", "scenario").unwrap();
+    exclave
+        .add_unit(
+            &UnitName::from_str("forks", "test").unwrap(),
+            "[Test]\nName=Forks\nDescription=Leave a child running\nExecStart=/bin/sh -c \"sleep 20 & echo started\"\n",
+        ).unwrap();
+    exclave
+        .add_unit(&scenario_name, "[Scenario]\nName=Background\nDescription=Run forks\nTests=forks\n")
+        .unwrap();
+    exclave.rescan();
+
+    exclave.start_scenario(&scenario_name);
+    let (code, _) = exclave.wait_for_finish(&scenario_name).unwrap();
+    assert_eq!(code, 200);
+}
+
+fn hello(name: &str) -> ManagerStatusMessage {
+    ManagerStatusMessage::Hello(name.to_owned())
+}
+
+fn pop_hello(queue: &LoggerQueue) -> Option<(String, u64)> {
+    match queue.pop() {
+        Some((ManagerStatusMessage::Hello(name), dropped)) => Some((name, dropped)),
+        Some((other, _)) => panic!("unexpected message {:?}", other),
+        None => None,
+    }
+}
+
+#[test]
+/// A full queue with Overflow=drop-oldest makes room for new messages, and says how many it dropped.
+fn logger_queue_drop_oldest() {
+    let queue = LoggerQueue::new(2);
+    for name in &["one", "two", "three"] {
+        queue.push(hello(name), LoggerOverflow::DropOldest).unwrap();
+    }
+    assert_eq!(queue.len(), 2);
+    assert_eq!(pop_hello(&queue), Some(("two".to_owned(), 1)));
+    assert_eq!(pop_hello(&queue), Some(("three".to_owned(), 0)));
+}
+
+#[test]
+/// A full queue with Overflow=restart turns new messages away, so the logger can be restarted.
+fn logger_queue_restart() {
+    let queue = LoggerQueue::new(1);
+    queue.push(hello("one"), LoggerOverflow::Restart).unwrap();
+    let err = queue.push(hello("two"), LoggerOverflow::Restart).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(queue.len(), 1);
+}
+
+#[test]
+/// A full queue with Overflow=block holds the sender up until the writer takes something.
+fn logger_queue_block() {
+    let queue = Arc::new(LoggerQueue::new(1));
+    queue.push(hello("one"), LoggerOverflow::Block).unwrap();
+
+    let thr_queue = queue.clone();
+    let sender = thread::spawn(move || thr_queue.push(hello("two"), LoggerOverflow::Block).unwrap());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(queue.len(), 1);
+
+    assert_eq!(pop_hello(&queue), Some(("one".to_owned(), 0)));
+    sender.join().unwrap();
+    assert_eq!(pop_hello(&queue), Some(("two".to_owned(), 0)));
+}
+
+#[test]
+/// Messages that are already queued when the logger stops are still written out.
+fn logger_queue_close_drains() {
+    let queue = LoggerQueue::new(4);
+    queue.push(hello("one"), LoggerOverflow::Block).unwrap();
+    queue.push(hello("two"), LoggerOverflow::Block).unwrap();
+    queue.close();
+
+    assert!(queue.push(hello("three"), LoggerOverflow::Block).is_err());
+    assert_eq!(pop_hello(&queue), Some(("one".to_owned(), 0)));
+    assert_eq!(pop_hello(&queue), Some(("two".to_owned(), 0)));
+    assert_eq!(pop_hello(&queue), None);
+}
+
+#[test]
+/// Times are written out as the right calendar date, including around leap days.
+fn rfc3339_dates() {
+    let cases: &[(u64, &str)] = &[
+        (0, "1970-01-01T00:00:00Z"),
+        (951_782_399, "2000-02-28T23:59:59Z"),
+        (951_782_400, "2000-02-29T00:00:00Z"),
+        (951_868_800, "2000-03-01T00:00:00Z"),
+        (1_709_208_000, "2024-02-29T12:00:00Z"),
+        (2_147_483_648, "2038-01-19T03:14:08Z"),
+        // 2100 isn't a leap year, as it's divisible by 100 but not by 400.
+        (4_107_456_000, "2100-02-28T00:00:00Z"),
+        (4_107_542_400, "2100-03-01T00:00:00Z"),
+    ];
+    for &(secs, text) in cases {
+        assert_eq!(rfc3339_in(TimeZone::Utc, secs, None), text);
+        assert_eq!(parse_rfc3339(text), Some(secs));
+    }
+    assert_eq!(rfc3339_in(TimeZone::Utc, 0, Some(5)), "1970-01-01T00:00:00.000000005Z");
+    assert_eq!(parse_rfc3339("1970-01-01T00:00:00.000000005Z"), Some(0));
+}
+
+#[test]
+/// A timezone west of UTC can put a time on the day before, and it's read back as the same time.
+fn rfc3339_negative_offset() {
+    let west = TimeZone::from_str("-0800").unwrap();
+    assert_eq!(west, TimeZone::Offset(-8 * 3600));
+
+    assert_eq!(rfc3339_in(west, 0, None), "1969-12-31T16:00:00-08:00");
+    assert_eq!(parse_rfc3339("1969-12-31T16:00:00-08:00"), Some(0));
+    assert_eq!(rfc3339_in(west, 951_868_800, None), "2000-02-29T16:00:00-08:00");
+    assert_eq!(parse_rfc3339("2000-02-29T16:00:00-08:00"), Some(951_868_800));
+
+    let east = TimeZone::from_str("+05:30").unwrap();
+    assert_eq!(rfc3339_in(east, 951_868_799, None), "2000-03-01T05:29:59+05:30");
+
+    // Anything before the epoch can't be represented.
+    assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), None);
+    assert_eq!(parse_rfc3339("1970-01-01T00:00:00+00:01"), None);
+}
+
+fn samples(lsl: Option<f64>, usl: Option<f64>, values: &[f64]) -> Samples {
+    Samples {
+        test: "rails".to_owned(),
+        measurement: "vdd".to_owned(),
+        unit: Some("V".to_owned()),
+        lsl: lsl,
+        usl: usl,
+        values: values.to_vec(),
+    }
+}
+
+fn assert_close(actual: Option<f64>, expected: f64) {
+    match actual {
+        Some(actual) => assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual),
+        None => panic!("expected {}, got nothing", expected),
+    }
+}
+
+#[test]
+/// A centred process three sigma from each limit has a Cp and Cpk of 1.
+fn spc_centred() {
+    let c = samples(Some(7.0), Some(13.0), &[9.0, 10.0, 11.0]).capability();
+    assert_eq!(c.count, 3);
+    assert_close(Some(c.mean), 10.0);
+    assert_close(c.sigma, 1.0);
+    assert_close(c.cp, 1.0);
+    assert_close(c.cpk, 1.0);
+    assert_eq!((c.min, c.max), (9.0, 11.0));
+}
+
+#[test]
+/// Moving the mean towards one limit leaves Cp alone, but brings Cpk down.
+fn spc_off_centre() {
+    let c = samples(Some(7.0), Some(13.0), &[10.0, 11.0, 12.0]).capability();
+    assert_close(c.cp, 1.0);
+    assert_close(c.cpk, 2.0 / 3.0);
+}
+
+#[test]
+/// With only one limit there's no Cp, and Cpk is measured against the limit there is.
+fn spc_one_sided() {
+    let c = samples(None, Some(16.0), &[10.0, 11.0, 12.0]).capability();
+    assert_eq!(c.cp, None);
+    assert_close(c.cpk, 5.0 / 3.0);
+
+    let c = samples(Some(8.0), None, &[10.0, 11.0, 12.0]).capability();
+    assert_eq!(c.cp, None);
+    assert_close(c.cpk, 1.0);
+}
+
+#[test]
+/// A measurement taken once, or that never varies, has no capability figures.
+fn spc_no_spread() {
+    let c = samples(Some(7.0), Some(13.0), &[10.0]).capability();
+    assert_eq!(c.sigma, None);
+    assert_eq!((c.cp, c.cpk), (None, None));
+
+    let c = samples(Some(7.0), Some(13.0), &[10.0, 10.0, 10.0]).capability();
+    assert_close(c.sigma, 0.0);
+    assert_eq!((c.cp, c.cpk), (None, None));
+}
+
+#[test]
+/// Run variables are expanded within values, but shell variables and unknown names are left alone,
+/// and a variable can't add directives of its own.
+fn unit_variables() {
+    use std::env;
+
+    env::set_var("EXCLAVE_VAR_UNIT_VARIABLES_PORT", "4000");
+    env::set_var("EXCLAVE_VAR_UNIT_VARIABLES_EVIL", "1\nExecStart=rm -rf /");
+    let text = expand_unit_text(
+        "[Test]\n# ${unit_variables_port}\nExecStart=serve --port ${unit_variables_port} --home $HOME $$5 $unit_variables_none\nDescription=$unit_variables_evil\n",
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "[Test]\nExecStart=serve --port 4000 --home $HOME $5 $unit_variables_none\nDescription=1 ExecStart=rm -rf /\n"
+    );
+}
+
+#[test]
+/// Specifiers are expanded, "%%" keeps a "%" of a program's own, and anything else is left alone.
+fn unit_specifiers() {
+    let name = UnitName::from_str("fixture@left", "jig").unwrap();
+    let dir = PathBuf::from("/etc/exclave");
+    assert_eq!(
+        name.expand_specifiers("run %N %i %d/x.sh", &dir),
+        "run fixture@left left /etc/exclave/x.sh"
+    );
+    assert_eq!(name.expand_specifiers("date +%%H %Y 100%", &dir), "date +%H %Y 100%");
+}
+
+#[test]
+/// A unit file is only taken from the parse cache if the variables it uses are unchanged, and
+/// an entry is replaced when they change.  The cache survives being saved and loaded again.
+fn parse_cache() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let text = "[Test]\nExecStart=true\n";
+    let uses_lot = "[Test]\nExecStart=flash ${parse_cache_lot}\n";
+    env::set_var("EXCLAVE_VAR_PARSE_CACHE_LOT", "1");
+    let before = expansion_inputs();
+    let (text_before, uses_lot_before) = (referenced_inputs(text), referenced_inputs(uses_lot));
+    env::set_var("EXCLAVE_VAR_PARSE_CACHE_LOT", "2");
+    assert!(expansion_inputs() != before);
+    assert_eq!(referenced_inputs(text), text_before);
+    assert!(referenced_inputs(uses_lot) != uses_lot_before);
+
+    assert!(parsecache::key(text, 1) != parsecache::key(text, 2));
+    let key = parsecache::key(text, 1);
+    parsecache::insert(text, key, vec![Item::Category("Test".to_owned()), Item::Directive("ExecStart".to_owned(), Some("true".to_owned()))]);
+
+    let directory = env::temp_dir().join(format!("exclave-parse-cache-{}", process::id()));
+    parsecache::save(&directory).unwrap();
+    assert!(directory.join(parsecache::PARSE_CACHE_FILE).exists());
+    parsecache::load(&directory).unwrap();
+    assert_eq!(parsecache::get(key).map(|items| items.len()), Some(2));
+    parsecache::insert(text, parsecache::key(text, 2), vec![]);
+    assert!(parsecache::get(key).is_none());
+    fs::remove_dir_all(&directory).ok();
+}
@@ -0,0 +1,207 @@
+// Automated tests of scenarios, for exclave's own test suite and for whoever
+// writes units for it.  A Harness runs a unit manager with no unit directories,
+// and units are added to it from strings.  MockJig and FakeProgram write unit
+// files for the jigs and tests that would otherwise need a real station, and an
+// in-memory interface keeps everything the manager sends it, so that a test can
+// look at the same CFTI that a real interface would have been sent.
+use std::sync::mpsc::RecvError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use config::Config;
+use engine::{Engine, Timing, VirtualInterface};
+use managerhandle::ManagerHandle;
+use unit::UnitName;
+use unitbroadcaster::{UnitBroadcaster, UnitEvent};
+use unitmanager::ManagerControlMessageContents;
+
+pub use units::interface::Capture;
+
+/// An engine with no unit directories, for a test to add units to one at a time.
+pub struct Harness {
+    engine: Engine,
+}
+
+impl Harness {
+    /// A harness with a unit manager of its own.  If there's a `timeout`, it shuts down once that's
+    /// passed, so that a scenario that never finishes fails its test rather than hanging it.
+    pub fn new(timeout: Option<Duration>) -> Harness {
+        let config = Arc::new(Mutex::new(Config::new()));
+        let broadcaster = UnitBroadcaster::new();
+        let engine = Engine::new(&broadcaster, &config, &Timing::default(), false);
+
+        if let Some(t) = timeout {
+            thread::spawn(move || {
+                thread::sleep(t);
+                broadcaster.broadcast(&UnitEvent::Shutdown);
+            });
+        }
+
+        Harness { engine: engine }
+    }
+
+    /// Load a unit from the text of its unit file.  See `Engine::add_unit()`.
+    pub fn add_unit(&self, name: &UnitName, unit_text: &str) -> Result<(), String> {
+        self.engine.add_unit(name, unit_text)
+    }
+
+    /// Load an interface that runs no program, and is sent everything a real interface would be.
+    pub fn add_interface(&self, name: &UnitName) -> Result<VirtualInterface, String> {
+        self.engine.add_interface(name)
+    }
+
+    /// Select and activate whatever has been added, in the same way as after the unit directories are scanned.
+    pub fn rescan(&self) {
+        self.engine.rescan();
+    }
+
+    pub fn start_scenario(&self, name: &UnitName) {
+        self.engine.start_scenario(Some(name));
+    }
+
+    pub fn manager(&self) -> &ManagerHandle {
+        self.engine.manager()
+    }
+
+    /// Wait for the next event, and handle it the way the main loop would.  It's an error once
+    /// the harness has shut down, e.g. because its timeout has passed.
+    pub fn run_once(&mut self) -> Result<UnitEvent, RecvError> {
+        self.engine.next().ok_or(RecvError)
+    }
+
+    /// Run until scenario `name` finishes, and return its result code and message.
+    pub fn wait_for_finish(&mut self, name: &UnitName) -> Result<(u32, String), RecvError> {
+        loop {
+            if let UnitEvent::ManagerRequest(ref msg) = self.run_once()? {
+                if let ManagerControlMessageContents::ScenarioFinished(code, ref message) = msg.contents {
+                    if msg.sender == *name {
+                        return Ok((code, message.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run until unit `name` logs `line`, e.g. when one of its programs prints it.
+    pub fn wait_for_log(&mut self, name: &UnitName, line: &str) -> Result<(), RecvError> {
+        loop {
+            if let UnitEvent::ManagerRequest(ref msg) = self.run_once()? {
+                if let ManagerControlMessageContents::Log(ref logged) = msg.contents {
+                    if msg.sender == *name && logged == line {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Step {
+    Print(String),
+    Sleep(f32),
+}
+
+/// A program that prints lines, sleeps, and exits as it's told to, for tests and
+/// jig checks that would otherwise need hardware.  Lines mustn't contain quotes.
+#[derive(Default)]
+pub struct FakeProgram {
+    steps: Vec<Step>,
+    code: u32,
+}
+
+impl FakeProgram {
+    pub fn new() -> FakeProgram {
+        FakeProgram::default()
+    }
+
+    pub fn print(mut self, line: &str) -> FakeProgram {
+        self.steps.push(Step::Print(line.to_owned()));
+        self
+    }
+
+    pub fn sleep(mut self, secs: f32) -> FakeProgram {
+        self.steps.push(Step::Sleep(secs));
+        self
+    }
+
+    /// The code the program exits with once it's done everything else.  It's 0 unless this is called.
+    pub fn exit(mut self, code: u32) -> FakeProgram {
+        self.code = code;
+        self
+    }
+
+    /// The command line that runs the program, for an ExecStart or the like.
+    #[cfg(unix)]
+    pub fn command(&self) -> String {
+        let mut parts: Vec<String> = self
+            .steps
+            .iter()
+            .map(|step| match *step {
+                Step::Print(ref line) => format!("echo '{}'", line),
+                Step::Sleep(secs) => format!("sleep {}", secs),
+            })
+            .collect();
+        parts.push(format!("exit {}", self.code));
+        format!("/bin/sh -c \"{}\"", parts.join("; "))
+    }
+
+    #[cfg(windows)]
+    pub fn command(&self) -> String {
+        let mut parts: Vec<String> = self
+            .steps
+            .iter()
+            .map(|step| match *step {
+                Step::Print(ref line) => format!("Write-Output {}", line),
+                Step::Sleep(secs) => format!("Start-Sleep {}", secs),
+            })
+            .collect();
+        parts.push(format!("exit {}", self.code));
+        format!("Powershell -NoProfile \"{}\"", parts.join("; "))
+    }
+
+    /// The unit file of a test called `name` that runs the program.  More
+    /// directives, e.g. Requires=, can be added on to the end of it.
+    pub fn test_unit(&self, name: &str) -> String {
+        format!("[Test]\nName={}\nDescription=Runs a fake program\nExecStart={}\n", name, self.command())
+    }
+}
+
+/// The unit file of a jig, which can be made to seem absent without unplugging anything.
+pub struct MockJig {
+    name: String,
+    default_scenario: Option<String>,
+    present: bool,
+}
+
+impl MockJig {
+    pub fn new(name: &str) -> MockJig {
+        MockJig {
+            name: name.to_owned(),
+            default_scenario: None,
+            present: true,
+        }
+    }
+
+    pub fn default_scenario(mut self, scenario: &str) -> MockJig {
+        self.default_scenario = Some(scenario.to_owned());
+        self
+    }
+
+    /// Whether the jig is found.  One that's absent has a TestProgram that fails, so it isn't loaded.
+    pub fn present(mut self, present: bool) -> MockJig {
+        self.present = present;
+        self
+    }
+
+    pub fn unit(&self) -> String {
+        let mut unit = format!("[Jig]\nName={}\nDescription=Mock jig\n", self.name);
+        if let Some(ref scenario) = self.default_scenario {
+            unit.push_str(&format!("DefaultScenario={}\n", scenario));
+        }
+        if !self.present {
+            unit.push_str(&format!("TestProgram={}\n", FakeProgram::new().exit(1).command()));
+        }
+        unit
+    }
+}
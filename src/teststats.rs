@@ -0,0 +1,112 @@
+// How each test has been doing lately: how long it takes, how often it fails,
+// and why it failed last, so that interfaces can point technicians at the
+// tests that keep failing, or that are getting slower, before they stop the line.
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use logsinks::csv::split_row;
+use summary::RunSummary;
+use timestamp;
+
+/// How many of a test's most recent results its figures are worked out from.
+pub const RECENT_RESULTS: usize = 50;
+
+/// One time a test ran.
+#[derive(Clone, Debug)]
+struct Outcome {
+    passed: bool,
+    duration: Option<Duration>,
+}
+
+/// How a test has been doing over its most recent results.
+#[derive(Clone, Debug)]
+pub struct TestFigures {
+    /// How long it took on average, if that's known.
+    pub mean_duration: Option<Duration>,
+
+    /// How many times it ran, and how many of those it failed.  Times it was skipped don't count.
+    pub runs: usize,
+    pub failures: usize,
+
+    /// When the run it last failed in started, in seconds since the epoch, and why it failed.
+    /// This is remembered even once the failure is no longer one of the recent results.
+    pub last_failure: Option<(u64, String)>,
+}
+
+#[derive(Default)]
+pub struct TestStats {
+    /// The most recent results of each test, by id, oldest first.
+    results: HashMap<String, VecDeque<Outcome>>,
+
+    last_failures: HashMap<String, (u64, String)>,
+}
+
+impl TestStats {
+    pub fn new() -> TestStats {
+        TestStats::default()
+    }
+
+    /// Pick up where a previous instance left off, from the tests.csv that run summaries are written to.
+    pub fn load(path: &Path) -> io::Result<TestStats> {
+        let mut stats = TestStats::new();
+        for line in BufReader::new(File::open(path)?).lines().skip(1) {
+            let fields = split_row(&line?);
+            if fields.len() < 8 {
+                continue;
+            }
+            let started = timestamp::parse_rfc3339(&fields[0]).unwrap_or(0);
+            let duration = fields[6].parse::<f64>().ok().map(|secs| Duration::from_millis((secs * 1000.0) as u64));
+            stats.record(&fields[3], started, &fields[4], duration, &fields[7]);
+        }
+        Ok(stats)
+    }
+
+    /// Add the result of a test in a run that started at `started`.  Results other than "pass"
+    /// and "fail", i.e. the test being skipped, say nothing about the test, and are left out.
+    pub fn record(&mut self, test: &str, started: u64, result: &str, duration: Option<Duration>, message: &str) {
+        let passed = match result {
+            "pass" => true,
+            "fail" => false,
+            _ => return,
+        };
+        let results = self.results.entry(test.to_owned()).or_insert_with(VecDeque::new);
+        results.push_back(Outcome {
+            passed: passed,
+            duration: duration,
+        });
+        while results.len() > RECENT_RESULTS {
+            results.pop_front();
+        }
+        if !passed {
+            self.last_failures.insert(test.to_owned(), (started, message.to_owned()));
+        }
+    }
+
+    /// Add the results of every test in a run that has finished.
+    pub fn record_run(&mut self, summary: &RunSummary) {
+        for test in summary.tests() {
+            self.record(&test.test, summary.started_secs(), test.result, test.duration, &test.message);
+        }
+    }
+
+    /// How a test has been doing, if it has run at all.
+    pub fn figures(&self, test: &str) -> Option<TestFigures> {
+        let results = self.results.get(test)?;
+        let durations: Vec<Duration> = results.iter().filter_map(|r| r.duration).collect();
+        let mean_duration = if durations.is_empty() {
+            None
+        } else {
+            let total_ms: u64 = durations.iter().map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis())).sum();
+            Some(Duration::from_millis(total_ms / durations.len() as u64))
+        };
+        Some(TestFigures {
+            mean_duration: mean_duration,
+            runs: results.len(),
+            failures: results.iter().filter(|r| !r.passed).count(),
+            last_failure: self.last_failures.get(test).cloned(),
+        })
+    }
+}
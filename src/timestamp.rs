@@ -0,0 +1,216 @@
+// Wall-clock timestamps for log records.
+//
+// Log records are created all over the place, far away from the Config,
+// so the timezone they're stamped in is kept here as process-wide state.
+#[cfg(unix)]
+extern crate libc;
+
+use std::fmt;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Which timezone wall-clock timestamps are written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    /// Coordinated Universal Time, written with a "Z" suffix.
+    Utc,
+
+    /// Whatever the system's local timezone is, including daylight saving.
+    Local,
+
+    /// A fixed number of seconds east of UTC.
+    Offset(i32),
+}
+
+const ZONE_UTC: usize = 0;
+const ZONE_LOCAL: usize = 1;
+const ZONE_OFFSET: usize = 2;
+
+static ZONE: AtomicUsize = AtomicUsize::new(ZONE_UTC);
+static ZONE_OFFSET_SECS: AtomicIsize = AtomicIsize::new(0);
+
+impl TimeZone {
+    /// Parse "UTC", "local", or an offset such as "+05:30" or "-0800".
+    pub fn from_str(s: &str) -> Result<TimeZone, String> {
+        match s.to_lowercase().as_ref() {
+            "utc" | "z" => return Ok(TimeZone::Utc),
+            "local" => return Ok(TimeZone::Local),
+            _ => (),
+        }
+
+        let invalid = || format!("invalid timezone {}, expected UTC, local, or an offset like +05:30", s);
+        let sign = match s.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hours: i32 = digits[0..2].parse().map_err(|_| invalid())?;
+        let minutes: i32 = digits[2..4].parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+
+    /// How many seconds east of UTC this zone is at the given time.
+    fn offset_at(&self, secs: u64) -> i32 {
+        match *self {
+            TimeZone::Utc => 0,
+            TimeZone::Offset(o) => o,
+            TimeZone::Local => local_offset(secs),
+        }
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeZone::Utc => write!(f, "UTC"),
+            TimeZone::Local => write!(f, "local"),
+            TimeZone::Offset(o) => {
+                let sign = if o < 0 { '-' } else { '+' };
+                write!(f, "{}{:02}:{:02}", sign, o.abs() / 3600, (o.abs() % 3600) / 60)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn local_offset(secs: u64) -> i32 {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i32
+}
+
+#[cfg(not(unix))]
+fn local_offset(_secs: u64) -> i32 {
+    0
+}
+
+/// Set the timezone that timestamps are written in from now on.
+pub fn set_timezone(zone: TimeZone) {
+    match zone {
+        TimeZone::Utc => ZONE.store(ZONE_UTC, Ordering::SeqCst),
+        TimeZone::Local => ZONE.store(ZONE_LOCAL, Ordering::SeqCst),
+        TimeZone::Offset(o) => {
+            ZONE_OFFSET_SECS.store(o as isize, Ordering::SeqCst);
+            ZONE.store(ZONE_OFFSET, Ordering::SeqCst);
+        }
+    }
+}
+
+pub fn timezone() -> TimeZone {
+    match ZONE.load(Ordering::SeqCst) {
+        ZONE_LOCAL => TimeZone::Local,
+        ZONE_OFFSET => TimeZone::Offset(ZONE_OFFSET_SECS.load(Ordering::SeqCst) as i32),
+        _ => TimeZone::Utc,
+    }
+}
+
+/// Turn a count of days since 1970-01-01 into a (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // From Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Turn a (year, month, day) into a count of days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Read back a time written by `rfc3339_in`, in any timezone, as seconds since the epoch.
+/// Any fraction of a second is ignored.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let number = |range: ::std::ops::Range<usize>| s.get(range).and_then(|n| n.parse::<u32>().ok());
+    if s.len() < 20 || s.get(4..5) != Some("-") || s.get(7..8) != Some("-") || s.get(10..11) != Some("T") {
+        return None;
+    }
+    let days = days_from_civil(number(0..4)? as i64, number(5..7)?, number(8..10)?);
+    let seconds_of_day = number(11..13)? * 3600 + number(14..16)? * 60 + number(17..19)?;
+
+    let zone = s.get(19..)?.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" => 0,
+        _ => match TimeZone::from_str(zone) {
+            Ok(TimeZone::Offset(o)) => o as i64,
+            _ => return None,
+        },
+    };
+    let secs = days * 86_400 + seconds_of_day as i64 - offset;
+    if secs < 0 {
+        None
+    } else {
+        Some(secs as u64)
+    }
+}
+
+/// Format a time as RFC3339 in the given timezone, e.g. "2018-02-14T13:28:07.123456789+13:00".
+/// The fractional part is left off if `nsecs` is None.
+pub fn rfc3339_in(zone: TimeZone, secs: u64, nsecs: Option<u32>) -> String {
+    let offset = zone.offset_at(secs);
+    let local = secs as i64 + offset as i64;
+    let days = local.div_euclid(86_400);
+    let seconds_of_day = local.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+
+    let suffix = if zone == TimeZone::Utc {
+        "Z".to_owned()
+    } else {
+        format!("{}", TimeZone::Offset(offset))
+    };
+    let fraction = match nsecs {
+        Some(n) => format!(".{:09}", n),
+        None => "".to_owned(),
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60,
+        fraction,
+        suffix
+    )
+}
+
+/// Format a time as RFC3339 in the configured timezone.
+pub fn rfc3339(secs: u64, nsecs: u32) -> String {
+    rfc3339_in(timezone(), secs, Some(nsecs))
+}
+
+/// Format a time as RFC3339 in the configured timezone, to the nearest second.
+pub fn rfc3339_seconds(secs: u64) -> String {
+    rfc3339_in(timezone(), secs, None)
+}
+
+/// Format the current time as RFC3339 in the configured timezone.
+pub fn now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::new(0, 0));
+    rfc3339(now.as_secs(), now.subsec_nanos())
+}
@@ -0,0 +1,221 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+
+use triggers::TriggerThread;
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+/// Reads a barcode scanner that presents itself as a keyboard, and starts
+/// a scenario with each code that gets scanned as the serial number.
+pub struct BarcodeTrigger {
+    /// The evdev device for the scanner, e.g. /dev/input/by-id/usb-...-event-kbd.
+    path: PathBuf,
+
+    /// The scenario to start, or None for the current scenario.
+    scenario: Option<UnitName>,
+
+    /// Whether to take the scanner away from everything else reading keyboards.
+    grab: bool,
+}
+
+impl BarcodeTrigger {
+    pub fn new(path: PathBuf, scenario: Option<UnitName>, grab: bool) -> BarcodeTrigger {
+        BarcodeTrigger {
+            path: path,
+            scenario: scenario,
+            grab: grab,
+        }
+    }
+
+    /// Start reading scans on a new thread.
+    #[cfg(target_os = "linux")]
+    pub fn start(self, id: UnitName, control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        let file = File::open(&self.path)?;
+        if self.grab {
+            evdev::grab(&file)?;
+        }
+        Ok(TriggerThread::spawn(id.clone(), control.clone(), move |stop| evdev::read_scans(file, self.path, self.scenario, id, control, stop)))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn start(self, _id: UnitName, _control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "barcode triggers are only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod evdev {
+    use super::libc;
+
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::slice;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+
+    use triggers::fail;
+    use unit::UnitName;
+    use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+    /// _IOW('E', 0x90, int), from linux/input.h
+    const EVIOCGRAB: libc::c_ulong = 0x4004_4590;
+
+    const EV_KEY: u16 = 1;
+    const KEY_RELEASED: i32 = 0;
+
+    const KEY_ENTER: u16 = 28;
+    const KEY_LEFTSHIFT: u16 = 42;
+    const KEY_RIGHTSHIFT: u16 = 54;
+    const KEY_KPENTER: u16 = 96;
+
+    /// How long to wait for a key before checking whether to stop.
+    const POLL_INTERVAL_MS: i32 = 100;
+
+    /// Stop anything else, such as the console, from seeing the scanner's keypresses.
+    pub fn grab(file: &File) -> io::Result<()> {
+        if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB, 1 as libc::c_int) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Turn a key code into the character it types on a US keyboard layout,
+    /// which is what scanners emulate unless they've been told otherwise.
+    fn key_char(code: u16, shift: bool) -> Option<char> {
+        let (plain, shifted) = match code {
+            2..=11 => {
+                let digit = b"1234567890"[code as usize - 2] as char;
+                (digit, b"!@#$%^&*()"[code as usize - 2] as char)
+            }
+            12 => ('-', '_'),
+            13 => ('=', '+'),
+            16..=25 => {
+                let c = b"qwertyuiop"[code as usize - 16] as char;
+                (c, c.to_ascii_uppercase())
+            }
+            26 => ('[', '{'),
+            27 => (']', '}'),
+            30..=38 => {
+                let c = b"asdfghjkl"[code as usize - 30] as char;
+                (c, c.to_ascii_uppercase())
+            }
+            39 => (';', ':'),
+            40 => ('\'', '"'),
+            41 => ('`', '~'),
+            43 => ('\\', '|'),
+            44..=50 => {
+                let c = b"zxcvbnm"[code as usize - 44] as char;
+                (c, c.to_ascii_uppercase())
+            }
+            51 => (',', '<'),
+            52 => ('.', '>'),
+            53 => ('/', '?'),
+            55 => ('*', '*'),
+            57 => (' ', ' '),
+            71..=73 => (b"789"[code as usize - 71] as char, b"789"[code as usize - 71] as char),
+            74 => ('-', '-'),
+            75..=77 => (b"456"[code as usize - 75] as char, b"456"[code as usize - 75] as char),
+            78 => ('+', '+'),
+            79..=81 => (b"123"[code as usize - 79] as char, b"123"[code as usize - 79] as char),
+            82 => ('0', '0'),
+            83 => ('.', '.'),
+            98 => ('/', '/'),
+            _ => return None,
+        };
+        Some(if shift { shifted } else { plain })
+    }
+
+    /// Wait for the device to have something to read.  Returns false if it timed out.
+    fn wait_readable(file: &File) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL_MS) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn read_event(file: &mut File) -> io::Result<libc::input_event> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        {
+            let buf = unsafe {
+                slice::from_raw_parts_mut(&mut event as *mut libc::input_event as *mut u8, mem::size_of::<libc::input_event>())
+            };
+            file.read_exact(buf)?;
+        }
+        Ok(event)
+    }
+
+    pub fn read_scans(
+        mut file: File,
+        path: PathBuf,
+        scenario: Option<UnitName>,
+        id: UnitName,
+        control: Sender<ManagerControlMessage>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut shift = false;
+        let mut scanned = String::new();
+
+        while !stop.load(Ordering::SeqCst) {
+            let event = match wait_readable(&file).and_then(|ready| if ready { read_event(&mut file).map(Some) } else { Ok(None) }) {
+                Ok(Some(e)) => e,
+                Ok(None) => continue,
+                Err(e) => {
+                    fail(&id, &control, format!("unable to read barcode scanner {}: {}", path.display(), e));
+                    return;
+                }
+            };
+            if event.type_ != EV_KEY {
+                continue;
+            }
+
+            match event.code {
+                KEY_LEFTSHIFT | KEY_RIGHTSHIFT => shift = event.value != KEY_RELEASED,
+                _ if event.value == KEY_RELEASED => (),
+                KEY_ENTER | KEY_KPENTER => {
+                    let serial = scanned.trim().to_owned();
+                    scanned.clear();
+                    if serial.is_empty() {
+                        continue;
+                    }
+                    let messages = vec![
+                        ManagerControlMessageContents::Serial(serial),
+                        ManagerControlMessageContents::StartScenario(scenario.clone()),
+                    ];
+                    for msg in messages {
+                        control.send(ManagerControlMessage::new(&id, msg)).ok();
+                    }
+                }
+                code => {
+                    if let Some(c) = key_char(code, shift) {
+                        scanned.push(c);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use unit::UnitName;
+
+/// How a combination trigger combines its inputs.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CombinationMode {
+    /// Every input must fire within the window, e.g. a two-hand start.
+    All,
+
+    /// Any one input will do.
+    Any,
+}
+
+/// A line that must be asserted for a combination trigger to fire, such as a lid-closed switch.
+#[derive(Clone)]
+pub struct Interlock {
+    /// A file that reads "0" or "1", such as a sysfs GPIO value file.
+    path: PathBuf,
+
+    /// The interlock is asserted when the file reads "0" rather than "1".
+    inverted: bool,
+}
+
+impl Interlock {
+    /// Parse an interlock, which is a path, optionally preceded by "!" if it's asserted when low.
+    pub fn from_str(s: &str) -> Interlock {
+        if s.starts_with('!') {
+            Interlock {
+                path: PathBuf::from(&s[1..]),
+                inverted: true,
+            }
+        } else {
+            Interlock {
+                path: PathBuf::from(s),
+                inverted: false,
+            }
+        }
+    }
+
+    /// Returns an explanation if the interlock isn't asserted, or can't be read.
+    fn check(&self) -> Result<(), String> {
+        let value = fs::read_to_string(&self.path)
+            .map_err(|e| format!("unable to read interlock {}: {}", self.path.display(), e))?;
+        let level = match value.trim() {
+            "0" => false,
+            "1" => true,
+            other => return Err(format!("unexpected interlock value {:?} in {}", other, self.path.display())),
+        };
+        if level == self.inverted {
+            Err(format!("interlock {} is not asserted", self.path.display()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Combines the start requests of other triggers, so the scenario only starts
+/// once enough of them have fired, and any interlocks are asserted.
+pub struct CombinationTrigger {
+    /// The triggers whose start requests are combined.
+    inputs: Vec<UnitName>,
+    mode: CombinationMode,
+
+    /// How close together the inputs must fire, in All mode.
+    window: Duration,
+
+    /// Lines that must all be asserted when the inputs fire.
+    interlocks: Vec<Interlock>,
+
+    /// When each input last fired, for inputs that have fired within the window.
+    fired: HashMap<UnitName, Instant>,
+}
+
+impl CombinationTrigger {
+    pub fn new(inputs: Vec<UnitName>, mode: CombinationMode, window: Duration, interlocks: Vec<Interlock>) -> CombinationTrigger {
+        CombinationTrigger {
+            inputs: inputs,
+            mode: mode,
+            window: window,
+            interlocks: interlocks,
+            fired: HashMap::new(),
+        }
+    }
+
+    /// Note that `input` has asked to start a scenario.  Returns Ok(true) if
+    /// the combination is now complete and the scenario should start,
+    /// Ok(false) if it's still waiting for other inputs, or an explanation if
+    /// an interlock isn't asserted.
+    pub fn input(&mut self, input: &UnitName) -> Result<bool, String> {
+        let now = Instant::now();
+        let window = self.window;
+        self.fired.retain(|_, at| now.duration_since(*at) <= window);
+        self.fired.insert(input.clone(), now);
+
+        let complete = match self.mode {
+            CombinationMode::Any => true,
+            CombinationMode::All => self.inputs.iter().all(|i| self.fired.contains_key(i)),
+        };
+        if !complete {
+            return Ok(false);
+        }
+
+        // Whether or not the interlocks allow it, the inputs have to be fired again.
+        self.fired.clear();
+        for interlock in &self.interlocks {
+            interlock.check()?;
+        }
+        Ok(true)
+    }
+}
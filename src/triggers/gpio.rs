@@ -0,0 +1,263 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use triggers::{fail, fire, TriggerThread};
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+/// How often a line that can't interrupt is sampled.
+const SAMPLE_INTERVAL_MS: u64 = 5;
+
+/// How often a thread waiting for the line to interrupt checks whether it's been stopped.
+#[cfg(target_os = "linux")]
+const STOP_CHECK_MS: u64 = 250;
+
+/// Which transitions of the line start a test.
+#[derive(Clone, Copy, PartialEq)]
+pub enum GpioEdge {
+    /// The line goes from low to high.
+    Rising,
+
+    /// The line goes from high to low.
+    Falling,
+
+    /// The line changes either way.
+    Both,
+}
+
+/// Watches a GPIO line through sysfs, and starts the scenario when it changes.
+pub struct GpioTrigger {
+    /// The sysfs "value" file for the line.
+    path: PathBuf,
+    edge: GpioEdge,
+
+    /// How long the line must hold its new level before the change counts.
+    debounce: Duration,
+
+    /// The scenario to start, or None for the current scenario.
+    scenario: Option<UnitName>,
+
+    /// Whether the kernel tells us when the line changes, rather than it having to be sampled.
+    interrupts: bool,
+}
+
+impl GpioTrigger {
+    /// Prepare to watch a line.  Either `line` is a GPIO number, which gets
+    /// exported and set up as an input if it isn't already, or `path` names
+    /// a file that reads "0" or "1" like a sysfs value file.
+    pub fn new(
+        line: Option<u32>,
+        path: Option<PathBuf>,
+        edge: GpioEdge,
+        debounce: Duration,
+        scenario: Option<UnitName>,
+    ) -> io::Result<GpioTrigger> {
+        let path = match (path, line) {
+            (Some(path), _) => path,
+            (None, Some(line)) => {
+                let gpio = PathBuf::from(format!("/sys/class/gpio/gpio{}", line));
+                if !gpio.exists() {
+                    fs::write("/sys/class/gpio/export", format!("{}", line))?;
+                }
+                // Some kernels don't allow the direction to be changed, but the line may be an input anyway.
+                fs::write(gpio.join("direction"), "in").ok();
+                gpio.join("value")
+            }
+            (None, None) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "no gpio line specified"));
+            }
+        };
+
+        Ok(GpioTrigger {
+            interrupts: enable_interrupts(&path, edge),
+            path: path,
+            edge: edge,
+            debounce: debounce,
+            scenario: scenario,
+        })
+    }
+
+    fn read_level(file: &mut File) -> io::Result<bool> {
+        let mut value = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut value)?;
+        match value.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected gpio value {:?}", other))),
+        }
+    }
+
+    fn fires(&self, from: bool, to: bool) -> bool {
+        match self.edge {
+            GpioEdge::Rising => !from && to,
+            GpioEdge::Falling => from && !to,
+            GpioEdge::Both => from != to,
+        }
+    }
+
+    /// Start watching the line on a new thread.
+    pub fn start(self, id: UnitName, control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        let mut file = File::open(&self.path)?;
+        // Reading the value also clears any interrupt from before we started.
+        let stable = Self::read_level(&mut file)?;
+
+        Ok(TriggerThread::spawn(id.clone(), control.clone(), move |stop| {
+            let result = if self.interrupts {
+                self.wait_for_changes(file, stable, &id, &control, &stop)
+            } else {
+                self.sample(file, stable, &id, &control, &stop)
+            };
+            if let Err(e) = result {
+                fail(&id, &control, format!("unable to read gpio {}: {}", self.path.display(), e));
+            }
+        }))
+    }
+
+    /// Watch a line that can't interrupt, e.g. a Path= that isn't a sysfs GPIO line, by reading it over and over.
+    fn sample(
+        &self,
+        mut file: File,
+        mut stable: bool,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let mut last = stable;
+        let mut changed_at = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            let level = Self::read_level(&mut file)?;
+
+            // Only believe a change once the line has settled on its new level.
+            if level != last {
+                last = level;
+                changed_at = Instant::now();
+            }
+            if level != stable && changed_at.elapsed() >= self.debounce {
+                if self.fires(stable, level) {
+                    fire(id, control, &self.scenario);
+                }
+                stable = level;
+            }
+
+            thread::sleep(Duration::from_millis(SAMPLE_INTERVAL_MS));
+        }
+        Ok(())
+    }
+
+    /// Watch a sysfs GPIO line by waiting for the kernel to say that it's had one of the edges we're after.
+    #[cfg(target_os = "linux")]
+    fn wait_for_changes(
+        &self,
+        mut file: File,
+        mut stable: bool,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let debounce_ms = self.debounce.as_secs() * 1000 + u64::from(self.debounce.subsec_millis());
+        while !stop.load(Ordering::SeqCst) {
+            if !sysfs::wait_for_edge(&file, STOP_CHECK_MS)? {
+                continue;
+            }
+
+            // Only believe a change once the line has gone `debounce` without another edge.  The value
+            // has to be read after each one before the next can be waited for.
+            let mut level = Self::read_level(&mut file)?;
+            while sysfs::wait_for_edge(&file, debounce_ms)? {
+                level = Self::read_level(&mut file)?;
+            }
+
+            // Only the configured edges interrupt, so the level before this one isn't always known.
+            let fired = match self.edge {
+                GpioEdge::Rising => level,
+                GpioEdge::Falling => !level,
+                GpioEdge::Both => self.fires(stable, level),
+            };
+            if fired {
+                fire(id, control, &self.scenario);
+            }
+            stable = level;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wait_for_changes(
+        &self,
+        file: File,
+        stable: bool,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        self.sample(file, stable, id, control, stop)
+    }
+}
+
+/// Ask the kernel to interrupt on `edge`, if `path` is the value file of a sysfs GPIO line.
+/// Returns false if it isn't one, or the line can't interrupt, so it has to be sampled instead.
+#[cfg(target_os = "linux")]
+fn enable_interrupts(path: &Path, edge: GpioEdge) -> bool {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let edge_path = match path.parent() {
+        Some(dir) if path.file_name().map(|n| n == "value").unwrap_or(false) => dir.join("edge"),
+        _ => return false,
+    };
+    let name = match edge {
+        GpioEdge::Rising => "rising",
+        GpioEdge::Falling => "falling",
+        GpioEdge::Both => "both",
+    };
+    // Never create an "edge" file beside some other file that happens to be called "value".
+    match OpenOptions::new().write(true).open(&edge_path) {
+        Ok(mut file) => file.write_all(name.as_bytes()).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_interrupts(_path: &Path, _edge: GpioEdge) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod sysfs {
+    use super::libc;
+
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// Wait for the line to have an edge, for no longer than `timeout_ms`.  Returns false if it timed out.
+    pub fn wait_for_edge(file: &File, timeout_ms: u64) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        };
+        let timeout_ms = if timeout_ms > i32::max_value() as u64 { i32::max_value() } else { timeout_ms as i32 };
+        match unsafe { libc::poll(&mut fds, 1, timeout_ms) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Built-in trigger backends.  A Trigger unit either reads commands from an
+// external program, or watches something itself using one of these.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use supervisor;
+use unit::UnitName;
+use units::jig::JigHealth;
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+pub mod barcode;
+pub mod combination;
+pub mod gpio;
+pub mod network;
+pub mod serial;
+pub mod usb;
+
+/// A built-in trigger, watching for events on its own thread.
+pub struct TriggerThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TriggerThread {
+    /// Run `watch` on a new thread for trigger `id`.  It should check the flag it's given
+    /// regularly, and return once it's set.  If it panics, the trigger is failed.
+    pub fn spawn<F>(id: UnitName, control: Sender<ManagerControlMessage>, watch: F) -> TriggerThread
+    where
+        F: FnOnce(Arc<AtomicBool>) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thr_stop = stop.clone();
+        TriggerThread {
+            stop: stop,
+            thread: Some(supervisor::spawn_worker(id, control, move || watch(thr_stop))),
+        }
+    }
+
+    /// Stop watching, and wait for the thread to finish.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for TriggerThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Turn a line of the trigger protocol into commands for the manager.
+/// A blank line starts the current scenario.
+pub fn parse_command(line: &str) -> Vec<ManagerControlMessageContents> {
+    let mut words: Vec<String> = line.split_whitespace().map(|x| cfti_unescape(x.to_owned())).collect();
+
+    if words.is_empty() {
+        return vec![ManagerControlMessageContents::StartScenario(None)];
+    }
+
+    let verb = words[0].to_lowercase();
+    words.remove(0);
+
+    match verb.as_str() {
+        "stop" => vec![ManagerControlMessageContents::Unimplemented("stop".to_owned(), "Unable to stop tests".to_owned())],
+        "start" => {
+            // START [scenario] [key=value ...]
+            let scenario = match words.first() {
+                Some(word) if !word.contains('=') => match UnitName::from_str(word, "scenario") {
+                    Ok(name) => Some(name),
+                    Err(_) => {
+                        return vec![ManagerControlMessageContents::Unimplemented(
+                            word.clone(),
+                            "name could not be decoded".to_owned(),
+                        )]
+                    }
+                },
+                _ => None,
+            };
+            let skip = if scenario.is_some() { 1 } else { 0 };
+            let mut variables = vec![];
+            for word in words.iter().skip(skip) {
+                match split_variable(word) {
+                    Some(variable) => variables.push(variable),
+                    None => {
+                        return vec![ManagerControlMessageContents::Unimplemented(
+                            word.clone(),
+                            "expected key=value".to_owned(),
+                        )]
+                    }
+                }
+            }
+            start_messages(scenario, variables)
+        }
+        "serial" => {
+            if !words.is_empty() {
+                vec![ManagerControlMessageContents::Serial(words.join(" "))]
+            } else {
+                vec![ManagerControlMessageContents::Unimplemented("serial".to_owned(), "no serial number given".to_owned())]
+            }
+        }
+        "tag" => {
+            let tags: Option<Vec<(String, String)>> = words.iter().map(|w| split_variable(w)).collect();
+            match tags {
+                Some(ref tags) if !tags.is_empty() => vec![ManagerControlMessageContents::Tag(tags.clone())],
+                _ => vec![ManagerControlMessageContents::Unimplemented("tag".to_owned(), "expected key=value".to_owned())],
+            }
+        }
+        "log" => vec![ManagerControlMessageContents::Log(words.join(" "))],
+        "jigstate" => match words.first().and_then(|w| JigHealth::from_str(w)) {
+            Some(health) => vec![ManagerControlMessageContents::JigState(health, words[1..].join(" "))],
+            None => vec![ManagerControlMessageContents::Unimplemented(
+                "jigstate".to_owned(),
+                "expected healthy, degraded, calibrating, or swapping".to_owned(),
+            )],
+        },
+        v => vec![ManagerControlMessageContents::Unimplemented(v.to_owned(), words.join(" "))],
+    }
+}
+
+/// Split "key=value" into its key and value.
+pub fn split_variable(word: &str) -> Option<(String, String)> {
+    let mut parts = word.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Some((key.to_owned(), value.to_owned())),
+        _ => None,
+    }
+}
+
+/// The commands that start a scenario with the given variables.
+/// A "serial" variable sets the serial number of the device under test.
+pub fn start_messages(scenario: Option<UnitName>, variables: Vec<(String, String)>) -> Vec<ManagerControlMessageContents> {
+    let mut messages = vec![];
+    let mut others = vec![];
+    for (key, value) in variables {
+        if key == "serial" {
+            messages.push(ManagerControlMessageContents::Serial(value));
+        } else {
+            others.push((key, value));
+        }
+    }
+    if !others.is_empty() {
+        messages.push(ManagerControlMessageContents::Variables(others));
+    }
+    messages.push(ManagerControlMessageContents::StartScenario(scenario));
+    messages
+}
+
+fn cfti_unescape(msg: String) -> String {
+    msg.replace("\\t", "\t")
+        .replace("\\n", "\n")
+        .replace("\\r", "\r")
+        .replace("\\\\", "\\")
+}
+
+/// Ask the manager to start a scenario, or the current scenario if None.
+pub fn fire(id: &UnitName, control: &Sender<ManagerControlMessage>, scenario: &Option<UnitName>) {
+    control
+        .send(ManagerControlMessage::new(id, ManagerControlMessageContents::StartScenario(scenario.clone())))
+        .ok();
+}
+
+/// Report that a built-in trigger can no longer watch for events.
+pub fn fail(id: &UnitName, control: &Sender<ManagerControlMessage>, reason: String) {
+    control
+        .send(ManagerControlMessage::new(id, ManagerControlMessageContents::LogError(reason)))
+        .ok();
+    control
+        .send(ManagerControlMessage::new(id, ManagerControlMessageContents::ChildExited))
+        .ok();
+}
@@ -0,0 +1,304 @@
+use std::io;
+use std::sync::mpsc::Sender;
+
+use triggers::TriggerThread;
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+/// What clients speak to a network trigger.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NetworkProtocol {
+    /// Requests such as "POST /start?scenario=final&serial=1234".
+    Http,
+
+    /// Lines of the same commands a trigger program prints, e.g. "START final".
+    Tcp,
+}
+
+/// Listens on a TCP port for requests to start a scenario.
+#[cfg_attr(not(feature = "network"), allow(dead_code))]
+pub struct NetworkTrigger {
+    address: String,
+    protocol: NetworkProtocol,
+
+    /// The scenario to start if a request doesn't name one, or None for the current scenario.
+    scenario: Option<UnitName>,
+}
+
+impl NetworkTrigger {
+    /// A trigger that will listen on the given address, e.g. "0.0.0.0:8080".
+    pub fn new(address: &str, protocol: NetworkProtocol, scenario: Option<UnitName>) -> NetworkTrigger {
+        NetworkTrigger {
+            address: address.to_owned(),
+            protocol: protocol,
+            scenario: scenario,
+        }
+    }
+
+    /// Start listening, and accept connections on a new thread.
+    #[cfg(feature = "network")]
+    pub fn start(self, id: UnitName, control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        listen::start(&self.address, self.protocol, self.scenario, id, control)
+    }
+
+    #[cfg(not(feature = "network"))]
+    pub fn start(self, _id: UnitName, _control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "network triggers aren't supported by this build of exclave",
+        ))
+    }
+}
+
+#[cfg(feature = "network")]
+mod listen {
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::str;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use supervisor;
+    use triggers::{parse_command, start_messages, TriggerThread};
+    use unit::UnitName;
+    use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+
+    use super::NetworkProtocol;
+
+    /// How often to check for new connections, and whether to stop.
+    const POLL_INTERVAL_MS: u64 = 100;
+
+    /// How long a client may take to send an HTTP request.
+    const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+    /// The longest HTTP request body that will be read.
+    const MAX_BODY_LEN: usize = 64 * 1024;
+
+    pub fn start(
+        address: &str,
+        protocol: NetworkProtocol,
+        scenario: Option<UnitName>,
+        id: UnitName,
+        control: Sender<ManagerControlMessage>,
+    ) -> io::Result<TriggerThread> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(TriggerThread::spawn(id.clone(), control.clone(), move |stop| {
+            while !stop.load(Ordering::SeqCst) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                        continue;
+                    }
+                    Err(e) => {
+                        control
+                            .send(ManagerControlMessage::new(
+                                &id,
+                                ManagerControlMessageContents::LogError(format!("unable to accept connection: {}", e)),
+                            ))
+                            .ok();
+                        continue;
+                    }
+                };
+
+                // The listener is non-blocking, but that isn't supposed to carry over to accepted streams.
+                if stream.set_nonblocking(false).is_err() {
+                    continue;
+                }
+                let thr_id = id.clone();
+                let thr_control = control.clone();
+                let thr_stop = stop.clone();
+                let scenario = scenario.clone();
+                match protocol {
+                    NetworkProtocol::Http => supervisor::spawn_worker(id.clone(), control.clone(), move || {
+                        serve_http(stream, &scenario, &thr_id, &thr_control).ok();
+                    }),
+                    NetworkProtocol::Tcp => supervisor::spawn_worker(id.clone(), control.clone(), move || {
+                        serve_tcp(stream, &scenario, &thr_id, &thr_control, &thr_stop).ok();
+                    }),
+                };
+            }
+        }))
+    }
+
+    /// Read commands from a client a line at a time, until it hangs up or the trigger is stopped.
+    fn serve_tcp(
+        stream: TcpStream,
+        scenario: &Option<UnitName>,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        stop: &Arc<AtomicBool>,
+    ) -> io::Result<()> {
+        // Wake up regularly to see whether the trigger has been stopped.
+        stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        while !stop.load(Ordering::SeqCst) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+
+            let reply = send(id, control, scenario, parse_command(&line));
+            writeln!(writer, "{}", reply)?;
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Handle a single HTTP request.  Only /start is supported, with the
+    /// scenario, serial number, and any other metadata in the query string
+    /// or a form-encoded body.
+    fn serve_http(
+        mut stream: TcpStream,
+        scenario: &Option<UnitName>,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+        let mut request = String::new();
+        let mut content_length = 0;
+        let mut body = vec![];
+        {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            reader.read_line(&mut request)?;
+            let mut header = String::new();
+            while reader.read_line(&mut header)? > 2 {
+                {
+                    let mut parts = header.splitn(2, ':');
+                    let name = parts.next().unwrap_or("").trim().to_lowercase();
+                    if name == "content-length" {
+                        content_length = parts.next().unwrap_or("").trim().parse().unwrap_or(0);
+                    }
+                }
+                header.clear();
+            }
+            if content_length > 0 && content_length <= MAX_BODY_LEN {
+                body.resize(content_length, 0);
+                reader.read_exact(&mut body)?;
+            }
+        }
+
+        let mut request_words = request.split_whitespace();
+        let method = request_words.next().unwrap_or("");
+        let target = request_words.next().unwrap_or("");
+        let (path, query) = match target.find('?') {
+            Some(idx) => (&target[..idx], &target[idx + 1..]),
+            None => (target, ""),
+        };
+
+        let (status, reply) = if path != "/start" {
+            ("404 Not Found", "not found".to_owned())
+        } else if method != "POST" && method != "GET" {
+            ("405 Method Not Allowed", "use POST".to_owned())
+        } else {
+            let body = String::from_utf8_lossy(&body);
+            let mut params = form_decode(query);
+            params.extend(form_decode(&body));
+            start_scenario(&params, scenario, id, control)
+        };
+
+        write!(
+            stream,
+            "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}\n",
+            status,
+            reply.len() + 1,
+            reply
+        )?;
+        stream.shutdown(Shutdown::Both).ok();
+        Ok(())
+    }
+
+    /// Ask the manager to start a scenario, as described by a set of request parameters.
+    fn start_scenario(
+        params: &[(String, String)],
+        scenario: &Option<UnitName>,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+    ) -> (&'static str, String) {
+        let mut requested = None;
+        let mut variables = vec![];
+        for &(ref key, ref value) in params {
+            match key.as_str() {
+                "scenario" => match UnitName::from_str(value, "scenario") {
+                    Ok(name) => requested = Some(name),
+                    Err(e) => return ("400 Bad Request", format!("invalid scenario name: {}", e)),
+                },
+                _ => variables.push((key.clone(), value.clone())),
+            }
+        }
+
+        let reply = send(id, control, scenario, start_messages(requested, variables));
+        ("202 Accepted", reply)
+    }
+
+    /// Pass commands on to the manager, starting the default scenario if none was named.
+    fn send(
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        scenario: &Option<UnitName>,
+        messages: Vec<ManagerControlMessageContents>,
+    ) -> String {
+        for msg in messages {
+            let msg = match msg {
+                ManagerControlMessageContents::Unimplemented(verb, reason) => {
+                    return format!("ERROR {}: {}", verb, reason)
+                }
+                ManagerControlMessageContents::StartScenario(None) => {
+                    ManagerControlMessageContents::StartScenario(scenario.clone())
+                }
+                msg => msg,
+            };
+            if control.send(ManagerControlMessage::new(id, msg)).is_err() {
+                return "ERROR shutting down".to_owned();
+            }
+        }
+        "OK".to_owned()
+    }
+
+    /// Split an application/x-www-form-urlencoded string into its keys and values.
+    fn form_decode(s: &str) -> Vec<(String, String)> {
+        s.split('&')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let mut parts = pair.trim().splitn(2, '=');
+                let key = percent_decode(parts.next().unwrap_or(""));
+                let value = percent_decode(parts.next().unwrap_or(""));
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn percent_decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => decoded.push(b' '),
+                b'%' if i + 2 < bytes.len() => {
+                    let hex = str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                    match u8::from_str_radix(hex, 16) {
+                        Ok(b) => {
+                            decoded.push(b);
+                            i += 2;
+                        }
+                        Err(_) => decoded.push(b'%'),
+                    }
+                }
+                b => decoded.push(b),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+}
@@ -0,0 +1,220 @@
+#[cfg(unix)]
+extern crate libc;
+extern crate regex;
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+
+use triggers::TriggerThread;
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+use self::regex::Regex;
+
+/// The baud rates a serial trigger may use.
+pub const BAUD_RATES: &[u32] = &[1200, 2400, 4800, 9600, 19200, 38400, 57600, 115_200, 230_400];
+
+/// Reads lines from a serial port, e.g. from a microcontroller in the
+/// fixture, and starts the scenario whenever one matches a pattern.
+pub struct SerialTrigger {
+    /// The serial device, e.g. /dev/ttyACM0.
+    path: PathBuf,
+
+    /// The speed of the port, which must be one of BAUD_RATES.
+    baud_rate: u32,
+
+    /// The pattern a line must match.  Named groups, such as
+    /// (?P<serial>\w+), are passed on to the run as variables.
+    pattern: Regex,
+
+    /// The scenario to start, or None for the current scenario.
+    scenario: Option<UnitName>,
+}
+
+impl SerialTrigger {
+    pub fn new(path: PathBuf, baud_rate: u32, pattern: Regex, scenario: Option<UnitName>) -> SerialTrigger {
+        SerialTrigger {
+            path: path,
+            baud_rate: baud_rate,
+            pattern: pattern,
+            scenario: scenario,
+        }
+    }
+
+    /// Open the port and start reading lines on a new thread.
+    #[cfg(unix)]
+    pub fn start(self, id: UnitName, control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        let file = tty::open(&self.path, self.baud_rate)?;
+        Ok(TriggerThread::spawn(id.clone(), control.clone(), move |stop| {
+            tty::read_lines(file, self.path, self.pattern, self.scenario, id, control, stop)
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn start(self, _id: UnitName, _control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "serial triggers are only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(unix)]
+mod tty {
+    use super::libc;
+    use super::regex::Regex;
+
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+
+    use triggers::{fail, start_messages};
+    use unit::UnitName;
+    use unitmanager::ManagerControlMessage;
+
+    /// How long to wait for data before checking whether to stop.
+    const POLL_INTERVAL_MS: i32 = 100;
+
+    /// The longest line that will be kept, in case the port is picking up
+    /// noise.  Longer lines are cut short.
+    const MAX_LINE_LEN: usize = 4096;
+
+    fn speed(baud_rate: u32) -> io::Result<libc::speed_t> {
+        Ok(match baud_rate {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115_200 => libc::B115200,
+            230_400 => libc::B230400,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported baud rate {}", other),
+                ))
+            }
+        })
+    }
+
+    /// Open the port, and set it up as a raw 8N1 line at the given speed.
+    pub fn open(path: &Path, baud_rate: u32) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+        let speed = speed(baud_rate)?;
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            if libc::cfsetispeed(&mut termios, speed) < 0 || libc::cfsetospeed(&mut termios, speed) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Throw away anything that arrived before the trigger was started.
+            libc::tcflush(fd, libc::TCIFLUSH);
+        }
+        Ok(file)
+    }
+
+    /// Wait for the port to have something to read.  Returns false if it timed out.
+    fn wait_readable(file: &File) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL_MS) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Start the scenario if a line matches, passing on any named groups as variables.
+    fn check_line(
+        line: &str,
+        pattern: &Regex,
+        scenario: &Option<UnitName>,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+    ) {
+        let captures = match pattern.captures(line) {
+            Some(c) => c,
+            None => return,
+        };
+        let variables = pattern
+            .capture_names()
+            .flatten()
+            .filter_map(|name| captures.name(name).map(|m| (name.to_owned(), m.as_str().to_owned())))
+            .collect();
+        for msg in start_messages(scenario.clone(), variables) {
+            control.send(ManagerControlMessage::new(id, msg)).ok();
+        }
+    }
+
+    pub fn read_lines(
+        mut file: File,
+        path: PathBuf,
+        pattern: Regex,
+        scenario: Option<UnitName>,
+        id: UnitName,
+        control: Sender<ManagerControlMessage>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut line = vec![];
+        let mut buf = [0; 256];
+
+        while !stop.load(Ordering::SeqCst) {
+            let count = match wait_readable(&file).and_then(|ready| if ready { file.read(&mut buf).map(Some) } else { Ok(None) }) {
+                Ok(Some(0)) => {
+                    fail(&id, &control, format!("serial port {} was disconnected", path.display()));
+                    return;
+                }
+                Ok(Some(c)) => c,
+                Ok(None) => continue,
+                Err(e) => {
+                    fail(&id, &control, format!("unable to read serial port {}: {}", path.display(), e));
+                    return;
+                }
+            };
+
+            for &byte in &buf[..count] {
+                match byte {
+                    b'\n' | b'\r' => {
+                        if !line.is_empty() {
+                            check_line(String::from_utf8_lossy(&line).trim(), &pattern, &scenario, &id, &control);
+                        }
+                        line.clear();
+                    }
+                    b if line.len() < MAX_LINE_LEN => line.push(b),
+                    _ => (),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use triggers::{fail, fire, TriggerThread};
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+/// Where the kernel lists USB devices.
+pub const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+
+/// How often the device list is checked.
+const SAMPLE_INTERVAL_MS: u64 = 250;
+
+/// Starts the scenario when a matching USB device is plugged in.
+pub struct UsbTrigger {
+    /// The directory listing USB devices, normally /sys/bus/usb/devices.
+    devices: PathBuf,
+
+    /// The vendor ID the device must have.
+    vendor: u16,
+
+    /// The product ID the device must have, or None for any product from the vendor.
+    product: Option<u16>,
+
+    /// How long the USB bus must be quiet after the device appears before the scenario is started.
+    settle: Duration,
+
+    /// The scenario to start, or None for the current scenario.
+    scenario: Option<UnitName>,
+}
+
+impl UsbTrigger {
+    pub fn new(
+        devices: PathBuf,
+        vendor: u16,
+        product: Option<u16>,
+        settle: Duration,
+        scenario: Option<UnitName>,
+    ) -> UsbTrigger {
+        UsbTrigger {
+            devices: devices,
+            vendor: vendor,
+            product: product,
+            settle: settle,
+            scenario: scenario,
+        }
+    }
+
+    fn read_id(path: &Path) -> Option<u16> {
+        let id = fs::read_to_string(path).ok()?;
+        u16::from_str_radix(id.trim(), 16).ok()
+    }
+
+    /// Returns every entry in the device list, and which of them match.
+    fn scan(&self) -> io::Result<(HashSet<String>, HashSet<String>)> {
+        let mut all = HashSet::new();
+        let mut matching = HashSet::new();
+        for entry in fs::read_dir(&self.devices)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // Interfaces such as "1-1:1.0" don't have IDs of their own, and are skipped here.
+            let vendor = Self::read_id(&entry.path().join("idVendor"));
+            let product = Self::read_id(&entry.path().join("idProduct"));
+            if vendor == Some(self.vendor) && (self.product.is_none() || product == self.product) {
+                matching.insert(name.clone());
+            }
+            all.insert(name);
+        }
+        Ok((all, matching))
+    }
+
+    /// Start watching for devices on a new thread.  Devices that are
+    /// already plugged in are ignored until they're plugged in again.
+    pub fn start(self, id: UnitName, control: Sender<ManagerControlMessage>) -> io::Result<TriggerThread> {
+        let (mut last_all, mut last_matching) = self.scan()?;
+
+        Ok(TriggerThread::spawn(id.clone(), control.clone(), move |stop| {
+            // The devices that have arrived, and when the bus was last seen to change.
+            let mut arrived: HashSet<String> = HashSet::new();
+            let mut changed_at = Instant::now();
+
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(SAMPLE_INTERVAL_MS));
+
+                let (all, matching) = match self.scan() {
+                    Ok(s) => s,
+                    Err(e) => {
+                        fail(&id, &control, format!("unable to list usb devices in {}: {}", self.devices.display(), e));
+                        return;
+                    }
+                };
+
+                // New devices, and any interfaces they bring with them, count as enumeration still going on.
+                if all != last_all {
+                    changed_at = Instant::now();
+                }
+                for name in matching.difference(&last_matching) {
+                    arrived.insert(name.clone());
+                }
+                // A device that was unplugged again before it settled doesn't start anything.
+                arrived.retain(|name| matching.contains(name));
+
+                if !arrived.is_empty() && changed_at.elapsed() >= self.settle {
+                    fire(&id, &control, &self.scenario);
+                    arrived.clear();
+                }
+
+                last_all = all;
+                last_matching = matching;
+            }
+        }))
+    }
+}
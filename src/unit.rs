@@ -1,28 +1,52 @@
 extern crate dependy;
 extern crate humantime;
+#[cfg(unix)]
+extern crate libc;
 extern crate regex;
 extern crate runny;
 extern crate systemd_parser;
+extern crate toml;
 
+use std::cell::{BorrowError, BorrowMutError, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
 use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::io;
+use std::sync::Arc;
 
 use self::dependy::DepError;
 use self::humantime::DurationError;
 use self::runny::RunnyError;
 use self::runny::running::RunningError;
 use self::systemd_parser::errors::ParserError;
+use self::systemd_parser::items::{DirectiveEntry, SystemdItem, SystemdUnit};
+use self::systemd_parser::parser;
+
+use config::is_site_variable;
+use managerhandle::ManagerError;
+use parsecache::{self, Item};
+use plugin;
+use run::{variable_env, VARIABLE_ENV_PREFIX};
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, PartialOrd, Ord, Serialize)]
 pub enum UnitKind {
     Interface,
     Jig,
+    Limits,
     Logger,
+    Resource,
     Scenario,
     Test,
     Trigger,
 
+    /// A kind that a plugin has added, e.g. "instrument".
+    Custom(Arc<String>),
+
     /// Exclave-generated types
     Internal,
 }
@@ -33,17 +57,22 @@ impl fmt::Display for UnitKind {
             &UnitKind::Interface => write!(f, "interface"),
             &UnitKind::Internal => write!(f, "internal"),
             &UnitKind::Jig => write!(f, "jig"),
+            &UnitKind::Limits => write!(f, "limits"),
             &UnitKind::Logger => write!(f, "logger"),
+            &UnitKind::Resource => write!(f, "resource"),
             &UnitKind::Scenario => write!(f, "scenario"),
             &UnitKind::Test => write!(f, "test"),
             &UnitKind::Trigger => write!(f, "trigger"),
+            &UnitKind::Custom(ref kind) => write!(f, "{}", kind),
         }
     }
 }
 
+/// Unit names are passed around in nearly every message, so the id is shared
+/// rather than copied each time a name is cloned.
 #[derive(PartialEq, Eq, Hash, Debug, Clone, PartialOrd, Ord, Serialize)]
 pub struct UnitName {
-    id: String,
+    id: Arc<String>,
     kind: UnitKind,
 }
 
@@ -53,6 +82,15 @@ pub enum UnitNameError {
     UnrecognizedUnitType(String),
 }
 
+impl UnitNameError {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            UnitNameError::NoFileExtension => ErrorCode::new("no-file-extension"),
+            UnitNameError::UnrecognizedUnitType(ref t) => ErrorCode::new("unrecognized-unit-type").with("type", t),
+        }
+    }
+}
+
 impl fmt::Display for UnitNameError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -74,6 +112,11 @@ impl UnitName {
     }
 
     pub fn from_path(path: &Path) -> Result<Self, UnitNameError> {
+        // Units may also be written in TOML, e.g. "flash.test.toml".
+        if path.extension().map(|e| e == "toml").unwrap_or(false) {
+            return Self::from_path(&path.with_extension(""));
+        }
+
         // Get the extension.  An empty extension is 'valid'
         // although it will get rejected below.
         let extension = match path.extension() {
@@ -92,30 +135,58 @@ impl UnitName {
         let unit_kind = match extension.as_str() {
             "interface" => UnitKind::Interface,
             "jig" => UnitKind::Jig,
+            "limits" => UnitKind::Limits,
             "logger" => UnitKind::Logger,
+            "resource" => UnitKind::Resource,
             "scenario" => UnitKind::Scenario,
             "test" => UnitKind::Test,
             "trigger" => UnitKind::Trigger,
+            other if plugin::find(other).is_some() => UnitKind::Custom(Arc::new(other.to_owned())),
             _ => return Err(UnitNameError::UnrecognizedUnitType(extension)),
         };
 
         Ok(UnitName {
-            id: unit_id,
+            id: Arc::new(unit_id),
             kind: unit_kind,
         })
     }
 
+    /// Generate a UnitName for a unit file somewhere underneath the unit directory `root`.
+    /// If `namespaced` is set, the subdirectories it's in become part of its id,
+    /// e.g. "tests/rf/power.test" becomes "tests/rf/power".
+    pub fn from_path_in(path: &Path, root: &Path, namespaced: bool) -> Result<Self, UnitNameError> {
+        let mut name = Self::from_path(path)?;
+        if !namespaced {
+            return Ok(name);
+        }
+        if let Some(dir) = path.strip_prefix(root).ok().and_then(|p| p.parent()) {
+            let mut prefix: Vec<String> = dir.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+            if !prefix.is_empty() {
+                prefix.push(name.id.to_string());
+                name.id = Arc::new(prefix.join("/"));
+            }
+        }
+        Ok(name)
+    }
+
     /// Generate a UnitName from the specified name.
     /// If no extension is present, use default_type as the extension.
+    /// Any directories are kept as part of the id, so that namespaced units
+    /// may be referred to, e.g. "tests/rf/power".
     pub fn from_str(name: &str, default_type: &str) -> Result<Self, UnitNameError> {
         let path = Path::new(name);
-        let result = if path.extension().is_none() {
+        let mut result = if path.extension().is_none() {
             let new_path = format!("{}.{}", path.to_string_lossy(), default_type);
-            Self::from_path(&Path::new(&new_path))
+            Self::from_path(&Path::new(&new_path))?
         } else {
-            Self::from_path(&path)
+            Self::from_path(&path)?
         };
-        return result;
+        if let Some(dir) = path.parent() {
+            if dir != Path::new("") {
+                result.id = Arc::new(format!("{}/{}", dir.to_string_lossy(), result.id));
+            }
+        }
+        Ok(result)
     }
 
     pub fn from_list(s: &str, default_type: &str) -> Result<Vec<Self>, UnitNameError> {
@@ -129,20 +200,610 @@ impl UnitName {
         Ok(out_list)
     }
 
+    /// The instance name, which is the part of the id after an "@", e.g. "left" for "fixture@left".
+    pub fn instance(&self) -> &str {
+        match self.id.find('@') {
+            Some(idx) => &self.id[idx + 1..],
+            None => "",
+        }
+    }
+
+    /// Expand systemd-style specifiers in a directive value:
+    ///
+    /// * %N - the unit's id, without the kind
+    /// * %i - the instance name
+    /// * %d - the directory the unit file is in
+    /// * %H - the hostname
+    /// * %% - a literal "%"
+    ///
+    /// Anything else after a "%" is left alone.
+    pub fn expand_specifiers(&self, value: &str, unit_directory: &Path) -> String {
+        let mut expanded = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('N') => expanded.push_str(&self.id),
+                Some('i') => expanded.push_str(self.instance()),
+                Some('d') => expanded.push_str(&unit_directory.to_string_lossy()),
+                Some('H') => expanded.push_str(&hostname()),
+                Some('%') => expanded.push('%'),
+                Some(other) => {
+                    expanded.push('%');
+                    expanded.push(other);
+                }
+                None => expanded.push('%'),
+            }
+        }
+        expanded
+    }
+
     pub fn internal(s: &str) -> Self {
         UnitName {
-            id: s.to_owned(),
+            id: Arc::new(s.to_owned()),
             kind: UnitKind::Internal,
         }
     }
 }
 
+/// Expand $VAR and ${VAR} references in the text of the configuration file.  Each
+/// name is looked up in exclave's environment, and then among the variables of the
+/// current run.  References to variables that aren't set are left alone, and "$$"
+/// is a literal "$".
+pub fn expand_variables(text: &str) -> String {
+    expand_with(text, |name| env::var(name).or_else(|_| env::var(variable_env(name))).ok())
+}
+
+/// Expand the variables in a unit file directive's value.  Only the site-wide variables from
+/// [Variables] in the configuration file and the current run's variables are expanded, so
+/// that e.g. $HOME in a shell command is left for the shell.
+fn expand_value(value: &str) -> String {
+    expand_with(value, |name| {
+        if is_site_variable(name) {
+            env::var(name).ok()
+        } else {
+            env::var(variable_env(name)).ok()
+        }
+    })
+}
+
+/// Parse the text of a unit file.  Variables are expanded in each directive's value once the
+/// file has been split up into directives, so that a variable can't add directives of its own.
+///
+/// Files that have been parsed with the same values for the variables they use before are taken
+/// from the parse cache.
+pub fn parse_unit_file(text: &str) -> Result<SystemdUnit, UnitDescriptionError> {
+    let key = parsecache::key(text, referenced_inputs(text));
+    let cached = match parsecache::get(key) {
+        Some(items) => items,
+        None => {
+            let text = text.replace("\\\n", "");
+            let items: Vec<Item> = parser::parse_unit(&text)
+                .map_err(ParserError::from)?
+                .into_iter()
+                .filter_map(|item| match item {
+                    SystemdItem::Category(category) => Some(Item::Category(category.to_owned())),
+                    SystemdItem::Directive(key, value) => Some(Item::Directive(key.to_owned(), value.map(expand_value))),
+                    SystemdItem::Comment(_) => None,
+                })
+                .collect();
+            parsecache::insert(&text, key, items.clone());
+            items
+        }
+    };
+    let items = cached
+        .iter()
+        .map(|item| match *item {
+            Item::Category(ref category) => SystemdItem::Category(category),
+            Item::Directive(ref key, ref value) => SystemdItem::Directive(key, value.as_ref().map(|v| v.as_str())),
+        })
+        .collect();
+    Ok(SystemdUnit::new(&items).map_err(ParserError::from)?)
+}
+
+/// A hash of the variables that unit files are expanded with, i.e. the site-wide variables and
+/// the current run's, so that units parsed with other values can be told apart.
+pub fn expansion_inputs() -> u64 {
+    let mut variables: Vec<(OsString, OsString)> = env::vars_os()
+        .filter(|&(ref name, _)| match name.to_str() {
+            Some(name) => is_site_variable(name) || name.starts_with(VARIABLE_ENV_PREFIX),
+            None => false,
+        })
+        .collect();
+    variables.sort();
+    let mut hasher = DefaultHasher::new();
+    variables.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A hash of the variables that unit file `text` refers to and their current values.  Unlike
+/// `expansion_inputs`, a new run's variables only change this for the files that use them, so
+/// that the parse cache doesn't get another copy of every file for each run.
+pub fn referenced_inputs(text: &str) -> u64 {
+    let names = RefCell::new(vec![]);
+    expand_with(&text.replace("\\\n", ""), |name| {
+        names.borrow_mut().push(name.to_owned());
+        None
+    });
+    let mut names = names.into_inner();
+    names.sort();
+    names.dedup();
+
+    let mut hasher = DefaultHasher::new();
+    for name in names {
+        let value = if is_site_variable(&name) {
+            env::var_os(&name)
+        } else {
+            env::var_os(variable_env(&name))
+        };
+        (name, value).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Expand the variables in the values of a unit file, as `parse_unit_file` does, and write it
+/// out again for a plugin to parse.  Line breaks in a variable become spaces, so that it can't
+/// add directives of its own.  Comments are left out.
+pub fn expand_unit_text(text: &str) -> Result<String, UnitDescriptionError> {
+    let text = text.replace("\\\n", "");
+    let items = parser::parse_unit(&text).map_err(ParserError::from)?;
+    let mut expanded = String::with_capacity(text.len());
+    for item in items {
+        match item {
+            SystemdItem::Category(category) => expanded.push_str(&format!("[{}]\n", category)),
+            SystemdItem::Directive(key, value) => {
+                let value = value.map(|v| expand_value(v).replace(|c| c == '\r' || c == '\n', " "));
+                expanded.push_str(&format!("{}={}\n", key, value.unwrap_or_default()));
+            }
+            SystemdItem::Comment(_) => (),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Expand $VAR and ${VAR} references in `text`, looking each name up with `lookup`.
+fn expand_with<F>(text: &str, lookup: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if rest.starts_with('$') {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let (name, len) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => ("", 0),
+            }
+        } else {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+
+        match if name.is_empty() { None } else { lookup(name) } {
+            Some(value) => expanded.push_str(&value),
+            None => {
+                expanded.push('$');
+                expanded.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
 impl fmt::Display for UnitName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}.{}", self.id, self.kind)
     }
 }
 
+/// The other units a unit depends on, from the optional [Unit] section of its file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitDependencies {
+    /// Units that must be loaded before this one, and that must be running for this
+    /// one to run.  If one of them fails or goes away, so does this unit.
+    pub requires: Vec<UnitName>,
+
+    /// Units that should be loaded and started before this one, but that it can do without.
+    pub wants: Vec<UnitName>,
+}
+
+/// Read the unit file for `name`, in the station's language.  Units written in TOML, e.g.
+/// "flash.test.toml", are turned into the usual unit file syntax first, so that they're
+/// parsed exactly the same way.  Units defined in a manifest are picked out of it.
+pub fn read_unit_file(name: &UnitName, path: &Path) -> Result<String, UnitDescriptionError> {
+    Ok(localize(&unit_text(name, path)?))
+}
+
+fn unit_text(name: &UnitName, path: &Path) -> Result<String, UnitDescriptionError> {
+    let contents = fs::read_to_string(path)?;
+    if is_manifest(path) {
+        return match manifest_unit(&contents, name) {
+            Some((_, unit)) => Ok(unit),
+            None => Err(UnitDescriptionError::FileOpenError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}.{} is no longer in {}", name.id(), name.kind(), path.display()),
+            ))),
+        };
+    }
+    if !path.extension().map(|e| e == "toml").unwrap_or(false) {
+        return Ok(contents);
+    }
+
+    let mut unit = String::new();
+    for (section, directives) in toml::from_str::<toml::value::Table>(&contents)? {
+        let directives = match directives {
+            toml::Value::Table(d) => d,
+            other => {
+                return Err(UnitDescriptionError::InvalidValue(
+                    "".to_owned(),
+                    section,
+                    other.to_string(),
+                    vec!["a table of directives, such as [Test]".to_owned()],
+                ))
+            }
+        };
+        unit.push_str(&format!("[{}]\n", section));
+        for (key, value) in directives {
+            let value = toml_value(&section, &key, value)?;
+            unit.push_str(&format!("{}={}\n", key, value));
+        }
+        unit.push('\n');
+    }
+    Ok(unit)
+}
+
+/// A TOML value as it would be written in a unit file.  Arrays become the
+/// comma-separated lists that unit files use.
+fn toml_value(section: &str, key: &str, value: toml::Value) -> Result<String, UnitDescriptionError> {
+    Ok(match value {
+        toml::Value::String(s) => s.replace('\n', " "),
+        toml::Value::Array(items) => {
+            let mut values = vec![];
+            for item in items {
+                values.push(toml_value(section, key, item)?);
+            }
+            values.join(", ")
+        }
+        toml::Value::Table(_) => {
+            return Err(UnitDescriptionError::InvalidValue(
+                section.to_owned(),
+                key.to_owned(),
+                "a table".to_owned(),
+                vec!["a string, number, boolean, or array".to_owned()],
+            ))
+        }
+        other => other.to_string(),
+    })
+}
+
+/// The environment variable holding the language that units are described in, e.g. "de".
+pub const LANGUAGE_ENV: &str = "EXCLAVE_LANGUAGE";
+
+/// Split a "Key@lang=value" line into its key and language, if it's a translation.
+fn translated_key(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with('#') || line.starts_with(';') || !line.contains('=') {
+        return None;
+    }
+    let key = line.splitn(2, '=').next().unwrap_or("").trim();
+    let at = key.find('@')?;
+    Some((&key[..at], &key[at + 1..]))
+}
+
+/// Use the translations of directives into the station's language, e.g. "Name@de=Netzteil"
+/// rather than "Name=Power supply" if it's "de" or "de_CH".  Translations into other
+/// languages are left out, and so are all of them if no language is set.
+fn localize(text: &str) -> String {
+    if !text.contains('@') {
+        return text.to_owned();
+    }
+    let language = env::var(LANGUAGE_ENV).unwrap_or_default().to_lowercase();
+    let primary = language.split(|c| c == '-' || c == '_' || c == '.').next().unwrap_or("").to_owned();
+    let wanted = |lang: &str| {
+        let lang = lang.to_lowercase();
+        !lang.is_empty() && (lang == language || lang == primary)
+    };
+    let is_header = |line: &str| line.starts_with('[') && line.ends_with(']');
+
+    // Find the directives that have been translated, section by section.
+    let mut translated = vec![];
+    let mut section = "";
+    for line in text.lines().map(|l| l.trim()) {
+        if is_header(line) {
+            section = line;
+        } else if let Some((key, lang)) = translated_key(line) {
+            if wanted(lang) {
+                translated.push((section, key));
+            }
+        }
+    }
+
+    let mut localized = String::with_capacity(text.len());
+    let mut section = "";
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if is_header(trimmed) {
+            section = trimmed;
+        } else if let Some((key, lang)) = translated_key(trimmed) {
+            if wanted(lang) {
+                let value = line.splitn(2, '=').nth(1).unwrap_or("");
+                localized.push_str(&format!("{}={}\n", key, value));
+            }
+            continue;
+        } else if trimmed.contains('=') && translated.contains(&(section, trimmed.splitn(2, '=').next().unwrap_or("").trim())) {
+            // The translation takes its place.
+            continue;
+        }
+        localized.push_str(line);
+        localized.push('\n');
+    }
+    localized
+}
+
+/// Whether `path` is a manifest, e.g. "station.exclave", which defines several units in one file.
+pub fn is_manifest(path: &Path) -> bool {
+    path.extension().map(|e| e == "exclave").unwrap_or(false)
+}
+
+/// The units defined in a manifest, as the file name each would otherwise have, the line
+/// its header is on, and its contents.  Each unit starts with its file name in double
+/// brackets, e.g. "[[flash.test]]", and runs until the next one.  Anything before the
+/// first unit is ignored, so it can be used for comments.
+pub fn manifest_units(contents: &str) -> Vec<(String, usize, String)> {
+    let mut units: Vec<(String, usize, String)> = vec![];
+    for (idx, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.len() > 4 && trimmed.starts_with("[[") && trimmed.ends_with("]]") {
+            units.push((trimmed[2..trimmed.len() - 2].trim().to_owned(), idx + 1, String::new()));
+        } else if let Some(unit) = units.last_mut() {
+            unit.2.push_str(line);
+            unit.2.push('\n');
+        }
+    }
+    units
+}
+
+/// Find the unit called `name` in a manifest, along with the line its header is on.
+/// Namespaced units are looked up by the last part of their id.
+fn manifest_unit(contents: &str, name: &UnitName) -> Option<(usize, String)> {
+    let id = name.id().rsplit('/').next().unwrap_or("");
+    manifest_units(contents)
+        .into_iter()
+        .find(|&(ref file, _, _)| match UnitName::from_path(Path::new(file)) {
+            Ok(ref n) => n.id() == id && n.kind() == name.kind(),
+            Err(_) => false,
+        })
+        .map(|(_, line, unit)| (line, unit))
+}
+
+/// The newest unit file format that this version of exclave understands.  It goes up
+/// whenever directives are added or change meaning, so that units relying on them can
+/// say so with FormatVersion= rather than having the new directives silently ignored.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Split a version such as "0.2.6" into its numbers, so that versions can be compared.
+fn version_numbers(version: &str) -> Option<Vec<u64>> {
+    version.trim().split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// Refuse a unit written for a newer exclave, as given by FormatVersion= or
+/// ExclaveVersion= in its [Unit] section, before it's misunderstood.
+pub fn check_format_version(unit_file: &SystemdUnit) -> Result<(), UnitDescriptionError> {
+    for entry in unit_file.lookup_by_category("Unit") {
+        if let &DirectiveEntry::Solo(ref directive) = entry {
+            let key = directive.key();
+            if key != "FormatVersion" && key != "ExclaveVersion" {
+                continue;
+            }
+            let value = match directive.value() {
+                Some(v) => v.trim(),
+                None => return Err(UnitDescriptionError::MissingValue("Unit".to_owned(), key.to_owned())),
+            };
+            let newer = if key == "FormatVersion" {
+                match value.parse::<u32>() {
+                    Ok(version) => version > FORMAT_VERSION,
+                    Err(_) => {
+                        return Err(UnitDescriptionError::InvalidValue(
+                            "Unit".to_owned(),
+                            key.to_owned(),
+                            value.to_owned(),
+                            vec![format!("a whole number, at most {}", FORMAT_VERSION)],
+                        ))
+                    }
+                }
+            } else {
+                match version_numbers(value) {
+                    Some(version) => version > version_numbers(env!("CARGO_PKG_VERSION")).unwrap_or_default(),
+                    None => {
+                        return Err(UnitDescriptionError::InvalidValue(
+                            "Unit".to_owned(),
+                            key.to_owned(),
+                            value.to_owned(),
+                            vec!["a version such as 0.2.6".to_owned()],
+                        ))
+                    }
+                }
+            };
+            if newer {
+                return Err(UnitDescriptionError::NeedsNewerVersion(key.to_owned(), value.to_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl UnitDependencies {
+    pub fn from_unit_file(unit_file: &SystemdUnit) -> Result<Self, UnitDescriptionError> {
+        let mut dependencies = UnitDependencies::default();
+        for entry in unit_file.lookup_by_category("Unit") {
+            if let &DirectiveEntry::Solo(ref directive) = entry {
+                // Dependencies may be of any kind, so the suffix is required.
+                match directive.key() {
+                    "Requires" => dependencies.requires = UnitName::from_list(directive.value().unwrap_or(""), "")?,
+                    "Wants" => dependencies.wants = UnitName::from_list(directive.value().unwrap_or(""), "")?,
+                    _ => (),
+                }
+            }
+        }
+        Ok(dependencies)
+    }
+
+    /// Everything this unit depends on, required or not.
+    pub fn all(&self) -> Vec<&UnitName> {
+        self.requires.iter().chain(self.wants.iter()).collect()
+    }
+}
+
+/// When a logger or interface is started, from its ActivateOn directive.  Those that are
+/// seldom needed can wait until they are, rather than running for as long as exclave does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActivateOn {
+    /// As soon as it's loaded.
+    Start,
+
+    /// The first time there's a message for it, other than a log message.
+    FirstEvent,
+
+    /// When the first run starts.
+    FirstRun,
+}
+
+impl ActivateOn {
+    pub fn from_value(section: &str, value: Option<&str>) -> Result<ActivateOn, UnitDescriptionError> {
+        match value.map(|v| v.to_lowercase()) {
+            None => Ok(ActivateOn::Start),
+            Some(v) => match v.as_str() {
+                "start" => Ok(ActivateOn::Start),
+                "first-event" => Ok(ActivateOn::FirstEvent),
+                "first-run" => Ok(ActivateOn::FirstRun),
+                _ => Err(UnitDescriptionError::InvalidValue(
+                    section.to_owned(),
+                    "ActivateOn".to_owned(),
+                    v.clone(),
+                    vec!["start".to_owned(), "first-event".to_owned(), "first-run".to_owned()],
+                )),
+            },
+        }
+    }
+}
+
+/// Whether to restart a logger, interface or trigger whose program exits, from its Restart directive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartPolicy {
+    /// Leave it stopped.
+    No,
+
+    /// Restart it unless its program exited cleanly with a return code of 0.
+    OnFailure,
+
+    /// Always restart it.
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn from_value(section: &str, value: Option<&str>) -> Result<RestartPolicy, UnitDescriptionError> {
+        match value.map(|v| v.to_lowercase()) {
+            None => Ok(RestartPolicy::No),
+            Some(v) => match v.as_str() {
+                "no" => Ok(RestartPolicy::No),
+                "on-failure" => Ok(RestartPolicy::OnFailure),
+                "always" => Ok(RestartPolicy::Always),
+                _ => Err(UnitDescriptionError::InvalidValue(
+                    section.to_owned(),
+                    "Restart".to_owned(),
+                    v.clone(),
+                    vec!["no".to_owned(), "on-failure".to_owned(), "always".to_owned()],
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ActivateOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ActivateOn::Start => write!(f, "start"),
+            ActivateOn::FirstEvent => write!(f, "first event"),
+            ActivateOn::FirstRun => write!(f, "first run"),
+        }
+    }
+}
+
+/// What went wrong, for programs to act on rather than people to read.  The code is short and
+/// fixed, e.g. "missing-value", and the context has the particulars, e.g. the section and key
+/// that need a value, so that frontends can translate errors and act on them without matching
+/// the English messages, which may be reworded from one release to the next.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize)]
+pub struct ErrorCode {
+    code: &'static str,
+    context: BTreeMap<&'static str, String>,
+}
+
+impl ErrorCode {
+    pub fn new(code: &'static str) -> ErrorCode {
+        ErrorCode {
+            code: code,
+            context: BTreeMap::new(),
+        }
+    }
+
+    /// Add one of the particulars, e.g. `.with("key", "ExecStart")`.
+    pub fn with<T: fmt::Display>(mut self, key: &'static str, value: T) -> ErrorCode {
+        self.context.insert(key, value.to_string());
+        self
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn context(&self) -> &BTreeMap<&'static str, String> {
+        &self.context
+    }
+}
+
+/// The code, followed by the context as "key=value" pairs, e.g. "missing-value key=ExecStart section=Test".
+/// Percent signs and spaces in values are written as "%25" and "%20", so that each pair is one word.
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        for (key, value) in &self.context {
+            write!(f, " {}={}", key, value.replace('%', "%25").replace(' ', "%20"))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum UnitIncompatibleReason {
     TestProgramReturnedNonzero(i32, String),
@@ -150,6 +811,35 @@ pub enum UnitIncompatibleReason {
     TestFileNotPresent(String),
     IncompatibleJig,
     DependencyError(DepError<UnitName>),
+    RequirementNotLoaded(UnitName),
+    ManagerUnavailable(ManagerError),
+    PluginRejected(String),
+}
+
+impl UnitIncompatibleReason {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            UnitIncompatibleReason::TestProgramReturnedNonzero(code, ref program) => {
+                ErrorCode::new("test-program-returned-nonzero").with("program", program).with("code", code)
+            }
+            UnitIncompatibleReason::TestProgramFailed(_) => ErrorCode::new("test-program-failed"),
+            UnitIncompatibleReason::TestFileNotPresent(ref file) => ErrorCode::new("test-file-not-present").with("file", file),
+            UnitIncompatibleReason::IncompatibleJig => ErrorCode::new("incompatible-jig"),
+            UnitIncompatibleReason::DependencyError(ref e) => match *e {
+                DepError::RequirementsNotFound(ref unit)
+                | DepError::SuggestionsNotFound(ref unit)
+                | DepError::DependencyNotFound(ref unit) => ErrorCode::new("dependency-error").with("unit", unit),
+                DepError::RequirementNotFound(ref unit, ref of)
+                | DepError::SuggestionNotFound(ref unit, ref of)
+                | DepError::CircularDependency(ref unit, ref of) => {
+                    ErrorCode::new("dependency-error").with("unit", unit).with("other", of)
+                }
+            },
+            UnitIncompatibleReason::RequirementNotLoaded(ref unit) => ErrorCode::new("requirement-not-loaded").with("unit", unit),
+            UnitIncompatibleReason::ManagerUnavailable(ref e) => ErrorCode::new("manager-unavailable").with("manager", e.code()),
+            UnitIncompatibleReason::PluginRejected(_) => ErrorCode::new("plugin-rejected"),
+        }
+    }
 }
 
 impl fmt::Display for UnitIncompatibleReason {
@@ -165,6 +855,11 @@ impl fmt::Display for UnitIncompatibleReason {
                 write!(f, "Test file {} not present", file_name)
             }
             &UnitIncompatibleReason::IncompatibleJig => write!(f, "Jig not compatible"),
+            &UnitIncompatibleReason::RequirementNotLoaded(ref req) => {
+                write!(f, "Required unit {} is not loaded", req)
+            }
+            &UnitIncompatibleReason::ManagerUnavailable(ref e) => write!(f, "Not loaded, as {}", e),
+            &UnitIncompatibleReason::PluginRejected(ref reason) => write!(f, "Plugin rejected the unit: {}", reason),
             &UnitIncompatibleReason::DependencyError(ref dep_error) => match dep_error {
                 &DepError::RequirementsNotFound(ref req) => {
                     write!(f, "Requirement '{}' not found", req)
@@ -189,6 +884,12 @@ impl fmt::Display for UnitIncompatibleReason {
     }
 }
 
+impl From<ManagerError> for UnitIncompatibleReason {
+    fn from(error: ManagerError) -> Self {
+        UnitIncompatibleReason::ManagerUnavailable(error)
+    }
+}
+
 impl From<RunnyError> for UnitIncompatibleReason {
     fn from(error: RunnyError) -> Self {
         match error {
@@ -218,6 +919,25 @@ pub enum UnitSelectError {
 
     /// This unit is for a jig that is not loaded.
     NoCompatibleJig,
+
+    /// The unit was already being changed further up the call stack.
+    UnitBusy,
+}
+
+impl UnitSelectError {
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode::new(match *self {
+            UnitSelectError::UnitNotFound => "unit-not-found",
+            UnitSelectError::NoCompatibleJig => "no-compatible-jig",
+            UnitSelectError::UnitBusy => "unit-busy",
+        })
+    }
+}
+
+impl From<BorrowError> for UnitSelectError {
+    fn from(_: BorrowError) -> Self {
+        UnitSelectError::UnitBusy
+    }
 }
 
 impl fmt::Display for UnitSelectError {
@@ -225,6 +945,7 @@ impl fmt::Display for UnitSelectError {
         match self {
             &UnitSelectError::UnitNotFound => write!(f, "couldn't find unit by ID"),
             &UnitSelectError::NoCompatibleJig => write!(f, "couldn't find any compatible jig"),
+            &UnitSelectError::UnitBusy => write!(f, "unit is busy"),
         }
     }
 }
@@ -232,11 +953,32 @@ impl fmt::Display for UnitSelectError {
 pub enum UnitDeselectError {
     /// We tried to select a unit based on its ID, but it wasn't found.
     UnitNotFound,
+
+    /// The unit was already being changed further up the call stack.
+    UnitBusy,
+}
+
+impl UnitDeselectError {
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode::new(match *self {
+            UnitDeselectError::UnitNotFound => "unit-not-found",
+            UnitDeselectError::UnitBusy => "unit-busy",
+        })
+    }
+}
+
+impl From<BorrowError> for UnitDeselectError {
+    fn from(_: BorrowError) -> Self {
+        UnitDeselectError::UnitBusy
+    }
 }
 
 impl fmt::Display for UnitDeselectError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "couldn't find unit by ID")
+        match self {
+            &UnitDeselectError::UnitNotFound => write!(f, "couldn't find unit by ID"),
+            &UnitDeselectError::UnitBusy => write!(f, "unit is busy"),
+        }
     }
 }
 
@@ -249,6 +991,46 @@ pub enum UnitActivateError {
 
     /// We tried to Exec, but an error occurred.
     ExecFailed(RunnyError),
+
+    /// Some other I/O error occurred, such as being unable to open an output file.
+    IoError(io::Error),
+
+    /// The unit was already in use further up the call stack.
+    UnitBusy,
+
+    /// A resource that a test uses couldn't be acquired for it.
+    ResourceUnavailable(UnitName, String /* reason */),
+
+    /// The plugin that a unit's kind comes from couldn't start it.
+    PluginFailed(String /* reason */),
+}
+
+impl UnitActivateError {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            UnitActivateError::UnitNotFound => ErrorCode::new("unit-not-found"),
+            UnitActivateError::UnitNotSelected => ErrorCode::new("unit-not-selected"),
+            UnitActivateError::ExecFailed(_) => ErrorCode::new("exec-failed"),
+            UnitActivateError::IoError(ref e) => ErrorCode::new("io-error").with("kind", format!("{:?}", e.kind())),
+            UnitActivateError::UnitBusy => ErrorCode::new("unit-busy"),
+            UnitActivateError::ResourceUnavailable(ref resource, _) => {
+                ErrorCode::new("resource-unavailable").with("resource", resource)
+            }
+            UnitActivateError::PluginFailed(_) => ErrorCode::new("plugin-failed"),
+        }
+    }
+}
+
+impl From<BorrowError> for UnitActivateError {
+    fn from(_: BorrowError) -> Self {
+        UnitActivateError::UnitBusy
+    }
+}
+
+impl From<BorrowMutError> for UnitActivateError {
+    fn from(_: BorrowMutError) -> Self {
+        UnitActivateError::UnitBusy
+    }
 }
 
 impl From<RunnyError> for UnitActivateError {
@@ -257,6 +1039,12 @@ impl From<RunnyError> for UnitActivateError {
     }
 }
 
+impl From<io::Error> for UnitActivateError {
+    fn from(error: io::Error) -> Self {
+        UnitActivateError::IoError(error)
+    }
+}
+
 impl fmt::Display for UnitActivateError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::runny::RunnyError;
@@ -274,8 +1062,14 @@ impl fmt::Display for UnitActivateError {
                     write!(f, "Unable to activate unit: Nix library error: {:?}", e)
                 }
             },
+            &UnitActivateError::IoError(ref e) => write!(f, "Unable to activate unit: {}", e),
             &UnitActivateError::UnitNotFound => write!(f, "Couldn't find unit by id"),
             &UnitActivateError::UnitNotSelected => write!(f, "Tried to activate a deselected unit"),
+            &UnitActivateError::UnitBusy => write!(f, "Unit is busy"),
+            &UnitActivateError::ResourceUnavailable(ref resource, ref reason) => {
+                write!(f, "Resource {} is unavailable: {}", resource, reason)
+            }
+            &UnitActivateError::PluginFailed(ref reason) => write!(f, "Plugin failed to start the unit: {}", reason),
         }
     }
 }
@@ -289,6 +1083,20 @@ pub enum UnitDeactivateError {
 
     /// Runny reported an error of some sort.
     RunningError(RunningError),
+
+    /// The unit was already being changed further up the call stack.
+    UnitBusy,
+}
+
+impl UnitDeactivateError {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            UnitDeactivateError::UnitNotFound => ErrorCode::new("unit-not-found"),
+            UnitDeactivateError::NonZeroReturn(code) => ErrorCode::new("nonzero-return").with("code", code),
+            UnitDeactivateError::RunningError(_) => ErrorCode::new("running-error"),
+            UnitDeactivateError::UnitBusy => ErrorCode::new("unit-busy"),
+        }
+    }
 }
 
 impl From<RunningError> for UnitDeactivateError {
@@ -297,12 +1105,19 @@ impl From<RunningError> for UnitDeactivateError {
     }
 }
 
+impl From<BorrowError> for UnitDeactivateError {
+    fn from(_: BorrowError) -> Self {
+        UnitDeactivateError::UnitBusy
+    }
+}
+
 impl fmt::Display for UnitDeactivateError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &UnitDeactivateError::NonZeroReturn(i) => write!(f, "Nonzero return: {}", i),
             &UnitDeactivateError::RunningError(ref e) => write!(f, "Running error: {:?}", e),
             &UnitDeactivateError::UnitNotFound => write!(f, "Couldn't find unit by id"),
+            &UnitDeactivateError::UnitBusy => write!(f, "Unit is busy"),
         }
     }
 }
@@ -314,6 +1129,7 @@ pub enum UnitDescriptionError {
     MissingValue(String /* section name */, String /* key name */),
     FileOpenError(io::Error),
     ParseError(ParserError),
+    TomlError(self::toml::de::Error),
     RegexError(self::regex::Error),
     HumantimeError(DurationError),
     ParseIntError(std::num::ParseIntError),
@@ -323,6 +1139,89 @@ pub enum UnitDescriptionError {
         String,      // Specified value
         Vec<String>, /* Allowed values */
     ),
+    NeedsNewerVersion(String /* key name */, String /* required version */),
+    Located(
+        usize,                       // Line number
+        usize,                       // Column of the value
+        String,                      // The offending directive, as written
+        Box<UnitDescriptionError>,   /* What's wrong with it */
+    ),
+}
+
+impl UnitDescriptionError {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            UnitDescriptionError::InvalidUnitName(ref e) => e.error_code(),
+            UnitDescriptionError::MissingSection(ref section) => ErrorCode::new("missing-section").with("section", section),
+            UnitDescriptionError::MissingValue(ref section, ref key) => {
+                ErrorCode::new("missing-value").with("section", section).with("key", key)
+            }
+            UnitDescriptionError::FileOpenError(ref e) => ErrorCode::new("file-open-failed").with("kind", format!("{:?}", e.kind())),
+            UnitDescriptionError::ParseError(_) => ErrorCode::new("syntax-error"),
+            UnitDescriptionError::TomlError(_) => ErrorCode::new("toml-syntax-error"),
+            UnitDescriptionError::RegexError(_) => ErrorCode::new("invalid-regex"),
+            UnitDescriptionError::HumantimeError(_) => ErrorCode::new("invalid-duration"),
+            UnitDescriptionError::ParseIntError(_) => ErrorCode::new("invalid-integer"),
+            UnitDescriptionError::InvalidValue(ref section, ref key, ref value, ref allowed) => ErrorCode::new("invalid-value")
+                .with("section", section)
+                .with("key", key)
+                .with("value", value)
+                .with("allowed", allowed.join(",")),
+            UnitDescriptionError::NeedsNewerVersion(ref key, ref version) => {
+                ErrorCode::new("needs-newer-version").with("key", key).with("version", version)
+            }
+            UnitDescriptionError::Located(line, column, ref directive, ref e) => {
+                e.error_code().with("line", line).with("column", column).with("directive", directive)
+            }
+        }
+    }
+
+    /// Point an error about a particular directive at the line of the unit file it's on,
+    /// so authors don't have to hunt for it.  Other errors are returned as they are.
+    pub fn locate(self, name: &UnitName, path: &Path) -> Self {
+        let directive = match self {
+            UnitDescriptionError::MissingValue(ref section, ref key) => Some((section.clone(), key.clone())),
+            UnitDescriptionError::NeedsNewerVersion(ref key, _) => Some(("Unit".to_owned(), key.clone())),
+            UnitDescriptionError::InvalidValue(ref section, ref key, _, _) => Some((section.clone(), key.clone())),
+            _ => None,
+        };
+        let (section, key) = match directive {
+            Some(d) => d,
+            None => return self,
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(_) => return self,
+        };
+        // Lines in a manifest are counted from the top of the manifest, not the unit.
+        let (first_line, contents) = if is_manifest(path) {
+            match manifest_unit(&contents, name) {
+                Some(found) => found,
+                None => return self,
+            }
+        } else {
+            (0, contents)
+        };
+
+        let mut current_section = String::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                current_section = trimmed[1..trimmed.len() - 1].to_owned();
+                continue;
+            }
+            if current_section != section {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            if parts.next().map(|k| k.trim() == key).unwrap_or(false) {
+                let value = parts.next().unwrap_or("");
+                let column = line.len() - value.trim_start().len() + 1;
+                return UnitDescriptionError::Located(first_line + idx + 1, column, trimmed.to_owned(), Box::new(self));
+            }
+        }
+        self
+    }
 }
 
 impl From<UnitNameError> for UnitDescriptionError {
@@ -343,6 +1242,12 @@ impl From<self::systemd_parser::errors::ParserError> for UnitDescriptionError {
     }
 }
 
+impl From<self::toml::de::Error> for UnitDescriptionError {
+    fn from(error: self::toml::de::Error) -> Self {
+        UnitDescriptionError::TomlError(error)
+    }
+}
+
 impl From<self::regex::Error> for UnitDescriptionError {
     fn from(error: self::regex::Error) -> Self {
         UnitDescriptionError::RegexError(error)
@@ -375,8 +1280,10 @@ impl fmt::Display for UnitDescriptionError {
                 write!(f, "unable to open file -- {}", e.description())
             }
             &UnitDescriptionError::ParseError(ref e) => {
-                write!(f, "syntax error: {}", e.description())
+                // The parser lists each error, with its line number, on a line of its own.
+                write!(f, "syntax error: {}", format!("{}", e).trim_end().replace('\n', " "))
             }
+            &UnitDescriptionError::TomlError(ref e) => write!(f, "TOML syntax error: {}", e),
             &UnitDescriptionError::HumantimeError(ref e) => {
                 write!(f, "time parse error: {}", e.description())
             }
@@ -395,6 +1302,17 @@ impl fmt::Display for UnitDescriptionError {
                 val,
                 allowed.join(",")
             ),
+            &UnitDescriptionError::NeedsNewerVersion(ref key, ref version) => write!(
+                f,
+                "this unit needs a newer exclave ({}={}), but this is exclave {}, which reads unit format version {}",
+                key,
+                version,
+                env!("CARGO_PKG_VERSION"),
+                FORMAT_VERSION
+            ),
+            &UnitDescriptionError::Located(line, column, ref directive, ref e) => {
+                write!(f, "line {}, column {}: {} (in \"{}\")", line, column, e, directive)
+            }
         }
     }
 }
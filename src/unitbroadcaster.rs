@@ -1,11 +1,13 @@
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time;
 
+use timestamp;
 use unitmanager::ManagerControlMessage;
-use unit::{UnitKind, UnitName};
+use unit::{ErrorCode, UnitKind, UnitName};
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum UnitStatus {
@@ -19,7 +21,10 @@ pub enum UnitStatus {
     LoadStarted(PathBuf /* path to the unit file that's gong away */),
 
     /// The unit file failed to load for some reason
-    LoadFailed(String /* reason */),
+    LoadFailed(ErrorCode, String /* reason */),
+
+    /// The unit file was parsed, but the unit can't be used on this station, e.g. because it's for another jig
+    Incompatible(ErrorCode, String /* reason */),
 
     /// The unit file has been loaded from disk, and may be selected.
     Loaded,
@@ -30,7 +35,7 @@ pub enum UnitStatus {
     Selected,
 
     /// The unit couldn't be selected for some reason.
-    SelectFailed(String /* reason */),
+    SelectFailed(ErrorCode, String /* reason */),
 
     /// The unit has been deselected (but is still loaded, and may be selected later)
     Deselected(String /* reason */),
@@ -43,13 +48,13 @@ pub enum UnitStatus {
     /// to move into the Active state but fails, then ActivationFailed will be sent.
     /// If instead the unit is Active for a while but then fails at a later time,
     /// ActivationFailed will be sent.
-    ActivationFailed(String /* reason */),
+    ActivationFailed(ErrorCode, String /* reason */),
 
     /// The unit was active, then stopped being active due to finishing successfully
     DeactivatedSuccessfully(String /* reason */),
 
     /// The unit was active, then stopped being active due to finishing unsuccessfully
-    DeactivatedUnsuccessfully(String /* reason */),
+    DeactivatedUnsuccessfully(ErrorCode, String /* reason */),
 
     /// The unit already successfully loaded, but is being removed
     UnloadStarted(PathBuf /* path to the unit file that's gong away */),
@@ -59,6 +64,180 @@ pub enum UnitStatus {
 
     /// The unit file was removed from the disk
     Removed(PathBuf),
+
+    /// The unit file changed, but the new version couldn't be loaded, so the previous one is still in use
+    Degraded(ErrorCode, String /* reason */),
+}
+
+impl UnitStatus {
+    /// A short word describing the status, e.g. "failed" or "active".
+    pub fn state(&self) -> &'static str {
+        match *self {
+            UnitStatus::Added(_) | UnitStatus::Updated(_) => "found",
+            UnitStatus::LoadStarted(_) | UnitStatus::UpdateStarted(_) => "loading",
+            UnitStatus::LoadFailed(_, _) | UnitStatus::SelectFailed(_, _) | UnitStatus::ActivationFailed(_, _) => "failed",
+            UnitStatus::Incompatible(_, _) => "incompatible",
+            UnitStatus::Loaded | UnitStatus::Deselected(_) => "loaded",
+            UnitStatus::Selected
+            | UnitStatus::DeactivatedSuccessfully(_)
+            | UnitStatus::DeactivatedUnsuccessfully(_, _) => "selected",
+            UnitStatus::Active => "active",
+            UnitStatus::UnloadStarted(_) | UnitStatus::Removed(_) => "removed",
+            UnitStatus::Degraded(_, _) => "degraded",
+        }
+    }
+
+    /// The stage of its lifecycle that a unit reaches with this status, along with a reason
+    /// code saying why.  Statuses that don't move a unit on, such as the start of a load, have none.
+    pub fn stage(&self) -> Option<(UnitStage, &'static str)> {
+        Some(match *self {
+            UnitStatus::Added(_) => (UnitStage::Discovered, "file-added"),
+            UnitStatus::Updated(_) => (UnitStage::Discovered, "file-changed"),
+            UnitStatus::LoadFailed(_, _) => (UnitStage::Failed, "invalid-unit-file"),
+            UnitStatus::Incompatible(ref error, _) => (UnitStage::Incompatible, error.code()),
+            UnitStatus::Loaded => (UnitStage::Compatible, "loaded"),
+            UnitStatus::Selected => (UnitStage::Selected, "selected"),
+            UnitStatus::SelectFailed(_, _) => (UnitStage::Failed, "select-failed"),
+            UnitStatus::Deselected(_) => (UnitStage::Compatible, "deselected"),
+            UnitStatus::Active => (UnitStage::Activated, "activated"),
+            UnitStatus::ActivationFailed(_, _) => (UnitStage::Failed, "activation-failed"),
+            UnitStatus::DeactivatedSuccessfully(_) => (UnitStage::Deactivated, "deactivated"),
+            UnitStatus::DeactivatedUnsuccessfully(_, _) => (UnitStage::Failed, "deactivation-failed"),
+            UnitStatus::Removed(_) => (UnitStage::Removed, "file-removed"),
+            // A unit that's degraded carries on with its previous version, in whatever stage it was in.
+            UnitStatus::LoadStarted(_)
+            | UnitStatus::UpdateStarted(_)
+            | UnitStatus::UnloadStarted(_)
+            | UnitStatus::Degraded(_, _) => return None,
+        })
+    }
+
+    /// What went wrong, if this status is a failure of some sort.
+    pub fn error(&self) -> Option<&ErrorCode> {
+        match *self {
+            UnitStatus::LoadFailed(ref error, _)
+            | UnitStatus::Incompatible(ref error, _)
+            | UnitStatus::SelectFailed(ref error, _)
+            | UnitStatus::ActivationFailed(ref error, _)
+            | UnitStatus::DeactivatedUnsuccessfully(ref error, _)
+            | UnitStatus::Degraded(ref error, _) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The stages of a unit's lifecycle, from its file being found to it being used.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize)]
+pub enum UnitStage {
+    Discovered,
+    Parsed,
+    Compatible,
+    Incompatible,
+    Selected,
+    Activated,
+    Deactivated,
+    Failed,
+    Removed,
+}
+
+impl fmt::Display for UnitStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnitStage::Discovered => write!(f, "discovered"),
+            UnitStage::Parsed => write!(f, "parsed"),
+            UnitStage::Compatible => write!(f, "compatible"),
+            UnitStage::Incompatible => write!(f, "incompatible"),
+            UnitStage::Selected => write!(f, "selected"),
+            UnitStage::Activated => write!(f, "activated"),
+            UnitStage::Deactivated => write!(f, "deactivated"),
+            UnitStage::Failed => write!(f, "failed"),
+            UnitStage::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// A unit moving from one stage of its lifecycle to another, so that interfaces and loggers
+/// can show what each unit is doing without piecing it together from log messages.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize)]
+pub struct UnitTransition {
+    unit: UnitName,
+
+    /// The stage the unit was in, or None if it hasn't been seen before.
+    from: Option<UnitStage>,
+
+    to: UnitStage,
+
+    /// Why the unit moved, e.g. "file-changed" or "incompatible-jig".
+    reason: String,
+
+    /// The details, for people to read.
+    message: String,
+
+    /// What went wrong, if the unit moved because something did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorCode>,
+
+    /// Number of seconds since the epoch
+    unix_time: u64,
+
+    /// Number of nanoseconds since the epoch
+    unix_time_nsecs: u32,
+
+    /// Wall-clock time in RFC3339 format, in the configured timezone
+    timestamp: String,
+}
+
+impl UnitTransition {
+    pub fn new(unit: &UnitName, from: Option<UnitStage>, to: UnitStage, reason: &str, message: String, error: Option<ErrorCode>) -> Self {
+        let elapsed = LogEntry::elapsed();
+        UnitTransition {
+            unit: unit.clone(),
+            from: from,
+            to: to,
+            reason: reason.to_owned(),
+            message: message,
+            error: error,
+            unix_time: elapsed.as_secs(),
+            unix_time_nsecs: elapsed.subsec_nanos(),
+            timestamp: timestamp::rfc3339(elapsed.as_secs(), elapsed.subsec_nanos()),
+        }
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.unit
+    }
+
+    pub fn from(&self) -> Option<UnitStage> {
+        self.from
+    }
+
+    pub fn to(&self) -> UnitStage {
+        self.to
+    }
+
+    pub fn reason(&self) -> &String {
+        &self.reason
+    }
+
+    pub fn error(&self) -> Option<&ErrorCode> {
+        self.error.as_ref()
+    }
+
+    pub fn message(&self) -> &String {
+        &self.message
+    }
+
+    pub fn secs(&self) -> u64 {
+        self.unix_time
+    }
+
+    pub fn nsecs(&self) -> u32 {
+        self.unix_time_nsecs
+    }
+
+    pub fn timestamp(&self) -> &String {
+        &self.timestamp
+    }
 }
 
 impl fmt::Display for UnitStatus {
@@ -67,22 +246,24 @@ impl fmt::Display for UnitStatus {
             &UnitStatus::Added(ref path) => write!(f, "added file {}", path.to_string_lossy()),
             &UnitStatus::Updated(ref path) => write!(f, "updated file {}", path.to_string_lossy()),
             &UnitStatus::LoadStarted(ref path) => write!(f, "load started {}", path.to_string_lossy()),
-            &UnitStatus::LoadFailed(ref x) => write!(f, "load failed: {}", x),
+            &UnitStatus::LoadFailed(_, ref x) => write!(f, "load failed: {}", x),
+            &UnitStatus::Incompatible(_, ref x) => write!(f, "incompatible: {}", x),
             &UnitStatus::Loaded => write!(f, "loaded"),
             &UnitStatus::Selected => write!(f, "selected"),
-            &UnitStatus::SelectFailed(ref reason) => write!(f, "select failed: {}", reason),
+            &UnitStatus::SelectFailed(_, ref reason) => write!(f, "select failed: {}", reason),
             &UnitStatus::Deselected(ref reason) => write!(f, "deselected: {}", reason),
             &UnitStatus::Active => write!(f, "active"),
-            &UnitStatus::ActivationFailed(ref reason) => write!(f, "activation failed: {}", reason),
+            &UnitStatus::ActivationFailed(_, ref reason) => write!(f, "activation failed: {}", reason),
             &UnitStatus::DeactivatedSuccessfully(ref x) => {
                 write!(f, "deactivated successfully: {}", x)
             }
-            &UnitStatus::DeactivatedUnsuccessfully(ref x) => {
+            &UnitStatus::DeactivatedUnsuccessfully(_, ref x) => {
                 write!(f, "deactivated unsuccessfilly: {}", x)
             }
             &UnitStatus::UnloadStarted(ref path) => write!(f, "unloading {}", path.to_string_lossy()),
             &UnitStatus::UpdateStarted(ref path) => write!(f, "updating {}", path.to_string_lossy()),
             &UnitStatus::Removed(ref path) => write!(f, "removed file {}", path.to_string_lossy()),
+            &UnitStatus::Degraded(_, ref reason) => write!(f, "degraded, still using the previous version: {}", reason),
         }
     }
 }
@@ -103,38 +284,23 @@ impl UnitStatusEvent {
     pub fn kind(&self) -> &UnitKind {
         &self.name.kind()
     }
-    pub fn new_added(path: &Path) -> Option<UnitStatusEvent> {
-        let name = match UnitName::from_path(path) {
-            Err(_) => return None,
-            Ok(s) => s,
-        };
-
-        Some(UnitStatusEvent {
-            name: name,
+    pub fn new_added(name: &UnitName, path: &Path) -> UnitStatusEvent {
+        UnitStatusEvent {
+            name: name.clone(),
             status: UnitStatus::Added(path.to_owned()),
-        })
+        }
     }
-    pub fn new_updated(path: &Path) -> Option<UnitStatusEvent> {
-        let name = match UnitName::from_path(path) {
-            Err(_) => return None,
-            Ok(s) => s,
-        };
-
-        Some(UnitStatusEvent {
-            name: name,
+    pub fn new_updated(name: &UnitName, path: &Path) -> UnitStatusEvent {
+        UnitStatusEvent {
+            name: name.clone(),
             status: UnitStatus::Updated(path.to_owned()),
-        })
+        }
     }
-    pub fn new_removed(path: &Path) -> Option<UnitStatusEvent> {
-        let name = match UnitName::from_path(path) {
-            Err(_) => return None,
-            Ok(s) => s,
-        };
-
-        Some(UnitStatusEvent {
-            name: name,
+    pub fn new_removed(name: &UnitName, path: &Path) -> UnitStatusEvent {
+        UnitStatusEvent {
+            name: name.clone(),
             status: UnitStatus::Removed(path.to_owned()),
-        })
+        }
     }
 
     pub fn new_selected(name: &UnitName) -> UnitStatusEvent {
@@ -144,10 +310,10 @@ impl UnitStatusEvent {
         }
     }
 
-    pub fn new_select_failed(name: &UnitName, msg: String) -> UnitStatusEvent {
+    pub fn new_select_failed(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
         UnitStatusEvent {
             name: name.clone(),
-            status: UnitStatus::SelectFailed(msg),
+            status: UnitStatus::SelectFailed(error, msg),
         }
     }
 
@@ -172,10 +338,24 @@ impl UnitStatusEvent {
         }
     }
 
-    pub fn new_load_failed(name: &UnitName, msg: String) -> UnitStatusEvent {
+    pub fn new_load_failed(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
+        UnitStatusEvent {
+            name: name.clone(),
+            status: UnitStatus::LoadFailed(error, msg),
+        }
+    }
+
+    pub fn new_incompatible(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
+        UnitStatusEvent {
+            name: name.clone(),
+            status: UnitStatus::Incompatible(error, msg),
+        }
+    }
+
+    pub fn new_degraded(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
         UnitStatusEvent {
             name: name.clone(),
-            status: UnitStatus::LoadFailed(msg),
+            status: UnitStatus::Degraded(error, msg),
         }
     }
 
@@ -186,10 +366,10 @@ impl UnitStatusEvent {
         }
     }
 
-    pub fn new_active_failed(name: &UnitName, msg: String) -> UnitStatusEvent {
+    pub fn new_active_failed(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
         UnitStatusEvent {
             name: name.clone(),
-            status: UnitStatus::ActivationFailed(msg),
+            status: UnitStatus::ActivationFailed(error, msg),
         }
     }
 
@@ -200,10 +380,10 @@ impl UnitStatusEvent {
         }
     }
 
-    pub fn new_deactivate_failure(name: &UnitName, msg: String) -> UnitStatusEvent {
+    pub fn new_deactivate_failure(name: &UnitName, error: ErrorCode, msg: String) -> UnitStatusEvent {
         UnitStatusEvent {
             name: name.clone(),
-            status: UnitStatus::DeactivatedUnsuccessfully(msg),
+            status: UnitStatus::DeactivatedUnsuccessfully(error, msg),
         }
     }
 
@@ -260,16 +440,25 @@ impl LogType {
     }
 }
 
+/// A log entry is copied to every interface and logger, so its strings are
+/// shared between the copies rather than duplicated for each one.
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize)]
 pub struct LogEntry {
     unit: UnitName,
     log_type: LogType,
-    log_message: String,
+    log_message: Arc<String>,
     /// Number of seconds since the epoch
     pub unix_time: u64,
 
     /// Number of nanoseconds since the epoch
     pub unix_time_nsecs: u32,
+
+    /// Wall-clock time in RFC3339 format, in the configured timezone
+    pub timestamp: Arc<String>,
+
+    /// Serial number of the device under test, if one was known when this was logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Arc<String>>,
 }
 
 impl LogEntry {
@@ -278,9 +467,11 @@ impl LogEntry {
         LogEntry {
             unit: id,
             log_type: LogType::Error,
-            log_message: message,
+            log_message: Arc::new(message),
             unix_time: elapsed.as_secs(),
             unix_time_nsecs: elapsed.subsec_nanos(),
+            timestamp: Arc::new(timestamp::rfc3339(elapsed.as_secs(), elapsed.subsec_nanos())),
+            serial: None,
         }
     }
 
@@ -289,9 +480,11 @@ impl LogEntry {
         LogEntry {
             unit: id,
             log_type: LogType::Info,
-            log_message: message,
+            log_message: Arc::new(message),
             unix_time: elapsed.as_secs(),
             unix_time_nsecs: elapsed.subsec_nanos(),
+            timestamp: Arc::new(timestamp::rfc3339(elapsed.as_secs(), elapsed.subsec_nanos())),
+            serial: None,
         }
     }
 
@@ -307,6 +500,10 @@ impl LogEntry {
         &self.log_message
     }
 
+    pub fn timestamp(&self) -> &String {
+        &self.timestamp
+    }
+
     pub fn kind(&self) -> &LogType {
         &self.log_type
     }
@@ -315,6 +512,16 @@ impl LogEntry {
         &self.unit
     }
 
+    pub fn serial(&self) -> Option<&String> {
+        self.serial.as_ref().map(|s| &**s)
+    }
+
+    /// The same entry, labelled with the serial number of the device that was being tested.
+    pub fn with_serial(mut self, serial: Option<String>) -> Self {
+        self.serial = serial.map(Arc::new);
+        self
+    }
+
     fn elapsed() -> time::Duration {
         let now = time::SystemTime::now();
         match now.duration_since(time::UNIX_EPOCH) {
@@ -333,6 +540,62 @@ impl fmt::Display for LogEntry {
     }
 }
 
+/// What was timed, when exclave is run with --instrument.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum TimingKind {
+    /// A whole rescan of the unit directories.
+    Rescan,
+
+    /// Parsing a single unit file.
+    Parse,
+
+    /// From a scenario moving on to a test, until the test's program is running.
+    TestScheduling,
+
+    /// Writing messages to an interface's program.
+    InterfaceWrite,
+}
+
+impl TimingKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TimingKind::Rescan => "rescan",
+            TimingKind::Parse => "parse",
+            TimingKind::TestScheduling => "test-scheduling",
+            TimingKind::InterfaceWrite => "interface-write",
+        }
+    }
+}
+
+impl fmt::Display for TimingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How long something took.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct TimingEvent {
+    pub kind: TimingKind,
+
+    /// What was timed, e.g. the unit that was parsed.  Rescans are timed by the library.
+    pub unit: UnitName,
+
+    pub duration: time::Duration,
+}
+
+impl TimingEvent {
+    pub fn seconds(&self) -> f64 {
+        self.duration.as_secs() as f64 + f64::from(self.duration.subsec_nanos()) / 1_000_000_000.0
+    }
+}
+
+impl fmt::Display for TimingEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} took {:.3} ms", self.unit, self.kind, self.seconds() * 1000.0)
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum UnitEvent {
     /// A unit has updated its status.
@@ -347,6 +610,12 @@ pub enum UnitEvent {
     /// The system has requested a rescan take place.
     RescanRequest,
 
+    /// Every unit file should be read again, e.g. because exclave was sent SIGHUP.
+    ReloadRequest,
+
+    /// The global configuration file has changed, and should be read again.
+    ConfigReloadRequest,
+
     /// A rescan has started.
     RescanStart,
 
@@ -358,16 +627,28 @@ pub enum UnitEvent {
 
     /// The system is shutting down.
     Shutdown,
+
+    /// Sent periodically when systemd is watching exclave, to check that the main loop is still running.
+    WatchdogPing,
+
+    /// Something was timed.  These are only sent when exclave is run with --instrument.
+    Timing(TimingEvent),
 }
 
 #[derive(Debug, Clone)]
 pub struct UnitBroadcaster {
     senders: Arc<Mutex<Vec<Sender<UnitEvent>>>>,
+
+    /// Whether timings are sent, which is shared by every copy of the broadcaster.
+    instrumented: Arc<AtomicBool>,
 }
 
 impl UnitBroadcaster {
     pub fn new() -> Self {
-        UnitBroadcaster { senders: Arc::new(Mutex::new(vec![])) }
+        UnitBroadcaster {
+            senders: Arc::new(Mutex::new(vec![])),
+            instrumented: Arc::new(AtomicBool::new(false)),
+        }
     }
 
     fn broadcast_core(senders: &Arc<Mutex<Vec<Sender<UnitEvent>>>>, event: &UnitEvent) {
@@ -408,4 +689,39 @@ impl UnitBroadcaster {
     pub fn log(&self, section: &str, message: String) {
         self.broadcast(&UnitEvent::Log(LogEntry::new_info(UnitName::internal(section), message)));
     }
+
+    pub fn set_instrumented(&self, instrumented: bool) {
+        self.instrumented.store(instrumented, Ordering::Relaxed);
+    }
+
+    pub fn instrumented(&self) -> bool {
+        self.instrumented.load(Ordering::Relaxed)
+    }
+
+    /// Start timing something, unless timings aren't being sent, in which case there's no need to.
+    pub fn timer(&self) -> Option<time::Instant> {
+        if self.instrumented() {
+            Some(time::Instant::now())
+        } else {
+            None
+        }
+    }
+
+    /// Send how long it's been since `started`, as given by `timer()`.
+    pub fn timed(&self, kind: TimingKind, unit: &UnitName, started: Option<time::Instant>) {
+        if let Some(started) = started {
+            self.timing(kind, unit, started.elapsed());
+        }
+    }
+
+    /// Send how long something took, if timings are being sent.
+    pub fn timing(&self, kind: TimingKind, unit: &UnitName, duration: time::Duration) {
+        if self.instrumented() {
+            self.broadcast(&UnitEvent::Timing(TimingEvent {
+                kind: kind,
+                unit: unit.clone(),
+                duration: duration,
+            }));
+        }
+    }
 }
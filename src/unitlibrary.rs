@@ -1,38 +1,66 @@
 // The UnitLibrary contains plans to load each valid Unit.  Units may
 // not actually be selected, e.g. if they aren't compatible.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use config::Config;
-use unit::{UnitKind, UnitName};
-use unitbroadcaster::{UnitBroadcaster, UnitCategoryEvent, UnitEvent, UnitStatus, UnitStatusEvent};
-use unitmanager::UnitManager;
+use parsecache;
+use unit::{expansion_inputs, ErrorCode, UnitDependencies, UnitDescriptionError, UnitKind, UnitName};
+use unitbroadcaster::{LogEntry, TimingKind, UnitBroadcaster, UnitCategoryEvent, UnitEvent, UnitStatus, UnitStatusEvent};
+use managerhandle::ManagerHandle;
+use units::custom::CustomDescription;
 use units::interface::InterfaceDescription;
 use units::jig::{JigDescription};
+use units::limits::LimitsDescription;
 use units::logger::LoggerDescription;
+use units::resource::ResourceDescription;
 use units::scenario::{ScenarioDescription};
 use units::test::{TestDescription};
 use units::trigger::TriggerDescription;
 
 macro_rules! process_if {
-    ($slf:ident, $name:ident, $status:ident, $tstkind:path, $path:ident, $trgt:ident, $desc:ident) => {
-        if $name.kind() == &$tstkind {
-            // Add the unit name to a list of "dirty units" that will be checked during "rescan()"
-            $slf.mark_dirty($name);
-            match $trgt::from_path($path) {
+    ($slf:ident, $name:ident, $status:ident, $tstkind:expr, $parsed:ident, $desc:ident) => {
+        {
+            let working = match $status {
+                &UnitStatus::UpdateStarted(_) => $slf.is_loaded($name),
+                _ => false,
+            };
+            match $parsed {
+                // Don't let a broken edit, e.g. a typo saved halfway through, take a working unit away.
+                Err(ref e) if working => {
+                    $slf.broadcaster
+                        .broadcast(&UnitEvent::Status(UnitStatusEvent::new_degraded($name, e.error_code(), format!("{}", e))));
+                },
                 Err(e) => {
-                    let status = UnitStatus::LoadFailed(format!("{}", e));
+                    // Add the unit name to a list of "dirty units" that will be checked during "rescan()"
+                    $slf.mark_dirty($name);
+                    $slf.keep_previous.borrow_mut().remove($name);
+                    let status = UnitStatus::LoadFailed(e.error_code(), format!("{}", e));
 
                     $slf.broadcaster
-                        .broadcast(&UnitEvent::Status(UnitStatusEvent::new_load_failed($name, format!("{}", e))));
+                        .broadcast(&UnitEvent::Status(UnitStatusEvent::new_load_failed($name, e.error_code(), format!("{}", e))));
                     // Add an entry to the status to report unit failure.
                     $slf.unit_status
                         .borrow_mut()
                         .insert($name.clone(), status);
                 },
                 Ok(description) => {
+                    // Add the unit name to a list of "dirty units" that will be checked during "rescan()"
+                    $slf.mark_dirty($name);
+                    if working {
+                        $slf.keep_previous.borrow_mut().insert($name.clone(), ());
+                    }
+
                     // Insert it into the description table
                     $slf.$desc.borrow_mut().insert($name.clone(), description);
 
@@ -54,39 +82,85 @@ macro_rules! process_if {
     }
 }
 
+macro_rules! remove_deleted_units {
+    ($slf:ident, $statuses:ident, $to_remove:ident, $dirty:ident, $descriptions:ident) => {
+        for (id, _) in $slf.$dirty.borrow().iter() {
+            match $statuses.get(id) {
+                Some(&UnitStatus::UnloadStarted(_)) | Some(&UnitStatus::LoadFailed(_, _)) => {
+                    $slf.$descriptions.borrow_mut().remove(id);
+                    $slf.clear_references(id);
+                    $slf.unload(id);
+                    $to_remove.push(id.clone());
+                }
+                Some(_) => (),
+                // There's nothing to go on, so drop it rather than tripping over it on every rescan.
+                None => {
+                    $slf.broadcaster.log("library", format!("No status for dirty unit {}", id));
+                    $to_remove.push(id.clone());
+                }
+            }
+        }
+    }
+}
+
 macro_rules! load_units_for_activation {
     ($slf:ident, $statuses:ident, $dirty:ident, $descriptions:ident, $load:ident) => {
         {
             let mut to_remove = vec![];
-            for (id, _) in $slf.$dirty.borrow().iter() {
+            let order = $slf.load_order(&$slf.$dirty.borrow());
+            for id in order.iter() {
                 let load_result = {
-                    let status = $statuses.get(id);
-                    if status.is_none() {
-                        to_remove.push(id.clone());
-                        continue;
-                    }
-                    let status = status.unwrap();
-
-                    let descriptions = $slf.$descriptions.borrow();
-                    let description = descriptions.get(id);
-                    if description.is_none() {
-                        to_remove.push(id.clone());
-                        continue;
+                    match $statuses.get(id) {
+                        Some(&UnitStatus::LoadStarted(_)) | Some(&UnitStatus::UpdateStarted(_)) => (),
+                        Some(status) => {
+                            let message = format!("unexpected unit status: {}", status);
+                            $slf.broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_load_failed(id, ErrorCode::new("unit-busy"), message)));
+                            to_remove.push(id.clone());
+                            continue;
+                        }
+                        None => {
+                            to_remove.push(id.clone());
+                            continue;
+                        }
                     }
-                    let description = description.unwrap();
-
-                    $slf.unit_manager.borrow_mut().unload(id);
 
-                    match status {
-                        &UnitStatus::LoadStarted(_) => $slf.unit_manager.borrow_mut().$load(description),
-                        &UnitStatus::UpdateStarted(_) => $slf.unit_manager.borrow_mut().$load(description),
-                        x => panic!("Unexpected unit status: {}", x),
+                    let description = match $slf.$descriptions.borrow().get(id) {
+                        Some(description) => description.clone(),
+                        None => {
+                            to_remove.push(id.clone());
+                            continue;
+                        }
+                    };
+
+                    // If the file was edited, the version that's running keeps
+                    // going until the new one has loaded.
+                    let wants_previous = $slf.keep_previous.borrow().contains_key(id);
+                    let load_id = id.clone();
+                    let reply = $slf.unit_manager.call(move |manager| {
+                        let keep_previous = wants_previous && manager.is_loaded(&load_id);
+                        if !keep_previous {
+                            manager.unload(&load_id);
+                        }
+                        (manager.$load(&description), keep_previous)
+                    });
+                    match reply {
+                        Ok(result) => result,
+                        Err(e) => (Err(e.into()), false),
                     }
                 };
 
-                if let Err(e) = load_result {
-                    $statuses.insert(id.clone(), UnitStatus::LoadFailed(format!("{}", e)));
-                    to_remove.push(id.clone());
+                // The manager has already announced the failure.  The description itself
+                // is fine, so keep its status, so that it can be loaded again later on,
+                // e.g. once something it requires becomes available.
+                match load_result {
+                    (Ok(_), _) => {
+                        $slf.keep_previous.borrow_mut().remove(id);
+                    }
+                    (Err(e), true) => {
+                        $slf.broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_degraded(id, e.error_code(), format!("{}", e))));
+                        to_remove.push(id.clone());
+                    }
+                    (Err(_), false) => to_remove.push(id.clone()),
                 }
             }
             let mut dirty = $slf.$dirty.borrow_mut();
@@ -101,8 +175,11 @@ macro_rules! select_and_activate_units {
     ($slf:ident, $dirty:ident) => {
         {
             for (id, _) in $slf.$dirty.borrow().iter() {
-                $slf.unit_manager.borrow_mut().select(id);
-                $slf.unit_manager.borrow_mut().activate(id);
+                let id = id.clone();
+                $slf.unit_manager.send(move |manager| {
+                    manager.select(&id);
+                    manager.activate(&id);
+                });
             }
             $slf.$dirty.borrow_mut().clear();
         }
@@ -116,9 +193,155 @@ macro_rules! load_units {
     }
 }
 
+/// The most unit files that get parsed at the same time.
+const PARSE_THREADS: usize = 8;
+
+/// A unit file that has been parsed, or the reason it couldn't be.
+enum Parsed {
+    Interface(Result<InterfaceDescription, UnitDescriptionError>),
+    Jig(Result<JigDescription, UnitDescriptionError>),
+    Limits(Result<LimitsDescription, UnitDescriptionError>),
+    Logger(Result<LoggerDescription, UnitDescriptionError>),
+    Resource(Result<ResourceDescription, UnitDescriptionError>),
+    Scenario(Result<ScenarioDescription, UnitDescriptionError>),
+    Test(Result<TestDescription, UnitDescriptionError>),
+    Trigger(Result<TriggerDescription, UnitDescriptionError>),
+    Custom(Result<CustomDescription, UnitDescriptionError>),
+}
+
+impl Parsed {
+    fn from_path(name: &UnitName, path: &Path) -> Option<Parsed> {
+        let located = |e: UnitDescriptionError| e.locate(name, path);
+        Some(match *name.kind() {
+            UnitKind::Interface => Parsed::Interface(InterfaceDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Jig => Parsed::Jig(JigDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Limits => Parsed::Limits(LimitsDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Logger => Parsed::Logger(LoggerDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Resource => Parsed::Resource(ResourceDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Scenario => Parsed::Scenario(ScenarioDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Test => Parsed::Test(TestDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Trigger => Parsed::Trigger(TriggerDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Custom(_) => Parsed::Custom(CustomDescription::from_path(name.clone(), path).map_err(located)),
+            UnitKind::Internal => return None,
+        })
+    }
+
+    /// A copy of a unit that was parsed successfully, for the cache.  Errors aren't
+    /// cached, since they're rare and the file is bound to be edited again soon.
+    fn cacheable(&self) -> Option<Parsed> {
+        Some(match *self {
+            Parsed::Interface(Ok(ref d)) => Parsed::Interface(Ok(d.clone())),
+            Parsed::Jig(Ok(ref d)) => Parsed::Jig(Ok(d.clone())),
+            Parsed::Limits(Ok(ref d)) => Parsed::Limits(Ok(d.clone())),
+            Parsed::Logger(Ok(ref d)) => Parsed::Logger(Ok(d.clone())),
+            Parsed::Resource(Ok(ref d)) => Parsed::Resource(Ok(d.clone())),
+            Parsed::Scenario(Ok(ref d)) => Parsed::Scenario(Ok(d.clone())),
+            Parsed::Test(Ok(ref d)) => Parsed::Test(Ok(d.clone())),
+            Parsed::Trigger(Ok(ref d)) => Parsed::Trigger(Ok(d.clone())),
+            Parsed::Custom(Ok(ref d)) => Parsed::Custom(Ok(d.clone())),
+            _ => return None,
+        })
+    }
+
+    /// What a unit that was parsed successfully refers to: the units it Requires= or Wants=,
+    /// and the jigs it's for.
+    fn references(&self) -> Option<Vec<UnitName>> {
+        let (dependencies, jigs): (&UnitDependencies, &[UnitName]) = match *self {
+            Parsed::Interface(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Jig(Ok(ref d)) => (d.dependencies(), &[][..]),
+            Parsed::Limits(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Logger(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Resource(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Scenario(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Test(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Trigger(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            Parsed::Custom(Ok(ref d)) => (d.dependencies(), d.jigs().as_slice()),
+            _ => return None,
+        };
+        Some(dependencies.all().into_iter().chain(jigs.iter()).cloned().collect())
+    }
+}
+
+/// When a unit file was last modified, a hash of its contents, and a hash of the variables
+/// it was expanded with.
+#[derive(Clone, Copy, PartialEq)]
+struct Fingerprint {
+    modified: SystemTime,
+    hash: u64,
+    inputs: u64,
+}
+
+impl Fingerprint {
+    fn of(path: &Path, inputs: u64) -> Option<Fingerprint> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        let contents = fs::read(path).ok()?;
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        Some(Fingerprint {
+            modified: modified,
+            hash: hasher.finish(),
+            inputs: inputs,
+        })
+    }
+}
+
+/// Parse a unit file, and say how long that took.
+fn parse_timed(name: &UnitName, path: &Path) -> (Option<Parsed>, Duration) {
+    let started = Instant::now();
+    let parsed = Parsed::from_path(name, path);
+    (parsed, started.elapsed())
+}
+
+/// Parse a batch of unit files on several threads at once, since a station
+/// may have hundreds of them.  The results are in the same order as `units`.
+fn parse_all(units: &[(UnitName, PathBuf)]) -> Vec<(Option<Parsed>, Duration)> {
+    // Not worth starting threads for a single edited file.
+    if units.len() < 2 {
+        return units.iter().map(|&(ref name, ref path)| parse_timed(name, path)).collect();
+    }
+
+    let jobs = Arc::new(Mutex::new(units.iter().cloned().enumerate().collect::<Vec<_>>()));
+    let (tx, rx) = channel();
+    for _ in 0..cmp::min(PARSE_THREADS, units.len()) {
+        let jobs = jobs.clone();
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let job = jobs.lock().unwrap().pop();
+            match job {
+                Some((idx, (name, path))) => {
+                    if tx.send((idx, parse_timed(&name, &path))).is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<(Option<Parsed>, Duration)> = units.iter().map(|_| (None, Duration::from_secs(0))).collect();
+    for (idx, parsed) in rx {
+        results[idx] = parsed;
+    }
+    results
+}
+
 pub struct UnitLibrary {
     broadcaster: UnitBroadcaster,
 
+    /// Unit files that have been added or changed since the last rescan.  They're
+    /// parsed all together at the start of the next rescan.
+    pending: RefCell<Vec<(UnitName, UnitStatus, PathBuf)>>,
+
+    /// Units that have already been parsed, so that files that are touched or
+    /// rewritten without actually changing don't have to be parsed again.
+    parse_cache: RefCell<HashMap<(UnitName, PathBuf), (Fingerprint, Parsed)>>,
+
+    /// Whether the parse cache that was saved in the state directory last time has been loaded.
+    parse_cache_loaded: Cell<bool>,
+
+    config: Arc<Mutex<Config>>,
+
     /// The unit status is used to determine whether to reload units or not.
     unit_status: RefCell<HashMap<UnitName, UnitStatus>>,
 
@@ -128,9 +351,15 @@ pub struct UnitLibrary {
     /// Currently available jig descriptions.  The jigs they describe might not be valid.
     jig_descriptions: RefCell<HashMap<UnitName, JigDescription>>,
 
+    /// Currently available limits descriptions.
+    limits_descriptions: RefCell<HashMap<UnitName, LimitsDescription>>,
+
     /// Currently available logger descriptions.
     logger_descriptions: RefCell<HashMap<UnitName, LoggerDescription>>,
 
+    /// Currently available resource descriptions.
+    resource_descriptions: RefCell<HashMap<UnitName, ResourceDescription>>,
+
     /// Currently available scenario descriptions.  The scenarios they describe might not be valid.
     scenario_descriptions: RefCell<HashMap<UnitName, ScenarioDescription>>,
 
@@ -140,39 +369,70 @@ pub struct UnitLibrary {
     /// Currently available trigger descriptions.  The triggers they describe might not be valid.
     trigger_descriptions: RefCell<HashMap<UnitName, TriggerDescription>>,
 
+    /// Currently available descriptions of units of the kinds that plugins add.
+    custom_descriptions: RefCell<HashMap<UnitName, CustomDescription>>,
+
+    /// Units whose files have been updated while they were loaded.  The version
+    /// that was loaded stays in use until the new one loads successfully.
+    keep_previous: RefCell<HashMap<UnitName, ()>>,
+
+    /// What each unit on disk refers to: the units it Requires= or Wants=, and the jigs it's for.
+    references: RefCell<HashMap<UnitName, Vec<UnitName>>>,
+
+    /// The other way around, i.e. the units that refer to each unit.  Editing one unit only
+    /// affects the units that refer to it, so a rescan needn't look at every unit on disk.
+    referrers: RefCell<HashMap<UnitName, HashMap<UnitName, ()>>>,
+
     /// A list of unit names that must be checked when a rescan() is performed.
     dirty_interfaces: RefCell<HashMap<UnitName, ()>>,
     dirty_jigs: RefCell<HashMap<UnitName, ()>>,
+    dirty_limits: RefCell<HashMap<UnitName, ()>>,
     dirty_loggers: RefCell<HashMap<UnitName, ()>>,
+    dirty_resources: RefCell<HashMap<UnitName, ()>>,
     dirty_scenarios: RefCell<HashMap<UnitName, ()>>,
     dirty_tests: RefCell<HashMap<UnitName, ()>>,
     dirty_triggers: RefCell<HashMap<UnitName, ()>>,
+    dirty_customs: RefCell<HashMap<UnitName, ()>>,
 
-    /// The object in charge of keeping track of units in-memory.
-    unit_manager: RefCell<UnitManager>,
+    /// The object in charge of keeping track of units in-memory, on a thread of its own.
+    unit_manager: ManagerHandle,
 }
 
 impl UnitLibrary {
     pub fn new(broadcaster: &UnitBroadcaster, config: &Arc<Mutex<Config>>) -> Self {
         UnitLibrary {
             broadcaster: broadcaster.clone(),
+            pending: RefCell::new(vec![]),
+            parse_cache: RefCell::new(HashMap::new()),
+            parse_cache_loaded: Cell::new(false),
+            config: config.clone(),
             unit_status: RefCell::new(HashMap::new()),
 
             interface_descriptions: RefCell::new(HashMap::new()),
             jig_descriptions: RefCell::new(HashMap::new()),
+            limits_descriptions: RefCell::new(HashMap::new()),
             logger_descriptions: RefCell::new(HashMap::new()),
+            resource_descriptions: RefCell::new(HashMap::new()),
             scenario_descriptions: RefCell::new(HashMap::new()),
             test_descriptions: RefCell::new(HashMap::new()),
             trigger_descriptions: RefCell::new(HashMap::new()),
+            custom_descriptions: RefCell::new(HashMap::new()),
+
+            keep_previous: RefCell::new(HashMap::new()),
+            references: RefCell::new(HashMap::new()),
+            referrers: RefCell::new(HashMap::new()),
 
             dirty_interfaces: RefCell::new(HashMap::new()),
             dirty_jigs: RefCell::new(HashMap::new()),
+            dirty_limits: RefCell::new(HashMap::new()),
             dirty_loggers: RefCell::new(HashMap::new()),
+            dirty_resources: RefCell::new(HashMap::new()),
             dirty_scenarios: RefCell::new(HashMap::new()),
             dirty_tests: RefCell::new(HashMap::new()),
             dirty_triggers: RefCell::new(HashMap::new()),
+            dirty_customs: RefCell::new(HashMap::new()),
 
-            unit_manager: RefCell::new(UnitManager::new(broadcaster, config)),
+            unit_manager: ManagerHandle::start(broadcaster, config),
         }
     }
 
@@ -181,166 +441,268 @@ impl UnitLibrary {
         match name.kind() {
             &UnitKind::Interface => self.dirty_interfaces.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Jig => self.dirty_jigs.borrow_mut().insert(name.clone(), ()),
+            &UnitKind::Limits => self.dirty_limits.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Logger => self.dirty_loggers.borrow_mut().insert(name.clone(), ()),
+            &UnitKind::Resource => self.dirty_resources.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Scenario => self.dirty_scenarios.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Test => self.dirty_tests.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Trigger => self.dirty_triggers.borrow_mut().insert(name.clone(), ()),
+            &UnitKind::Custom(_) => self.dirty_customs.borrow_mut().insert(name.clone(), ()),
             &UnitKind::Internal => None,
         };
     }
 
-    /// Examine all of the loaded units and ensure they can be loaded.
-    ///
-    /// Each unit type must be handled differently.
-    ///
-    /// 1. Mark every Interface, Scenario or Test that depends on a dirty jig as dirty.
-    ///    That way, they will be rescanned.
-    /// 2. Mark every Scenario that uses a dirty Test as dirty.
-    ///    That way, scenario dependency graphs will be re-evaluated.
-    /// 3. Delete any "dirty" objects that were Deleted.
-    /// 4. Select all Jigs that are valid.
-    /// 5. Select all Interfaces that are valid.
-    /// 6. Select all Tests that are compatible with this Jig.
-    /// 7. Select all Scenarios.
-    /// 8. Activate all Jigs (only the last one will be 'active')
-    /// 9. Activate all Interfaces.
-    pub fn rescan(&self) {
-        self.broadcaster.broadcast(&UnitEvent::RescanStart);
-        let mut statuses = self.unit_status.borrow_mut();
-
-        // 1. Go through jigs and mark dependent scenarios and tests as dirty.
-        for (jig_name, _) in self.dirty_jigs.borrow().iter() {
-            for (test_name, test_description) in self.test_descriptions.borrow().iter() {
-                if test_description.supports_jig(jig_name) {
-                    self.dirty_tests.borrow_mut().insert(test_name.clone(), ());
+    /// Parse every unit file that was added or changed since the last rescan.
+    fn parse_pending(&self) {
+        let pending: Vec<(UnitName, UnitStatus, PathBuf)> = self.pending.borrow_mut().drain(..).collect();
+        let state_directory = self.config.lock().unwrap().state_directory().clone();
+        if let Some(ref directory) = state_directory {
+            if !self.parse_cache_loaded.replace(true) {
+                if let Err(e) = parsecache::load(directory) {
+                    self.log_parse_cache_error(format!("unable to read parse cache from {}: {}", directory.to_string_lossy(), e));
                 }
             }
+        }
 
-            for (scenario_name, scenario_description) in self.scenario_descriptions
-                .borrow()
-                .iter() {
-                if scenario_description.supports_jig(jig_name) {
-                    self.dirty_scenarios
-                        .borrow_mut()
-                        .insert(scenario_name.clone(), ());
-                }
+        // Only parse the files that have changed since they were last parsed, or whose variables have.
+        let inputs = expansion_inputs();
+        let mut results: Vec<Option<Parsed>> = vec![];
+        let mut misses = vec![];
+        let mut to_parse = vec![];
+        for (idx, &(ref name, _, ref path)) in pending.iter().enumerate() {
+            let key = (name.clone(), path.clone());
+            let fingerprint = Fingerprint::of(path, inputs);
+            let cached = match (self.parse_cache.borrow().get(&key), fingerprint) {
+                (Some(&(old, ref parsed)), Some(new)) if old == new => parsed.cacheable(),
+                _ => None,
+            };
+            if cached.is_none() {
+                misses.push((idx, fingerprint));
+                to_parse.push(key);
             }
+            results.push(cached);
+        }
 
-            for (interface_name, interface_description) in self.interface_descriptions
-                .borrow()
-                .iter() {
-                if interface_description.supports_jig(jig_name) {
-                    self.dirty_interfaces.borrow_mut().insert(interface_name.clone(), ());
+        let parsed = parse_all(&to_parse);
+        for ((idx, fingerprint), (key, (result, took))) in misses.into_iter().zip(to_parse.into_iter().zip(parsed)) {
+            self.broadcaster.timing(TimingKind::Parse, &key.0, took);
+            match (fingerprint, result.as_ref().and_then(|p| p.cacheable())) {
+                (Some(fingerprint), Some(entry)) => {
+                    self.parse_cache.borrow_mut().insert(key, (fingerprint, entry));
                 }
-            }
-
-            for (logger_name, logger_description) in self.logger_descriptions
-                .borrow()
-                .iter() {
-                if logger_description.supports_jig(jig_name) {
-                    self.dirty_loggers.borrow_mut().insert(logger_name.clone(), ());
+                _ => {
+                    self.parse_cache.borrow_mut().remove(&key);
                 }
             }
-
-            for (trigger_name, trigger_description) in self.trigger_descriptions
-                .borrow()
-                .iter() {
-                if trigger_description.supports_jig(jig_name) {
-                    self.dirty_triggers.borrow_mut().insert(trigger_name.clone(), ());
-                }
+            results[idx] = result;
+        }
+        if let Some(ref directory) = state_directory {
+            if let Err(e) = parsecache::save(directory) {
+                self.log_parse_cache_error(format!("unable to write parse cache to {}: {}", directory.to_string_lossy(), e));
             }
         }
 
-        // 2. Go through tests and mark scenarios as dirty.
-        for (test_name, _) in self.dirty_tests.borrow().iter() {
-            let unit_manager = self.unit_manager.borrow();
-            let scenarios_rc = unit_manager.get_scenarios();
-            let scenarios = scenarios_rc.borrow();
-            for (scenario_name, scenario) in scenarios.iter() {
-                if scenario.borrow().uses_test(test_name) {
-                    self.dirty_scenarios
-                        .borrow_mut()
-                        .insert(scenario_name.clone(), ());
-                }
+        for ((name, status, _), parsed) in pending.into_iter().zip(results) {
+            let name = &name;
+            let status = &status;
+            let references = parsed.as_ref().and_then(|p| p.references());
+            match parsed {
+                Some(Parsed::Interface(result)) => process_if!(self, name, status, UnitKind::Interface, result, interface_descriptions),
+                Some(Parsed::Jig(result)) => process_if!(self, name, status, UnitKind::Jig, result, jig_descriptions),
+                Some(Parsed::Limits(result)) => process_if!(self, name, status, UnitKind::Limits, result, limits_descriptions),
+                Some(Parsed::Logger(result)) => process_if!(self, name, status, UnitKind::Logger, result, logger_descriptions),
+                Some(Parsed::Resource(result)) => process_if!(self, name, status, UnitKind::Resource, result, resource_descriptions),
+                Some(Parsed::Scenario(result)) => process_if!(self, name, status, UnitKind::Scenario, result, scenario_descriptions),
+                Some(Parsed::Test(result)) => process_if!(self, name, status, UnitKind::Test, result, test_descriptions),
+                Some(Parsed::Trigger(result)) => process_if!(self, name, status, UnitKind::Trigger, result, trigger_descriptions),
+                Some(Parsed::Custom(result)) => process_if!(self, name, status, name.kind().clone(), result, custom_descriptions),
+                None => (),
+            }
+            // A broken edit leaves the old description, and what it refers to, in place.
+            if let Some(references) = references {
+                self.set_references(name, references);
             }
         }
+    }
 
-        // 3. Delete any "dirty" objects that were Deleted.
+    fn log_parse_cache_error(&self, message: String) {
+        self.broadcaster
+            .broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("library"), message)));
+    }
+
+    /// Every dirty unit, of every kind.
+    fn dirty_units(&self) -> Vec<UnitName> {
+        let mut dirty = vec![];
+        dirty.extend(self.dirty_interfaces.borrow().keys().cloned());
+        dirty.extend(self.dirty_jigs.borrow().keys().cloned());
+        dirty.extend(self.dirty_limits.borrow().keys().cloned());
+        dirty.extend(self.dirty_loggers.borrow().keys().cloned());
+        dirty.extend(self.dirty_resources.borrow().keys().cloned());
+        dirty.extend(self.dirty_scenarios.borrow().keys().cloned());
+        dirty.extend(self.dirty_tests.borrow().keys().cloned());
+        dirty.extend(self.dirty_triggers.borrow().keys().cloned());
+        dirty.extend(self.dirty_customs.borrow().keys().cloned());
+        dirty
+    }
+
+    /// Record what a unit refers to, in place of whatever it referred to before.
+    fn set_references(&self, name: &UnitName, references: Vec<UnitName>) {
+        self.clear_references(name);
         {
-            let mut to_remove = vec![];
-            for (id, _) in self.dirty_jigs.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty jig in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.jig_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
-                    }
-                    _ => (),
-                }
+            let mut referrers = self.referrers.borrow_mut();
+            for reference in references.iter() {
+                referrers.entry(reference.clone()).or_insert_with(HashMap::new).insert(name.clone(), ());
             }
-            for (id, _) in self.dirty_tests.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty test in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.test_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
-                    }
-                    _ => (),
+        }
+        self.references.borrow_mut().insert(name.clone(), references);
+    }
+
+    /// Forget what a unit refers to, once its description is gone.
+    fn clear_references(&self, name: &UnitName) {
+        let old = match self.references.borrow_mut().remove(name) {
+            Some(old) => old,
+            None => return,
+        };
+        let mut referrers = self.referrers.borrow_mut();
+        for reference in old {
+            let unreferenced = match referrers.get_mut(&reference) {
+                Some(names) => {
+                    names.remove(name);
+                    names.is_empty()
                 }
+                None => false,
+            };
+            if unreferenced {
+                referrers.remove(&reference);
             }
-            for (id, _) in self.dirty_scenarios.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty scenario in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.scenario_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
-                    }
-                    _ => (),
-                }
+        }
+    }
+
+    fn is_dirty(&self, name: &UnitName) -> bool {
+        match name.kind() {
+            &UnitKind::Interface => self.dirty_interfaces.borrow().contains_key(name),
+            &UnitKind::Jig => self.dirty_jigs.borrow().contains_key(name),
+            &UnitKind::Limits => self.dirty_limits.borrow().contains_key(name),
+            &UnitKind::Logger => self.dirty_loggers.borrow().contains_key(name),
+            &UnitKind::Resource => self.dirty_resources.borrow().contains_key(name),
+            &UnitKind::Scenario => self.dirty_scenarios.borrow().contains_key(name),
+            &UnitKind::Test => self.dirty_tests.borrow().contains_key(name),
+            &UnitKind::Trigger => self.dirty_triggers.borrow().contains_key(name),
+            &UnitKind::Custom(_) => self.dirty_customs.borrow().contains_key(name),
+            &UnitKind::Internal => false,
+        }
+    }
+
+    /// Order dirty units of one kind so that each one comes after the units it depends on.
+    fn load_order(&self, dirty: &HashMap<UnitName, ()>) -> Vec<UnitName> {
+        let references = self.references.borrow();
+        let mut names: Vec<&UnitName> = dirty.keys().collect();
+        names.sort();
+
+        fn visit(
+            name: &UnitName,
+            dirty: &HashMap<UnitName, ()>,
+            references: &HashMap<UnitName, Vec<UnitName>>,
+            ordered: &mut Vec<UnitName>,
+            visiting: &mut Vec<UnitName>,
+        ) {
+            // A circular dependency gets reported when the units are loaded.
+            if ordered.contains(name) || visiting.contains(name) {
+                return;
             }
-            for (id, _) in self.dirty_interfaces.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty interface in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.interface_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
+            visiting.push(name.clone());
+            if let Some(deps) = references.get(name) {
+                for dep in deps.iter() {
+                    if dirty.contains_key(dep) {
+                        visit(dep, dirty, references, ordered, visiting);
                     }
-                    _ => (),
                 }
             }
+            ordered.push(name.clone());
+        }
 
-            for (id, _) in self.dirty_loggers.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty logger in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.logger_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
-                    }
-                    _ => (),
-                }
+        let mut ordered = vec![];
+        for name in names {
+            visit(name, dirty, &references, &mut ordered, &mut vec![]);
+        }
+        ordered
+    }
+
+    /// Examine all of the loaded units and ensure they can be loaded.
+    ///
+    /// Each unit type must be handled differently.
+    ///
+    /// 0. Parse every unit file that was added or changed since the last rescan.
+    /// 1. Mark every unit that is for a dirty jig, or that Requires= or Wants= a dirty
+    ///    unit, as dirty, so that it's checked again.  This carries on through whatever
+    ///    refers to those, but never looks at units that a change can't affect.
+    /// 2. Mark every Scenario that uses a dirty Test as dirty.
+    ///    That way, scenario dependency graphs will be re-evaluated.
+    /// 3. Delete any "dirty" objects that were Deleted.
+    /// 4. Select all Jigs that are valid.
+    /// 5. Select all Interfaces that are valid.
+    /// 6. Select all Tests that are compatible with this Jig.
+    /// 7. Select all Scenarios.
+    /// 8. Activate all Jigs (only the last one will be 'active')
+    /// 9. Activate all Interfaces.
+    pub fn rescan(&self) {
+        let started = self.broadcaster.timer();
+        self.broadcaster.broadcast(&UnitEvent::RescanStart);
+        self.parse_pending();
+        let mut statuses = self.unit_status.borrow_mut();
+
+        // 1. Mark everything that refers to a dirty unit as dirty, then everything that
+        //    refers to those, and so on.  Only the units that refer to each one are looked at.
+        let mut unchecked = self.dirty_units();
+        while let Some(name) = unchecked.pop() {
+            let mut affected: Vec<UnitName> = match self.referrers.borrow().get(&name) {
+                Some(referrers) => referrers.keys().cloned().collect(),
+                None => vec![],
+            };
+
+            // 2. Scenarios that use a dirty test need their test order worked out again.
+            //    Tests can be pulled in by what other tests provide, so ask the scenarios themselves.
+            if let &UnitKind::Test = name.kind() {
+                let test_name = name.clone();
+                let scenarios = self.unit_manager.call(move |manager| manager.scenarios_using_test(&test_name));
+                affected.extend(scenarios.unwrap_or_default());
             }
 
-            for (id, _) in self.dirty_triggers.borrow().iter() {
-                match *statuses.get(id).expect("Unable to find dirty trigger in status list") {
-                    UnitStatus::UnloadStarted(_) | UnitStatus::LoadFailed(_) => {
-                        self.trigger_descriptions.borrow_mut().remove(id);
-                        self.unit_manager.borrow_mut().unload(id);
-                        to_remove.push(id.clone());
-                    }
-                    _ => (),
+            for unit in affected {
+                if !self.is_dirty(&unit) {
+                    self.mark_dirty(&unit);
+                    unchecked.push(unit);
                 }
             }
+        }
+
+        // 3. Delete any "dirty" objects that were Deleted.
+        {
+            let mut to_remove = vec![];
+            remove_deleted_units!(self, statuses, to_remove, dirty_jigs, jig_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_limits, limits_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_resources, resource_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_tests, test_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_scenarios, scenario_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_interfaces, interface_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_loggers, logger_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_triggers, trigger_descriptions);
+            remove_deleted_units!(self, statuses, to_remove, dirty_customs, custom_descriptions);
 
             for id in to_remove {
                 match *id.kind() {
                     UnitKind::Interface => self.dirty_interfaces.borrow_mut().remove(&id),
                     UnitKind::Jig => self.dirty_jigs.borrow_mut().remove(&id),
+                    UnitKind::Limits => self.dirty_limits.borrow_mut().remove(&id),
                     UnitKind::Logger => self.dirty_loggers.borrow_mut().remove(&id),
+                    UnitKind::Resource => self.dirty_resources.borrow_mut().remove(&id),
                     UnitKind::Scenario => self.dirty_scenarios.borrow_mut().remove(&id),
                     UnitKind::Test => self.dirty_tests.borrow_mut().remove(&id),
                     UnitKind::Trigger => self.dirty_triggers.borrow_mut().remove(&id),
+                    UnitKind::Custom(_) => self.dirty_customs.borrow_mut().remove(&id),
                     UnitKind::Internal => None,
                 };
+                self.keep_previous.borrow_mut().remove(&id);
                 statuses.remove(&id);
             }
         }
@@ -357,27 +719,40 @@ impl UnitLibrary {
         // 7. Load all Triggers that are compatible with this Jig.
         load_units_for_activation!(self, statuses, dirty_triggers, trigger_descriptions, load_trigger);
 
-        // 8. Load all Tests that are compatible with this Jig.
+        // 8. Load all units of the kinds that plugins add that are compatible with this Jig.
+        load_units_for_activation!(self, statuses, dirty_customs, custom_descriptions, load_custom);
+
+        // 9. Load all Limits that are compatible with this Jig.
+        load_units!(self, statuses, dirty_limits, limits_descriptions, load_limits);
+
+        // 10. Load all Resources that are compatible with this Jig.
+        load_units!(self, statuses, dirty_resources, resource_descriptions, load_resource);
+
+        // 11. Load all Tests that are compatible with this Jig.
         load_units!(self, statuses, dirty_tests, test_descriptions, load_test);
 
-        // 9. Load all Scenarios that are compatible with this Jig.
+        // 12. Load all Scenarios that are compatible with this Jig.
         load_units!(self, statuses, dirty_scenarios, scenario_descriptions, load_scenario);
 
-        // 10. Activate all jigs that were just loaded.
+        // 13. Activate all jigs that were just loaded.
         select_and_activate_units!(self, dirty_jigs);
 
-        // 11. Activate all interfaces that were just loaded.
+        // 14. Activate all interfaces that were just loaded.
         select_and_activate_units!(self, dirty_interfaces);
 
-        // 11. Activate all loggers that were just loaded.
+        // 15. Activate all loggers that were just loaded.
         select_and_activate_units!(self, dirty_loggers);
 
-        // 12. Activate all triggers that were just loaded.
+        // 16. Activate all triggers that were just loaded.
         select_and_activate_units!(self, dirty_triggers);
 
-        // 13. Prepare any defaults that need loading (i.e. jigs, scenarios, etc.)
-        self.unit_manager.borrow_mut().refresh_defaults();
+        // 17. Activate all units of the kinds that plugins add that were just loaded.
+        select_and_activate_units!(self, dirty_customs);
+
+        // 18. Prepare any defaults that need loading (i.e. jigs, scenarios, etc.)
+        self.unit_manager.send(|manager| manager.refresh_defaults());
 
+        self.broadcaster.timed(TimingKind::Rescan, &UnitName::internal("library"), started);
         self.broadcaster.broadcast(&UnitEvent::RescanFinish);
     }
 
@@ -387,22 +762,15 @@ impl UnitLibrary {
                 let &UnitStatusEvent {ref name, ref status} = msg;
 
                 match status {
-                    &UnitStatus::LoadStarted(ref path) => {
-                        process_if!(self, name, status, UnitKind::Interface, path, InterfaceDescription, interface_descriptions);
-                        process_if!(self, name, status, UnitKind::Logger, path, LoggerDescription, logger_descriptions);
-                        process_if!(self, name, status, UnitKind::Jig, path, JigDescription, jig_descriptions);
-                        process_if!(self, name, status, UnitKind::Scenario, path, ScenarioDescription, scenario_descriptions);
-                        process_if!(self, name, status, UnitKind::Test, path, TestDescription, test_descriptions);
-                        process_if!(self, name, status, UnitKind::Trigger, path, TriggerDescription, trigger_descriptions);
-                    }
-                    &UnitStatus::UpdateStarted(ref path) => {
-                        process_if!(self, name, status, UnitKind::Interface, path, InterfaceDescription, interface_descriptions);
-                        process_if!(self, name, status, UnitKind::Jig, path, JigDescription, jig_descriptions);
-                        process_if!(self, name, status, UnitKind::Logger, path, LoggerDescription, logger_descriptions);
-                        process_if!(self, name, status, UnitKind::Scenario, path, ScenarioDescription, scenario_descriptions);
-                        process_if!(self, name, status, UnitKind::Trigger, path, TriggerDescription, trigger_descriptions);
+                    // Parsing waits for the rescan, so that a whole directory of units can be parsed at once.
+                    &UnitStatus::LoadStarted(ref path) | &UnitStatus::UpdateStarted(ref path) => {
+                        let mut pending = self.pending.borrow_mut();
+                        pending.retain(|&(ref n, _, _)| n != name);
+                        pending.push((name.clone(), status.clone(), path.clone()));
                     }
                     &UnitStatus::UnloadStarted(ref path) => {
+                        self.pending.borrow_mut().retain(|&(ref n, _, _)| n != name);
+                        self.parse_cache.borrow_mut().retain(|&(ref n, _), _| n != name);
                         self.unit_status
                             .borrow_mut()
                             .insert(name.clone(), UnitStatus::UnloadStarted(path.clone()));
@@ -412,14 +780,28 @@ impl UnitLibrary {
                 }
             },
             &UnitEvent::RescanRequest => self.rescan(),
+            // Parse everything again, even files that haven't changed, as whatever
+            // they expand to may have.
+            &UnitEvent::ReloadRequest => self.parse_cache.borrow_mut().clear(),
             _ => (),
         }
 
         // Also pass the message on to the unit manager.
-        self.unit_manager.borrow().process_message(evt);
+        let evt = evt.clone();
+        self.unit_manager.send(move |manager| manager.process_message(&evt));
+    }
+
+    fn is_loaded(&self, id: &UnitName) -> bool {
+        let id = id.clone();
+        self.unit_manager.call(move |manager| manager.is_loaded(&id)).unwrap_or(false)
+    }
+
+    fn unload(&self, id: &UnitName) {
+        let id = id.clone();
+        self.unit_manager.send(move |manager| manager.unload(&id));
     }
 
-    pub fn get_manager(&self) -> &RefCell<UnitManager> {
+    pub fn get_manager(&self) -> &ManagerHandle {
         &self.unit_manager
     }
 }
@@ -1,10 +1,20 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use unit::UnitName;
 use unitbroadcaster::{UnitBroadcaster, UnitEvent, UnitStatus, UnitStatusEvent};
 
 pub struct UnitLoader {
     broadcaster: UnitBroadcaster,
+
+    /// The unit directories, lowest precedence first.  A unit file in a
+    /// later directory overrides one with the same name in an earlier one.
+    paths: Vec<PathBuf>,
+
+    /// Every file found for each unit, along with the precedence of the
+    /// directory it's in.  Only the one with the highest precedence is loaded.
+    files: RefCell<HashMap<UnitName, Vec<(usize, PathBuf)>>>,
 }
 
 impl UnitLoader {
@@ -12,6 +22,23 @@ impl UnitLoader {
                -> Self {
         UnitLoader {
             broadcaster: broadcaster.clone(),
+            paths: vec![],
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Add a unit directory, which takes precedence over all of the ones added before it.
+    pub fn add_path(&mut self, config_dir: &str) {
+        let dir = Path::new(config_dir);
+        self.paths.push(dir.canonicalize().unwrap_or(dir.to_owned()));
+    }
+
+    /// Remove a unit directory.  Its units are unloaded, or fall back to the files
+    /// in other directories, as the watcher reports them removed.
+    pub fn remove_path(&mut self, config_dir: &Path) {
+        let dir = config_dir.canonicalize().unwrap_or(config_dir.to_owned());
+        if let Some(idx) = self.paths.iter().rposition(|p| *p == dir) {
+            self.paths.remove(idx);
         }
     }
 
@@ -20,19 +47,104 @@ impl UnitLoader {
             &UnitEvent::Shutdown => return,
             &UnitEvent::Status(ref evt) => self.handle_status(evt),
             &UnitEvent::RescanRequest => (),
+            &UnitEvent::ReloadRequest => (),
+            &UnitEvent::ConfigReloadRequest => (),
             &UnitEvent::RescanStart => (),
             &UnitEvent::RescanFinish => (),
             &UnitEvent::Category(_) => (),
             &UnitEvent::Log(_) => (),
             &UnitEvent::ManagerRequest(_) => (),
+            &UnitEvent::WatchdogPing => (),
+            &UnitEvent::Timing(_) => (),
         }
     }
 
     fn handle_status(&self, event: &UnitStatusEvent) {
         match event.status() {
-            &UnitStatus::Added(ref path) => self.load(event.name(), path),
-            &UnitStatus::Updated(ref path) => self.update(event.name(), path),
-            &UnitStatus::Removed(ref path) => self.unload(event.name(), path),
+            &UnitStatus::Added(ref path) => self.added(event.name(), path),
+            &UnitStatus::Updated(ref path) => self.updated(event.name(), path),
+            &UnitStatus::Removed(ref path) => self.removed(event.name(), path),
+            _ => (),
+        }
+    }
+
+    /// The precedence of the unit directory a file is in.  If unit
+    /// directories are nested, the innermost one counts.
+    fn precedence(&self, path: &Path) -> usize {
+        // The file may already be gone, but its directory is probably still there.
+        let canonical = path
+            .parent()
+            .and_then(|p| p.canonicalize().ok())
+            .and_then(|p| path.file_name().map(|f| p.join(f)));
+        self.paths
+            .iter()
+            .enumerate()
+            .filter(|&(_, dir)| path.starts_with(dir) || canonical.as_ref().map(|c| c.starts_with(dir)).unwrap_or(false))
+            .max_by_key(|&(_, dir)| dir.components().count())
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+
+    /// The file that should be loaded for a unit, if there is one.
+    fn winner(&self, name: &UnitName) -> Option<PathBuf> {
+        self.files
+            .borrow()
+            .get(name)
+            .and_then(|files| files.iter().max_by_key(|&&(precedence, _)| precedence))
+            .map(|&(_, ref path)| path.clone())
+    }
+
+    fn added(&self, name: &UnitName, path: &PathBuf) {
+        let previous = self.winner(name);
+        {
+            let mut files = self.files.borrow_mut();
+            let entry = files.entry(name.clone()).or_insert(vec![]);
+            entry.retain(|&(_, ref p)| p != path);
+            entry.push((self.precedence(path), path.clone()));
+        }
+
+        match (previous, self.winner(name)) {
+            (None, _) => self.load(name, path),
+            (Some(ref old), Some(ref new)) if old != new => {
+                self.broadcaster.log("loader", format!("{} overrides {}", new.display(), old.display()));
+                self.update(name, new);
+            }
+            (Some(ref old), _) if old == path => self.update(name, path),
+            (Some(old), _) => {
+                self.broadcaster.log("loader", format!("{} is overridden by {}", path.display(), old.display()));
+            }
+        }
+    }
+
+    fn updated(&self, name: &UnitName, path: &PathBuf) {
+        let known = self.files
+            .borrow()
+            .get(name)
+            .map(|files| files.iter().any(|&(_, ref p)| p == path))
+            .unwrap_or(false);
+        if !known {
+            self.added(name, path);
+        } else if self.winner(name).as_ref() == Some(path) {
+            // Changes to files that are overridden don't matter.
+            self.update(name, path);
+        }
+    }
+
+    fn removed(&self, name: &UnitName, path: &PathBuf) {
+        let previous = self.winner(name);
+        if let Some(files) = self.files.borrow_mut().get_mut(name) {
+            files.retain(|&(_, ref p)| p != path);
+        }
+
+        match (previous, self.winner(name)) {
+            (Some(ref old), Some(ref new)) if old != new => {
+                self.broadcaster.log("loader", format!("{} was removed, falling back to {}", old.display(), new.display()));
+                self.update(name, new);
+            }
+            (_, None) => {
+                self.files.borrow_mut().remove(name);
+                self.unload(name, path);
+            }
             _ => (),
         }
     }
@@ -48,4 +160,4 @@ impl UnitLoader {
     pub fn unload(&self, name: &UnitName, path: &PathBuf) {
         self.broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_unload_started(name, path)));
     }
-}
\ No newline at end of file
+}
@@ -1,35 +1,68 @@
 // The UnitManager contains all units that are Selected.  This includes
 // units that are Active.
+extern crate dependy;
+
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env;
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use artifacts::{self, Artifact};
+use audit::AuditEntry;
 use config::Config;
-use unit::{UnitName, UnitKind, UnitActivateError, UnitDeactivateError, UnitSelectError, UnitDeselectError, UnitIncompatibleReason};
-use unitbroadcaster::{UnitBroadcaster, UnitEvent, UnitStatusEvent, UnitStatus, LogEntry};
+use golden::{Golden, GoldenMode};
+use journal::{Journal, INTERRUPTED_CODE};
+use junit::Outcome;
+use labels;
+use logsinks::Record;
+use mes::MesReporter;
+use upload::Uploader;
+use retention;
+use timestamp;
+use run::{free_space, variable_env, Run, OPERATOR_ENV, SERIAL_ENV};
+use summary::{RunSummary, RUNS_FILE, TESTS_FILE};
+use unit::{ActivateOn, ErrorCode, UnitName, UnitKind, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitSelectError, UnitDeselectError, UnitIncompatibleReason};
+use unitbroadcaster::{TimingKind, UnitBroadcaster, UnitEvent, UnitStatusEvent, UnitStatus, UnitStage, UnitTransition, LogEntry};
+use usage::{Meter, ProcessStats};
+use teststats::TestStats;
+use yieldstats::{YieldStats, YieldWindow};
+use self::dependy::DepError;
+use units::custom::{CustomDescription, CustomUnit};
 use units::interface::{Interface, InterfaceDescription};
-use units::jig::{Jig, JigDescription};
+use units::jig::{Jig, JigDescription, JigHealth};
+use units::limits::{self, Limit, LimitCheck, Limits, LimitsDescription};
 use units::logger::{Logger, LoggerDescription};
+use units::resource::{Resource, ResourceDescription};
 use units::scenario::{Scenario, ScenarioDescription};
 use units::test::{Test, TestDescription};
-use units::trigger::{Trigger, TriggerDescription};
+use units::trigger::{JigRequirement, Trigger, TriggerDescription, TriggerLockout};
 
 macro_rules! load {
     ($slf:ident, $dest:ident, $desc:ident) => {
         {
-            // If the item exists in the array already, then it is active and will be deselected first.
-            if $slf.$dest.borrow().contains_key($desc.id()) {
-                // Deselect the old one it before unloading
-                $slf.deselect($desc.id(), "reloading");
-            };
             // "Load" the Unit, which means we can select or activate it later on.
-            match $desc.load($slf, &*$slf.cfg.lock().unwrap()) {
+            // Anything it requires has to have been loaded first.
+            let result = match $slf.check_requirements($desc.id(), $desc.dependencies()) {
+                Err(e) => Err(e),
+                Ok(()) => $desc.load($slf, &*$slf.cfg.lock().unwrap()),
+            };
+            match result {
                 Ok(o) => {
+                    // If the item exists in the array already, then it is active and will be deselected first.
+                    // If the new one can't be loaded, the old one is left alone.
+                    if $slf.$dest.borrow().contains_key($desc.id()) {
+                        // Deselect the old one it before unloading
+                        $slf.deselect($desc.id(), "reloading");
+                    };
                     $slf.$dest.borrow_mut().insert($desc.id().clone(), Rc::new(RefCell::new(o)));
+                    $slf.dependencies.borrow_mut().insert($desc.id().clone(), $desc.dependencies().clone());
 
                     // Announce the fact that the unit was loaded successfully.
                     $slf.bc
@@ -39,8 +72,9 @@ macro_rules! load {
                 }
                 Err(e) => {
                     $slf.bc.broadcast(
-                        &UnitEvent::Status(UnitStatusEvent::new_load_failed(
+                        &UnitEvent::Status(UnitStatusEvent::new_incompatible(
                             $desc.id(),
+                            e.error_code(),
                             format!("{}", e),
                         )),
                     );
@@ -55,6 +89,20 @@ macro_rules! load {
 pub enum FieldType {
     Name,
     Description,
+    State,
+    Error,
+
+    /// The code and context of what last went wrong with a unit, e.g. "missing-value key=ExecStart section=Test".
+    ErrorCode,
+
+    /// How long a test has taken on average lately, in seconds.
+    MeanDuration,
+
+    /// How many of a test's recent runs failed, out of how many, e.g. "3/50".
+    Failures,
+
+    /// When the run a test last failed in started, and why it failed.
+    LastFailure,
 }
 
 impl fmt::Display for FieldType {
@@ -62,6 +110,12 @@ impl fmt::Display for FieldType {
         match self {
             &FieldType::Name => write!(f, "name"),
             &FieldType::Description => write!(f, "description"),
+            &FieldType::State => write!(f, "state"),
+            &FieldType::Error => write!(f, "error"),
+            &FieldType::ErrorCode => write!(f, "errorcode"),
+            &FieldType::MeanDuration => write!(f, "meanduration"),
+            &FieldType::Failures => write!(f, "failures"),
+            &FieldType::LastFailure => write!(f, "lastfailure"),
         }
     }
 }
@@ -79,7 +133,7 @@ pub enum ManagerStatusMessage {
     Scenario(Option<UnitName>),
 
     /// Return a list of tests in a scenario.
-    Tests(UnitName /* Scenario name */, Vec<UnitName> /* List of tests */),
+    Tests(UnitName /* Scenario name */, Arc<Vec<UnitName>> /* List of tests */),
 
     /// Greeting identifying the server.
     Hello(String /* Server identification name */),
@@ -108,6 +162,38 @@ pub enum ManagerStatusMessage {
     /// Sent when a scenario has finished running.
     Finished(UnitName /* Scenario name */, u32 /* Result code */, String /* Reason for finishing */),
 
+    /// A control command was received.  Only sent to loggers that asked for the audit log.
+    Audit(AuditEntry),
+
+    /// The current status of a unit, including why it failed, if it did.
+    Status(UnitName, UnitStatus),
+
+    /// A unit has moved from one stage of its lifecycle to another.
+    Transition(UnitTransition),
+
+    /// What a unit's program used while it ran.  Only sent to loggers that asked for stats.
+    Stats(ProcessStats),
+
+    /// Yield figures over one window of time.
+    Yield(YieldWindow),
+
+    /// A file in a run's directory, and the test it belongs to.
+    Artifact(String /* run */, Artifact),
+
+    /// The contents of a file in a run's directory.
+    ArtifactData(String /* run */, String /* path */, String /* base64 contents */),
+
+    /// A golden run has finished: "recorded", "ok", "drift", or "failed", and why.
+    Golden(UnitName /* scenario */, String /* verdict */, String /* message */),
+
+    /// A run was in progress when exclave last stopped, and can be resumed or thrown out.
+    Interrupted(String /* scenario */, Option<String> /* run */, Option<String> /* serial */, usize /* tests passed */),
+
+    /// The operator now logged in, or None if nobody is.
+    Operator(Option<String>),
+
+    /// An operator tried to log in, but isn't on the list of those who may.
+    LoginFailed(String /* operator */, String /* reason */),
 }
 
 /// Messages for Unit -> Library communication
@@ -137,6 +223,12 @@ pub enum ManagerControlMessageContents {
     /// Indicates the child (Interface, Test, etc.) has exited.
     ChildExited,
 
+    /// A long-running child (e.g. a Logger) exited without being asked to.
+    UnexpectedExit(i32 /* return code */),
+
+    /// A thread doing a unit's work panicked.
+    Panicked(String /* what the panic said */),
+
     /// Client sent an unimplemented message.
     Unimplemented(String /* verb */, String /* rest of line */),
 
@@ -149,6 +241,27 @@ pub enum ManagerControlMessageContents {
     /// Start running a scenario, or the default scenario if None
     StartScenario(Option<UnitName>),
 
+    /// Act as though the named trigger had fired, e.g. to try out a trigger-driven flow without the hardware.
+    FireTrigger(UnitName),
+
+    /// Get the status of every unit, or only those of a given kind or id.
+    Status(Option<String>),
+
+    /// Get yield figures for each of the configured windows.
+    Yield,
+
+    /// List the artifacts of a run, or of the current or most recent run if None.
+    Artifacts(Option<String> /* run */),
+
+    /// Get the contents of one of a run's artifacts.
+    Artifact(String /* run */, String /* path */),
+
+    /// Make the next run a golden run, recording or verifying the scenario's baseline.
+    Golden(GoldenMode),
+
+    /// Resume the run that was interrupted when exclave last stopped (true), or throw it out (false).
+    Recover(bool /* resume */),
+
     /// Start running a given test.
     StartTest(UnitName),
 
@@ -169,6 +282,32 @@ pub enum ManagerControlMessageContents {
 
     /// Shutdown the entire system
     Shutdown(Option<String>),
+
+    /// Rescan the unit directories now, rather than waiting for unit files to stop changing.
+    Rescan,
+
+    /// Switch to a different station profile from the configuration file.
+    Profile(String),
+
+    /// Set the operator responsible for the station, or clear it if None.
+    Operator(Option<String>),
+
+    /// The operator list has been checked for an operator who's logging in, and they may
+    /// log in if there's no reason given.
+    OperatorChecked(String /* operator */, Option<String> /* why they may not */),
+
+    /// Set the serial number of the device that the next scenario will test.
+    Serial(String),
+
+    /// Set variables for the next scenario to run with, e.g. which product variant it's testing.
+    Variables(Vec<(String /* key */, String /* value */)>),
+
+    /// Tag the run in progress, or the next one if none is running, e.g. with a work order or
+    /// firmware version.  Tags are kept as variables, so they end up wherever variables do.
+    Tag(Vec<(String /* key */, String /* value */)>),
+
+    /// Report how fit the current jig is for testing, and why.
+    JigState(JigHealth, String /* reason */),
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
@@ -186,6 +325,51 @@ impl ManagerControlMessage {
     }
 }
 
+/// The result given to a test that passed, but reported a measurement outside of its limits.
+const OUT_OF_SPEC: i32 = -4;
+
+/// What each measurement outside of its limits is logged as, ahead of the reason.
+pub const OUT_OF_SPEC_MESSAGE: &str = "measurement out of spec";
+
+/// The result given to a test that couldn't be run because one of its resources was unavailable.
+const RESOURCE_UNAVAILABLE: i32 = -5;
+
+/// The result given to a test that couldn't be run because MaxTests daemons are running already.
+const TOO_MANY_TESTS: i32 = -6;
+
+/// Look up a unit by id.  The table is only borrowed for the length of the lookup, so that
+/// the unit is free to call back into the manager, even if that ends up changing the table.
+fn find<T>(table: &RefCell<HashMap<UnitName, Rc<RefCell<T>>>>, id: &UnitName) -> Option<Rc<RefCell<T>>> {
+    table.borrow().get(id).cloned()
+}
+
+/// Whether units of this kind keep running once they've been activated by a rescan.
+fn runs_continuously(kind: &UnitKind) -> bool {
+    match *kind {
+        UnitKind::Interface | UnitKind::Logger | UnitKind::Trigger | UnitKind::Custom(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether units of this kind are only started on the active instance of a hot-standby pair.
+fn held_by_standby(kind: &UnitKind) -> bool {
+    match *kind {
+        UnitKind::Jig | UnitKind::Trigger => true,
+        _ => false,
+    }
+}
+
+/// Tell an interface about a run that was interrupted, so that it can ask the operator what to do with it.
+fn interrupted_message(journal: &Journal) -> ManagerStatusMessage {
+    ManagerStatusMessage::Interrupted(journal.scenario.clone(), journal.run_id(), journal.serial.clone(), journal.passed.len())
+}
+
+/// Units are kept in RefCells of their own.  Only the jig, scenario and tests are borrowed mutably
+/// to start them, and units are borrowed mutably to take on a new Name, Description or terminate
+/// timeout.  Those borrows are taken with try_borrow_mut(), and a unit that's already in use is
+/// reported as busy.  None of them call back into the manager while they're held, other than for its
+/// control channel, so the shared borrows everywhere else can't overlap with them.  The manager's own
+/// tables are only borrowed for as long as it takes to look something up or change it.
 pub struct UnitManager {
     cfg: Arc<Mutex<Config>>,
     bc: UnitBroadcaster,
@@ -196,9 +380,15 @@ pub struct UnitManager {
     /// Loaded Jigs, available for selection and activation.
     jigs: RefCell<HashMap<UnitName, Rc<RefCell<Jig>>>>,
 
+    /// Loaded Limits, which tests' measurements are checked against.
+    limits: RefCell<HashMap<UnitName, Rc<RefCell<Limits>>>>,
+
     /// Loaded Loggers.
     loggers: RefCell<HashMap<UnitName, Rc<RefCell<Logger>>>>,
 
+    /// Loaded Resources, which tests acquire while they run.
+    resources: RefCell<HashMap<UnitName, Rc<RefCell<Resource>>>>,
+
     /// Loaded Scenarios, available for selected and activation.
     scenarios: Rc<RefCell<HashMap<UnitName, Rc<RefCell<Scenario>>>>>,
 
@@ -208,6 +398,18 @@ pub struct UnitManager {
     /// Loaded Triggers, available for selection and activation.
     triggers: Rc<RefCell<HashMap<UnitName, Rc<RefCell<Trigger>>>>>,
 
+    /// Loaded units of the kinds that plugins add.
+    custom_units: RefCell<HashMap<UnitName, Rc<RefCell<CustomUnit>>>>,
+
+    /// What each loaded unit Requires= and Wants=.
+    dependencies: RefCell<HashMap<UnitName, UnitDependencies>>,
+
+    /// The most recent status of every unit, including the ones that couldn't be loaded.
+    statuses: RefCell<HashMap<UnitName, UnitStatus>>,
+
+    /// The stage of its lifecycle that every unit has reached.
+    stages: RefCell<HashMap<UnitName, UnitStage>>,
+
     /// Prototypical message sender that will be cloned and passed to each new unit.
     control_sender: Sender<ManagerControlMessage>,
 
@@ -222,6 +424,90 @@ pub struct UnitManager {
 
     /// A list of active units.  These units must also be selected.
     active: Rc<RefCell<HashMap<UnitName, ()>>>,
+
+    /// The scenario run currently being logged, if per-run logging is enabled.
+    current_run: RefCell<Option<Run>>,
+
+    /// The directory of the most recent run to finish, to list its artifacts by default.
+    last_run: RefCell<Option<PathBuf>>,
+
+    /// Whether the next run will be a golden run, and what for.
+    golden_next: RefCell<Option<GoldenMode>>,
+
+    /// What the current golden run has done, if the current run is one.
+    golden: RefCell<Option<Golden>>,
+
+    /// The journal of the run in progress, which is kept in the state directory.
+    journal: RefCell<Option<Journal>>,
+
+    /// The run that was in progress when exclave last stopped, until the operator decides what to do with it.
+    interrupted: RefCell<Option<Journal>>,
+
+    /// Tests that passed before the run being resumed was interrupted, which aren't run again.
+    resume_tests: RefCell<Vec<String>>,
+
+    /// The scenario run currently being summarized.
+    current_summary: RefCell<Option<RunSummary>>,
+
+    /// Sends the result of each run to the MES, if there is one.
+    mes: RefCell<Option<MesReporter>>,
+
+    /// Uploads the result of each run to a central server, if there is one.
+    uploader: RefCell<Option<Uploader>>,
+
+    /// Recent runs, to work out yield figures from.  Loaded from the summary directory when it's first needed.
+    yields: RefCell<Option<YieldStats>>,
+
+    /// Recent results of each test, to tell interfaces which tests are slow or keep failing.
+    /// Loaded from the summary directory when it's first needed.
+    test_stats: RefCell<Option<TestStats>>,
+
+    /// The operator currently responsible for the station, if one has identified themselves.
+    operator: RefCell<Option<String>>,
+
+    /// The serial number of the device under test, if it's known, e.g. because it was scanned.
+    serial: RefCell<Option<String>>,
+
+    /// Variables that the next or current scenario was started with.
+    variables: RefCell<Vec<(String, String)>>,
+
+    /// Tags for the next run, which are added to its variables when it starts.
+    next_tags: RefCell<Vec<(String, String)>>,
+
+    /// The variables currently set in the environment, so they can be removed again.
+    exported_variables: RefCell<Vec<String>>,
+
+    /// Whether the first scan of the unit directories has finished, after which AutoStart applies.
+    started_up: RefCell<bool>,
+
+    /// Requests from triggers that arrived while a scenario was running, to be handled once it finishes.
+    queued_requests: RefCell<Vec<ManagerControlMessage>>,
+
+    /// Why exclave is shutting down, while it waits for the running scenario to finish first.
+    shutdown_pending: RefCell<Option<String>>,
+
+    /// When each test, interface, and logger that's running was started, and what its programs
+    /// have used since.
+    usage: RefCell<HashMap<UnitName, (Instant, Meter)>>,
+
+    /// Tests waiting for one of the MaxTests that are running to stop, in the order they were started.
+    waiting_tests: RefCell<Vec<UnitName>>,
+
+    /// Interfaces and loggers that have been selected, but that wait to be started until they're needed.
+    deferred: RefCell<HashMap<UnitName, ActivateOn>>,
+
+    /// Whether this is the standby of a hot-standby pair, which leaves the jig and triggers to the
+    /// active instance, and the ones that it would otherwise have started.
+    standby: RefCell<bool>,
+    standing_by: RefCell<Vec<UnitName>>,
+
+    /// Why each test that's running or just finished is out of spec, if it reported measurements outside of its limits.
+    out_of_spec: RefCell<HashMap<UnitName, Vec<String>>>,
+
+    /// When the current scenario last moved on, and when each test that's due to start was
+    /// asked for as a result, for timing how long tests take to start with --instrument.
+    advanced_at: RefCell<Option<Instant>>,
+    scheduled: RefCell<HashMap<UnitName, Instant>>,
 }
 
 impl UnitManager {
@@ -231,31 +517,79 @@ impl UnitManager {
         let monitor_broadcaster = broadcaster.clone();
         thread::spawn(move || Self::control_message_monitor(receiver, monitor_broadcaster));
 
-        UnitManager {
+        let manager = UnitManager {
             cfg: config.clone(),
             bc: broadcaster.clone(),
 
             interfaces: RefCell::new(HashMap::new()),
             jigs: RefCell::new(HashMap::new()),
+            limits: RefCell::new(HashMap::new()),
             loggers: RefCell::new(HashMap::new()),
+            resources: RefCell::new(HashMap::new()),
             scenarios: Rc::new(RefCell::new(HashMap::new())),
             tests: Rc::new(RefCell::new(HashMap::new())),
             triggers: Rc::new(RefCell::new(HashMap::new())),
+            custom_units: RefCell::new(HashMap::new()),
+            dependencies: RefCell::new(HashMap::new()),
+            statuses: RefCell::new(HashMap::new()),
+            stages: RefCell::new(HashMap::new()),
 
             selected: Rc::new(RefCell::new(HashMap::new())),
             active: Rc::new(RefCell::new(HashMap::new())),
 
             current_scenario: Rc::new(RefCell::new(None)),
             current_jig: Rc::new(RefCell::new(None)),
+            current_run: RefCell::new(None),
+            last_run: RefCell::new(None),
+            golden_next: RefCell::new(None),
+            golden: RefCell::new(None),
+            journal: RefCell::new(None),
+            interrupted: RefCell::new(None),
+            resume_tests: RefCell::new(vec![]),
+            current_summary: RefCell::new(None),
+            yields: RefCell::new(None),
+            test_stats: RefCell::new(None),
+            mes: RefCell::new(None),
+            uploader: RefCell::new(None),
+            operator: RefCell::new(None),
+            serial: RefCell::new(None),
+            variables: RefCell::new(vec![]),
+            next_tags: RefCell::new(vec![]),
+            exported_variables: RefCell::new(vec![]),
+            started_up: RefCell::new(false),
+            queued_requests: RefCell::new(vec![]),
+            shutdown_pending: RefCell::new(None),
+            usage: RefCell::new(HashMap::new()),
+            waiting_tests: RefCell::new(vec![]),
+            deferred: RefCell::new(HashMap::new()),
+            standby: RefCell::new(false),
+            standing_by: RefCell::new(vec![]),
+            out_of_spec: RefCell::new(HashMap::new()),
+            advanced_at: RefCell::new(None),
+            scheduled: RefCell::new(HashMap::new()),
 
             control_sender: sender,
-        }
+        };
+
+        // Programs started before the first run still see the profile's variables.
+        manager.export_variables();
+        manager
     }
 
     /// Runs in a separate thread and consolidates control messages
     fn control_message_monitor(receiver: Receiver<ManagerControlMessage>, broadcaster: UnitBroadcaster) {
         while let Ok(msg) = receiver.recv() {
+            // Log lines are turned into log events here, rather than on the main thread, so that
+            // they stay in order with any results that the same unit reports afterwards.
+            let log = match msg.contents {
+                ManagerControlMessageContents::Log(ref txt) => Some(LogEntry::new_info(msg.sender.clone(), txt.clone())),
+                ManagerControlMessageContents::LogError(ref txt) => Some(LogEntry::new_error(msg.sender.clone(), txt.clone())),
+                _ => None,
+            };
             broadcaster.broadcast(&UnitEvent::ManagerRequest(msg));
+            if let Some(log) = log {
+                broadcaster.broadcast(&UnitEvent::Log(log));
+            }
         }
     }
 
@@ -263,14 +597,52 @@ impl UnitManager {
         self.control_sender.clone()
     }
 
+    pub fn broadcaster(&self) -> &UnitBroadcaster {
+        &self.bc
+    }
+
     pub fn load_interface(&self, description: &InterfaceDescription) -> Result<UnitName, UnitIncompatibleReason> {
+        // If only the Name or Description changed, there's no need to restart the interface.
+        let updated = match find(&self.interfaces, description.id()) {
+            Some(interface) => interface.try_borrow_mut().map(|mut i| i.update_metadata(description)).unwrap_or(false),
+            None => false,
+        };
+        if updated {
+            self.metadata_updated(description.id(), description.name(), description.description());
+            return Ok(description.id().clone());
+        }
         load!(self, interfaces, description)
     }
 
     pub fn load_logger(&self, description: &LoggerDescription) -> Result<UnitName, UnitIncompatibleReason> {
+        let updated = match find(&self.loggers, description.id()) {
+            Some(logger) => logger.try_borrow_mut().map(|mut l| l.update_metadata(description)).unwrap_or(false),
+            None => false,
+        };
+        if updated {
+            self.metadata_updated(description.id(), description.name(), description.description());
+            return Ok(description.id().clone());
+        }
         load!(self, loggers, description)
     }
 
+    /// Announce the new Name and Description of a unit that was updated without being restarted.
+    fn metadata_updated(&self, id: &UnitName, name: &String, description: &String) {
+        let messages = vec![
+            ManagerStatusMessage::Describe(id.clone(), FieldType::Name, name.clone()),
+            ManagerStatusMessage::Describe(id.clone(), FieldType::Description, description.clone()),
+        ];
+        for (interface_id, _) in self.interfaces.borrow().iter() {
+            self.send_messages_to(interface_id, messages.clone());
+        }
+
+        // The unit never stopped, so it ends up where it was before the update started.
+        self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_loaded(id)));
+        if self.active.borrow().contains_key(id) {
+            self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active(id)));
+        }
+    }
+
     pub fn load_test(&self, desceription: &TestDescription) -> Result<UnitName, UnitIncompatibleReason> {
         load!(self, tests, desceription)
     }
@@ -287,6 +659,71 @@ impl UnitManager {
         load!(self, triggers, desceription)
     }
 
+    pub fn load_limits(&self, desceription: &LimitsDescription) -> Result<UnitName, UnitIncompatibleReason> {
+        load!(self, limits, desceription)
+    }
+
+    pub fn load_resource(&self, desceription: &ResourceDescription) -> Result<UnitName, UnitIncompatibleReason> {
+        load!(self, resources, desceription)
+    }
+
+    pub fn load_custom(&self, description: &CustomDescription) -> Result<UnitName, UnitIncompatibleReason> {
+        load!(self, custom_units, description)
+    }
+
+    /// Whether a unit of any kind is loaded.
+    pub fn is_loaded(&self, id: &UnitName) -> bool {
+        match *id.kind() {
+            UnitKind::Interface => self.interfaces.borrow().contains_key(id),
+            UnitKind::Jig => self.jigs.borrow().contains_key(id),
+            UnitKind::Limits => self.limits.borrow().contains_key(id),
+            UnitKind::Logger => self.loggers.borrow().contains_key(id),
+            UnitKind::Resource => self.resources.borrow().contains_key(id),
+            UnitKind::Scenario => self.scenarios.borrow().contains_key(id),
+            UnitKind::Test => self.tests.borrow().contains_key(id),
+            UnitKind::Trigger => self.triggers.borrow().contains_key(id),
+            UnitKind::Custom(_) => self.custom_units.borrow().contains_key(id),
+            UnitKind::Internal => true,
+        }
+    }
+
+    /// Make sure that everything a unit requires is loaded, and that nothing it
+    /// depends on depends on it in turn.
+    fn check_requirements(&self, id: &UnitName, dependencies: &UnitDependencies) -> Result<(), UnitIncompatibleReason> {
+        if let Some(missing) = dependencies.requires.iter().find(|req| !self.is_loaded(req)) {
+            return Err(UnitIncompatibleReason::RequirementNotLoaded(missing.clone()));
+        }
+
+        let loaded = self.dependencies.borrow();
+        for dep in dependencies.all() {
+            let mut to_visit = vec![dep.clone()];
+            let mut visited = vec![];
+            while let Some(name) = to_visit.pop() {
+                if &name == id {
+                    return Err(UnitIncompatibleReason::DependencyError(DepError::CircularDependency(
+                        id.clone(),
+                        dep.clone(),
+                    )));
+                }
+                if let Some(deps) = loaded.get(&name) {
+                    to_visit.extend(deps.all().into_iter().filter(|d| !visited.contains(*d)).cloned());
+                }
+                visited.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Loaded units that have this one in their Requires=.
+    fn required_by(&self, id: &UnitName) -> Vec<UnitName> {
+        self.dependencies
+            .borrow()
+            .iter()
+            .filter(|&(_, deps)| deps.requires.contains(id))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     pub fn select(&self, id: &UnitName) {
         // Don't select already-selected units.
         if self.selected.borrow().contains_key(id) {
@@ -296,10 +733,14 @@ impl UnitManager {
         let result = match *id.kind() {
             UnitKind::Interface => self.select_interface(id),
             UnitKind::Jig => self.select_jig(id),
+            // Limits are only ever consulted, and resources are acquired by tests, so there's nothing to start.
+            UnitKind::Limits | UnitKind::Resource => Ok(()),
             UnitKind::Logger => self.select_logger(id),
             UnitKind::Scenario => self.select_scenario(id),
             UnitKind::Test => self.select_test(id),
             UnitKind::Trigger => self.select_trigger(id),
+            // Plugins are only asked to do anything once their units are activated.
+            UnitKind::Custom(_) => Ok(()),
             UnitKind::Internal => Ok(()),
         };
 
@@ -311,7 +752,7 @@ impl UnitManager {
             },
             Err(e) =>
                self.bc.broadcast(
-                    &UnitEvent::Status(UnitStatusEvent::new_select_failed(id, format!("{}", e)))),
+                    &UnitEvent::Status(UnitStatusEvent::new_select_failed(id, e.error_code(), format!("{}", e)))),
         }
     }
 
@@ -340,11 +781,12 @@ impl UnitManager {
         }
         
         // Select this scenario.
-        new_scenario.borrow_mut().select()?;
+        new_scenario.try_borrow()?.select()?;
         *self.current_scenario.borrow_mut() = Some(new_scenario.clone());
 
         // Now select every test associated with the scenario.
-        for test_id in &new_scenario.borrow().test_sequence() {
+        let test_sequence = new_scenario.try_borrow()?.test_sequence();
+        for test_id in test_sequence.iter() {
             self.select(test_id);
         }
         Ok(())
@@ -374,47 +816,49 @@ impl UnitManager {
         }
 
         // Select this jig.
-        new_jig.borrow_mut().select()?;
+        new_jig.try_borrow()?.select()?;
         *self.current_jig.borrow_mut() = Some(new_jig.clone());
 
-        // If this jig has a default scenario, select that too.
-        if let Some(ref scenario_name) = *new_jig.borrow().default_scenario() {
+        // If this jig has a default scenario, select that too, unless one was given on the command line.
+        let default_scenario = match self.configured_scenario() {
+            Some(scenario) => Some(scenario),
+            None => new_jig.try_borrow()?.default_scenario().clone(),
+        };
+        if let Some(ref scenario_name) = default_scenario {
             self.select(scenario_name);
         }
 
         Ok(())
     }
 
-    fn select_test(&self, id: &UnitName) -> Result<(), UnitSelectError> { 
-        match self.tests.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().select(self),
-            None => Err(UnitSelectError::UnitNotFound),
-        }
+    fn select_test(&self, id: &UnitName) -> Result<(), UnitSelectError> {
+        let test = find(&self.tests, id).ok_or(UnitSelectError::UnitNotFound)?;
+        let test = test.try_borrow()?;
+        test.select(self)
     }
 
     fn select_interface(&self, id: &UnitName) -> Result<(), UnitSelectError> {
-        match self.interfaces.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().select(),
-            None => Err(UnitSelectError::UnitNotFound),
-        }
+        let interface = find(&self.interfaces, id).ok_or(UnitSelectError::UnitNotFound)?;
+        let interface = interface.try_borrow()?;
+        interface.select()
     }
 
     fn select_trigger(&self, id: &UnitName) -> Result<(), UnitSelectError> {
-        match self.triggers.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().select(),
-            None => Err(UnitSelectError::UnitNotFound),
-        }
+        let trigger = find(&self.triggers, id).ok_or(UnitSelectError::UnitNotFound)?;
+        let trigger = trigger.try_borrow()?;
+        trigger.select()
     }
 
     fn select_logger(&self, id: &UnitName) -> Result<(), UnitSelectError> {
-        match self.loggers.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().select(),
-            None => Err(UnitSelectError::UnitNotFound),
-        }
+        let logger = find(&self.loggers, id).ok_or(UnitSelectError::UnitNotFound)?;
+        let logger = logger.try_borrow()?;
+        logger.select()
     }
 
     pub fn deselect(&self, id: &UnitName, reason: &str) {
         self.deactivate(id, "unit is being deselcted");
+        self.deferred.borrow_mut().remove(id);
+        self.standing_by.borrow_mut().retain(|s| s != id);
 
         // Don't deselect a unit that hasn't been selected.
         if ! self.selected.borrow().contains_key(id) {
@@ -428,6 +872,7 @@ impl UnitManager {
             &UnitKind::Internal => Ok(()),
             &UnitKind::Interface => self.deselect_interface(id),
             &UnitKind::Jig => self.deselect_jig(id),
+            &UnitKind::Limits | &UnitKind::Resource | &UnitKind::Custom(_) => Ok(()),
             &UnitKind::Logger => self.deselect_logger(id),
             &UnitKind::Scenario => self.deselect_scenario(id),
             &UnitKind::Test => self.deselect_test(id),
@@ -442,83 +887,81 @@ impl UnitManager {
     }
 
     fn deselect_test(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
-        match self.tests.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().deselect(),
-            None => Err(UnitDeselectError::UnitNotFound),
-        }
+        let test = find(&self.tests, id).ok_or(UnitDeselectError::UnitNotFound)?;
+        let test = test.try_borrow()?;
+        test.deselect()
     }
 
     fn deselect_interface(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
-        match self.interfaces.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().deselect(),
-            None => Err(UnitDeselectError::UnitNotFound),
-        }
+        let interface = find(&self.interfaces, id).ok_or(UnitDeselectError::UnitNotFound)?;
+        let interface = interface.try_borrow()?;
+        interface.deselect()
     }
 
     fn deselect_trigger(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
-        match self.triggers.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().deselect(),
-            None => Err(UnitDeselectError::UnitNotFound),
-        }
+        let trigger = find(&self.triggers, id).ok_or(UnitDeselectError::UnitNotFound)?;
+        let trigger = trigger.try_borrow()?;
+        trigger.deselect()
     }
 
     fn deselect_logger(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
-        match self.loggers.borrow().get(id) {
-            Some(ref s) => s.borrow_mut().deselect(),
-            None => Err(UnitDeselectError::UnitNotFound),
-        }
+        let logger = find(&self.loggers, id).ok_or(UnitDeselectError::UnitNotFound)?;
+        let logger = logger.try_borrow()?;
+        logger.deselect()
     }
 
     fn deselect_jig(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
         // If the specified jig isn't the current jig, then there's nothing to do.
-        let mut current_jig_opt = self.current_jig.borrow_mut();
-
-        let current_jig = match *current_jig_opt {
+        // Only hold on to the current jig for as long as it takes to check, because
+        // deselecting its default scenario may well come back around to it.
+        let current_jig = match self.current_jig.borrow().clone() {
             None => return Ok(()),
-            Some(ref s) => {
-                let current_jig = s.borrow();
-                if current_jig.id() != id {
-                    return Ok(());
-                }
-                s.clone()
-            }
+            Some(s) => s,
         };
+        if current_jig.try_borrow()?.id() != id {
+            return Ok(());
+        }
 
         // If there is a default scenario, make sure it's deselected.
-        if let Some(new_scenario_id) = current_jig.borrow().default_scenario().clone() {
+        let default_scenario = current_jig.try_borrow()?.default_scenario().clone();
+        if let Some(new_scenario_id) = default_scenario {
             self.deselect(&new_scenario_id, "jig is deselecting");
         }
 
-        current_jig.borrow_mut().deselect()?;
-        *current_jig_opt = None;
+        current_jig.try_borrow()?.deselect()?;
+        *self.current_jig.borrow_mut() = None;
         Ok(())
     }
 
     fn deselect_scenario(&self, id: &UnitName) -> Result<(), UnitDeselectError> {
         // If the specified scenario isn't the current scenario, then there's nothing to do.
-        match *self.current_scenario.borrow() {
+        let old_scenario = match self.current_scenario.borrow().clone() {
             None => return Ok(()),
-            Some(ref s) => {
-                let current_scenario = s.borrow();
-                if current_scenario.id() != id {
-                    return Ok(());
-                }
-            }
+            Some(s) => s,
+        };
+        if old_scenario.try_borrow()?.id() != id {
+            return Ok(());
         }
+        *self.current_scenario.borrow_mut() = None;
 
-        if let Some(ref old_scenario) = self.current_scenario.borrow_mut().take() {
-            // Deselect every test in this scenario first.
-            for test_id in &old_scenario.borrow().test_sequence() {
-                self.deselect(test_id, "scenario is deselecting");
-            }
-
-            // Deselect the actual scenario
-            old_scenario.borrow_mut().deselect()?;
+        // Deselect every test in this scenario first.
+        let test_sequence = old_scenario.try_borrow()?.test_sequence();
+        for test_id in test_sequence.iter() {
+            self.deselect(test_id, "scenario is deselecting");
         }
-        Ok(())
+
+        // Deselect the actual scenario
+        let result = old_scenario.try_borrow()?.deselect();
+        result
     }
 
     pub fn activate(&self, id: &UnitName) {
+        self.activate_unit(id, false)
+    }
+
+    /// Activate a unit.  Interfaces and loggers that only start when they're needed are
+    /// put aside until then, unless they're `needed` now.
+    fn activate_unit(&self, id: &UnitName, needed: bool) {
         self.select(id);
 
         // Don't activate a unit that is already active.
@@ -531,13 +974,66 @@ impl UnitManager {
             return;
         }
 
+        // Only the active instance of a hot-standby pair runs the jig and listens to triggers.
+        if *self.standby.borrow() && held_by_standby(id.kind()) {
+            let mut standing_by = self.standing_by.borrow_mut();
+            if !standing_by.contains(id) {
+                standing_by.push(id.clone());
+            }
+            return;
+        }
+
+        if needed {
+            self.deferred.borrow_mut().remove(id);
+        } else {
+            let activate_on = self.activate_on(id);
+            if activate_on != ActivateOn::Start {
+                if self.deferred.borrow_mut().insert(id.clone(), activate_on).is_none() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("waiting for the {} to start", activate_on))));
+                }
+                return;
+            }
+        }
+
+        // Start whatever this unit depends on first.  Tests and scenarios are
+        // only started by scenario runs, and only one jig is ever active, so
+        // those merely have to be loaded.  Units that would otherwise wait
+        // to be started are needed now.
+        let dependencies = self.dependencies.borrow().get(id).cloned().unwrap_or_default();
+        for dep in dependencies.all() {
+            if runs_continuously(dep.kind()) {
+                self.activate_unit(dep, true);
+            }
+        }
+        if let Some(req) = dependencies
+            .requires
+            .iter()
+            .find(|req| runs_continuously(req.kind()) && !self.active.borrow().contains_key(req))
+        {
+            self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active_failed(
+                id,
+                ErrorCode::new("requirement-not-active").with("unit", req),
+                format!("unable to activate: required unit {} is not active", req),
+            )));
+            return;
+        }
+
+        // Anything the unit's programs used before now doesn't count towards this time.
+        let started = Instant::now();
+        let meter = self.meter(id);
+        if let Some(ref meter) = meter {
+            meter.take();
+        }
+
         let result = match *id.kind() {
             UnitKind::Interface => self.activate_interface(id),
             UnitKind::Jig => self.activate_jig(id),
+            UnitKind::Limits | UnitKind::Resource => Ok(()),
             UnitKind::Logger => self.activate_logger(id),
             UnitKind::Scenario => self.activate_scenario(id),
             UnitKind::Test => self.activate_test(id),
             UnitKind::Trigger => self.activate_trigger(id),
+            UnitKind::Custom(_) => self.activate_custom(id),
             UnitKind::Internal => Ok(()),
         };
 
@@ -545,11 +1041,58 @@ impl UnitManager {
         match result {
             Ok(_) => {
                 self.active.borrow_mut().insert(id.clone(), ());
+                if let Some(meter) = meter {
+                    self.usage.borrow_mut().insert(id.clone(), (started, meter));
+                }
                 self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active(id)))
             },
             Err(e) =>
                self.bc.broadcast(
-                    &UnitEvent::Status(UnitStatusEvent::new_active_failed(id, format!("unable to activate: {}", e)))),
+                    &UnitEvent::Status(UnitStatusEvent::new_active_failed(id, e.error_code(), format!("unable to activate: {}", e)))),
+        }
+    }
+
+    /// When an interface or logger asked to be started.  Everything else starts as soon as it can.
+    fn activate_on(&self, id: &UnitName) -> ActivateOn {
+        let activate_on = match *id.kind() {
+            UnitKind::Interface => find(&self.interfaces, id).map(|i| i.borrow().activate_on()),
+            UnitKind::Logger => find(&self.loggers, id).map(|l| l.borrow().activate_on()),
+            _ => None,
+        };
+        activate_on.unwrap_or(ActivateOn::Start)
+    }
+
+    /// Start the units that were waiting for the first run.
+    fn start_deferred_for_run(&self) {
+        let waiting: Vec<UnitName> = self
+            .deferred
+            .borrow()
+            .iter()
+            .filter(|&(_, on)| *on == ActivateOn::FirstRun)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in waiting {
+            self.activate_unit(&id, true);
+        }
+    }
+
+    /// Start the units that were waiting for their first event, if `msg` is one they'd be sent.
+    fn start_deferred_for_event(&self, msg: &ManagerStatusMessage) {
+        let waiting: Vec<UnitName> = self
+            .deferred
+            .borrow()
+            .iter()
+            .filter(|&(_, on)| *on == ActivateOn::FirstEvent)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in waiting {
+            let wanted = match *id.kind() {
+                UnitKind::Logger => find(&self.loggers, &id).map(|l| l.borrow().wants(msg)).unwrap_or(false),
+                _ => true,
+            };
+            if wanted {
+                self.activate_unit(&id, true);
+            }
         }
     }
 
@@ -557,80 +1100,236 @@ impl UnitManager {
     /// For example, if there is no current Jig, activate the first Jig we find.
     /// Likewise, if there is no selected Scenario, select the first scenario we find.
     pub fn refresh_defaults(&self) {
-        // Activate a "random" available jig.
+        // Activate the configured default jig if it's available, or else a "random" one.
         if self.current_jig.borrow().is_none() && !self.jigs.borrow().is_empty() {
-            let new_jig_id = self.jigs.borrow().keys().next().unwrap().clone();
+            let default_jig = self.cfg.lock().unwrap().default_jig().clone();
+            let new_jig_id = match default_jig {
+                Some(ref jig) if self.jigs.borrow().contains_key(jig) => jig.clone(),
+                _ => self.jigs.borrow().keys().next().unwrap().clone(),
+            };
             self.activate(&new_jig_id);
         }
 
-        // If there is no current scenario, select a random one.
+        // If there is no current scenario, select the configured default, or a random one.
         if self.current_scenario.borrow().is_none() && !self.scenarios.borrow().is_empty() {
-            let new_scenario_id = self.scenarios.borrow().keys().next().unwrap().clone();
+            let new_scenario_id = match self.configured_scenario() {
+                Some(scenario) => scenario,
+                None => self.scenarios.borrow().keys().next().unwrap().clone(),
+            };
             self.select(&new_scenario_id);
         }
     }
 
+    /// Put new global settings in place without restarting anything.  Interfaces, loggers and
+    /// triggers that are already running get the new terminate timeout, and the default scenario is
+    /// selected unless a scenario is running.
+    pub fn config_changed(&self) {
+        let terminate_timeout = *self.cfg.lock().unwrap().terminate_timeout();
+        for interface in self.interfaces.borrow().values() {
+            if let Ok(mut interface) = interface.try_borrow_mut() {
+                interface.set_terminate_timeout(terminate_timeout);
+            }
+        }
+        for logger in self.loggers.borrow().values() {
+            if let Ok(mut logger) = logger.try_borrow_mut() {
+                logger.set_terminate_timeout(terminate_timeout);
+            }
+        }
+        for trigger in self.triggers.borrow().values() {
+            if let Ok(mut trigger) = trigger.try_borrow_mut() {
+                trigger.set_terminate_timeout(terminate_timeout);
+            }
+        }
+
+        if self.running_scenario().is_some() {
+            return;
+        }
+        self.export_variables();
+        if let Some(scenario) = self.configured_scenario() {
+            let current = self.current_scenario.borrow().as_ref().map(|s| s.borrow().id().clone());
+            if current.as_ref() != Some(&scenario) {
+                self.select(&scenario);
+                self.broadcast_selected_scenario();
+            }
+        }
+    }
+
+    /// Hand the jig and triggers over to the other instance of a hot-standby pair, or take them over
+    /// from it.  While standing by, they're selected as usual, but not started.
+    pub fn set_standby(&self, standby: bool) {
+        if self.standby.replace(standby) == standby {
+            return;
+        }
+        if standby {
+            // Triggers first, so that nothing new is started while the jig is stopping.
+            for kind in [UnitKind::Trigger, UnitKind::Jig].iter() {
+                let ids: Vec<UnitName> = self.active.borrow().keys().filter(|id| id.kind() == kind).cloned().collect();
+                for id in ids {
+                    self.deactivate(&id, "standing by");
+                    self.standing_by.borrow_mut().push(id);
+                }
+            }
+        } else {
+            for id in self.standing_by.replace(vec![]) {
+                self.activate(&id);
+            }
+        }
+    }
+
+    /// The configured default scenario, if there is one and it's loaded.
+    fn configured_scenario(&self) -> Option<UnitName> {
+        let default_scenario = self.cfg.lock().unwrap().default_scenario().clone();
+        default_scenario.filter(|scenario| self.scenarios.borrow().contains_key(scenario))
+    }
+
     fn activate_interface(&self, id: &UnitName) -> Result<(), UnitActivateError> {
         // Activate the interface, which actually starts it up.
-        match self.interfaces.borrow().get(id) {
-            Some(i) => i.borrow_mut().activate(self, &*self.cfg.lock().unwrap()),
-            None => return Err(UnitActivateError::UnitNotFound),
-        }
+        let interface = find(&self.interfaces, id).ok_or(UnitActivateError::UnitNotFound)?;
+        let interface = interface.try_borrow()?;
+        interface.activate(self, &*self.cfg.lock().unwrap())
     }
 
     fn activate_trigger(&self, id: &UnitName) -> Result<(), UnitActivateError> {
-        // Activate the interface, which actually starts it up.
-        match self.triggers.borrow().get(id) {
-            Some(i) => i.borrow_mut().activate(self, &*self.cfg.lock().unwrap()),
-            None => return Err(UnitActivateError::UnitNotFound),
-        }
+        let trigger = find(&self.triggers, id).ok_or(UnitActivateError::UnitNotFound)?;
+        let trigger = trigger.try_borrow()?;
+        trigger.activate(self, &*self.cfg.lock().unwrap())
+    }
+
+    fn activate_custom(&self, id: &UnitName) -> Result<(), UnitActivateError> {
+        let unit = find(&self.custom_units, id).ok_or(UnitActivateError::UnitNotFound)?;
+        let unit = unit.try_borrow()?;
+        unit.activate()
     }
 
     fn activate_logger(&self, id: &UnitName) -> Result<(), UnitActivateError> {
-        // Activate the interface, which actually starts it up.
-        match self.loggers.borrow().get(id) {
-            Some(i) => i.borrow_mut().activate(self, &*self.cfg.lock().unwrap()),
-            None => return Err(UnitActivateError::UnitNotFound),
-        }
+        let logger = find(&self.loggers, id).ok_or(UnitActivateError::UnitNotFound)?;
+        let logger = logger.try_borrow()?;
+        logger.activate(self, &*self.cfg.lock().unwrap())
     }
 
     /// Set the new jig as "Active".
     /// The jig must already be set as the current jig.
     fn activate_jig(&self, id: &UnitName) -> Result<(), UnitActivateError> {
-        let current_jig_opt = self.current_jig.borrow();
-
-        match *current_jig_opt {
-            None => Err(UnitActivateError::UnitNotSelected),
-            Some(ref s) => if s.borrow_mut().id() != id {
-                Err(UnitActivateError::UnitNotSelected)
-            } else {
-                // Activate this jig.
-                s.borrow_mut().activate(self, &*self.cfg.lock().unwrap())
-            }
+        let jig = self.current_jig.borrow().clone().ok_or(UnitActivateError::UnitNotSelected)?;
+        let mut jig = jig.try_borrow_mut()?;
+        if jig.id() != id {
+            return Err(UnitActivateError::UnitNotSelected);
         }
+
+        // Activate this jig.
+        jig.activate(self, &*self.cfg.lock().unwrap())
     }
 
     /// Set the specified scenario as "Active".
     /// This actually runs the scenario.
     fn activate_scenario(&self, id: &UnitName) -> Result<(), UnitActivateError> {
-        let current_opt = self.current_scenario.borrow();
+        let scenario = self.current_scenario.borrow().clone().ok_or(UnitActivateError::UnitNotSelected)?;
+        let mut scenario = scenario.try_borrow_mut()?;
+        if scenario.id() != id {
+            return Err(UnitActivateError::UnitNotSelected);
+        }
 
-        match *current_opt {
-            None => Err(UnitActivateError::UnitNotSelected),
-            Some(ref s) => if s.borrow().id() != id {
-                Err(UnitActivateError::UnitNotSelected)
-            } else {
-                // Activate this scenario.
-                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("scenario starting"))));
-                s.borrow_mut().activate(self, &*self.cfg.lock().unwrap())
+        // Activate this scenario.
+        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("scenario starting"))));
+        scenario.activate(self, &*self.cfg.lock().unwrap())
+    }
+
+    fn activate_test(&self, id: &UnitName) -> Result<(), UnitActivateError> {
+        let scheduled = self.scheduled.borrow_mut().remove(id);
+        let test = find(&self.tests, id).ok_or(UnitActivateError::UnitNotFound)?;
+        let resources = test.try_borrow()?.resources().clone();
+        self.acquire_resources(id, &resources)?;
+        let mut test = test.try_borrow_mut()?;
+        test.activate(self, &*self.cfg.lock().unwrap())?;
+        self.bc.timed(TimingKind::TestScheduling, id, scheduled);
+        Ok(())
+    }
+
+    /// Start a test, unless MaxTests are running already, in which case it waits for one of them
+    /// to stop.  Daemons only stop when their scenario does, so if every test that's running is
+    /// a daemon, waiting would stall the scenario, and the test fails without being run instead.
+    fn start_test(&self, id: &UnitName) {
+        let max_tests = self.cfg.lock().unwrap().max_tests();
+        let max_tests = match max_tests {
+            None => return self.activate(id),
+            Some(m) => m,
+        };
+        let running: Vec<UnitName> = self.active.borrow().keys().filter(|t| *t.kind() == UnitKind::Test).cloned().collect();
+        if running.len() < max_tests {
+            return self.activate(id);
+        }
+
+        let all_daemons = running
+            .iter()
+            .all(|t| find(&self.tests, t).map(|test| test.borrow().is_daemon()).unwrap_or(false));
+        if !all_daemons {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("waiting to start: {} of {} tests are running", running.len(), max_tests))));
+            self.waiting_tests.borrow_mut().push(id.clone());
+            return;
+        }
+
+        let message = format!("unable to start: {} daemons are running, and MaxTests is {}", running.len(), max_tests);
+        for contents in vec![
+            ManagerControlMessageContents::TestStarted,
+            ManagerControlMessageContents::LogError(message.clone()),
+            ManagerControlMessageContents::TestFinished(TOO_MANY_TESTS, message.clone()),
+            ManagerControlMessageContents::AdvanceScenario(TOO_MANY_TESTS),
+        ] {
+            self.control_sender.send(ManagerControlMessage::new(id, contents)).ok();
+        }
+    }
+
+    /// Start the test that has been waiting longest, now that one of the tests that were running has stopped.
+    fn start_waiting_test(&self) {
+        let next = {
+            let mut waiting = self.waiting_tests.borrow_mut();
+            if waiting.is_empty() {
+                return;
+            }
+            waiting.remove(0)
+        };
+        self.start_test(&next);
+    }
+
+    /// Acquire the resources a test uses before it starts.  If one of them can't be had, the
+    /// ones already acquired are released, and the test fails without being run.
+    fn acquire_resources(&self, test: &UnitName, resources: &[UnitName]) -> Result<(), UnitActivateError> {
+        for resource_id in resources {
+            let result = match find(&self.resources, resource_id) {
+                None => Err("not loaded".to_owned()),
+                Some(resource) => {
+                    let resource = resource.borrow();
+                    resource.acquire(test, &*self.cfg.lock().unwrap())
+                }
+            };
+            if let Err(reason) = result {
+                self.release_resources(test);
+                let message = format!("unable to acquire resource {}: {}", resource_id, reason);
+                for contents in vec![
+                    ManagerControlMessageContents::TestStarted,
+                    ManagerControlMessageContents::LogError(message.clone()),
+                    ManagerControlMessageContents::TestFinished(RESOURCE_UNAVAILABLE, message.clone()),
+                    ManagerControlMessageContents::AdvanceScenario(RESOURCE_UNAVAILABLE),
+                ] {
+                    self.control_sender.send(ManagerControlMessage::new(test, contents)).ok();
+                }
+                return Err(UnitActivateError::ResourceUnavailable(resource_id.clone(), reason));
             }
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(test.clone(), format!("acquired resource {}", resource_id))));
         }
+        Ok(())
     }
 
-    fn activate_test(&self, id: &UnitName) -> Result<(), UnitActivateError> {
-        match self.tests.borrow().get(id) {
-            None => Err(UnitActivateError::UnitNotFound),
-            Some(ref s) => s.borrow_mut().activate(self, &*self.cfg.lock().unwrap()),
+    /// Release every resource a test holds, closing the ones that nothing else is using.
+    fn release_resources(&self, test: &UnitName) {
+        let resources: Vec<Rc<RefCell<Resource>>> = self.resources.borrow().values().cloned().collect();
+        for resource in resources {
+            let resource = resource.borrow();
+            match resource.release(test, &*self.cfg.lock().unwrap()) {
+                Ok(true) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(test.clone(), format!("released resource {}", resource.id())))),
+                Ok(false) => (),
+                Err(e) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(resource.id().clone(), format!("unable to close resource: {}", e)))),
+            }
         }
     }
 
@@ -644,105 +1343,223 @@ impl UnitManager {
         let result = match *id.kind() {
             UnitKind::Interface => self.deactivate_interface(id),
             UnitKind::Jig => self.deactivate_jig(id),
+            UnitKind::Limits | UnitKind::Resource => Ok(()),
             UnitKind::Logger => self.deactivate_logger(id),
             UnitKind::Scenario => self.deactivate_scenario(id),
             UnitKind::Test => self.deactivate_test(id),
             UnitKind::Trigger => self.deactivate_trigger(id),
+            UnitKind::Custom(_) => self.deactivate_custom(id),
             UnitKind::Internal => Ok(()),
         };
         match result {
             Ok(_) => {
                 self.active.borrow_mut().remove(id);
-                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_deactivate_success(id, reason.to_owned())))
+                self.record_usage(id);
+                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_deactivate_success(id, reason.to_owned())));
+                if *id.kind() == UnitKind::Test {
+                    self.start_waiting_test();
+                }
+
+                // Anything that requires this unit can't keep running without it.
+                for dependent in self.required_by(id) {
+                    self.deactivate(&dependent, &format!("required unit {} stopped: {}", id, reason));
+                }
             },
             Err(e) =>
                 self.bc.broadcast(
-                        &UnitEvent::Status(UnitStatusEvent::new_deactivate_failure(id, format!("unable to deactivate: {}", e)))),
+                        &UnitEvent::Status(UnitStatusEvent::new_deactivate_failure(id, e.error_code(), format!("unable to deactivate: {}", e)))),
         }
     }
 
-    fn deactivate_interface(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let interfaces = self.interfaces.borrow();
-        match interfaces.get(id) {
-            None => return Err(UnitDeactivateError::UnitNotFound),
-            Some(interface) => interface.borrow_mut().deactivate(),
+    /// Stop every unit, then let everything else know that exclave is shutting down.
+    fn shutdown(&self, reason: &str) {
+        self.stop_all(reason);
+        self.bc.broadcast(&UnitEvent::Shutdown);
+    }
+
+    /// Deactivate every active unit of one kind.
+    fn deactivate_kind(&self, kind: &UnitKind, reason: &str) {
+        let ids: Vec<UnitName> = self.active
+            .borrow()
+            .keys()
+            .filter(|id| id.kind() == kind)
+            .cloned()
+            .collect();
+        for id in ids {
+            self.deactivate(&id, reason);
         }
     }
 
-    fn deactivate_trigger(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let triggers = self.triggers.borrow();
-        match triggers.get(id) {
-            None => return Err(UnitDeactivateError::UnitNotFound),
-            Some(trigger) => trigger.borrow_mut().deactivate(),
+    /// Stop everything but the loggers ahead of a shutdown, so that no programs are left
+    /// running behind.  Triggers go first, so that nothing new is started, then the rest
+    /// in the reverse of the order they're started in: scenarios and tests, interfaces,
+    /// and the jig.  Loggers are stopped once the shutdown itself is processed, so that
+    /// they get to record all of this.
+    fn stop_all(&self, reason: &str) {
+        for kind in [UnitKind::Trigger, UnitKind::Scenario, UnitKind::Test, UnitKind::Interface, UnitKind::Jig].iter() {
+            self.deactivate_kind(kind, reason);
+        }
+        let resources: Vec<Rc<RefCell<Resource>>> = self.resources.borrow().values().cloned().collect();
+        for resource in resources {
+            let resource = resource.borrow();
+            if let Err(e) = resource.close(&*self.cfg.lock().unwrap()) {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(resource.id().clone(), format!("unable to close resource: {}", e))));
+            }
         }
     }
 
+    fn deactivate_interface(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
+        let interface = find(&self.interfaces, id).ok_or(UnitDeactivateError::UnitNotFound)?;
+        let interface = interface.try_borrow()?;
+        interface.deactivate()
+    }
+
+    fn deactivate_trigger(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
+        let trigger = find(&self.triggers, id).ok_or(UnitDeactivateError::UnitNotFound)?;
+        let trigger = trigger.try_borrow()?;
+        trigger.deactivate()
+    }
+
     fn deactivate_logger(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let loggers = self.loggers.borrow();
-        match loggers.get(id) {
-            None => return Err(UnitDeactivateError::UnitNotFound),
-            Some(interface) => interface.borrow_mut().deactivate(),
-        }
+        let logger = find(&self.loggers, id).ok_or(UnitDeactivateError::UnitNotFound)?;
+        let logger = logger.try_borrow()?;
+        logger.deactivate()
+    }
+
+    fn deactivate_custom(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
+        let unit = find(&self.custom_units, id).ok_or(UnitDeactivateError::UnitNotFound)?;
+        let unit = unit.try_borrow()?;
+        unit.deactivate()
     }
 
     fn deactivate_test(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let tests = self.tests.borrow();
-        match tests.get(id) {
-            None => return Err(UnitDeactivateError::UnitNotFound),
-            Some(test) => test.borrow_mut().deactivate(self),
-        }
+        let test = find(&self.tests, id).ok_or(UnitDeactivateError::UnitNotFound)?;
+        let test = test.try_borrow()?;
+        test.deactivate(self)
     }
 
     fn deactivate_scenario(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let current_scenario_opt = self.current_scenario.borrow_mut();
-
         // If the specified scenario isn't the current scenario, then there's nothing to do.
-        match *current_scenario_opt {
-            None => Ok(()),
-            Some(ref s) => {
-                let current_scenario = s.borrow_mut();
-                if current_scenario.id() != id {
-                    Ok(())
-                }
-                else {
-                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("scenario ended"))));
-                    current_scenario.deactivate()
-                }
-            }
+        let scenario = match self.current_scenario.borrow().clone() {
+            None => return Ok(()),
+            Some(s) => s,
+        };
+        let scenario = scenario.try_borrow()?;
+        if scenario.id() != id {
+            return Ok(());
         }
+        // None of its tests are started once it's over.
+        self.waiting_tests.borrow_mut().clear();
+        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), format!("scenario ended"))));
+        scenario.deactivate()
     }
 
     fn deactivate_jig(&self, id: &UnitName) -> Result<(), UnitDeactivateError> {
-        let current_opt = self.current_jig.borrow_mut();
-
         // If the specified jig isn't the current jig, then there's nothing to do.
-        match *current_opt {
-            None => Ok(()),
-            Some(ref s) => {
-                let current = s.borrow_mut();
-                if current.id() != id {
-                    Ok(())
-                }
-                else {
-                    current.deactivate()
-                }
-            }
+        let jig = match self.current_jig.borrow().clone() {
+            None => return Ok(()),
+            Some(s) => s,
+        };
+        let jig = jig.try_borrow()?;
+        if jig.id() != id {
+            return Ok(());
         }
+        jig.deactivate()
     }
 
     pub fn unload(&self, id: &UnitName) {
         self.deselect(id, "unloading");
+        self.dependencies.borrow_mut().remove(id);
         match *id.kind() {
             UnitKind::Interface => self.unload_interface(id),
             UnitKind::Jig => self.unload_jig(id),
+            UnitKind::Limits => self.unload_limits(id),
+            UnitKind::Resource => self.unload_resource(id),
             UnitKind::Logger => self.unload_logger(id),
             UnitKind::Scenario => self.unload_scenario(id),
             UnitKind::Test => self.unload_test(id),
             UnitKind::Trigger => self.unload_trigger(id),
+            UnitKind::Custom(_) => self.unload_custom(id),
             UnitKind::Internal => (),
         }
     }
     
+    /// Bring a crashed logger back up, or deactivate it if it's not coming back.
+    /// `panicked` is why, if it was one of its threads that failed rather than its program.
+    fn recover_logger(&self, id: &UnitName, panicked: Option<&str>) {
+        if ! self.active.borrow().contains_key(id) {
+            return;
+        }
+        let result = match find(&self.loggers, id) {
+            None => return,
+            Some(logger) => match logger.try_borrow() {
+                Ok(logger) => match panicked {
+                    Some(reason) => logger.restart_after_failure(None, reason),
+                    None => logger.recover(),
+                },
+                // Whatever is using the logger will find out if it's gone.
+                Err(_) => return,
+            },
+        };
+        if let Err(e) = result {
+            self.deactivate(id, &format!("{}", e));
+        }
+    }
+
+    /// Stop an interface or trigger whose program has exited with `code`, or which failed
+    /// some other way, so that anything it started goes with it, and start it again if its
+    /// Restart= policy allows it.
+    fn recover_program(&self, id: &UnitName, code: Option<i32>, reason: &str) {
+        if ! self.active.borrow().contains_key(id) {
+            return;
+        }
+        let result = match *id.kind() {
+            UnitKind::Interface => match find(&self.interfaces, id) {
+                None => return,
+                Some(interface) => match interface.try_borrow() {
+                    Ok(interface) => interface.restart_after_failure(code, reason),
+                    Err(_) => return,
+                },
+            },
+            UnitKind::Trigger => match find(&self.triggers, id) {
+                None => return,
+                Some(trigger) => match trigger.try_borrow() {
+                    Ok(trigger) => trigger.restart_after_failure(code, reason),
+                    Err(_) => return,
+                },
+            },
+            _ => return,
+        };
+        match result {
+            Ok(()) => {
+                self.deactivate(id, &format!("restarting after it {}", reason));
+                self.activate(id);
+            }
+            Err(reason) => self.deactivate(id, &reason),
+        }
+    }
+
+    /// A test or scenario command whose thread panicked won't ever say that it's finished, so
+    /// fail it on its behalf if the running scenario is waiting on it, rather than leave the run stuck.
+    fn fail_step(&self, id: &UnitName) {
+        let waiting = match *self.current_scenario.borrow() {
+            Some(ref scenario) => match scenario.try_borrow() {
+                Ok(scenario) => scenario.current_step().as_ref() == Some(id),
+                Err(_) => false,
+            },
+            None => false,
+        };
+        if !waiting {
+            return;
+        }
+        if let Some(test) = find(&self.tests, id) {
+            if let Ok(test) = test.try_borrow() {
+                test.finish(&self.control_sender, -1);
+            }
+        }
+        self.control_sender.send(ManagerControlMessage::new(id, ManagerControlMessageContents::AdvanceScenario(-1))).ok();
+    }
+
     fn unload_interface(&self, id: &UnitName) {
         self.deactivate(id, "interface is being unloaded");
         self.deselect(id, "interface is being unloaded");
@@ -764,6 +1581,13 @@ impl UnitManager {
         self.loggers.borrow_mut().remove(id);
     }
 
+    fn unload_custom(&self, id: &UnitName) {
+        self.deactivate(id, "unit is being unloaded");
+        self.deselect(id, "unit is being unloaded");
+
+        self.custom_units.borrow_mut().remove(id);
+    }
+
     fn unload_jig(&self, id: &UnitName) {
         self.deactivate(id, "jig is being unloaded");
         self.deselect(id, "jig is being unloaded");
@@ -771,6 +1595,19 @@ impl UnitManager {
         self.jigs.borrow_mut().remove(id);
     }
 
+    fn unload_limits(&self, id: &UnitName) {
+        self.limits.borrow_mut().remove(id);
+    }
+
+    fn unload_resource(&self, id: &UnitName) {
+        let resource = self.resources.borrow_mut().remove(id);
+        if let Some(resource) = resource {
+            if let Err(e) = resource.borrow().close(&*self.cfg.lock().unwrap()) {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id.clone(), format!("unable to close resource: {}", e))));
+            }
+        }
+    }
+
     fn unload_test(&self, id: &UnitName) {
         self.deactivate(id, "test is being unloaded");
         self.deselect(id, "test is being unloaded");
@@ -800,12 +1637,33 @@ impl UnitManager {
         }
     }
 
+    /// The limits a measurement that `test` reports is checked against: the test's own, if it
+    /// has one for it, or else every one that the loaded Limits units have for it.
+    pub fn limits_for(&self, test: &UnitName, name: &str, variables: &[(String, String)]) -> Vec<Limit> {
+        if let Some(t) = self.get_test_named(test) {
+            if let Some(limit) = limits::test_limit(t.borrow().limits(), name, variables) {
+                return vec![limit.clone()];
+            }
+        }
+        self.limits
+            .borrow()
+            .values()
+            .filter_map(|limits| limits.borrow().limit_for(test, name, variables).cloned())
+            .collect()
+    }
+
     pub fn get_tests(&self) -> Rc<RefCell<HashMap<UnitName, Rc<RefCell<Test>>>>> {
         self.tests.clone()
     }
 
-    pub fn get_scenarios(&self) -> Rc<RefCell<HashMap<UnitName, Rc<RefCell<Scenario>>>>> {
-        self.scenarios.clone()
+    /// The scenarios that run a test, whether they name it or it's pulled in by another test.
+    pub fn scenarios_using_test(&self, test: &UnitName) -> Vec<UnitName> {
+        self.scenarios
+            .borrow()
+            .iter()
+            .filter(|&(_, scenario)| scenario.borrow().uses_test(test))
+            .map(|(name, _)| name.clone())
+            .collect()
     }
 
      pub fn jig_is_loaded(&self, id: &UnitName) -> bool {
@@ -817,6 +1675,10 @@ impl UnitManager {
             &UnitEvent::ManagerRequest(ref req) => self.manager_request(req),
             &UnitEvent::Status(ref stat) => self.status_message(stat),
             &UnitEvent::Log(ref log) => {
+                self.note_reported_serial(log);
+                self.note_reported_tag(log);
+                self.note_artifact(log);
+                let log = &log.clone().with_serial(self.serial.borrow().clone());
                 let mut units_to_deactivate = vec![];
                 for (interface_id, interface) in self.interfaces.borrow().iter() {
                     let log_status_msg = ManagerStatusMessage::Log(log.clone());
@@ -830,16 +1692,68 @@ impl UnitManager {
                         units_to_deactivate.push((logger_id.clone(), format!("unable to send message to logger: {:?}", e)));
                     }
                 }
+                for unit in self.custom_units.borrow().values() {
+                    unit.borrow().output_message(&ManagerStatusMessage::Log(log.clone()));
+                }
                 for (unit_id, reason) in units_to_deactivate {
                     self.deactivate(&unit_id, &reason);
                 }
+                self.record_run_log(log);
+                self.check_limits(log);
+                self.note_golden_measurement(log);
             },
+            &UnitEvent::RescanFinish => self.rescan_finished(),
+            // Everything logged while the other units were stopping has been passed on by now.
+            &UnitEvent::Shutdown => {
+                self.deactivate_kind(&UnitKind::Logger, "shutting down");
+                let custom_units: Vec<UnitName> = self.custom_units.borrow().keys().cloned().collect();
+                for id in custom_units {
+                    self.deactivate(&id, "shutting down");
+                }
+            }
             _ => (),
         }
     }
 
+    /// Once the units have been loaded at startup, start the default scenario if the station
+    /// is configured to do so, as if it had been asked to by an interface.  Triggers are
+    /// active by then, so if the scenario can't start yet they'll still start it later.
+    fn rescan_finished(&self) {
+        // Results queued by a previous instance are delivered as soon as exclave is up.
+        self.update_mes();
+        self.update_uploader();
+        if self.started_up.replace(true) {
+            return;
+        }
+        // The operator decides whether to carry on with an interrupted run before anything else is started.
+        if self.recover_interrupted_run() {
+            return;
+        }
+        let (auto_start, scenario) = {
+            let config = self.cfg.lock().unwrap();
+            (config.auto_start(), config.default_scenario().clone())
+        };
+        if !auto_start {
+            return;
+        }
+        let id = UnitName::internal("autostart");
+        let contents = ManagerControlMessageContents::StartScenario(scenario);
+        self.bc.broadcast(&UnitEvent::ManagerRequest(ManagerControlMessage::new(&id, contents)));
+    }
+
     fn status_message(&self, msg: &UnitStatusEvent) {
         let &UnitStatusEvent {ref name, ref status} = msg;
+        match status {
+            &UnitStatus::Removed(_) => self.statuses.borrow_mut().remove(name),
+            _ => self.statuses.borrow_mut().insert(name.clone(), status.clone()),
+        };
+        if let Some((stage, reason)) = status.stage() {
+            self.transition(name, stage, reason, format!("{}", status), status.error().cloned());
+        }
+        // Frontends can act on the code, rather than on the message.
+        if let Some(error) = status.error() {
+            self.broadcast_message(ManagerStatusMessage::Describe(name.clone(), FieldType::ErrorCode, error.to_string()));
+        }
         match status {
             &UnitStatus::Loaded => match name.kind() {
                 &UnitKind::Jig => self.broadcast_jig_named(name),
@@ -852,18 +1766,60 @@ impl UnitManager {
                 &UnitKind::Scenario => self.broadcast_selected_scenario(),
                 _ => (),
             },
+            // Let interfaces show unit authors what's wrong with their units.
+            &UnitStatus::LoadFailed(_, ref reason)
+            | &UnitStatus::Incompatible(_, ref reason)
+            | &UnitStatus::Degraded(_, ref reason) => {
+                self.broadcast_message(ManagerStatusMessage::Describe(name.clone(), FieldType::Error, reason.clone()))
+            }
             _ => (),
         }
     }
 
+    /// Record that a unit has reached a new stage of its lifecycle, and tell interfaces and loggers.
+    fn transition(&self, name: &UnitName, stage: UnitStage, reason: &str, message: String, error: Option<ErrorCode>) {
+        let mut from = match stage {
+            UnitStage::Removed => self.stages.borrow_mut().remove(name),
+            _ => self.stages.borrow_mut().insert(name.clone(), stage),
+        };
+
+        // Units are only checked for compatibility once their file has been parsed successfully.
+        if from == Some(UnitStage::Discovered) && (stage == UnitStage::Compatible || stage == UnitStage::Incompatible) {
+            self.broadcast_message(ManagerStatusMessage::Transition(UnitTransition::new(
+                name,
+                from,
+                UnitStage::Parsed,
+                "parsed",
+                String::new(),
+                None,
+            )));
+            from = Some(UnitStage::Parsed);
+        }
+        self.broadcast_message(ManagerStatusMessage::Transition(UnitTransition::new(name, from, stage, reason, message, error)));
+    }
+
     fn manager_request(&self, msg: &ManagerControlMessage) {
-        let &ManagerControlMessage {sender: ref sender_name, contents: ref msg} = msg;
+        if let Some(entry) = AuditEntry::from_control(msg, &self.operator.borrow()) {
+            self.broadcast_audit(entry);
+        }
+        self.handle_request(msg);
+    }
+
+    fn handle_request(&self, request: &ManagerControlMessage) {
+        let &ManagerControlMessage {sender: ref sender_name, contents: ref msg} = request;
 
         match *msg {
             ManagerControlMessageContents::Scenarios => self.send_scenarios_to(sender_name),
             ManagerControlMessageContents::Tests(ref scenario_name) => self.send_tests_to(sender_name, scenario_name),
-            ManagerControlMessageContents::Log(ref txt) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), txt.clone()))),
-            ManagerControlMessageContents::LogError(ref txt) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), txt.clone()))),
+            ManagerControlMessageContents::Status(ref filter) => self.send_statuses_to(sender_name, filter),
+            ManagerControlMessageContents::Yield => {
+                let messages = self.yield_windows().into_iter().map(ManagerStatusMessage::Yield).collect();
+                self.send_messages_to(sender_name, messages);
+            }
+            ManagerControlMessageContents::Artifacts(ref run) => self.send_artifacts_to(sender_name, run),
+            ManagerControlMessageContents::Artifact(ref run, ref path) => self.send_artifact_data_to(sender_name, run, path),
+            // The control message monitor has already broadcast these as log events.
+            ManagerControlMessageContents::Log(_) | ManagerControlMessageContents::LogError(_) => (),
             ManagerControlMessageContents::Scenario(ref new_scenario_name) => {
                 if self.get_scenario_named(new_scenario_name).is_some() {
                     self.select(new_scenario_name);
@@ -877,34 +1833,96 @@ impl UnitManager {
             },
             ManagerControlMessageContents::Jig => self.send_jig_to(sender_name),
             ManagerControlMessageContents::InitialGreeting => {
-                // Send some initial information to the client.
-                self.send_hello_to(sender_name);
-                self.send_jig_to(sender_name);
-                self.send_scenarios_to(sender_name);
+                // Send some initial information to the client, all in one go, as there can be
+                // hundreds of messages on a station with a large unit library.
+                let mut messages = self.hello_messages();
+                messages.extend(self.jig_messages());
+                messages.extend(self.scenarios_messages());
                 // If there is a scenario selected, send that too.
-                if let Some(ref sc) = *self.current_scenario.borrow() {
-                    self.send_scenario_to(sender_name, &sc.borrow().id().clone());
+                let current_scenario = self.current_scenario.borrow().as_ref().map(|sc| sc.borrow().id().clone());
+                if let Some(ref scenario_id) = current_scenario {
+                    messages.extend(self.scenario_messages(scenario_id));
                 }
+                if let Some(ref journal) = *self.interrupted.borrow() {
+                    messages.push(interrupted_message(journal));
+                }
+                messages.push(ManagerStatusMessage::Operator(self.operator.borrow().clone()));
+                self.send_messages_to(sender_name, messages);
             },
             ManagerControlMessageContents::ChildExited => {
-                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active_failed(sender_name, "Unit unexpectedly exited".to_owned())));
+                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active_failed(sender_name, ErrorCode::new("unexpected-exit"), "Unit unexpectedly exited".to_owned())));
+            },
+            ManagerControlMessageContents::UnexpectedExit(code) => {
+                let error = ErrorCode::new("unexpected-exit").with("code", code);
+                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active_failed(sender_name, error, format!("unit unexpectedly exited with code {}", code))));
+                match *sender_name.kind() {
+                    UnitKind::Logger => self.recover_logger(sender_name, None),
+                    UnitKind::Interface | UnitKind::Trigger => {
+                        self.recover_program(sender_name, Some(code), &format!("exited with code {}", code))
+                    }
+                    _ => (),
+                }
+            },
+            ManagerControlMessageContents::Panicked(ref message) => {
+                let reason = format!("panicked: {}", message);
+                self.bc.broadcast(&UnitEvent::Status(UnitStatusEvent::new_active_failed(sender_name, ErrorCode::new("panicked"), format!("unit {}", reason))));
+                match *sender_name.kind() {
+                    UnitKind::Logger => self.recover_logger(sender_name, Some(&reason)),
+                    UnitKind::Interface | UnitKind::Trigger => self.recover_program(sender_name, None, &reason),
+                    UnitKind::Test | UnitKind::Scenario => self.fail_step(sender_name),
+                    _ => (),
+                }
             },
             ManagerControlMessageContents::AdvanceScenario(result) => {
-                match *self.current_scenario.borrow() {
-                    None => (),
-                    Some(ref current_scenario) => current_scenario.borrow_mut().advance(sender_name, result, &self.control_sender),
+                let result = match result {
+                    0 if self.out_of_spec.borrow().contains_key(sender_name) => OUT_OF_SPEC,
+                    r => r,
+                };
+                let current_scenario = self.current_scenario.borrow().clone();
+                if let Some(current_scenario) = current_scenario {
+                    *self.advanced_at.borrow_mut() = self.bc.timer();
+                    match current_scenario.try_borrow() {
+                        Ok(scenario) => scenario.advance(sender_name, result, &self.control_sender),
+                        Err(_) => self.bc.log("manager", format!("Unable to advance scenario for {}: scenario is busy", sender_name)),
+                    }
                 }
             },
             ManagerControlMessageContents::Unimplemented(ref verb, ref remainder) => {
                 self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unimplemented verb: {} (args: {})", verb, remainder))));
             },
             ManagerControlMessageContents::StartScenario(ref scenario_name_opt) => {
+                // The replayed run's messages would be mixed up with the real one's.
+                if self.cfg.lock().unwrap().replaying() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to start scenario: a recorded run is being replayed".to_owned())));
+                    return;
+                }
+                // The jig belongs to the active instance.
+                if *self.standby.borrow() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to start scenario: this instance is standing by".to_owned())));
+                    return;
+                }
+
+                // Inputs to a combination trigger only start a scenario by way of it.
+                if let Some(combination) = self.combination_for(sender_name) {
+                    self.combine(&combination, sender_name);
+                    return;
+                }
+
+                if let Some(reason) = self.jig_unfit_for(sender_name) {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unable to start scenario: {}", reason))));
+                    return;
+                }
+                if let Some(remaining) = self.cooldown_remaining(sender_name) {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("ignoring start request: cooling down for another {}ms", remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis())))));
+                    return;
+                }
+
                 // If a scenario exists and is running, don't start a new one.
-                if let Some(ref scenario) = *self.current_scenario.borrow() {
-                    if scenario.borrow().is_running() {
+                if let Some(running) = self.running_scenario() {
+                    if !self.lock_out(request, &running) {
                         self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to start scenario: scenario not idle".to_owned())));
-                        return;
                     }
+                    return;
                 }
                 let scenario_name = if let Some(ref scenario_name) = *scenario_name_opt {
                     self.select(scenario_name);
@@ -919,20 +1937,69 @@ impl UnitManager {
                     }
                 };
 
+                if let Some(trigger) = self.triggers.borrow().get(sender_name) {
+                    trigger.borrow().started();
+                }
+                let tags = self.next_tags.replace(vec![]);
+                if !tags.is_empty() {
+                    self.merge_variables(&tags);
+                }
+                self.start_deferred_for_run();
+                self.start_run(&scenario_name);
+                self.start_journal(&scenario_name);
                 self.activate(&scenario_name);
-                self.broadcast_message(ManagerStatusMessage::Start(scenario_name));
+                self.broadcast_message(ManagerStatusMessage::Start(scenario_name.clone()));
+
+                // Loggers pick the serial number up from the log, the same as if a test had reported it.
+                if let Some(ref serial) = *self.serial.borrow() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(scenario_name.clone(), format!("SERIAL {}", serial))));
+                }
+                if let Some(ref operator) = *self.operator.borrow() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(scenario_name.clone(), format!("OPERATOR {}", operator))));
+                }
+                for (key, value) in self.all_variables() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(scenario_name.clone(), format!("VAR {}={}", key, value))));
+                }
+                self.resume_passed_tests(&scenario_name);
+            },
+            ManagerControlMessageContents::FireTrigger(ref trigger_name) => {
+                let scenario = match self.triggers.borrow().get(trigger_name) {
+                    None => None,
+                    Some(trigger) => Some(trigger.borrow().scenario().clone()),
+                };
+                match scenario {
+                    None => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unable to fire trigger: {} is not loaded", trigger_name)))),
+                    Some(scenario) => {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(trigger_name.clone(), format!("simulated trigger event from {}", sender_name))));
+                        self.handle_request(&ManagerControlMessage::new(trigger_name, ManagerControlMessageContents::StartScenario(scenario)));
+                    }
+                }
             },
             ManagerControlMessageContents::Skip(ref test_name, ref reason) => {
                 self.broadcast_skipped(test_name, reason);
             },
             ManagerControlMessageContents::TestStarted => {
+                if let Some(ref run) = *self.current_run.borrow() {
+                    run.test_started(sender_name);
+                }
+                if let Some(ref mut summary) = *self.current_summary.borrow_mut() {
+                    summary.test_started(sender_name);
+                }
+                self.out_of_spec.borrow_mut().remove(sender_name);
                 self.broadcast_message(ManagerStatusMessage::Running(sender_name.clone()));
             }
             ManagerControlMessageContents::TestFinished(result, ref message) => {
-                self.broadcast_message(match result {
-                    0 => ManagerStatusMessage::Pass(sender_name.clone(), message.clone()),
-                    i => ManagerStatusMessage::Fail(sender_name.clone(), i, message.clone()),
-                });
+                self.release_resources(sender_name);
+                self.record_usage(sender_name);
+                let out_of_spec = self.out_of_spec.borrow().get(sender_name).map(|reasons| reasons.join("; "));
+                let status = match (result, out_of_spec) {
+                    (0, Some(reasons)) => ManagerStatusMessage::Fail(sender_name.clone(), OUT_OF_SPEC, reasons),
+                    (0, None) => ManagerStatusMessage::Pass(sender_name.clone(), message.clone()),
+                    (i, _) => ManagerStatusMessage::Fail(sender_name.clone(), i, message.clone()),
+                };
+                self.record_run_result(&status);
+                self.log_result(&status);
+                self.broadcast_message(status);
             }
             ManagerControlMessageContents::ScenarioFinished(code, ref message) => {
                 // Deactivate the current scenario.
@@ -942,55 +2009,1082 @@ impl UnitManager {
                     self.deactivate(cs.as_ref().unwrap().borrow().id(), &message);
                 }
                 self.broadcast_finished(sender_name, code, message);
+                self.finish_run(code, message);
+                self.set_serial(None);
+                self.set_variables(vec![]);
+
+                // Requests queued while it ran aren't handled if exclave is shutting down.
+                let pending = self.shutdown_pending.borrow_mut().take();
+                if let Some(reason) = pending {
+                    self.queued_requests.borrow_mut().clear();
+                    self.shutdown(&reason);
+                    return;
+                }
+
+                let queued: Vec<ManagerControlMessage> = self.queued_requests.borrow_mut().drain(..).collect();
+                for request in queued {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(request.sender.clone(), "handling request queued while scenario was running".to_owned())));
+                    self.handle_request(&request);
+                }
             }
             ManagerControlMessageContents::StartTest(ref test_name) => {
-                self.activate(test_name);
+                // Tests asked for some other way are timed from now.
+                if let Some(asked) = self.advanced_at.borrow_mut().take().or_else(|| self.bc.timer()) {
+                    self.scheduled.borrow_mut().insert(test_name.clone(), asked);
+                }
+                self.start_test(test_name);
             }
             ManagerControlMessageContents::StopTest(ref test_name) => {
                 self.deactivate(test_name, "controller requested test stop");
             }
+            ManagerControlMessageContents::Operator(ref operator) => {
+                let operators = self.cfg.lock().unwrap().operators().clone();
+                match (operator, operators) {
+                    // Looking the operator up may take a while, so it's done in the background.
+                    (&Some(ref id), Some(operators)) => {
+                        let id = id.clone();
+                        let sender = sender_name.clone();
+                        let control = self.control_sender.clone();
+                        thread::spawn(move || {
+                            let refused = operators.check(&id).err();
+                            control.send(ManagerControlMessage::new(&sender, ManagerControlMessageContents::OperatorChecked(id, refused))).ok();
+                        });
+                    }
+                    _ => self.set_operator(sender_name, operator.clone()),
+                }
+            }
+            ManagerControlMessageContents::OperatorChecked(ref id, None) => self.set_operator(sender_name, Some(id.clone())),
+            ManagerControlMessageContents::OperatorChecked(ref id, Some(ref reason)) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("operator {} may not log in: {}", id, reason))));
+                self.send_messages_to(sender_name, vec![ManagerStatusMessage::LoginFailed(id.clone(), reason.clone())]);
+            }
+            ManagerControlMessageContents::Recover(resume) => {
+                let journal = match self.interrupted.borrow_mut().take() {
+                    Some(j) => j,
+                    None => {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "no interrupted run to recover".to_owned())));
+                        return;
+                    }
+                };
+                self.clear_journal();
+                if !resume {
+                    if let Err(e) = journal.mark_run("invalidated") {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("journal"), format!("unable to mark interrupted run as invalidated: {}", e))));
+                    }
+                    self.set_serial(None);
+                    self.set_variables(vec![]);
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("interrupted run of {} invalidated", journal.scenario))));
+                    return;
+                }
+                match UnitName::from_str(&journal.scenario, "scenario") {
+                    Err(e) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unable to resume interrupted run: {}", e)))),
+                    Ok(scenario) => {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("resuming interrupted run of {}, keeping {} tests that passed", scenario, journal.passed.len()))));
+                        *self.resume_tests.borrow_mut() = journal.passed.clone();
+                        self.handle_request(&ManagerControlMessage::new(sender_name, ManagerControlMessageContents::StartScenario(Some(scenario))));
+                        // If the scenario couldn't be started, its tests shouldn't be kept for the next run.
+                        self.resume_tests.borrow_mut().clear();
+                    }
+                }
+            }
+            ManagerControlMessageContents::Golden(mode) => {
+                if self.cfg.lock().unwrap().golden_directory().is_none() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to start a golden run: no GoldenDirectory is configured".to_owned())));
+                    return;
+                }
+                if self.running_scenario().is_some() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to start a golden run: scenario not idle".to_owned())));
+                    return;
+                }
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("next run is a golden run, to {} the baseline", mode))));
+                *self.golden_next.borrow_mut() = Some(mode);
+            }
+            ManagerControlMessageContents::Serial(ref serial) => {
+                // The start that goes with it will be refused, and explain why.
+                if self.jig_unfit_for(sender_name).is_some() || self.cooldown_remaining(sender_name).is_some() {
+                    return;
+                }
+
+                // Don't relabel a run that's already underway.
+                if let Some(running) = self.running_scenario() {
+                    if !self.lock_out(request, &running) {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("ignoring serial number {}: scenario not idle", serial))));
+                    }
+                    return;
+                }
+                self.set_serial(Some(serial.clone()));
+            }
+            ManagerControlMessageContents::Variables(ref variables) => {
+                if self.jig_unfit_for(sender_name).is_some() || self.cooldown_remaining(sender_name).is_some() {
+                    return;
+                }
+                if let Some(running) = self.running_scenario() {
+                    if !self.lock_out(request, &running) {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "ignoring variables: scenario not idle".to_owned())));
+                    }
+                    return;
+                }
+                self.set_variables(variables.clone());
+            }
+            ManagerControlMessageContents::Tag(ref tags) => self.add_tags(sender_name, tags),
+            ManagerControlMessageContents::JigState(health, ref reason) => {
+                let current_jig = self.current_jig.borrow().clone();
+                let jig_id = match current_jig.as_ref().map(|jig| jig.try_borrow_mut()) {
+                    None => None,
+                    Some(Ok(mut jig)) => {
+                        jig.set_health(health, reason.clone());
+                        Some(jig.id().clone())
+                    }
+                    Some(Err(_)) => {
+                        self.bc.log("manager", format!("Unable to set jig state for {}: jig is busy", sender_name));
+                        return;
+                    }
+                };
+                match jig_id {
+                    None => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), "unable to set jig state: no jig selected".to_owned()))),
+                    Some(jig_id) => {
+                        let state = format!("{} {}", health, reason).trim().to_owned();
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("jig {} is now {}", jig_id, state))));
+                        self.broadcast_message(ManagerStatusMessage::Describe(jig_id, FieldType::State, state));
+                    }
+                }
+            }
             ManagerControlMessageContents::Shutdown(ref reason) => {
                 let txt = match reason {
                     &None => format!("shutdown requested (no reason given)"),
                     &Some(ref s) => format!("shutdown requested: {}", s),
                 };
-                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), txt)));
-                self.bc.broadcast(&UnitEvent::Shutdown);
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), txt.clone())));
+
+                // Nothing new may be started from here on.
+                self.deactivate_kind(&UnitKind::Trigger, &txt);
+
+                // Asking a second time doesn't wait any longer.
+                let finish = self.cfg.lock().unwrap().finish_on_shutdown() && self.shutdown_pending.borrow().is_none();
+                if finish {
+                    if let Some(running) = self.running_scenario() {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("waiting for scenario {} to finish before shutting down", running))));
+                        *self.shutdown_pending.borrow_mut() = Some(txt);
+                        return;
+                    }
+                }
+                self.shutdown(&txt);
+            }
+            ManagerControlMessageContents::Rescan => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), "rescan requested".to_owned())));
+                self.bc.broadcast(&UnitEvent::RescanRequest);
+            }
+            ManagerControlMessageContents::Profile(ref profile) => {
+                if self.running_scenario().is_some() {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("not switching to profile {}: scenario not idle", profile))));
+                    return;
+                }
+                if !self.cfg.lock().unwrap().profiles().contains(profile) {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("not switching to profile {}: no such profile", profile))));
+                    return;
+                }
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("switching to profile {}", profile))));
+                self.cfg.lock().unwrap().set_profile(Some(profile.clone()));
+                self.bc.broadcast(&UnitEvent::ConfigReloadRequest);
+            }
+        }
+    }
+
+    /// If per-run logging is enabled, create a new run directory for the scenario.
+    fn start_run(&self, scenario_name: &UnitName) {
+        *self.golden.borrow_mut() = self.golden_next.borrow_mut().take().map(|mode| Golden::new(mode, scenario_name));
+        *self.current_summary.borrow_mut() = Some(RunSummary::new(scenario_name, self.serial.borrow().clone()));
+
+        let log_directory = match *self.cfg.lock().unwrap().log_directory() {
+            None => return,
+            Some(ref d) => d.clone(),
+        };
+
+        // Any previous run that never finished is abandoned, and has no result recorded.
+        if let Some(old_run) = self.current_run.borrow_mut().take() {
+            old_run.unexport();
+        }
+
+        let operator = self.operator.borrow().clone();
+        match Run::new(&log_directory, scenario_name, self.serial.borrow().as_ref().map(|s| s.as_str()), operator.as_ref().map(|o| o.as_str())) {
+            Ok(run) => {
+                run.export();
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(scenario_name.clone(), format!("run {} logging to {}", run.id(), run.directory().to_string_lossy()))));
+                self.check_free_space(run.directory());
+                *self.current_run.borrow_mut() = Some(run);
+            }
+            Err(e) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(scenario_name.clone(), format!("unable to create run directory in {}: {}", log_directory.to_string_lossy(), e))));
+            }
+        }
+    }
+
+    /// Warn if the volume that runs are logged to is nearly full, before it fills up partway through one.
+    fn check_free_space(&self, directory: &Path) {
+        let min_free_space = self.cfg.lock().unwrap().min_free_space();
+        if min_free_space == 0 {
+            return;
+        }
+        let id = UnitName::internal("storage");
+        match free_space(directory) {
+            Ok(free) if free < min_free_space => {
+                let message = format!(
+                    "only {} MiB free for run logs and artifacts in {}, less than the {} MiB required by MinFreeSpace",
+                    free >> 20,
+                    directory.to_string_lossy(),
+                    min_free_space >> 20
+                );
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id, message)));
+            }
+            Ok(_) => (),
+            Err(e) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id, format!("unable to check free space in {}: {}", directory.to_string_lossy(), e)))),
+        }
+    }
+
+    /// If the sender is a trigger that needs the jig to be in a particular
+    /// state to start a scenario, and it isn't, explain why.
+    fn jig_unfit_for(&self, sender: &UnitName) -> Option<String> {
+        let requirement = match self.triggers.borrow().get(sender) {
+            None => return None,
+            Some(trigger) => trigger.borrow().requires_jig_state(),
+        };
+        if requirement == JigRequirement::Any {
+            return None;
+        }
+
+        let current_jig = self.current_jig.borrow();
+        let jig = match *current_jig {
+            None => return Some("no jig is selected".to_owned()),
+            Some(ref jig) => jig.borrow(),
+        };
+        if !self.active.borrow().contains_key(jig.id()) {
+            return Some(format!("jig {} is not active", jig.id()));
+        }
+        if requirement == JigRequirement::Healthy && jig.health() != JigHealth::Healthy {
+            return Some(format!("jig {} is {}", jig.id(), jig.state()));
+        }
+        None
+    }
+
+    /// If the sender is a trigger that started a scenario too recently, how much longer it must wait.
+    fn cooldown_remaining(&self, sender: &UnitName) -> Option<Duration> {
+        match self.triggers.borrow().get(sender) {
+            None => None,
+            Some(trigger) => trigger.borrow().cooldown_remaining(),
+        }
+    }
+
+    /// The combination trigger that takes start requests from `sender`, if any.
+    fn combination_for(&self, sender: &UnitName) -> Option<UnitName> {
+        self.triggers
+            .borrow()
+            .iter()
+            .find(|&(_, trigger)| trigger.borrow().has_input(sender))
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Pass a start request from an input on to its combination trigger, and
+    /// start the scenario on the combination's behalf once it's complete.
+    fn combine(&self, combination: &UnitName, input: &UnitName) {
+        let result = match self.triggers.borrow().get(combination) {
+            None => return,
+            Some(trigger) => {
+                let trigger = trigger.borrow();
+                trigger
+                    .combine(input)
+                    .map(|complete| if complete { Some(trigger.scenario().clone()) } else { None })
+            }
+        };
+        match result {
+            Ok(None) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(combination.clone(), format!("{} fired, waiting for other inputs", input))));
+            }
+            Ok(Some(scenario)) => {
+                self.handle_request(&ManagerControlMessage::new(combination, ManagerControlMessageContents::StartScenario(scenario)));
+            }
+            Err(reason) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(combination.clone(), format!("unable to start scenario: {}", reason))));
+            }
+        }
+    }
+
+    /// The scenario that's currently running, if any.
+    fn running_scenario(&self) -> Option<UnitName> {
+        match *self.current_scenario.borrow() {
+            Some(ref scenario) if scenario.borrow().is_running() => Some(scenario.borrow().id().clone()),
+            _ => None,
+        }
+    }
+
+    /// Apply a trigger's WhileRunning policy to a request that arrived while a scenario is running.
+    /// Returns false if the request didn't come from a trigger, and should be refused as usual.
+    fn lock_out(&self, msg: &ManagerControlMessage, running: &UnitName) -> bool {
+        let lockout = match self.triggers.borrow().get(&msg.sender) {
+            None => return false,
+            Some(trigger) => trigger.borrow().lockout(),
+        };
+
+        let is_start = |m: &ManagerControlMessage| match m.contents {
+            ManagerControlMessageContents::StartScenario(_) => true,
+            _ => false,
+        };
+        let txt = match lockout {
+            TriggerLockout::Ignore => format!("ignoring request while {} is running", running),
+            TriggerLockout::Queue => {
+                let mut queued = self.queued_requests.borrow_mut();
+                // Only one more run is queued up, no matter how many times the button is pressed.
+                if queued.iter().any(|m| is_start(m)) {
+                    format!("ignoring request while {} is running, another run is already queued", running)
+                } else {
+                    queued.push(msg.clone());
+                    if is_start(msg) {
+                        format!("queued start until {} finishes", running)
+                    } else {
+                        format!("queued request until {} finishes", running)
+                    }
+                }
+            }
+        };
+        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(msg.sender.clone(), txt)));
+        true
+    }
+
+    /// Log an operator in, or out if None, and make their id available to programs started from here on.
+    /// Runs that start from now on are recorded as theirs.
+    fn set_operator(&self, sender_name: &UnitName, operator: Option<String>) {
+        let txt = match operator {
+            None => {
+                env::remove_var(OPERATOR_ENV);
+                "operator cleared".to_owned()
+            }
+            Some(ref o) => {
+                env::set_var(OPERATOR_ENV, o);
+                format!("operator set to {}", o)
+            }
+        };
+        *self.operator.borrow_mut() = operator.clone();
+        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), txt)));
+        self.broadcast_message(ManagerStatusMessage::Operator(operator));
+    }
+
+    /// Remember the serial number of the device under test, and make it available to programs started from here on.
+    fn set_serial(&self, serial: Option<String>) {
+        match serial {
+            Some(ref s) => env::set_var(SERIAL_ENV, s),
+            None => env::remove_var(SERIAL_ENV),
+        }
+        *self.serial.borrow_mut() = serial;
+    }
+
+    /// Set the variables for the next run, and make them available to programs started from here on.
+    /// Any variables from the previous run are forgotten.
+    fn set_variables(&self, variables: Vec<(String, String)>) {
+        *self.variables.borrow_mut() = variables;
+        self.export_variables();
+    }
+
+    /// Add to the variables, replacing any with the same names, and make them available to programs started from here on.
+    fn merge_variables(&self, new_variables: &[(String, String)]) {
+        {
+            let mut variables = self.variables.borrow_mut();
+            for &(ref key, ref value) in new_variables {
+                variables.retain(|&(ref k, _)| k != key);
+                variables.push((key.clone(), value.clone()));
+            }
+        }
+        self.export_variables();
+    }
+
+    /// The variables for the next run: its own, along with any from the
+    /// station profile that it wasn't given a different value for.
+    fn all_variables(&self) -> Vec<(String, String)> {
+        let mut variables = self.variables.borrow().clone();
+        for &(ref key, ref value) in self.cfg.lock().unwrap().variables() {
+            if !variables.iter().any(|&(ref k, _)| k == key) {
+                variables.push((key.clone(), value.clone()));
+            }
+        }
+        variables
+    }
+
+    /// Make the variables for the next run available to programs started from here on,
+    /// removing any that were exported before but no longer apply.
+    fn export_variables(&self) {
+        let variables = self.all_variables();
+        for key in self.exported_variables.borrow().iter() {
+            env::remove_var(variable_env(key));
+        }
+        for &(ref key, ref value) in &variables {
+            env::set_var(variable_env(key), value);
+        }
+        *self.exported_variables.borrow_mut() = variables.into_iter().map(|(key, _)| key).collect();
+    }
+
+    /// Check a measurement that a test reported against the test's own limits, or else against
+    /// every loaded set of limits, and log how close it came.  Tests with measurements that are
+    /// out of spec fail, even if they'd otherwise pass.
+    fn check_limits(&self, log: &LogEntry) {
+        // A replayed run already has what its limits said about each measurement.
+        if self.cfg.lock().unwrap().replaying() {
+            return;
+        }
+        let (name, value, unit) = match Record::from_log(log) {
+            Some(Record::Measure(name, value, unit)) => (name, value, unit),
+            _ => return,
+        };
+        let test = log.id();
+        let variables = self.all_variables();
+        let unit = unit.as_ref().map(|u| u.as_str());
+
+        // A test's own limits take precedence over any Limits units.
+        let own_limits = find(&self.tests, test)
+            .and_then(|t| t.try_borrow().ok().map(|t| t.limits().clone()))
+            .unwrap_or_default();
+        let checks: Vec<LimitCheck> = match limits::check_test_limits(&own_limits, &name, &value, unit, &variables) {
+            Some(check) => vec![check],
+            None => self.limits
+                .borrow()
+                .values()
+                .filter_map(|limits| limits.borrow().check(test, &name, &value, unit, &variables))
+                .collect(),
+        };
+
+        // If more than one Limits unit covers the measurement, the closest limit is the one that matters.
+        let margin = checks
+            .iter()
+            .filter_map(|c| c.margin.map(|m| (m, c.unit.clone())))
+            .fold(None, |closest: Option<(f64, Option<String>)>, (m, u)| match closest {
+                Some((c, _)) if c <= m => closest,
+                _ => Some((m, u)),
+            });
+        if let Some((margin, margin_unit)) = margin {
+            let message = match margin_unit {
+                Some(u) => format!("MARGIN {} {} {}", name, limits::format_number(margin), u),
+                None => format!("MARGIN {} {}", name, limits::format_number(margin)),
+            };
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(test.clone(), message)));
+        }
+
+        for problem in checks.into_iter().filter_map(|c| c.problem) {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(test.clone(), format!("{}: {}", OUT_OF_SPEC_MESSAGE, problem))));
+            self.out_of_spec.borrow_mut().entry(test.clone()).or_insert(vec![]).push(problem);
+        }
+    }
+
+    /// Note a measurement that a test reported, if this is a golden run.
+    fn note_golden_measurement(&self, log: &LogEntry) {
+        if let Some(ref mut golden) = *self.golden.borrow_mut() {
+            if let Some(Record::Measure(name, value, unit)) = Record::from_log(log) {
+                let tolerance = self.cfg.lock().unwrap().golden_tolerance();
+                golden.measure(log.id(), &name, &value, unit, tolerance);
+            }
+        }
+    }
+
+    /// A test may report the serial number of the device it's testing, e.g. after reading it from
+    /// the device itself.  It then belongs to the whole run, including everything logged so far.
+    fn note_reported_serial(&self, log: &LogEntry) {
+        let serial = match Record::from_log(log) {
+            Some(Record::Serial(serial)) => serial,
+            _ => return,
+        };
+        if self.running_scenario().is_none() || self.serial.borrow().as_ref() == Some(&serial) {
+            return;
+        }
+        self.set_serial(Some(serial.clone()));
+        if let Some(ref run) = *self.current_run.borrow() {
+            run.set_serial(&serial);
+        }
+        if let Some(ref mut summary) = *self.current_summary.borrow_mut() {
+            summary.set_serial(&serial);
+        }
+    }
+
+    /// A test may tag the run with something it found out, e.g. the firmware version it read back.
+    fn note_reported_tag(&self, log: &LogEntry) {
+        if let Some(Record::Tag(key, value)) = Record::from_log(log) {
+            if self.running_scenario().is_some() {
+                self.add_tags(log.id(), &[(key, value)]);
+            }
+        }
+    }
+
+    /// Tag the run in progress, or the next run if none is running.  Tags for the next run are
+    /// kept apart from its variables until it starts, so that a trigger's variables don't replace them.
+    /// The scenario logs the tags as it does the variables it started with, so that they're recorded
+    /// with the run's results.
+    fn add_tags(&self, sender_name: &UnitName, tags: &[(String, String)]) {
+        let scenario_name = match self.running_scenario() {
+            Some(s) => s,
+            None => {
+                let mut next_tags = self.next_tags.borrow_mut();
+                for &(ref key, ref value) in tags {
+                    next_tags.retain(|&(ref k, _)| k != key);
+                    next_tags.push((key.clone(), value.clone()));
+                }
+                let description = tags.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(" ");
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(sender_name.clone(), format!("next run tagged with {}", description))));
+                return;
+            }
+        };
+        self.merge_variables(tags);
+        for &(ref key, ref value) in tags {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_info(scenario_name.clone(), format!("VAR {}={}", key, value))));
+        }
+        if let Some(ref mut journal) = *self.journal.borrow_mut() {
+            journal.variables = self.variables.borrow().clone();
+        }
+        self.save_journal();
+    }
+
+    /// If a test announced a file it stored, link it to the test in the current run.
+    fn note_artifact(&self, log: &LogEntry) {
+        let path = match Record::from_log(log) {
+            Some(Record::Artifact(path)) => path,
+            _ => return,
+        };
+        let linked = match *self.current_run.borrow() {
+            None => return,
+            Some(ref run) => run.link_artifact(log.id(), &path),
+        };
+        if linked.is_none() {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(log.id().clone(), format!("artifact {} is not in the run directory", path))));
+        }
+    }
+
+    /// Save a log entry to the current run, if there is one.
+    fn record_run_log(&self, log: &LogEntry) {
+        let result = match *self.current_run.borrow() {
+            None => return,
+            Some(ref run) => run.record(log),
+        };
+
+        // If the run can't be written to, stop trying so we don't spam the log with errors.
+        if let Err(e) = result {
+            if let Some(run) = self.current_run.borrow_mut().take() {
+                run.unexport();
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(run.scenario().clone(), format!("unable to write to run {}, no longer logging it: {}", run.id(), e))));
+            }
+        }
+    }
+
+    /// What the programs of a test, interface, or logger use is counted towards this.
+    fn meter(&self, id: &UnitName) -> Option<Meter> {
+        match *id.kind() {
+            UnitKind::Interface => find(&self.interfaces, id).and_then(|u| u.try_borrow().ok().map(|u| u.meter().clone())),
+            UnitKind::Logger => find(&self.loggers, id).and_then(|u| u.try_borrow().ok().map(|u| u.meter().clone())),
+            UnitKind::Test => find(&self.tests, id).and_then(|u| u.try_borrow().ok().map(|u| u.meter().clone())),
+            _ => None,
+        }
+    }
+
+    /// Work out what a unit's program used, now that it has exited, and record it
+    /// in the current run and send it to the loggers that asked for stats.
+    fn record_usage(&self, id: &UnitName) {
+        let started = self.usage.borrow_mut().remove(id);
+        let (started, meter) = match started {
+            None => return,
+            Some(s) => s,
+        };
+        let stats = ProcessStats::new(id, started.elapsed(), &meter.take());
+
+        let result = match *self.current_run.borrow() {
+            None => Ok(()),
+            Some(ref run) => run.record_stats(&stats),
+        };
+        if let Err(e) = result {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id.clone(), format!("unable to record stats: {}", e))));
+        }
+
+        let loggers: Vec<UnitName> = self.loggers.borrow().keys().map(|x| x.clone()).collect();
+        for logger in loggers {
+            self.send_messages_to(&logger, vec![ManagerStatusMessage::Stats(stats.clone())]);
+        }
+    }
+
+    /// How many tests, interfaces, and loggers are running.
+    pub fn running_units(&self) -> usize {
+        self.usage.borrow().len()
+    }
+
+    /// How many messages are waiting to be written to each logger.
+    pub fn logger_queue_lengths(&self) -> Vec<(UnitName, usize)> {
+        self.loggers
+            .borrow()
+            .iter()
+            .map(|(id, logger)| (id.clone(), logger.borrow().queue_len()))
+            .collect()
+    }
+
+    /// Note how a test ended up in the current run, for its summary and JUnit report.
+    fn record_run_result(&self, msg: &ManagerStatusMessage) {
+        let (id, outcome) = match *msg {
+            ManagerStatusMessage::Pass(ref id, _) => (id, Outcome::Passed),
+            ManagerStatusMessage::Fail(ref id, code, ref message) => (id, Outcome::Failed(code, message.clone())),
+            ManagerStatusMessage::Skipped(ref id, ref reason) => (id, Outcome::Skipped(reason.clone())),
+            _ => return,
+        };
+        if let Some(ref mut summary) = *self.current_summary.borrow_mut() {
+            match *msg {
+                ManagerStatusMessage::Pass(_, ref message) => summary.test_finished(id, "pass", Some(0), message),
+                ManagerStatusMessage::Fail(_, code, ref message) => summary.test_finished(id, "fail", Some(code), message),
+                ManagerStatusMessage::Skipped(_, ref reason) => summary.test_finished(id, "skip", None, reason),
+                _ => (),
+            }
+        }
+        self.journal_test_finished(id, match *msg {
+            ManagerStatusMessage::Pass(..) => true,
+            _ => false,
+        });
+        if let Some(ref mut golden) = *self.golden.borrow_mut() {
+            match *msg {
+                ManagerStatusMessage::Pass(..) => golden.test_finished(id, "pass"),
+                ManagerStatusMessage::Fail(..) => golden.test_finished(id, "fail"),
+                _ => golden.test_finished(id, "skip"),
+            }
+        }
+        if let Some(ref run) = *self.current_run.borrow() {
+            run.test_finished(id, outcome);
+        }
+    }
+
+    /// Start journalling a run that has just started, if there's a state directory to keep the journal in.
+    fn start_journal(&self, scenario_name: &UnitName) {
+        if self.cfg.lock().unwrap().state_directory().is_none() {
+            return;
+        }
+        *self.journal.borrow_mut() = Some(Journal {
+            scenario: scenario_name.id().clone(),
+            serial: self.serial.borrow().clone(),
+            variables: self.variables.borrow().clone(),
+            started: timestamp::now(),
+            run_directory: self.current_run.borrow().as_ref().map(|r| r.directory().to_owned()),
+            passed: vec![],
+            not_passed: vec![],
+        });
+        self.save_journal();
+    }
+
+    /// Note in the journal how a test ended up, as soon as it has.
+    fn journal_test_finished(&self, test: &UnitName, passed: bool) {
+        if let Some(ref mut journal) = *self.journal.borrow_mut() {
+            if passed {
+                journal.passed.push(test.id().clone());
+            } else {
+                journal.not_passed.push(test.id().clone());
+            }
+            journal.serial = self.serial.borrow().clone();
+        }
+        self.save_journal();
+    }
+
+    fn save_journal(&self) {
+        let directory = match *self.cfg.lock().unwrap().state_directory() {
+            Some(ref d) => d.clone(),
+            None => return,
+        };
+        let result = match *self.journal.borrow() {
+            Some(ref journal) => journal.save(&directory),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("journal"), format!("unable to write run journal to {}: {}", directory.to_string_lossy(), e))));
+        }
+    }
+
+    /// Forget the run in progress, once it has finished.
+    fn clear_journal(&self) {
+        self.journal.borrow_mut().take();
+        let directory = match *self.cfg.lock().unwrap().state_directory() {
+            Some(ref d) => d.clone(),
+            None => return,
+        };
+        if let Err(e) = Journal::clear(&directory) {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("journal"), format!("unable to remove run journal from {}: {}", directory.to_string_lossy(), e))));
+        }
+    }
+
+    /// If exclave stopped partway through a run last time, e.g. because the station lost power, mark
+    /// the run as interrupted, put its serial number and variables back, and ask the operator whether
+    /// to resume it or throw it out.  Returns true if there was such a run.
+    fn recover_interrupted_run(&self) -> bool {
+        let directory = match *self.cfg.lock().unwrap().state_directory() {
+            Some(ref d) => d.clone(),
+            None => return false,
+        };
+        let id = UnitName::internal("journal");
+        let journal = match Journal::load(&directory) {
+            Ok(Some(journal)) => journal,
+            Ok(None) => return false,
+            Err(e) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id, format!("unable to read run journal from {}: {}", directory.to_string_lossy(), e))));
+                return false;
+            }
+        };
+
+        let result = format!(
+            "result\t{}\tinterrupted: exclave stopped before the run finished\t-\t{}",
+            INTERRUPTED_CODE,
+            journal.serial.clone().unwrap_or_default()
+        );
+        if let Err(e) = journal.mark_run(&result) {
+            self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id.clone(), format!("unable to mark interrupted run as interrupted: {}", e))));
+        }
+        self.set_serial(journal.serial.clone());
+        self.set_variables(journal.variables.clone());
+        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(
+            id,
+            format!(
+                "run of {} started {} was interrupted after {} tests passed, waiting for RECOVER resume or RECOVER invalidate",
+                journal.scenario,
+                journal.started,
+                journal.passed.len()
+            ),
+        )));
+        self.broadcast_message(interrupted_message(&journal));
+        *self.interrupted.borrow_mut() = Some(journal);
+        true
+    }
+
+    /// When resuming an interrupted run, count the tests that passed before as passed, rather than running them again.
+    fn resume_passed_tests(&self, scenario_name: &UnitName) {
+        let passed: Vec<String> = self.resume_tests.borrow_mut().drain(..).collect();
+        if passed.is_empty() {
+            return;
+        }
+        let kept = match *self.current_scenario.borrow() {
+            Some(ref scenario) if scenario.borrow().id() == scenario_name => scenario.borrow().keep_passed(&passed),
+            _ => return,
+        };
+        for test in kept {
+            let status = ManagerStatusMessage::Pass(test, "passed before the run was interrupted".to_owned());
+            self.record_run_result(&status);
+            self.broadcast_message(status);
+        }
+    }
+
+    /// Record the result of the current run, and close it.
+    fn finish_run(&self, code: u32, message: &String) {
+        self.clear_journal();
+        // A golden run is of a reference unit, so it's left out of production results and yield.
+        let golden = self.golden.borrow_mut().take();
+        if let Some(golden) = golden {
+            self.current_summary.borrow_mut().take();
+            self.finish_golden(&golden, code);
+        }
+        if let Some(summary) = self.current_summary.borrow_mut().take() {
+            self.report_to_mes(&summary, code, message);
+            self.upload_result(&summary, code, message);
+            self.print_label(&summary, code, message);
+            self.load_yields();
+            if let Some(ref mut yields) = *self.yields.borrow_mut() {
+                yields.record(summary.outcome(code));
+            }
+            self.load_test_stats();
+            if let Some(ref mut test_stats) = *self.test_stats.borrow_mut() {
+                test_stats.record_run(&summary);
+            }
+            // Interfaces showing the scenario's tests can update what they show about them.
+            for test in summary.tests() {
+                if let Ok(test_id) = UnitName::from_str(&test.test, "test") {
+                    for message in self.test_figures(&test_id) {
+                        self.broadcast_message(message);
+                    }
+                }
+            }
+
+            let summary_directory = self.cfg.lock().unwrap().summary_directory().clone();
+            if let Some(directory) = summary_directory {
+                if let Err(e) = summary.write(&directory, code, message, &self.all_variables()) {
+                    self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("summary"), format!("unable to write run summary to {}: {}", directory.to_string_lossy(), e))));
+                }
             }
         }
+
+        if let Some(run) = self.current_run.borrow_mut().take() {
+            run.unexport();
+            if let Err(e) = run.finish(code, message) {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(run.scenario().clone(), format!("unable to record result of run {}: {}", run.id(), e))));
+            }
+            *self.last_run.borrow_mut() = Some(run.directory().to_owned());
+        }
+        self.prune_runs();
+    }
+
+    /// Save a golden run as the scenario's baseline, or compare it with the baseline, and say how it went.
+    fn finish_golden(&self, golden: &Golden, code: u32) {
+        let directory = match *self.cfg.lock().unwrap().golden_directory() {
+            Some(ref d) => d.clone(),
+            None => return,
+        };
+        let scenario = golden.scenario().clone();
+        let path = Golden::baseline_path(&directory, &scenario);
+        let (verdict, message) = match golden.mode() {
+            // A reference unit that fails isn't a good reference.
+            GoldenMode::Record if code != 200 => ("failed", "baseline not recorded, as the scenario failed".to_owned()),
+            GoldenMode::Record => match golden.save(&directory) {
+                Ok(()) => ("recorded", format!("baseline recorded in {} with {} measurements", path.to_string_lossy(), golden.measurement_count())),
+                Err(e) => ("failed", format!("unable to record baseline in {}: {}", path.to_string_lossy(), e)),
+            },
+            GoldenMode::Verify => match Golden::load(&directory, &scenario) {
+                Err(e) => ("failed", format!("unable to load baseline from {}: {}", path.to_string_lossy(), e)),
+                Ok(baseline) => {
+                    let drift = golden.drift_from(&baseline);
+                    for difference in &drift {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(scenario.clone(), format!("golden drift: {}", difference))));
+                    }
+                    match drift.len() {
+                        0 => ("ok", "matches the baseline".to_owned()),
+                        1 => ("drift", "1 difference from the baseline".to_owned()),
+                        n => ("drift", format!("{} differences from the baseline", n)),
+                    }
+                }
+            },
+        };
+
+        let entry = format!("golden run {}: {}", verdict, message);
+        self.bc.broadcast(&UnitEvent::Log(match verdict {
+            "recorded" | "ok" => LogEntry::new_info(scenario.clone(), entry),
+            _ => LogEntry::new_error(scenario.clone(), entry),
+        }));
+        self.broadcast_message(ManagerStatusMessage::Golden(scenario, verdict.to_owned(), message));
     }
 
-    pub fn send_hello_to(&self, sender_name: &UnitName) {
-        self.send_messages_to(sender_name, vec![ManagerStatusMessage::Hello("Jig/20 1.0".to_owned())]);
+    /// Start sending results to the MES, or stop, or switch to a new one, if the configuration has changed.
+    fn update_mes(&self) {
+        let (target, queue_directory) = {
+            let cfg = self.cfg.lock().unwrap();
+            (cfg.mes().clone(), cfg.mes_queue_directory().clone())
+        };
+        let target = match target {
+            None => {
+                *self.mes.borrow_mut() = None;
+                return;
+            }
+            Some(t) => t,
+        };
+        let unchanged = match *self.mes.borrow() {
+            Some(ref mes) => *mes.target() == target && *mes.queue_directory() == queue_directory,
+            None => false,
+        };
+        if !unchanged {
+            *self.mes.borrow_mut() = Some(MesReporter::start(target, queue_directory, &self.bc));
+        }
+    }
+
+    /// Queue the result of a run that has just finished to be sent to the MES, if there is one.
+    fn report_to_mes(&self, summary: &RunSummary, code: u32, message: &str) {
+        self.update_mes();
+        if self.mes.borrow().is_none() {
+            return;
+        }
+        let run = self.current_run.borrow().as_ref().map(|r| r.id().clone());
+        let result = summary.result(code, message, self.operator.borrow().clone(), &self.all_variables(), run);
+        if let Some(ref mes) = *self.mes.borrow() {
+            if let Err(e) = mes.report(&result) {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("mes"), format!("unable to queue result: {}", e))));
+            }
+        }
+    }
+
+    /// Start uploading results to a central server, or stop, or switch to a new one, if the configuration has changed.
+    fn update_uploader(&self) {
+        let (url, queue_directory, station) = {
+            let cfg = self.cfg.lock().unwrap();
+            (cfg.upload_url().clone(), cfg.upload_queue_directory().clone(), cfg.upload_station())
+        };
+        let url = match url {
+            None => {
+                *self.uploader.borrow_mut() = None;
+                return;
+            }
+            Some(u) => u,
+        };
+        let unchanged = match *self.uploader.borrow() {
+            Some(ref uploader) => *uploader.url() == url && *uploader.station() == station && *uploader.queue_directory() == queue_directory,
+            None => false,
+        };
+        if !unchanged {
+            *self.uploader.borrow_mut() = Some(Uploader::start(&url, &station, queue_directory, &self.bc));
+        }
+    }
+
+    /// Queue the result of a run that has just finished to be uploaded, if there's somewhere to upload it to.
+    fn upload_result(&self, summary: &RunSummary, code: u32, message: &str) {
+        self.update_uploader();
+        if self.uploader.borrow().is_none() {
+            return;
+        }
+        let run = self.current_run.borrow().as_ref().map(|r| r.id().clone());
+        let result = summary.result(code, message, self.operator.borrow().clone(), &self.all_variables(), run);
+        if let Some(ref uploader) = *self.uploader.borrow() {
+            if let Err(e) = uploader.upload(&result) {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("upload"), format!("unable to queue record: {}", e))));
+            }
+        }
+    }
+
+    /// Print a label for the device if it passed, or a reject tag if it failed, if there's a command to do it with.
+    fn print_label(&self, summary: &RunSummary, code: u32, message: &str) {
+        let command = match *self.cfg.lock().unwrap().label_command(code == 200) {
+            Some(ref c) => c.clone(),
+            None => return,
+        };
+        let run = self.current_run.borrow().as_ref().map(|r| r.id().clone());
+        let result = summary.result(code, message, self.operator.borrow().clone(), &self.all_variables(), run);
+        labels::print(&command, &result, &self.bc);
+    }
+
+    /// Read the runs that previous instances recorded in the summary directory, if that hasn't been done yet.
+    fn load_yields(&self) {
+        let (summary_directory, keep) = {
+            let cfg = self.cfg.lock().unwrap();
+            (cfg.summary_directory().clone(), cfg.yield_history())
+        };
+        if let Some(ref mut yields) = *self.yields.borrow_mut() {
+            // The windows may have changed since they were loaded.
+            yields.set_keep(keep);
+            return;
+        }
+
+        let yields = match summary_directory {
+            None => YieldStats::new(keep),
+            Some(directory) => {
+                let path = directory.join(RUNS_FILE);
+                match YieldStats::load(&path, keep) {
+                    Ok(yields) => yields,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => YieldStats::new(keep),
+                    Err(e) => {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("summary"), format!("unable to read past runs from {}: {}", path.to_string_lossy(), e))));
+                        YieldStats::new(keep)
+                    }
+                }
+            }
+        };
+        *self.yields.borrow_mut() = Some(yields);
+    }
+
+    /// Read the test results that previous instances recorded in the summary directory, if that hasn't been done yet.
+    fn load_test_stats(&self) {
+        if self.test_stats.borrow().is_some() {
+            return;
+        }
+        let summary_directory = self.cfg.lock().unwrap().summary_directory().clone();
+        let test_stats = match summary_directory {
+            None => TestStats::new(),
+            Some(directory) => {
+                let path = directory.join(TESTS_FILE);
+                match TestStats::load(&path) {
+                    Ok(test_stats) => test_stats,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => TestStats::new(),
+                    Err(e) => {
+                        self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(UnitName::internal("summary"), format!("unable to read past test results from {}: {}", path.to_string_lossy(), e))));
+                        TestStats::new()
+                    }
+                }
+            }
+        };
+        *self.test_stats.borrow_mut() = Some(test_stats);
+    }
+
+    /// DESCRIBE messages saying how a test has been doing lately, if it has run before.
+    fn test_figures(&self, test_id: &UnitName) -> Vec<ManagerStatusMessage> {
+        self.load_test_stats();
+        let figures = match *self.test_stats.borrow() {
+            Some(ref test_stats) => test_stats.figures(test_id.id()),
+            None => None,
+        };
+        let figures = match figures {
+            Some(f) => f,
+            None => return vec![],
+        };
+        let mut messages = vec![];
+        if let Some(mean) = figures.mean_duration {
+            messages.push(ManagerStatusMessage::Describe(test_id.clone(), FieldType::MeanDuration, format!("{}.{:03}", mean.as_secs(), mean.subsec_millis())));
+        }
+        messages.push(ManagerStatusMessage::Describe(test_id.clone(), FieldType::Failures, format!("{}/{}", figures.failures, figures.runs)));
+        if let Some((started, reason)) = figures.last_failure {
+            messages.push(ManagerStatusMessage::Describe(test_id.clone(), FieldType::LastFailure, format!("{} {}", timestamp::rfc3339_seconds(started), reason)));
+        }
+        messages
+    }
+
+    /// Yield figures for each of the configured windows.
+    pub fn yield_windows(&self) -> Vec<YieldWindow> {
+        self.load_yields();
+        let windows = self.cfg.lock().unwrap().yield_windows().clone();
+        match *self.yields.borrow() {
+            None => vec![],
+            Some(ref yields) => windows.into_iter().map(|w| yields.window(w)).collect(),
+        }
+    }
+
+    /// Remove old runs from the log directory in the background, if there are limits on how many to keep.
+    fn prune_runs(&self) {
+        let (log_directory, retention) = {
+            let cfg = self.cfg.lock().unwrap();
+            match *cfg.log_directory() {
+                Some(ref d) if !cfg.retention().is_unlimited() => (d.clone(), cfg.retention().clone()),
+                _ => return,
+            }
+        };
+
+        let bc = self.bc.clone();
+        thread::spawn(move || {
+            let id = UnitName::internal("storage");
+            match retention::prune(&log_directory, &retention) {
+                Ok(ref pruned) if pruned.runs == 0 => (),
+                Ok(pruned) => bc.broadcast(&UnitEvent::Log(LogEntry::new_info(
+                    id,
+                    format!("removed {} old run(s) from {}, freeing {} MiB", pruned.runs, log_directory.to_string_lossy(), pruned.bytes >> 20),
+                ))),
+                Err(e) => bc.broadcast(&UnitEvent::Log(LogEntry::new_error(
+                    id,
+                    format!("unable to remove old runs from {}: {}", log_directory.to_string_lossy(), e),
+                ))),
+            }
+        });
+    }
+
+    fn hello_messages(&self) -> Vec<ManagerStatusMessage> {
+        vec![ManagerStatusMessage::Hello("Jig/20 1.0".to_owned())]
     }
 
     pub fn send_jig_to(&self, sender_name: &UnitName) {
-        let messages = match *self.current_jig.borrow() {
+        self.send_messages_to(sender_name, self.jig_messages());
+    }
+
+    fn jig_messages(&self) -> Vec<ManagerStatusMessage> {
+        match *self.current_jig.borrow() {
             None => vec![ManagerStatusMessage::Jig(None)],
             Some(ref jig_rc) => {
                 let jig = jig_rc.borrow();
                 vec![
                     ManagerStatusMessage::Jig(Some(jig.id().clone())),
                     ManagerStatusMessage::Describe(jig.id().clone(), FieldType::Name, jig.name().clone()),
-                    ManagerStatusMessage::Describe(jig.id().clone(), FieldType::Description, jig.description().clone())
+                    ManagerStatusMessage::Describe(jig.id().clone(), FieldType::Description, jig.description().clone()),
+                    ManagerStatusMessage::Describe(jig.id().clone(), FieldType::State, jig.state()),
                 ]
             }
-        };
-        self.send_messages_to(sender_name, messages);
+        }
     }
 
     /// Send all available scenarios to the specified endpoint.
     pub fn send_scenarios_to(&self, sender_name: &UnitName) {
+        self.send_messages_to(sender_name, self.scenarios_messages());
+    }
+
+    fn scenarios_messages(&self) -> Vec<ManagerStatusMessage> {
         let mut messages = vec![ManagerStatusMessage::Scenarios(self.scenarios.borrow().keys().map(|x| x.clone()).collect())];
         for (scenario_id, scenario) in self.scenarios.borrow().iter() {
             messages.push(ManagerStatusMessage::Describe(scenario_id.clone(), FieldType::Name, scenario.borrow().name().clone()));
             messages.push(ManagerStatusMessage::Describe(scenario_id.clone(), FieldType::Description, scenario.borrow().description().clone()));
         }
-        self.send_messages_to(sender_name, messages);
+        messages
     }
 
-    pub fn send_scenario_to(&self, sender_name: &UnitName, scenario_name: &UnitName) {
-        let messages = match self.scenarios.borrow().get(scenario_name) {
+    fn scenario_messages(&self, scenario_name: &UnitName) -> Vec<ManagerStatusMessage> {
+        match self.scenarios.borrow().get(scenario_name) {
             None => vec![ManagerStatusMessage::Scenario(None)],
             Some(scenario_rc) => {
                 let scenario = scenario_rc.borrow();
@@ -999,12 +3093,12 @@ impl UnitManager {
                     let test = test_rc.borrow();
                     messages.push(ManagerStatusMessage::Describe(test_id.clone(), FieldType::Name, test.name().clone()));
                     messages.push(ManagerStatusMessage::Describe(test_id.clone(), FieldType::Description, test.description().clone()));
+                    messages.extend(self.test_figures(test_id));
                 }
                 messages.push(ManagerStatusMessage::Tests(scenario.id().clone(), scenario.test_sequence()));
                 messages
             }
-        };
-        self.send_messages_to(sender_name, messages);
+        }
     }
 
     /// Send a list of tests to the specified recipient.
@@ -1060,10 +3154,11 @@ impl UnitManager {
             None => return,
             Some(ref j) => {
                 let scenario = j.borrow();
+                let tests = scenario.test_sequence();
                 for (interface_id, _) in self.interfaces.borrow().iter() {
                     let messages = vec![
                         ManagerStatusMessage::Scenario(Some(scenario.id().clone())),
-                        ManagerStatusMessage::Tests(scenario.id().clone(), scenario.test_sequence())
+                        ManagerStatusMessage::Tests(scenario.id().clone(), tests.clone())
                     ];
                     self.send_messages_to(interface_id, messages);
                 }
@@ -1125,22 +3220,138 @@ impl UnitManager {
     }
 
     fn broadcast_skipped(&self, unit_id: &UnitName, reason: &String) {
-        let msg = ManagerStatusMessage::Skipped(unit_id.clone(), reason.clone());
-        for (interface_id, _) in self.interfaces.borrow().iter() {
-            self.send_messages_to(interface_id, vec![msg.clone()]);
-        }
+        let status = ManagerStatusMessage::Skipped(unit_id.clone(), reason.clone());
+        self.record_run_result(&status);
+        self.log_result(&status);
+        self.broadcast_message(status);
     }
 
     fn broadcast_finished(&self, unit_id: &UnitName, code: u32, message: &String) {
-        let msg = ManagerStatusMessage::Finished(unit_id.clone(), code, message.clone());
-        for (interface_id, _) in self.interfaces.borrow().iter() {
-            self.send_messages_to(interface_id, vec![msg.clone()]);
+        let status = ManagerStatusMessage::Finished(unit_id.clone(), code, message.clone());
+        self.log_result(&status);
+        self.broadcast_message(status);
+    }
+
+    /// Stations without an interface, e.g. ones driven by triggers alone, have nothing to show
+    /// results on.  Log them instead, so that they reach the console and every logger.
+    fn log_result(&self, msg: &ManagerStatusMessage) {
+        if !self.interfaces.borrow().is_empty() {
+            return;
+        }
+        let entry = match *msg {
+            ManagerStatusMessage::Pass(ref id, ref message) => LogEntry::new_info(id.clone(), format!("passed: {}", message)),
+            ManagerStatusMessage::Fail(ref id, code, ref message) => {
+                LogEntry::new_error(id.clone(), format!("failed with {}: {}", code, message))
+            }
+            ManagerStatusMessage::Skipped(ref id, ref reason) => LogEntry::new_info(id.clone(), format!("skipped: {}", reason)),
+            ManagerStatusMessage::Finished(ref id, 200, ref message) => {
+                LogEntry::new_info(id.clone(), format!("finished: {}", message))
+            }
+            ManagerStatusMessage::Finished(ref id, code, ref message) => {
+                LogEntry::new_error(id.clone(), format!("finished with {}: {}", code, message))
+            }
+            _ => return,
+        };
+        self.bc.broadcast(&UnitEvent::Log(entry));
+    }
+
+    /// Send a control command to the loggers that keep an audit log.
+    fn broadcast_audit(&self, entry: AuditEntry) {
+        let loggers: Vec<UnitName> = self.loggers.borrow().keys().map(|x| x.clone()).collect();
+        for logger in loggers {
+            self.send_messages_to(&logger, vec![ManagerStatusMessage::Audit(entry.clone())]);
         }
     }
 
+    /// Send a message to every interface and logger.
     fn broadcast_message(&self, msg: ManagerStatusMessage) {
-        for (interface_id, _) in self.interfaces.borrow().iter() {
-            self.send_messages_to(interface_id, vec![msg.clone()]);
+        self.start_deferred_for_event(&msg);
+        let mut recipients: Vec<UnitName> = self.interfaces.borrow().keys().map(|x| x.clone()).collect();
+        recipients.extend(self.loggers.borrow().keys().map(|x| x.clone()));
+        recipients.extend(self.custom_units.borrow().keys().map(|x| x.clone()));
+        for recipient in recipients {
+            self.send_messages_to(&recipient, vec![msg.clone()]);
+        }
+    }
+
+    /// Send the status of every unit matching `filter`, which may be a kind or a unit's id.
+    pub fn send_statuses_to(&self, sender_name: &UnitName, filter: &Option<String>) {
+        let mut statuses: Vec<(UnitName, UnitStatus)> = self.statuses
+            .borrow()
+            .iter()
+            .filter(|&(name, _)| match *filter {
+                None => true,
+                Some(ref f) => *f == format!("{}", name.kind()) || f == name.id() || *f == format!("{}", name),
+            })
+            .map(|(name, status)| (name.clone(), status.clone()))
+            .collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let messages = statuses
+            .into_iter()
+            .map(|(name, status)| ManagerStatusMessage::Status(name, status))
+            .collect();
+        self.send_messages_to(sender_name, messages);
+    }
+
+    /// Find a run's directory by its id, or the current or most recent run if there's no id.
+    fn find_run(&self, run: &Option<String>) -> Result<(String, PathBuf), String> {
+        let directory = match *run {
+            None => match (&*self.current_run.borrow(), &*self.last_run.borrow()) {
+                (&Some(ref run), _) => run.directory().to_owned(),
+                (&None, &Some(ref directory)) => directory.clone(),
+                (&None, &None) => return Err("no run has been logged yet".to_owned()),
+            },
+            Some(ref id) => {
+                let log_directory = match *self.cfg.lock().unwrap().log_directory() {
+                    Some(ref d) => d.clone(),
+                    None => return Err("runs are not being logged".to_owned()),
+                };
+                match artifacts::resolve(&log_directory, id) {
+                    Some(ref d) if d.join("events.log").is_file() => d.clone(),
+                    _ => return Err(format!("unable to find run {}", id)),
+                }
+            }
+        };
+        let id = directory.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok((id, directory))
+    }
+
+    /// Send the artifacts of a run to a unit that asked for them, one per message.
+    pub fn send_artifacts_to(&self, sender_name: &UnitName, run: &Option<String>) {
+        let (id, directory) = match self.find_run(run) {
+            Ok(r) => r,
+            Err(e) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), e)));
+                return;
+            }
+        };
+        // The current run's manifest isn't written until it finishes, so list what's there now.
+        let listing = match *self.current_run.borrow() {
+            Some(ref current) if current.directory() == directory.as_path() => current.artifacts(),
+            _ => artifacts::list(&directory),
+        };
+        match listing {
+            Ok(list) => {
+                let messages = list.into_iter().map(|a| ManagerStatusMessage::Artifact(id.clone(), a)).collect();
+                self.send_messages_to(sender_name, messages);
+            }
+            Err(e) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unable to list artifacts of run {}: {}", id, e)))),
+        }
+    }
+
+    /// Send the contents of one of a run's artifacts to a unit that asked for it.
+    pub fn send_artifact_data_to(&self, sender_name: &UnitName, run: &str, path: &str) {
+        let (id, directory) = match self.find_run(&Some(run.to_owned())) {
+            Ok(r) => r,
+            Err(e) => {
+                self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), e)));
+                return;
+            }
+        };
+        match artifacts::read_base64(&directory, path) {
+            Ok(data) => self.send_messages_to(sender_name, vec![ManagerStatusMessage::ArtifactData(id, path.to_owned(), data)]),
+            Err(e) => self.bc.broadcast(&UnitEvent::Log(LogEntry::new_error(sender_name.clone(), format!("unable to read {} from run {}: {}", path, id, e)))),
         }
     }
 
@@ -1149,15 +3360,60 @@ impl UnitManager {
         let mut deactivate_reason = None;
         match *sender_name.kind() {
             UnitKind::Interface => {
-                let interface_table = self.interfaces.borrow();
-                let interface = interface_table.get(sender_name).expect("Unable to find Interface in the library");
+                // The interface may have been unloaded since it sent its request.
+                let interface = match find(&self.interfaces, sender_name) {
+                    Some(i) => i,
+                    None => {
+                        self.bc.log("manager", format!("Unable to send messages to {}: not loaded", sender_name));
+                        return;
+                    }
+                };
+                let interface = match interface.try_borrow() {
+                    Ok(i) => i,
+                    Err(_) => {
+                        self.bc.log("manager", format!("Unable to send messages to {}: busy", sender_name));
+                        return;
+                    }
+                };
+                if let Err(e) = interface.output_messages(messages) {
+                    deactivate_reason = Some(e);
+                }
+            },
+            UnitKind::Logger => {
+                let logger = match find(&self.loggers, sender_name) {
+                    Some(l) => l,
+                    None => {
+                        self.bc.log("manager", format!("Unable to send messages to {}: not loaded", sender_name));
+                        return;
+                    }
+                };
+                let logger = match logger.try_borrow() {
+                    Ok(l) => l,
+                    Err(_) => {
+                        self.bc.log("manager", format!("Unable to send messages to {}: busy", sender_name));
+                        return;
+                    }
+                };
                 for msg in messages {
-                    if let Err(e) = interface.borrow().output_message(msg) {
+                    if let Err(e) = logger.output_message(msg) {
                         deactivate_reason = Some(e);
                         break;
                     }
                 }
             },
+            UnitKind::Custom(_) => {
+                let unit = match find(&self.custom_units, sender_name) {
+                    Some(u) => u,
+                    None => return,
+                };
+                let unit = match unit.try_borrow() {
+                    Ok(u) => u,
+                    Err(_) => return,
+                };
+                for msg in messages {
+                    unit.output_message(&msg);
+                }
+            },
             _ => (),
         }
         if let Some(deactivate_reason) = deactivate_reason {
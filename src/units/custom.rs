@@ -0,0 +1,258 @@
+extern crate serde_json;
+extern crate systemd_parser;
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+use config::Config;
+use plugin::{self, Host, PluginVTable, RawUnit};
+use unit::{check_format_version, expand_unit_text, read_unit_file, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError,
+           UnitIncompatibleReason, UnitName};
+use unitmanager::{ManagerControlMessage, ManagerControlMessageContents, ManagerStatusMessage, UnitManager};
+
+use self::systemd_parser::items::DirectiveEntry;
+
+/// A unit of a kind that a plugin has added.  Exclave only looks at the directives
+/// that every unit has, and the plugin is given the whole file to make sense of.
+#[derive(Clone)]
+pub struct CustomDescription {
+    /// The id of the unit (including the kind)
+    id: UnitName,
+
+    /// The kind of unit, which is the plugin's.
+    kind: String,
+
+    /// A short name
+    name: String,
+
+    /// A detailed description of this unit, up to one paragraph.
+    description: String,
+
+    /// A Vec<String> of jig names that this unit is compatible with.
+    jigs: Vec<UnitName>,
+
+    /// The text of the unit file, with its variables expanded, for the plugin.
+    text: String,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+}
+
+/// The section that a unit of `kind` is described in, e.g. "Instrument" for "instrument".
+fn section_name(kind: &str) -> String {
+    let mut chars = kind.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl CustomDescription {
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<CustomDescription, UnitDescriptionError> {
+        let contents = read_unit_file(&unit_name, path)?;
+        Self::from_string(&contents, unit_name, path)
+    }
+
+    pub fn from_string(contents: &str, unit_name: UnitName, _path: &Path) -> Result<CustomDescription, UnitDescriptionError> {
+        let text = expand_unit_text(&contents)?;
+        let unit_file = systemd_parser::parse_string(&text)?;
+        check_format_version(&unit_file)?;
+
+        let kind = format!("{}", unit_name.kind());
+        let section = section_name(&kind);
+        if !unit_file.has_category(&section) {
+            return Err(UnitDescriptionError::MissingSection(section));
+        }
+
+        let mut custom_description = CustomDescription {
+            id: unit_name.clone(),
+            kind: kind,
+            name: "".to_owned(),
+            description: "".to_owned(),
+            jigs: vec![],
+            text: text.clone(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+        };
+
+        for entry in unit_file.lookup_by_category(&section) {
+            match entry {
+                &DirectiveEntry::Solo(ref directive) => match directive.key() {
+                    "Name" => custom_description.name = directive.value().unwrap_or("").to_owned(),
+                    "Description" => custom_description.description = directive.value().unwrap_or("").to_owned(),
+                    "Jigs" => {
+                        custom_description.jigs = match directive.value() {
+                            Some(s) => UnitName::from_list(s, "jig")?,
+                            None => vec![],
+                        }
+                    }
+                    &_ => (),
+                },
+                &_ => (),
+            }
+        }
+        Ok(custom_description)
+    }
+
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
+    /// Determine if a unit is compatible with this system.
+    pub fn is_compatible(&self, manager: &UnitManager, _: &Config) -> Result<(), UnitIncompatibleReason> {
+        if self.jigs.len() == 0 {
+            return Ok(());
+        }
+        for jig_name in &self.jigs {
+            if manager.jig_is_loaded(&jig_name) {
+                return Ok(());
+            }
+        }
+        Err(UnitIncompatibleReason::IncompatibleJig)
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.id
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn description(&self) -> &String {
+        &self.description
+    }
+
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
+    /// Have the plugin make the unit, which it may refuse to do.
+    pub fn load(&self, manager: &UnitManager, config: &Config) -> Result<CustomUnit, UnitIncompatibleReason> {
+        self.is_compatible(manager, config)?;
+        CustomUnit::new(self, manager.get_control_channel())
+    }
+}
+
+/// Where a unit's log lines go.  The last error is also kept, as the reason for whatever
+/// the plugin just failed to do.
+struct HostContext {
+    id: UnitName,
+    control: Mutex<Sender<ManagerControlMessage>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl HostContext {
+    fn take_error(&self) -> String {
+        self.last_error.lock().unwrap().take().unwrap_or("no reason given".to_owned())
+    }
+}
+
+extern "C" fn host_log(context: *mut c_void, error: bool, message: *const c_char) {
+    if context.is_null() || message.is_null() {
+        return;
+    }
+    let context = unsafe { &*(context as *const HostContext) };
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned();
+    let contents = if error {
+        *context.last_error.lock().unwrap() = Some(message.clone());
+        ManagerControlMessageContents::LogError(message)
+    } else {
+        ManagerControlMessageContents::Log(message)
+    };
+    context.control.lock().unwrap().send(ManagerControlMessage::new(&context.id, contents)).ok();
+}
+
+pub struct CustomUnit {
+    description: CustomDescription,
+    vtable: PluginVTable,
+    raw: RawUnit,
+
+    /// What the plugin reports back with.  They're boxed, so that they stay where the
+    /// plugin was told they are until the unit is destroyed.
+    _host: Box<Host>,
+    context: Box<HostContext>,
+
+    running: Cell<bool>,
+}
+
+impl CustomUnit {
+    fn new(desc: &CustomDescription, control: Sender<ManagerControlMessage>) -> Result<CustomUnit, UnitIncompatibleReason> {
+        let vtable = plugin::find(&desc.kind).ok_or_else(|| UnitIncompatibleReason::PluginRejected(format!("no plugin adds {} units", desc.kind)))?;
+        let id = CString::new(desc.id.id().as_str()).map_err(|_| UnitIncompatibleReason::PluginRejected("unit id contains a nul".to_owned()))?;
+        let text = CString::new(desc.text.as_str()).map_err(|_| UnitIncompatibleReason::PluginRejected("unit file contains a nul".to_owned()))?;
+
+        let mut context = Box::new(HostContext {
+            id: desc.id.clone(),
+            control: Mutex::new(control),
+            last_error: Mutex::new(None),
+        });
+        let host = Box::new(Host {
+            context: &mut *context as *mut HostContext as *mut c_void,
+            log: host_log,
+        });
+
+        let raw = (vtable.create)(id.as_ptr(), text.as_ptr(), &*host);
+        if raw.is_null() {
+            return Err(UnitIncompatibleReason::PluginRejected(context.take_error()));
+        }
+        Ok(CustomUnit {
+            description: desc.clone(),
+            vtable: vtable,
+            raw: raw,
+            _host: host,
+            context: context,
+            running: Cell::new(false),
+        })
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.description.id
+    }
+
+    pub fn activate(&self) -> Result<(), UnitActivateError> {
+        if !(self.vtable.activate)(self.raw) {
+            return Err(UnitActivateError::PluginFailed(self.context.take_error()));
+        }
+        self.running.set(true);
+        Ok(())
+    }
+
+    pub fn deactivate(&self) -> Result<(), UnitDeactivateError> {
+        if self.running.replace(false) {
+            (self.vtable.deactivate)(self.raw);
+        }
+        Ok(())
+    }
+
+    /// Pass on what the unit would be sent if it were a logger, as JSON, while it's running.
+    pub fn output_message(&self, msg: &ManagerStatusMessage) {
+        if !self.running.get() {
+            return;
+        }
+        let event = match *msg {
+            ManagerStatusMessage::Log(ref l) => ("log", serde_json::to_string(l)),
+            ManagerStatusMessage::Audit(ref a) => ("audit", serde_json::to_string(a)),
+            ManagerStatusMessage::Transition(ref t) => ("transition", serde_json::to_string(t)),
+            ManagerStatusMessage::Stats(ref s) => ("stats", serde_json::to_string(s)),
+            _ => return,
+        };
+        if let (kind, Ok(json)) = event {
+            // JSON escapes any nul in a string, so there's never one in the line.
+            let kind = CString::new(kind).unwrap();
+            let json = CString::new(json).unwrap();
+            (self.vtable.event)(self.raw, kind.as_ptr(), json.as_ptr());
+        }
+    }
+}
+
+impl Drop for CustomUnit {
+    fn drop(&mut self) {
+        self.deactivate().ok();
+        (self.vtable.destroy)(self.raw);
+    }
+}
@@ -1,35 +1,121 @@
-extern crate runny;
 extern crate systemd_parser;
 
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::thread;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use config::Config;
+use golden::GoldenMode;
+use reactor;
+use supervisor::{Restarts, Supervised};
 use unit::{
-    UnitActivateError, UnitDeactivateError, UnitDescriptionError, UnitDeselectError,
+    check_format_version, parse_unit_file, read_unit_file, ActivateOn, RestartPolicy, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError, UnitDeselectError,
     UnitIncompatibleReason, UnitName, UnitSelectError,
 };
+use triggers::{split_variable, start_messages};
+use units::jig::JigHealth;
+use units::sessions::Sessions;
+use unitbroadcaster::{TimingKind, UnitBroadcaster};
 use unitmanager::{
     ManagerControlMessage, ManagerControlMessageContents, ManagerStatusMessage, UnitManager,
 };
+use usage::Meter;
 
-use self::runny::running::{Running, RunningOutput};
-use self::runny::Runny;
 use self::systemd_parser::items::DirectiveEntry;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 enum InterfaceFormat {
     Text,
     JSON,
 }
 
+/// How an interface and whatever is driving it talk to each other.
+#[derive(Clone, PartialEq)]
+enum Transport {
+    /// Over the standard input and output of the program in ExecStart.
+    Exec,
+
+    /// With any number of clients that connect to a TCP address.
+    Tcp(SocketAddr),
+
+    /// With any number of clients that connect to a Unix socket.
+    Unix(PathBuf),
+}
+
+impl Transport {
+    fn from_value(value: Option<&str>) -> Result<Transport, UnitDescriptionError> {
+        let value = match value {
+            None => return Ok(Transport::Exec),
+            Some(v) => v.trim(),
+        };
+        if value.to_lowercase() == "exec" {
+            return Ok(Transport::Exec);
+        }
+        if value.to_lowercase().starts_with("tcp:") {
+            // A port on its own is only reachable from this machine, as clients aren't authenticated.
+            if let Ok(port) = value[4..].parse::<u16>() {
+                return Ok(Transport::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)));
+            }
+            if let Ok(address) = value[4..].parse::<SocketAddr>() {
+                return Ok(Transport::Tcp(address));
+            }
+        }
+        if value.to_lowercase().starts_with("unix:") && value.len() > 5 {
+            return Ok(Transport::Unix(PathBuf::from(&value[5..])));
+        }
+        Err(UnitDescriptionError::InvalidValue(
+            "Interface".to_owned(),
+            "Transport".to_owned(),
+            value.to_owned(),
+            vec!["exec".to_owned(), "tcp:<port>".to_owned(), "tcp:<address>:<port>".to_owned(), "unix:<path>".to_owned()],
+        ))
+    }
+}
+
+/// What's written to an in-memory interface, which has no program to write it to.  Clones share
+/// the same lines, so that whoever made the interface can see what the manager told it.
+#[derive(Clone, Default)]
+pub struct Capture {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    /// Every line written so far, without line endings.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    /// Every line written since the last time this was called.
+    pub fn take(&self) -> Vec<String> {
+        mem::replace(&mut *self.lines.lock().unwrap(), vec![])
+    }
+
+    fn write(&self, buffer: &[u8]) {
+        let mut lines = self.lines.lock().unwrap();
+        for line in String::from_utf8_lossy(buffer).lines() {
+            lines.push(line.to_owned());
+        }
+    }
+}
+
+/// Two captures are the same if they share their lines.
+impl PartialEq for Capture {
+    fn eq(&self, other: &Capture) -> bool {
+        Arc::ptr_eq(&self.lines, &other.lines)
+    }
+}
+
 /// A struct defining an in-memory representation of a .Interface file
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct InterfaceDescription {
     /// The id of the unit (including the kind)
     id: UnitName,
@@ -49,37 +135,57 @@ pub struct InterfaceDescription {
     /// The format expected by the interface
     format: InterfaceFormat,
 
+    /// Whether to run a program, or to listen for clients
+    transport: Transport,
+
     /// The working directory to start from when running the interface
     working_directory: Option<PathBuf>,
 
     /// The path of the unit file
     unit_directory: PathBuf,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
+    /// When to start the interface
+    activate_on: ActivateOn,
+
+    /// Whether to restart the interface if its program exits
+    restart: RestartPolicy,
+
+    /// Where what's written to the interface goes, if it's an in-memory one with no program
+    capture: Option<Capture>,
 }
 
 impl InterfaceDescription {
-    pub fn from_path(path: &Path) -> Result<InterfaceDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<InterfaceDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Interface") {
             return Err(UnitDescriptionError::MissingSection("Interface".to_owned()));
         }
 
         let mut interface_description = InterfaceDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
             jigs: vec![],
             format: InterfaceFormat::Text,
+            transport: Transport::Exec,
             exec_start: "".to_owned(),
             working_directory: None,
             unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+            activate_on: ActivateOn::Start,
+            restart: RestartPolicy::No,
+            capture: None,
         };
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Interface") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => match directive.key() {
@@ -102,12 +208,12 @@ impl InterfaceDescription {
                         if let Some(wd) = directive.value() {
                             interface_description
                                 .working_directory
-                                = Some(PathBuf::from(wd));
+                                = Some(PathBuf::from(expand(wd)));
                         }
                     }
                     "ExecStart" => {
                         interface_description.exec_start = match directive.value() {
-                            Some(s) => s.to_owned(),
+                            Some(s) => expand(s),
                             None => {
                                 return Err(UnitDescriptionError::MissingValue(
                                     "Interface".to_owned(),
@@ -133,6 +239,16 @@ impl InterfaceDescription {
                             },
                         }
                     }
+                    "Transport" => {
+                        let value = directive.value().map(|v| expand(v));
+                        interface_description.transport = Transport::from_value(value.as_ref().map(|v| v.as_str()))?
+                    }
+                    "ActivateOn" => {
+                        interface_description.activate_on = ActivateOn::from_value("Interface", directive.value())?
+                    }
+                    "Restart" => {
+                        interface_description.restart = RestartPolicy::from_value("Interface", directive.value())?
+                    }
                     &_ => (),
                 },
                 &_ => (),
@@ -141,11 +257,36 @@ impl InterfaceDescription {
         Ok(interface_description)
     }
 
+    /// An interface that runs no program.  What's written to it is kept in `capture`, and
+    /// commands are given to it with `Interface::command()`, e.g. by the testing harness.
+    pub fn in_memory(id: UnitName, capture: &Capture) -> InterfaceDescription {
+        InterfaceDescription {
+            name: format!("In-memory interface {}", id.id()),
+            id: id,
+            description: "".to_owned(),
+            jigs: vec![],
+            format: InterfaceFormat::Text,
+            transport: Transport::Exec,
+            exec_start: "".to_owned(),
+            working_directory: None,
+            unit_directory: PathBuf::from("."),
+            dependencies: UnitDependencies::default(),
+            activate_on: ActivateOn::Start,
+            restart: RestartPolicy::No,
+            capture: Some(capture.clone()),
+        }
+    }
+
     /// Returns true if this test is supported on the named jig.
     pub fn supports_jig(&self, name: &UnitName) -> bool {
         self.jigs.contains(name)
     }
 
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
     /// Determine if a unit is compatible with this system.
     pub fn is_compatible(
         &self,
@@ -167,6 +308,27 @@ impl InterfaceDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn description(&self) -> &String {
+        &self.description
+    }
+
+    /// Returns true if `other` only has a different Name or Description,
+    /// so that a running interface doesn't need to be restarted to pick it up.
+    pub fn differs_only_in_metadata(&self, other: &InterfaceDescription) -> bool {
+        let mut renamed = other.clone();
+        renamed.name = self.name.clone();
+        renamed.description = self.description.clone();
+        renamed == *self
+    }
+
     pub fn load(
         &self,
         manager: &UnitManager,
@@ -180,16 +342,33 @@ impl InterfaceDescription {
 
 pub struct Interface {
     desc: InterfaceDescription,
-    process: RefCell<Option<Running>>,
+    process: RefCell<Option<Supervised>>,
+
+    /// The clients connected to an interface that listens for them, rather than running a program.
+    sessions: RefCell<Option<Sessions>>,
+
     terminate_timeout: Duration,
+
+    /// When the interface was recently restarted after its program exited.
+    restarts: RefCell<Restarts>,
+
+    /// For timing writes, when exclave is run with --instrument.
+    broadcaster: UnitBroadcaster,
+
+    /// What the interface's programs have used.
+    meter: Meter,
 }
 
 impl Interface {
-    pub fn new(desc: &InterfaceDescription, _: &UnitManager, config: &Config) -> Interface {
+    pub fn new(desc: &InterfaceDescription, manager: &UnitManager, config: &Config) -> Interface {
         Interface {
             desc: desc.clone(),
             process: RefCell::new(None),
+            sessions: RefCell::new(None),
             terminate_timeout: config.terminate_timeout().clone(),
+            restarts: RefCell::new(Restarts::new()),
+            broadcaster: manager.broadcaster().clone(),
+            meter: Meter::new(),
         }
     }
 
@@ -197,6 +376,29 @@ impl Interface {
         &self.desc.id
     }
 
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    pub fn activate_on(&self) -> ActivateOn {
+        self.desc.activate_on
+    }
+
+    /// How long the program gets to exit when the interface is deactivated.
+    pub fn set_terminate_timeout(&mut self, timeout: Duration) {
+        self.terminate_timeout = timeout;
+    }
+
+    /// Take on the Name and Description of an updated unit file without restarting.
+    /// Returns false, leaving everything alone, if anything else changed too.
+    pub fn update_metadata(&mut self, desc: &InterfaceDescription) -> bool {
+        if !self.desc.differs_only_in_metadata(desc) {
+            return false;
+        }
+        self.desc = desc.clone();
+        true
+    }
+
     pub fn select(&self) -> Result<(), UnitSelectError> {
         Ok(())
     }
@@ -210,25 +412,41 @@ impl Interface {
         manager: &UnitManager,
         config: &Config,
     ) -> Result<(), UnitActivateError> {
-        let mut running = Runny::new(&self.desc.exec_start)
-            .directory(&Some(config.working_directory(&self.desc.unit_directory, &self.desc.working_directory)))
-            .start()?;
+        let control_sender = manager.get_control_channel();
+        let control_sender_id = self.id().clone();
+        if self.desc.capture.is_some() {
+            control_sender
+                .send(ManagerControlMessage::new(&control_sender_id, ManagerControlMessageContents::InitialGreeting))
+                .ok();
+            return Ok(());
+        }
+        // Clients are greeted as they connect.
+        match self.desc.transport {
+            Transport::Exec => (),
+            Transport::Tcp(address) => {
+                *self.sessions.borrow_mut() = Some(Sessions::listen_tcp(address, &control_sender_id, &control_sender)?);
+                return Ok(());
+            }
+            Transport::Unix(ref path) => {
+                *self.sessions.borrow_mut() = Some(Sessions::listen_unix(path, &control_sender_id, &control_sender)?);
+                return Ok(());
+            }
+        }
+        let working_directory = config.working_directory(&self.desc.unit_directory, &self.desc.working_directory);
+        let mut running = Supervised::start(self.id(), &self.desc.exec_start, working_directory, Some(&self.meter), &control_sender)?;
 
         let stdout = running.take_output();
         let stderr = running.take_error();
 
-        let control_sender = manager.get_control_channel();
-        let control_sender_id = self.id().clone();
         match self.desc.format {
             InterfaceFormat::Text => {
-                // Pass control to an out-of-object thread, and shuttle communications
-                // from stdout onto the control_sender channel.
+                // Have the reactor shuttle communications from stdout onto the control_sender channel.
                 let thr_sender_id = control_sender_id.clone();
                 let thr_sender = control_sender.clone();
-                thread::spawn(move || Self::text_read(thr_sender_id, thr_sender, stdout));
+                reactor::watch_lines(stdout, &control_sender_id, &control_sender, move |line| Self::text_read(&thr_sender_id, &thr_sender, line));
                 let thr_sender_id = control_sender_id.clone();
                 let thr_sender = control_sender.clone();
-                thread::spawn(move || Self::text_read_stderr(thr_sender_id, thr_sender, stderr));
+                reactor::watch_lines(stderr, &control_sender_id, &control_sender, move |line| Self::text_read_stderr(&thr_sender_id, &thr_sender, line));
             }
             InterfaceFormat::JSON => {
                 ();
@@ -248,28 +466,36 @@ impl Interface {
     }
 
     pub fn deactivate(&self) -> Result<(), UnitDeactivateError> {
-        if let Some(process) = self.process.borrow_mut().take() {
-            match process.terminate(Some(self.terminate_timeout)) {
-                Ok(retval) => match retval {
-                    0 => Ok(()),
-                    i => Err(UnitDeactivateError::NonZeroReturn(i)),
-                },
-                Err(e) => Err(UnitDeactivateError::RunningError(e)),
-            }
-        } else {
-            Ok(())
+        if let Some(mut sessions) = self.sessions.borrow_mut().take() {
+            sessions.stop();
+        }
+        match self.process.borrow_mut().take() {
+            Some(process) => process.stop(Some(self.terminate_timeout)),
+            None => Ok(()),
         }
     }
 
+    /// Whether to start the interface again, now that it's failed because of `reason`.  `code`
+    /// is its program's return code, if it exited.  Returns why not if it's to stay stopped.
+    pub fn restart_after_failure(&self, code: Option<i32>, reason: &str) -> Result<(), String> {
+        self.restarts.borrow_mut().allow(self.desc.restart, code, reason)
+    }
+
     /// Cause a MessageControlContents to be written out.
     pub fn output_message(&self, msg: ManagerStatusMessage) -> Result<(), Error> {
+        self.output_messages(vec![msg])
+    }
+
+    /// Cause several messages to be written out in a single write, so that an interface
+    /// that's just started gets the whole state of the station at once.
+    pub fn output_messages(&self, msgs: Vec<ManagerStatusMessage>) -> Result<(), Error> {
         match self.desc.format {
-            InterfaceFormat::Text => self.text_write(msg),
-            InterfaceFormat::JSON => self.json_write(msg),
+            InterfaceFormat::Text => self.text_write(msgs),
+            InterfaceFormat::JSON => self.json_write(msgs),
         }
     }
 
-    fn json_write(&self, _: ManagerStatusMessage) -> Result<(), Error> {
+    fn json_write(&self, _: Vec<ManagerStatusMessage>) -> Result<(), Error> {
         unimplemented!();
     }
 
@@ -280,8 +506,33 @@ impl Interface {
             .replace("\r", "\\r")
     }
 
-    /// Write a UnitInterfaceMessage to a Text-formatted output.
-    fn text_write(&self, msg: ManagerStatusMessage) -> Result<(), Error> {
+    /// Write UnitInterfaceMessages to a Text-formatted output.  They're formatted
+    /// first, so that the interface gets them all in one write.
+    fn text_write(&self, msgs: Vec<ManagerStatusMessage>) -> Result<(), Error> {
+        if let Some(ref capture) = self.desc.capture {
+            let mut buffer = vec![];
+            for msg in msgs {
+                Self::text_format(&mut buffer, msg)?;
+            }
+            capture.write(&buffer);
+            return Ok(());
+        }
+        if let Some(ref sessions) = *self.sessions.borrow() {
+            // The manager's greeting always starts with HELLO.
+            let greeting = match msgs.first() {
+                Some(&ManagerStatusMessage::Hello(_)) => true,
+                _ => false,
+            };
+            let mut buffer = vec![];
+            for msg in msgs {
+                Self::text_format(&mut buffer, msg)?;
+            }
+            let started = self.broadcaster.timer();
+            sessions.write_all(&buffer, greeting);
+            self.broadcaster.timed(TimingKind::InterfaceWrite, self.id(), started);
+            return Ok(());
+        }
+
         let mut process_opt = self.process.borrow_mut();
 
         if process_opt.is_none() {
@@ -290,82 +541,173 @@ impl Interface {
 
         let process = process_opt.as_mut().unwrap();
 
+        let mut buffer = vec![];
+        for msg in msgs {
+            Self::text_format(&mut buffer, msg)?;
+        }
+        let started = self.broadcaster.timer();
+        let result = process.write_all(&buffer);
+        self.broadcaster.timed(TimingKind::InterfaceWrite, self.id(), started);
+        result
+    }
+
+    /// Format a UnitInterfaceMessage as a line of text.
+    fn text_format(out: &mut Vec<u8>, msg: ManagerStatusMessage) -> Result<(), Error> {
         match msg {
             ManagerStatusMessage::Jig(j) => match j {
                 Some(jig_name) => writeln!(
-                    process,
+                    out,
                     "JIG {}",
                     Self::cfti_escape(&format!("{}", jig_name))
                 ),
-                None => writeln!(process, "JIG"),
+                None => writeln!(out, "JIG"),
             },
             ManagerStatusMessage::Hello(id) => {
-                writeln!(process, "HELLO {}", Self::cfti_escape(&format!("{}", id)))
+                writeln!(out, "HELLO {}", Self::cfti_escape(&format!("{}", id)))
             }
             ManagerStatusMessage::Tests(scenario, tests) => {
-                write!(process, "TESTS {}", Self::cfti_escape(scenario.id()))?;
-                for test in &tests {
-                    write!(process, " {}", Self::cfti_escape(test.id()))?;
+                write!(out, "TESTS {}", Self::cfti_escape(scenario.id()))?;
+                for test in tests.iter() {
+                    write!(out, " {}", Self::cfti_escape(test.id()))?;
                 }
-                writeln!(process, "")
+                writeln!(out, "")
             }
             ManagerStatusMessage::Scenario(name) => match name {
-                Some(s) => writeln!(process, "SCENARIO {}", Self::cfti_escape(s.id())),
-                None => writeln!(process, "SCENARIO"),
+                Some(s) => writeln!(out, "SCENARIO {}", Self::cfti_escape(s.id())),
+                None => writeln!(out, "SCENARIO"),
             },
             ManagerStatusMessage::Scenarios(list) => {
-                write!(process, "SCENARIOS")?;
+                write!(out, "SCENARIOS")?;
                 for scenario_name in list {
-                    write!(process, " {}", Self::cfti_escape(scenario_name.id()))?;
+                    write!(out, " {}", Self::cfti_escape(scenario_name.id()))?;
                 }
-                writeln!(process, "")
+                writeln!(out, "")
             }
             ManagerStatusMessage::Describe(id, field, value) => writeln!(
-                process,
+                out,
                 "DESCRIBE {}",
                 Self::cfti_escape(&format!("{} {} {} {}", id.kind(), field, id.id(), value))
             ),
             ManagerStatusMessage::Log(l) => writeln!(
-                process,
-                "LOG {}\t{}\t{}\t{}\t{}\t{}",
+                out,
+                "LOG {}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
                 l.kind().as_str(),
                 Self::cfti_escape(l.id().id()),
                 l.id().kind(),
                 l.secs(),
                 l.nsecs(),
-                Self::cfti_escape(l.message())
+                Self::cfti_escape(l.message()),
+                l.timestamp(),
+                l.serial().map(|s| Self::cfti_escape(s)).unwrap_or_default()
             ),
             ManagerStatusMessage::Running(test) => {
-                writeln!(process, "RUNNING {}", Self::cfti_escape(test.id()))
+                writeln!(out, "RUNNING {}", Self::cfti_escape(test.id()))
             }
             ManagerStatusMessage::Skipped(test, reason) => writeln!(
-                process,
+                out,
                 "SKIP {} {}",
                 Self::cfti_escape(test.id()),
                 Self::cfti_escape(&reason)
             ),
             ManagerStatusMessage::Finished(scenario, result, reason) => writeln!(
-                process,
+                out,
                 "FINISH {} {} {}",
                 Self::cfti_escape(scenario.id()),
                 result,
                 Self::cfti_escape(&reason)
             ),
             ManagerStatusMessage::Fail(test, _code, reason) => writeln!(
-                process,
+                out,
                 "FAIL {} {}",
                 Self::cfti_escape(test.id()),
                 Self::cfti_escape(&reason)
             ),
             ManagerStatusMessage::Pass(test, reason) => writeln!(
-                process,
+                out,
                 "PASS {} {}",
                 Self::cfti_escape(test.id()),
                 Self::cfti_escape(&reason)
             ),
             ManagerStatusMessage::Start(scenario) => {
-                writeln!(process, "START {}", Self::cfti_escape(scenario.id()))
-            } /*
+                writeln!(out, "START {}", Self::cfti_escape(scenario.id()))
+            }
+            // The audit log and stats only go to loggers.
+            ManagerStatusMessage::Audit(_) | ManagerStatusMessage::Stats(_) => Ok(()),
+            ManagerStatusMessage::Status(id, status) => writeln!(
+                out,
+                "STATUS {} {} {} {}",
+                id.kind(),
+                Self::cfti_escape(id.id()),
+                status.state(),
+                Self::cfti_escape(&format!("{}", status))
+            ),
+            ManagerStatusMessage::Transition(t) => writeln!(
+                out,
+                "TRANSITION {} {} {} {} {} {}",
+                t.id().kind(),
+                Self::cfti_escape(t.id().id()),
+                t.from().map(|s| s.to_string()).unwrap_or("none".to_owned()),
+                t.to(),
+                t.reason(),
+                Self::cfti_escape(t.message())
+            ),
+            ManagerStatusMessage::Yield(y) => {
+                let percent = |f: Option<f64>| f.map(|f| format!("{:.1}", f * 100.0)).unwrap_or("-".to_owned());
+                write!(
+                    out,
+                    "YIELD {} {} {} {}",
+                    y.window.as_secs(),
+                    y.runs,
+                    percent(y.first_pass_yield),
+                    percent(y.retest_rate)
+                )?;
+                for (test, count) in y.top_failures {
+                    write!(out, " {}={}", Self::cfti_escape(&test), count)?;
+                }
+                writeln!(out, "")
+            }
+            ManagerStatusMessage::Artifact(run, artifact) => writeln!(
+                out,
+                "ARTIFACT {} {} {} {} {}",
+                Self::cfti_escape(&run),
+                Self::cfti_escape(&artifact.test.unwrap_or("-".to_owned())),
+                artifact.size,
+                artifact.sha256,
+                Self::cfti_escape(&artifact.path)
+            ),
+            ManagerStatusMessage::Interrupted(scenario, run, serial, passed) => writeln!(
+                out,
+                "INTERRUPTED {} {} {} {}",
+                Self::cfti_escape(&scenario),
+                Self::cfti_escape(&run.unwrap_or("-".to_owned())),
+                Self::cfti_escape(&serial.unwrap_or("-".to_owned())),
+                passed
+            ),
+            ManagerStatusMessage::Golden(scenario, verdict, message) => writeln!(
+                out,
+                "GOLDEN {} {} {}",
+                Self::cfti_escape(scenario.id()),
+                verdict,
+                Self::cfti_escape(&message)
+            ),
+            ManagerStatusMessage::ArtifactData(run, path, data) => writeln!(
+                out,
+                "ARTIFACTDATA {} {} {}",
+                Self::cfti_escape(&run),
+                Self::cfti_escape(&path),
+                data
+            ),
+            ManagerStatusMessage::Operator(operator) => match operator {
+                Some(operator) => writeln!(out, "OPERATOR {}", Self::cfti_escape(&operator)),
+                None => writeln!(out, "OPERATOR"),
+            },
+            ManagerStatusMessage::LoginFailed(operator, reason) => writeln!(
+                out,
+                "LOGINFAILED {} {}",
+                Self::cfti_escape(&operator),
+                Self::cfti_escape(&reason)
+            ),
+            /*
             //            BroadcastMessageContents::Hello(name) => writeln!(stdin,
             //                                                "HELLO {}", name),
             //            BroadcastMessageContents::Ping(val) => writeln!(stdin,
@@ -410,116 +752,199 @@ impl Interface {
         out
     }
 
+    /// Log a line from stderr.  Returns false once there's no one left to send lines to.
     fn text_read_stderr(
-        id: UnitName,
-        control: Sender<ManagerControlMessage>,
-        output: RunningOutput,
-    ) {
-        for line in BufReader::new(output).lines() {
-            let line = line.expect("Unable to get next line");
-            // If the send fails, that means the other end has closed the pipe.
-            if let Err(_) = control.send(ManagerControlMessage::new(
-                &id,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        line: Option<String>,
+    ) -> bool {
+        let line = match line {
+            Some(line) => line,
+            None => return false,
+        };
+        // If the send fails, that means the other end has closed the pipe.
+        control
+            .send(ManagerControlMessage::new(
+                id,
                 ManagerControlMessageContents::LogError(line),
-            )) {
-                break;
-            }
-        }
+            )).is_ok()
     }
 
-    fn text_read(id: UnitName, control: Sender<ManagerControlMessage>, stdout: RunningOutput) {
-        for line in BufReader::new(stdout).lines() {
-            let line = line.expect("Unable to get next line");
-            let mut words: Vec<String> = line
-                .split_whitespace()
-                .map(|x| Self::cfti_unescape(x.to_owned()))
-                .collect();
+    /// Handle a line of CFTI as if interface `id`'s program had printed it, e.g. for an in-memory interface.
+    pub fn command(id: &UnitName, control: &Sender<ManagerControlMessage>, line: &str) {
+        Self::text_read(id, control, Some(line.to_owned()));
+    }
 
-            // Don't crash if we get a blank line.
-            if words.len() == 0 {
-                continue;
-            }
+    /// Handle a command from stdout.  The interface exiting is reported by its supervisor.
+    /// Returns false once there's no one left to send commands to.
+    fn text_read(id: &UnitName, control: &Sender<ManagerControlMessage>, line: Option<String>) -> bool {
+        let line = match line {
+            Some(line) => line,
+            None => return false,
+        };
+        let mut words: Vec<String> = line
+            .split_whitespace()
+            .map(|x| Self::cfti_unescape(x.to_owned()))
+            .collect();
+
+        // Don't crash if we get a blank line.
+        if words.len() == 0 {
+            return true;
+        }
 
-            let verb = words[0].to_lowercase();
-            words.remove(0);
-
-            let response = match verb.as_str() {
-                "scenarios" => ManagerControlMessageContents::Scenarios,
-                "scenario" => match UnitName::from_str(
-                    words
-                        .get(0)
-                        .unwrap_or(&"".to_owned())
-                        .to_lowercase()
-                        .as_str(),
-                    "scenario",
-                ) {
-                    Err(e) => ManagerControlMessageContents::Error(format!(
+        let verb = words[0].to_lowercase();
+        words.remove(0);
+
+        let response = match verb.as_str() {
+            "scenarios" => ManagerControlMessageContents::Scenarios,
+            "scenario" => match UnitName::from_str(
+                words
+                    .get(0)
+                    .unwrap_or(&"".to_owned())
+                    .to_lowercase()
+                    .as_str(),
+                "scenario",
+            ) {
+                Err(e) => ManagerControlMessageContents::Error(format!(
+                    "Invalid scenario name: {}",
+                    e
+                )),
+                Ok(o) => ManagerControlMessageContents::Scenario(o),
+            },
+            "tests" => {
+                if words.is_empty() {
+                    ManagerControlMessageContents::Tests(None)
+                } else {
+                    match UnitName::from_str(words[0].to_lowercase().as_str(), "test") {
+                        Ok(scenario_name) => {
+                            ManagerControlMessageContents::Tests(Some(scenario_name))
+                        }
+                        Err(e) => ManagerControlMessageContents::Error(format!(
+                            "Invalid test name specified: {}",
+                            e
+                        )),
+                    }
+                }
+            }
+            "jig" => ManagerControlMessageContents::Jig,
+            "status" => ManagerControlMessageContents::Status(words.first().map(|w| w.to_lowercase())),
+            "yield" => ManagerControlMessageContents::Yield,
+            "artifacts" => ManagerControlMessageContents::Artifacts(words.first().cloned()),
+            "login" => match words.len() {
+                1 => ManagerControlMessageContents::Operator(Some(words[0].to_owned())),
+                0 => ManagerControlMessageContents::Error("No operator specified".to_owned()),
+                _ => ManagerControlMessageContents::Error("Operator ids can't contain spaces".to_owned()),
+            },
+            "logout" => ManagerControlMessageContents::Operator(None),
+            "tag" => {
+                // TAG key=value [key=value ...]
+                let tags: Option<Vec<(String, String)>> = words.iter().map(|w| split_variable(w)).collect();
+                match tags {
+                    Some(ref tags) if !tags.is_empty() => ManagerControlMessageContents::Tag(tags.clone()),
+                    _ => ManagerControlMessageContents::Error("Invalid tag, expected key=value".to_owned()),
+                }
+            }
+            "recover" => match words.first().map(|w| w.to_lowercase()) {
+                Some(ref w) if w == "resume" => ManagerControlMessageContents::Recover(true),
+                Some(ref w) if w == "invalidate" => ManagerControlMessageContents::Recover(false),
+                _ => ManagerControlMessageContents::Error("Invalid recovery, expected resume or invalidate".to_owned()),
+            },
+            "golden" => {
+                // GOLDEN <record|verify> [scenario]
+                let mode = words.first().and_then(|w| GoldenMode::from_str(w));
+                let scenario = words.get(1).map(|w| UnitName::from_str(w.to_lowercase().as_str(), "scenario"));
+                match (mode, scenario) {
+                    (None, _) => ManagerControlMessageContents::Error("Invalid golden run, expected record or verify".to_owned()),
+                    (_, Some(Err(e))) => ManagerControlMessageContents::Error(format!("Invalid scenario name: {}", e)),
+                    (Some(mode), scenario) => {
+                        control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Golden(mode))).ok();
+                        ManagerControlMessageContents::StartScenario(scenario.and_then(|s| s.ok()))
+                    }
+                }
+            }
+            "artifact" => match (words.get(0), words.get(1)) {
+                (Some(run), Some(path)) => ManagerControlMessageContents::Artifact(run.clone(), path.clone()),
+                _ => ManagerControlMessageContents::Error("Expected a run and the path of an artifact".to_owned()),
+            },
+            "log" => ManagerControlMessageContents::Log(words.join(" ")),
+            "start" => {
+                // START [scenario] [key=value ...]
+                let scenario = match words.first() {
+                    Some(word) if !word.contains('=') => {
+                        Some(UnitName::from_str(word.to_lowercase().as_str(), "scenario"))
+                    }
+                    _ => None,
+                };
+                let skip = if scenario.is_some() { 1 } else { 0 };
+                let variables: Option<Vec<(String, String)>> =
+                    words.iter().skip(skip).map(|w| split_variable(w)).collect();
+                match (scenario, variables) {
+                    (Some(Err(e)), _) => ManagerControlMessageContents::Error(format!(
                         "Invalid scenario name: {}",
                         e
                     )),
-                    Ok(o) => ManagerControlMessageContents::Scenario(o),
-                },
-                "tests" => {
-                    if words.is_empty() {
-                        ManagerControlMessageContents::Tests(None)
-                    } else {
-                        match UnitName::from_str(words[0].to_lowercase().as_str(), "test") {
-                            Ok(scenario_name) => {
-                                ManagerControlMessageContents::Tests(Some(scenario_name))
-                            }
-                            Err(e) => ManagerControlMessageContents::Error(format!(
-                                "Invalid test name specified: {}",
-                                e
-                            )),
+                    (_, None) => ManagerControlMessageContents::Error(
+                        "Invalid variable, expected key=value".to_owned(),
+                    ),
+                    (scenario, Some(variables)) => {
+                        // The serial number and variables go first, so they're in place when the scenario starts.
+                        let mut messages = start_messages(scenario.and_then(|s| s.ok()), variables);
+                        let start = messages.pop().unwrap();
+                        for msg in messages {
+                            control.send(ManagerControlMessage::new(&id, msg)).ok();
                         }
+                        start
                     }
                 }
-                "jig" => ManagerControlMessageContents::Jig,
-                "log" => ManagerControlMessageContents::Log(words.join(" ")),
-                "start" => {
-                    if words.is_empty() {
-                        ManagerControlMessageContents::StartScenario(None)
-                    } else {
-                        match UnitName::from_str(
-                            words
-                                .get(0)
-                                .unwrap_or(&"".to_owned())
-                                .to_lowercase()
-                                .as_str(),
-                            "scenario",
-                        ) {
-                            Err(e) => ManagerControlMessageContents::Error(format!(
-                                "Invalid scenario name: {}",
-                                e
-                            )),
-                            Ok(o) => ManagerControlMessageContents::StartScenario(Some(o)),
-                        }
-                    }
+            }
+            "trigger" => match words.first() {
+                None => ManagerControlMessageContents::Error("No trigger specified".to_owned()),
+                Some(word) => match UnitName::from_str(word.to_lowercase().as_str(), "trigger") {
+                    Err(e) => ManagerControlMessageContents::Error(format!("Invalid trigger name: {}", e)),
+                    Ok(o) => ManagerControlMessageContents::FireTrigger(o),
+                },
+            },
+            "operator" => {
+                if words.is_empty() {
+                    ManagerControlMessageContents::Operator(None)
+                } else {
+                    ManagerControlMessageContents::Operator(Some(words.join(" ")))
                 }
-                "shutdown" => {
-                    if words.is_empty() {
-                        ManagerControlMessageContents::Shutdown(None)
-                    } else {
-                        ManagerControlMessageContents::Shutdown(Some(words.join(" ")))
-                    }
+            }
+            "jigstate" => match words.first().and_then(|w| JigHealth::from_str(w)) {
+                Some(health) => ManagerControlMessageContents::JigState(health, words[1..].join(" ")),
+                None => ManagerControlMessageContents::Error(
+                    "Invalid jig state, expected healthy, degraded, calibrating, or swapping".to_owned(),
+                ),
+            },
+            "serial" => {
+                if words.is_empty() {
+                    ManagerControlMessageContents::Error("No serial number specified".to_owned())
+                } else {
+                    ManagerControlMessageContents::Serial(words.join(" "))
                 }
-                /*
-                "abort" => ControlMessageContents::AbortTests,
-                "pong" => ControlMessageContents::Pong(words[0].to_lowercase()),
-                "hello" => ControlMessageContents::Hello(words.join(" ")),
-                */
-                v => ManagerControlMessageContents::Unimplemented(v.to_owned(), words.join(" ")),
-            };
-
-            // If the send fails, that means the other end has closed the pipe.
-            if let Err(_) = control.send(ManagerControlMessage::new(&id, response)) {
-                break;
             }
-        }
-        control
-            .send(ManagerControlMessage::new(
-                &id,
-                ManagerControlMessageContents::ChildExited,
-            )).expect("interface couldn't send exit message to controller");
+            "rescan" => ManagerControlMessageContents::Rescan,
+            "profile" => match words.first() {
+                Some(profile) => ManagerControlMessageContents::Profile(profile.clone()),
+                None => ManagerControlMessageContents::Error("No profile specified".to_owned()),
+            },
+            "shutdown" => {
+                if words.is_empty() {
+                    ManagerControlMessageContents::Shutdown(None)
+                } else {
+                    ManagerControlMessageContents::Shutdown(Some(words.join(" ")))
+                }
+            }
+            /*
+            "abort" => ControlMessageContents::AbortTests,
+            "pong" => ControlMessageContents::Pong(words[0].to_lowercase()),
+            "hello" => ControlMessageContents::Hello(words.join(" ")),
+            */
+            v => ManagerControlMessageContents::Unimplemented(v.to_owned(), words.join(" ")),
+        };
+
+        // If the send fails, that means the other end has closed the pipe.
+        control.send(ManagerControlMessage::new(id, response)).is_ok()
     }
 }
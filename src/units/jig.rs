@@ -1,17 +1,15 @@
-extern crate runny;
 extern crate systemd_parser;
 
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::io::Read;
-use std::fs::File;
 
 use config::Config;
-use unit::{UnitActivateError, UnitDeactivateError, UnitDescriptionError, UnitIncompatibleReason,
+use program::Exec;
+use unit::{check_format_version, parse_unit_file, read_unit_file, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError, UnitIncompatibleReason,
            UnitName, UnitSelectError, UnitDeselectError};
 use unitmanager::UnitManager;
 
 use self::systemd_parser::items::DirectiveEntry;
-use self::runny::Runny;
 
 /// A struct defining an in-memory representation of a .jig file
 #[derive(Clone)]
@@ -34,6 +32,9 @@ pub struct JigDescription {
     /// The path to the unit file,
     unit_directory: PathBuf,
 
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
     /// A program to run to determine if this jig is compatible, if any
     test_program: Option<String>,
 
@@ -42,33 +43,34 @@ pub struct JigDescription {
 }
 
 impl JigDescription {
-    pub fn from_path(path: &Path) -> Result<JigDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<JigDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
         Self::from_string(&contents, unit_name, path)
     }
 
     pub fn from_string(contents: &str, unit_name: UnitName, path: &Path) -> Result<JigDescription, UnitDescriptionError> {
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Jig") {
             return Err(UnitDescriptionError::MissingSection("Jig".to_owned()));
         }
 
         let mut jig_description = JigDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
             default_scenario: None,
             working_directory: None,
             unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
             test_program: None,
             test_file: None,
         };
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Jig") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => match directive.key() {
@@ -78,7 +80,7 @@ impl JigDescription {
                     }
                     "WorkingDirectory" | "DefaultWorkingDirectory" => {
                         if let Some(wd) = directive.value() {
-                            jig_description.working_directory = Some(PathBuf::from(wd));
+                            jig_description.working_directory = Some(PathBuf::from(expand(wd)));
                         }
                     }
                     "TestFile" => {
@@ -95,7 +97,7 @@ impl JigDescription {
                     }
                     "TestProgram" => {
                         jig_description.test_program = match directive.value() {
-                            Some(s) => Some(s.to_owned()),
+                            Some(s) => Some(expand(s)),
                             None => None,
                         }
                     }
@@ -114,6 +116,11 @@ impl JigDescription {
         _: &UnitManager,
         config: &Config,
     ) -> Result<(), UnitIncompatibleReason> {
+        // When simulating, e.g. on the bench, any jig will do.
+        if config.simulate() {
+            return Ok(());
+        }
+
         // If this Jig has a file-existence test, run it.
         if let Some(ref test_file) = self.test_file {
             if !Path::new(&test_file).exists() {
@@ -125,26 +132,24 @@ impl JigDescription {
 
         // If this Jig has a test-program, run that program and check the output.
         if let Some(ref cmd_str) = self.test_program {
-            use std::io::{BufRead, BufReader};
+            use std::io::{BufReader, Read};
 
-            let running = Runny::new(cmd_str)
+            let running = Exec::new(cmd_str)
                 .directory(&Some(config.working_directory(&self.unit_directory, &self.working_directory).clone()))
                 .timeout(config.timeout().clone())
-                .path(config.paths().clone())
                 .start()?;
 
+            // Read the output as bytes, as a program that prints something other than UTF-8
+            // would otherwise stop it being read partway through, before the program exits.
             let mut reader = BufReader::new(running);
-            let mut buf = String::new();
-            loop {
-                if let Err(_) = reader.read_line(&mut buf) {
-                    break;
-                }
-            }
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).ok();
+            reader.get_mut().wait().ok();
             let result = reader.get_ref().result();
             if result != 0 {
                 return Err(UnitIncompatibleReason::TestProgramReturnedNonzero(
                     result,
-                    buf,
+                    String::from_utf8_lossy(&bytes).into_owned(),
                 ));
             }
         }
@@ -155,6 +160,10 @@ impl JigDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
     pub fn load(
         &self,
         manager: &UnitManager,
@@ -166,17 +175,76 @@ impl JigDescription {
     }
 }
 
+/// How fit the jig is for testing, as reported by an interface or trigger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum JigHealth {
+    /// Ready to test.
+    Healthy,
+
+    /// Running, but something is wrong with it, e.g. a worn fixture.
+    Degraded,
+
+    /// Being calibrated.
+    Calibrating,
+
+    /// Having its fixture or device under test swapped.
+    Swapping,
+}
+
+impl JigHealth {
+    pub fn from_str(s: &str) -> Option<JigHealth> {
+        match s.to_lowercase().as_ref() {
+            "healthy" => Some(JigHealth::Healthy),
+            "degraded" => Some(JigHealth::Degraded),
+            "calibrating" => Some(JigHealth::Calibrating),
+            "swapping" => Some(JigHealth::Swapping),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JigHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JigHealth::Healthy => write!(f, "healthy"),
+            JigHealth::Degraded => write!(f, "degraded"),
+            JigHealth::Calibrating => write!(f, "calibrating"),
+            JigHealth::Swapping => write!(f, "swapping"),
+        }
+    }
+}
+
 pub struct Jig {
     description: JigDescription,
+
+    /// How fit the jig is for testing, and why.
+    health: JigHealth,
+    health_reason: String,
 }
 
 impl Jig {
     pub fn new(desc: &JigDescription) -> Jig {
         Jig {
             description: desc.clone(),
+            health: JigHealth::Healthy,
+            health_reason: "".to_owned(),
         }
     }
 
+    pub fn health(&self) -> JigHealth {
+        self.health
+    }
+
+    pub fn set_health(&mut self, health: JigHealth, reason: String) {
+        self.health = health;
+        self.health_reason = reason;
+    }
+
+    /// The jig's health as a description, e.g. "calibrating weekly calibration".
+    pub fn state(&self) -> String {
+        format!("{} {}", self.health, self.health_reason).trim().to_owned()
+    }
+
     pub fn id(&self) -> &UnitName {
         &self.description.id
     }
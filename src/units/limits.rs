@@ -0,0 +1,384 @@
+extern crate systemd_parser;
+
+use std::path::Path;
+
+use config::Config;
+use unit::{check_format_version, parse_unit_file, read_unit_file, UnitDependencies, UnitDescriptionError, UnitIncompatibleReason, UnitName};
+use unitmanager::UnitManager;
+
+use self::systemd_parser::items::DirectiveEntry;
+
+/// The variable that says which SKU is being tested, unless otherwise specified.
+const DEFAULT_SKU_VARIABLE: &str = "SKU";
+
+/// The specification for one measurement, as given by a Limit= directive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limit {
+    /// The name the test reports the measurement under.
+    name: String,
+
+    /// The SKU this limit applies to, or None if it applies to every SKU without one of its own.
+    sku: Option<String>,
+
+    /// The lowest acceptable value, if there is one.
+    min: Option<f64>,
+
+    /// The highest acceptable value, if there is one.
+    max: Option<f64>,
+
+    /// The value the measurement ought to be, which is only reported.
+    nominal: Option<f64>,
+
+    /// The unit the limits are in, e.g. "V".
+    unit: Option<String>,
+}
+
+/// How a measurement compared with its limit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitCheck {
+    /// How far the measurement is from the nearest limit, in the limit's unit: positive if
+    /// it's within the limits, and negative if it's outside them.  None if it isn't a number.
+    pub margin: Option<f64>,
+
+    /// The unit the limit is in, if it has one.
+    pub unit: Option<String>,
+
+    /// Why the measurement is out of spec, or None if it isn't.
+    pub problem: Option<String>,
+}
+
+impl Limit {
+    /// Parse one or more limits from a Limit= directive in `section`.  Several limits may
+    /// share a line, separated by commas, which is how TOML unit files list them.
+    pub fn parse_list(section: &str, value: &str) -> Result<Vec<Limit>, UnitDescriptionError> {
+        let mut limits = vec![];
+        for value in value.split(',') {
+            if !value.trim().is_empty() {
+                limits.push(Limit::parse(section, value.trim())?);
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Parse a limit of the form `<name> [sku=<sku>] [min=<n>] [max=<n>] [nominal=<n>] [unit=<unit>]`.
+    fn parse(section: &str, value: &str) -> Result<Limit, UnitDescriptionError> {
+        let invalid = |why: &str| UnitDescriptionError::InvalidValue(section.to_owned(), "Limit".to_owned(), value.to_owned(), vec![why.to_owned()]);
+        let number = |s: &str| s.parse::<f64>().map_err(|_| invalid("min=, max=, and nominal= are numbers"));
+
+        let mut words = value.split_whitespace();
+        let mut limit = Limit {
+            name: words.next().ok_or(invalid("<name> [sku=<sku>] [min=<n>] [max=<n>] [nominal=<n>] [unit=<unit>]"))?.to_owned(),
+            sku: None,
+            min: None,
+            max: None,
+            nominal: None,
+            unit: None,
+        };
+        for word in words {
+            let mut parts = word.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("sku"), Some(v)) => limit.sku = Some(v.to_owned()),
+                (Some("min"), Some(v)) => limit.min = Some(number(v)?),
+                (Some("max"), Some(v)) => limit.max = Some(number(v)?),
+                (Some("nominal"), Some(v)) => limit.nominal = Some(number(v)?),
+                (Some("unit"), Some(v)) => limit.unit = Some(v.to_owned()),
+                _ => return Err(invalid("sku=, min=, max=, nominal=, or unit=")),
+            }
+        }
+        if limit.min.is_none() && limit.max.is_none() {
+            return Err(invalid("a limit with a min= or max="));
+        }
+        if let (Some(min), Some(max)) = (limit.min, limit.max) {
+            if min > max {
+                return Err(invalid("a min= no greater than max="));
+            }
+        }
+        Ok(limit)
+    }
+
+    /// The lower specification limit, if there is one.
+    pub fn min(&self) -> Option<f64> {
+        self.min
+    }
+
+    /// The upper specification limit, if there is one.
+    pub fn max(&self) -> Option<f64> {
+        self.max
+    }
+
+    pub fn unit(&self) -> Option<&String> {
+        self.unit.as_ref()
+    }
+
+    /// A value along with the unit the limit is in, if it has one.
+    fn with_unit(&self, value: f64) -> String {
+        match self.unit {
+            Some(ref unit) => format!("{} {}", value, unit),
+            None => format!("{}", value),
+        }
+    }
+
+    /// How far `value` is from the nearest limit, if there's a minimum or maximum.
+    fn margin(&self, value: f64) -> Option<f64> {
+        let above_min = self.min.map(|min| value - min);
+        let below_max = self.max.map(|max| max - value);
+        match (above_min, below_max) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (m, None) | (None, m) => m,
+        }
+    }
+
+    /// Check a measurement against this limit.
+    fn check(&self, value: &str, unit: Option<&str>) -> LimitCheck {
+        let mut check = LimitCheck {
+            margin: None,
+            unit: self.unit.clone(),
+            problem: None,
+        };
+        let value = match value.parse::<f64>() {
+            Ok(v) => v,
+            Err(_) => {
+                check.problem = Some(format!("{} is not a number", value));
+                return check;
+            }
+        };
+        if let (Some(reported), Some(expected)) = (unit, self.unit.as_ref()) {
+            if reported != expected {
+                check.problem = Some(format!("reported in {}, but the limit is in {}", reported, expected));
+                return check;
+            }
+        }
+        check.margin = self.margin(value);
+        let problem = match (self.min, self.max) {
+            (Some(min), _) if value < min => format!("{} is below the minimum of {}", self.with_unit(value), self.with_unit(min)),
+            (_, Some(max)) if value > max => format!("{} is above the maximum of {}", self.with_unit(value), self.with_unit(max)),
+            _ => return check,
+        };
+        check.problem = Some(match self.nominal {
+            Some(nominal) => format!("{} (nominal {})", problem, self.with_unit(nominal)),
+            None => problem,
+        });
+        check
+    }
+}
+
+/// The limit for a measurement: the one for the SKU being tested if there is one, or else the one for every SKU.
+fn find_limit<'a>(limits: &'a [Limit], name: &str, sku: Option<&str>) -> Option<&'a Limit> {
+    let candidates = || limits.iter().filter(|l| l.name == name);
+    candidates()
+        .find(|l| sku.is_some() && l.sku.as_ref().map(|s| s.as_str()) == sku)
+        .or_else(|| candidates().find(|l| l.sku.is_none()))
+}
+
+/// The limit in a test's own unit file for a measurement, if it has one, for the SKU
+/// named by the run's "SKU" variable.
+pub fn test_limit<'a>(limits: &'a [Limit], name: &str, variables: &[(String, String)]) -> Option<&'a Limit> {
+    let sku = variables
+        .iter()
+        .find(|&&(ref key, _)| key == DEFAULT_SKU_VARIABLE)
+        .map(|&(_, ref value)| value.as_str());
+    find_limit(limits, name, sku)
+}
+
+/// Check a measurement that a test reported against the limits in its own unit file.
+/// Returns None if the test has no limit for it.
+pub fn check_test_limits(limits: &[Limit], name: &str, value: &str, unit: Option<&str>, variables: &[(String, String)]) -> Option<LimitCheck> {
+    let limit = test_limit(limits, name, variables)?;
+    let mut check = limit.check(value, unit);
+    check.problem = check.problem.map(|problem| match limit.sku {
+        Some(ref sku) => format!("{}: {} for SKU {}", name, problem, sku),
+        None => format!("{}: {}", name, problem),
+    });
+    Some(check)
+}
+
+/// A margin or measurement as it's written in the log, without floating-point noise
+/// such as "0.22999999999999954".
+pub fn format_number(n: f64) -> String {
+    let s = format!("{:.6}", n);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0".to_owned() } else { s.to_owned() }
+}
+
+/// A struct defining an in-memory representation of a .limits file
+#[derive(Clone)]
+pub struct LimitsDescription {
+    /// The id of the unit (including the kind)
+    id: UnitName,
+
+    /// A short name
+    name: String,
+
+    /// A detailed description of these limits, up to one paragraph.
+    description: String,
+
+    /// A Vec<String> of jig names that these limits are compatible with.
+    jigs: Vec<UnitName>,
+
+    /// The tests whose measurements are checked, or every test if it's empty.
+    tests: Vec<UnitName>,
+
+    /// The run variable that says which SKU is being tested, for picking per-SKU limits.
+    sku_variable: String,
+
+    /// Every limit, including the per-SKU ones.
+    limits: Vec<Limit>,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+}
+
+impl LimitsDescription {
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<LimitsDescription, UnitDescriptionError> {
+        // Parse the file into a systemd unit_file object
+        let contents = read_unit_file(&unit_name, path)?;
+        Self::from_string(&contents, unit_name, path)
+    }
+
+    pub fn from_string(contents: &str, unit_name: UnitName, _path: &Path) -> Result<LimitsDescription, UnitDescriptionError> {
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
+
+        if !unit_file.has_category("Limits") {
+            return Err(UnitDescriptionError::MissingSection("Limits".to_owned()));
+        }
+
+        let mut limits_description = LimitsDescription {
+            id: unit_name.clone(),
+            name: "".to_owned(),
+            description: "".to_owned(),
+            jigs: vec![],
+            tests: vec![],
+            sku_variable: DEFAULT_SKU_VARIABLE.to_owned(),
+            limits: vec![],
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+        };
+
+        for entry in unit_file.lookup_by_category("Limits") {
+            // Limit= is the only directive that's expected to be given more than once.
+            let directives = match entry {
+                &DirectiveEntry::Solo(ref directive) => vec![directive],
+                &DirectiveEntry::Many(ref directives) => directives.iter().collect(),
+            };
+            for directive in directives {
+                match directive.key() {
+                    "Name" => limits_description.name = directive.value().unwrap_or("").to_owned(),
+                    "Description" => {
+                        limits_description.description = directive.value().unwrap_or("").to_owned()
+                    }
+                    "Jigs" => {
+                        limits_description.jigs = match directive.value() {
+                            Some(s) => UnitName::from_list(s, "jig")?,
+                            None => vec![],
+                        }
+                    }
+                    "Tests" => {
+                        limits_description.tests = match directive.value() {
+                            Some(s) => UnitName::from_list(s, "test")?,
+                            None => vec![],
+                        }
+                    }
+                    "SkuVariable" => {
+                        limits_description.sku_variable = directive.value().unwrap_or(DEFAULT_SKU_VARIABLE).to_owned()
+                    }
+                    "Limit" => {
+                        limits_description.limits.extend(Limit::parse_list("Limits", directive.value().unwrap_or(""))?)
+                    }
+                    &_ => (),
+                }
+            }
+        }
+
+        if limits_description.limits.is_empty() {
+            return Err(UnitDescriptionError::MissingValue("Limits".to_owned(), "Limit".to_owned()));
+        }
+        Ok(limits_description)
+    }
+
+    /// Returns true if these limits are supported on the named jig.
+    pub fn supports_jig(&self, name: &UnitName) -> bool {
+        self.jigs.contains(name)
+    }
+
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
+    /// Determine if a unit is compatible with this system.
+    pub fn is_compatible(
+        &self,
+        manager: &UnitManager,
+        _: &Config,
+    ) -> Result<(), UnitIncompatibleReason> {
+        if self.jigs.len() == 0 {
+            return Ok(());
+        }
+        for jig_name in &self.jigs {
+            if manager.jig_is_loaded(&jig_name) {
+                return Ok(());
+            }
+        }
+        Err(UnitIncompatibleReason::IncompatibleJig)
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.id
+    }
+
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
+    pub fn load(
+        &self,
+        manager: &UnitManager,
+        config: &Config,
+    ) -> Result<Limits, UnitIncompatibleReason> {
+        self.is_compatible(manager, config)?;
+
+        Ok(Limits::new(self))
+    }
+}
+
+pub struct Limits {
+    description: LimitsDescription,
+}
+
+impl Limits {
+    pub fn new(desc: &LimitsDescription) -> Limits {
+        Limits {
+            description: desc.clone(),
+        }
+    }
+
+    /// Check a measurement that `test` reported against these limits, using the limits for
+    /// the SKU named by the run's variables if there are any.  Returns None if there's no
+    /// limit for it.
+    pub fn check(&self, test: &UnitName, name: &str, value: &str, unit: Option<&str>, variables: &[(String, String)]) -> Option<LimitCheck> {
+        let desc = &self.description;
+        let limit = self.limit_for(test, name, variables)?;
+
+        let mut check = limit.check(value, unit);
+        let source = if desc.name.is_empty() { desc.id.to_string() } else { desc.name.clone() };
+        check.problem = check.problem.map(|problem| match limit.sku {
+            Some(ref sku) => format!("{}: {} for SKU {} ({})", name, problem, sku, source),
+            None => format!("{}: {} ({})", name, problem, source),
+        });
+        Some(check)
+    }
+
+    /// The limit these limits set for a measurement that `test` reports, if there is one.
+    pub fn limit_for(&self, test: &UnitName, name: &str, variables: &[(String, String)]) -> Option<&Limit> {
+        let desc = &self.description;
+        if !desc.tests.is_empty() && !desc.tests.contains(test) {
+            return None;
+        }
+
+        let sku = variables
+            .iter()
+            .find(|&&(ref key, _)| *key == desc.sku_variable)
+            .map(|&(_, ref value)| value.as_str());
+        find_limit(&desc.limits, name, sku)
+    }
+}
@@ -1,34 +1,86 @@
-extern crate runny;
 extern crate serde_json;
 extern crate systemd_parser;
 
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::time::Duration;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::thread::JoinHandle;
 
 use config::Config;
-use unit::{UnitActivateError, UnitDeactivateError, UnitDescriptionError, UnitDeselectError,
+use logsinks::LogSink;
+use logsinks::csv::{CsvLayout, CsvSink};
+use logsinks::elastic::{ElasticsearchSink, LogstashSink};
+use logsinks::metrics::{InfluxSink, PrometheusSink};
+use logsinks::mqtt::MqttSink;
+use program::ProgramInput;
+use reactor;
+use supervisor::{self, Restarts, Supervised};
+use unit::{check_format_version, parse_unit_file, read_unit_file, ActivateOn, RestartPolicy, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError, UnitDeselectError,
            UnitIncompatibleReason, UnitName, UnitSelectError};
-use unitbroadcaster::LogEntry;
+use audit::AuditEntry;
+use unitbroadcaster::{LogEntry, UnitTransition};
+use usage::{Meter, ProcessStats};
 use unitmanager::{ManagerControlMessage, ManagerControlMessageContents, ManagerStatusMessage,
                   UnitManager};
 
 use self::systemd_parser::items::DirectiveEntry;
-use self::runny::Runny;
-use self::runny::running::{Running, RunningOutput};
 
-#[derive(Clone, Copy)]
+/// How many messages may be waiting for a logger before the overflow policy kicks in.
+const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+/// How many events Elasticsearch and Logstash loggers send at once, at most.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Where a logger sends its messages.
+#[derive(Clone, Copy, PartialEq)]
+enum LoggerType {
+    /// Feed messages to the program named in ExecStart.
+    Program,
+
+    /// Append test measurements to a CSV file.
+    Csv,
+
+    /// Push run and test statistics to InfluxDB.
+    InfluxDB,
+
+    /// Serve run and test statistics for Prometheus to scrape.
+    Prometheus,
+
+    /// Publish run summaries and test failures to an MQTT broker.
+    Mqtt,
+
+    /// Index events in Elasticsearch, using its bulk API.
+    Elasticsearch,
+
+    /// Send events to a Logstash tcp input.
+    Logstash,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum LoggerFormat {
     TSV,
     JSON,
 }
 
+/// What to do when a logger can't keep up and its queue is full.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum LoggerOverflow {
+    /// Wait for the logger to catch up.
+    Block,
+
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+
+    /// Kill the logger process and start a fresh one with an empty queue.
+    Restart,
+}
+
 /// A struct defining an in-memory representation of a .logger file
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct LoggerDescription {
     /// The id of the unit (including the kind)
     id: UnitName,
@@ -42,6 +94,9 @@ pub struct LoggerDescription {
     /// A Vec<String> of jig names that this test is compatible with.
     jigs: Vec<UnitName>,
 
+    /// Which backend the logger uses
+    logger_type: LoggerType,
+
     /// Path to the command to start the logger
     exec_start: String,
 
@@ -54,35 +109,102 @@ pub struct LoggerDescription {
     /// The path to the unit file
     unit_directory: PathBuf,
 
-    /// How long to wait for a terminate() call
-    terminate_timeout: Duration,
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
+    /// The maximum number of messages that may be queued for the logger
+    queue_depth: usize,
+
+    /// What to do when the queue is full
+    overflow: LoggerOverflow,
+
+    /// Whether to restart the logger if it exits or stops accepting messages
+    restart: RestartPolicy,
+
+    /// Whether the logger receives the audit log of control commands
+    audit: bool,
+
+    /// Whether the logger receives a record of every unit moving between stages of its lifecycle
+    lifecycle: bool,
+
+    /// Whether the logger receives the CPU time, memory, and wall time used by each program
+    stats: bool,
+
+    /// When to start the logger
+    activate_on: ActivateOn,
+
+    /// The file that built-in loggers write to
+    path: Option<PathBuf>,
+
+    /// How a CSV logger lays out its measurements
+    layout: CsvLayout,
+
+    /// Run variables that a CSV logger gives a column each
+    variables: Vec<String>,
+
+    /// Where an InfluxDB, MQTT, Elasticsearch, or Logstash logger sends its data
+    url: Option<String>,
+
+    /// The most events an Elasticsearch or Logstash logger sends at once
+    batch_size: usize,
+
+    /// The prefix for topics an MQTT logger publishes to
+    topic: String,
+
+    /// Credentials for the MQTT broker
+    username: Option<String>,
+    password: Option<String>,
+
+    /// The address a Prometheus logger listens on
+    listen: Option<String>,
+
+    /// A name for this station, attached to metrics
+    station: Option<String>,
 }
 
 impl LoggerDescription {
-    pub fn from_path(path: &Path) -> Result<LoggerDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<LoggerDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Logger") {
             return Err(UnitDescriptionError::MissingSection("Logger".to_owned()));
         }
 
         let mut logger_description = LoggerDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
             jigs: vec![],
+            logger_type: LoggerType::Program,
             format: LoggerFormat::TSV,
             exec_start: "".to_owned(),
             working_directory: None,
             unit_directory: path.parent().unwrap().to_owned(),
-            terminate_timeout: Duration::from_secs(5),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            overflow: LoggerOverflow::Block,
+            restart: RestartPolicy::No,
+            audit: false,
+            lifecycle: false,
+            stats: false,
+            activate_on: ActivateOn::Start,
+            path: None,
+            layout: CsvLayout::Long,
+            variables: vec![],
+            url: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            topic: "exclave".to_owned(),
+            username: None,
+            password: None,
+            listen: None,
+            station: None,
         };
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Logger") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => match directive.key() {
@@ -98,12 +220,134 @@ impl LoggerDescription {
                     }
                     "WorkingDirectory" => {
                         if let Some(wd) = directive.value() {
-                            logger_description.working_directory = Some(PathBuf::from(wd));
+                            logger_description.working_directory = Some(PathBuf::from(expand(wd)));
+                        }
+                    }
+                    "Type" => {
+                        logger_description.logger_type = match directive.value() {
+                            None => LoggerType::Program,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "program" => LoggerType::Program,
+                                "csv" => LoggerType::Csv,
+                                "influxdb" => LoggerType::InfluxDB,
+                                "prometheus" => LoggerType::Prometheus,
+                                "mqtt" => LoggerType::Mqtt,
+                                "elasticsearch" => LoggerType::Elasticsearch,
+                                "logstash" => LoggerType::Logstash,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Type".to_owned(),
+                                        other.to_owned(),
+                                        vec![
+                                            "program".to_owned(),
+                                            "csv".to_owned(),
+                                            "influxdb".to_owned(),
+                                            "prometheus".to_owned(),
+                                            "mqtt".to_owned(),
+                                            "elasticsearch".to_owned(),
+                                            "logstash".to_owned(),
+                                        ],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Path" => {
+                        logger_description.path = directive.value().map(|s| PathBuf::from(s));
+                    }
+                    "Restart" => {
+                        logger_description.restart = RestartPolicy::from_value("Logger", directive.value())?
+                    }
+                    "Audit" => {
+                        logger_description.audit = match directive.value() {
+                            None => false,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "yes" => true,
+                                "no" => false,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Audit".to_owned(),
+                                        other.to_owned(),
+                                        vec!["yes".to_owned(), "no".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Lifecycle" => {
+                        logger_description.lifecycle = match directive.value() {
+                            None => false,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "yes" => true,
+                                "no" => false,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Lifecycle".to_owned(),
+                                        other.to_owned(),
+                                        vec!["yes".to_owned(), "no".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Stats" => {
+                        logger_description.stats = match directive.value() {
+                            None => false,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "yes" => true,
+                                "no" => false,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Stats".to_owned(),
+                                        other.to_owned(),
+                                        vec!["yes".to_owned(), "no".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "ActivateOn" => logger_description.activate_on = ActivateOn::from_value("Logger", directive.value())?,
+                    "Url" => logger_description.url = directive.value().map(|s| s.to_owned()),
+                    "Topic" => {
+                        logger_description.topic = directive.value().unwrap_or("exclave").to_owned()
+                    }
+                    "Username" => logger_description.username = directive.value().map(|s| s.to_owned()),
+                    "Password" => logger_description.password = directive.value().map(|s| s.to_owned()),
+                    "Listen" => logger_description.listen = directive.value().map(|s| s.to_owned()),
+                    "Station" => logger_description.station = directive.value().map(|s| s.to_owned()),
+                    "Variables" => {
+                        logger_description.variables = directive
+                            .value()
+                            .unwrap_or("")
+                            .split(|c| c == ',' || c == ' ')
+                            .filter(|s| !s.is_empty())
+                            .map(|s| s.to_owned())
+                            .collect();
+                    }
+                    "Layout" => {
+                        logger_description.layout = match directive.value() {
+                            None => CsvLayout::Long,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "long" => CsvLayout::Long,
+                                "wide" => CsvLayout::Wide,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Layout".to_owned(),
+                                        other.to_owned(),
+                                        vec!["long".to_owned(), "wide".to_owned()],
+                                    ))
+                                }
+                            },
                         }
                     }
                     "ExecStart" => {
                         logger_description.exec_start = match directive.value() {
-                            Some(s) => s.to_owned(),
+                            Some(s) => expand(s),
                             None => {
                                 return Err(UnitDescriptionError::MissingValue(
                                     "Logger".to_owned(),
@@ -129,11 +373,77 @@ impl LoggerDescription {
                             },
                         }
                     }
+                    "BatchSize" => {
+                        logger_description.batch_size = match directive.value() {
+                            None => DEFAULT_BATCH_SIZE,
+                            Some(s) => s.parse::<usize>()?,
+                        }
+                    }
+                    "QueueDepth" => {
+                        logger_description.queue_depth = match directive.value() {
+                            None => DEFAULT_QUEUE_DEPTH,
+                            Some(s) => s.parse::<usize>()?,
+                        }
+                    }
+                    "Overflow" => {
+                        logger_description.overflow = match directive.value() {
+                            None => LoggerOverflow::Block,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "block" => LoggerOverflow::Block,
+                                "drop-oldest" => LoggerOverflow::DropOldest,
+                                "restart" => LoggerOverflow::Restart,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Logger".to_owned(),
+                                        "Overflow".to_owned(),
+                                        other.to_owned(),
+                                        vec![
+                                            "block".to_owned(),
+                                            "drop-oldest".to_owned(),
+                                            "restart".to_owned(),
+                                        ],
+                                    ))
+                                }
+                            },
+                        }
+                    }
                     &_ => (),
                 },
                 &_ => (),
             }
         }
+        let missing = match logger_description.logger_type {
+            LoggerType::Csv if logger_description.path.is_none() => Some("Path"),
+            LoggerType::InfluxDB | LoggerType::Mqtt | LoggerType::Elasticsearch | LoggerType::Logstash
+                if logger_description.url.is_none() =>
+            {
+                Some("Url")
+            }
+            LoggerType::Prometheus if logger_description.listen.is_none() => Some("Listen"),
+            _ => None,
+        };
+        if let Some(key) = missing {
+            return Err(UnitDescriptionError::MissingValue(
+                "Logger".to_owned(),
+                key.to_owned(),
+            ));
+        }
+        if logger_description.batch_size == 0 {
+            return Err(UnitDescriptionError::InvalidValue(
+                "Logger".to_owned(),
+                "BatchSize".to_owned(),
+                "0".to_owned(),
+                vec!["a number greater than 0".to_owned()],
+            ));
+        }
+        if logger_description.queue_depth == 0 {
+            return Err(UnitDescriptionError::InvalidValue(
+                "Logger".to_owned(),
+                "QueueDepth".to_owned(),
+                "0".to_owned(),
+                vec!["a number greater than 0".to_owned()],
+            ));
+        }
         Ok(logger_description)
     }
 
@@ -142,6 +452,11 @@ impl LoggerDescription {
         self.jigs.contains(name)
     }
 
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
     /// Determine if a unit is compatible with this system.
     pub fn is_compatible(
         &self,
@@ -163,6 +478,27 @@ impl LoggerDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn description(&self) -> &String {
+        &self.description
+    }
+
+    /// Returns true if `other` only has a different Name or Description,
+    /// so that a running logger doesn't need to be restarted to pick it up.
+    pub fn differs_only_in_metadata(&self, other: &LoggerDescription) -> bool {
+        let mut renamed = other.clone();
+        renamed.name = self.name.clone();
+        renamed.description = self.description.clone();
+        renamed == *self
+    }
+
     pub fn load(
         &self,
         manager: &UnitManager,
@@ -174,16 +510,195 @@ impl LoggerDescription {
     }
 }
 
+/// Messages waiting to be written out to a logger.
+///
+/// Writes to the logger happen on their own thread, so a logger that stops
+/// reading its input only ever fills this queue rather than stalling the
+/// rest of the system.  What happens once the queue fills up is governed by
+/// the logger's `Overflow=` policy.
+pub(crate) struct LoggerQueue {
+    state: Mutex<LoggerQueueState>,
+
+    /// Signalled when a message is added, or when the queue is closed.
+    not_empty: Condvar,
+
+    /// Signalled when a message is removed, or when the queue is closed.
+    not_full: Condvar,
+
+    /// Signalled when the writer has finished with the queue.
+    finished: Condvar,
+
+    depth: usize,
+}
+
+struct LoggerQueueState {
+    messages: VecDeque<ManagerStatusMessage>,
+
+    /// How many messages have been dropped since the writer last checked.
+    dropped: u64,
+
+    /// Set once the logger is going away.  The writer stops once it's written what's left.
+    closed: bool,
+
+    /// Set once the writer has stopped taking messages.
+    done: bool,
+
+    /// Set if the writer was unable to write to the logger.
+    failure: Option<String>,
+}
+
+impl LoggerQueue {
+    pub(crate) fn new(depth: usize) -> LoggerQueue {
+        LoggerQueue {
+            state: Mutex::new(LoggerQueueState {
+                messages: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+                done: false,
+                failure: None,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            finished: Condvar::new(),
+            depth: depth,
+        }
+    }
+
+    /// Add a message to the queue, applying the overflow policy if it's full.
+    /// Returns an error of kind `WouldBlock` if the policy is `Restart` and
+    /// the queue is full.
+    pub(crate) fn push(&self, msg: ManagerStatusMessage, overflow: LoggerOverflow) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(ref reason) = state.failure {
+                return Err(Error::new(ErrorKind::Other, reason.clone()));
+            }
+            if state.closed {
+                return Err(Error::new(ErrorKind::Other, "logger is shutting down"));
+            }
+            if state.messages.len() < self.depth {
+                break;
+            }
+            match overflow {
+                LoggerOverflow::Block => state = self.not_full.wait(state).unwrap(),
+                LoggerOverflow::DropOldest => {
+                    state.messages.pop_front();
+                    state.dropped += 1;
+                }
+                LoggerOverflow::Restart => {
+                    return Err(Error::new(ErrorKind::WouldBlock, "logger queue is full"))
+                }
+            }
+        }
+        state.messages.push_back(msg);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the next message.  Returns None once the queue is closed and
+    /// everything in it has been taken.  Also returns the number of messages
+    /// dropped since the last call.
+    pub(crate) fn pop(&self) -> Option<(ManagerStatusMessage, u64)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(msg) = state.messages.pop_front() {
+                let dropped = state.dropped;
+                state.dropped = 0;
+                self.not_full.notify_one();
+                return Some((msg, dropped));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().messages.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    /// Record that the logger could no longer be written to.
+    fn fail(&self, reason: String) {
+        let mut state = self.state.lock().unwrap();
+        state.failure = Some(reason);
+        state.messages.clear();
+        self.not_full.notify_all();
+    }
+
+    /// Stop taking messages.  Those already queued are still written out.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// Record that the writer has stopped taking messages.
+    fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+        self.finished.notify_all();
+    }
+
+    /// Wait for the writer to write out what's left in the queue, but for no
+    /// longer than `timeout`.  There's nothing left to wait for once the logger
+    /// has failed.  Returns false if it's still writing.
+    fn wait_finished(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.done && state.failure.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.finished.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
 pub struct Logger {
     description: LoggerDescription,
-    process: RefCell<Option<Running>>,
+    process: RefCell<Option<Supervised>>,
+
+    /// When the logger was recently restarted after failing.
+    restarts: RefCell<Restarts>,
+
+    /// Messages waiting to be written to the running process.
+    queue: RefCell<Option<Arc<LoggerQueue>>>,
+
+    /// The thread draining the queue.
+    writer: RefCell<Option<JoinHandle<()>>>,
+
+    /// The working directory the logger was started in, used when restarting it.
+    working_directory: RefCell<Option<PathBuf>>,
+
+    /// How long to wait for a terminate() call
+    terminate_timeout: Duration,
+
+    /// Channel back to the manager, kept around for restarting the logger.
+    control: RefCell<Option<Sender<ManagerControlMessage>>>,
+
+    /// What the logger's programs have used.
+    meter: Meter,
 }
 
 impl Logger {
-    pub fn new(desc: &LoggerDescription, _: &UnitManager, _config: &Config) -> Logger {
+    pub fn new(desc: &LoggerDescription, _: &UnitManager, config: &Config) -> Logger {
         Logger {
             description: desc.clone(),
             process: RefCell::new(None),
+            restarts: RefCell::new(Restarts::new()),
+            queue: RefCell::new(None),
+            writer: RefCell::new(None),
+            working_directory: RefCell::new(None),
+            terminate_timeout: config.terminate_timeout().clone(),
+            control: RefCell::new(None),
+            meter: Meter::new(),
         }
     }
 
@@ -191,6 +706,39 @@ impl Logger {
         &self.description.id
     }
 
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    pub fn activate_on(&self) -> ActivateOn {
+        self.description.activate_on
+    }
+
+    /// Whether the logger is sent this kind of message at all.
+    pub fn wants(&self, msg: &ManagerStatusMessage) -> bool {
+        match *msg {
+            ManagerStatusMessage::Audit(_) => self.description.audit,
+            ManagerStatusMessage::Transition(_) => self.description.lifecycle,
+            ManagerStatusMessage::Stats(_) => self.description.stats,
+            _ => true,
+        }
+    }
+
+    /// How long the program gets to exit when the logger is stopped.
+    pub fn set_terminate_timeout(&mut self, timeout: Duration) {
+        self.terminate_timeout = timeout;
+    }
+
+    /// Take on the Name and Description of an updated unit file without restarting.
+    /// Returns false, leaving everything alone, if anything else changed too.
+    pub fn update_metadata(&mut self, desc: &LoggerDescription) -> bool {
+        if !self.description.differs_only_in_metadata(desc) {
+            return false;
+        }
+        self.description = desc.clone();
+        true
+    }
+
     pub fn select(&self) -> Result<(), UnitSelectError> {
         Ok(())
     }
@@ -199,51 +747,227 @@ impl Logger {
         Ok(())
     }
 
-    fn text_read(id: UnitName, control: Sender<ManagerControlMessage>, output: RunningOutput) {
-        for line in BufReader::new(output).lines() {
-            let line = line.expect("Unable to get next line");
-            // If the send fails, that means the other end has closed the pipe.
-            if let Err(_) = control.send(ManagerControlMessage::new(
-                &id,
+    /// Log a line from the logger's output.  Returns false once there's no one left to send lines to.
+    fn text_read(id: &UnitName, control: &Sender<ManagerControlMessage>, line: Option<String>) -> bool {
+        let line = match line {
+            Some(line) => line,
+            None => return false,
+        };
+        // If the send fails, that means the other end has closed the pipe.
+        control
+            .send(ManagerControlMessage::new(
+                id,
                 ManagerControlMessageContents::LogError(line),
-            )) {
+            )).is_ok()
+    }
+
+    /// Runs in its own thread, and drains the queue into the logger's sink.
+    fn queue_write(
+        id: UnitName,
+        control: Sender<ManagerControlMessage>,
+        queue: Arc<LoggerQueue>,
+        mut sink: Box<dyn LogSink>,
+    ) {
+        while let Some((msg, dropped)) = queue.pop() {
+            if dropped > 0 {
+                control
+                    .send(ManagerControlMessage::new(
+                        &id,
+                        ManagerControlMessageContents::LogError(format!(
+                            "logger fell behind, dropped {} messages",
+                            dropped
+                        )),
+                    ))
+                    .ok();
+            }
+            let mut result = sink.write_message(msg);
+            if result.is_ok() && queue.is_empty() {
+                result = sink.flush();
+            }
+            if let Err(e) = result {
+                queue.fail(format!("{}", e));
                 break;
             }
         }
+        queue.finish();
     }
 
-    pub fn activate(
+    /// Start the logger, along with the thread that feeds it.
+    fn start(
         &self,
-        manager: &UnitManager,
-        config: &Config,
+        working_directory: PathBuf,
+        control_sender: Sender<ManagerControlMessage>,
     ) -> Result<(), UnitActivateError> {
-        let mut running = Runny::new(self.description.exec_start.as_str())
-            .directory(&Some(
-                config.working_directory(&self.description.unit_directory, &self.description.working_directory),
-            ))
-            .start()?;
+        let sink: Box<dyn LogSink> = match self.description.logger_type {
+            LoggerType::Program => self.start_program(&working_directory, &control_sender)?,
+            LoggerType::Csv => Box::new(CsvSink::new(
+                working_directory.join(self.description.path.as_ref().unwrap()),
+                self.description.layout,
+                self.description.variables.clone(),
+            )?),
+            LoggerType::InfluxDB => Box::new(InfluxSink::new(
+                self.description.url.as_ref().unwrap(),
+                self.description.station.clone(),
+            )?),
+            LoggerType::Prometheus => Box::new(PrometheusSink::new(
+                self.description.listen.as_ref().unwrap(),
+            )?),
+            LoggerType::Mqtt => Box::new(MqttSink::new(
+                self.description.url.as_ref().unwrap(),
+                self.description.topic.clone(),
+                self.description.username.clone(),
+                self.description.password.clone(),
+                self.description.station.clone(),
+            )?),
+            LoggerType::Elasticsearch => Box::new(ElasticsearchSink::new(
+                self.description.url.as_ref().unwrap(),
+                self.description.batch_size,
+                self.description.station.clone(),
+            )?),
+            LoggerType::Logstash => Box::new(LogstashSink::new(
+                self.description.url.as_ref().unwrap(),
+                self.description.batch_size,
+                self.description.station.clone(),
+            )?),
+        };
+
+        // Writes to the logger go through the queue, so a slow logger can't hold anyone up.
+        let queue = Arc::new(LoggerQueue::new(self.description.queue_depth));
+        let thr_queue = queue.clone();
+        let thr_sender_id = self.id().clone();
+        let thr_sender = control_sender.clone();
+        let writer = supervisor::spawn_worker(self.id().clone(), control_sender.clone(), move || {
+            Self::queue_write(thr_sender_id, thr_sender, thr_queue, sink)
+        });
+
+        *self.queue.borrow_mut() = Some(queue);
+        *self.writer.borrow_mut() = Some(writer);
+        *self.working_directory.borrow_mut() = Some(working_directory);
+        *self.control.borrow_mut() = Some(control_sender);
+        Ok(())
+    }
+
+    /// Start the logger program, and return a sink that writes to its stdin.
+    fn start_program(
+        &self,
+        working_directory: &PathBuf,
+        control_sender: &Sender<ManagerControlMessage>,
+    ) -> Result<Box<dyn LogSink>, UnitActivateError> {
+        let mut running = Supervised::start(self.id(), &self.description.exec_start, working_directory.clone(), Some(&self.meter), control_sender)?;
 
         // Have stdout and stderr log their output.
-        let control_sender = manager.get_control_channel();
         let control_sender_id = self.id().clone();
         let stdout = running.take_output();
         let stderr = running.take_error();
         let thr_sender_id = control_sender_id.clone();
         let thr_sender = control_sender.clone();
-        thread::spawn(move || Self::text_read(thr_sender_id, thr_sender, stdout));
-        thread::spawn(move || {
-            Self::text_read(control_sender_id, control_sender, stderr)
-        });
-
-        let control_sender = manager.get_control_channel();
-        let control_sender_id = self.id().clone();
+        reactor::watch_lines(stdout, &control_sender_id, control_sender, move |line| Self::text_read(&thr_sender_id, &thr_sender, line));
+        let thr_sender_id = control_sender_id.clone();
+        let thr_sender = control_sender.clone();
+        reactor::watch_lines(stderr, &control_sender_id, control_sender, move |line| Self::text_read(&thr_sender_id, &thr_sender, line));
 
+        let sink = ProcessSink {
+            format: self.description.format,
+            input: running.take_input(),
+        };
         *self.process.borrow_mut() = Some(running);
+        Ok(Box::new(sink))
+    }
+
+    /// Close the queue, give the writer until the terminate timeout to write out
+    /// what's left in it, and terminate the logger process.
+    fn stop(&self) -> Result<(), UnitDeactivateError> {
+        if let Some(queue) = self.queue.borrow_mut().take() {
+            queue.close();
+            // A logger that's stopped reading is terminated regardless, which lets the writer go.
+            queue.wait_finished(self.terminate_timeout);
+        }
+        let result = match self.process.borrow_mut().take() {
+            Some(process) => process.stop(Some(self.terminate_timeout)),
+            None => Ok(()),
+        };
+
+        // Wait for the writer to let go of its sink, so a restarted logger can reopen it.
+        if let Some(writer) = self.writer.borrow_mut().take() {
+            writer.join().ok();
+        }
+        result
+    }
+
+    /// Kill the logger and start it up again from scratch.
+    fn restart(&self, reason: &str) -> Result<(), Error> {
+        let working_directory = self.working_directory.borrow().clone();
+        let control_sender = self.control.borrow().clone();
+        let (working_directory, control_sender) = match (working_directory, control_sender) {
+            (Some(wd), Some(c)) => (wd, c),
+            _ => return Err(Error::new(ErrorKind::Other, "logger was never started")),
+        };
+
+        control_sender
+            .send(ManagerControlMessage::new(
+                self.id(),
+                ManagerControlMessageContents::LogError(format!("restarting logger: {}", reason)),
+            ))
+            .ok();
+        // The new logger starts with an empty queue, so the old one isn't written out.
+        if let Some(ref queue) = *self.queue.borrow() {
+            queue.fail(reason.to_owned());
+        }
+        self.stop().ok();
+        if let Err(e) = self.start(working_directory, control_sender.clone()) {
+            return Err(Error::new(ErrorKind::Other, format!("{}", e)));
+        }
+        control_sender
+            .send(ManagerControlMessage::new(
+                self.id(),
+                ManagerControlMessageContents::InitialGreeting,
+            ))
+            .ok();
+        Ok(())
+    }
+
+    /// Restart a logger that has failed, if its Restart= policy allows it.
+    /// `code` is the logger program's return code, if it exited.
+    pub fn restart_after_failure(&self, code: Option<i32>, reason: &str) -> Result<(), Error> {
+        self.restarts
+            .borrow_mut()
+            .allow(self.description.restart, code, reason)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        self.restart(reason)
+    }
+
+    /// Called when the logger process exited without being asked to.
+    /// Returns Ok if the logger is running again (or was never really down),
+    /// or an error describing why it's staying stopped.
+    pub fn recover(&self) -> Result<(), Error> {
+        let status = match *self.process.borrow() {
+            // Not a program, or it's already been stopped or replaced.
+            None => return Ok(()),
+            Some(ref process) => process.exit_status(),
+        };
+        match status {
+            // The exit belonged to a process that has since been replaced.
+            None => Ok(()),
+            Some(0) => self.restart_after_failure(Some(0), "logger exited cleanly"),
+            Some(code) => self.restart_after_failure(Some(code), &format!("logger exited with code {}", code)),
+        }
+    }
+
+    pub fn activate(
+        &self,
+        manager: &UnitManager,
+        config: &Config,
+    ) -> Result<(), UnitActivateError> {
+        let control_sender = manager.get_control_channel();
+        self.start(
+            config.working_directory(&self.description.unit_directory, &self.description.working_directory),
+            control_sender.clone(),
+        )?;
 
         // Send some initial configuration to the client.
         control_sender
             .send(ManagerControlMessage::new(
-                &control_sender_id,
+                self.id(),
                 ManagerControlMessageContents::InitialGreeting,
             ))
             .ok();
@@ -252,51 +976,85 @@ impl Logger {
     }
 
     pub fn deactivate(&self) -> Result<(), UnitDeactivateError> {
-        if let Some(process) = self.process.borrow_mut().take() {
-            match process.terminate(Some(self.description.terminate_timeout)) {
-                Ok(retval) => match retval {
-                    0 => Ok(()),
-                    i => Err(UnitDeactivateError::NonZeroReturn(i)),
-                },
-                Err(e) => Err(UnitDeactivateError::RunningError(e)),
-            }
-        } else {
-            Ok(())
+        self.stop()
+    }
+
+    /// How many messages are waiting to be written out.
+    pub fn queue_len(&self) -> usize {
+        match *self.queue.borrow() {
+            None => 0,
+            Some(ref queue) => queue.len(),
         }
     }
 
-    /// Cause a MessageControlContents to be written out.
+    /// Queue a ManagerStatusMessage to be written out.
     pub fn output_message(&self, msg: ManagerStatusMessage) -> Result<(), Error> {
-        let mut process_opt = self.process.borrow_mut();
+        if !self.wants(&msg) {
+            return Ok(());
+        }
+
+        let result = match *self.queue.borrow() {
+            None => return Err(Error::new(ErrorKind::Other, "logger is not running")),
+            Some(ref queue) => queue.push(msg.clone(), self.description.overflow),
+        };
 
-        if process_opt.is_none() {
-            return Err(Error::new(ErrorKind::Other, "no process running"));
+        match result {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => self.restart("logger queue overflowed")?,
+            Err(e) => self.restart_after_failure(None, &format!("{}", e))?,
+            Ok(()) => return Ok(()),
         }
+        match *self.queue.borrow() {
+            None => Err(Error::new(ErrorKind::Other, "logger is not running")),
+            Some(ref queue) => queue.push(msg, self.description.overflow),
+        }
+    }
+}
 
-        let process = process_opt.as_mut().unwrap();
+/// Feeds messages to a logger program's stdin.
+struct ProcessSink {
+    format: LoggerFormat,
+    input: ProgramInput,
+}
 
+impl LogSink for ProcessSink {
+    fn write_message(&mut self, msg: ManagerStatusMessage) -> Result<(), Error> {
         match msg {
-            ManagerStatusMessage::Log(l) =>
-                match self.description.format {
-                    LoggerFormat::TSV => self.tsv_write(l, process),
-                    LoggerFormat::JSON => self.json_write(l, process),
-                },
+            ManagerStatusMessage::Log(l) => match self.format {
+                LoggerFormat::TSV => Self::tsv_write(l, &mut self.input),
+                LoggerFormat::JSON => Self::json_write(l, &mut self.input),
+            },
+            ManagerStatusMessage::Audit(a) => match self.format {
+                LoggerFormat::TSV => Self::tsv_write_audit(a, &mut self.input),
+                LoggerFormat::JSON => Self::json_write_audit(a, &mut self.input),
+            },
+            ManagerStatusMessage::Transition(t) => match self.format {
+                LoggerFormat::TSV => Self::tsv_write_transition(t, &mut self.input),
+                LoggerFormat::JSON => Self::json_write_transition(t, &mut self.input),
+            },
+            ManagerStatusMessage::Stats(s) => match self.format {
+                LoggerFormat::TSV => Self::tsv_write_stats(s, &mut self.input),
+                LoggerFormat::JSON => Self::json_write_stats(s, &mut self.input),
+            },
             _ => Ok(()),
         }
     }
+}
+
+impl ProcessSink {
+    fn json_write<W: Write>(entry: LogEntry, output: &mut W) -> Result<(), Error> {
+        writeln!(output, "{}", serde_json::to_string(&entry)?)
+    }
+
+    fn json_write_audit<W: Write>(entry: AuditEntry, output: &mut W) -> Result<(), Error> {
+        writeln!(output, "{}", serde_json::to_string(&entry)?)
+    }
+
+    fn json_write_transition<W: Write>(transition: UnitTransition, output: &mut W) -> Result<(), Error> {
+        writeln!(output, "{}", serde_json::to_string(&transition)?)
+    }
 
-    fn json_write(&self, entry: LogEntry, process: &mut Running) -> Result<(), Error> {
-        /*
-        let mut object = json::JsonValue::new_object();
-        object["message_class"] = msg.message_class.into();
-        object["unit_id"] = msg.unit_id.into();
-        object["unit_type"] = msg.unit_type.into();
-        object["unix_time"] = msg.unix_time.into();
-        object["unix_time_nsecs"] = msg.unix_time_nsecs.into();
-        object["message"] = log.into();
-        writeln!(&mut stdin, "{}", json::stringify(object))
-        */
-        writeln!(process, "{}", serde_json::to_string(&entry)?)
+    fn json_write_stats<W: Write>(stats: ProcessStats, output: &mut W) -> Result<(), Error> {
+        writeln!(output, "{}", serde_json::to_string(&stats)?)
     }
 
     fn cfti_escape(msg: &String) -> String {
@@ -307,16 +1065,68 @@ impl Logger {
     }
 
     /// Write a ManagerStatusMessage to a TSV-formatted output.
-    fn tsv_write(&self, l: LogEntry, process: &mut Running) -> Result<(), Error> {
+    fn tsv_write<W: Write>(l: LogEntry, output: &mut W) -> Result<(), Error> {
         writeln!(
-            process,
-            "{}\t{}\t{}\t{}\t{}\t{}",
+            output,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
             l.kind().as_str(),
             Self::cfti_escape(l.id().id()),
             Self::cfti_escape(&format!("{}", l.id().kind())),
             l.secs(),
             l.nsecs(),
-            Self::cfti_escape(l.message())
+            Self::cfti_escape(l.message()),
+            l.timestamp(),
+            l.serial().map(|s| Self::cfti_escape(s)).unwrap_or_default()
+        )
+    }
+
+    /// Write an AuditEntry to a TSV-formatted output.
+    fn tsv_write_audit<W: Write>(a: AuditEntry, output: &mut W) -> Result<(), Error> {
+        writeln!(
+            output,
+            "audit\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            Self::cfti_escape(a.id().id()),
+            Self::cfti_escape(&format!("{}", a.id().kind())),
+            a.secs(),
+            a.nsecs(),
+            Self::cfti_escape(a.operator().as_ref().unwrap_or(&"".to_owned())),
+            Self::cfti_escape(a.verb()),
+            Self::cfti_escape(a.arguments()),
+            a.timestamp()
+        )
+    }
+
+    /// Write a UnitTransition to a TSV-formatted output.
+    fn tsv_write_transition<W: Write>(t: UnitTransition, output: &mut W) -> Result<(), Error> {
+        writeln!(
+            output,
+            "transition\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            Self::cfti_escape(t.id().id()),
+            Self::cfti_escape(&format!("{}", t.id().kind())),
+            t.secs(),
+            t.nsecs(),
+            t.from().map(|s| s.to_string()).unwrap_or("".to_owned()),
+            t.to(),
+            t.reason(),
+            Self::cfti_escape(t.message()),
+            t.timestamp()
+        )
+    }
+
+    /// Write ProcessStats to a TSV-formatted output.  The peak memory is empty if it isn't known.
+    fn tsv_write_stats<W: Write>(s: ProcessStats, output: &mut W) -> Result<(), Error> {
+        writeln!(
+            output,
+            "stats\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            Self::cfti_escape(s.id().id()),
+            Self::cfti_escape(&format!("{}", s.id().kind())),
+            s.secs(),
+            s.nsecs(),
+            s.wall_ms(),
+            s.user_ms(),
+            s.system_ms(),
+            s.max_rss_kb().map(|kb| kb.to_string()).unwrap_or("".to_owned()),
+            s.timestamp()
         )
     }
 }
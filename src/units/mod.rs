@@ -1,6 +1,10 @@
+pub mod custom;
 pub mod interface;
 pub mod jig;
+pub mod limits;
 pub mod logger;
+pub mod resource;
 pub mod scenario;
+pub mod sessions;
 pub mod test;
 pub mod trigger;
\ No newline at end of file
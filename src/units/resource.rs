@@ -0,0 +1,258 @@
+extern crate systemd_parser;
+
+use std::cell::RefCell;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use config::Config;
+use program::Exec;
+use unit::{check_format_version, parse_unit_file, read_unit_file, UnitDependencies, UnitDescriptionError, UnitIncompatibleReason, UnitName};
+use unitmanager::UnitManager;
+
+use self::systemd_parser::items::DirectiveEntry;
+
+/// A struct defining an in-memory representation of a .resource file
+#[derive(Clone)]
+pub struct ResourceDescription {
+    /// The id of the unit (including the kind)
+    id: UnitName,
+
+    /// A short name
+    name: String,
+
+    /// A detailed description of this resource, up to one paragraph.
+    description: String,
+
+    /// A Vec<String> of jig names that this resource is compatible with.
+    jigs: Vec<UnitName>,
+
+    /// Whether several tests may use this resource at once.
+    shared: bool,
+
+    /// ExecOpen: A command to run to get the resource ready, before the first test uses it.
+    exec_open: Option<String>,
+
+    /// ExecClose: A command to run to put the resource away, after the last test is done with it.
+    exec_close: Option<String>,
+
+    /// working_directory: Directory to run programs from, if any.
+    working_directory: Option<PathBuf>,
+
+    /// The path to the unit file
+    unit_directory: PathBuf,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+}
+
+impl ResourceDescription {
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<ResourceDescription, UnitDescriptionError> {
+        // Parse the file into a systemd unit_file object
+        let contents = read_unit_file(&unit_name, path)?;
+        Self::from_string(&contents, unit_name, path)
+    }
+
+    pub fn from_string(contents: &str, unit_name: UnitName, path: &Path) -> Result<ResourceDescription, UnitDescriptionError> {
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
+
+        if !unit_file.has_category("Resource") {
+            return Err(UnitDescriptionError::MissingSection("Resource".to_owned()));
+        }
+
+        let mut resource_description = ResourceDescription {
+            id: unit_name.clone(),
+            name: "".to_owned(),
+            description: "".to_owned(),
+            jigs: vec![],
+            shared: false,
+            exec_open: None,
+            exec_close: None,
+            working_directory: None,
+            unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+        };
+
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
+        for entry in unit_file.lookup_by_category("Resource") {
+            match entry {
+                &DirectiveEntry::Solo(ref directive) => match directive.key() {
+                    "Name" => resource_description.name = directive.value().unwrap_or("").to_owned(),
+                    "Description" => {
+                        resource_description.description = directive.value().unwrap_or("").to_owned()
+                    }
+                    "Jigs" => {
+                        resource_description.jigs = match directive.value() {
+                            Some(s) => UnitName::from_list(s, "jig")?,
+                            None => vec![],
+                        }
+                    }
+                    "Shared" => {
+                        resource_description.shared = match directive.value() {
+                            None => false,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "yes" => true,
+                                "no" => false,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Resource".to_owned(),
+                                        "Shared".to_owned(),
+                                        other.to_owned(),
+                                        vec!["yes".to_owned(), "no".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "ExecOpen" => resource_description.exec_open = directive.value().map(|s| expand(s)),
+                    "ExecClose" => resource_description.exec_close = directive.value().map(|s| expand(s)),
+                    "WorkingDirectory" => {
+                        if let Some(wd) = directive.value() {
+                            resource_description.working_directory = Some(PathBuf::from(expand(wd)));
+                        }
+                    }
+                    &_ => (),
+                },
+                &_ => (),
+            }
+        }
+        Ok(resource_description)
+    }
+
+    /// Returns true if this resource is supported on the named jig.
+    pub fn supports_jig(&self, name: &UnitName) -> bool {
+        self.jigs.contains(name)
+    }
+
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
+    /// Determine if a unit is compatible with this system.
+    pub fn is_compatible(
+        &self,
+        manager: &UnitManager,
+        _: &Config,
+    ) -> Result<(), UnitIncompatibleReason> {
+        if self.jigs.len() == 0 {
+            return Ok(());
+        }
+        for jig_name in &self.jigs {
+            if manager.jig_is_loaded(&jig_name) {
+                return Ok(());
+            }
+        }
+        Err(UnitIncompatibleReason::IncompatibleJig)
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.id
+    }
+
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
+    pub fn load(
+        &self,
+        manager: &UnitManager,
+        config: &Config,
+    ) -> Result<Resource, UnitIncompatibleReason> {
+        self.is_compatible(manager, config)?;
+
+        Ok(Resource::new(self))
+    }
+}
+
+pub struct Resource {
+    description: ResourceDescription,
+
+    /// The tests that are currently using this resource.  The resource is open
+    /// for as long as there are any.
+    holders: RefCell<Vec<UnitName>>,
+}
+
+impl Resource {
+    pub fn new(desc: &ResourceDescription) -> Resource {
+        Resource {
+            description: desc.clone(),
+            holders: RefCell::new(vec![]),
+        }
+    }
+
+    /// Let `test` use this resource, opening it first if nothing else is using it.
+    /// Unless the resource is shared, only one test may use it at a time.
+    pub fn acquire(&self, test: &UnitName, config: &Config) -> Result<(), String> {
+        let mut holders = self.holders.borrow_mut();
+        if holders.contains(test) {
+            return Ok(());
+        }
+        if !self.description.shared {
+            if let Some(holder) = holders.first() {
+                return Err(format!("in use by {}", holder));
+            }
+        }
+        if holders.is_empty() {
+            if let Some(ref cmd) = self.description.exec_open {
+                self.run(cmd, config)?;
+            }
+        }
+        holders.push(test.clone());
+        Ok(())
+    }
+
+    /// Stop `test` from using this resource, closing it if nothing else is using it.
+    /// Returns false if the test wasn't using it.
+    pub fn release(&self, test: &UnitName, config: &Config) -> Result<bool, String> {
+        let mut holders = self.holders.borrow_mut();
+        if !holders.contains(test) {
+            return Ok(false);
+        }
+        holders.retain(|h| h != test);
+        if holders.is_empty() {
+            if let Some(ref cmd) = self.description.exec_close {
+                self.run(cmd, config)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Close the resource if anything is still using it, e.g. because it's being unloaded.
+    pub fn close(&self, config: &Config) -> Result<(), String> {
+        let mut holders = self.holders.borrow_mut();
+        if holders.is_empty() {
+            return Ok(());
+        }
+        holders.clear();
+        match self.description.exec_close {
+            Some(ref cmd) => self.run(cmd, config),
+            None => Ok(()),
+        }
+    }
+
+    /// Run an ExecOpen or ExecClose command to completion.
+    fn run(&self, cmd: &str, config: &Config) -> Result<(), String> {
+        let running = Exec::new(cmd)
+            .directory(&Some(config.working_directory(&self.description.unit_directory, &self.description.working_directory)))
+            .timeout(config.timeout().clone())
+            .start()
+            .map_err(|e| format!("unable to run {}: {:?}", cmd, e))?;
+
+        // Programs don't always print valid UTF-8, and their output is only for the error message.
+        let mut reader = BufReader::new(running);
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).ok();
+        let output = String::from_utf8_lossy(&bytes);
+        reader.get_mut().wait().ok();
+        match reader.get_ref().result() {
+            0 => Ok(()),
+            r => Err(format!("{} returned {}: {}", cmd, r, output.lines().last().unwrap_or("").trim())),
+        }
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.description.id
+    }
+}
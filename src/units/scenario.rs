@@ -1,26 +1,24 @@
 extern crate dependy;
 extern crate humantime;
-extern crate runny;
 extern crate systemd_parser;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
 use std::sync::mpsc::Sender;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use self::dependy::{Dependy, Dependency};
 use self::humantime::{parse_duration, DurationError};
-use self::runny::Runny;
-use self::runny::running::Running;
 use self::systemd_parser::items::DirectiveEntry;
 
 use config::Config;
-use unit::{UnitActivateError, UnitDeactivateError, UnitDescriptionError, UnitIncompatibleReason,
+use program::{Exec, Program};
+use reactor;
+use supervisor;
+use unit::{check_format_version, parse_unit_file, read_unit_file, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError, UnitIncompatibleReason,
            UnitName, UnitSelectError, UnitDeselectError};
 use unitmanager::{ManagerControlMessage, ManagerControlMessageContents,
                   UnitManager};
@@ -89,6 +87,9 @@ pub struct ScenarioDescription {
     /// The path where the .scenario file is
     unit_directory: PathBuf,
 
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
     /// A preflight command to run before the scenario starts.  A failure here will prevent the test from running.
     exec_start: Option<String>,
 
@@ -112,24 +113,22 @@ pub struct ScenarioDescription {
 }
 
 impl ScenarioDescription {
-    pub fn from_path(path: &Path) -> Result<ScenarioDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<ScenarioDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
         Self::from_string(&contents, unit_name, path)
     }
 
     pub fn from_string(contents: &str, unit_name: UnitName, path: &Path) -> Result<ScenarioDescription, UnitDescriptionError> {
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Scenario") {
             return Err(UnitDescriptionError::MissingSection("Scenario".to_owned()));
         }
 
         let mut scenario_description = ScenarioDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
 
@@ -140,6 +139,7 @@ impl ScenarioDescription {
             timeout: None,
 
             unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
             working_directory: None,
 
             exec_start: None,
@@ -156,6 +156,8 @@ impl ScenarioDescription {
         let mut exec_stop = None;
         let mut exec_stop_timeout = None;
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Scenario") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => {
@@ -175,7 +177,7 @@ impl ScenarioDescription {
                         }
                         "WorkingDirectory" => {
                             if let Some(wd) = directive.value() {
-                                Some(PathBuf::from(wd));
+                                scenario_description.working_directory = Some(PathBuf::from(expand(wd)));
                             }
                         }
                         "Tests" => {
@@ -193,7 +195,7 @@ impl ScenarioDescription {
                         "ExecStart" => {
                             scenario_description.exec_start = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStartTimeout" => {
@@ -211,7 +213,7 @@ impl ScenarioDescription {
                         "ExecStopSuccess" => {
                             scenario_description.exec_stop_success = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopSuccessTimeout" => {
@@ -223,7 +225,7 @@ impl ScenarioDescription {
                         "ExecStopFail" => {
                             scenario_description.exec_stop_failure = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopFailTimeout" => {
@@ -235,7 +237,7 @@ impl ScenarioDescription {
                         "ExecStopFailure" => {
                             scenario_description.exec_stop_failure = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopFailureTimeout" => {
@@ -247,7 +249,7 @@ impl ScenarioDescription {
                         "ExecStop" => {
                             exec_stop = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopTimeout" => {
@@ -302,11 +304,20 @@ impl ScenarioDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
     /// Returns true if this scenario is supported on the named jig.
     pub fn supports_jig(&self, name: &UnitName) -> bool {
         self.jigs.contains(name)
     }
 
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
     /// Determine if a unit is compatible with this system.
     pub fn is_compatible(&self,
                          manager: &UnitManager,
@@ -425,6 +436,9 @@ pub struct Scenario {
     /// A list of tests, in the order in which they will run.
     test_sequence: Vec<Rc<RefCell<Test>>>,
 
+    /// The ids of the tests in `test_sequence`, shared with every interface that asks for them.
+    test_ids: Arc<Vec<UnitName>>,
+
     /// A pointer to the tests that are part of this scenario.
     tests: HashMap<UnitName, Rc<RefCell<Test>>>,
 
@@ -451,7 +465,7 @@ pub struct Scenario {
     start_time: Instant,
 
     /// The currently-executing program (if any)
-    program: Rc<RefCell<Option<Running>>>,
+    program: Rc<RefCell<Option<Program>>>,
 }
 
 impl Scenario {
@@ -464,6 +478,7 @@ impl Scenario {
         let mut tests = HashMap::new();
         let mut test_sequence = vec![];
         let mut test_state = HashMap::new();
+        let test_ids = Arc::new(test_order.clone());
 
         for test_name in test_order {
             let test = manager.get_test_named(&test_name).expect("Unable to check out requested test from library");
@@ -476,6 +491,7 @@ impl Scenario {
             description: desc.clone(),
             tests: tests,
             test_sequence: test_sequence,
+            test_ids: test_ids,
             test_states: test_state,
             exec_start_state: Rc::new(RefCell::new(TestState::Pending)),
             state: Rc::new(RefCell::new(ScenarioState::Idle)),
@@ -487,12 +503,8 @@ impl Scenario {
         }
     }
 
-    pub fn test_sequence(&self) -> Vec<UnitName> {
-        let mut test_sequence = vec![];
-        for test in &self.test_sequence {
-            test_sequence.push(test.borrow().id().clone());
-        }
-        test_sequence
+    pub fn test_sequence(&self) -> Arc<Vec<UnitName>> {
+        self.test_ids.clone()
     }
 
     pub fn tests(&self) -> &HashMap<UnitName, Rc<RefCell<Test>>> {
@@ -646,7 +658,7 @@ impl Scenario {
     /// Will emit an AdvanceScenario message upon completion.
     fn run_support_cmd(&self, cmd: &String, ctrl: &Sender<ManagerControlMessage>, timeout: &Option<Duration>, testname: &str) {
         ctrl.send(ManagerControlMessage::new(self.id(), ManagerControlMessageContents::Log(format!("{}: starting [{}]", testname, cmd)))).ok();
-        let mut run_cmd = Runny::new(cmd);
+        let mut run_cmd = Exec::new(cmd);
         if let Some(timeout) = *timeout {
             run_cmd.timeout(timeout);
         }
@@ -668,7 +680,7 @@ impl Scenario {
         let id = self.id().clone();
         let thr_cmd = cmd.clone();
         let thr_testname = testname.to_owned();
-        thread::spawn(move || {
+        supervisor::spawn_worker(id.clone(), ctrl.clone(), move || {
             thr_waiter.wait();
             thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(thr_waiter.result()))).ok();
             thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Log(format!("{}: finished [{}]", thr_testname, thr_cmd)))).ok();
@@ -677,30 +689,22 @@ impl Scenario {
         *self.program.borrow_mut() = Some(running);
     }
 
-    fn log_output(&self, control: &Sender<ManagerControlMessage>, process: &mut Running) {
+    fn log_output(&self, control: &Sender<ManagerControlMessage>, process: &mut Program) {
         
         let stdout = process.take_output();
         let thr_control = control.clone();
         let id = self.id().clone();
-        thread::spawn(move || {
-            for line in BufReader::new(stdout).lines() {
-                let line = line.expect("Unable to get next line");
-                if let Err(_) = thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Log(line))) {
-                    break;
-                }
-            }
+        reactor::watch_lines(stdout, self.id(), control, move |line| match line {
+            Some(line) => thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Log(line))).is_ok(),
+            None => false,
         });
 
         let stderr = process.take_error();
         let thr_control = control.clone();
         let id = self.id().clone();
-        thread::spawn(move || {
-            for line in BufReader::new(stderr).lines() {
-                let line = line.expect("Unable to get next line");
-                if let Err(_) = thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(line))) {
-                    break;
-                }
-            }
+        reactor::watch_lines(stderr, self.id(), control, move |line| match line {
+            Some(line) => thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(line))).is_ok(),
+            None => false,
         });
     }
 
@@ -890,6 +894,30 @@ impl Scenario {
         }
     }
 
+    /// Resume an interrupted run: tests that passed before it was interrupted count as
+    /// passed, and aren't run again.  Returns the ones that are in this scenario, in order.
+    pub fn keep_passed(&self, passed: &[String]) -> Vec<UnitName> {
+        let mut kept = vec![];
+        for test in &self.test_sequence {
+            let id = test.borrow().id().clone();
+            if passed.contains(id.id()) {
+                *self.test_states.get(&id).unwrap().borrow_mut() = TestState::Pass;
+                kept.push(id);
+            }
+        }
+        kept
+    }
+
+    /// The unit whose AdvanceScenario the scenario is waiting on, if it's waiting on one:
+    /// the test that's running, or the scenario itself while one of its own commands runs.
+    pub fn current_step(&self) -> Option<UnitName> {
+        match *self.state.borrow() {
+            ScenarioState::Running(step) => Some(self.test_sequence[step].borrow().id().clone()),
+            ScenarioState::PreStart | ScenarioState::PostSuccess | ScenarioState::PostFailure => Some(self.id().clone()),
+            ScenarioState::Idle | ScenarioState::ScenarioFinished => None,
+        }
+    }
+
     // Determine if Scenario is running or idle
     pub fn is_running(&self) -> bool {
         let s = self.state.borrow();
@@ -0,0 +1,339 @@
+// Interfaces that clients connect to, rather than ones that run a program of their
+// own, so that e.g. a dashboard on another machine can attach to a jig that has no
+// display, or a debugging tool can attach to a running station.  Each client that
+// connects has a session of its own, and speaks CFTI as if it were the interface's
+// program: what it sends is handled as the program's output would be.  A client is
+// greeted with the state of the station, and from then on is sent everything that
+// the interface is sent.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use unit::UnitName;
+use unitmanager::ManagerControlMessage;
+
+/// A connection to one client.
+#[cfg_attr(not(feature = "network"), allow(dead_code))]
+enum Client {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+#[cfg_attr(not(any(unix, feature = "network")), allow(dead_code))]
+impl Client {
+    fn try_clone(&self) -> io::Result<Client> {
+        match *self {
+            Client::Tcp(ref s) => s.try_clone().map(Client::Tcp),
+            #[cfg(unix)]
+            Client::Unix(ref s) => s.try_clone().map(Client::Unix),
+        }
+    }
+
+    /// Block, but for no longer than `read_timeout` and `write_timeout`.
+    fn set_timeouts(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Client::Tcp(ref s) => s
+                .set_nonblocking(false)
+                .and_then(|_| s.set_read_timeout(read_timeout))
+                .and_then(|_| s.set_write_timeout(write_timeout)),
+            #[cfg(unix)]
+            Client::Unix(ref s) => s
+                .set_nonblocking(false)
+                .and_then(|_| s.set_read_timeout(read_timeout))
+                .and_then(|_| s.set_write_timeout(write_timeout)),
+        }
+    }
+
+    fn shutdown(&self) {
+        match *self {
+            Client::Tcp(ref s) => s.shutdown(Shutdown::Both).ok(),
+            #[cfg(unix)]
+            Client::Unix(ref s) => s.shutdown(Shutdown::Both).ok(),
+        };
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Client::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Client::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Client::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Client::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Client::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Client::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// A client's end of the interface, and whether it's been greeted yet.
+struct Session {
+    client: Client,
+    greeted: bool,
+}
+
+/// The clients connected to an interface, and the thread that accepts new ones.
+pub struct Sessions {
+    sessions: Arc<Mutex<Vec<Session>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+
+    /// The socket file, for a Unix socket, which is removed once the interface stops.
+    path: Option<PathBuf>,
+}
+
+impl Sessions {
+    /// Listen on TCP `address` for clients of interface `id`.
+    #[cfg(feature = "network")]
+    pub fn listen_tcp(address: SocketAddr, id: &UnitName, control: &Sender<ManagerControlMessage>) -> io::Result<Sessions> {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Sessions::start(id, control, None, move || {
+            listener.accept().map(|(s, address)| (Client::Tcp(s), address.to_string()))
+        }))
+    }
+
+    #[cfg(not(feature = "network"))]
+    pub fn listen_tcp(_address: SocketAddr, _id: &UnitName, _control: &Sender<ManagerControlMessage>) -> io::Result<Sessions> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "interfaces that listen on TCP aren't supported by this build of exclave",
+        ))
+    }
+
+    /// Listen on a Unix socket at `path` for clients of interface `id`.
+    #[cfg(unix)]
+    pub fn listen_unix(path: &Path, id: &UnitName, control: &Sender<ManagerControlMessage>) -> io::Result<Sessions> {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        // A socket left behind by an instance that didn't shut down cleanly would stop us from binding.
+        // Anything else that's in the way is left alone, and binding fails instead.
+        let stale = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.file_type().is_socket() && UnixStream::connect(path).is_err(),
+            Err(_) => false,
+        };
+        if stale {
+            fs::remove_file(path).ok();
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        // Clients of a Unix socket are usually unnamed, so they're numbered instead.
+        let mut connected = 0;
+        Ok(Sessions::start(id, control, Some(path.to_owned()), move || {
+            let (s, _) = listener.accept()?;
+            connected += 1;
+            Ok((Client::Unix(s), connected.to_string()))
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen_unix(_path: &Path, _id: &UnitName, _control: &Sender<ManagerControlMessage>) -> io::Result<Sessions> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "interfaces that listen on a Unix socket are only supported on Unix",
+        ))
+    }
+
+    #[cfg_attr(not(any(unix, feature = "network")), allow(dead_code))]
+    fn start<F>(id: &UnitName, control: &Sender<ManagerControlMessage>, path: Option<PathBuf>, accept: F) -> Sessions
+    where
+        F: FnMut() -> io::Result<(Client, String)> + Send + 'static,
+    {
+        let sessions = Arc::new(Mutex::new(vec![]));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = listen::start(id, control, &sessions, &stop, accept);
+        Sessions {
+            sessions: sessions,
+            stop: stop,
+            thread: Some(thread),
+            path: path,
+        }
+    }
+
+    /// Send `buffer` to every client that's been greeted.  If it's a greeting, it's only sent to the
+    /// clients that haven't been, so that everyone else isn't sent the same state over again.
+    /// Clients that can't take it are disconnected.
+    pub fn write_all(&self, buffer: &[u8], greeting: bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut connected = vec![];
+        for mut session in sessions.drain(..) {
+            if session.greeted != greeting {
+                if session.client.write_all(buffer).is_err() {
+                    session.client.shutdown();
+                    continue;
+                }
+                session.greeted = true;
+            }
+            connected.push(session);
+        }
+        *sessions = connected;
+    }
+
+    /// Stop listening, and disconnect every client.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        for session in self.sessions.lock().unwrap().drain(..) {
+            session.client.shutdown();
+        }
+        if let Some(path) = self.path.take() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl Drop for Sessions {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg_attr(not(any(unix, feature = "network")), allow(dead_code))]
+mod listen {
+    use std::io::{self, BufRead, BufReader};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use supervisor;
+    use unit::UnitName;
+    use unitmanager::{ManagerControlMessage, ManagerControlMessageContents};
+    use units::interface::Interface;
+
+    use super::{Client, Session};
+
+    /// How often to check for new connections, and whether to stop.
+    const POLL_INTERVAL_MS: u64 = 100;
+
+    /// How long a client has to take what it's sent before it's disconnected, so
+    /// that one that's stopped reading can't hold up the manager.
+    const WRITE_TIMEOUT_MS: u64 = 1000;
+
+    /// Accept clients with `accept`, which mustn't block, on a thread of the interface's.
+    pub fn start<F>(
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        sessions: &Arc<Mutex<Vec<Session>>>,
+        stop: &Arc<AtomicBool>,
+        accept: F,
+    ) -> JoinHandle<()>
+    where
+        F: FnMut() -> io::Result<(Client, String)> + Send + 'static,
+    {
+        let thr_id = id.clone();
+        let thr_control = control.clone();
+        let thr_sessions = sessions.clone();
+        let thr_stop = stop.clone();
+        supervisor::spawn_worker(id.clone(), control.clone(), move || {
+            accept_clients(accept, &thr_id, &thr_control, &thr_sessions, &thr_stop)
+        })
+    }
+
+    fn accept_clients<F>(
+        mut accept: F,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        sessions: &Arc<Mutex<Vec<Session>>>,
+        stop: &Arc<AtomicBool>,
+    ) where
+        F: FnMut() -> io::Result<(Client, String)>,
+    {
+        while !stop.load(Ordering::SeqCst) {
+            let (client, address) = match accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    continue;
+                }
+                Err(e) => {
+                    send(id, control, ManagerControlMessageContents::LogError(format!("unable to accept connection: {}", e)));
+                    continue;
+                }
+            };
+
+            // The listener is non-blocking, but that isn't supposed to carry over to accepted streams.
+            // Reads wake up regularly to see whether the interface has been stopped.
+            let timeouts = client.set_timeouts(
+                Some(Duration::from_millis(POLL_INTERVAL_MS)),
+                Some(Duration::from_millis(WRITE_TIMEOUT_MS)),
+            );
+            let writer = match timeouts.and_then(|_| client.try_clone()) {
+                Ok(writer) => writer,
+                Err(_) => continue,
+            };
+            send(id, control, ManagerControlMessageContents::Log(format!("client {} connected", address)));
+            sessions.lock().unwrap().push(Session {
+                client: writer,
+                greeted: false,
+            });
+
+            let thr_id = id.clone();
+            let thr_control = control.clone();
+            let thr_stop = stop.clone();
+            supervisor::spawn_worker(id.clone(), control.clone(), move || {
+                serve(client, &address, &thr_id, &thr_control, &thr_stop).ok();
+            });
+            send(id, control, ManagerControlMessageContents::InitialGreeting);
+        }
+    }
+
+    /// Read commands from a client a line at a time, until it hangs up or the interface is stopped.
+    fn serve(
+        client: Client,
+        address: &str,
+        id: &UnitName,
+        control: &Sender<ManagerControlMessage>,
+        stop: &Arc<AtomicBool>,
+    ) -> io::Result<()> {
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+
+        while !stop.load(Ordering::SeqCst) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+            Interface::command(id, control, &line);
+            line.clear();
+        }
+        send(id, control, ManagerControlMessageContents::Log(format!("client {} disconnected", address)));
+        Ok(())
+    }
+
+    fn send(id: &UnitName, control: &Sender<ManagerControlMessage>, contents: ManagerControlMessageContents) {
+        control.send(ManagerControlMessage::new(id, contents)).ok();
+    }
+}
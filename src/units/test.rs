@@ -1,32 +1,34 @@
 extern crate dependy;
 extern crate humantime;
 extern crate regex;
-extern crate runny;
 extern crate systemd_parser;
 
 use std::cell::RefCell;
-use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
-use std::thread;
+use std::sync::mpsc::{channel, Sender};
 use std::time::Duration;
 
 use self::dependy::Dependency;
 use self::humantime::{parse_duration, DurationError};
 use self::regex::Regex;
-use self::runny::Runny;
-use self::runny::running::{RunningOutput, RunningWaiter};
 use self::systemd_parser::items::DirectiveEntry;
 
 use config::Config;
-use unit::{UnitName, UnitActivateError, UnitDeactivateError, UnitSelectError, UnitDeselectError,
+use program::{Exec, ProgramOutput, Waiter};
+use reactor::{self, Finished};
+use supervisor;
+use unit::{check_format_version, parse_unit_file, read_unit_file, UnitDependencies, UnitName, UnitActivateError, UnitDeactivateError, UnitSelectError, UnitDeselectError,
            UnitIncompatibleReason, UnitDescriptionError};
 use unitmanager::{ManagerControlMessage, ManagerControlMessageContents,
                   UnitManager};
+use units::limits::Limit;
+use usage::Meter;
+
+/// How long a test's output may carry on after its program has exited before its result is
+/// announced anyway.  Anything its program started that's still running may be holding on to it.
+const OUTPUT_DRAIN_MS: u64 = 1000;
 
 #[derive(Debug, PartialEq, Clone)]
 enum TestType {
@@ -61,6 +63,9 @@ pub struct TestDescription {
     /// want to skip when fixing things in the factory.
     provides: Vec<UnitName>,
 
+    /// A Vec<String> of resources that this test uses, which it holds for as long as it runs.
+    resources: Vec<UnitName>,
+
     /// The maximum duration this test can be run for.
     timeout: Option<Duration>,
 
@@ -81,6 +86,9 @@ pub struct TestDescription {
     /// ExecStart: The command to run as part of this test.
     exec_start: String,
 
+    /// ExecSimulate: The command to run instead of ExecStart when simulating, e.g. on a laptop.
+    exec_simulate: Option<String>,
+
     /// ExecStopFail: When stopping tests, if the test failed, then this stop command will be run.
     exec_stop_failure: Option<String>,
 
@@ -92,27 +100,32 @@ pub struct TestDescription {
 
     /// The path to the unit file
     unit_directory: PathBuf,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
+    /// Limit: The limits this test's measurements must be within.  These take precedence over
+    /// any Limits units that cover the same measurements.
+    limits: Vec<Limit>,
 }
 
 impl TestDescription {
-    pub fn from_path(path: &Path) -> Result<TestDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<TestDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
         Self::from_string(&contents, unit_name, path)
     }
 
     pub fn from_string(contents: &str, unit_name: UnitName, path: &Path) -> Result<TestDescription, UnitDescriptionError> {
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Test") {
             return Err(UnitDescriptionError::MissingSection("Test".to_owned()));
         }
 
         let mut test_description = TestDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
 
@@ -121,6 +134,7 @@ impl TestDescription {
             requires: vec![],
             suggests: vec![],
             provides: vec![],
+            resources: vec![],
 
             timeout: None,
             exec_stop_success_timeout: None,
@@ -131,12 +145,17 @@ impl TestDescription {
             test_daemon_ready: None,
 
             exec_start: "".to_owned(),
+            exec_simulate: None,
             exec_stop_failure: None,
             exec_stop_success: None,
             working_directory: None,
             unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+            limits: vec![],
         };
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Test") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => {
@@ -166,6 +185,12 @@ impl TestDescription {
                                 None => vec![],
                             }
                         }
+                        "Resources" => {
+                            test_description.resources = match directive.value() {
+                                Some(s) => UnitName::from_list(s, "resource")?,
+                                None => vec![],
+                            }
+                        }
                         "Suggests" => {
                             test_description.suggests = match directive.value() {
                                 Some(s) => UnitName::from_list(s, "test")?,
@@ -199,15 +224,16 @@ impl TestDescription {
                             // If a WorkingDirectory was specified, add it to the current directory
                             // (replaces `working_directory` if the new WD is absolute)
                             if let Some(wd) = directive.value() {
-                                test_description.working_directory = Some(PathBuf::from(wd));
+                                test_description.working_directory = Some(PathBuf::from(expand(wd)));
                             }
                         }
                         "ExecStart" => {
                             test_description.exec_start = match directive.value() {
                                 None => return Err(UnitDescriptionError::MissingValue("Test".to_owned(), "ExecStart".to_owned())),
-                                Some(s) => s.to_owned(),
+                                Some(s) => expand(s),
                             }
                         }
+                        "ExecSimulate" => test_description.exec_simulate = directive.value().map(|s| expand(s)),
                         "Timeout" => {
                             test_description.timeout = match directive.value() {
                                 None => None,
@@ -217,7 +243,7 @@ impl TestDescription {
                         "ExecStopSuccess" => {
                             test_description.exec_stop_success = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopSuccessTimeout" => {
@@ -229,7 +255,7 @@ impl TestDescription {
                         "ExecStopFailure" => {
                             test_description.exec_stop_failure = match directive.value() {
                                 None => None,
-                                Some(s) => Some(s.to_owned()),
+                                Some(s) => Some(expand(s)),
                             }
                         }
                         "ExecStopFailureTimeout" => {
@@ -237,15 +263,31 @@ impl TestDescription {
                                 None => None,
                                 Some(s) => Some(Self::parse_time(s)?),
                             }
-                        }                        &_ => (),
+                        }
+                        "Limit" => {
+                            test_description.limits.extend(Limit::parse_list("Test", directive.value().unwrap_or(""))?)
+                        }
+                        &_ => (),
+                    }
+                }
+                // A test with more than one limit may give a Limit= line for each of them.
+                &DirectiveEntry::Many(ref directives) => {
+                    for directive in directives.iter().filter(|d| d.key() == "Limit") {
+                        test_description.limits.extend(Limit::parse_list("Test", directive.value().unwrap_or(""))?)
                     }
                 }
-                &_ => (),
             }
         }
         if test_description.exec_start == "" {
             return Err(UnitDescriptionError::MissingValue("Test".to_owned(), "ExecStart".to_owned()));
         }
+
+        // A test can't run without its resources, so it isn't loaded until they are.
+        for resource in &test_description.resources {
+            if !test_description.dependencies.requires.contains(resource) {
+                test_description.dependencies.requires.push(resource.clone());
+            }
+        }
         Ok(test_description)
     }
 
@@ -261,11 +303,20 @@ impl TestDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
     /// Returns true if this test is supported on the named jig.
     pub fn supports_jig(&self, name: &UnitName) -> bool {
         self.jigs.contains(name)
     }
 
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
     pub fn load(&self, 
         _manager: &UnitManager,
         _config: &Config) -> Result<Test, UnitIncompatibleReason> {
@@ -275,9 +326,12 @@ impl TestDescription {
 
 pub struct Test {
     description: TestDescription,
-    program: Rc<RefCell<Option<RunningWaiter>>>,
+    program: Rc<RefCell<Option<Waiter>>>,
     result_arc: Arc<Mutex<Option<i32>>>,
     last_line: Arc<Mutex<String>>,
+
+    /// What the test's programs have used.
+    meter: Meter,
 }
 
 impl Test {
@@ -287,9 +341,14 @@ impl Test {
             program: Rc::new(RefCell::new(None)),
             result_arc: Arc::new(Mutex::new(None)),
             last_line: Arc::new(Mutex::new("".to_owned())),
+            meter: Meter::new(),
          }
     }
 
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
     pub fn select(&self, manager: &UnitManager) -> Result<(), UnitSelectError> {
         // If there is at least one jig in the description list, then make sure
         // that jig is loaded.
@@ -329,6 +388,11 @@ impl Test {
         }
     }
 
+    /// Report that the test finished with `result`, unless that's already been reported.
+    pub fn finish(&self, ctrl: &Sender<ManagerControlMessage>, result: i32) {
+        Self::send_finished_once(&self.description.id, ctrl, result, &self.result_arc, &self.last_line);
+    }
+
     pub fn activate(
         &mut self,
         manager: &UnitManager,
@@ -344,10 +408,22 @@ impl Test {
         // Announce to the world that we've started considering this test.
         ctrl.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::TestStarted)).ok();
 
-        let cmd = &self.description.exec_start;
+        // When simulating, tests without a command of their own for it pass without running anything.
+        let cmd = if !config.simulate() {
+            &self.description.exec_start
+        } else if let Some(ref cmd) = self.description.exec_simulate {
+            cmd
+        } else {
+            *self.last_line.lock().unwrap() = "simulated".to_owned();
+            ctrl.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Log("simulated, passing without running ExecStart".to_owned()))).ok();
+            Self::send_finished_once(&id, &ctrl, 0, &self.result_arc, &self.last_line);
+            ctrl.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(0))).ok();
+            return Ok(());
+        };
         let timeout = &self.description.timeout;
 
-        let mut cmd = Runny::new(cmd);
+        let mut cmd = Exec::new(cmd);
+        cmd.meter(&self.meter);
         if let Some(timeout) = *timeout {
             cmd.timeout(timeout);
         }
@@ -373,54 +449,40 @@ impl Test {
             TestType::Daemon => {
                 let daemon_ready_string = self.description.test_daemon_ready.clone();
 
-                thread::spawn(move || {
+                supervisor::spawn_worker(id.clone(), ctrl.clone(), move || {
                     Self::log_error(&id, &ctrl, running.take_error(), &last_line);
-                    let buf_reader = BufReader::new(running.take_output());
-                    let buf_lines = buf_reader.lines();
-                    let mut buf_iter = buf_lines.into_iter();
-                    if let Some(ref r) = daemon_ready_string {
-                        let mut found = false;
-                        while let Some(line_result) = buf_iter.next() {
-                            match line_result {
-                                Err(e) => {
-                                    thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(format!("test daemon raised an error: {}", e.description())))).unwrap();
-                                    thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(-2))).ok();
-                                    running.terminate(Some(Duration::from_secs(1))).ok();
-                                    // thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::TestFinished(-2, thr_last_line.lock().unwrap().clone()))).ok();
-                                    Self::send_finished_once(&id, &thr_control, -2, &thr_result_arc, &thr_last_line);
-                                    return;
-                                }
-                                Ok(line) => {
-                                    thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::Log(line.clone()))).unwrap();
-                                    if r.is_match(&line) {
-                                        found = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        if !found {
-                            thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(format!("test daemon exited before ready string was found")))).unwrap();
-                            thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(-1))).ok();
-                            running.terminate(Some(Duration::from_secs(1))).ok();
-                            Self::send_finished_once(&id, &thr_control, -1, &thr_result_arc, &thr_last_line);
-//                            thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::TestFinished(-1, thr_last_line.lock().unwrap().clone()))).ok();
-                            return;
-                        }
-                    }
-                    // Log the output normally, now that the daemon has started up.
+
+                    // Log the output as it comes, and let this thread know once the ready string turns up.
+                    // The sender is dropped along with the output, so the wait ends if the daemon exits first.
+                    let wait_for_ready = daemon_ready_string.is_some();
+                    let (ready_sender, ready) = channel();
+                    let mut ready_string = daemon_ready_string;
                     let thr_thr_control = thr_control.clone();
-                    let thr_thr_last_line = last_line.clone();
                     let thr_id = id.clone();
-                    thread::spawn(move || {
-                        for line in buf_iter {
-                            let line = line.expect("Unable to get next line");
-                            *thr_thr_last_line.lock().unwrap() = line.clone();
-                            if let Err(_) = thr_thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::Log(line))) {
-                                break;
+                    reactor::watch_lines(running.take_output(), &id, &thr_control, move |line| {
+                        let line = match line {
+                            Some(line) => line,
+                            None => return false,
+                        };
+                        // Output from before the daemon has started up doesn't count towards its status.
+                        if let Some(r) = ready_string.take() {
+                            if r.is_match(&line) {
+                                ready_sender.send(()).ok();
+                            } else {
+                                ready_string = Some(r);
                             }
+                        } else {
+                            *last_line.lock().unwrap() = line.clone();
                         }
+                        thr_thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::Log(line))).is_ok()
                     });
+                    if wait_for_ready && ready.recv().is_err() {
+                        thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(format!("test daemon exited before ready string was found")))).ok();
+                        thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(-1))).ok();
+                        running.terminate(Some(Duration::from_secs(1))).ok();
+                        Self::send_finished_once(&id, &thr_control, -1, &thr_result_arc, &thr_last_line);
+                        return;
+                    }
 
                     // Advance to the next test while this one hangs out.
                     thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(0))).ok();
@@ -431,10 +493,16 @@ impl Test {
             TestType::Simple => {
 
                 // Keep a waiter around in a separate thread to send that AdvanceScenario message upon completion.
-                Self::log_output(&id, &ctrl, running.take_output(), &last_line);
-                Self::log_error(&id, &ctrl, running.take_error(), &last_line);
-                thread::spawn(move || {
+                let stdout_finished = Self::log_output(&id, &ctrl, running.take_output(), &last_line);
+                let stderr_finished = Self::log_error(&id, &ctrl, running.take_error(), &last_line);
+                supervisor::spawn_worker(id.clone(), ctrl.clone(), move || {
                     running.wait().ok();
+                    // Let the last of the output get logged before announcing the result.
+                    let drain = Duration::from_millis(OUTPUT_DRAIN_MS);
+                    if !stdout_finished.wait_timeout(drain) || !stderr_finished.wait_timeout(drain) {
+                        thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(
+                            "output is still open after the test exited, so not waiting for the rest of it".to_owned()))).ok();
+                    }
                     Self::send_finished_once(&id, &thr_control, running.result(), &thr_result_arc, &thr_last_line);
                     thr_control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::AdvanceScenario(running.result()))).ok();
                 });
@@ -479,34 +547,38 @@ impl Test {
         &self.description.timeout
     }
 
-    fn log_output(id: &UnitName, control: &Sender<ManagerControlMessage>, stdout: RunningOutput, last_line: &Arc<Mutex<String>>) {
+    pub fn resources(&self) -> &Vec<UnitName> {
+        &self.description.resources
+    }
+
+    pub fn limits(&self) -> &Vec<Limit> {
+        &self.description.limits
+    }
+
+    fn log_output(id: &UnitName, control: &Sender<ManagerControlMessage>, stdout: ProgramOutput, last_line: &Arc<Mutex<String>>) -> Finished {
         let thr_control = control.clone();
         let thr_last_line = last_line.clone();
         let thr_id = id.clone();
-        thread::spawn(move || {
-            for line in BufReader::new(stdout).lines() {
-                let line = line.expect("Unable to get next line");
+        reactor::watch_lines(stdout, id, control, move |line| match line {
+            Some(line) => {
                 *thr_last_line.lock().unwrap() = line.clone();
-                if let Err(_) = thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::Log(line))) {
-                    break;
-                }
+                thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::Log(line))).is_ok()
             }
-        });
+            None => false,
+        })
     }
 
-    fn log_error(id: &UnitName, control: &Sender<ManagerControlMessage>, stderr: RunningOutput, last_line: &Arc<Mutex<String>>) {
+    fn log_error(id: &UnitName, control: &Sender<ManagerControlMessage>, stderr: ProgramOutput, last_line: &Arc<Mutex<String>>) -> Finished {
         let thr_control = control.clone();
         let thr_last_line = last_line.clone();
         let thr_id = id.clone();
-        thread::spawn(move || {
-            for line in BufReader::new(stderr).lines() {
-                let line = line.expect("Unable to get next line");
+        reactor::watch_lines(stderr, id, control, move |line| match line {
+            Some(line) => {
                 *thr_last_line.lock().unwrap() = line.clone();
-                if let Err(_) = thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::LogError(line))) {
-                    break;
-                }
+                thr_control.send(ManagerControlMessage::new(&thr_id, ManagerControlMessageContents::LogError(line))).is_ok()
             }
-        });
+            None => false,
+        })
     }
 }
 
@@ -1,21 +1,97 @@
-extern crate runny;
+extern crate humantime;
+extern crate regex;
 extern crate systemd_parser;
 
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::thread;
+use std::time::{Duration, Instant};
 
 use config::Config;
-use unit::{UnitActivateError, UnitDeactivateError, UnitDescriptionError, UnitIncompatibleReason, UnitSelectError, UnitDeselectError,
+use reactor;
+use supervisor::{Restarts, Supervised};
+use triggers::{self, TriggerThread};
+use triggers::barcode::BarcodeTrigger;
+use triggers::combination::{CombinationMode, CombinationTrigger, Interlock};
+use triggers::gpio::{GpioEdge, GpioTrigger};
+use triggers::network::{NetworkProtocol, NetworkTrigger};
+use triggers::serial::{SerialTrigger, BAUD_RATES};
+use triggers::usb::{UsbTrigger, USB_DEVICES_PATH};
+use unit::{check_format_version, parse_unit_file, read_unit_file, RestartPolicy, UnitActivateError, UnitDeactivateError, UnitDependencies, UnitDescriptionError, UnitIncompatibleReason, UnitSelectError, UnitDeselectError,
            UnitName};
 use unitmanager::{ManagerControlMessage, ManagerControlMessageContents, UnitManager};
 
+use self::humantime::{parse_duration, DurationError};
+use self::regex::Regex;
 use self::systemd_parser::items::DirectiveEntry;
-use self::runny::Runny;
-use self::runny::running::{Running, RunningOutput};
+
+/// How long a GPIO line must hold a new level before it counts, unless otherwise specified.
+const DEFAULT_DEBOUNCE_MS: u64 = 50;
+
+/// The speed of a serial port, unless otherwise specified.
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// How close together the inputs of a combination trigger must fire, unless otherwise specified.
+const DEFAULT_WINDOW_MS: u64 = 500;
+
+/// Where a trigger gets its events from.
+#[derive(Clone, Copy, PartialEq)]
+enum TriggerType {
+    /// Read commands from the program named in ExecStart.
+    Program,
+
+    /// Watch a GPIO line, such as a start button.
+    Gpio,
+
+    /// Read serial numbers from a barcode scanner.
+    Barcode,
+
+    /// Accept start requests over the network, e.g. from a PLC.
+    Network,
+
+    /// Wait for a USB device to be plugged in.
+    Usb,
+
+    /// Read lines from a serial port, e.g. from a microcontroller in the fixture.
+    Serial,
+
+    /// Combine the start requests of other triggers, e.g. for a two-hand start.
+    Combination,
+}
+
+/// What the manager does with start requests from a trigger while a scenario is already running.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TriggerLockout {
+    /// Drop the request, e.g. because the button was pressed twice.
+    Ignore,
+
+    /// Hold on to the request, and start the scenario again once it finishes.
+    Queue,
+}
+
+/// What state the jig must be in for a trigger to start a scenario.
+#[derive(Clone, Copy, PartialEq)]
+pub enum JigRequirement {
+    /// The trigger works regardless of the jig.
+    Any,
+
+    /// A jig must be selected and active.
+    Active,
+
+    /// The jig must be active, and not degraded, being calibrated, or being swapped.
+    Healthy,
+}
+
+impl TriggerType {
+    /// Whether events come from hardware at the station, rather than from a program or the network.
+    fn uses_hardware(&self) -> bool {
+        match *self {
+            TriggerType::Gpio | TriggerType::Barcode | TriggerType::Usb | TriggerType::Serial => true,
+            TriggerType::Program | TriggerType::Network | TriggerType::Combination => false,
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 enum TriggerFormat {
@@ -38,43 +114,135 @@ pub struct TriggerDescription {
     /// A Vec<String> of jig names that this test is compatible with.
     jigs: Vec<UnitName>,
 
+    /// Where the Trigger gets its events from
+    trigger_type: TriggerType,
+
     /// Path to the command to start the Trigger
     exec_start: String,
 
     /// The format expected by the Trigger
     format: TriggerFormat,
 
+    /// Whether to restart the Trigger if its program exits
+    restart: RestartPolicy,
+
     /// The working directory to start from when running the Trigger
     working_directory: Option<PathBuf>,
 
     /// The path to the unit file
     unit_directory: PathBuf,
+
+    /// Other units this one depends on.
+    dependencies: UnitDependencies,
+
+    /// The GPIO line number to watch
+    line: Option<u32>,
+
+    /// A file to read the GPIO level from, the input device of the barcode scanner, or the serial port
+    path: Option<PathBuf>,
+
+    /// Which GPIO transitions start a test
+    edge: GpioEdge,
+
+    /// How long a GPIO line must settle before a change counts
+    debounce: Duration,
+
+    /// The scenario that a scan or network request starts, or None for the current scenario
+    scenario: Option<UnitName>,
+
+    /// Whether to keep the barcode scanner's keypresses to ourselves
+    grab: bool,
+
+    /// The address a network trigger listens on
+    listen: Option<String>,
+
+    /// What network clients speak
+    protocol: NetworkProtocol,
+
+    /// What to do with requests that arrive while a scenario is running
+    lockout: TriggerLockout,
+
+    /// What state the jig must be in for this trigger to start a scenario
+    requires_jig_state: JigRequirement,
+
+    /// The vendor ID of the USB device to wait for
+    vendor: Option<u16>,
+
+    /// The product ID of the USB device to wait for, or None for any product from the vendor
+    product: Option<u16>,
+
+    /// How long USB enumeration must be quiet before the scenario starts
+    settle: Duration,
+
+    /// The speed of the serial port
+    baud_rate: u32,
+
+    /// The pattern a line from the serial port must match to start the scenario
+    pattern: Option<Regex>,
+
+    /// The triggers whose start requests a combination trigger combines
+    inputs: Vec<UnitName>,
+
+    /// Whether all of the inputs must fire, or any one of them
+    mode: CombinationMode,
+
+    /// How close together the inputs must fire
+    window: Duration,
+
+    /// Lines that must be asserted for a combination trigger to fire
+    interlocks: Vec<Interlock>,
+
+    /// How long after starting a scenario this trigger must wait before it can start another
+    cooldown: Duration,
 }
 
 impl TriggerDescription {
-    pub fn from_path(path: &Path) -> Result<TriggerDescription, UnitDescriptionError> {
-        let unit_name = UnitName::from_path(path)?;
-
+    pub fn from_path(unit_name: UnitName, path: &Path) -> Result<TriggerDescription, UnitDescriptionError> {
         // Parse the file into a systemd unit_file object
-        let mut contents = String::with_capacity(8192);
-        File::open(path)?.read_to_string(&mut contents)?;
-        let unit_file = systemd_parser::parse_string(&contents)?;
+        let contents = read_unit_file(&unit_name, path)?;
+        let unit_file = parse_unit_file(&contents)?;
+        check_format_version(&unit_file)?;
 
         if !unit_file.has_category("Trigger") {
             return Err(UnitDescriptionError::MissingSection("Trigger".to_owned()));
         }
 
         let mut interface_description = TriggerDescription {
-            id: unit_name,
+            id: unit_name.clone(),
             name: "".to_owned(),
             description: "".to_owned(),
             jigs: vec![],
+            trigger_type: TriggerType::Program,
             format: TriggerFormat::Text,
+            restart: RestartPolicy::No,
             exec_start: "".to_owned(),
             working_directory: None,
             unit_directory: path.parent().unwrap().to_owned(),
+            dependencies: UnitDependencies::from_unit_file(&unit_file)?,
+            line: None,
+            path: None,
+            edge: GpioEdge::Rising,
+            debounce: Duration::from_millis(DEFAULT_DEBOUNCE_MS),
+            scenario: None,
+            grab: true,
+            listen: None,
+            protocol: NetworkProtocol::Http,
+            lockout: TriggerLockout::Ignore,
+            requires_jig_state: JigRequirement::Any,
+            vendor: None,
+            product: None,
+            settle: Duration::from_secs(0),
+            baud_rate: DEFAULT_BAUD_RATE,
+            pattern: None,
+            inputs: vec![],
+            mode: CombinationMode::All,
+            window: Duration::from_millis(DEFAULT_WINDOW_MS),
+            interlocks: vec![],
+            cooldown: Duration::from_secs(0),
         };
 
+        let expand = |s: &str| unit_name.expand_specifiers(s, path.parent().unwrap());
+
         for entry in unit_file.lookup_by_category("Trigger") {
             match entry {
                 &DirectiveEntry::Solo(ref directive) => match directive.key() {
@@ -93,12 +261,233 @@ impl TriggerDescription {
                     }
                     "WorkingDirectory" => {
                         if let Some(wd) = directive.value() {
-                            interface_description.working_directory = Some(PathBuf::from(wd));
+                            interface_description.working_directory = Some(PathBuf::from(expand(wd)));
+                        }
+                    }
+                    "Type" => {
+                        interface_description.trigger_type = match directive.value() {
+                            None => TriggerType::Program,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "program" => TriggerType::Program,
+                                "gpio" => TriggerType::Gpio,
+                                "barcode" => TriggerType::Barcode,
+                                "network" => TriggerType::Network,
+                                "usb" => TriggerType::Usb,
+                                "serial" => TriggerType::Serial,
+                                "combination" => TriggerType::Combination,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "Type".to_owned(),
+                                        other.to_owned(),
+                                        vec![
+                                            "program".to_owned(),
+                                            "gpio".to_owned(),
+                                            "barcode".to_owned(),
+                                            "network".to_owned(),
+                                            "usb".to_owned(),
+                                            "serial".to_owned(),
+                                            "combination".to_owned(),
+                                        ],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Line" => {
+                        interface_description.line = match directive.value() {
+                            None => None,
+                            Some(s) => Some(s.parse::<u32>()?),
+                        }
+                    }
+                    "Path" => {
+                        interface_description.path = directive.value().map(|s| PathBuf::from(s));
+                    }
+                    "Edge" => {
+                        interface_description.edge = match directive.value() {
+                            None => GpioEdge::Rising,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "rising" => GpioEdge::Rising,
+                                "falling" => GpioEdge::Falling,
+                                "both" => GpioEdge::Both,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "Edge".to_owned(),
+                                        other.to_owned(),
+                                        vec!["rising".to_owned(), "falling".to_owned(), "both".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Debounce" => {
+                        interface_description.debounce = match directive.value() {
+                            None => Duration::from_millis(DEFAULT_DEBOUNCE_MS),
+                            Some(s) => Self::parse_time(s)?,
+                        }
+                    }
+                    "Vendor" => {
+                        interface_description.vendor = match directive.value() {
+                            None => None,
+                            Some(s) => Some(Self::parse_usb_id(s)?),
+                        }
+                    }
+                    "Product" => {
+                        interface_description.product = match directive.value() {
+                            None => None,
+                            Some(s) => Some(Self::parse_usb_id(s)?),
+                        }
+                    }
+                    "Settle" => {
+                        interface_description.settle = match directive.value() {
+                            None => Duration::from_secs(0),
+                            Some(s) => Self::parse_time(s)?,
+                        }
+                    }
+                    "BaudRate" => {
+                        interface_description.baud_rate = match directive.value() {
+                            None => DEFAULT_BAUD_RATE,
+                            Some(s) => {
+                                let rate = s.trim().parse::<u32>()?;
+                                if !BAUD_RATES.contains(&rate) {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "BaudRate".to_owned(),
+                                        s.to_owned(),
+                                        BAUD_RATES.iter().map(|r| r.to_string()).collect(),
+                                    ));
+                                }
+                                rate
+                            }
+                        }
+                    }
+                    "Match" => {
+                        interface_description.pattern = match directive.value() {
+                            None => None,
+                            Some(s) => Some(Regex::new(s)?),
+                        }
+                    }
+                    "Inputs" => {
+                        interface_description.inputs = match directive.value() {
+                            Some(s) => UnitName::from_list(s, "trigger")?,
+                            None => vec![],
+                        }
+                    }
+                    "Mode" => {
+                        interface_description.mode = match directive.value() {
+                            None => CombinationMode::All,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "all" => CombinationMode::All,
+                                "any" => CombinationMode::Any,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "Mode".to_owned(),
+                                        other.to_owned(),
+                                        vec!["all".to_owned(), "any".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Window" => {
+                        interface_description.window = match directive.value() {
+                            None => Duration::from_millis(DEFAULT_WINDOW_MS),
+                            Some(s) => Self::parse_time(s)?,
+                        }
+                    }
+                    "Interlocks" => {
+                        interface_description.interlocks = match directive.value() {
+                            None => vec![],
+                            Some(s) => s.split_whitespace().map(|i| Interlock::from_str(i)).collect(),
+                        }
+                    }
+                    "Cooldown" => {
+                        interface_description.cooldown = match directive.value() {
+                            None => Duration::from_secs(0),
+                            Some(s) => Self::parse_time(s)?,
+                        }
+                    }
+                    "Scenario" => {
+                        interface_description.scenario = match directive.value() {
+                            None => None,
+                            Some(s) => Some(UnitName::from_str(s, "scenario")?),
+                        }
+                    }
+                    "Grab" => {
+                        interface_description.grab = match directive.value() {
+                            None => true,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "yes" => true,
+                                "no" => false,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "Grab".to_owned(),
+                                        other.to_owned(),
+                                        vec!["yes".to_owned(), "no".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "Listen" => interface_description.listen = directive.value().map(|s| s.to_owned()),
+                    "Protocol" => {
+                        interface_description.protocol = match directive.value() {
+                            None => NetworkProtocol::Http,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "http" => NetworkProtocol::Http,
+                                "tcp" => NetworkProtocol::Tcp,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "Protocol".to_owned(),
+                                        other.to_owned(),
+                                        vec!["http".to_owned(), "tcp".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "WhileRunning" => {
+                        interface_description.lockout = match directive.value() {
+                            None => TriggerLockout::Ignore,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "ignore" => TriggerLockout::Ignore,
+                                "queue" => TriggerLockout::Queue,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "WhileRunning".to_owned(),
+                                        other.to_owned(),
+                                        vec!["ignore".to_owned(), "queue".to_owned()],
+                                    ))
+                                }
+                            },
+                        }
+                    }
+                    "RequiresJigState" => {
+                        interface_description.requires_jig_state = match directive.value() {
+                            None => JigRequirement::Any,
+                            Some(s) => match s.to_string().to_lowercase().as_ref() {
+                                "any" => JigRequirement::Any,
+                                "active" => JigRequirement::Active,
+                                "healthy" => JigRequirement::Healthy,
+                                other => {
+                                    return Err(UnitDescriptionError::InvalidValue(
+                                        "Trigger".to_owned(),
+                                        "RequiresJigState".to_owned(),
+                                        other.to_owned(),
+                                        vec!["any".to_owned(), "active".to_owned(), "healthy".to_owned()],
+                                    ))
+                                }
+                            },
                         }
                     }
                     "ExecStart" => {
                         interface_description.exec_start = match directive.value() {
-                            Some(s) => s.to_owned(),
+                            Some(s) => expand(s),
                             None => {
                                 return Err(UnitDescriptionError::MissingValue(
                                     "Trigger".to_owned(),
@@ -107,6 +496,9 @@ impl TriggerDescription {
                             }
                         }
                     }
+                    "Restart" => {
+                        interface_description.restart = RestartPolicy::from_value("Trigger", directive.value())?
+                    }
                     "Format" => {
                         interface_description.format = match directive.value() {
                             None => TriggerFormat::Text,
@@ -129,14 +521,68 @@ impl TriggerDescription {
                 &_ => (),
             }
         }
+        if interface_description.trigger_type == TriggerType::Gpio
+            && interface_description.line.is_none()
+            && interface_description.path.is_none()
+        {
+            return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Line".to_owned()));
+        }
+        if interface_description.trigger_type == TriggerType::Barcode && interface_description.path.is_none() {
+            return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Path".to_owned()));
+        }
+        if interface_description.trigger_type == TriggerType::Network && interface_description.listen.is_none() {
+            return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Listen".to_owned()));
+        }
+        if interface_description.trigger_type == TriggerType::Usb && interface_description.vendor.is_none() {
+            return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Vendor".to_owned()));
+        }
+        if interface_description.trigger_type == TriggerType::Serial {
+            if interface_description.path.is_none() {
+                return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Path".to_owned()));
+            }
+            if interface_description.pattern.is_none() {
+                return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Match".to_owned()));
+            }
+        }
+        if interface_description.trigger_type == TriggerType::Combination && interface_description.inputs.is_empty() {
+            return Err(UnitDescriptionError::MissingValue("Trigger".to_owned(), "Inputs".to_owned()));
+        }
+        if interface_description.inputs.contains(&interface_description.id) {
+            return Err(UnitDescriptionError::InvalidValue(
+                "Trigger".to_owned(),
+                "Inputs".to_owned(),
+                interface_description.id.to_string(),
+                vec!["other triggers".to_owned()],
+            ));
+        }
         Ok(interface_description)
     }
 
+    /// Parse a USB vendor or product ID, which is in hex, e.g. "1d50" or "0x1d50".
+    fn parse_usb_id(id: &str) -> Result<u16, ParseIntError> {
+        let id = id.trim();
+        let id = if id.starts_with("0x") || id.starts_with("0X") { &id[2..] } else { id };
+        u16::from_str_radix(id, 16)
+    }
+
+    fn parse_time(time_str: &str) -> Result<Duration, DurationError> {
+        if let Ok(val) = time_str.parse::<u64>() {
+            Ok(Duration::from_secs(val))
+        } else {
+            parse_duration(time_str)
+        }
+    }
+
     /// Returns true if this test is supported on the named jig.
     pub fn supports_jig(&self, name: &UnitName) -> bool {
         self.jigs.contains(name)
     }
 
+    /// The jigs this is for.  It's for every jig if there aren't any.
+    pub fn jigs(&self) -> &Vec<UnitName> {
+        &self.jigs
+    }
+
     /// Determine if a unit is compatible with this system.
     pub fn is_compatible(
         &self,
@@ -158,6 +604,10 @@ impl TriggerDescription {
         &self.id
     }
 
+    pub fn dependencies(&self) -> &UnitDependencies {
+        &self.dependencies
+    }
+
     pub fn load(
         &self,
         manager: &UnitManager,
@@ -171,14 +621,34 @@ impl TriggerDescription {
 
 pub struct Trigger {
     description: TriggerDescription,
-    process: RefCell<Option<Running>>,
+    process: RefCell<Option<Supervised>>,
+
+    /// How long the program gets to exit when the trigger is deactivated.
+    terminate_timeout: Duration,
+
+    /// When the trigger was recently restarted after its program exited.
+    restarts: RefCell<Restarts>,
+
+    /// The thread watching for events, for built-in triggers.
+    watcher: RefCell<Option<TriggerThread>>,
+
+    /// Which inputs have fired, for combination triggers that are active.
+    combination: RefCell<Option<CombinationTrigger>>,
+
+    /// When this trigger last started a scenario.
+    last_start: RefCell<Option<Instant>>,
 }
 
 impl Trigger {
-    pub fn new(desc: &TriggerDescription, _: &UnitManager, _: &Config) -> Trigger {
+    pub fn new(desc: &TriggerDescription, _: &UnitManager, config: &Config) -> Trigger {
         Trigger {
             description: desc.clone(),
             process: RefCell::new(None),
+            terminate_timeout: config.terminate_timeout().clone(),
+            restarts: RefCell::new(Restarts::new()),
+            watcher: RefCell::new(None),
+            combination: RefCell::new(None),
+            last_start: RefCell::new(None),
         }
     }
 
@@ -186,6 +656,55 @@ impl Trigger {
         &self.description.id
     }
 
+    pub fn set_terminate_timeout(&mut self, timeout: Duration) {
+        self.terminate_timeout = timeout;
+    }
+
+    pub fn lockout(&self) -> TriggerLockout {
+        self.description.lockout
+    }
+
+    pub fn requires_jig_state(&self) -> JigRequirement {
+        self.description.requires_jig_state
+    }
+
+    /// How much longer this trigger must wait before it can start another
+    /// scenario, or None if it may start one now.
+    pub fn cooldown_remaining(&self) -> Option<Duration> {
+        let last_start = (*self.last_start.borrow())?;
+        let elapsed = last_start.elapsed();
+        if elapsed < self.description.cooldown {
+            Some(self.description.cooldown - elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// Note that this trigger has just started a scenario, so its cooldown begins.
+    pub fn started(&self) {
+        *self.last_start.borrow_mut() = Some(Instant::now());
+    }
+
+    /// The scenario this trigger starts, or None for the current scenario.
+    pub fn scenario(&self) -> &Option<UnitName> {
+        &self.description.scenario
+    }
+
+    /// Returns true if this is a combination trigger, and `id` is one of its inputs.
+    pub fn has_input(&self, id: &UnitName) -> bool {
+        self.description.trigger_type == TriggerType::Combination && self.description.inputs.contains(id)
+    }
+
+    /// Pass a start request from one of the inputs to a combination trigger.
+    /// Returns Ok(true) if the scenario should now start, Ok(false) if the
+    /// trigger is waiting for other inputs, or why the request was refused.
+    pub fn combine(&self, input: &UnitName) -> Result<bool, String> {
+        match *self.combination.borrow_mut() {
+            None => Err(format!("combination trigger {} is not active", self.id())),
+            Some(ref mut combination) => combination.input(input),
+        }
+    }
+
     pub fn select(&self) -> Result<(), UnitSelectError> {
         Ok(())
     }
@@ -199,25 +718,114 @@ impl Trigger {
         manager: &UnitManager,
         config: &Config,
     ) -> Result<(), UnitActivateError> {
-        let mut running = Runny::new(self.description.exec_start.as_str())
-                    .directory(&Some(config.working_directory(&self.description.unit_directory, &self.description.working_directory)))
-                    .start()?;
+        // When simulating, there's no hardware to watch, but TRIGGER still fires it.
+        if config.simulate() && self.description.trigger_type.uses_hardware() {
+            let message = "simulated, not watching for events.  Fire it with TRIGGER instead".to_owned();
+            manager
+                .get_control_channel()
+                .send(ManagerControlMessage::new(self.id(), ManagerControlMessageContents::Log(message)))
+                .ok();
+            return Ok(());
+        }
 
-        let stdout = running.take_output();
-        let stderr = running.take_error();
+        match self.description.trigger_type {
+            TriggerType::Program => self.activate_program(manager, config),
+            TriggerType::Gpio => {
+                let gpio = GpioTrigger::new(
+                    self.description.line,
+                    self.path(config),
+                    self.description.edge,
+                    self.description.debounce,
+                    self.description.scenario.clone(),
+                )?;
+                *self.watcher.borrow_mut() = Some(gpio.start(self.id().clone(), manager.get_control_channel())?);
+                Ok(())
+            }
+            TriggerType::Barcode => {
+                // Path is required for barcode triggers, so it's always present here.
+                let scanner = BarcodeTrigger::new(
+                    self.path(config).unwrap(),
+                    self.description.scenario.clone(),
+                    self.description.grab,
+                );
+                *self.watcher.borrow_mut() = Some(scanner.start(self.id().clone(), manager.get_control_channel())?);
+                Ok(())
+            }
+            TriggerType::Network => {
+                // Listen is required for network triggers, so it's always present here.
+                let listener = NetworkTrigger::new(
+                    self.description.listen.as_ref().unwrap(),
+                    self.description.protocol,
+                    self.description.scenario.clone(),
+                );
+                *self.watcher.borrow_mut() = Some(listener.start(self.id().clone(), manager.get_control_channel())?);
+                Ok(())
+            }
+            TriggerType::Usb => {
+                // Vendor is required for usb triggers, so it's always present here.
+                let watcher = UsbTrigger::new(
+                    self.path(config).unwrap_or(PathBuf::from(USB_DEVICES_PATH)),
+                    self.description.vendor.unwrap(),
+                    self.description.product,
+                    self.description.settle,
+                    self.description.scenario.clone(),
+                );
+                *self.watcher.borrow_mut() = Some(watcher.start(self.id().clone(), manager.get_control_channel())?);
+                Ok(())
+            }
+            TriggerType::Serial => {
+                // Path and Match are required for serial triggers, so they're always present here.
+                let port = SerialTrigger::new(
+                    self.path(config).unwrap(),
+                    self.description.baud_rate,
+                    self.description.pattern.clone().unwrap(),
+                    self.description.scenario.clone(),
+                );
+                *self.watcher.borrow_mut() = Some(port.start(self.id().clone(), manager.get_control_channel())?);
+                Ok(())
+            }
+            TriggerType::Combination => {
+                *self.combination.borrow_mut() = Some(CombinationTrigger::new(
+                    self.description.inputs.clone(),
+                    self.description.mode,
+                    self.description.window,
+                    self.description.interlocks.clone(),
+                ));
+                Ok(())
+            }
+        }
+    }
+
+    /// The Path= setting, relative to the working directory.
+    fn path(&self, config: &Config) -> Option<PathBuf> {
+        self.description
+            .path
+            .as_ref()
+            .map(|p| config.working_directory(&self.description.unit_directory, &Some(p.clone())))
+    }
 
+    fn activate_program(
+        &self,
+        manager: &UnitManager,
+        config: &Config,
+    ) -> Result<(), UnitActivateError> {
         let control_sender = manager.get_control_channel();
         let control_sender_id = self.id().clone();
+        let working_directory = config.working_directory(&self.description.unit_directory, &self.description.working_directory);
+        let mut running = Supervised::start(self.id(), &self.description.exec_start, working_directory, None, &control_sender)?;
+
+        let stdout = running.take_output();
+        let stderr = running.take_error();
+
         match self.description.format {
             TriggerFormat::Text => {
-                // Pass control to an out-of-object thread, and shuttle communications
-                // from stdout onto the control_sender channel.
+                // Have the reactor shuttle communications from stdout onto the control_sender channel.
                 let thr_sender_id = control_sender_id.clone();
                 let thr_sender = control_sender.clone();
-                thread::spawn(move || Self::text_read(thr_sender_id, thr_sender, stdout));
+                reactor::watch_lines(stdout, &control_sender_id, &control_sender, move |line| Self::text_read(&thr_sender_id, &thr_sender, line));
                 let thr_sender_id = control_sender_id.clone();
                 let thr_sender = control_sender.clone();
-                thread::spawn(move || Self::text_read_stderr(thr_sender_id, thr_sender, stderr));
+                reactor::watch_lines(stderr, &control_sender_id, &control_sender, move |line| Self::text_read_stderr(&thr_sender_id, &thr_sender, line));
             }
             TriggerFormat::JSON => {
                 ();
@@ -233,74 +841,44 @@ impl Trigger {
     }
 
     pub fn deactivate(&self) -> Result<(), UnitDeactivateError> {
-        if let Some(process) = self.process.borrow_mut().take() {
-            match process.terminate(None) {
-                Ok(retval) => match retval {
-                    0 => Ok(()),
-                    i => Err(UnitDeactivateError::NonZeroReturn(i)),
-                },
-                Err(e) => Err(UnitDeactivateError::RunningError(e)),
-            }
+        self.combination.borrow_mut().take();
+        if let Some(mut watcher) = self.watcher.borrow_mut().take() {
+            watcher.stop();
         }
-        else {
-            Ok(())
+        match self.process.borrow_mut().take() {
+            Some(process) => process.stop(Some(self.terminate_timeout)),
+            None => Ok(()),
         }
     }
 
-    fn cfti_unescape(msg: String) -> String {
-        msg.replace("\\t", "\t")
-            .replace("\\n", "\n")
-            .replace("\\r", "\r")
-            .replace("\\\\", "\\")
+    /// Whether to start the trigger again, now that it's failed because of `reason`.  `code`
+    /// is its program's return code, if it exited.  Returns why not if it's to stay stopped.
+    pub fn restart_after_failure(&self, code: Option<i32>, reason: &str) -> Result<(), String> {
+        self.restarts.borrow_mut().allow(self.description.restart, code, reason)
     }
 
-    fn text_read_stderr(id: UnitName, control: Sender<ManagerControlMessage>, output: RunningOutput) {
-        for line in BufReader::new(output).lines() {
-            let line = line.expect("Unable to get next line");
-            // If the send fails, that means the other end has closed the pipe.
-            if let Err(_) = control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::LogError(line))) {
-                break;
-            }
-        }
+    /// Log a line from stderr.  Returns false once there's no one left to send lines to.
+    fn text_read_stderr(id: &UnitName, control: &Sender<ManagerControlMessage>, line: Option<String>) -> bool {
+        let line = match line {
+            Some(line) => line,
+            None => return false,
+        };
+        // If the send fails, that means the other end has closed the pipe.
+        control.send(ManagerControlMessage::new(id, ManagerControlMessageContents::LogError(line))).is_ok()
     }
 
-    fn text_read(id: UnitName, control: Sender<ManagerControlMessage>, stdout: RunningOutput) {
-        for line in BufReader::new(stdout).lines() {
-            let line = line.expect("Unable to get next line");
-            let mut words: Vec<String> = line.split_whitespace()
-                .map(|x| Self::cfti_unescape(x.to_owned()))
-                .collect();
-
-            // Don't crash if we get a blank line.
-            let msg = if words.len() == 0 {
-                ManagerControlMessageContents::StartScenario(None)
-            }
-            else {
-
-                let verb = words[0].to_lowercase();
-                words.remove(0);
-
-                match verb.as_str() {
-                    "stop" => ManagerControlMessageContents::Unimplemented("stop".to_owned(), "Unable to stop tests".to_owned()),
-                    "start" => {
-                        if words.len() > 0 {
-                            match UnitName::from_str(&words[0], "test") {
-                                Ok(name) => ManagerControlMessageContents::StartScenario(Some(name)),
-                                Err(_) => ManagerControlMessageContents::Unimplemented(words[0].clone(), "name could not be decoded".to_owned()),
-                            }
-                        } else {
-                            ManagerControlMessageContents::StartScenario(None)
-                        }
-                    },
-                    v => ManagerControlMessageContents::Unimplemented(v.to_owned(), words.join(" ")),
-                }
-            };
-
-            // If the send fails, that means the other end has closed the pipe.
-            if let Err(_) = control.send(ManagerControlMessage::new(&id, msg)) {
-                break;
-            }
-        }
-        control.send(ManagerControlMessage::new(&id, ManagerControlMessageContents::ChildExited)).expect("interface couldn't send exit message to controller");
+    /// Handle a command from stdout.  The trigger exiting is reported by its supervisor.
+    /// Returns false once there's no one left to send commands to.
+    fn text_read(id: &UnitName, control: &Sender<ManagerControlMessage>, line: Option<String>) -> bool {
+        let line = match line {
+            Some(line) => line,
+            None => return false,
+        };
+        // If the send fails, that means the other end has closed the pipe.
+        let sent: Result<Vec<()>, _> = triggers::parse_command(&line)
+            .into_iter()
+            .map(|msg| control.send(ManagerControlMessage::new(id, msg)))
+            .collect();
+        sent.is_ok()
     }
 }
@@ -1,86 +1,384 @@
-extern crate notify;
-
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::io;
-use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::Duration;
 use std::thread;
 
+use unit::{is_manifest, manifest_units, UnitName};
 use unitbroadcaster::*;
-
-use self::notify::{RecommendedWatcher, Watcher, RecursiveMode};
+use watch::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
 pub struct UnitWatcher {
-    paths: Vec<PathBuf>,
-    watcher: RecommendedWatcher,
+    /// The unit directories being watched, which are shared with the watcher thread
+    /// so that it can work out the name of units in subdirectories.
+    paths: Arc<Mutex<Vec<PathBuf>>>,
+    watcher: Arc<Mutex<RecommendedWatcher>>,
     broadcaster: UnitBroadcaster,
+    namespaced: bool,
+
+    /// The units found in each file, so that the ones taken out of a manifest can be
+    /// removed when it changes, and all of them when their directory goes away.
+    files: Files,
+}
+
+type Files = Arc<Mutex<HashMap<PathBuf, Vec<UnitName>>>>;
+
+/// Work out the name of the unit at `path`, relative to whichever unit directory it's in.
+fn unit_name(roots: &[PathBuf], path: &Path, namespaced: bool) -> Option<UnitName> {
+    // The file may already be gone, but its directory is probably still there.
+    let canonical = path
+        .parent()
+        .and_then(|p| p.canonicalize().ok())
+        .and_then(|p| path.file_name().map(|f| p.join(f)));
+    let found = roots
+        .iter()
+        .filter_map(|root| {
+            if path.starts_with(root) {
+                Some((root, path.to_owned()))
+            } else {
+                canonical.as_ref().filter(|c| c.starts_with(root)).map(|c| (root, c.clone()))
+            }
+        })
+        .max_by_key(|&(root, _)| root.components().count());
+    match found {
+        Some((root, path)) => UnitName::from_path_in(&path, root, namespaced).ok(),
+        None => UnitName::from_path(path).ok(),
+    }
+}
+
+/// Announce the units defined in the manifest at `path`, all of which are updated whenever
+/// it changes.  Units that are no longer in it are removed.
+fn scan_manifest(path: &Path, roots: &[PathBuf], namespaced: bool, files: &Files, broadcaster: &UnitBroadcaster) {
+    let found: Vec<UnitName> = fs::read_to_string(path)
+        .map(|contents| {
+            manifest_units(&contents)
+                .into_iter()
+                .filter_map(|(file, _, _)| unit_name(roots, &path.with_file_name(file), namespaced))
+                .collect()
+        })
+        .unwrap_or(vec![]);
+    let previous = files
+        .lock()
+        .unwrap()
+        .insert(path.to_owned(), found.clone())
+        .unwrap_or(vec![]);
+
+    for name in previous.iter().filter(|n| !found.contains(n)) {
+        broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_removed(name, path)));
+    }
+    for name in &found {
+        let event = if previous.contains(name) {
+            UnitStatusEvent::new_updated(name, path)
+        } else {
+            UnitStatusEvent::new_added(name, path)
+        };
+        broadcaster.broadcast(&UnitEvent::Status(event));
+    }
+}
+
+/// Remove every unit found in `path`, which may be a unit file, a manifest, or a whole directory.
+fn forget(path: &Path, files: &Files, broadcaster: &UnitBroadcaster) {
+    let mut gone: Vec<(PathBuf, Vec<UnitName>)> = {
+        let mut files = files.lock().unwrap();
+        let paths: Vec<PathBuf> = files.keys().filter(|p| p.starts_with(path)).cloned().collect();
+        paths
+            .into_iter()
+            .filter_map(|p| files.remove(&p).map(|names| (p, names)))
+            .collect()
+    };
+    gone.sort();
+    for (file, names) in gone {
+        for name in names {
+            broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_removed(&name, &file)));
+        }
+    }
+}
+
+/// Announce every unit file in `dir` and its subdirectories.
+fn scan(dir: &Path, roots: &[PathBuf], namespaced: bool, files: &Files, broadcaster: &UnitBroadcaster) -> Result<(), io::Error> {
+    let mut entries = vec![];
+    for entry in dir.read_dir()? {
+        entries.push(entry?.path());
+    }
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            scan(&path, roots, namespaced, files, broadcaster)?;
+        } else if is_manifest(&path) {
+            scan_manifest(&path, roots, namespaced, files, broadcaster);
+        } else if let Some(name) = unit_name(roots, &path, namespaced) {
+            files.lock().unwrap().insert(path.clone(), vec![name.clone()]);
+            broadcaster.broadcast(&UnitEvent::Status(UnitStatusEvent::new_added(&name, &path)));
+        }
+    }
+    Ok(())
+}
+
+/// Every file in `dir` and its subdirectories.
+fn walk(dir: &Path, found: &mut BTreeSet<PathBuf>) -> Result<(), io::Error> {
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, found)?;
+        } else {
+            found.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Work out what a burst of changes to the files at `touched` came to, once they've stopped, by
+/// comparing each unit directory in `present` that they were in with the units already known of.
+/// Only the difference is announced, so a unit file that a deploy removed and put back, or rewrote
+/// many times over, is updated once, and a temporary file that came and went isn't noticed at all.
+fn settle(
+    touched: &BTreeSet<PathBuf>,
+    present: &[PathBuf],
+    roots: &[PathBuf],
+    namespaced: bool,
+    files: &Files,
+    broadcaster: &UnitBroadcaster,
+) {
+    // Unit directories can be nested, and files in the inner one shouldn't be announced twice.
+    let mut seen = HashSet::new();
+    for root in present {
+        let changes: Vec<&PathBuf> = touched.iter().filter(|t| t.starts_with(root) && *t != root).collect();
+        if changes.is_empty() {
+            continue;
+        }
+        // A file has changed if it, or a directory it's in, was touched.
+        let changed = |path: &Path| changes.iter().any(|c| path.starts_with(c));
+
+        let mut current = BTreeSet::new();
+        if let Err(e) = walk(root, &mut current) {
+            broadcaster.log("watcher", format!("Unable to rescan {}: {}", root.display(), e));
+            continue;
+        }
+
+        let mut gone: Vec<PathBuf> = files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|p| p.starts_with(root) && !current.contains(*p))
+            .cloned()
+            .collect();
+        gone.sort();
+        for path in gone {
+            forget(&path, files, broadcaster);
+        }
+
+        for path in current {
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            let known = files.lock().unwrap().contains_key(&path);
+            if is_manifest(&path) {
+                if !known || changed(&path) {
+                    scan_manifest(&path, roots, namespaced, files, broadcaster);
+                }
+            } else if let Some(name) = unit_name(roots, &path, namespaced) {
+                let event = if !known {
+                    UnitStatusEvent::new_added(&name, &path)
+                } else if changed(&path) {
+                    UnitStatusEvent::new_updated(&name, &path)
+                } else {
+                    continue;
+                };
+                files.lock().unwrap().insert(path.clone(), vec![name]);
+                broadcaster.broadcast(&UnitEvent::Status(event));
+            }
+        }
+    }
+}
+
+/// Notice unit directories that have gone away or come back, e.g. during an atomic deploy
+/// that swaps in a new copy of one.  A directory that's gone has its units removed, and its
+/// parent is watched until it reappears, at which point it's watched and scanned again.
+fn check_roots(
+    roots: &[PathBuf],
+    missing: &mut HashSet<PathBuf>,
+    watcher: &Mutex<RecommendedWatcher>,
+    namespaced: bool,
+    files: &Files,
+    broadcaster: &UnitBroadcaster,
+) {
+    for root in roots {
+        let exists = root.is_dir();
+        if !exists && !missing.contains(root) {
+            broadcaster.log("watcher", format!("Unit directory {} has gone away", root.display()));
+            missing.insert(root.clone());
+            let mut watcher = watcher.lock().unwrap();
+            // The watch has usually been dropped along with the directory already.
+            watcher.unwatch(root).ok();
+            if let Some(parent) = root.parent() {
+                if let Err(e) = watcher.watch(parent, RecursiveMode::NonRecursive) {
+                    broadcaster.log("watcher", format!("Unable to watch {} for {} to come back: {}", parent.display(), root.display(), e));
+                }
+            }
+            drop(watcher);
+            forget(root, files, broadcaster);
+        } else if exists && missing.remove(root) {
+            broadcaster.log("watcher", format!("Unit directory {} is back", root.display()));
+            {
+                let mut watcher = watcher.lock().unwrap();
+                if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+                    broadcaster.log("watcher", format!("Unable to watch {}: {}", root.display(), e));
+                }
+                // Stop watching the parent, unless it's still needed for something else.
+                if let Some(parent) = root.parent() {
+                    let needed = roots.iter().any(|r| parent.starts_with(r))
+                        || missing.iter().any(|m| m.parent() == Some(parent));
+                    if !needed {
+                        watcher.unwatch(parent).ok();
+                    }
+                }
+            }
+            scan(root, roots, namespaced, files, broadcaster).ok();
+        }
+    }
 }
 
 impl UnitWatcher {
-    pub fn new(broadcaster: &UnitBroadcaster) -> UnitWatcher {
+    /// Events for the same file that arrive within `debounce` of each other are merged into one, and
+    /// nothing is announced until files have stopped changing for `settle_delay`.
+    /// If `namespaced` is set, units in subdirectories get the directories as part of their id.
+    pub fn new(broadcaster: &UnitBroadcaster, debounce: Duration, settle_delay: Duration, namespaced: bool) -> UnitWatcher {
         let (watcher_tx, watcher_rx) = channel();
+        let paths = Arc::new(Mutex::new(vec![]));
+        let files: Files = Arc::new(Mutex::new(HashMap::new()));
 
         // Automatically select the best implementation for your platform.
         // You can also access each implementation directly e.g. INotifyWatcher.
-        let watcher: RecommendedWatcher = Watcher::new(watcher_tx, Duration::from_secs(0))
+        let watcher: RecommendedWatcher = Watcher::new(watcher_tx, debounce)
             .expect("Unable to create file watcher");
+        // The watcher thread needs it too, to watch unit directories again after they're recreated.
+        let watcher = Arc::new(Mutex::new(watcher));
 
         // This is a simple loop, but you may want to use more complex logic here,
         // for example to handle I/O.
         let thread_broadcaster = broadcaster.clone();
+        let thread_paths = paths.clone();
+        let thread_files = files.clone();
+        let thread_watcher = watcher.clone();
         thread::spawn(move || {
+            let mut missing = HashSet::new();
+            let mut touched = BTreeSet::new();
             loop {
-                match watcher_rx.recv() {
-                    Ok(event) => {
-                        // Convert the DebouncedEvent into a UnitEvent
-                        let status_event = match event {
-                            notify::DebouncedEvent::Create(path) => UnitStatusEvent::new_added(&path),
-                            notify::DebouncedEvent::Write(path) => UnitStatusEvent::new_updated(&path),
-                            notify::DebouncedEvent::Remove(path) => UnitStatusEvent::new_removed(&path),
-                            // Convert Rename() into removed/added
-                            notify::DebouncedEvent::Rename(old_name, new_name) => {
-                                if let Some(evt) = UnitStatusEvent::new_removed(&old_name) {
-                                    thread_broadcaster.broadcast(&UnitEvent::Status(evt));
-                                }
-                                UnitStatusEvent::new_added(&new_name)
-                            } 
-                            _ => None,
-                        };
-
-                        // Send a copy of the message to each of the listeners.
-                        if let Some(evt) = status_event {
-                            thread_broadcaster.broadcast(&UnitEvent::Status(evt));
+                // Wait as long as it takes for something to change, and then until things stop changing.
+                let event = if touched.is_empty() {
+                    match watcher_rx.recv() {
+                        Ok(event) => event,
+                        Err(_) => return,
+                    }
+                } else {
+                    match watcher_rx.recv_timeout(settle_delay) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let roots = thread_paths.lock().unwrap().clone();
+                            let was_missing = missing.clone();
+                            check_roots(&roots, &mut missing, &thread_watcher, namespaced, &thread_files, &thread_broadcaster);
+
+                            // Directories that have just come back have been scanned in full already.
+                            let present: Vec<PathBuf> = roots
+                                .iter()
+                                .filter(|r| !missing.contains(*r) && !was_missing.contains(*r))
+                                .cloned()
+                                .collect();
+                            settle(&touched, &present, &roots, namespaced, &thread_files, &thread_broadcaster);
+                            touched.clear();
+                            continue;
                         }
+                        Err(RecvTimeoutError::Disconnected) => return,
                     }
-                    Err(e) => eprintln!("watch error: {:?}", e),
+                };
+
+                match event {
+                    DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) => {
+                        touched.insert(path);
+                    }
+                    DebouncedEvent::Rename(old_name, new_name) => {
+                        touched.insert(old_name);
+                        touched.insert(new_name);
+                    }
+                    // The poller only ever reports the events above.
+                    #[cfg(feature = "notify")]
+                    _ => (),
                 }
             }
         });
 
         UnitWatcher {
-            paths: vec![],
+            paths: paths,
             broadcaster: broadcaster.clone(),
             watcher: watcher,
+            namespaced: namespaced,
+            files: files,
         }
     }
 
     pub fn add_path(&mut self, config_dir: &str) -> Result<(), io::Error> {
-        let dir = Path::new(config_dir);
-        for entry in dir.read_dir()? {
-           if let Some(evt) = UnitStatusEvent::new_added(&entry?.path()) {
-               self.broadcaster.broadcast(&UnitEvent::Status(evt));
-           }
-        }
+        // Use the canonical path throughout, so that the names of units in subdirectories
+        // can still be worked out after their directory has been removed.
+        let dir = Path::new(config_dir).canonicalize()?;
+        self.paths.lock().unwrap().push(dir.clone());
+
+        let roots = self.paths.lock().unwrap().clone();
+        scan(&dir, &roots, self.namespaced, &self.files, &self.broadcaster)?;
 
         self.watch(&dir).expect("Unable to watch directory");
-        self.paths.push(dir.to_owned());
         Ok(())
     }
 
-    fn watch(&mut self, path: &Path) -> notify::Result<()> {
+    /// Stop watching a unit directory that was added before, and remove all of its units.
+    pub fn remove_path(&mut self, config_dir: &Path) {
+        let dir = config_dir.canonicalize().unwrap_or(config_dir.to_owned());
+        {
+            let mut paths = self.paths.lock().unwrap();
+            match paths.iter().position(|p| *p == dir) {
+                Some(idx) => paths.remove(idx),
+                None => return,
+            };
+        }
+        self.watcher.lock().unwrap().unwatch(&dir).ok();
+        forget(&dir, &self.files, &self.broadcaster);
+    }
+
+    pub fn process_message(&self, msg: &UnitEvent) {
+        match msg {
+            &UnitEvent::ReloadRequest => self.reload(),
+            _ => (),
+        }
+    }
+
+    /// Announce every unit file again, as if they had all just changed, and
+    /// remove the ones that have gone away without the watcher noticing.
+    fn reload(&self) {
+        let roots = self.paths.lock().unwrap().clone();
+        let mut gone: Vec<PathBuf> = self.files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|p| !p.exists())
+            .cloned()
+            .collect();
+        gone.sort();
+        for path in gone {
+            forget(&path, &self.files, &self.broadcaster);
+        }
+        for root in &roots {
+            if let Err(e) = scan(root, &roots, self.namespaced, &self.files, &self.broadcaster) {
+                self.broadcaster.log("watcher", format!("Unable to reload {}: {}", root.display(), e));
+            }
+        }
+    }
+
+    fn watch(&mut self, path: &Path) -> watch::Result<()> {
 
         // Add a path to be watched. All files and directories at that path and
         // below will be monitored for changes.
-        self.watcher.watch(path, RecursiveMode::Recursive)
+        self.watcher.lock().unwrap().watch(path, RecursiveMode::Recursive)
     }
-}
\ No newline at end of file
+}
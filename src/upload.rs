@@ -0,0 +1,260 @@
+// Store-and-forward upload of finished runs to a central server, for sites that
+// collect results from every station in one place.  Each record is kept on disk
+// until the server has it, so that a network that drops out for minutes or days
+// at a time, as factory networks do, loses nothing.
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use logsinks::elastic::split_url;
+use summary::RunResult;
+use unit::UnitName;
+use unitbroadcaster::{LogEntry, UnitBroadcaster, UnitEvent};
+
+/// How long to wait on the server before counting an upload as failed.
+const UPLOAD_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait before retrying after the first failed upload.  The wait doubles
+/// after every failure in a row, up to the maximum, so a long outage isn't hammered.
+const RETRY_MIN_SECS: u64 = 5;
+const RETRY_MAX_SECS: u64 = 300;
+
+/// Where records the server refused outright are moved to, under the queue directory.
+const REJECTED_DIRECTORY: &str = "rejected";
+
+/// What's uploaded for each run.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Unique to this record, and the same every time it's sent, so that the server can
+    /// tell a record it already has from a new one.
+    id: &'a str,
+
+    /// Which station the run was on.
+    station: &'a str,
+
+    result: &'a RunResult,
+}
+
+/// A record waiting to be uploaded, along with the copy of it in the queue directory, if there is one.
+struct Pending {
+    id: String,
+    body: String,
+    path: Option<PathBuf>,
+}
+
+/// How an upload went.
+enum Upload {
+    /// The server has the record, either because it was just sent or because it already had it.
+    Done,
+
+    /// The server won't ever take the record, so there's no point sending it again.
+    Rejected(String),
+}
+
+/// Uploads records in the background, in the order the runs finished.  Records that can't be
+/// uploaded are retried until they are, and if there's a queue directory they're kept there in
+/// the meantime, so that they survive exclave being restarted.
+pub struct Uploader {
+    url: String,
+    station: String,
+    queue_directory: Option<PathBuf>,
+    sender: Sender<Pending>,
+}
+
+impl Uploader {
+    pub fn start(url: &str, station: &str, queue_directory: Option<PathBuf>, broadcaster: &UnitBroadcaster) -> Uploader {
+        let (sender, receiver) = channel::<Pending>();
+        let mut queue = VecDeque::new();
+        let id = UnitName::internal("upload");
+
+        // Anything left over from last time goes first.
+        if let Some(ref directory) = queue_directory {
+            match queued(directory) {
+                Ok(pending) => queue.extend(pending),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => broadcaster.broadcast(&UnitEvent::Log(LogEntry::new_error(
+                    id.clone(),
+                    format!("unable to read queued records from {}: {}", directory.to_string_lossy(), e),
+                ))),
+            }
+        }
+
+        let thr_url = url.to_owned();
+        let thr_directory = queue_directory.clone();
+        let bc = broadcaster.clone();
+        thread::spawn(move || {
+            let mut retry = Duration::from_secs(RETRY_MIN_SECS);
+            loop {
+                // Wait for a record if there's nothing to do, or pick up any new ones if there is.
+                if queue.is_empty() {
+                    match receiver.recv() {
+                        Ok(pending) => queue.push_back(pending),
+                        Err(_) => return,
+                    }
+                }
+                while let Ok(pending) = receiver.try_recv() {
+                    queue.push_back(pending);
+                }
+
+                let result = upload(&thr_url, queue.front().unwrap());
+                match result {
+                    Ok(Upload::Done) => {
+                        let uploaded = queue.pop_front().unwrap();
+                        if let Some(path) = uploaded.path {
+                            fs::remove_file(path).ok();
+                        }
+                        if retry > Duration::from_secs(RETRY_MIN_SECS) {
+                            bc.broadcast(&UnitEvent::Log(LogEntry::new_info(id.clone(), "uploading records again".to_owned())));
+                        }
+                        retry = Duration::from_secs(RETRY_MIN_SECS);
+                    }
+                    Ok(Upload::Rejected(reason)) => {
+                        let rejected = queue.pop_front().unwrap();
+                        let kept = match (rejected.path, thr_directory.as_ref()) {
+                            (Some(path), Some(directory)) => set_aside(&path, directory).ok(),
+                            _ => None,
+                        };
+                        let message = match kept {
+                            Some(kept) => format!("server refused record {}, so it's been moved to {}: {}", rejected.id, kept.to_string_lossy(), reason),
+                            None => format!("server refused record {}, so it's been dropped: {}", rejected.id, reason),
+                        };
+                        bc.broadcast(&UnitEvent::Log(LogEntry::new_error(id.clone(), message)));
+                    }
+                    Err(e) => {
+                        bc.broadcast(&UnitEvent::Log(LogEntry::new_error(
+                            id.clone(),
+                            format!("unable to upload record, {} waiting, retrying in {}s: {}", queue.len(), retry.as_secs(), e),
+                        )));
+                        // Keep collecting new records while waiting to retry.
+                        match receiver.recv_timeout(retry) {
+                            Ok(pending) => queue.push_back(pending),
+                            Err(RecvTimeoutError::Timeout) => (),
+                            // exclave is exiting.  Whatever is in the queue directory is uploaded next time.
+                            Err(RecvTimeoutError::Disconnected) => return,
+                        }
+                        retry = (retry * 2).min(Duration::from_secs(RETRY_MAX_SECS));
+                    }
+                }
+            }
+        });
+
+        Uploader {
+            url: url.to_owned(),
+            station: station.to_owned(),
+            queue_directory: queue_directory,
+            sender: sender,
+        }
+    }
+
+    pub fn url(&self) -> &String {
+        &self.url
+    }
+
+    pub fn station(&self) -> &String {
+        &self.station
+    }
+
+    pub fn queue_directory(&self) -> &Option<PathBuf> {
+        &self.queue_directory
+    }
+
+    /// Queue a run's result to be uploaded.  It's written to the queue directory first, if there is one.
+    pub fn upload(&self, result: &RunResult) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        // Named so that they sort in the order the runs finished.
+        let name = format!("{:012}.{:09}", now.as_secs(), now.subsec_nanos());
+        let id = format!("{}-{}", self.station, name);
+        let body = serde_json::to_string(&Record {
+            id: &id,
+            station: &self.station,
+            result: result,
+        })?;
+        let path = match self.queue_directory {
+            None => None,
+            Some(ref directory) => {
+                fs::create_dir_all(directory)?;
+                let path = directory.join(format!("{}.json", name));
+                let mut file = File::create(&path)?;
+                file.write_all(body.as_bytes())?;
+                // The record must still be there if the station loses power straight after the run.
+                file.sync_all()?;
+                Some(path)
+            }
+        };
+        self.sender
+            .send(Pending { id: id, body: body, path: path })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "uploading has stopped"))
+    }
+}
+
+/// Records that were queued but not uploaded, oldest first.
+fn queued(directory: &Path) -> io::Result<Vec<Pending>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+        .collect();
+    paths.sort();
+
+    let mut pending = vec![];
+    for path in paths {
+        let mut body = String::new();
+        File::open(&path)?.read_to_string(&mut body)?;
+        // A record that was cut short when it was written can't be sent, and is set aside.
+        let id = match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(ref record) => record.get("id").and_then(|id| id.as_str()).map(|id| id.to_owned()),
+            Err(_) => None,
+        };
+        match id {
+            Some(id) => pending.push(Pending { id: id, body: body, path: Some(path) }),
+            None => {
+                set_aside(&path, directory)?;
+            }
+        }
+    }
+    Ok(pending)
+}
+
+/// Move a record that can't be uploaded out of the queue, so that it can be looked at later.
+fn set_aside(path: &Path, directory: &Path) -> io::Result<PathBuf> {
+    let rejected = directory.join(REJECTED_DIRECTORY);
+    fs::create_dir_all(&rejected)?;
+    let destination = rejected.join(path.file_name().unwrap_or_default());
+    fs::rename(path, &destination)?;
+    Ok(destination)
+}
+
+/// POST a record to the server.  The record's id goes in an Idempotency-Key header as well, so that
+/// a server which got a record but whose response was lost can recognise it when it's sent again.
+fn upload(url: &str, pending: &Pending) -> io::Result<Upload> {
+    let (address, path) = split_url(url, "http", 80)?;
+    let path = if path.is_empty() { "/" } else { path };
+    let mut stream = TcpStream::connect(&*address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(UPLOAD_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(UPLOAD_TIMEOUT_SECS)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nIdempotency-Key: {}\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        address,
+        pending.id,
+        pending.body.len(),
+        pending.body
+    )?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        // 409 Conflict means the server already has a record with this id.
+        Some(code) if code.starts_with('2') || code == "409" => Ok(Upload::Done),
+        // Anything else in the 4xx range won't be any different next time, apart from timeouts and rate limits.
+        Some(code) if code.starts_with('4') && code != "408" && code != "429" => Ok(Upload::Rejected(status.trim().to_owned())),
+        _ => Err(io::Error::new(io::ErrorKind::Other, format!("server refused record: {}", status.trim()))),
+    }
+}
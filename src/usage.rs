@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use std::time::{self, Duration};
+
+use timestamp;
+use unit::UnitName;
+
+/// The CPU time and memory used by one or more programs, as reported when they were reaped.
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    user: Duration,
+    system: Duration,
+
+    /// The largest peak resident set size of any of the programs, in kilobytes, or 0 if it isn't known.
+    max_rss: u64,
+}
+
+impl Usage {
+    pub fn new(user: Duration, system: Duration, max_rss: u64) -> Usage {
+        Usage {
+            user: user,
+            system: system,
+            max_rss: max_rss,
+        }
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.user += other.user;
+        self.system += other.system;
+        self.max_rss = self.max_rss.max(other.max_rss);
+    }
+}
+
+/// Adds up what a unit's programs use, as each of them is reaped.  Programs that a
+/// unit's program started are included, once they've exited and been waited for.
+#[derive(Clone, Default)]
+pub struct Meter {
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Meter {
+    pub fn new() -> Meter {
+        Meter::default()
+    }
+
+    /// Count what a program used, once it's been reaped.
+    pub fn add(&self, usage: &Usage) {
+        self.usage.lock().unwrap().add(usage);
+    }
+
+    /// What's been used since the last time this was called.
+    pub fn take(&self) -> Usage {
+        ::std::mem::replace(&mut *self.usage.lock().unwrap(), Usage::default())
+    }
+}
+
+/// What a test, interface, or logger used, from when it was started until its program exited.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize)]
+pub struct ProcessStats {
+    unit: UnitName,
+
+    /// How long the unit ran for, in milliseconds.
+    wall_ms: u64,
+
+    /// CPU time spent in the program itself and in the kernel on its behalf, in milliseconds.
+    user_ms: u64,
+    system_ms: u64,
+
+    /// The program's peak resident set size in kilobytes, if it's known.
+    max_rss_kb: Option<u64>,
+
+    /// Number of seconds since the epoch
+    unix_time: u64,
+
+    /// Number of nanoseconds since the epoch
+    unix_time_nsecs: u32,
+
+    /// Wall-clock time in RFC3339 format, in the configured timezone
+    timestamp: String,
+}
+
+impl ProcessStats {
+    /// The usage of `unit`, which ran for `wall` and whose programs used `usage`.
+    pub fn new(unit: &UnitName, wall: Duration, usage: &Usage) -> ProcessStats {
+        let now = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(d) => d,
+            Err(_) => time::Duration::new(0, 0),
+        };
+
+        ProcessStats {
+            unit: unit.clone(),
+            wall_ms: millis(wall),
+            user_ms: millis(usage.user),
+            system_ms: millis(usage.system),
+            max_rss_kb: if usage.max_rss > 0 { Some(usage.max_rss) } else { None },
+            unix_time: now.as_secs(),
+            unix_time_nsecs: now.subsec_nanos(),
+            timestamp: timestamp::rfc3339(now.as_secs(), now.subsec_nanos()),
+        }
+    }
+
+    pub fn id(&self) -> &UnitName {
+        &self.unit
+    }
+
+    pub fn wall_ms(&self) -> u64 {
+        self.wall_ms
+    }
+
+    pub fn user_ms(&self) -> u64 {
+        self.user_ms
+    }
+
+    pub fn system_ms(&self) -> u64 {
+        self.system_ms
+    }
+
+    pub fn max_rss_kb(&self) -> Option<u64> {
+        self.max_rss_kb
+    }
+
+    pub fn secs(&self) -> u64 {
+        self.unix_time
+    }
+
+    pub fn nsecs(&self) -> u32 {
+        self.unix_time_nsecs
+    }
+
+    pub fn timestamp(&self) -> &String {
+        &self.timestamp
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
@@ -0,0 +1,183 @@
+// Watching unit directories and the configuration file for changes.  Normally
+// this is left to notify, which uses inotify on Linux.  Builds without the
+// "notify" feature, e.g. static ones for test controllers that boot from a small
+// initramfs, look at the files every so often instead.  That takes longer to
+// notice a change, but needs nothing beyond std.
+#[cfg(feature = "notify")]
+extern crate notify;
+
+#[cfg(feature = "notify")]
+pub use self::notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Result, Watcher};
+
+#[cfg(not(feature = "notify"))]
+pub use self::poll::{DebouncedEvent, PollWatcher as RecommendedWatcher, RecursiveMode, Result, Watcher};
+
+#[cfg(not(feature = "notify"))]
+mod poll {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    /// How often files are looked at, unless the delay the watcher was made with is longer.
+    const POLL_INTERVAL_MS: u64 = 1000;
+
+    pub type Result<T> = io::Result<T>;
+
+    /// The same changes that notify reports, as far as polling can tell them apart.
+    #[derive(Debug)]
+    pub enum DebouncedEvent {
+        Create(PathBuf),
+        Write(PathBuf),
+        Remove(PathBuf),
+
+        /// Polling sees a file that was renamed as one that was removed and another that was
+        /// created, so this is never sent.  It's here so that the same code handles both watchers.
+        #[allow(dead_code)]
+        Rename(PathBuf, PathBuf),
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum RecursiveMode {
+        Recursive,
+        NonRecursive,
+    }
+
+    /// The parts of notify's Watcher that exclave uses.
+    pub trait Watcher: Sized {
+        fn new(tx: Sender<DebouncedEvent>, delay: Duration) -> Result<Self>;
+        fn watch<P: AsRef<Path>>(&mut self, path: P, mode: RecursiveMode) -> Result<()>;
+        fn unwatch<P: AsRef<Path>>(&mut self, path: P) -> Result<()>;
+    }
+
+    /// What a file looked like the last time it was looked at.
+    #[derive(PartialEq)]
+    struct Stamp {
+        is_dir: bool,
+        modified: Option<SystemTime>,
+        len: u64,
+    }
+
+    #[derive(Default)]
+    struct State {
+        watches: HashMap<PathBuf, RecursiveMode>,
+        known: BTreeMap<PathBuf, Stamp>,
+    }
+
+    pub struct PollWatcher {
+        state: Arc<Mutex<State>>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl Watcher for PollWatcher {
+        fn new(tx: Sender<DebouncedEvent>, delay: Duration) -> Result<PollWatcher> {
+            let state = Arc::new(Mutex::new(State::default()));
+            let stop = Arc::new(AtomicBool::new(false));
+            let interval = delay.max(Duration::from_millis(POLL_INTERVAL_MS));
+
+            let thr_state = state.clone();
+            let thr_stop = stop.clone();
+            thread::spawn(move || {
+                while !thr_stop.load(Ordering::SeqCst) {
+                    thread::sleep(interval);
+                    let mut state = thr_state.lock().unwrap();
+                    let mut current = BTreeMap::new();
+                    for (path, mode) in &state.watches {
+                        look(path, *mode, &mut current);
+                    }
+                    for event in differences(&state.known, &current) {
+                        if tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    state.known = current;
+                }
+            });
+
+            Ok(PollWatcher { state: state, stop: stop })
+        }
+
+        fn watch<P: AsRef<Path>>(&mut self, path: P, mode: RecursiveMode) -> Result<()> {
+            let path = path.as_ref();
+            if !path.is_dir() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} isn't a directory", path.display())));
+            }
+            // Whatever is there already isn't a change.
+            let mut state = self.state.lock().unwrap();
+            look(path, mode, &mut state.known);
+            state.watches.insert(path.to_owned(), mode);
+            Ok(())
+        }
+
+        fn unwatch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+            let path = path.as_ref();
+            let mut state = self.state.lock().unwrap();
+            if state.watches.remove(path).is_none() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} isn't being watched", path.display())));
+            }
+            let mut known = BTreeMap::new();
+            for (path, mode) in &state.watches {
+                look(path, *mode, &mut known);
+            }
+            state.known = known;
+            Ok(())
+        }
+    }
+
+    impl Drop for PollWatcher {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Note down `path` and what's in it, along with everything in its subdirectories if `mode` is Recursive.
+    fn look(path: &Path, mode: RecursiveMode, found: &mut BTreeMap<PathBuf, Stamp>) {
+        let here = match stamp(path) {
+            Some(here) => here,
+            None => return,
+        };
+        let is_dir = here.is_dir;
+        found.insert(path.to_owned(), here);
+        if !is_dir {
+            return;
+        }
+        for entry in path.read_dir().into_iter().flat_map(|entries| entries).filter_map(|e| e.ok()) {
+            let entry = entry.path();
+            if mode == RecursiveMode::Recursive {
+                look(&entry, mode, found);
+            } else if let Some(stamp) = stamp(&entry) {
+                found.insert(entry, stamp);
+            }
+        }
+    }
+
+    fn stamp(path: &Path) -> Option<Stamp> {
+        fs::metadata(path).ok().map(|metadata| Stamp {
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+
+    /// What changed between two looks at the same files, in the order of their paths.  Only files
+    /// are written to; a directory changes whenever anything in it does, which isn't news.
+    fn differences(before: &BTreeMap<PathBuf, Stamp>, after: &BTreeMap<PathBuf, Stamp>) -> Vec<DebouncedEvent> {
+        let mut events = vec![];
+        for (path, stamp) in after {
+            match before.get(path) {
+                None => events.push(DebouncedEvent::Create(path.clone())),
+                Some(old) if !stamp.is_dir && old != stamp => events.push(DebouncedEvent::Write(path.clone())),
+                Some(_) => (),
+            }
+        }
+        for path in before.keys().filter(|p| !after.contains_key(*p)) {
+            events.push(DebouncedEvent::Remove(path.clone()));
+        }
+        events
+    }
+}
@@ -0,0 +1,147 @@
+// Yield figures over recent windows of time, so that line leads can see at a
+// glance how a station is doing: how many devices pass the first time they're
+// tested, how often devices come back for a retest, and which tests fail most.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use logsinks::csv::split_row;
+use timestamp;
+
+/// How many of the most-failed tests are reported for each window.
+pub const TOP_FAILURES: usize = 5;
+
+/// A run that has finished, as far as yield is concerned.
+#[derive(Clone, Debug)]
+pub struct RunOutcome {
+    /// When the run started, in seconds since the epoch.
+    pub started: u64,
+
+    /// The serial number of the device that was tested, if it's known.
+    pub serial: Option<String>,
+
+    pub passed: bool,
+
+    /// Ids of the tests that failed.
+    pub failed_tests: Vec<String>,
+}
+
+/// Yield figures over the runs that started in one window of time, ending now.
+#[derive(Clone, Debug)]
+pub struct YieldWindow {
+    pub window: Duration,
+
+    pub runs: usize,
+
+    /// The fraction of devices first tested in the window that passed that first time.
+    /// Devices without a serial number can't be told apart, so each run of one counts as a first test.
+    pub first_pass_yield: Option<f64>,
+
+    /// The fraction of runs in the window that were of a device that had been tested before.
+    pub retest_rate: Option<f64>,
+
+    /// The tests that failed most often in the window, and how often, most first.
+    pub top_failures: Vec<(String, usize)>,
+}
+
+pub struct YieldStats {
+    /// Finished runs, oldest first.
+    history: VecDeque<RunOutcome>,
+
+    /// How long runs are remembered for.  This is longer than the longest window, so
+    /// that devices tested just before a window began are still known to be retests.
+    keep: Duration,
+}
+
+impl YieldStats {
+    pub fn new(keep: Duration) -> YieldStats {
+        YieldStats {
+            history: VecDeque::new(),
+            keep: keep,
+        }
+    }
+
+    /// Pick up where a previous instance left off, from the runs.csv that run summaries are written to.
+    pub fn load(path: &Path, keep: Duration) -> io::Result<YieldStats> {
+        let mut stats = YieldStats::new(keep);
+        for line in BufReader::new(File::open(path)?).lines().skip(1) {
+            let fields = split_row(&line?);
+            if fields.len() < 7 {
+                continue;
+            }
+            let started = match timestamp::parse_rfc3339(&fields[0]) {
+                Some(s) => s,
+                None => continue,
+            };
+            stats.record(RunOutcome {
+                started: started,
+                serial: if fields[1].is_empty() { None } else { Some(fields[1].clone()) },
+                passed: fields[3] == "pass",
+                failed_tests: fields[6].split_whitespace().map(|t| t.to_owned()).collect(),
+            });
+        }
+        Ok(stats)
+    }
+
+    pub fn set_keep(&mut self, keep: Duration) {
+        self.keep = keep;
+    }
+
+    pub fn record(&mut self, outcome: RunOutcome) {
+        self.history.push_back(outcome);
+        let oldest = now().saturating_sub(self.keep.as_secs());
+        while self.history.front().map(|r| r.started < oldest).unwrap_or(false) {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn window(&self, window: Duration) -> YieldWindow {
+        let start = now().saturating_sub(window.as_secs());
+        let mut seen: HashSet<&String> = HashSet::new();
+        let mut runs = 0;
+        let (mut first_tests, mut first_passes, mut retests) = (0, 0, 0);
+        let mut failures: HashMap<&String, usize> = HashMap::new();
+
+        for run in &self.history {
+            let retest = match run.serial {
+                Some(ref serial) => !seen.insert(serial),
+                None => false,
+            };
+            if run.started < start {
+                continue;
+            }
+
+            runs += 1;
+            if retest {
+                retests += 1;
+            } else {
+                first_tests += 1;
+                if run.passed {
+                    first_passes += 1;
+                }
+            }
+            for test in &run.failed_tests {
+                *failures.entry(test).or_insert(0) += 1;
+            }
+        }
+
+        let mut top_failures: Vec<(String, usize)> = failures.into_iter().map(|(test, count)| (test.clone(), count)).collect();
+        top_failures.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_failures.truncate(TOP_FAILURES);
+
+        let fraction = |n: usize, of: usize| if of == 0 { None } else { Some(n as f64 / of as f64) };
+        YieldWindow {
+            window: window,
+            runs: runs,
+            first_pass_yield: fraction(first_passes, first_tests),
+            retest_rate: fraction(retests, runs),
+            top_failures: top_failures,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0)).as_secs()
+}